@@ -0,0 +1,419 @@
+//! Benchmarks of the handshake rate, data plane throughput and latency, hello reassembly and the
+//! cost of rejecting garbage.
+//! Run them with `cargo bench` from the `performance` directory. The benchmarks of internals,
+//! such as `hello_reassembly`, also need `--features fuzzing`.
+//!
+//! Every context is seeded with a fixed RNG and time stands still, so timers never fire and
+//! consecutive runs do the same work.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rand_core::{CryptoRng, RngCore};
+
+use zssp::application::*;
+use zssp::crypto::{P384KeyPair, P384PublicKey};
+use zssp::crypto_impl::*;
+use zssp::result::{ReceiveOk, SessionEvent};
+use zssp::{Context, Session};
+
+const MTU: usize = 1500;
+
+/// A xorshift generator, which is only acceptable because these contexts protect nothing.
+struct BenchRng(u64);
+impl RngCore for BenchRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+impl CryptoRng for BenchRng {}
+
+struct C;
+impl CryptoLayer for C {
+    type Rng = BenchRng;
+    type PrpEnc = OpenSSLAes256Enc;
+    type PrpDec = OpenSSLAes256Dec;
+    type Aead = OpenSSLAesGcm;
+    type AeadPool = OpenSSLAesGcmPool;
+    type Hash = CrateSha512;
+    type Hmac = CrateHmacSha512;
+    type PublicKey = CrateP384PublicKey;
+    type KeyPair = CrateP384KeyPair;
+    type Kem = CrateKyber1024PrivateKey;
+
+    type SessionData = ();
+    type FingerprintData = ();
+    type IncomingPacketBuffer = Vec<u8>;
+}
+
+/// Accepts every session without a challenge and never stores ratchet states, so every
+/// handshake does the same work.
+#[derive(Clone, Copy)]
+struct App {
+    kyber: bool,
+}
+impl ApplicationLayer<C> for App {
+    fn time(&mut self) -> i64 {
+        0
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        IncomingSessionAction::Allow
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session<C>>) -> bool {
+        false
+    }
+    fn check_accept_session(&mut self, _: &CrateP384PublicKey, _: &[u8], _: Option<&()>) -> AcceptAction<C> {
+        AcceptAction {
+            session_data: Some(()),
+            responder_disallows_downgrade: false,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(&mut self, _: &[u8; RATCHET_SIZE]) -> std::io::Result<Option<(RatchetState, ())>> {
+        Ok(None)
+    }
+    fn restore_by_identity(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        Ok(None)
+    }
+    fn save_ratchet_state(&mut self, _: &CrateP384PublicKey, _: &(), _: CompareAndSwap<'_>) -> std::io::Result<bool> {
+        Ok(true)
+    }
+    fn prefer_kyber(&mut self) -> bool {
+        self.kyber
+    }
+}
+
+type Link = RefCell<VecDeque<Vec<u8>>>;
+fn sender(link: &Link) -> impl FnMut(&mut [u8]) -> bool + Copy + '_ {
+    move |packet: &mut [u8]| {
+        link.borrow_mut().push_back(packet.to_vec());
+        true
+    }
+}
+
+/// An in-memory connection between two contexts.
+struct Pair {
+    alice: Context<C>,
+    bob: Context<C>,
+    bob_public: CrateP384PublicKey,
+    to_alice: Link,
+    to_bob: Link,
+    output: Vec<u8>,
+}
+impl Pair {
+    fn new() -> Self {
+        let mut alice_rng = BenchRng(0x2545f4914f6cdd1d);
+        let mut bob_rng = BenchRng(0x9e3779b97f4a7c15);
+        let alice_secret = <CrateP384KeyPair as P384KeyPair<BenchRng>>::generate(&mut alice_rng);
+        let bob_secret = <CrateP384KeyPair as P384KeyPair<BenchRng>>::generate(&mut bob_rng);
+        let bob_public = <CrateP384KeyPair as P384KeyPair<BenchRng>>::public_key_bytes(&bob_secret);
+        Self {
+            alice: Context::new(alice_secret, alice_rng),
+            bob: Context::new(bob_secret, bob_rng),
+            bob_public: CrateP384PublicKey::from_bytes(&bob_public).unwrap(),
+            to_alice: Link::default(),
+            to_bob: Link::default(),
+            output: Vec::new(),
+        }
+    }
+    /// Receive the next packet queued on `inbox` by `ctx`.
+    fn deliver(&mut self, app: App, to_bob: bool) -> Option<ReceiveOk<C>> {
+        let (ctx, inbox, outbox) = if to_bob {
+            (&self.bob, &self.to_bob, &self.to_alice)
+        } else {
+            (&self.alice, &self.to_alice, &self.to_bob)
+        };
+        let packet = inbox.borrow_mut().pop_front()?;
+        let send = sender(outbox);
+        self.output.clear();
+        let result = ctx.receive(
+            app,
+            send,
+            MTU,
+            |_: &Arc<Session<C>>| Some((send, MTU)),
+            &(),
+            packet,
+            &mut self.output,
+        );
+        Some(result.unwrap().0)
+    }
+    /// Run a full handshake, returning Alice's and Bob's sessions.
+    fn handshake(&mut self, app: App) -> (Arc<Session<C>>, Arc<Session<C>>) {
+        let (alice_session, _) = self
+            .alice
+            .open(app, sender(&self.to_bob), MTU, self.bob_public, (), &[])
+            .unwrap();
+        let mut bob_session = None;
+        while !self.to_bob.borrow().is_empty() || !self.to_alice.borrow().is_empty() {
+            if let Some(ReceiveOk::Associated(session, SessionEvent::NewSession)) = self.deliver(app, true) {
+                bob_session = Some(session);
+            }
+            self.deliver(app, false);
+        }
+        assert!(alice_session.established());
+        (alice_session, bob_session.unwrap())
+    }
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+    for (name, kyber) in [("p384_kyber1024", true), ("p384", false)] {
+        let mut pair = Pair::new();
+        let app = App { kyber };
+        group.bench_function(name, |b| b.iter(|| pair.handshake(app)));
+    }
+    group.finish();
+}
+
+fn bench_data(c: &mut Criterion) {
+    let app = App { kyber: false };
+    let mut pair = Pair::new();
+    let (alice_session, _bob_session) = pair.handshake(app);
+    let mut work_buffer = vec![0u8; MTU];
+    let mut group = c.benchmark_group("data");
+    for size in [64, 1400] {
+        let data = vec![0x5au8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("{}_bytes", size), |b| {
+            b.iter(|| {
+                let send = sender(&pair.to_bob);
+                pair.alice
+                    .send(&alice_session, send, MTU, &mut work_buffer, &data)
+                    .unwrap();
+                while pair.deliver(app, true).is_some() {}
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Runs handshakes from `alice` to `bob` and drops the sessions right away until `stop` is set,
+/// so that `bob` keeps inserting and removing key ids.
+fn churn(alice: &Context<C>, bob: &Context<C>, bob_public: CrateP384PublicKey, stop: &AtomicBool) {
+    let app = App { kyber: false };
+    let (to_alice, to_bob) = (Link::default(), Link::default());
+    let mut output = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        let (_session, _) = alice.open(app, sender(&to_bob), MTU, bob_public, (), &[]).unwrap();
+        let mut bob_sessions = Vec::new();
+        loop {
+            let (ctx, packet, outbox) = if let Some(packet) = to_bob.borrow_mut().pop_front() {
+                (bob, packet, &to_alice)
+            } else if let Some(packet) = to_alice.borrow_mut().pop_front() {
+                (alice, packet, &to_bob)
+            } else {
+                break;
+            };
+            let send = sender(outbox);
+            let send_to = |_: &Arc<Session<C>>| Some((send, MTU));
+            if let Ok((ReceiveOk::Associated(session, _), _)) =
+                ctx.receive(app, send, MTU, send_to, &(), packet, &mut output)
+            {
+                bob_sessions.push(session);
+            }
+        }
+    }
+}
+
+/// Measures the 99th percentile latency of receiving minimum size data packets on several threads
+/// at once, while another thread keeps running handshakes with the same context. Each iteration
+/// receives one packet on every thread, and the time reported is the 99th percentile of all of
+/// them rather than their mean.
+fn bench_receive_under_churn(c: &mut Criterion) {
+    const THREADS: usize = 8;
+    let app = App { kyber: false };
+    let mut pair = Pair::new();
+    // Bob's sessions are kept alive alongside Alice's, or he would expire them.
+    let sessions: Vec<_> = (0..THREADS).map(|_| pair.handshake(app)).collect();
+    let churn_secret = <CrateP384KeyPair as P384KeyPair<BenchRng>>::generate(&mut BenchRng(0xd1b54a32d192ed03));
+    let churn_alice = Context::<C>::new(churn_secret, BenchRng(0xaf251af3b0f025b5));
+    // `Pair` cannot be shared between threads, but its contexts can.
+    let (alice, bob, bob_public) = (&pair.alice, &pair.bob, pair.bob_public);
+    let mut group = c.benchmark_group("receive_under_churn");
+    group.bench_function(format!("{}_threads_p99", THREADS), |b| {
+        b.iter_custom(|iters| {
+            // Every thread gets its own session, so its packets arrive in order.
+            let packets: Vec<Vec<Vec<u8>>> = sessions
+                .iter()
+                .map(|(session, _)| {
+                    let link = Link::default();
+                    let mut work_buffer = [0u8; MTU];
+                    for _ in 0..iters {
+                        alice
+                            .send(session, sender(&link), MTU, &mut work_buffer, &[])
+                            .unwrap();
+                    }
+                    link.into_inner().into()
+                })
+                .collect();
+            let stop = AtomicBool::new(false);
+            let mut latencies: Vec<Duration> = std::thread::scope(|s| {
+                s.spawn(|| churn(&churn_alice, bob, bob_public, &stop));
+                let receivers: Vec<_> = packets
+                    .into_iter()
+                    .map(|packets| {
+                        s.spawn(|| {
+                            let mut output = Vec::new();
+                            let send = |_: &mut [u8]| true;
+                            let send_to = |_: &Arc<Session<C>>| Some((send, MTU));
+                            let mut latencies = Vec::with_capacity(packets.len());
+                            for packet in packets {
+                                let start = Instant::now();
+                                let result = bob.receive(app, send, MTU, send_to, &(), packet, &mut output);
+                                latencies.push(start.elapsed());
+                                if let Err(e) = result {
+                                    stop.store(true, Ordering::Relaxed);
+                                    panic!("{}", e);
+                                }
+                            }
+                            latencies
+                        })
+                    })
+                    .collect();
+                let latencies = receivers.into_iter().flat_map(|r| r.join().unwrap()).collect();
+                stop.store(true, Ordering::Relaxed);
+                latencies
+            });
+            let p99 = latencies.len() * 99 / 100;
+            let (_, p99, _) = latencies.select_nth_unstable(p99);
+            *p99 * iters as u32
+        })
+    });
+    group.finish();
+}
+
+/// Measures Bob receiving one hello split at different MTUs, so the cost of copying the
+/// fragments into contiguous memory before parsing can be compared against the unfragmented case.
+fn bench_hello(c: &mut Criterion) {
+    let app = App { kyber: true };
+    let mut group = c.benchmark_group("receive_hello");
+    for (name, mtu) in [("mtu_1500", MTU), ("mtu_128", zssp::proto::MIN_TRANSPORT_MTU)] {
+        let pair = Pair::new();
+        pair.alice
+            .open(app, sender(&pair.to_bob), mtu, pair.bob_public, (), &[])
+            .unwrap();
+        let hello: Vec<Vec<u8>> = pair.to_bob.borrow_mut().drain(..).collect();
+        let mut output = Vec::new();
+        group.bench_function(format!("{}_{}_fragments", name, hello.len()), |b| {
+            b.iter_batched(
+                || hello.clone(),
+                |fragments| {
+                    for fragment in fragments {
+                        let send_to = |_: &Arc<Session<C>>| None::<(fn(&mut [u8]) -> bool, usize)>;
+                        pair.bob
+                            .receive(app, sender(&pair.to_alice), MTU, send_to, &(), fragment, &mut output)
+                            .unwrap();
+                    }
+                    // Every replay of the hello is answered, so each iteration did the full work.
+                    assert!(pair.to_alice.borrow_mut().drain(..).count() > 0);
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Compares copying the fragments of a hello into contiguous memory, which `receive` does for
+/// every control packet that arrives in more than one fragment, with processing the same hello
+/// once it is contiguous. This is the most that parsing fragments in place could save.
+#[cfg(feature = "fuzzing")]
+fn bench_reassembly(c: &mut Criterion) {
+    use arrayvec::ArrayVec;
+    use zssp::proto::v1::{HANDSHAKE_HELLO_CHALLENGE_SIZE, HANDSHAKE_HELLO_SIZE};
+    use zssp::proto::{HEADER_SIZE, MIN_TRANSPORT_MTU};
+
+    let mut app = App { kyber: true };
+    let pair = Pair::new();
+    pair.alice
+        .open(app, sender(&pair.to_bob), MIN_TRANSPORT_MTU, pair.bob_public, (), &[])
+        .unwrap();
+    let fragments: Vec<Vec<u8>> = pair.to_bob.borrow_mut().drain(..).collect();
+    let mut group = c.benchmark_group("hello_reassembly");
+    group.bench_function(format!("concat_{}_fragments", fragments.len()), |b| {
+        b.iter(|| {
+            let mut buffer = ArrayVec::<u8, HANDSHAKE_HELLO_CHALLENGE_SIZE>::new();
+            zssp::fuzzing::concat_payloads(&fragments, &mut buffer).unwrap().len()
+        })
+    });
+    // The header of a hello is not encrypted, and ends with its big endian counter.
+    let counter = u64::from_be_bytes(fragments[0][HEADER_SIZE - 8..HEADER_SIZE].try_into().unwrap());
+    let mut hello = ArrayVec::<u8, HANDSHAKE_HELLO_CHALLENGE_SIZE>::new();
+    let x1 = zssp::fuzzing::concat_payloads(&fragments, &mut hello).unwrap()[..HANDSHAKE_HELLO_SIZE].to_vec();
+    group.bench_function("process_unfragmented", |b| {
+        b.iter_batched(
+            || x1.clone(),
+            |mut x1| zssp::fuzzing::received_x1(&mut app, &pair.bob, &(), counter, &mut x1).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_garbage(c: &mut Criterion) {
+    let app = App { kyber: false };
+    let pair = Pair::new();
+    let mut garbage = vec![0u8; 1400];
+    BenchRng(1).fill_bytes(&mut garbage);
+    // A key id of zero makes the packet unassociated, as if it were a hello.
+    garbage[..4].fill(0);
+    let mut output = Vec::new();
+    c.bench_function("receive_unassociated_garbage", |b| {
+        b.iter_batched(
+            || garbage.clone(),
+            |packet| {
+                let send_to = |_: &Arc<Session<C>>| None::<(fn(&mut [u8]) -> bool, usize)>;
+                let result = pair
+                    .bob
+                    .receive(app, |_: &mut [u8]| true, MTU, send_to, &(), packet, &mut output);
+                assert!(result.is_err());
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+#[cfg(not(feature = "fuzzing"))]
+criterion_group!(
+    benches,
+    bench_handshake,
+    bench_data,
+    bench_receive_under_churn,
+    bench_hello,
+    bench_garbage
+);
+#[cfg(feature = "fuzzing")]
+criterion_group!(
+    benches,
+    bench_handshake,
+    bench_data,
+    bench_receive_under_churn,
+    bench_hello,
+    bench_reassembly,
+    bench_garbage
+);
+criterion_main!(benches);
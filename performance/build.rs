@@ -0,0 +1,16 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // The C loopback test in `tests/ffi` is compiled here, because Cargo cannot build C sources
+    // for a single test target. It is only linked into `tests/ffi_loopback.rs`.
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=include/zssp.h");
+        println!("cargo:rerun-if-changed=tests/ffi/loopback.c");
+        cc::Build::new()
+            .file("tests/ffi/loopback.c")
+            .include("include")
+            .cargo_metadata(false)
+            .compile("zssp_loopback");
+        println!("cargo:rustc-link-search=native={}", std::env::var("OUT_DIR").unwrap());
+    }
+}
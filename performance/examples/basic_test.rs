@@ -1,5 +1,3 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
 use std::iter::ExactSizeIterator;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -17,6 +15,7 @@ use zssp::application::{
 };
 use zssp::crypto::P384KeyPair;
 use zssp::crypto_impl::*;
+use zssp::ratchet_storage::FileRatchetStore;
 use zssp::result::ReceiveError;
 
 const TEST_MTU: usize = 1500;
@@ -24,18 +23,21 @@ const TEST_MTU: usize = 1500;
 struct TestApplication {
     time: Instant,
     name: &'static str,
-    ratchets: Mutex<Ratchets>,
+    ratchets: Mutex<FileRatchetStore<[u8; 16]>>,
 }
 
 type Session = zssp::Session<TestApplication>;
 
-struct Ratchets {
-    rf_map: HashMap<[u8; RATCHET_SIZE], RatchetState>,
-    peer_map: HashMap<u128, RatchetStates>,
-}
-impl Ratchets {
-    fn new() -> Self {
-        Self { rf_map: HashMap::new(), peer_map: HashMap::new() }
+impl TestApplication {
+    fn new(name: &'static str) -> Self {
+        // New static keys are generated on every run, so old ratchet states would only get in the way.
+        let path = std::env::temp_dir().join(format!("zssp_basic_test_{}.ratchets", name));
+        let _ = std::fs::remove_file(&path);
+        Self {
+            time: Instant::now(),
+            name,
+            ratchets: Mutex::new(FileRatchetStore::open(path).unwrap()),
+        }
     }
 }
 
@@ -49,6 +51,7 @@ impl CryptoLayer for TestApplication {
         rekey_after_key_uses: Settings::REKEY_AFTER_KEY_USES,
         resend_time: 250,
         fragment_assembly_timeout: Settings::FRAGMENT_ASSEMBLY_TIMEOUT_MS,
+        ..Settings::new_ms()
     };
 
     type Rng = OsRng;
@@ -70,7 +73,7 @@ impl CryptoLayer for TestApplication {
 #[allow(unused)]
 impl ApplicationLayer<TestApplication> for &TestApplication {
     fn incoming_session(&mut self) -> IncomingSessionAction {
-        IncomingSessionAction::Challenge
+        IncomingSessionAction::Challenge(0)
     }
 
     fn hello_requires_recognized_ratchet(&mut self) -> bool {
@@ -99,7 +102,9 @@ impl ApplicationLayer<TestApplication> for &TestApplication {
         ratchet_fingerprint: &[u8; RATCHET_SIZE],
     ) -> Result<Option<(RatchetState, ())>, std::io::Error> {
         let ratchets = self.ratchets.lock();
-        Ok(ratchets.rf_map.get(ratchet_fingerprint).cloned().map(|r| (r, ())))
+        Ok(ratchets
+            .restore_by_fingerprint(ratchet_fingerprint)
+            .map(|(r, _)| (r, ())))
     }
 
     fn restore_by_identity(
@@ -109,7 +114,7 @@ impl ApplicationLayer<TestApplication> for &TestApplication {
         _: Option<&()>,
     ) -> Result<Option<RatchetStates>, std::io::Error> {
         let ratchets = self.ratchets.lock();
-        Ok(ratchets.peer_map.get(session_data).cloned())
+        Ok(ratchets.restore_by_identity(&session_data.to_le_bytes()))
     }
 
     fn save_ratchet_state(
@@ -118,35 +123,17 @@ impl ApplicationLayer<TestApplication> for &TestApplication {
         session_data: &u128,
         update_data: CompareAndSwap<'_>,
     ) -> Result<bool, std::io::Error> {
-        let mut ratchets = self.ratchets.lock();
-        match ratchets.peer_map.entry(*session_data) {
-            Entry::Occupied(mut entry) => {
-                if update_data.compare(&entry.get()) {
-                    entry.insert(update_data.to_new_states());
-                } else {
-                    return Ok(false);
-                }
-            }
-            Entry::Vacant(entry) => {
-                if update_data.cur_is_initial_states() {
-                    entry.insert(update_data.to_new_states());
-                } else {
-                    return Ok(false);
-                }
-            }
-        }
-
-        if let Some(rf) = update_data.added_fingerprint() {
-            ratchets.rf_map.insert(*rf, update_data.new_state1.clone());
-            println!("[{}] new ratchet #{}", self.name, update_data.new_state1.chain_len());
-        }
-        if let Some(rf) = update_data.deleted_fingerprint1() {
-            ratchets.rf_map.remove(rf);
+        let new_chain_len = update_data
+            .added_fingerprint()
+            .map(|_| update_data.new_state1.chain_len());
+        let saved = self
+            .ratchets
+            .lock()
+            .save_ratchet_state(&session_data.to_le_bytes(), update_data)?;
+        if let (true, Some(chain_len)) = (saved, new_chain_len) {
+            println!("[{}] new ratchet #{}", self.name, chain_len);
         }
-        if let Some(rf) = update_data.deleted_fingerprint2() {
-            ratchets.rf_map.remove(rf);
-        }
-        Ok(true)
+        Ok(saved)
     }
 
     fn time(&mut self) -> i64 {
@@ -207,10 +194,10 @@ fn alice_main(
                         pkt,
                         &mut output_data,
                     ) {
+                        Ok((Established(_), _)) => {
+                            up = true;
+                        }
                         Ok((Associated(_, event), _)) => match event {
-                            Established => {
-                                up = true;
-                            }
                             Data => {
                                 assert!(!output_data.is_empty());
                                 //println!("[alice] received {}", data.len());
@@ -239,6 +226,7 @@ fn alice_main(
                 .send(
                     alice_session.as_ref().unwrap(),
                     |b: &mut [u8]| alice_out.send(b.to_vec()).is_ok(),
+                    TEST_MTU,
                     &mut [0u8; TEST_MTU],
                     &test_data[..1400 + ((OsRng.next_u64() as usize) % (test_data.len() - 1400))],
                 )
@@ -258,6 +246,9 @@ fn alice_main(
                 });
         }
     }
+    assert!(read_metric(&context, "zssp_handshakes_completed_total") > 0);
+    assert!(read_metric(&context, "zssp_data_packets_sent_total") > 0);
+    assert!(read_metric(&context, "zssp_data_bytes_received_total") > 0);
 }
 
 #[allow(unused)]
@@ -309,6 +300,7 @@ fn bob_main(
                                 .send(
                                     &s,
                                     |b: &mut [u8]| bob_out.send(b.to_vec()).is_ok(),
+                                    TEST_MTU,
                                     &mut [0u8; TEST_MTU],
                                     &output_data,
                                 )
@@ -345,24 +337,28 @@ fn bob_main(
                 });
         }
     }
+    assert!(read_metric(&context, "zssp_handshakes_completed_total") > 0);
+    assert!(read_metric(&context, "zssp_data_packets_received_total") > 0);
+    assert!(read_metric(&context, "zssp_data_bytes_sent_total") > 0);
+}
+
+/// Reads a single sample out of the Prometheus text rendered by `Context::render_metrics`.
+fn read_metric(context: &zssp::Context<TestApplication>, name: &str) -> u64 {
+    let mut out = Vec::new();
+    context.render_metrics(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let line = out.lines().find(|line| line.split(' ').next() == Some(name)).unwrap();
+    line[name.len() + 1..].parse().unwrap()
 }
 
 fn core(time: u64, packet_success_rate: u32) {
     let run = &AtomicBool::new(true);
 
     let alice_keypair = CrateP384KeyPair::generate(&mut OsRng);
-    let alice_app = TestApplication {
-        time: Instant::now(),
-        name: "alice",
-        ratchets: Mutex::new(Ratchets::new()),
-    };
+    let alice_app = TestApplication::new("alice");
     let bob_keypair = CrateP384KeyPair::generate(&mut OsRng);
     let bob_pubkey = bob_keypair.public_key();
-    let bob_app = TestApplication {
-        time: Instant::now(),
-        name: "bob",
-        ratchets: Mutex::new(Ratchets::new()),
-    };
+    let bob_app = TestApplication::new("bob");
 
     let (alice_out, bob_in) = mpsc::sync_channel::<Vec<u8>>(256);
     let (bob_out, alice_in) = mpsc::sync_channel::<Vec<u8>>(256);
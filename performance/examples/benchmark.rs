@@ -159,10 +159,10 @@ fn alice_main(
                     pkt,
                     &mut output_data,
                 ) {
+                    Ok((Established(_), _)) => {
+                        up = true;
+                    }
                     Ok((Associated(_, event), _)) => match event {
-                        Established => {
-                            up = true;
-                        }
                         Data => {
                             assert!(!output_data.is_empty());
                             //println!("[alice] received {}", data.len());
@@ -188,6 +188,7 @@ fn alice_main(
                 .send(
                     alice_session.as_ref().unwrap(),
                     |b: &mut [u8]| alice_out.send(alloc(b)).is_ok(),
+                    TEST_MTU,
                     &mut [0u8; TEST_MTU],
                     &test_data[..1400 + ((OsRng.next_u64() as usize) % (test_data.len() - 1400))],
                 )
@@ -252,6 +253,7 @@ fn bob_main(
                             .send(
                                 &s,
                                 |b: &mut [u8]| bob_out.send(alloc(b)).is_ok(),
+                                TEST_MTU,
                                 &mut [0u8; TEST_MTU],
                                 &output_data,
                             )
@@ -0,0 +1,57 @@
+//! Generates the handshake test vectors published in `tests/vectors/handshake.json`:
+//!
+//! ```text
+//! cargo run --example test_vectors --features test-vectors > tests/vectors/handshake.json
+//! ```
+//!
+//! The inputs are fixed, so running this again only changes the vectors if the output of the
+//! handshake changed. `tests/test_vectors.rs` fails until they are regenerated, which should
+//! only ever happen together with an intentional change to the protocol.
+use rand_core::RngCore;
+
+use zssp::test_vectors::*;
+
+/// Fixed filler bytes, so that the inputs do not have to be written out by hand.
+fn bytes(seed: u64, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    VectorRng::seeded(seed).fill_bytes(&mut bytes);
+    bytes
+}
+
+fn main() {
+    let vectors = [
+        ("First contact without Kyber", false, false),
+        ("First contact with Kyber", true, false),
+        ("Known ratchet key without Kyber", false, true),
+        ("Known ratchet key with Kyber", true, true),
+    ];
+    let vectors: Vec<TestVector> = vectors
+        .into_iter()
+        .map(|(description, kyber, ratchet)| {
+            let optional = |present: bool, seed: u64, len: usize| {
+                if present {
+                    bytes(seed, len)
+                } else {
+                    Vec::new()
+                }
+            };
+            let inputs = VectorInputs {
+                description: description.into(),
+                alice_static: bytes(1, 48),
+                bob_static: bytes(2, 48),
+                alice_ephemeral: bytes(3, 48),
+                bob_ephemeral: bytes(4, 48),
+                alice_kyber_randomness: optional(kyber, 5, 64),
+                bob_kyber_randomness: optional(kyber, 6, 32),
+                ratchet_key: optional(ratchet, 7, 32),
+                ratchet_fingerprint: optional(ratchet, 8, 32),
+                ratchet_chain_len: ratchet as u64,
+                identity: b"alice".to_vec(),
+                context_seed: 9,
+            };
+            let outputs = run_handshake(&inputs);
+            TestVector { inputs, outputs }
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&vectors).unwrap());
+}
@@ -0,0 +1,152 @@
+//! A handshake and a round of data between two contexts over an in-memory channel, using only
+//! pure Rust cryptography so that it also builds for `wasm32-unknown-unknown`:
+//!
+//! ```text
+//! cargo build --example wasm_handshake --target wasm32-unknown-unknown \
+//!     --no-default-features --features std,p384,sha2,pqc_kyber,aes
+//! ```
+//!
+//! Nothing here reads a clock or spawns a thread. The time is a counter that the event loop
+//! advances, where a browser would use `Date.now()` or `performance.now()` instead.
+//! Randomness comes from `OsRng`, which on `wasm32-unknown-unknown` requires getrandom's `js`
+//! feature to be enabled by the final binary.
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use rand_core::OsRng;
+
+use zssp::application::{
+    AcceptAction, ApplicationLayer, CompareAndSwap, CryptoLayer, IncomingSessionAction, RatchetState, RatchetStates,
+    RATCHET_SIZE,
+};
+use zssp::crypto::{P384KeyPair, P384PublicKey};
+use zssp::crypto_impl::*;
+use zssp::result::{ReceiveOk, SessionEvent};
+
+const MTU: usize = 1280;
+
+struct WasmCrypto;
+impl CryptoLayer for WasmCrypto {
+    type Rng = OsRng;
+    type PrpEnc = CrateAes256Enc;
+    type PrpDec = CrateAes256Dec;
+    type Aead = CrateAesGcm;
+    type AeadPool = CrateAesGcmPool;
+    type Hash = CrateSha512;
+    type Hmac = CrateHmacSha512;
+    type PublicKey = CrateP384PublicKey;
+    type KeyPair = CrateP384KeyPair;
+    type Kem = CrateKyber1024PrivateKey;
+
+    type SessionData = ();
+    type FingerprintData = ();
+    type IncomingPacketBuffer = Vec<u8>;
+}
+type Session = zssp::Session<WasmCrypto>;
+
+/// Ratchet states are kept in memory only, since browsers have no file system to store them in.
+struct App<'a> {
+    time: &'a Cell<i64>,
+}
+impl ApplicationLayer<WasmCrypto> for App<'_> {
+    fn time(&mut self) -> i64 {
+        self.time.get()
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        IncomingSessionAction::Allow
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session>) -> bool {
+        false
+    }
+    fn check_accept_session(&mut self, _: &CrateP384PublicKey, _: &[u8], _: Option<&()>) -> AcceptAction<WasmCrypto> {
+        AcceptAction {
+            session_data: Some(()),
+            responder_disallows_downgrade: false,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(&mut self, _: &[u8; RATCHET_SIZE]) -> std::io::Result<Option<(RatchetState, ())>> {
+        Ok(None)
+    }
+    fn restore_by_identity(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        Ok(None)
+    }
+    fn save_ratchet_state(&mut self, _: &CrateP384PublicKey, _: &(), _: CompareAndSwap<'_>) -> std::io::Result<bool> {
+        Ok(true)
+    }
+}
+
+type Link = RefCell<VecDeque<Vec<u8>>>;
+fn sender(link: &Link) -> impl FnMut(&mut [u8]) -> bool + Copy + '_ {
+    move |packet: &mut [u8]| {
+        link.borrow_mut().push_back(packet.to_vec());
+        true
+    }
+}
+
+fn main() {
+    let time = Cell::new(0);
+    let (to_alice, to_bob) = (Link::default(), Link::default());
+    let bob_secret = CrateP384KeyPair::generate(&mut OsRng);
+    let bob_public = <CrateP384KeyPair as P384KeyPair<OsRng>>::public_key_bytes(&bob_secret);
+    let bob_public = CrateP384PublicKey::from_bytes(&bob_public).unwrap();
+    let alice = zssp::Context::<WasmCrypto>::new(CrateP384KeyPair::generate(&mut OsRng), OsRng);
+    let bob = zssp::Context::<WasmCrypto>::new(bob_secret, OsRng);
+
+    let (alice_session, _) = alice
+        .open(App { time: &time }, sender(&to_bob), MTU, bob_public, (), &[])
+        .unwrap();
+    // Contexts do not keep sessions alive, that is up to the application.
+    let mut bob_session = None;
+    let mut received = Vec::new();
+    let mut sent = false;
+    while received.is_empty() {
+        time.set(time.get() + 10);
+        if alice_session.established() && !sent {
+            alice
+                .send(
+                    &alice_session,
+                    sender(&to_bob),
+                    MTU,
+                    &mut [0u8; MTU],
+                    b"hello from wasm",
+                )
+                .unwrap();
+            sent = true;
+        }
+        let mut idle = true;
+        for (ctx, inbox, outbox) in [(&alice, &to_alice, &to_bob), (&bob, &to_bob, &to_alice)] {
+            let send = sender(outbox);
+            while let Some(packet) = inbox.borrow_mut().pop_front() {
+                idle = false;
+                let result = ctx.receive(
+                    App { time: &time },
+                    send,
+                    MTU,
+                    |_: &Arc<Session>| Some((send, MTU)),
+                    &(),
+                    packet,
+                    &mut received,
+                );
+                if let Ok((ReceiveOk::Associated(session, SessionEvent::NewSession), _)) = result {
+                    println!("[bob] new session");
+                    bob_session = Some(session);
+                }
+            }
+            ctx.service(App { time: &time }, |_: &Arc<Session>| Some((send, MTU)));
+        }
+        assert!(!idle, "the handshake stalled");
+    }
+    assert!(bob_session.is_some_and(|s| s.established()));
+    assert_eq!(received, b"hello from wasm");
+    println!("[bob] received {:?}", String::from_utf8_lossy(&received));
+}
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zssp_fuzz::*;
+
+// Every input selects one packet of a deterministic handshake by its index in delivery order,
+// then alters it with a list of `(offset, xor)` edits and an optional new length. Since the
+// packets before it are always the same, the fuzzer explores mutations of valid hellos, replies
+// and key confirmations, including the fragments of each, without having to produce them itself.
+fuzz_target!(|input: (u8, Vec<(u16, u8)>, Option<u16>)| {
+    let (index, edits, len) = input;
+    Handshake::new().run(|_, i, _, packet| {
+        if i != index as usize || packet.is_empty() {
+            return;
+        }
+        for &(offset, xor) in &edits {
+            let offset = offset as usize % packet.len();
+            packet[offset] ^= xor;
+        }
+        if let Some(len) = len {
+            packet.resize(len as usize % (2 * FUZZ_MTU), 0);
+        }
+    });
+});
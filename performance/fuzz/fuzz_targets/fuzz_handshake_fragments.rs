@@ -0,0 +1,30 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zssp::proto::HEADER_SIZE;
+use zssp_fuzz::*;
+
+// Every input is a sequence of `(index, flags, fragment)`, where each fragment is delivered just
+// before the packet of a deterministic handshake with the same index in delivery order. Bit 0 of
+// the flags sends the fragment to Alice instead of the side that packet is for, and bit 1 copies
+// the header of that packet over the start of the fragment, so that it reaches the defragmentation
+// buffers and handshake states of the sessions under way.
+fuzz_target!(|fragments: Vec<(u8, u8, Vec<u8>)>| {
+    Handshake::new().run(|handshake, i, to, packet| {
+        for (index, flags, fragment) in &fragments {
+            if *index as usize != i {
+                continue;
+            }
+            let mut fragment = fragment.clone();
+            if flags & 2 != 0 {
+                let len = fragment.len().min(packet.len()).min(HEADER_SIZE);
+                fragment[..len].copy_from_slice(&packet[..len]);
+            }
+            let to = if flags & 1 != 0 {
+                Side::Alice
+            } else {
+                to
+            };
+            handshake.deliver(to, fragment);
+        }
+    });
+});
@@ -0,0 +1,20 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zssp::fuzzing::Defragmenter;
+
+// Every input is a sequence of `(counter, fragment_no, fragment_count, fragment)`.
+// Each fragment is tagged with its own position so that the order of an assembled packet can be
+// checked.
+fuzz_target!(|fragments: Vec<(u8, u8, u8, u8)>| {
+    let mut defrag = Defragmenter::new();
+    for (counter, fragment_no, fragment_count, payload) in fragments {
+        let fragment = vec![fragment_no, payload];
+        let assembled = defrag.assemble(counter as u64, fragment, fragment_no as usize, fragment_count as usize);
+        if !assembled.is_empty() {
+            assert_eq!(assembled.len(), fragment_count as usize);
+            for (i, fragment) in assembled.iter().enumerate() {
+                assert_eq!(fragment[0] as usize, i);
+            }
+        }
+    }
+});
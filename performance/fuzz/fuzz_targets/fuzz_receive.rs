@@ -0,0 +1,30 @@
+#![no_main]
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use zssp::Session;
+use zssp_fuzz::*;
+
+// Every input is a sequence of fragments received from the same address by a fresh context.
+fuzz_target!(|fragments: Vec<Vec<u8>>| {
+    let ctx = new_context();
+    let mut sessions = Vec::new();
+    let mut output = Vec::new();
+    for fragment in fragments {
+        output.clear();
+        let result = ctx.receive(
+            FuzzApp,
+            |_: &mut [u8]| false,
+            FUZZ_MTU,
+            |_: &Arc<Session<FuzzCrypto>>| Some((|_: &mut [u8]| false, FUZZ_MTU)),
+            &0,
+            fragment,
+            &mut output,
+        );
+        // Sessions are only weakly held by the context, so they are kept alive for any
+        // later fragments of the input to reach.
+        if let Ok((zssp::result::ReceiveOk::Associated(session, _), _)) = result {
+            sessions.push(session);
+        }
+    }
+});
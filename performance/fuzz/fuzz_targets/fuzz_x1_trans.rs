@@ -0,0 +1,17 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zssp::proto::HANDSHAKE_HELLO_SIZE;
+use zssp_fuzz::*;
+
+// The first 8 bytes of an input are the counter of the hello, the rest is its body. The body
+// is resized to that of a valid hello so that the fuzzer spends its time past the length check.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+    let counter = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let mut x1 = data[8..].to_vec();
+    x1.resize(HANDSHAKE_HELLO_SIZE, 0);
+    let ctx = new_context();
+    let _ = zssp::fuzzing::received_x1(&mut FuzzApp, &ctx, &0, counter, &mut x1);
+});
@@ -0,0 +1,239 @@
+//! Shared setup for the ZSSP fuzz targets.
+//! Run a target with `cargo fuzz run <target>` from the `performance` directory.
+//!
+//! Everything here is deterministic, so a crashing input reproduces on every run.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use rand_core::{CryptoRng, RngCore};
+
+use zssp::application::*;
+use zssp::crypto::{P384KeyPair, P384PublicKey};
+use zssp::crypto_impl::*;
+use zssp::result::{ReceiveOk, SessionEvent};
+use zssp::{Context, Session};
+
+/// The largest MTU a fuzz target sends with.
+pub const FUZZ_MTU: usize = 1500;
+
+const DEFAULT_SEED: u64 = 0x2545f4914f6cdd1d;
+
+/// A xorshift generator with a fixed seed.
+/// It is not remotely cryptographically secure, it only exists to keep fuzzing reproducible.
+pub struct FuzzRng(u64);
+impl FuzzRng {
+    pub fn new() -> Self {
+        Self::with_seed(DEFAULT_SEED)
+    }
+    /// A generator with a different fixed seed, which must not be 0.
+    pub fn with_seed(seed: u64) -> Self {
+        Self(seed)
+    }
+}
+impl Default for FuzzRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl RngCore for FuzzRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+impl CryptoRng for FuzzRng {}
+
+pub struct FuzzCrypto;
+impl CryptoLayer for FuzzCrypto {
+    type Rng = FuzzRng;
+    type PrpEnc = OpenSSLAes256Enc;
+    type PrpDec = OpenSSLAes256Dec;
+    type Aead = OpenSSLAesGcm;
+    type AeadPool = OpenSSLAesGcmPool;
+    type Hash = CrateSha512;
+    type Hmac = CrateHmacSha512;
+    type PublicKey = CrateP384PublicKey;
+    type KeyPair = CrateP384KeyPair;
+    type Kem = CrateKyber1024PrivateKey;
+
+    type SessionData = ();
+    type FingerprintData = ();
+    type IncomingPacketBuffer = Vec<u8>;
+}
+
+/// An application that accepts every session without a challenge, so that hellos reach the
+/// handshake transitions, and that has no ratchet states stored.
+pub struct FuzzApp;
+impl ApplicationLayer<FuzzCrypto> for FuzzApp {
+    fn time(&mut self) -> i64 {
+        0
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        IncomingSessionAction::Allow
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session<FuzzCrypto>>) -> bool {
+        false
+    }
+    fn check_accept_session(&mut self, _: &CrateP384PublicKey, _: &[u8], _: Option<&()>) -> AcceptAction<FuzzCrypto> {
+        AcceptAction {
+            session_data: Some(()),
+            responder_disallows_downgrade: false,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(&mut self, _: &[u8; RATCHET_SIZE]) -> std::io::Result<Option<(RatchetState, ())>> {
+        Ok(None)
+    }
+    fn restore_by_identity(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        Ok(None)
+    }
+    fn save_ratchet_state(&mut self, _: &CrateP384PublicKey, _: &(), _: CompareAndSwap<'_>) -> std::io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Create a context whose static key is the same on every call.
+pub fn new_context() -> Context<FuzzCrypto> {
+    new_context_with_seed(DEFAULT_SEED)
+}
+/// Create a context whose static key is derived from `seed`, see `FuzzRng::with_seed`.
+pub fn new_context_with_seed(seed: u64) -> Context<FuzzCrypto> {
+    let mut rng = FuzzRng::with_seed(seed);
+    let s_secret = <CrateP384KeyPair as P384KeyPair<FuzzRng>>::generate(&mut rng);
+    Context::new(s_secret, rng)
+}
+/// The static public key of the context returned by `new_context_with_seed`.
+pub fn public_key_with_seed(seed: u64) -> CrateP384PublicKey {
+    let s_secret = <CrateP384KeyPair as P384KeyPair<FuzzRng>>::generate(&mut FuzzRng::with_seed(seed));
+    let public_key = <CrateP384KeyPair as P384KeyPair<FuzzRng>>::public_key_bytes(&s_secret);
+    CrateP384PublicKey::from_bytes(&public_key).unwrap()
+}
+
+/// The side of a handshake a packet is addressed to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Alice,
+    Bob,
+}
+
+/// The size of the payload sent each way once the handshake is complete. It is larger than
+/// `FUZZ_MTU` so that it is fragmented.
+pub const FUZZ_DATA_SIZE: usize = 2 * FUZZ_MTU;
+/// The most packets a handshake delivers, in case a mutated packet makes the two sides answer
+/// each other forever.
+const MAX_DELIVERIES: usize = 256;
+
+/// A handshake between two fresh contexts, followed by a fragmented payload in each direction.
+///
+/// The clock never moves and both contexts use fixed seeds, so every run produces the exact same
+/// packets, in the same order, until a packet is altered.
+pub struct Handshake {
+    pub alice: Context<FuzzCrypto>,
+    pub bob: Context<FuzzCrypto>,
+    alice_session: Arc<Session<FuzzCrypto>>,
+    // Sessions are only weakly held by the context, so the ones Bob accepts are kept alive here.
+    bob_sessions: RefCell<Vec<Arc<Session<FuzzCrypto>>>>,
+    to_alice: RefCell<VecDeque<Vec<u8>>>,
+    to_bob: RefCell<VecDeque<Vec<u8>>>,
+}
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Handshake {
+    /// Create both contexts, and have Alice open a session with Bob.
+    /// Nothing is delivered until `run` is called.
+    pub fn new() -> Self {
+        let alice = new_context_with_seed(1);
+        let bob = new_context();
+        let to_bob = RefCell::new(VecDeque::new());
+        let send = |packet: &mut [u8]| Self::push(&to_bob, packet);
+        let bob_key = public_key_with_seed(DEFAULT_SEED);
+        let (alice_session, _) = alice.open(FuzzApp, send, FUZZ_MTU, bob_key, (), &[]).unwrap();
+        Self {
+            alice,
+            bob,
+            alice_session,
+            bob_sessions: RefCell::new(Vec::new()),
+            to_alice: RefCell::new(VecDeque::new()),
+            to_bob,
+        }
+    }
+    fn push(queue: &RefCell<VecDeque<Vec<u8>>>, packet: &[u8]) -> bool {
+        queue.borrow_mut().push_back(packet.to_vec());
+        true
+    }
+    /// Deliver a packet to one side, queueing any replies for the other side.
+    pub fn deliver(&self, to: Side, packet: Vec<u8>) {
+        let (ctx, outbox, remote_address) = match to {
+            Side::Alice => (&self.alice, &self.to_bob, 2),
+            Side::Bob => (&self.bob, &self.to_alice, 1),
+        };
+        let send = |packet: &mut [u8]| Self::push(outbox, packet);
+        let send_to = |_: &Arc<Session<FuzzCrypto>>| Some((send, FUZZ_MTU));
+        let mut output = Vec::new();
+        let result = ctx.receive(FuzzApp, send, FUZZ_MTU, send_to, &remote_address, packet, &mut output);
+        if let (Side::Bob, Ok((ReceiveOk::Associated(session, SessionEvent::NewSession), _))) = (to, result) {
+            self.bob_sessions.borrow_mut().push(session);
+        }
+    }
+    /// Queue `FUZZ_DATA_SIZE` bytes from each side that has an established session.
+    fn send_data(&self) {
+        let data = [0x5a; FUZZ_DATA_SIZE];
+        let mut work_buffer = [0u8; FUZZ_MTU];
+        let send = |packet: &mut [u8]| Self::push(&self.to_bob, packet);
+        let _ = self
+            .alice
+            .send(&self.alice_session, send, FUZZ_MTU, &mut work_buffer, &data);
+        if let Some(session) = self.bob_sessions.borrow().last() {
+            let send = |packet: &mut [u8]| Self::push(&self.to_alice, packet);
+            let _ = self.bob.send(session, send, FUZZ_MTU, &mut work_buffer, &data);
+        }
+    }
+    /// Deliver queued packets, Bob's first, until none are left, then send data both ways and
+    /// deliver it too.
+    ///
+    /// `hook` is called with the index of every packet in delivery order before it is delivered,
+    /// and may alter it or deliver packets of its own.
+    pub fn run(&self, mut hook: impl FnMut(&Self, usize, Side, &mut Vec<u8>)) {
+        let mut sent_data = false;
+        let mut delivered = 0;
+        while delivered < MAX_DELIVERIES {
+            let next = self.to_bob.borrow_mut().pop_front().map(|p| (Side::Bob, p));
+            let next = next.or_else(|| self.to_alice.borrow_mut().pop_front().map(|p| (Side::Alice, p)));
+            let Some((to, mut packet)) = next else {
+                if sent_data {
+                    return;
+                }
+                sent_data = true;
+                self.send_data();
+                continue;
+            };
+            hook(self, delivered, to, &mut packet);
+            self.deliver(to, packet);
+            delivered += 1;
+        }
+    }
+}
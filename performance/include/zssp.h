@@ -0,0 +1,105 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ * (c) ZeroTier, Inc.
+ * https://www.zerotier.com/
+ */
+/* C interface to ZSSP, built into the zssp crate with the `ffi` feature.
+ * The Rust documentation of the `ffi` module describes each function in more detail. */
+#ifndef ZSSP_H
+#define ZSSP_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ZSSP_PUBLIC_KEY_SIZE 49
+#define ZSSP_RATCHET_SIZE 32
+/* Size of the `RatchetState::to_bytes` encoding. */
+#define ZSSP_RATCHET_STATE_SIZE 75
+/* Size of the `RatchetStates::to_bytes` encoding. */
+#define ZSSP_RATCHET_STATES_SIZE 152
+#define ZSSP_MIN_TRANSPORT_MTU 128
+
+/* Status codes, mirroring `OpenError`, `SendError` and `ReceiveError`. */
+#define ZSSP_OK 0
+#define ZSSP_ERR_IDENTITY_TOO_LARGE -1
+#define ZSSP_ERR_MTU_TOO_SMALL -2
+#define ZSSP_ERR_INVALID_REMOTE_KEY -3
+#define ZSSP_ERR_STORAGE_READ -4
+#define ZSSP_ERR_SESSION_EXPIRED -5
+#define ZSSP_ERR_SESSION_NOT_ESTABLISHED -6
+#define ZSSP_ERR_DATA_TOO_LARGE -7
+#define ZSSP_ERR_KEY_EXCHANGE_IN_PROGRESS -8
+#define ZSSP_ERR_BYZANTINE_FAULT -9
+#define ZSSP_ERR_MAX_KEY_LIFETIME_EXCEEDED -10
+#define ZSSP_ERR_REJECTED -11
+#define ZSSP_ERR_STORAGE_WRITE -12
+#define ZSSP_ERR_PAYLOAD_TOO_LARGE -13
+#define ZSSP_ERR_INVALID_ARGUMENT -14
+#define ZSSP_ERR_VERSION_DOWNGRADE -15
+#define ZSSP_ERR_REKEY_URGENTLY_NEEDED -16
+#define ZSSP_ERR_KEY_IDS_EXHAUSTED -17
+
+/* Events reported by `zssp_receive`, mirroring `SessionEvent` and the other variants of `ReceiveOk`. */
+#define ZSSP_EVENT_NONE 0
+#define ZSSP_EVENT_NEW_SESSION 1
+#define ZSSP_EVENT_NEW_DOWNGRADED_SESSION 2
+#define ZSSP_EVENT_REJECTED 3
+#define ZSSP_EVENT_REKEY_REJECTED 4
+#define ZSSP_EVENT_DATA 5
+#define ZSSP_EVENT_CONTROL 6
+#define ZSSP_EVENT_DOWNGRADED_RATCHET_KEY 7
+#define ZSSP_EVENT_ESTABLISHED 8
+#define ZSSP_EVENT_VERSION_UNSUPPORTED 9
+
+typedef struct zssp_context_t zssp_context_t;
+/* A reference counted session handle. Every handle returned by ZSSP must be released. */
+typedef struct zssp_session_t zssp_session_t;
+
+/* `time`, `send_to_session` and `send_to_address` are required, the other callbacks may be NULL.
+ * If `check_accept_session` is NULL every incoming session is rejected. If the storage callbacks
+ * are NULL ratchet states are not persisted. Storage callbacks return 1 on success, 0 if nothing
+ * was found or the compare and swap failed, and a negative number on error. */
+typedef struct zssp_callbacks_t {
+    void *user;
+    size_t mtu;
+    int64_t (*time)(void *user);
+    bool (*send_to_session)(void *user, uint64_t session_data, uint8_t *packet, size_t len);
+    bool (*send_to_address)(void *user, uint64_t remote_address, uint8_t *packet, size_t len);
+    bool (*check_accept_session)(void *user, const uint8_t *remote_key, const uint8_t *identity, size_t identity_len,
+                                 uint64_t *session_data);
+    int (*restore_by_identity)(void *user, const uint8_t *remote_key, uint64_t session_data, uint8_t *states_out);
+    int (*restore_by_fingerprint)(void *user, const uint8_t *fingerprint, uint8_t *state_out);
+    int (*save_ratchet_state)(void *user, const uint8_t *remote_key, uint64_t session_data, const uint8_t *cur_states,
+                              const uint8_t *new_states);
+} zssp_callbacks_t;
+
+zssp_context_t *zssp_context_new(const zssp_callbacks_t *callbacks);
+void zssp_context_free(zssp_context_t *ctx);
+void zssp_context_public_key(const zssp_context_t *ctx, uint8_t *out);
+
+int zssp_open(const zssp_context_t *ctx, const uint8_t *remote_key, uint64_t session_data, const uint8_t *identity,
+              size_t identity_len, zssp_session_t **session_out);
+int zssp_receive(const zssp_context_t *ctx, uint64_t remote_address, uint8_t *packet, size_t packet_len,
+                 uint8_t *payload, size_t payload_capacity, size_t *payload_len, int *event,
+                 zssp_session_t **session_out);
+int zssp_send(const zssp_context_t *ctx, const zssp_session_t *session, const uint8_t *data, size_t len);
+int64_t zssp_service(const zssp_context_t *ctx);
+
+void zssp_session_close(const zssp_session_t *session);
+bool zssp_session_established(const zssp_session_t *session);
+uint64_t zssp_session_data(const zssp_session_t *session);
+zssp_session_t *zssp_session_retain(const zssp_session_t *session);
+void zssp_session_release(zssp_session_t *session);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -1,24 +1,225 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
-pub struct Window<const L: usize, const MAX: u64>([AtomicU64; L]);
+use crate::sync::atomic::{AtomicU64, Ordering};
 
-impl<const L: usize, const MAX: u64> Window<L, MAX> {
-    pub fn new() -> Self {
-        Self(std::array::from_fn(|_| AtomicU64::new(0)))
+/// Replay protection for the counters of received packets.
+pub(crate) trait AntiReplayWindow {
+    /// Create a window that remembers at least `max_ooo` counters, and that rejects counters more
+    /// than `max_skip_ahead` steps ahead of the counters it has accepted.
+    fn new(max_ooo: usize, max_skip_ahead: u64) -> Self;
+    /// Check the window without mutating state.
+    fn check(&self, counter: u64) -> bool;
+    /// Check the window like `check`, but count the counter as rejected in `stats` if it is not
+    /// valid. This is what the receive path uses, since a rejected counter means a dropped packet.
+    fn check_and_count(&self, counter: u64) -> bool;
+    /// Update the window, returning true if the packet is still valid.
+    /// This should only be called after the packet is authenticated.
+    ///
+    /// If several threads concurrently update the window with the same counter, exactly one of
+    /// them must see the counter as valid.
+    fn update(&self, counter: u64) -> bool;
+    /// The internal state of this window, for `Context::export_session_state`.
+    fn load_slots(&self) -> Vec<u64>;
+    /// Overwrite the internal state of this window with values returned by `load_slots`.
+    /// Returns false and leaves the window unchanged if they are not compatible with this window.
+    fn store_slots(&self, counters: &[u64]) -> bool;
+    /// Get the statistics recorded so far by this window.
+    fn stats(&self) -> ReplayStats;
+}
+
+/// The replay protection used by sessions, selected by the `compact-window` feature.
+#[cfg(not(feature = "compact-window"))]
+pub(crate) type SessionWindow = Window;
+/// The replay protection used by sessions, selected by the `compact-window` feature.
+#[cfg(feature = "compact-window")]
+pub(crate) type SessionWindow = crate::antireplay_compact::CompactWindow;
+
+pub struct Window {
+    slots: Box<[AtomicU64]>,
+    max_skip_ahead: u64,
+    rejected: AtomicU64,
+    accepted: AtomicU64,
+    max_counter: AtomicU64,
+    max_reorder_distance: AtomicU64,
+}
+
+/// Statistics recorded by the replay protection of a session.
+///
+/// Like the totals of `Context::statistics`, the fields may not all reflect the same packets when
+/// read during concurrent receives.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// The number of received packets that were rejected because their counter was a replay, too
+    /// old, or too far ahead. This can include packets that failed authentication afterwards.
+    pub rejected: u64,
+    /// The number of authenticated packets whose counter was accepted.
+    pub accepted: u64,
+    /// The largest counter that has been accepted.
+    pub max_counter: u64,
+    /// The largest distance, in counters, by which an accepted packet arrived behind the largest
+    /// counter accepted before it.
+    pub max_reorder_distance: u64,
+}
+
+impl AntiReplayWindow for Window {
+    /// Create a window that remembers `max_ooo` counters, and that rejects counters more than
+    /// `max_skip_ahead` steps ahead of the counter previously stored in their slot.
+    /// A window always remembers at least one counter, so a `max_ooo` of 0 is treated as 1.
+    fn new(max_ooo: usize, max_skip_ahead: u64) -> Self {
+        Self {
+            slots: (0..max_ooo.max(1)).map(|_| AtomicU64::new(0)).collect(),
+            max_skip_ahead,
+            rejected: AtomicU64::new(0),
+            accepted: AtomicU64::new(0),
+            max_counter: AtomicU64::new(0),
+            max_reorder_distance: AtomicU64::new(0),
+        }
     }
     /// Check the window without mutating state.
-    pub fn check(&self, counter: u64) -> bool {
-        let slot = &self.0[(counter as usize) % self.0.len()];
+    fn check(&self, counter: u64) -> bool {
+        let slot = &self.slots[(counter % self.slots.len() as u64) as usize];
         let counter = counter.wrapping_add(1);
         let prev_counter = slot.load(Ordering::Relaxed);
-        prev_counter < counter && counter.wrapping_sub(prev_counter) <= MAX
+        prev_counter < counter && counter.wrapping_sub(prev_counter) <= self.max_skip_ahead
+    }
+    /// Check the window without updating its slots, counting the counter as rejected if it is not
+    /// valid.
+    fn check_and_count(&self, counter: u64) -> bool {
+        let is_valid = self.check(counter);
+        if !is_valid {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        is_valid
     }
     /// Update the window, returning true if the packet is still valid.
     /// This should only be called after the packet is authenticated.
-    pub fn update(&self, counter: u64) -> bool {
-        let slot = &self.0[(counter as usize) % self.0.len()];
-        let counter = counter.wrapping_add(1);
-        let prev_counter = slot.fetch_max(counter, Ordering::Relaxed);
-        prev_counter < counter && counter.wrapping_sub(prev_counter) <= MAX
+    ///
+    /// The slot is updated with a single atomic read-modify-write, so if several threads
+    /// concurrently update the window with the same counter, exactly one of them will see the
+    /// counter as valid. This is what prevents a duplicated packet from being delivered twice
+    /// when both copies have already passed `check` on different threads.
+    fn update(&self, counter: u64) -> bool {
+        let slot = &self.slots[(counter % self.slots.len() as u64) as usize];
+        let adj_counter = counter.wrapping_add(1);
+        let prev_counter = slot.fetch_max(adj_counter, Ordering::Relaxed);
+        let is_valid = prev_counter < adj_counter && adj_counter.wrapping_sub(prev_counter) <= self.max_skip_ahead;
+        if is_valid {
+            self.accepted.fetch_add(1, Ordering::Relaxed);
+            let max_counter = self.max_counter.fetch_max(counter, Ordering::Relaxed);
+            self.max_reorder_distance
+                .fetch_max(max_counter.saturating_sub(counter), Ordering::Relaxed);
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        is_valid
+    }
+    /// The counters currently stored in each slot of this window.
+    fn load_slots(&self) -> Vec<u64> {
+        self.slots.iter().map(|slot| slot.load(Ordering::Relaxed)).collect()
+    }
+    /// Overwrite the slots of this window with counters returned by `load_slots`.
+    /// Returns false and leaves the window unchanged if the number of slots does not match.
+    fn store_slots(&self, counters: &[u64]) -> bool {
+        if counters.len() != self.slots.len() {
+            return false;
+        }
+        for (slot, counter) in self.slots.iter().zip(counters) {
+            slot.store(*counter, Ordering::Relaxed);
+        }
+        true
     }
+    /// Get the statistics recorded so far by this window.
+    fn stats(&self) -> ReplayStats {
+        ReplayStats {
+            rejected: self.rejected.load(Ordering::Relaxed),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            max_counter: self.max_counter.load(Ordering::Relaxed),
+            max_reorder_distance: self.max_reorder_distance.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[test]
+fn test_window_reordering() {
+    const N: u64 = 16;
+    // A packet delayed by N - 1 other packets is still remembered.
+    let window = Window::new(N as usize, 1 << 24);
+    for c in 1..N {
+        assert!(window.check(c) && window.update(c));
+    }
+    assert!(window.check(0) && window.update(0));
+    assert!(!window.check(0) && !window.update(0));
+    // A packet delayed by N + 1 other packets has been forgotten and must be rejected.
+    let window = Window::new(N as usize, 1 << 24);
+    for c in 1..N + 2 {
+        assert!(window.check(c) && window.update(c));
+    }
+    assert!(!window.check(0) && !window.update(0));
+}
+
+#[test]
+fn test_window_skip_ahead() {
+    let window = Window::new(4, 100);
+    assert!(window.check(99) && window.update(99));
+    assert!(!window.check(104));
+    assert!(window.check(103) && window.update(103));
+}
+
+#[test]
+fn test_window_without_reordering() {
+    // A window configured to remember no counters still remembers the last one.
+    let window = Window::new(0, 100);
+    assert!(window.check(0) && window.update(0));
+    assert!(!window.check(0) && !window.update(0));
+    assert!(window.check(1) && window.update(1));
+    assert!(!window.check(0));
+}
+
+#[test]
+fn test_window_stats() {
+    let window = Window::new(16, 1 << 24);
+    assert!(window.check(5) && window.update(5));
+    assert!(window.check(2) && window.update(2));
+    assert_eq!(window.stats().rejected, 0);
+    // Replay a packet. Only the receive path counts the rejection, a plain check records nothing.
+    assert!(!window.check(5));
+    assert_eq!(window.stats().rejected, 0);
+    assert!(!window.check_and_count(5));
+    assert!(!window.update(5));
+    let stats = window.stats();
+    assert_eq!(stats.rejected, 2);
+    assert_eq!(stats.accepted, 2);
+    assert_eq!(stats.max_counter, 5);
+    assert_eq!(stats.max_reorder_distance, 3);
+}
+
+#[test]
+fn test_window_concurrent_update() {
+    use std::sync::{Arc, Barrier};
+    const THREADS: usize = 8;
+    const COUNTERS: u64 = 10000;
+    let window = Arc::new(Window::new(128, 1 << 24));
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let window = window.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                let mut accepted = 0;
+                for c in 0..COUNTERS {
+                    // Every thread checks before updating, exactly like the receive path does.
+                    if window.check(c) && window.update(c) {
+                        accepted += 1;
+                    }
+                }
+                accepted
+            })
+        })
+        .collect();
+    let accepted: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    // Each counter must be accepted exactly once no matter which thread got to it first.
+    assert_eq!(accepted, COUNTERS);
+    assert_eq!(window.stats().accepted, COUNTERS);
 }
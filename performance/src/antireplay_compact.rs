@@ -0,0 +1,130 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::antireplay::{AntiReplayWindow, ReplayStats};
+use crate::sync::Mutex;
+
+/// Replay protection that remembers only the 64 counters below the largest accepted counter,
+/// for targets where the memory used by `Window` is too much.
+///
+/// It is enabled with the `compact-window` feature, in which case
+/// `Settings::counter_window_max_out_of_order` is ignored. Counters that arrive more than 63
+/// counters behind the largest accepted counter are always rejected.
+pub struct CompactWindow {
+    max_skip_ahead: u64,
+    state: Mutex<CompactState>,
+}
+#[derive(Default)]
+struct CompactState {
+    /// One more than the largest accepted counter, or zero if no counter has been accepted.
+    base_counter: u64,
+    /// Bit `i` is set if counter `base_counter - 1 - i` has been accepted.
+    bitmap: u64,
+    rejected: u64,
+    accepted: u64,
+    max_reorder_distance: u64,
+}
+
+impl CompactState {
+    fn is_valid(&self, adj_counter: u64, max_skip_ahead: u64) -> bool {
+        if adj_counter > self.base_counter {
+            adj_counter - self.base_counter <= max_skip_ahead
+        } else {
+            let behind = self.base_counter - adj_counter;
+            behind < u64::BITS as u64 && self.bitmap & (1 << behind) == 0
+        }
+    }
+}
+
+impl AntiReplayWindow for CompactWindow {
+    fn new(_: usize, max_skip_ahead: u64) -> Self {
+        Self { max_skip_ahead, state: Mutex::new(CompactState::default()) }
+    }
+    fn check(&self, counter: u64) -> bool {
+        self.state.lock().is_valid(counter.wrapping_add(1), self.max_skip_ahead)
+    }
+    fn check_and_count(&self, counter: u64) -> bool {
+        let mut state = self.state.lock();
+        let is_valid = state.is_valid(counter.wrapping_add(1), self.max_skip_ahead);
+        if !is_valid {
+            state.rejected += 1;
+        }
+        is_valid
+    }
+    fn update(&self, counter: u64) -> bool {
+        let mut state = self.state.lock();
+        let adj_counter = counter.wrapping_add(1);
+        if !state.is_valid(adj_counter, self.max_skip_ahead) {
+            state.rejected += 1;
+            return false;
+        }
+        if adj_counter > state.base_counter {
+            let shift = adj_counter - state.base_counter;
+            let bitmap = if shift < u64::BITS as u64 {
+                state.bitmap << shift
+            } else {
+                0
+            };
+            state.bitmap = bitmap | 1;
+            state.base_counter = adj_counter;
+        } else {
+            let behind = state.base_counter - adj_counter;
+            state.bitmap |= 1 << behind;
+            state.max_reorder_distance = state.max_reorder_distance.max(behind);
+        }
+        state.accepted += 1;
+        true
+    }
+    fn load_slots(&self) -> Vec<u64> {
+        let state = self.state.lock();
+        vec![state.base_counter, state.bitmap]
+    }
+    fn store_slots(&self, counters: &[u64]) -> bool {
+        let &[base_counter, bitmap] = counters else {
+            return false;
+        };
+        let mut state = self.state.lock();
+        state.base_counter = base_counter;
+        state.bitmap = bitmap;
+        true
+    }
+    fn stats(&self) -> ReplayStats {
+        let state = self.state.lock();
+        ReplayStats {
+            rejected: state.rejected,
+            accepted: state.accepted,
+            max_counter: state.base_counter.saturating_sub(1),
+            max_reorder_distance: state.max_reorder_distance,
+        }
+    }
+}
+
+#[test]
+fn test_compact_window() {
+    let window = CompactWindow::new(0, 100);
+    assert!(window.check(0) && window.update(0));
+    assert!(!window.check(0) && !window.update(0));
+    assert!(window.check(10) && window.update(10));
+    // Counters behind the largest accepted counter are remembered individually.
+    assert!(window.check(5) && window.update(5));
+    assert!(!window.check(5));
+    assert!(!window.check(111));
+    assert!(window.check(73) && window.update(73));
+    // Counter 10 is 63 behind and still remembered, counter 9 is 64 behind and forgotten.
+    assert!(!window.check(10));
+    assert!(!window.check(9));
+    assert!(window.check(72) && window.update(72));
+    // A jump larger than the bitmap clears it.
+    assert!(window.check(150) && window.update(150));
+    assert!(window.check(149) && !window.check(150));
+
+    let stats = window.stats();
+    assert_eq!(stats.accepted, 6);
+    assert_eq!(stats.max_counter, 150);
+    assert_eq!(stats.max_reorder_distance, 5);
+
+    let restored = CompactWindow::new(0, 100);
+    assert!(!restored.store_slots(&[1, 2, 3]));
+    assert!(restored.store_slots(&window.load_slots()));
+    assert!(!restored.check(150) && restored.check(149));
+}
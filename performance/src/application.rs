@@ -1,7 +1,13 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::fmt;
+use core::net::SocketAddr;
+
 use rand_core::{CryptoRng, RngCore};
-use std::sync::Arc;
 
 use crate::crypto::*;
+use crate::fault_stats::FaultStats;
 use crate::zeta::Session;
 
 pub use crate::proto::RATCHET_SIZE;
@@ -27,7 +33,9 @@ pub struct Settings {
     /// This prevents rekeying from occurring predictably on the hour, so traffic analysis is harder.
     pub rekey_time_max_jitter: u64,
     /// How many key uses may occur before the session starts attempting to rekey.
-    /// The session will forceably close at 2^32 key uses so it is recommended this value be smaller.
+    /// Data stops being sent shortly before 2^32 key uses, with `SendError::RekeyUrgentlyNeeded`,
+    /// and the session will forceably close at 2^32 key uses, so it is recommended this value be
+    /// much smaller.
     pub rekey_after_key_uses: u64,
     /// Retry interval for outgoing connection initiation or rekey attempts.
     ///
@@ -37,6 +45,84 @@ pub struct Settings {
     /// How long fragments are allowed to linger in the defragmentation buffer before they are dropped.
     /// This implementation of a defrag buffer only bounds memory consumption based on this value.
     pub fragment_assembly_timeout: u64,
+    /// Determines the number of counters a session will remember. If a counter arrives over
+    /// this amount out of order relative to other received counters, it is likely to be
+    /// rejected on the basis that the session can't remember if this counter was replayed.
+    /// A value of 0 is treated as 1.
+    /// Increasing this value makes a session consume more memory.
+    /// This is ignored if the `compact-window` feature is enabled, in which case every session
+    /// remembers 64 counters.
+    pub counter_window_max_out_of_order: usize,
+    /// Maximum number of counter steps that the counter of a received packet is allowed to skip
+    /// ahead of the counters that were previously received.
+    /// Must be at least 2^20 and no greater than 2^24, values outside of that range are clamped.
+    pub counter_window_max_skip_ahead: u64,
+    /// The maximum number of handshakes with an unauthenticated Alice that a context will cache.
+    /// These are extremely large and since Alice has not been authenticated we put a hard
+    /// limit to how many we cache. When the limit is reached the oldest handshake is dropped.
+    /// Larger values consume more memory but provide better reliability and DDOS resistance.
+    /// Must be greater than 0.
+    pub max_unassociated_handshake_states: usize,
+    /// The maximum number of handshakes with an unauthenticated Alice that a context will cache
+    /// for any one remote address. When an address exceeds this limit its own oldest handshake is
+    /// dropped, so one address flooding us with handshakes cannot crowd out every other address.
+    /// Must be greater than 0, values larger than `max_unassociated_handshake_states` have no effect.
+    pub max_unassociated_handshake_states_per_address: usize,
+    /// The proof of work difficulty of challenges issued while the context is under no load,
+    /// in leading zero bits. Each additional bit doubles the expected work of solving a challenge.
+    /// Values outside the range 13 to 24 are clamped to that range.
+    pub challenge_difficulty_min: u32,
+    /// The proof of work difficulty of challenges issued while the context is fully loaded,
+    /// meaning either the unassociated handshake cache is full or hellos are arriving at
+    /// `challenge_max_hello_rate`. The difficulty scales linearly between the min and max with load.
+    /// Values outside the range `challenge_difficulty_min` to 24 are clamped to that range.
+    pub challenge_difficulty_max: u32,
+    /// The rate of hellos received per `challenge_hello_rate_window` at which the context is
+    /// considered to be fully loaded.
+    /// Must be greater than 0.
+    pub challenge_max_hello_rate: u64,
+    /// The window of time over which the rate of received hellos is estimated.
+    pub challenge_hello_rate_window: u64,
+    /// How long a challenge remains valid after it was issued. Responses to older challenges are
+    /// rejected and a new challenge is issued.
+    /// This should leave Alice ample time to solve the challenge at `challenge_difficulty_max`.
+    ///
+    /// The key challenges are authenticated with is also rotated every `challenge_timeout`,
+    /// and challenges only survive one rotation, so a challenge always remains valid for the full
+    /// timeout and its MAC never outlives twice that.
+    pub challenge_timeout: u64,
+    /// If true, challenges are stateless cookies rather than counters checked against a replay
+    /// window. A cookie is a MAC over Alice's address, her key id and its issue time, keyed by a
+    /// secret that is replaced every `challenge_cookie_lifetime`. Verifying a cookie does not
+    /// require any lookup, but a solved cookie can be replayed by the same address until it expires.
+    /// Challenges are compatible with peers either way, so this only affects the local responder.
+    pub stateless_challenges: bool,
+    /// The length of an epoch of stateless challenge cookies.
+    /// A cookie is valid during the epoch it was issued in and the following one, so it expires
+    /// after between one and two lifetimes. This should leave Alice ample time to solve the
+    /// challenge at `challenge_difficulty_max`.
+    /// Must be greater than 0.
+    pub challenge_cookie_lifetime: u64,
+    /// The number of unnatural byzantine faults that may be attributed to a single session within
+    /// `unnatural_fault_window` before `ApplicationLayer::on_fault_threshold_exceeded` is called.
+    /// If this is `None` the callback is never called.
+    pub unnatural_fault_threshold: Option<u64>,
+    /// The length of the windows over which `unnatural_fault_threshold` is counted.
+    pub unnatural_fault_window: u64,
+    /// The lowest protocol version this context will agree to use with a remote peer.
+    /// A handshake with a peer whose `PROTOCOL_VERSION` is lower than this is rejected.
+    /// Must be greater than 0 and no greater than `PROTOCOL_VERSION`.
+    pub min_accepted_version: u8,
+    /// The largest identity in bytes that can be streamed during the initial handshake.
+    /// An identity larger than `IDENTITY_MAX_SIZE` does not fit in Alice's handshake completion,
+    /// so she sends the rest of it in additional packets. Bob buffers these with the handshake
+    /// until the whole identity has arrived and can be passed to
+    /// `ApplicationLayer::check_accept_session`, and rejects streamed identities larger than this.
+    /// The buffer is dropped along with the handshake after `fragment_assembly_timeout`.
+    /// Alice cannot open a session with an identity larger than this.
+    /// Values no greater than `IDENTITY_MAX_SIZE` disable streaming.
+    /// Larger values let each unassociated handshake consume more memory.
+    pub max_streamed_identity_size: usize,
 }
 impl Settings {
     /// Default value for the `initial_offer_timeout`.
@@ -60,6 +146,51 @@ impl Settings {
     /// Default value for the `fragment_assembly_timeout`.
     /// The default is 5 seconds in ms.
     pub const FRAGMENT_ASSEMBLY_TIMEOUT_MS: u64 = 5 * 1000;
+    /// Default value for the `counter_window_max_out_of_order`.
+    /// The default is 128 counters.
+    pub const COUNTER_WINDOW_MAX_OUT_OF_ORDER: usize = 128;
+    /// Default value for the `counter_window_max_skip_ahead`.
+    /// The default is 2^24, which is also the greatest value allowed.
+    pub const COUNTER_WINDOW_MAX_SKIP_AHEAD: u64 = 1 << 24;
+    /// Default value for the `max_unassociated_handshake_states`.
+    /// The default is 32 handshakes.
+    pub const MAX_UNASSOCIATED_HANDSHAKE_STATES: usize = 32;
+    /// Default value for the `max_unassociated_handshake_states_per_address`.
+    /// The default is 8 handshakes, a quarter of the default cache size.
+    pub const MAX_UNASSOCIATED_HANDSHAKE_STATES_PER_ADDRESS: usize = 8;
+    /// Default value for the `challenge_difficulty_min`.
+    /// The default is 13 bits, the fixed difficulty used by peers without adaptive difficulty.
+    pub const CHALLENGE_DIFFICULTY_MIN: u32 = 13;
+    /// Default value for the `challenge_difficulty_max`.
+    /// The default is 20 bits, 128 times the work of the minimum.
+    pub const CHALLENGE_DIFFICULTY_MAX: u32 = 20;
+    /// Default value for the `challenge_max_hello_rate`.
+    /// The default is 1000 hellos.
+    pub const CHALLENGE_MAX_HELLO_RATE: u64 = 1000;
+    /// Default value for the `challenge_hello_rate_window`.
+    /// The default is 1 second in ms.
+    pub const CHALLENGE_HELLO_RATE_WINDOW_MS: u64 = 1000;
+    /// Default value for the `challenge_timeout`.
+    /// The default is 30 seconds in ms.
+    pub const CHALLENGE_TIMEOUT_MS: u64 = 30 * 1000;
+    /// Default value for the `stateless_challenges`.
+    /// The default is false, challenges are checked against a replay window.
+    pub const STATELESS_CHALLENGES: bool = false;
+    /// Default value for the `challenge_cookie_lifetime`.
+    /// The default is 30 seconds in ms.
+    pub const CHALLENGE_COOKIE_LIFETIME_MS: u64 = 30 * 1000;
+    /// Default value for the `unnatural_fault_threshold`.
+    /// The default is `None`, the threshold is disabled.
+    pub const UNNATURAL_FAULT_THRESHOLD: Option<u64> = None;
+    /// Default value for the `unnatural_fault_window`.
+    /// The default is 1 minute in ms.
+    pub const UNNATURAL_FAULT_WINDOW_MS: u64 = 60 * 1000;
+    /// Default value for the `min_accepted_version`.
+    /// The default is 1, every protocol version is accepted.
+    pub const MIN_ACCEPTED_VERSION: u8 = 1;
+    /// Default value for the `max_streamed_identity_size`.
+    /// The default is 16 KiB, enough for most certificate chains.
+    pub const MAX_STREAMED_IDENTITY_SIZE: usize = 16 * 1024;
     /// Create an instance of Settings with all default values.
     /// These defaults are in units of milliseconds, so if these defaults are used, `App::time`
     /// must return timestamps in unts of milliseconds as well.
@@ -72,6 +203,21 @@ impl Settings {
             rekey_after_key_uses: Self::REKEY_AFTER_KEY_USES,
             resend_time: Self::RESEND_TIME,
             fragment_assembly_timeout: Self::FRAGMENT_ASSEMBLY_TIMEOUT_MS,
+            counter_window_max_out_of_order: Self::COUNTER_WINDOW_MAX_OUT_OF_ORDER,
+            counter_window_max_skip_ahead: Self::COUNTER_WINDOW_MAX_SKIP_AHEAD,
+            max_unassociated_handshake_states: Self::MAX_UNASSOCIATED_HANDSHAKE_STATES,
+            max_unassociated_handshake_states_per_address: Self::MAX_UNASSOCIATED_HANDSHAKE_STATES_PER_ADDRESS,
+            challenge_difficulty_min: Self::CHALLENGE_DIFFICULTY_MIN,
+            challenge_difficulty_max: Self::CHALLENGE_DIFFICULTY_MAX,
+            challenge_max_hello_rate: Self::CHALLENGE_MAX_HELLO_RATE,
+            challenge_hello_rate_window: Self::CHALLENGE_HELLO_RATE_WINDOW_MS,
+            challenge_timeout: Self::CHALLENGE_TIMEOUT_MS,
+            stateless_challenges: Self::STATELESS_CHALLENGES,
+            challenge_cookie_lifetime: Self::CHALLENGE_COOKIE_LIFETIME_MS,
+            unnatural_fault_threshold: Self::UNNATURAL_FAULT_THRESHOLD,
+            unnatural_fault_window: Self::UNNATURAL_FAULT_WINDOW_MS,
+            min_accepted_version: Self::MIN_ACCEPTED_VERSION,
+            max_streamed_identity_size: Self::MAX_STREAMED_IDENTITY_SIZE,
         }
     }
 }
@@ -81,6 +227,65 @@ impl Default for Settings {
     }
 }
 
+/// Trait for the application defined object attached to each session, `CryptoLayer::SessionData`.
+///
+/// This is automatically implemented for every type that is `Send + Sync + 'static`, so it
+/// places no additional requirements on the application. To describe its sessions to generic
+/// tooling, an application implements `SessionInfo` for its session data and returns it from
+/// `CryptoLayer::session_info`.
+pub trait SessionMetadata: Send + Sync + 'static {}
+impl<T: Send + Sync + 'static> SessionMetadata for T {}
+
+/// Metadata about a session that generic tooling, such as loggers, can use to tag sessions
+/// without knowing the concrete type of their data. See `CryptoLayer::session_info`.
+///
+/// Every method returns `None` by default, so an implementation only overrides those for which it
+/// has an answer. It is already implemented for `()`, for unsigned integers, which are used as
+/// their own session id, for `SocketAddr`, which is used as its own peer address, and for `Arc`
+/// and `Box` of any implementation.
+pub trait SessionInfo {
+    /// An application defined numeric identifier for this session, if it has one.
+    fn session_id(&self) -> Option<u64> {
+        None
+    }
+    /// The network address of the remote peer of this session, if it is known.
+    fn peer_address(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+impl SessionInfo for () {}
+macro_rules! impl_session_info_for_int {
+    ($($t:ty),*) => {$(
+        impl SessionInfo for $t {
+            fn session_id(&self) -> Option<u64> {
+                u64::try_from(*self).ok()
+            }
+        }
+    )*};
+}
+impl_session_info_for_int!(u8, u16, u32, u64, u128, usize);
+impl SessionInfo for SocketAddr {
+    fn peer_address(&self) -> Option<SocketAddr> {
+        Some(*self)
+    }
+}
+impl<T: SessionInfo + ?Sized> SessionInfo for Arc<T> {
+    fn session_id(&self) -> Option<u64> {
+        (**self).session_id()
+    }
+    fn peer_address(&self) -> Option<SocketAddr> {
+        (**self).peer_address()
+    }
+}
+impl<T: SessionInfo + ?Sized> SessionInfo for Box<T> {
+    fn session_id(&self) -> Option<u64> {
+        (**self).session_id()
+    }
+    fn peer_address(&self) -> Option<SocketAddr> {
+        (**self).peer_address()
+    }
+}
+
 /// Trait to implement to integrate the session into an application.
 ///
 /// Templating the session on this trait lets the code here be almost entirely transport, OS,
@@ -95,18 +300,46 @@ pub trait CryptoLayer: Sized {
     /// and negotiation timeout behavior. If two sides of a ZSSP session have different constants,
     /// the protocol will tend to default to the smaller constants.
     const SETTINGS: Settings = Settings::new_ms();
+    /// The number of independent `Rng` instances a context created with `Context::new_sharded`
+    /// will hold. Each thread is assigned to a shard by the hash of its thread ID, so raising this
+    /// reduces contention over the RNG locks when many threads are opening sessions at once.
+    const RNG_SHARDS: usize = 1;
+    /// How the cache that reassembles fragmented packets from peers without a session decides
+    /// which incomplete packets to drop. See `FragCachePolicy`.
+    const FRAG_CACHE_POLICY: FragCachePolicy = FragCachePolicy::TimeoutBased;
 
     /// The random number generator that ZSSP should use.
     /// It is used infrequently, but should still be cryptographically secure.
     ///
     /// FIPS compliance requires use of a FIPS certified implementation.
-    type Rng: CryptoRng + RngCore;
+    type Rng: CryptoRng + RngCore + Send;
 
     /// The implementation of AES-256 block encryption that ZSSP should use.
+    /// It encrypts one block of the header of every fragment sent, and generates unpredictable
+    /// local key ids.
+    ///
+    /// The header block is used as a pseudo-random permutation (PRP). It holds the 12 bytes of the
+    /// header that follow the key id, which are the fragment number, the fragment count and the
+    /// packet nonce, along with the first 4 bytes of the payload. Each direction of a session has
+    /// its own header key.
+    ///
+    /// Encrypting this block hides the packet type, counter and fragmentation info from passive
+    /// observers, so they cannot trivially link fragments to the packet they belong to or tell the
+    /// packets of a session apart. It also authenticates the header: a forged or corrupted block
+    /// decrypts to a random packet nonce that is almost never valid, so forged fragments are
+    /// dropped before they take up space in a defragmentation buffer. Section 6 of the whitepaper
+    /// proves this secure under the assumption that AES-256 is a PRP.
+    ///
+    /// PRP security is required, a pseudo-random function (PRF) is not enough. The receiver has to
+    /// invert the permutation to read the header of every fragment before it can even tell which
+    /// packet the fragment belongs to, so decryption must be just as efficient as encryption.
+    /// Substituting a keyed hash or other PRF that cannot be inverted efficiently would break both
+    /// the protocol and its security proof.
     ///
     /// FIPS compliance requires use of a FIPS certified implementation.
     type PrpEnc: Aes256Enc;
     /// The implementation of AES-256 block decryption that ZSSP should use.
+    /// It decrypts the header of every fragment received, so it should be as fast as `PrpEnc`.
     ///
     /// FIPS compliance requires use of a FIPS certified implementation.
     type PrpDec: Aes256Dec;
@@ -151,7 +384,7 @@ pub trait CryptoLayer: Sized {
 
     /// Type for arbitrary opaque object for use by the application that is attached to
     /// each session.
-    type SessionData;
+    type SessionData: SessionMetadata;
 
     /// Type for arbitrary opaque object that is attached to a new connection attempt if Alice sends
     /// us a ratchet fingerprint recognized by `restore_by_fingerprint`.
@@ -176,6 +409,13 @@ pub trait CryptoLayer: Sized {
     /// hold these for a short period of time when assembling fragmented packets on the receive
     /// path.
     type IncomingPacketBuffer: AsRef<[u8]> + AsMut<[u8]>;
+
+    /// The metadata of a session with `session_data` attached, see `Session::info`.
+    /// By default sessions have none.
+    fn session_info(session_data: &Self::SessionData) -> Option<&dyn SessionInfo> {
+        let _ = session_data;
+        None
+    }
 }
 
 /// Trait to implement to integrate ZSSP into an application.
@@ -197,6 +437,20 @@ pub trait ApplicationLayer<C: CryptoLayer>: Sized {
     /// Hello packet. If DDOS mitigation is not needed, this function can just be a single line that
     /// returns `Allow`.
     fn incoming_session(&mut self) -> IncomingSessionAction;
+    /// Like `incoming_session`, but also given the address the Hello packet was received from.
+    ///
+    /// This is the function ZSSP actually calls, so it can be implemented instead of
+    /// `incoming_session` to allow-list address ranges or to rate limit Hello packets per subnet.
+    /// The address of a Hello packet is not authenticated and may be spoofed.
+    ///
+    /// `remote_address` is the address that was passed to `Context::receive`, and can be downcast
+    /// back to its concrete type.
+    ///
+    /// The default implementation ignores the address and calls `incoming_session`.
+    #[allow(unused)]
+    fn incoming_session_with_address(&mut self, remote_address: &dyn Any) -> IncomingSessionAction {
+        self.incoming_session()
+    }
     /// This function will be called whenever Alice's initial Hello packet contains the empty ratchet
     /// fingerprint. Brand new peers will always connect to Bob with the empty ratchet, but from
     /// then on they should be using non-empty ratchet states.
@@ -229,6 +483,22 @@ pub trait ApplicationLayer<C: CryptoLayer>: Sized {
     /// Corresponds to the "Initiator Disallows Downgrade, π_2" security flag of Transition
     /// Algorithm 3 within the ZSSP whitepaper.
     fn initiator_disallows_downgrade(&mut self, session: &Arc<Session<C>>) -> bool;
+    /// This function is called if a handshake with a peer would use protocol version `version`,
+    /// but the ratchet state we share with them records that `min_version` was already
+    /// negotiated with them in the past.
+    ///
+    /// If it returns false the handshake is refused. As Alice, `Context::open` returns
+    /// `OpenError::VersionDowngrade`, as Bob, the Hello packet is dropped.
+    /// If it returns true the handshake continues with the older version.
+    ///
+    /// A peer that has spoken a later version with us should only go back to an older one if
+    /// their software was deliberately rolled back. Otherwise this is a sign of an attacker
+    /// trying to force the use of an older, possibly weaker, version of the protocol.
+    /// The default implementation always returns false.
+    #[allow(unused)]
+    fn allow_version_downgrade(&mut self, min_version: u8, version: u8) -> bool {
+        false
+    }
     /// Function to accept sessions after final negotiation.
     ///
     /// The implementor must verify that three arguments, `remote_static_key`, `identity` and
@@ -268,11 +538,14 @@ pub trait ApplicationLayer<C: CryptoLayer>: Sized {
     /// If this is done, the implementor is required in `check_accept_session` to verify that the
     /// cached resources in `FingerprintData` indeed belong to the specified remote peer.
     ///
+    /// If this returns `Err`, the received packet is dropped and the error is returned as
+    /// `ReceiveError::StorageReadError`.
+    ///
     /// Corresponds to the **Restore** call of Transition Algorithm 2 within the ZSSP whitepaper.
     fn restore_by_fingerprint(
         &mut self,
         ratchet_fingerprint: &[u8; RATCHET_SIZE],
-    ) -> Result<Option<(RatchetState, C::FingerprintData)>, std::io::Error>;
+    ) -> Result<Option<(RatchetState, C::FingerprintData)>, crate::io::Error>;
     /// Lookup the specific ratchet states based on the identity of the peer being communicated with.
     /// This function will be called whenever Alice attempts to open a session, or Bob attempts
     /// to verify Alice's identity.
@@ -289,13 +562,16 @@ pub trait ApplicationLayer<C: CryptoLayer>: Sized {
     /// Filtering peers should be done by the caller to `Context::open` as well as by the
     /// function `ApplicationLayer::check_accept_session`.
     ///
+    /// If this returns `Err`, it is returned as `OpenError::StorageReadError` by `Context::open`,
+    /// or as `ReceiveError::StorageReadError` by the receive functions.
+    ///
     /// Corresponds to the **Restore** call of Transition Algorithm 1 and 4 within the ZSSP whitepaper.
     fn restore_by_identity(
         &mut self,
         remote_static_key: &C::PublicKey,
         session_data: &C::SessionData,
         fingerprint_data: Option<&C::FingerprintData>,
-    ) -> Result<Option<RatchetStates>, std::io::Error>;
+    ) -> Result<Option<RatchetStates>, crate::io::Error>;
     /// Atomically compare-and-swap (a.k.a. compare-exchange) `update` to storage.
     ///
     /// If `update.cur_state1` and `update.cur_state2` are currently in storage, they must
@@ -308,6 +584,7 @@ pub trait ApplicationLayer<C: CryptoLayer>: Sized {
     /// If this returns `Err`, the packet which triggered this function to be called will be
     /// dropped, and no session state will be mutated, preserving synchronization. The remote peer
     /// will eventually resend that packet and so this function will be called again.
+    /// The error is returned as `ReceiveError::StorageWriteError`.
     ///
     /// If persistent storage is supported, this function should not return until the ratchet state
     /// is saved, otherwise it is possible, albeit unlikely, for a sudden restart of the local
@@ -330,14 +607,220 @@ pub trait ApplicationLayer<C: CryptoLayer>: Sized {
         remote_static_key: &C::PublicKey,
         session_data: &C::SessionData,
         update: CompareAndSwap<'_>,
-    ) -> Result<bool, std::io::Error>;
+    ) -> Result<bool, crate::io::Error>;
+
+    /// This function is called whenever we, as Alice, send a new Hello to Bob, and determines
+    /// whether the handshake will include the Kyber1024 key encapsulation.
+    ///
+    /// If this returns false, the Kyber public key in the Hello is replaced with zeros and Bob
+    /// skips the encapsulation step, so the handshake falls back to plain Noise_XK. Bob accepts
+    /// this unless his `require_kyber` returns true. Packet sizes do not change, so this only saves the several hundred
+    /// microseconds of CPU time that Kyber costs. Both peers must be running a version of ZSSP
+    /// that understands this.
+    ///
+    /// # Security
+    /// **Returning false removes the post-quantum forward secrecy of the session.** Without Kyber
+    /// the session keys are protected only by P-384 ECDH and the ratchet key. An adversary who
+    /// records the handshake today and later gains access to a cryptographically relevant quantum
+    /// computer will be able to decrypt the entire session, unless they also lack the ratchet key.
+    /// Only return false for deployments where some peers cannot afford Kyber.
+    fn prefer_kyber(&mut self) -> bool {
+        true
+    }
+    /// This function is called whenever we, as Bob, receive a Hello without a Kyber1024 public
+    /// key, because Alice's `prefer_kyber` returned false.
+    ///
+    /// If this returns true the Hello is dropped and `ReceiveError::Rejected` is returned, so
+    /// every session we accept has post-quantum forward secrecy, see `Session::is_post_quantum`.
+    /// Alice is not told, she keeps retrying the handshake with new Hellos for as long as her
+    /// session is open.
+    fn require_kyber(&mut self) -> bool {
+        false
+    }
+
+    /// This function is called whenever we, as Alice, send our identity in a handshake completion,
+    /// and determines whether it is compressed with deflate first.
+    ///
+    /// Identities such as certificate chains often compress to a fraction of their size, which
+    /// saves fragments, and the identity continuations of identities larger than
+    /// `IDENTITY_MAX_SIZE`. The identity is sent as is if compressing it would not make it smaller.
+    /// Bob decompresses it before passing it to `check_accept_session`, and drops identities that
+    /// decompress to more than `Settings::max_streamed_identity_size` as invalid. Both peers must
+    /// be running a version of ZSSP with the `deflate` feature, other peers drop the handshake as
+    /// an invalid packet.
+    ///
+    /// Compressed identities are still encrypted, but the size of the handshake then depends on
+    /// how well the identity compresses rather than only on its length.
+    ///
+    /// It is only available with the `deflate` feature.
+    #[cfg(feature = "deflate")]
+    fn compress_identity(&mut self) -> bool {
+        false
+    }
+
+    /// This function is called whenever a key exchange with a new peer reaches the point where
+    /// the AEAD for its data packets is chosen: as Bob when he answers a Hello, and as Alice when
+    /// she receives Bob's answer. See `MisuseResistance` for the possible policies.
+    ///
+    /// Data packets are encrypted with AES-GCM unless both peers prefer or require the nonce
+    /// misuse resistant AEAD of `CryptoLayer::AeadPool`, see
+    /// `HighThroughputAesGcmPool::MISUSE_RESISTANT`. If the pool is not misuse resistant it is
+    /// treated as unavailable, so `Required` makes every key exchange fail.
+    ///
+    /// The choice is authenticated by the key exchange and kept for the lifetime of the session,
+    /// across rekeys. See `Session::is_misuse_resistant`.
+    fn misuse_resistance(&mut self) -> MisuseResistance {
+        MisuseResistance::Disabled
+    }
+
+    /// This function is called whenever a key exchange with a new peer begins.
+    ///
+    /// As Alice, `is_initiator` is true and this is called when `Context::open` sends its Hello.
+    /// As Bob, `is_initiator` is false and this is called when a Hello is accepted and responded to.
+    /// Since Bob's peer is still anonymous at this point, this may be called many times for a
+    /// handshake that never completes.
+    ///
+    /// This hook is intended for passive monitoring, such as security auditing, and must not be
+    /// used to make protocol-level decisions.
+    #[allow(unused)]
+    fn on_handshake_started(&mut self, is_initiator: bool) {}
+    /// This function is called whenever the remote peer of an established session asks to rekey
+    /// it, after its request has been authenticated and before any work is done to process it.
+    ///
+    /// It can be used by policy engines to stop sessions from rekeying, for example during a
+    /// security incident investigation. See `RekeyAction` for the possible responses.
+    /// Keep in mind that a session which cannot rekey will eventually exceed its key lifetime,
+    /// at which point it is expired with `ReceiveError::MaxKeyLifetimeExceeded`.
+    ///
+    /// No lock is held while this is called, so it may call back into ZSSP with this session.
+    /// If the session changes state in the meantime, for example because it was expired, the
+    /// request is dropped and the returned action is ignored.
+    #[allow(unused)]
+    fn incoming_rekey(&mut self, session: &Arc<Session<C>>) -> RekeyAction {
+        RekeyAction::Allow
+    }
+    /// This function is called whenever a key exchange of `session` completes, to choose when the
+    /// new key should be rekeyed.
+    ///
+    /// Returning `Some((after_time_ms, after_key_uses))` overrides `Settings::rekey_after_time` and
+    /// `Settings::rekey_after_key_uses` for this key, so that for example a session carrying
+    /// financial data can be rekeyed every minute while one carrying telemetry keeps the default
+    /// schedule. Returning `None` uses the global settings.
+    /// The random jitter subtracted from the time is limited to half of `after_time_ms`.
+    ///
+    /// Either peer starts a rekey once its own schedule runs out, so the shorter of the two
+    /// schedules is the one that takes effect. Sessions imported with `Context::import_session_state`
+    /// keep the schedule that was chosen for their current key.
+    ///
+    /// This is called while the state machine of `session` is locked, so it must not call back
+    /// into ZSSP with this session.
+    #[allow(unused)]
+    fn choose_rekey_timing(&mut self, session: &Arc<Session<C>>) -> Option<(u64, u64)> {
+        None
+    }
+    /// This function is called whenever the initial key exchange of a session has completed.
+    ///
+    /// As Alice this is called right before `ReceiveOk::Established` is returned, and as Bob
+    /// right before `SessionEvent::NewSession` or `SessionEvent::NewDowngradedSession` is returned.
+    /// `duration_ms` is the time elapsed since the matching call to `on_handshake_started`,
+    /// as measured by `ApplicationLayer::time`.
+    ///
+    /// This hook is intended for passive monitoring, such as security auditing, and must not be
+    /// used to make protocol-level decisions.
+    #[allow(unused)]
+    fn on_handshake_completed(&mut self, session: &Arc<Session<C>>, is_initiator: bool, duration_ms: u64) {}
+    /// This function is called when more than `Settings::unnatural_fault_threshold` unnatural
+    /// byzantine faults have been attributed to `session` within one `unnatural_fault_window`.
+    /// It is called at most once per window, from within the receive call that returned the
+    /// fault which crossed the threshold. `remote_address` is the source of that packet, as passed
+    /// to `Context::receive`, and can be downcast back to its concrete type.
+    ///
+    /// The application may respond by blocking `remote_address` or by expiring the session.
+    /// Be careful with the latter: `stats.pre_auth` faults can be caused by anyone who has
+    /// observed the key id of the session, so expiring sessions based on them lets an observer
+    /// close sessions at will. `stats.post_auth` faults can only be caused by the remote peer or
+    /// by replaying its packets.
+    #[allow(unused)]
+    fn on_fault_threshold_exceeded(&mut self, session: &Arc<Session<C>>, remote_address: &dyn Any, stats: FaultStats) {}
+    /// This function is called for every fragment received that is not addressed to a session,
+    /// meaning Hello and Challenge packets as well as garbage, before any cryptographic work is
+    /// done on it. It can be used to monitor the volume of pre-authentication traffic, for example
+    /// to detect amplification attacks or to tune `incoming_session` and the challenge settings.
+    ///
+    /// `remote_address_hash` is a hash of the remote address, salted with a key that is random
+    /// for every context, so it can be used to correlate the packets of one address without
+    /// recording the address itself.
+    #[allow(unused)]
+    fn on_unassociated_packet(
+        &mut self,
+        packet_type: u8,
+        remote_address_hash: u64,
+        fragment_no: usize,
+        fragment_count: usize,
+    ) {
+    }
+    /// This function is called when a `send` function or `Sender` returned `false` for a fragment
+    /// of a key exchange or control packet of `session`. The remaining fragments of that packet
+    /// were not sent. `packet_type` is the type of the packet and `fragment_no` is the index of
+    /// the fragment that failed.
+    ///
+    /// It is called once ZSSP has finished processing the packet, from within the call that sent
+    /// it. Key exchange packets are resent by `Context::service` as usual, so a failure here is
+    /// not fatal to the session.
+    ///
+    /// This is not called for data packets sent with `Context::send`, since the application can
+    /// observe failures of its own `send` function there directly, nor for the replies to hello
+    /// packets, which are sent before a session exists.
+    #[allow(unused)]
+    fn on_send_failure(&mut self, session: &Arc<Session<C>>, packet_type: u8, fragment_no: u8) {}
 
     /// Receives a stream of events that occur during an execution of ZSSP.
     /// These are provided for debugging, logging or metrics purposes, and must be used for
     /// nothing else. Do not base protocol-level decisions upon the events passed to this function.
+    ///
+    /// If the `tracing` feature is also enabled every event is emitted to the current `tracing`
+    /// subscriber as well as being passed to this function.
     #[cfg(feature = "logging")]
     #[allow(unused)]
     fn event_log(&mut self, event: crate::LogEvent<'_, C>) {}
+
+    /// Receives the plaintext of every encrypted segment of a key exchange or control packet,
+    /// immediately before it is encrypted or immediately after it was successfully decrypted.
+    /// This is for debugging interoperability problems only, data packets are not passed here.
+    ///
+    /// `packet_type` and `counter` are the ones in the nonce the segment is encrypted with.
+    /// Noise handshake packets contain several segments, each encrypted with its own counter, and
+    /// rekey packets are encrypted twice, so both their Noise segment and the whole packet around
+    /// it are passed here.
+    ///
+    /// It is only available with the `capture` feature. Without it this is compiled out entirely,
+    /// so plaintext can never leak through it in production builds.
+    #[cfg(feature = "capture")]
+    #[allow(unused)]
+    fn debug_packet(&mut self, direction: PacketDirection, packet_type: u8, counter: u64, plaintext: &[u8]) {}
+
+    /// Supplies the randomness the ephemeral keys of the next hello or response this side sends
+    /// are generated from, in place of the RNG of the context. The P-384 ephemeral key is
+    /// generated first, followed by the Kyber key pair of a hello or the Kyber encapsulation of a
+    /// response. Return `None` to use the RNG of the context.
+    ///
+    /// This exists to produce reproducible test vectors, see the `test_vectors` module. It is only
+    /// available with the `test-vectors` feature. Without it this is compiled out entirely, so
+    /// ephemeral keys can never be chosen by the application in production builds.
+    #[cfg(feature = "test-vectors")]
+    fn ephemeral_rng(&mut self) -> Option<C::Rng> {
+        None
+    }
+}
+
+/// Whether a packet passed to `ApplicationLayer::debug_packet` is being sent or was received.
+#[cfg(feature = "capture")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketDirection {
+    /// The packet is about to be encrypted and sent.
+    Sent,
+    /// The packet was just received and decrypted.
+    Received,
 }
 
 /// Possible responses that can be made to Hello packets from an anonymous peer.
@@ -355,11 +838,73 @@ pub enum IncomingSessionAction {
     /// new Hello packets from the same peer or set of peers.
     ///
     /// If they complete the challenge they will be allowed to continue connecting.
-    Challenge,
+    ///
+    /// The contained value is the minimum difficulty of the proof of work, in leading zero bits.
+    /// The challenge that is issued will be at least this difficult, and may be more difficult
+    /// if load based scaling chooses a higher difficulty, see `Settings::challenge_difficulty_min`.
+    /// Return `Challenge(0)` to only use load based scaling. Difficulties are capped at 24 bits.
+    ///
+    /// The difficulty is bound into the challenge MAC, so the peer cannot solve an easier
+    /// challenge than the one it was issued. Each additional bit doubles the expected work for the
+    /// peer: solving a challenge takes on average 2^difficulty SHA-512 hashes, which is roughly
+    /// 1 to 5 ms of CPU time at 13 bits, 0.1 to 0.5 s at 20 bits and 2 to 8 s at 24 bits on a
+    /// modern CPU core. Verifying a response always costs a single hash, regardless of difficulty.
+    Challenge(u32),
     /// Drop the anonymous peer's Hello packet, preventing them from connecting.
     Drop,
 }
 
+/// The eviction policy of the cache that reassembles fragmented packets from peers without a
+/// session, such as Hello packets. See `CryptoLayer::FRAG_CACHE_POLICY`.
+///
+/// Under both policies an incomplete packet is dropped once `Settings::fragment_assembly_timeout`
+/// has passed. They differ in which packets are dropped early when the cache is full.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FragCachePolicy {
+    /// Drop the packets whose first fragment arrived the longest time ago, even if they are
+    /// still receiving fragments. The timeout is measured from the first fragment.
+    /// This is the cheapest policy, and the default.
+    TimeoutBased,
+    /// Drop the packets that have gone the longest without receiving a new fragment, so packets
+    /// that are actively being received are kept. The timeout is measured from the last fragment.
+    Lru,
+}
+
+/// Possible responses that can be made to a remote peer's request to rekey a session.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RekeyAction {
+    /// Rekey the session as usual.
+    Allow,
+    /// Ask the remote peer to try again later. The remote peer will resend its request every
+    /// `Settings::resend_time`, and `incoming_rekey` will be called for each request.
+    ///
+    /// Each deferral restarts the remote peer's `Settings::rekey_timeout`, so the session
+    /// survives being deferred for any length of time.
+    Defer,
+    /// Refuse to rekey and expire the session. The receive call returns
+    /// `SessionEvent::RekeyRejected`. The remote peer is not told, its session will expire once
+    /// its `Settings::rekey_timeout` has passed.
+    Reject,
+}
+
+/// Whether the data packets of a session should use the nonce misuse resistant AEAD of
+/// `CryptoLayer::AeadPool`, see `ApplicationLayer::misuse_resistance`.
+///
+/// A misuse resistant AEAD such as AES-GCM-SIV keeps a session secure if a send counter is ever
+/// replayed, for example after a VM snapshot is restored, at the cost of a significant amount of
+/// throughput. See `crypto_impl::AesGcmSivPool`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MisuseResistance {
+    /// Always use AES-GCM. This is the default.
+    Disabled,
+    /// Use the misuse resistant AEAD if the remote peer supports it, and AES-GCM otherwise.
+    Preferred,
+    /// Only establish sessions that use the misuse resistant AEAD. Key exchanges with peers that do
+    /// not support it fail: as Alice the session expires with `ReceiveError::Rejected`, and as Bob
+    /// Alice is sent a rejection.
+    Required,
+}
+
 /// A collection of fields specifying how to complete the key exchange with a specific remote peer,
 /// used by Bob, the responder, at the very last stage of the key exchange.
 ///
@@ -416,12 +961,28 @@ pub trait SendTo<C: CryptoLayer> {
     /// If no error occurs this function should return a `Sender` instance configured to send to the
     /// remote peer specified by `session`. It should also return the MTU of this link. This MTU can
     /// be `usize::MAX`, in which case the packet is not fragmented and the `Sender` instance is
-    /// only called once.
+    /// only called once. If it is 0, the MTU set with `Session::set_mtu_hint` is used instead.
     ///
     /// If `None` is returned then sending to this session is cancelled.
     fn init_send<'a>(&'a mut self, session: &'a Arc<Session<C>>) -> Option<(Self::Sender<'a>, usize)>;
 }
 
+/// A trait to genericize where the plaintext of received data packets is written to.
+///
+/// Is implemented by every `io::Write`, which includes `Vec<u8>` and `&mut [u8]`. Like any
+/// `io::Write`, a `&mut [u8]` is advanced past the bytes written to it, so pass a reference to
+/// the slice to find out how much of it was used. A payload that does not fit fails with an error.
+/// Implement this directly to deliver payloads into other buffers, such as ring buffers, or to
+/// return a custom error type.
+pub trait PayloadSink {
+    /// The error returned if the sink cannot accept a payload. It is returned to the caller of
+    /// `Context::receive` as `ReceiveError::WriteError`.
+    type Error: fmt::Display;
+    /// Append the given plaintext to this sink. A fragmented payload is written with one call
+    /// per fragment, and the packet is dropped as soon as any call returns an error.
+    fn write_payload(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
 impl<F: FnMut(&mut [u8]) -> bool> Sender for F {
     fn send_frag(&mut self, frag: &mut [u8]) -> bool {
         self(frag)
@@ -434,3 +995,28 @@ impl<C: CryptoLayer, F: FnMut(&Arc<Session<C>>) -> Option<(S, usize)>, S: Sender
         self(session)
     }
 }
+
+impl<W: crate::io::Write> PayloadSink for W {
+    type Error = crate::io::Error;
+    fn write_payload(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.write_all(data)
+    }
+}
+
+#[test]
+fn test_session_info() {
+    use crate::sim::SimCrypto;
+    fn is_session_data<T: SessionMetadata>() {}
+    // Any type can still be used as session data, whether or not it describes itself.
+    is_session_data::<alloc::string::String>();
+    is_session_data::<SocketAddr>();
+    assert!(SimCrypto::session_info(&()).is_none());
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 9993));
+    let info: Box<dyn SessionInfo> = Box::new(Arc::new(addr));
+    assert_eq!(info.peer_address(), Some(addr));
+    assert_eq!(info.session_id(), None);
+    assert_eq!(7u32.session_id(), Some(7));
+    assert_eq!(u128::MAX.session_id(), None);
+    assert_eq!(().peer_address(), None);
+}
@@ -1,16 +1,113 @@
-use std::hash::Hasher;
-use std::sync::atomic::{AtomicU64, Ordering};
+use core::hash::{BuildHasher, Hasher};
 
 use rand_core::{CryptoRng, RngCore};
 
-use crate::antireplay::Window;
+use crate::antireplay::{AntiReplayWindow, Window};
+use crate::collections::{random_state, RandomState};
 use crate::crypto::*;
 use crate::proto::*;
+use crate::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use crate::sync::{Mutex, RwLock};
 
 pub struct ChallengeContext {
     counter: AtomicU64,
-    antireplay_window: Window<CHALLENGE_COUNTER_WINDOW_MAX_OOO, { u64::MAX }>,
-    salt: [u8; SALT_SIZE],
+    antireplay_window: Window,
+    keys: RwLock<ChallengeKeys>,
+    last_rotation: AtomicI64,
+    cookie_secrets: RwLock<CookieSecrets>,
+    cookie_lifetime: u64,
+    timeout: i64,
+    address_salt: RandomState,
+    issued: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    expired: AtomicU64,
+}
+
+/// Statistics recorded by the challenge layer of a context.
+///
+/// These are counted with relaxed atomics, see `Context::statistics`, so while hellos are being
+/// received concurrently `succeeded + failed + expired` can briefly exceed `issued`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChallengeStats {
+    /// The number of challenges that were sent.
+    pub issued: u64,
+    /// The number of hellos that passed their challenge.
+    pub succeeded: u64,
+    /// The number of hellos whose response failed verification for any reason other than expiry.
+    /// Hellos that did not respond to any challenge are not counted.
+    pub failed: u64,
+    /// The number of hellos that responded to a valid challenge that had expired.
+    pub expired: u64,
+}
+
+/// The reason a hello did not pass its challenge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChallengeFailure {
+    /// The hello did not contain a response to any challenge.
+    /// This is expected of the first hello of every handshake.
+    NoResponse,
+    /// The response was not to a challenge we issued to this address, or was tampered with.
+    InvalidMac,
+    /// The response was to a challenge that was issued too long ago.
+    Expired,
+    /// The proof of work of the response was incorrect.
+    InsufficientWork,
+    /// The challenge was already successfully responded to.
+    Replayed,
+}
+
+/// A hello that did not pass its challenge, along with the new challenge that should be sent.
+pub(crate) struct ChallengeRejection {
+    pub challenge: [u8; CHALLENGE_SIZE],
+    #[cfg_attr(not(any(feature = "logging", feature = "tracing")), allow(dead_code))]
+    pub reason: ChallengeFailure,
+    /// The age of the challenge that was responded to, if it was a valid challenge.
+    #[cfg_attr(not(any(feature = "logging", feature = "tracing")), allow(dead_code))]
+    pub age: Option<i64>,
+}
+
+/// The secrets used to key challenge MACs. They are rotated periodically by `Context::service`,
+/// and the previous key is kept so challenges issued right before a rotation can still be verified.
+struct ChallengeKeys {
+    current: [u8; SALT_SIZE],
+    previous: [u8; SALT_SIZE],
+}
+
+/// The secrets used to key stateless challenge cookies.
+/// A new secret is generated every epoch, and the secret of the previous epoch is kept so that
+/// cookies issued right before a rotation can still be verified.
+struct CookieSecrets {
+    epoch: u64,
+    current: [u8; SALT_SIZE],
+    previous: [u8; SALT_SIZE],
+}
+
+/// Tracks how many hellos a context has received recently.
+///
+/// The rate is estimated over fixed windows, linearly blending in the count of the previous window
+/// so the estimate does not drop to zero every time a new window starts.
+pub struct HelloRate {
+    window_start: AtomicI64,
+    count: AtomicU64,
+    prev_count: AtomicU64,
+}
+
+/// Encode a challenge difficulty into the most significant byte of a challenge counter.
+/// The protocol difficulty `DIFFICULTY` is encoded as 0, so peers that predate adaptive
+/// difficulty, which always send 0, are treated as requesting the minimum.
+fn encode_difficulty(difficulty: u32, counter: u64) -> u64 {
+    let d = if difficulty <= DIFFICULTY { 0 } else { difficulty.min(CHALLENGE_DIFFICULTY_LIMIT) as u64 };
+    (d << CHALLENGE_DIFFICULTY_SHIFT) | (counter & CHALLENGE_COUNTER_MASK)
+}
+/// Decode the challenge difficulty from the most significant byte of a challenge counter.
+fn decode_difficulty(counter: u64) -> u32 {
+    let d = (counter >> CHALLENGE_DIFFICULTY_SHIFT) as u32;
+    if d == 0 {
+        DIFFICULTY
+    } else {
+        d
+    }
 }
 
 /// Corresponds to Algorithm 11 found in Section 5.
@@ -26,13 +123,18 @@ pub fn respond_to_challenge_in_place(
     challenge: &[u8; CHALLENGE_SIZE],
     pre_response: &mut [u8; CHALLENGE_SIZE],
 ) {
+    let difficulty = decode_difficulty(u64::from_be_bytes(challenge[..COUNTER_SIZE].try_into().unwrap()));
+    // Refuse to burn an unbounded amount of CPU on a challenge from a misbehaving Bob.
+    if difficulty > CHALLENGE_DIFFICULTY_LIMIT {
+        return;
+    }
     if challenge[POW_START..] == pre_response[POW_START..] {
         pre_response.copy_from_slice(challenge);
         let mut pow = rng.next_u64();
         let mut work_buf = [0u8; SHA512_HASH_SIZE];
         loop {
             pre_response[POW_START..].copy_from_slice(&pow.to_ne_bytes());
-            if verify_pow(hash, pre_response, &mut work_buf) {
+            if verify_pow(hash, pre_response, difficulty, &mut work_buf) {
                 return;
             }
             pow = pow.wrapping_add(1);
@@ -41,51 +143,302 @@ pub fn respond_to_challenge_in_place(
 }
 
 impl ChallengeContext {
-    pub fn new<Rng: RngCore + CryptoRng>(rng: &mut Rng) -> Self {
-        let mut salt = [0u8; SALT_SIZE];
-        rng.fill_bytes(&mut salt);
+    /// Create a new challenge context. Challenges older than `timeout` are rejected, and
+    /// stateless cookie secrets are rotated every `cookie_lifetime`.
+    pub fn new<Rng: RngCore + CryptoRng>(rng: &mut Rng, timeout: u64, cookie_lifetime: u64) -> Self {
+        let mut keys = ChallengeKeys { current: [0u8; SALT_SIZE], previous: [0u8; SALT_SIZE] };
+        rng.fill_bytes(&mut keys.current);
+        rng.fill_bytes(&mut keys.previous);
+        let mut cookie_secrets = CookieSecrets { epoch: 0, current: [0u8; SALT_SIZE], previous: [0u8; SALT_SIZE] };
+        rng.fill_bytes(&mut cookie_secrets.current);
+        rng.fill_bytes(&mut cookie_secrets.previous);
         Self {
             counter: AtomicU64::new(0),
-            antireplay_window: Window::new(),
-            salt,
+            antireplay_window: Window::new(CHALLENGE_COUNTER_WINDOW_MAX_OOO, u64::MAX),
+            keys: RwLock::new(keys),
+            last_rotation: AtomicI64::new(i64::MIN),
+            cookie_secrets: RwLock::new(cookie_secrets),
+            cookie_lifetime: cookie_lifetime.max(1),
+            timeout: timeout.min(i64::MAX as u64) as i64,
+            address_salt: random_state(rng),
+            issued: AtomicU64::new(0),
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
         }
     }
     /// Corresponds to Algorithm 12 found in Section 5.
+    ///
+    /// A response is verified against the difficulty embedded in its own challenge, since the
+    /// MAC prevents Alice from lowering it. If a new challenge must be issued it will require a
+    /// proof of work of `difficulty` leading zero bits.
+    ///
+    /// On success the age of the challenge that was responded to is returned.
     pub fn process_hello(
         &self,
         hash: &mut impl Sha512Hash,
-        addr: &impl std::hash::Hash,
+        addr: &impl core::hash::Hash,
         response: &[u8; CHALLENGE_SIZE],
-    ) -> Result<(), [u8; CHALLENGE_SIZE]> {
+        difficulty: u32,
+        current_time: i64,
+    ) -> Result<i64, ChallengeRejection> {
+        // Holding the read lock guarantees the key cannot be rotated between verifying the
+        // response and issuing a new challenge.
+        let keys = self.keys.read();
         let c = u64::from_be_bytes(response[..COUNTER_SIZE].try_into().unwrap());
+        let mac = &response[COUNTER_SIZE..POW_START];
+        let is_auth = secure_eq(mac, &create_mac(hash, c, addr, &keys.current))
+            || secure_eq(mac, &create_mac(hash, c, addr, &keys.previous));
+        let result = if is_auth {
+            let issue_time = (c & CHALLENGE_COUNTER_MASK) >> CHALLENGE_TIME_SHIFT;
+            let age = ((current_time as u64).wrapping_sub(issue_time) & CHALLENGE_TIME_MASK) as i64;
+            // The time in the counter wraps every 2^32 milliseconds, so it cannot order challenges
+            // for the antireplay window. The sequence number wraps as well, but its full value can be
+            // recovered from the number of challenges issued so far, as long as fewer than 2^24
+            // challenges are issued within the challenge timeout.
+            let issued = self.counter.load(Ordering::Relaxed);
+            let sequence = issued.wrapping_sub(issued.wrapping_sub(c) & CHALLENGE_SEQUENCE_MASK);
+            self.verify_response(hash, response, c, age, |_| {
+                self.antireplay_window.check_and_count(sequence) && self.antireplay_window.update(sequence)
+            })
+        } else {
+            Err(self.mac_failure(response))
+        };
+        result.map_err(|(reason, age)| {
+            let sequence = self.counter.fetch_add(1, Ordering::Relaxed) & CHALLENGE_SEQUENCE_MASK;
+            let time = (current_time as u64 & CHALLENGE_TIME_MASK) << CHALLENGE_TIME_SHIFT;
+            let d = encode_difficulty(difficulty, time | sequence);
+            let mac = create_mac(hash, d, addr, &keys.current);
+            self.issue(d, &mac, response, reason, age)
+        })
+    }
+    /// A stateless variant of `process_hello`.
+    ///
+    /// The challenge is a cookie holding the time it was issued at, with a MAC over that time,
+    /// Alice's key id `kid_send` and her address, keyed by a secret that is rotated every cookie
+    /// lifetime. A response is accepted if it was issued during the current or the previous
+    /// lifetime, and is no older than the challenge timeout.
+    /// Verification needs no lookup and issuing a cookie stores nothing, so no memory is spent on
+    /// peers that have not yet proven ownership of their address.
+    ///
+    /// Unlike `process_hello` there is no replay protection, a solved cookie may be reused by the
+    /// same address and key id until it expires.
+    pub fn process_hello_stateless<Rng: RngCore + CryptoRng>(
+        &self,
+        hash: &mut impl Sha512Hash,
+        rng: &Mutex<Rng>,
+        addr: &impl core::hash::Hash,
+        kid_send: &[u8; KID_SIZE],
+        response: &[u8; CHALLENGE_SIZE],
+        difficulty: u32,
+        current_time: i64,
+    ) -> Result<i64, ChallengeRejection> {
+        let current_time = (current_time.max(0) as u64) & CHALLENGE_COUNTER_MASK;
+        self.rotate_cookie_secret(rng, current_time / self.cookie_lifetime);
+        let secrets = self.cookie_secrets.read();
+
+        let c = u64::from_be_bytes(response[..COUNTER_SIZE].try_into().unwrap());
+        let issue_time = c & CHALLENGE_COUNTER_MASK;
+        let secret = if issue_time / self.cookie_lifetime == secrets.epoch {
+            Some(&secrets.current)
+        } else if issue_time / self.cookie_lifetime + 1 == secrets.epoch {
+            Some(&secrets.previous)
+        } else {
+            None
+        };
+        let mac = secret.map(|secret| create_cookie_mac(hash, c, kid_send, addr, secret));
+        let result = match mac {
+            Some(mac) if secure_eq(&response[COUNTER_SIZE..POW_START], &mac) => {
+                let age = current_time.saturating_sub(issue_time) as i64;
+                self.verify_response(hash, response, c, age, |_| true)
+            }
+            _ => Err(self.mac_failure(response)),
+        };
+        result.map_err(|(reason, age)| {
+            let d = encode_difficulty(difficulty, current_time);
+            let mac = create_cookie_mac(hash, d, kid_send, addr, &secrets.current);
+            self.issue(d, &mac, response, reason, age)
+        })
+    }
+    /// Finish verifying a response whose MAC is valid, recording the outcome in the statistics.
+    fn verify_response(
+        &self,
+        hash: &mut impl Sha512Hash,
+        response: &[u8; CHALLENGE_SIZE],
+        c: u64,
+        age: i64,
+        check_replay: impl FnOnce(u64) -> bool,
+    ) -> Result<i64, (ChallengeFailure, Option<i64>)> {
+        if age > self.timeout {
+            self.expired.fetch_add(1, Ordering::Relaxed);
+            return Err((ChallengeFailure::Expired, Some(age)));
+        }
         let mut work_buf = [0u8; SHA512_HASH_SIZE];
-        if self.antireplay_window.check(c)
-            && secure_eq(&response[COUNTER_SIZE..POW_START], &self.create_mac(hash, c, addr))
-            && verify_pow(hash, response, &mut work_buf)
-        {
-            self.antireplay_window.update(c);
-            Ok(())
+        let reason = if !verify_pow(hash, response, decode_difficulty(c), &mut work_buf) {
+            ChallengeFailure::InsufficientWork
+        } else if !check_replay(c) {
+            ChallengeFailure::Replayed
+        } else {
+            self.succeeded.fetch_add(1, Ordering::Relaxed);
+            return Ok(age);
+        };
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        Err((reason, Some(age)))
+    }
+    /// Classify and record a response whose MAC did not match.
+    fn mac_failure(&self, response: &[u8; CHALLENGE_SIZE]) -> (ChallengeFailure, Option<i64>) {
+        // Null responses are all zero apart from their proof of work.
+        if response[..POW_START].iter().all(|b| *b == 0) {
+            (ChallengeFailure::NoResponse, None)
+        } else {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            (ChallengeFailure::InvalidMac, None)
+        }
+    }
+    /// Build a new challenge out of the counter `d` and its `mac`.
+    fn issue(
+        &self,
+        d: u64,
+        mac: &[u8; MAC_SIZE],
+        response: &[u8; CHALLENGE_SIZE],
+        reason: ChallengeFailure,
+        age: Option<i64>,
+    ) -> ChallengeRejection {
+        self.issued.fetch_add(1, Ordering::Relaxed);
+        let mut challenge = [0u8; CHALLENGE_SIZE];
+        challenge[..COUNTER_SIZE].copy_from_slice(&d.to_be_bytes());
+        challenge[COUNTER_SIZE..POW_START].copy_from_slice(mac);
+        challenge[POW_START..].copy_from_slice(&response[POW_START..]);
+        ChallengeRejection { challenge, reason, age }
+    }
+    /// Get the statistics recorded so far by this challenge context.
+    pub fn stats(&self) -> ChallengeStats {
+        ChallengeStats {
+            issued: self.issued.load(Ordering::Relaxed),
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+    /// A salted hash of `addr`, so that logs can correlate the hellos of one address without
+    /// recording the address itself.
+    pub fn address_hash(&self, addr: &impl core::hash::Hash) -> u64 {
+        self.address_salt.hash_one(addr)
+    }
+    /// Rotate the challenge key if at least the challenge timeout has passed since it was last
+    /// rotated. Challenges remain valid for one rotation, so rotating any sooner would invalidate
+    /// challenges before they expire.
+    /// Returns the time at which the key should next be rotated, or `i64::MAX` if no challenge has
+    /// been issued yet, in which case there is nothing to protect.
+    pub fn service<Rng: RngCore + CryptoRng>(&self, rng: &Mutex<Rng>, current_time: i64) -> i64 {
+        let interval = self.timeout;
+        let last_rotation = self.last_rotation.load(Ordering::Relaxed);
+        if last_rotation == i64::MIN {
+            // Start the rotation schedule from the first call.
+            let _ = self.last_rotation.compare_exchange(i64::MIN, current_time, Ordering::Relaxed, Ordering::Relaxed);
+        } else if current_time.saturating_sub(last_rotation) >= interval {
+            // Only one thread can win the exchange, so only one rotation occurs per interval.
+            let exchange = self.last_rotation.compare_exchange(
+                last_rotation,
+                current_time,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            if exchange.is_ok() {
+                let mut keys = self.keys.write();
+                keys.previous = keys.current;
+                rng.lock().fill_bytes(&mut keys.current);
+            }
+        }
+        if self.issued.load(Ordering::Relaxed) == 0 {
+            i64::MAX
         } else {
-            let mut challenge = [0u8; CHALLENGE_SIZE];
-            let d = self.counter.fetch_add(1, Ordering::Relaxed);
-            challenge[..COUNTER_SIZE].copy_from_slice(&d.to_be_bytes());
-            challenge[COUNTER_SIZE..POW_START].copy_from_slice(&self.create_mac(hash, d, addr));
-            challenge[POW_START..].copy_from_slice(&response[POW_START..]);
-            Err(challenge)
+            self.last_rotation.load(Ordering::Relaxed).saturating_add(interval)
+        }
+    }
+    /// Replace the cookie secrets if `epoch` is newer than the epoch they were generated for.
+    fn rotate_cookie_secret<Rng: RngCore + CryptoRng>(&self, rng: &Mutex<Rng>, epoch: u64) {
+        if self.cookie_secrets.read().epoch >= epoch {
+            return;
+        }
+        let mut secrets = self.cookie_secrets.write();
+        if secrets.epoch < epoch {
+            // If epochs were skipped the previous secret will be labeled with the wrong epoch.
+            // This is harmless since no cookie was ever issued under that label.
+            secrets.previous = secrets.current;
+            rng.lock().fill_bytes(&mut secrets.current);
+            secrets.epoch = epoch;
         }
     }
-    fn create_mac(&self, hash: &mut impl Sha512Hash, c: u64, addr: &impl std::hash::Hash) -> [u8; MAC_SIZE] {
-        let mut hasher = ShaHasher(hash);
-        hasher.write(&c.to_be_bytes());
-        addr.hash(&mut hasher);
-        hasher.write(&self.salt);
+}
 
-        let mut mac = [0u8; SHA512_HASH_SIZE];
-        hash.finish_and_reset(&mut mac);
-        mac[..MAC_SIZE].try_into().unwrap()
+impl HelloRate {
+    pub fn new() -> Self {
+        Self { window_start: AtomicI64::new(i64::MIN), count: AtomicU64::new(0), prev_count: AtomicU64::new(0) }
+    }
+    /// Record that a hello was received at `current_time`, and return the estimated number of
+    /// hellos received over the last `window` units of time, including this one.
+    ///
+    /// Concurrent callers may race when a window rolls over, which can lose a handful of counts.
+    /// This is acceptable since the result is only an estimate.
+    pub fn record(&self, current_time: i64, window: i64) -> u64 {
+        let window = window.max(1);
+        let mut start = self.window_start.load(Ordering::Relaxed);
+        let elapsed = current_time.saturating_sub(start);
+        if elapsed >= window {
+            // Windows are aligned to the first hello ever recorded.
+            let new_start = if start == i64::MIN { current_time } else { current_time - elapsed % window };
+            if self
+                .window_start
+                .compare_exchange(start, new_start, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            {
+                // If more than one window has passed the previous window received nothing.
+                let prev = if elapsed < window.saturating_mul(2) { self.count.load(Ordering::Relaxed) } else { 0 };
+                self.prev_count.store(prev, Ordering::Relaxed);
+                self.count.store(0, Ordering::Relaxed);
+            }
+            start = self.window_start.load(Ordering::Relaxed);
+        }
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let remaining = (window - current_time.saturating_sub(start).clamp(0, window)) as u64;
+        count + self.prev_count.load(Ordering::Relaxed) * remaining / window as u64
     }
 }
 
+fn create_mac(
+    hash: &mut impl Sha512Hash,
+    c: u64,
+    addr: &impl core::hash::Hash,
+    key: &[u8; SALT_SIZE],
+) -> [u8; MAC_SIZE] {
+    let mut hasher = ShaHasher(hash);
+    hasher.write(&c.to_be_bytes());
+    addr.hash(&mut hasher);
+    hasher.write(key);
+
+    let mut mac = [0u8; SHA512_HASH_SIZE];
+    hash.finish_and_reset(&mut mac);
+    mac[..MAC_SIZE].try_into().unwrap()
+}
+fn create_cookie_mac(
+    hash: &mut impl Sha512Hash,
+    c: u64,
+    kid_send: &[u8; KID_SIZE],
+    addr: &impl core::hash::Hash,
+    secret: &[u8; SALT_SIZE],
+) -> [u8; MAC_SIZE] {
+    let mut hasher = ShaHasher(hash);
+    hasher.write(&c.to_be_bytes());
+    hasher.write(kid_send);
+    addr.hash(&mut hasher);
+    hasher.write(secret);
+
+    let mut mac = [0u8; SHA512_HASH_SIZE];
+    hash.finish_and_reset(&mut mac);
+    mac[..MAC_SIZE].try_into().unwrap()
+}
+
 /// Trick rust into letting us use a hasher that returns more than 64 bits.
 struct ShaHasher<'a, ShaImpl: Sha512Hash>(&'a mut ShaImpl);
 impl<'a, ShaImpl: Sha512Hash> Hasher for ShaHasher<'a, ShaImpl> {
@@ -99,9 +452,187 @@ impl<'a, ShaImpl: Sha512Hash> Hasher for ShaHasher<'a, ShaImpl> {
 
 /// Check if the proof of work attached to the first message contains the correct number of leading
 /// zeros.
-fn verify_pow(hash: &mut impl Sha512Hash, response: &[u8], work_buf: &mut [u8; SHA512_HASH_SIZE]) -> bool {
+fn verify_pow(
+    hash: &mut impl Sha512Hash,
+    response: &[u8],
+    difficulty: u32,
+    work_buf: &mut [u8; SHA512_HASH_SIZE],
+) -> bool {
     hash.update(response);
     hash.finish_and_reset(work_buf);
-    let n = u32::from_be_bytes(work_buf[..4].try_into().unwrap());
-    n.leading_zeros() >= DIFFICULTY
+    let n = u64::from_be_bytes(work_buf[..8].try_into().unwrap());
+    n.leading_zeros() >= difficulty
+}
+
+#[test]
+fn test_challenge_difficulty() {
+    use crate::crypto_impl::CrateSha512;
+    let rng = &mut rand_core::OsRng;
+    let hash = &mut CrateSha512::new();
+    let ctx = ChallengeContext::new(rng, 1000, 1000);
+    let addr = "10.0.0.1:9993";
+
+    for difficulty in [DIFFICULTY, 16] {
+        let null_response = gen_null_response(rng);
+        let rejection = ctx.process_hello(hash, &addr, &null_response, difficulty, 0).err().unwrap();
+        assert_eq!(rejection.reason, ChallengeFailure::NoResponse);
+        let challenge = rejection.challenge;
+        let c = u64::from_be_bytes(challenge[..COUNTER_SIZE].try_into().unwrap());
+        assert_eq!(decode_difficulty(c), difficulty);
+        // Peers without adaptive difficulty always receive a counter with no difficulty byte.
+        assert_eq!(c >> CHALLENGE_DIFFICULTY_SHIFT == 0, difficulty == DIFFICULTY);
+
+        let mut response = null_response;
+        respond_to_challenge_in_place(rng, hash, &challenge, &mut response);
+        let mut work_buf = [0u8; SHA512_HASH_SIZE];
+        assert!(verify_pow(hash, &response, difficulty, &mut work_buf));
+        // The response is accepted even though the current difficulty has since increased.
+        assert_eq!(ctx.process_hello(hash, &addr, &response, CHALLENGE_DIFFICULTY_LIMIT, 10).ok(), Some(10));
+        let rejection = ctx.process_hello(hash, &addr, &response, difficulty, 10).err().unwrap();
+        assert_eq!(rejection.reason, ChallengeFailure::Replayed);
+    }
+    // Raising the encoded difficulty invalidates the MAC.
+    let challenge = ctx.process_hello(hash, &addr, &gen_null_response(rng), 16, 0).err().unwrap().challenge;
+    let mut response = challenge;
+    response[0] = DIFFICULTY as u8;
+    let rejection = ctx.process_hello(hash, &addr, &response, 16, 0).err().unwrap();
+    assert_eq!(rejection.reason, ChallengeFailure::InvalidMac);
+    response[0] = 0;
+    assert!(ctx.process_hello(hash, &addr, &response, 16, 0).is_err());
+
+    assert_eq!(ctx.stats(), ChallengeStats { issued: 7, succeeded: 2, failed: 4, expired: 0 });
+}
+
+#[test]
+fn test_challenge_expiry() {
+    use crate::crypto_impl::CrateSha512;
+    let rng = &mut rand_core::OsRng;
+    let hash = &mut CrateSha512::new();
+    let ctx = ChallengeContext::new(rng, 1000, 1000);
+    let addr = "10.0.0.1:9993";
+
+    let null_response = gen_null_response(rng);
+    let challenge = ctx.process_hello(hash, &addr, &null_response, DIFFICULTY, 5000).err().unwrap().challenge;
+    let mut response = null_response;
+    respond_to_challenge_in_place(rng, hash, &challenge, &mut response);
+    let rejection = ctx.process_hello(hash, &addr, &response, DIFFICULTY, 6001).err().unwrap();
+    assert_eq!((rejection.reason, rejection.age), (ChallengeFailure::Expired, Some(1001)));
+    assert_eq!(ctx.process_hello(hash, &addr, &response, DIFFICULTY, 6000).ok(), Some(1000));
+    assert_eq!(ctx.stats().expired, 1);
+}
+
+#[test]
+fn test_challenge_counter_wrap() {
+    use crate::crypto_impl::CrateSha512;
+    let rng = &mut rand_core::OsRng;
+    let hash = &mut CrateSha512::new();
+    let ctx = ChallengeContext::new(rng, 1000, 1000);
+    let addr = "10.0.0.1:9993";
+    let solve = |rng: &mut rand_core::OsRng, hash: &mut CrateSha512, time: i64| {
+        let null_response = gen_null_response(rng);
+        let challenge = ctx.process_hello(hash, &addr, &null_response, DIFFICULTY, time).err().unwrap().challenge;
+        let mut response = null_response;
+        respond_to_challenge_in_place(rng, hash, &challenge, &mut response);
+        response
+    };
+    // Challenges issued on either side of the low 32 bits of the time wrapping.
+    let wrap = 1i64 << 32;
+    let before = solve(rng, hash, wrap - 5);
+    let after = solve(rng, hash, wrap + 5);
+    assert_eq!(ctx.process_hello(hash, &addr, &before, DIFFICULTY, wrap + 10).ok(), Some(15));
+    assert_eq!(ctx.process_hello(hash, &addr, &after, DIFFICULTY, wrap + 10).ok(), Some(5));
+    let rejection = ctx.process_hello(hash, &addr, &after, DIFFICULTY, wrap + 10).err().unwrap();
+    assert_eq!(rejection.reason, ChallengeFailure::Replayed);
+    let rejection = ctx.process_hello(hash, &addr, &before, DIFFICULTY, wrap + 996).err().unwrap();
+    assert_eq!((rejection.reason, rejection.age), (ChallengeFailure::Expired, Some(1001)));
+
+    // Challenges issued on either side of the sequence number wrapping.
+    ctx.counter.store(CHALLENGE_SEQUENCE_MASK, Ordering::Relaxed);
+    let before = solve(rng, hash, 2 * wrap);
+    let after = solve(rng, hash, 2 * wrap);
+    assert!(ctx.process_hello(hash, &addr, &before, DIFFICULTY, 2 * wrap).is_ok());
+    assert!(ctx.process_hello(hash, &addr, &after, DIFFICULTY, 2 * wrap).is_ok());
+    let rejection = ctx.process_hello(hash, &addr, &before, DIFFICULTY, 2 * wrap).err().unwrap();
+    assert_eq!(rejection.reason, ChallengeFailure::Replayed);
+}
+
+#[test]
+fn test_hello_rate() {
+    let rate = HelloRate::new();
+    for i in 0..100 {
+        assert_eq!(rate.record(i * 10, 1000), i as u64 + 1);
+    }
+    // Halfway through the next window half of the previous window's count is still remembered.
+    assert_eq!(rate.record(1500, 1000), 51);
+    // After two idle windows the estimate starts over.
+    assert_eq!(rate.record(4000, 1000), 1);
+}
+
+#[test]
+fn test_stateless_challenge() {
+    use crate::crypto_impl::CrateSha512;
+    let rng = Mutex::new(rand_core::OsRng);
+    let hash = &mut CrateSha512::new();
+    let ctx = ChallengeContext::new(&mut *rng.lock(), 1000, 1000);
+    let addr = "10.0.0.1:9993";
+    let kid = 1234u32.to_ne_bytes();
+
+    let null_response = gen_null_response(&mut *rng.lock());
+    let challenge = ctx
+        .process_hello_stateless(hash, &rng, &addr, &kid, &null_response, DIFFICULTY, 5500)
+        .err()
+        .unwrap()
+        .challenge;
+    let mut response = null_response;
+    respond_to_challenge_in_place(&mut *rng.lock(), hash, &challenge, &mut response);
+    // Cookies are bound to the address and key id they were issued to.
+    let other_addr = "10.0.0.2:9993";
+    assert!(ctx.process_hello_stateless(hash, &rng, &other_addr, &kid, &response, DIFFICULTY, 5500).is_err());
+    let other_kid = 4321u32.to_ne_bytes();
+    assert!(ctx.process_hello_stateless(hash, &rng, &addr, &other_kid, &response, DIFFICULTY, 5500).is_err());
+    // A cookie remains valid after its secret is rotated, until it expires.
+    assert!(ctx.process_hello_stateless(hash, &rng, &addr, &kid, &response, DIFFICULTY, 5500).is_ok());
+    assert!(ctx.process_hello_stateless(hash, &rng, &addr, &kid, &response, DIFFICULTY, 6500).is_ok());
+    let rejection = ctx
+        .process_hello_stateless(hash, &rng, &addr, &kid, &response, DIFFICULTY, 6501)
+        .err()
+        .unwrap();
+    assert_eq!(rejection.reason, ChallengeFailure::Expired);
+    // After two rotations the secret it was issued under is gone.
+    let rejection = ctx
+        .process_hello_stateless(hash, &rng, &addr, &kid, &response, DIFFICULTY, 7000)
+        .err()
+        .unwrap();
+    assert_eq!(rejection.reason, ChallengeFailure::InvalidMac);
+}
+
+#[test]
+fn test_challenge_key_rotation() {
+    use crate::crypto_impl::CrateSha512;
+    let rng = Mutex::new(rand_core::OsRng);
+    let hash = &mut CrateSha512::new();
+    let ctx = ChallengeContext::new(&mut *rng.lock(), 100, 1000);
+    let addr = "10.0.0.1:9993";
+    assert_eq!(ctx.service(&rng, 0), i64::MAX);
+
+    let mut responses = Vec::new();
+    for time in [0, 99] {
+        let null_response = gen_null_response(&mut *rng.lock());
+        let challenge = ctx.process_hello(hash, &addr, &null_response, DIFFICULTY, time).err().unwrap().challenge;
+        let mut response = null_response;
+        respond_to_challenge_in_place(&mut *rng.lock(), hash, &challenge, &mut response);
+        responses.push(response);
+    }
+    // The key is rotated at most once per challenge timeout.
+    assert_eq!(ctx.service(&rng, 99), 100);
+    assert_eq!(ctx.service(&rng, 100), 200);
+    assert_eq!(ctx.service(&rng, 150), 200);
+    // Challenges issued under the previous key remain valid until they expire.
+    assert_eq!(ctx.process_hello(hash, &addr, &responses[0], DIFFICULTY, 100).ok(), Some(100));
+    assert_eq!(ctx.service(&rng, 199), 200);
+    assert_eq!(ctx.process_hello(hash, &addr, &responses[1], DIFFICULTY, 199).ok(), Some(100));
+    // After a second rotation they are gone.
+    assert_eq!(ctx.service(&rng, 200), 300);
+    let rejection = ctx.process_hello(hash, &addr, &responses[1], DIFFICULTY, 200).err().unwrap();
+    assert_eq!(rejection.reason, ChallengeFailure::InvalidMac);
 }
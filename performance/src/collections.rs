@@ -0,0 +1,36 @@
+use rand_core::RngCore;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::{hash_map, HashMap, HashSet};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{hash_map, hash_map::RandomState, HashMap, HashSet};
+
+/// Create a new random salt for hashing attacker controlled values such as remote addresses.
+///
+/// With `std` the keys come from the OS like those of every `std` hash map, so `rng` is unused.
+#[cfg(feature = "std")]
+pub(crate) fn random_state(_rng: &mut impl RngCore) -> RandomState {
+    RandomState::new()
+}
+/// Create a new random salt for hashing attacker controlled values such as remote addresses.
+#[cfg(not(feature = "std"))]
+pub(crate) fn random_state(rng: &mut impl RngCore) -> RandomState {
+    RandomState { k0: rng.next_u64(), k1: rng.next_u64() }
+}
+
+/// A stand-in for `std::collections::hash_map::RandomState`, which is also keyed SipHash.
+/// Without an OS to seed it the keys are taken from the RNG of the context instead.
+#[cfg(not(feature = "std"))]
+#[derive(Clone)]
+pub(crate) struct RandomState {
+    k0: u64,
+    k1: u64,
+}
+#[cfg(not(feature = "std"))]
+#[allow(deprecated)]
+impl core::hash::BuildHasher for RandomState {
+    type Hasher = core::hash::SipHasher;
+    fn build_hasher(&self) -> Self::Hasher {
+        core::hash::SipHasher::new_with_keys(self.k0, self.k1)
+    }
+}
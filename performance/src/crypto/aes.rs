@@ -29,6 +29,8 @@ pub trait Aes256Enc: Sized + Send + Sync {
 }
 
 /// A trait for decrypting individual blocks of plaintext using AES-256.
+/// It is the inverse of `Aes256Enc`, used to decrypt and authenticate the headers of received
+/// fragments.
 ///
 /// Instances must securely delete their keys when dropped or reset.
 pub trait Aes256Dec: Sized + Send + Sync {
@@ -79,12 +81,12 @@ pub trait HighThroughputAesGcmPool: Send + Sync {
 
     /// Borrow an encryption context to be used to stream encrypt a message.
     /// `nonce` must be set as the AEAD nonce.
-    /// There is no additional associated data to be used.
-    fn start_enc<'a>(&'a self, nonce: &[u8; AES_GCM_NONCE_SIZE]) -> Self::EncContext<'a>;
+    /// `aad` must be used as the additional associated data, it is usually empty.
+    fn start_enc<'a>(&'a self, nonce: &[u8; AES_GCM_NONCE_SIZE], aad: &[u8]) -> Self::EncContext<'a>;
     /// Borrow a decryption context to be used to stream decrypt a message.
     /// `nonce` must be set as the AEAD nonce.
-    /// There is no additional associated data to be used.
-    fn start_dec<'a>(&'a self, nonce: &[u8; AES_GCM_NONCE_SIZE]) -> Self::DecContext<'a>;
+    /// `aad` must be used as the additional associated data, it is usually empty.
+    fn start_dec<'a>(&'a self, nonce: &[u8; AES_GCM_NONCE_SIZE], aad: &[u8]) -> Self::DecContext<'a>;
 
     /// Stream-encrypt `input` using the specified encryption context `enc`, and write the
     /// resulting ciphertext to `output`.
@@ -120,6 +122,45 @@ pub trait HighThroughputAesGcmPool: Send + Sync {
     /// Be sure to perform your own benchmark.
     #[must_use]
     fn finish_dec<'a>(&'a self, dec: Self::DecContext<'a>, tag: &[u8; AES_GCM_TAG_SIZE]) -> bool;
+
+    /// Whether this pool also implements `encrypt_siv` and `decrypt_siv` with a nonce misuse
+    /// resistant AEAD, such as AES-GCM-SIV (RFC 8452).
+    ///
+    /// If the AES-GCM nonce of a key is ever reused, for example because a VM snapshot was restored
+    /// and a send counter replayed, an observer learns the XOR of the two plaintexts and can forge
+    /// packets. A misuse resistant AEAD only leaks whether the two plaintexts were equal.
+    /// Sessions use it instead of AES-GCM when both peers can, see
+    /// `ApplicationLayer::misuse_resistance`.
+    const MISUSE_RESISTANT: bool = false;
+
+    /// Encrypt `data` in place with the nonce misuse resistant AEAD, using the encryption key,
+    /// and return the resulting authentication tag.
+    /// `nonce` and `aad` must be used exactly as they are by `start_enc`.
+    ///
+    /// This is only called if `MISUSE_RESISTANT` is true. The default implementation fails
+    /// closed: it erases `data` and returns an all zero tag, which `decrypt_siv` never accepts.
+    #[allow(unused)]
+    fn encrypt_siv(&self, nonce: &[u8; AES_GCM_NONCE_SIZE], aad: &[u8], data: &mut [u8]) -> [u8; AES_GCM_TAG_SIZE] {
+        data.fill(0);
+        [0; AES_GCM_TAG_SIZE]
+    }
+    /// Decrypt `data` in place with the nonce misuse resistant AEAD, using the decryption key,
+    /// and check in constant-time that the expected authentication tag matches `tag`.
+    /// Output `true` only if `tag` is correct.
+    ///
+    /// This is only called if `MISUSE_RESISTANT` is true. The default implementation fails
+    /// closed and rejects every packet.
+    #[allow(unused)]
+    #[must_use]
+    fn decrypt_siv(
+        &self,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; AES_GCM_TAG_SIZE],
+    ) -> bool {
+        false
+    }
 }
 
 /// A trait for implementing AES-GCM-256 to handle the more varied, but much lower throughput
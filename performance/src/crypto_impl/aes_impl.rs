@@ -0,0 +1,283 @@
+use aes::cipher::{BlockDecrypt, BlockEncrypt, InnerIvInit, KeyInit, StreamCipher};
+use aes::Aes256;
+use ghash::universal_hash::UniversalHash;
+use ghash::GHash;
+use zeroize::Zeroizing;
+
+use crate::crypto::*;
+
+type Block = aes::Block;
+type Ctr = ctr::Ctr32BE<Aes256>;
+
+/// A type that implements `Aes256Enc` using the pure Rust aes crate.
+///
+/// Unlike the OpenSSL implementations this compiles to targets without a C toolchain,
+/// such as `wasm32-unknown-unknown`.
+pub struct CrateAes256Enc(Aes256);
+impl Aes256Enc for CrateAes256Enc {
+    fn new(key: &[u8; AES_256_KEY_SIZE]) -> Self {
+        Self(Aes256::new(key.into()))
+    }
+
+    fn encrypt_in_place(&self, block: &mut [u8; AES_256_BLOCK_SIZE]) {
+        self.0.encrypt_block(block.into())
+    }
+}
+/// A type that implements `Aes256Dec` using the pure Rust aes crate.
+pub struct CrateAes256Dec(Aes256);
+impl Aes256Dec for CrateAes256Dec {
+    fn new(key: &[u8; AES_256_KEY_SIZE]) -> Self {
+        Self(Aes256::new(key.into()))
+    }
+
+    fn decrypt_in_place(&self, block: &mut [u8; AES_256_BLOCK_SIZE]) {
+        self.0.decrypt_block(block.into())
+    }
+}
+
+/// The state needed to stream a single message through AES-GCM, built from the aes, ctr and
+/// ghash crates.
+/// Users are encouraged to not use one of these directly.
+pub struct CrateAesGcmStream {
+    ctr: Ctr,
+    ghash: GHash,
+    /// The encryption of the initial counter block, which masks the GHASH output.
+    tag_mask: Zeroizing<Block>,
+    /// Ciphertext that has not yet filled a whole GHASH block.
+    partial: Zeroizing<Block>,
+    partial_len: usize,
+    aad_len: u64,
+    data_len: u64,
+}
+impl CrateAesGcmStream {
+    fn new(cipher: &Aes256, ghash: GHash, nonce: &[u8; AES_GCM_NONCE_SIZE]) -> Self {
+        let mut counter = Block::default();
+        counter[..AES_GCM_NONCE_SIZE].copy_from_slice(nonce);
+        counter[15] = 1;
+        let mut tag_mask = Zeroizing::new(counter);
+        cipher.encrypt_block(&mut tag_mask);
+        counter[15] = 2;
+        Self {
+            ctr: Ctr::from_core(ctr::CtrCore::inner_iv_init(cipher.clone(), &counter)),
+            ghash,
+            tag_mask,
+            partial: Zeroizing::new(Block::default()),
+            partial_len: 0,
+            aad_len: 0,
+            data_len: 0,
+        }
+    }
+    /// Authenticate `aad`, which must happen before any data is streamed.
+    fn aad(&mut self, aad: &[u8]) {
+        self.ghash.update_padded(aad);
+        self.aad_len = aad.len() as u64;
+    }
+    /// Add ciphertext to the authentication tag, buffering whatever does not fill a whole block.
+    fn authenticate(&mut self, mut data: &[u8]) {
+        self.data_len += data.len() as u64;
+        if self.partial_len > 0 {
+            let n = (self.partial.len() - self.partial_len).min(data.len());
+            self.partial[self.partial_len..self.partial_len + n].copy_from_slice(&data[..n]);
+            self.partial_len += n;
+            data = &data[n..];
+            if self.partial_len < self.partial.len() {
+                return;
+            }
+            self.ghash.update(&[*self.partial]);
+            self.partial_len = 0;
+        }
+        let (blocks, rest) = data.split_at(data.len() - data.len() % self.partial.len());
+        self.ghash.update_padded(blocks);
+        self.partial[..rest.len()].copy_from_slice(rest);
+        self.partial_len = rest.len();
+    }
+    fn encrypt(&mut self, input: &[u8], output: &mut [u8]) {
+        self.ctr.apply_keystream_b2b(input, output).unwrap();
+        self.authenticate(output);
+    }
+    fn decrypt_in_place(&mut self, data: &mut [u8]) {
+        self.authenticate(data);
+        self.ctr.apply_keystream(data);
+    }
+    /// Pad out the last block and add the lengths, leaving only the mask to be applied.
+    fn finish_ghash(mut self) -> (GHash, Zeroizing<Block>) {
+        if self.partial_len > 0 {
+            self.ghash.update_padded(&self.partial[..self.partial_len]);
+        }
+        let mut lengths = Block::default();
+        lengths[..8].copy_from_slice(&(self.aad_len * 8).to_be_bytes());
+        lengths[8..].copy_from_slice(&(self.data_len * 8).to_be_bytes());
+        self.ghash.update(&[lengths]);
+        (self.ghash, self.tag_mask)
+    }
+    fn finish_enc(self) -> [u8; AES_GCM_TAG_SIZE] {
+        let (ghash, tag_mask) = self.finish_ghash();
+        let mut tag: [u8; AES_GCM_TAG_SIZE] = ghash.finalize().into();
+        for (t, m) in tag.iter_mut().zip(tag_mask.iter()) {
+            *t ^= m;
+        }
+        tag
+    }
+    fn finish_dec(self, tag: &[u8; AES_GCM_TAG_SIZE]) -> bool {
+        let (ghash, mut expected) = self.finish_ghash();
+        // Unmasking `tag` instead of masking the GHASH output lets `verify` do the constant-time compare.
+        for (e, t) in expected.iter_mut().zip(tag) {
+            *e ^= t;
+        }
+        ghash.verify(&expected).is_ok()
+    }
+}
+/// Create the GHASH instance keyed by `cipher`, which can be cloned for every message.
+fn new_ghash(cipher: &Aes256) -> GHash {
+    let mut h = Zeroizing::new(Block::default());
+    cipher.encrypt_block(&mut h);
+    GHash::new(&h)
+}
+
+/// A type that implements `HighThroughputAesGcmPool` using the pure Rust aes, ctr and ghash
+/// crates.
+///
+/// The expanded keys are computed once, so starting a message only has to copy them.
+pub struct CrateAesGcmPool {
+    enc: Aes256,
+    dec: Aes256,
+    enc_ghash: GHash,
+    dec_ghash: GHash,
+}
+impl HighThroughputAesGcmPool for CrateAesGcmPool {
+    type EncContext<'a> = CrateAesGcmStream;
+
+    type DecContext<'a> = CrateAesGcmStream;
+
+    fn new(encrypt_key: &[u8; AES_256_KEY_SIZE], decrypt_key: &[u8; AES_256_KEY_SIZE]) -> Self {
+        let enc = Aes256::new(encrypt_key.into());
+        let dec = Aes256::new(decrypt_key.into());
+        Self {
+            enc_ghash: new_ghash(&enc),
+            dec_ghash: new_ghash(&dec),
+            enc,
+            dec,
+        }
+    }
+
+    fn start_enc(&self, nonce: &[u8; AES_GCM_NONCE_SIZE], aad: &[u8]) -> CrateAesGcmStream {
+        let mut ctx = CrateAesGcmStream::new(&self.enc, self.enc_ghash.clone(), nonce);
+        ctx.aad(aad);
+        ctx
+    }
+    fn start_dec(&self, nonce: &[u8; AES_GCM_NONCE_SIZE], aad: &[u8]) -> CrateAesGcmStream {
+        let mut ctx = CrateAesGcmStream::new(&self.dec, self.dec_ghash.clone(), nonce);
+        ctx.aad(aad);
+        ctx
+    }
+
+    fn encrypt(&self, ctx: &mut CrateAesGcmStream, input: &[u8], output: &mut [u8]) {
+        ctx.encrypt(input, output);
+    }
+    fn decrypt_in_place(&self, ctx: &mut CrateAesGcmStream, data: &mut [u8]) {
+        ctx.decrypt_in_place(data);
+    }
+
+    fn finish_enc(&self, ctx: CrateAesGcmStream) -> [u8; AES_GCM_TAG_SIZE] {
+        ctx.finish_enc()
+    }
+    fn finish_dec(&self, ctx: CrateAesGcmStream, tag: &[u8; AES_GCM_TAG_SIZE]) -> bool {
+        ctx.finish_dec(tag)
+    }
+}
+/// An empty struct which implements `LowThroughputAesGcm` using the pure Rust aes, ctr and
+/// ghash crates.
+pub struct CrateAesGcm;
+impl LowThroughputAesGcm for CrateAesGcm {
+    fn encrypt_in_place(
+        key: &[u8; AES_256_KEY_SIZE],
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> [u8; AES_GCM_TAG_SIZE] {
+        let cipher = Aes256::new(key.into());
+        let mut ctx = CrateAesGcmStream::new(&cipher, new_ghash(&cipher), nonce);
+        ctx.aad(aad);
+        ctx.ctr.apply_keystream(data);
+        ctx.authenticate(data);
+        ctx.finish_enc()
+    }
+
+    fn decrypt_in_place(
+        key: &[u8; AES_256_KEY_SIZE],
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; AES_GCM_TAG_SIZE],
+    ) -> bool {
+        let cipher = Aes256::new(key.into());
+        let mut ctx = CrateAesGcmStream::new(&cipher, new_ghash(&cipher), nonce);
+        ctx.aad(aad);
+        ctx.decrypt_in_place(data);
+        ctx.finish_dec(tag)
+    }
+}
+
+#[cfg(all(test, feature = "openssl-sys"))]
+mod test {
+    use super::*;
+    use crate::crypto_impl::{OpenSSLAes256Enc, OpenSSLAesGcm, OpenSSLAesGcmPool};
+
+    #[test]
+    fn matches_openssl() {
+        let key = [7u8; AES_256_KEY_SIZE];
+        let nonce = [3u8; AES_GCM_NONCE_SIZE];
+        let mut block = [5u8; AES_256_BLOCK_SIZE];
+        let mut expected = block;
+        CrateAes256Enc::new(&key).encrypt_in_place(&mut block);
+        OpenSSLAes256Enc::new(&key).encrypt_in_place(&mut expected);
+        assert_eq!(block, expected);
+        CrateAes256Dec::new(&key).decrypt_in_place(&mut block);
+        assert_eq!(block, [5u8; AES_256_BLOCK_SIZE]);
+
+        let plaintext: Vec<u8> = (0..200u8).collect();
+        for len in [0, 1, 15, 16, 17, 100, 200] {
+            let aad = &plaintext[..len / 3];
+            let mut data = plaintext[..len].to_vec();
+            let mut expected = data.clone();
+            let tag = CrateAesGcm::encrypt_in_place(&key, &nonce, aad, &mut data);
+            let expected_tag = OpenSSLAesGcm::encrypt_in_place(&key, &nonce, aad, &mut expected);
+            assert_eq!((&data, tag), (&expected, expected_tag));
+            assert!(!CrateAesGcm::decrypt_in_place(
+                &key,
+                &nonce,
+                &[1],
+                &mut data.clone(),
+                &tag
+            ));
+            assert!(CrateAesGcm::decrypt_in_place(&key, &nonce, aad, &mut data, &tag));
+            assert_eq!(data, &plaintext[..len]);
+
+            // Stream in uneven chunks so the partial GHASH block is carried between calls.
+            let pool = CrateAesGcmPool::new(&key, &key);
+            let openssl = OpenSSLAesGcmPool::new(&key, &key);
+            let mut ctx = pool.start_enc(&nonce, aad);
+            let mut expected_ctx = openssl.start_enc(&nonce, aad);
+            let mut output = vec![0u8; len];
+            let mut expected = vec![0u8; len];
+            let mut i = 0;
+            while i < len {
+                let end = (i + 7).min(len);
+                pool.encrypt(&mut ctx, &plaintext[i..end], &mut output[i..end]);
+                openssl.encrypt(&mut expected_ctx, &plaintext[i..end], &mut expected[i..end]);
+                i = end;
+            }
+            let tag = pool.finish_enc(ctx);
+            assert_eq!((&output, tag), (&expected, openssl.finish_enc(expected_ctx)));
+            let mut ctx = pool.start_dec(&nonce, &[1]);
+            pool.decrypt_in_place(&mut ctx, &mut output.clone());
+            assert!(!pool.finish_dec(ctx, &tag));
+            let mut ctx = pool.start_dec(&nonce, aad);
+            for chunk in output.chunks_mut(5) {
+                pool.decrypt_in_place(&mut ctx, chunk);
+            }
+            assert!(pool.finish_dec(ctx, &tag));
+            assert_eq!(output, &plaintext[..len]);
+        }
+    }
+}
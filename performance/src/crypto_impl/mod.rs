@@ -28,6 +28,20 @@ pub use sha2;
 #[cfg(feature = "sha2")]
 pub use sha512::*;
 
+#[cfg(feature = "aes")]
+mod aes_impl;
+#[cfg(feature = "aes")]
+pub use aes;
+#[cfg(feature = "aes")]
+pub use aes_impl::*;
+
+#[cfg(feature = "gcm-siv")]
+mod gcm_siv;
+#[cfg(feature = "gcm-siv")]
+pub use aes_gcm_siv;
+#[cfg(feature = "gcm-siv")]
+pub use gcm_siv::*;
+
 #[cfg(feature = "openssl-sys")]
 mod openssl;
 #[cfg(feature = "openssl-sys")]
@@ -44,7 +58,7 @@ pub use openssl_sys;
 pub trait DefaultCrypto {
     /// Type for arbitrary opaque object for use by the application that is attached to
     /// each session.
-    type SessionData;
+    type SessionData: crate::application::SessionMetadata;
     /// Data type for incoming packet buffers.
     ///
     /// This can be something like `Vec<u8>` or `Box<[u8]>` or it can be something like a pooled
@@ -52,6 +66,12 @@ pub trait DefaultCrypto {
     /// hold these for a short period of time when assembling fragmented packets on the receive
     /// path.
     type IncomingPacketBuffer: AsMut<[u8]> + AsRef<[u8]>;
+
+    /// The metadata of a session with `session_data` attached, see `CryptoLayer::session_info`.
+    fn session_info(session_data: &Self::SessionData) -> Option<&dyn crate::application::SessionInfo> {
+        let _ = session_data;
+        None
+    }
 }
 #[cfg(feature = "default-crypto")]
 impl<C: DefaultCrypto> crate::application::CryptoLayer for C {
@@ -69,4 +89,8 @@ impl<C: DefaultCrypto> crate::application::CryptoLayer for C {
 
     type SessionData = C::SessionData;
     type IncomingPacketBuffer = C::IncomingPacketBuffer;
+
+    fn session_info(session_data: &Self::SessionData) -> Option<&dyn crate::application::SessionInfo> {
+        C::session_info(session_data)
+    }
 }
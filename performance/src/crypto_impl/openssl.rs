@@ -1,11 +1,11 @@
-use std::ptr::{self, NonNull};
-use parking_lot::Mutex;
+use core::ptr::{self, NonNull};
 
 use arrayvec::ArrayVec;
 use openssl_sys::*;
 use zeroize::Zeroizing;
 
 use crate::crypto::*;
+use crate::sync::Mutex;
 
 /// A wrapper for a `EVP_CIPHER_CTX` that will free itself on drop.
 /// Users are encouraged to not use one of these directly.
@@ -201,10 +201,10 @@ impl HighThroughputAesGcmPool for OpenSSLAesGcmPool {
         }
     }
 
-    fn start_enc(&self, nonce: &[u8; AES_GCM_NONCE_SIZE]) -> OpenSSLCtx {
+    fn start_enc(&self, nonce: &[u8; AES_GCM_NONCE_SIZE], aad: &[u8]) -> OpenSSLCtx {
         let ctx = self.enc.lock().pop();
         unsafe {
-            if let Some(ctx) = ctx {
+            let ctx = if let Some(ctx) = ctx {
                 assert!(ctx.cipher_init::<true>(ptr::null(), ptr::null(), nonce.as_ptr()));
                 ctx
             } else {
@@ -213,13 +213,17 @@ impl HighThroughputAesGcmPool for OpenSSLAesGcmPool {
                 assert!(ctx.cipher_init::<true>(t, self.enc_key.as_ptr(), nonce.as_ptr()));
                 openssl_sys::EVP_CIPHER_CTX_set_padding(ctx.as_ptr(), 0);
                 ctx
+            };
+            if !aad.is_empty() {
+                assert!(ctx.update::<true>(aad, ptr::null_mut()));
             }
+            ctx
         }
     }
-    fn start_dec(&self, nonce: &[u8; AES_GCM_NONCE_SIZE]) -> OpenSSLCtx {
+    fn start_dec(&self, nonce: &[u8; AES_GCM_NONCE_SIZE], aad: &[u8]) -> OpenSSLCtx {
         let ctx = self.dec.lock().pop();
         unsafe {
-            if let Some(ctx) = ctx {
+            let ctx = if let Some(ctx) = ctx {
                 assert!(ctx.cipher_init::<false>(ptr::null(), ptr::null(), nonce.as_ptr()));
                 ctx
             } else {
@@ -228,7 +232,11 @@ impl HighThroughputAesGcmPool for OpenSSLAesGcmPool {
                 assert!(ctx.cipher_init::<false>(t, self.dec_key.as_ptr(), nonce.as_ptr()));
                 openssl_sys::EVP_CIPHER_CTX_set_padding(ctx.as_ptr(), 0);
                 ctx
+            };
+            if !aad.is_empty() {
+                assert!(ctx.update::<false>(aad, ptr::null_mut()));
             }
+            ctx
         }
     }
 
@@ -15,7 +15,7 @@ impl Sha512Hash for CrateSha512 {
 
     fn finish_and_reset(&mut self, output: &mut [u8; SHA512_HASH_SIZE]) {
         let mut hasher = Digest::new();
-        std::mem::swap(self, &mut hasher);
+        core::mem::swap(self, &mut hasher);
         *output = hasher.finalize().into();
     }
 }
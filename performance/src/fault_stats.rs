@@ -0,0 +1,166 @@
+use crate::result::FaultType;
+use crate::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// The number of byzantine faults of each type that were attributed to a session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultCounts {
+    /// See `FaultType::UnknownLocalKeyId`.
+    pub unknown_local_key_id: u64,
+    /// See `FaultType::InvalidPacket`.
+    pub invalid_packet: u64,
+    /// See `FaultType::FailedAuth`.
+    pub failed_auth: u64,
+    /// See `FaultType::ExpiredCounter`.
+    pub expired_counter: u64,
+    /// See `FaultType::OutOfSequence`.
+    pub out_of_sequence: u64,
+    /// The number of the faults above that were unnatural, see `ByzantineFault::unnatural`.
+    pub unnatural: u64,
+}
+
+/// Statistics about the byzantine faults that were attributed to a session.
+///
+/// A fault is only attributed to a session if the offending packet was addressed to one of the
+/// key ids of that session. Key ids are sent in the clear, so `pre_auth` faults can still be
+/// caused by anyone who has observed the session, while `post_auth` faults can only be caused by
+/// the remote peer or by replaying its packets.
+///
+/// Each count is a separate relaxed atomic, see `Context::statistics`, so `unnatural` can be read
+/// ahead of the count of the fault type it belongs to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Faults that occurred before the offending packet was authenticated.
+    pub pre_auth: FaultCounts,
+    /// Faults that occurred after the offending packet was authenticated.
+    pub post_auth: FaultCounts,
+}
+
+impl FaultCounts {
+    /// The number of faults of the given type.
+    pub fn get(&self, error: FaultType) -> u64 {
+        match error {
+            FaultType::UnknownLocalKeyId => self.unknown_local_key_id,
+            FaultType::InvalidPacket => self.invalid_packet,
+            FaultType::FailedAuth => self.failed_auth,
+            FaultType::ExpiredCounter => self.expired_counter,
+            FaultType::OutOfSequence => self.out_of_sequence,
+        }
+    }
+    /// The number of faults of all types.
+    pub fn total(&self) -> u64 {
+        self.unknown_local_key_id + self.invalid_packet + self.failed_auth + self.expired_counter + self.out_of_sequence
+    }
+}
+
+const NUM_COUNTERS: usize = 6;
+const UNNATURAL: usize = 5;
+
+fn counter_index(error: FaultType) -> usize {
+    match error {
+        FaultType::UnknownLocalKeyId => 0,
+        FaultType::InvalidPacket => 1,
+        FaultType::FailedAuth => 2,
+        FaultType::ExpiredCounter => 3,
+        FaultType::OutOfSequence => 4,
+    }
+}
+
+/// The fault counters of a single session.
+pub(crate) struct FaultCounters {
+    pre_auth: [AtomicU64; NUM_COUNTERS],
+    post_auth: [AtomicU64; NUM_COUNTERS],
+    window_start: AtomicI64,
+    window_unnatural: AtomicU64,
+}
+
+impl FaultCounters {
+    pub fn new() -> Self {
+        Self {
+            pre_auth: Default::default(),
+            post_auth: Default::default(),
+            window_start: AtomicI64::new(i64::MIN),
+            window_unnatural: AtomicU64::new(0),
+        }
+    }
+    /// Count a fault.
+    ///
+    /// Unnatural faults are also counted within fixed windows of `window` time units. Returns true
+    /// if this fault was the one that took the count of the current window over `threshold`, so
+    /// this returns true at most once per window.
+    pub fn record(
+        &self,
+        error: FaultType,
+        unnatural: bool,
+        authenticated: bool,
+        current_time: i64,
+        threshold: Option<u64>,
+        window: u64,
+    ) -> bool {
+        let counters = if authenticated {
+            &self.post_auth
+        } else {
+            &self.pre_auth
+        };
+        counters[counter_index(error)].fetch_add(1, Ordering::Relaxed);
+        if !unnatural {
+            return false;
+        }
+        counters[UNNATURAL].fetch_add(1, Ordering::Relaxed);
+
+        let Some(threshold) = threshold else { return false };
+        let window_start = self.window_start.load(Ordering::Relaxed);
+        if current_time.saturating_sub(window_start) as u64 >= window
+            && self
+                .window_start
+                .compare_exchange(window_start, current_time, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.window_unnatural.store(0, Ordering::Relaxed);
+        }
+        self.window_unnatural.fetch_add(1, Ordering::Relaxed) == threshold
+    }
+    pub fn stats(&self) -> FaultStats {
+        let load = |counters: &[AtomicU64; NUM_COUNTERS]| FaultCounts {
+            unknown_local_key_id: counters[0].load(Ordering::Relaxed),
+            invalid_packet: counters[1].load(Ordering::Relaxed),
+            failed_auth: counters[2].load(Ordering::Relaxed),
+            expired_counter: counters[3].load(Ordering::Relaxed),
+            out_of_sequence: counters[4].load(Ordering::Relaxed),
+            unnatural: counters[UNNATURAL].load(Ordering::Relaxed),
+        };
+        FaultStats {
+            pre_auth: load(&self.pre_auth),
+            post_auth: load(&self.post_auth),
+        }
+    }
+}
+
+#[test]
+fn test_fault_counters() {
+    let counters = FaultCounters::new();
+    let record =
+        |error, unnatural, authenticated, time| counters.record(error, unnatural, authenticated, time, Some(2), 100);
+    assert!(!record(FaultType::FailedAuth, true, false, 0));
+    assert!(!record(FaultType::OutOfSequence, false, false, 10));
+    assert!(!record(FaultType::ExpiredCounter, true, true, 20));
+    assert!(record(FaultType::FailedAuth, true, false, 30));
+    // The threshold is only reported as exceeded once per window.
+    assert!(!record(FaultType::FailedAuth, true, false, 40));
+
+    let stats = counters.stats();
+    assert_eq!(stats.pre_auth.get(FaultType::FailedAuth), 3);
+    assert_eq!(stats.pre_auth.total(), 4);
+    assert_eq!(stats.pre_auth.unnatural, 3);
+    assert_eq!(stats.post_auth.get(FaultType::ExpiredCounter), 1);
+    assert_eq!(stats.post_auth.total(), 1);
+
+    // A new window starts counting from zero.
+    assert!(!record(FaultType::FailedAuth, true, false, 100));
+    assert!(!record(FaultType::FailedAuth, true, false, 110));
+    assert!(record(FaultType::FailedAuth, true, false, 120));
+    // Natural faults never count towards the threshold.
+    let counters = FaultCounters::new();
+    for time in 0..10 {
+        assert!(!counters.record(FaultType::OutOfSequence, false, true, time, Some(0), 100));
+    }
+}
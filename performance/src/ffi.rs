@@ -0,0 +1,577 @@
+//! The C interface of ZSSP. See `include/zssp.h` for the matching declarations.
+#![allow(non_camel_case_types)]
+
+use core::ffi::{c_int, c_void};
+use core::mem::ManuallyDrop;
+use core::ptr;
+use std::sync::Arc;
+
+use rand_core::OsRng;
+
+use crate::application::*;
+use crate::crypto::{P384KeyPair, P384PublicKey, P384_PUBLIC_KEY_SIZE};
+use crate::crypto_impl::{CrateP384KeyPair, CrateP384PublicKey, DefaultCrypto};
+use crate::result::{OpenError, ReceiveError, ReceiveOk, SendError, SessionEvent};
+
+/// The `CryptoLayer` of sessions created through the C interface.
+///
+/// Session data is an opaque 64-bit tag chosen by the C application, and remote addresses are
+/// opaque 64-bit tokens, since neither side can know the layout of the other's types.
+pub struct FfiCrypto;
+impl DefaultCrypto for FfiCrypto {
+    type SessionData = u64;
+    type IncomingPacketBuffer = Vec<u8>;
+}
+type Session = crate::Session<FfiCrypto>;
+
+// These sizes are repeated as constants in `include/zssp.h`.
+const _: () = assert!(P384_PUBLIC_KEY_SIZE == 49 && RATCHET_SIZE == 32);
+const _: () = assert!(RATCHET_STATE_ENCODED_SIZE == 75 && RATCHET_STATES_ENCODED_SIZE == 152);
+const _: () = assert!(crate::proto::MIN_TRANSPORT_MTU == 128);
+
+/// The call succeeded.
+pub const ZSSP_OK: c_int = 0;
+/// `OpenError::IdentityTooLarge`.
+pub const ZSSP_ERR_IDENTITY_TOO_LARGE: c_int = -1;
+/// `OpenError::MtuTooSmall` or `SendError::MtuTooSmall`.
+pub const ZSSP_ERR_MTU_TOO_SMALL: c_int = -2;
+/// `OpenError::InvalidRemoteKey`.
+pub const ZSSP_ERR_INVALID_REMOTE_KEY: c_int = -3;
+/// `OpenError::StorageReadError` or `ReceiveError::StorageReadError`.
+pub const ZSSP_ERR_STORAGE_READ: c_int = -4;
+/// `SendError::SessionExpired`.
+pub const ZSSP_ERR_SESSION_EXPIRED: c_int = -5;
+/// `SendError::SessionNotEstablished`.
+pub const ZSSP_ERR_SESSION_NOT_ESTABLISHED: c_int = -6;
+/// `SendError::DataTooLarge`.
+pub const ZSSP_ERR_DATA_TOO_LARGE: c_int = -7;
+/// `SendError::KeyExchangeInProgress`.
+pub const ZSSP_ERR_KEY_EXCHANGE_IN_PROGRESS: c_int = -8;
+/// `ReceiveError::ByzantineFault`.
+pub const ZSSP_ERR_BYZANTINE_FAULT: c_int = -9;
+/// `ReceiveError::MaxKeyLifetimeExceeded`.
+pub const ZSSP_ERR_MAX_KEY_LIFETIME_EXCEEDED: c_int = -10;
+/// `ReceiveError::Rejected`.
+pub const ZSSP_ERR_REJECTED: c_int = -11;
+/// `ReceiveError::StorageWriteError`.
+pub const ZSSP_ERR_STORAGE_WRITE: c_int = -12;
+/// `ReceiveError::WriteError`, the payload did not fit in the buffer given to `zssp_receive`.
+pub const ZSSP_ERR_PAYLOAD_TOO_LARGE: c_int = -13;
+/// A required pointer was null.
+pub const ZSSP_ERR_INVALID_ARGUMENT: c_int = -14;
+/// `OpenError::VersionDowngrade`.
+pub const ZSSP_ERR_VERSION_DOWNGRADE: c_int = -15;
+/// `SendError::RekeyUrgentlyNeeded`.
+pub const ZSSP_ERR_REKEY_URGENTLY_NEEDED: c_int = -16;
+/// `OpenError::KeyIdsExhausted`, `SendError::KeyIdsExhausted` or `ReceiveError::KeyIdsExhausted`.
+pub const ZSSP_ERR_KEY_IDS_EXHAUSTED: c_int = -17;
+
+/// The packet was not associated with a session, or was a fragment of a larger packet.
+pub const ZSSP_EVENT_NONE: c_int = 0;
+/// `SessionEvent::NewSession`.
+pub const ZSSP_EVENT_NEW_SESSION: c_int = 1;
+/// `SessionEvent::NewDowngradedSession`.
+pub const ZSSP_EVENT_NEW_DOWNGRADED_SESSION: c_int = 2;
+/// `SessionEvent::Rejected`.
+pub const ZSSP_EVENT_REJECTED: c_int = 3;
+/// `SessionEvent::RekeyRejected`.
+pub const ZSSP_EVENT_REKEY_REJECTED: c_int = 4;
+/// `SessionEvent::Data`.
+pub const ZSSP_EVENT_DATA: c_int = 5;
+/// `SessionEvent::Control`.
+pub const ZSSP_EVENT_CONTROL: c_int = 6;
+/// `SessionEvent::DowngradedRatchetKey`.
+pub const ZSSP_EVENT_DOWNGRADED_RATCHET_KEY: c_int = 7;
+/// `ReceiveOk::Established`.
+pub const ZSSP_EVENT_ESTABLISHED: c_int = 8;
+/// `ReceiveOk::VersionUnsupported`. The versions Bob supports are not reported.
+pub const ZSSP_EVENT_VERSION_UNSUPPORTED: c_int = 9;
+
+/// The function pointers and settings through which ZSSP calls back into the C application.
+///
+/// Every function is passed `user` as its first argument. `time`, `send_to_session` and
+/// `send_to_address` are required. The storage hooks may be null, in which case ratchet states
+/// are not persisted, and if `check_accept_session` is null every incoming session is rejected.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct zssp_callbacks_t {
+    /// Opaque pointer passed back to every callback.
+    pub user: *mut c_void,
+    /// The MTU of every path, at least `MIN_TRANSPORT_MTU`.
+    pub mtu: usize,
+    /// `ApplicationLayer::time`.
+    pub time: Option<extern "C" fn(user: *mut c_void) -> i64>,
+    /// Send a packet to the peer of the session with the given session data.
+    pub send_to_session:
+        Option<extern "C" fn(user: *mut c_void, session_data: u64, packet: *mut u8, len: usize) -> bool>,
+    /// Send a reply to a peer without a session, at the remote address given to `zssp_receive`.
+    pub send_to_address:
+        Option<extern "C" fn(user: *mut c_void, remote_address: u64, packet: *mut u8, len: usize) -> bool>,
+    /// `ApplicationLayer::check_accept_session`. Return true and write the session data of the new
+    /// session to `session_data` to accept it.
+    pub check_accept_session: Option<
+        extern "C" fn(
+            user: *mut c_void,
+            remote_key: *const u8,
+            identity: *const u8,
+            identity_len: usize,
+            session_data: *mut u64,
+        ) -> bool,
+    >,
+    /// `ApplicationLayer::restore_by_identity`. Write the `RatchetStates::to_bytes` encoding to
+    /// `states_out` and return 1, return 0 if nothing is stored, or return a negative number on
+    /// error.
+    pub restore_by_identity: Option<
+        extern "C" fn(user: *mut c_void, remote_key: *const u8, session_data: u64, states_out: *mut u8) -> c_int,
+    >,
+    /// `ApplicationLayer::restore_by_fingerprint`. Write the `RatchetState::to_bytes` encoding to
+    /// `state_out` and return 1, return 0 if the fingerprint is unknown, or return a negative
+    /// number on error.
+    pub restore_by_fingerprint:
+        Option<extern "C" fn(user: *mut c_void, fingerprint: *const u8, state_out: *mut u8) -> c_int>,
+    /// `ApplicationLayer::save_ratchet_state`. `cur_states` and `new_states` are
+    /// `RatchetStates::to_bytes` encodings. Return 1 if `new_states` replaced `cur_states`, 0 if
+    /// `cur_states` was not what is stored, or a negative number on error.
+    pub save_ratchet_state: Option<
+        extern "C" fn(
+            user: *mut c_void,
+            remote_key: *const u8,
+            session_data: u64,
+            cur_states: *const u8,
+            new_states: *const u8,
+        ) -> c_int,
+    >,
+}
+
+/// An opaque ZSSP context.
+pub struct zssp_context_t {
+    ctx: crate::Context<FfiCrypto>,
+    callbacks: zssp_callbacks_t,
+}
+/// An opaque, reference counted handle to a session.
+///
+/// Every handle returned by ZSSP must be released with `zssp_session_release`. A session is
+/// terminated once its last handle is released.
+#[repr(C)]
+pub struct zssp_session_t {
+    _private: [u8; 0],
+}
+
+struct FfiApp<'a>(&'a zssp_callbacks_t);
+impl ApplicationLayer<FfiCrypto> for FfiApp<'_> {
+    fn time(&mut self) -> i64 {
+        (self.0.time.unwrap())(self.0.user)
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        IncomingSessionAction::Allow
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session>) -> bool {
+        false
+    }
+    fn check_accept_session(
+        &mut self,
+        remote_static_key: &CrateP384PublicKey,
+        identity: &[u8],
+        _: Option<&()>,
+    ) -> AcceptAction<FfiCrypto> {
+        let mut session_data = 0;
+        let accept = self.0.check_accept_session.is_some_and(|f| {
+            let remote_key = remote_static_key.to_bytes();
+            f(
+                self.0.user,
+                remote_key.as_ptr(),
+                identity.as_ptr(),
+                identity.len(),
+                &mut session_data,
+            )
+        });
+        AcceptAction {
+            session_data: accept.then_some(session_data),
+            responder_disallows_downgrade: false,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(
+        &mut self,
+        ratchet_fingerprint: &[u8; RATCHET_SIZE],
+    ) -> std::io::Result<Option<(RatchetState, ())>> {
+        let Some(f) = self.0.restore_by_fingerprint else {
+            return Ok(None);
+        };
+        let mut state = zeroize::Zeroizing::new([0u8; RATCHET_STATE_ENCODED_SIZE]);
+        match f(self.0.user, ratchet_fingerprint.as_ptr(), state.as_mut_ptr()) {
+            0 => Ok(None),
+            1 => RatchetState::from_bytes(&state[..])
+                .map(|s| Some((s, ())))
+                .ok_or_else(invalid_state),
+            _ => Err(std::io::Error::other("restore_by_fingerprint failed")),
+        }
+    }
+    fn restore_by_identity(
+        &mut self,
+        remote_static_key: &CrateP384PublicKey,
+        session_data: &u64,
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        let Some(f) = self.0.restore_by_identity else {
+            return Ok(None);
+        };
+        let remote_key = remote_static_key.to_bytes();
+        let mut states = zeroize::Zeroizing::new([0u8; RATCHET_STATES_ENCODED_SIZE]);
+        match f(self.0.user, remote_key.as_ptr(), *session_data, states.as_mut_ptr()) {
+            0 => Ok(None),
+            1 => RatchetStates::from_bytes(&states[..])
+                .map(Some)
+                .ok_or_else(invalid_state),
+            _ => Err(std::io::Error::other("restore_by_identity failed")),
+        }
+    }
+    fn save_ratchet_state(
+        &mut self,
+        remote_static_key: &CrateP384PublicKey,
+        session_data: &u64,
+        update: CompareAndSwap<'_>,
+    ) -> std::io::Result<bool> {
+        let Some(f) = self.0.save_ratchet_state else {
+            return Ok(true);
+        };
+        let remote_key = remote_static_key.to_bytes();
+        let cur = update.to_cur_states().to_bytes();
+        let new = update.to_new_states().to_bytes();
+        match f(
+            self.0.user,
+            remote_key.as_ptr(),
+            *session_data,
+            cur.as_ptr(),
+            new.as_ptr(),
+        ) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(std::io::Error::other("save_ratchet_state failed")),
+        }
+    }
+}
+fn invalid_state() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid ratchet state encoding")
+}
+
+impl zssp_callbacks_t {
+    fn address_sender(&self, remote_address: u64) -> impl FnMut(&mut [u8]) -> bool + '_ {
+        move |packet: &mut [u8]| {
+            (self.send_to_address.unwrap())(self.user, remote_address, packet.as_mut_ptr(), packet.len())
+        }
+    }
+    fn session_sender(&self, session_data: u64) -> impl FnMut(&mut [u8]) -> bool + '_ {
+        move |packet: &mut [u8]| {
+            (self.send_to_session.unwrap())(self.user, session_data, packet.as_mut_ptr(), packet.len())
+        }
+    }
+    fn send_to(&self) -> impl SendTo<FfiCrypto> + '_ {
+        move |session: &Arc<Session>| Some((self.session_sender(session.session_data), self.mtu))
+    }
+}
+
+/// Turn an owned session into a handle for C.
+fn into_handle(session: Arc<Session>) -> *mut zssp_session_t {
+    Arc::into_raw(session) as *mut zssp_session_t
+}
+/// Borrow the session behind a handle without changing its reference count.
+///
+/// # Safety
+/// `session` must be a handle returned by ZSSP that has not been released.
+unsafe fn borrow_handle(session: *const zssp_session_t) -> ManuallyDrop<Arc<Session>> {
+    ManuallyDrop::new(Arc::from_raw(session as *const Session))
+}
+/// # Safety
+/// `data` must be null or point to `len` readable bytes.
+unsafe fn slice<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        core::slice::from_raw_parts(data, len)
+    }
+}
+
+fn open_error_code(e: OpenError) -> c_int {
+    match e {
+        OpenError::IdentityTooLarge => ZSSP_ERR_IDENTITY_TOO_LARGE,
+        OpenError::MtuTooSmall => ZSSP_ERR_MTU_TOO_SMALL,
+        OpenError::InvalidRemoteKey => ZSSP_ERR_INVALID_REMOTE_KEY,
+        OpenError::StorageReadError(_) => ZSSP_ERR_STORAGE_READ,
+        OpenError::VersionDowngrade => ZSSP_ERR_VERSION_DOWNGRADE,
+        OpenError::KeyIdsExhausted => ZSSP_ERR_KEY_IDS_EXHAUSTED,
+    }
+}
+fn send_error_code(e: SendError) -> c_int {
+    match e {
+        SendError::MtuTooSmall => ZSSP_ERR_MTU_TOO_SMALL,
+        SendError::SessionExpired => ZSSP_ERR_SESSION_EXPIRED,
+        SendError::SessionNotEstablished => ZSSP_ERR_SESSION_NOT_ESTABLISHED,
+        SendError::DataTooLarge => ZSSP_ERR_DATA_TOO_LARGE,
+        SendError::KeyExchangeInProgress => ZSSP_ERR_KEY_EXCHANGE_IN_PROGRESS,
+        SendError::RekeyUrgentlyNeeded => ZSSP_ERR_REKEY_URGENTLY_NEEDED,
+        SendError::KeyIdsExhausted => ZSSP_ERR_KEY_IDS_EXHAUSTED,
+    }
+}
+fn event_code(event: SessionEvent) -> c_int {
+    match event {
+        SessionEvent::NewSession => ZSSP_EVENT_NEW_SESSION,
+        SessionEvent::NewDowngradedSession => ZSSP_EVENT_NEW_DOWNGRADED_SESSION,
+        SessionEvent::Rejected => ZSSP_EVENT_REJECTED,
+        SessionEvent::RekeyRejected => ZSSP_EVENT_REKEY_REJECTED,
+        SessionEvent::Data => ZSSP_EVENT_DATA,
+        SessionEvent::Control => ZSSP_EVENT_CONTROL,
+        SessionEvent::DowngradedRatchetKey => ZSSP_EVENT_DOWNGRADED_RATCHET_KEY,
+    }
+}
+
+/// Create a new context with a freshly generated static key, or return null if `callbacks` is
+/// null or is missing a required function.
+///
+/// The public half of the static key can be read with `zssp_context_public_key`.
+///
+/// # Safety
+/// `callbacks` must be null or point to a valid `zssp_callbacks_t`. It is copied, but `user`
+/// must stay valid until the context is freed.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_context_new(callbacks: *const zssp_callbacks_t) -> *mut zssp_context_t {
+    let Some(callbacks) = callbacks.as_ref() else {
+        return ptr::null_mut();
+    };
+    if callbacks.time.is_none() || callbacks.send_to_session.is_none() || callbacks.send_to_address.is_none() {
+        return ptr::null_mut();
+    }
+    let ctx = crate::Context::new(CrateP384KeyPair::generate(&mut OsRng), OsRng);
+    Box::into_raw(Box::new(zssp_context_t { ctx, callbacks: *callbacks }))
+}
+/// Free a context created with `zssp_context_new`.
+///
+/// Sessions of the context stop working, but their handles must still be released.
+///
+/// # Safety
+/// `ctx` must be null or a context that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_context_free(ctx: *mut zssp_context_t) {
+    if !ctx.is_null() {
+        drop(Box::from_raw(ctx));
+    }
+}
+/// Write the `ZSSP_PUBLIC_KEY_SIZE` byte public key of the context to `out`.
+///
+/// # Safety
+/// `ctx` must be a valid context and `out` must point to `ZSSP_PUBLIC_KEY_SIZE` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_context_public_key(ctx: *const zssp_context_t, out: *mut u8) {
+    let ctx = &*ctx;
+    let key = <CrateP384KeyPair as P384KeyPair<OsRng>>::public_key_bytes(&ctx.ctx.0.s_secret);
+    ptr::copy_nonoverlapping(key.as_ptr(), out, P384_PUBLIC_KEY_SIZE);
+}
+
+/// Open a session to the peer with the given `ZSSP_PUBLIC_KEY_SIZE` byte public key, see
+/// `Context::open`. On success a new handle is written to `session_out`.
+///
+/// # Safety
+/// `ctx` must be a valid context, `remote_key` must point to `ZSSP_PUBLIC_KEY_SIZE` readable bytes,
+/// `identity` must point to `identity_len` readable bytes and `session_out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_open(
+    ctx: *const zssp_context_t,
+    remote_key: *const u8,
+    session_data: u64,
+    identity: *const u8,
+    identity_len: usize,
+    session_out: *mut *mut zssp_session_t,
+) -> c_int {
+    if ctx.is_null() || remote_key.is_null() || session_out.is_null() {
+        return ZSSP_ERR_INVALID_ARGUMENT;
+    }
+    let ctx = &*ctx;
+    let Some(remote_key) = CrateP384PublicKey::from_bytes(&*(remote_key as *const [u8; P384_PUBLIC_KEY_SIZE])) else {
+        return ZSSP_ERR_INVALID_REMOTE_KEY;
+    };
+    let cb = &ctx.callbacks;
+    let identity = slice(identity, identity_len);
+    match ctx.ctx.open(
+        FfiApp(cb),
+        cb.session_sender(session_data),
+        cb.mtu,
+        remote_key,
+        session_data,
+        identity,
+    ) {
+        Ok((session, _)) => {
+            *session_out = into_handle(session);
+            ZSSP_OK
+        }
+        Err(e) => open_error_code(e),
+    }
+}
+
+/// Process a packet received from `remote_address`, see `Context::receive`.
+///
+/// The packet is decrypted in place, so its contents are unspecified afterwards. A decrypted
+/// payload is written to `payload` and its length to `payload_len`. What happened is written to
+/// `event` as one of the `ZSSP_EVENT_` codes. If the packet was associated with a session, and
+/// also for the errors that name a session, a new handle to it is written to `session_out`,
+/// which is set to null otherwise.
+///
+/// # Safety
+/// `ctx` must be a valid context, `packet` must point to `packet_len` writable bytes, `payload`
+/// must point to `payload_capacity` writable bytes, and the out pointers must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_receive(
+    ctx: *const zssp_context_t,
+    remote_address: u64,
+    packet: *mut u8,
+    packet_len: usize,
+    payload: *mut u8,
+    payload_capacity: usize,
+    payload_len: *mut usize,
+    event: *mut c_int,
+    session_out: *mut *mut zssp_session_t,
+) -> c_int {
+    if ctx.is_null() || packet.is_null() || payload_len.is_null() || event.is_null() || session_out.is_null() {
+        return ZSSP_ERR_INVALID_ARGUMENT;
+    }
+    let ctx = &*ctx;
+    let cb = &ctx.callbacks;
+    let mut output: &mut [u8] = if payload_capacity == 0 {
+        &mut []
+    } else {
+        core::slice::from_raw_parts_mut(payload, payload_capacity)
+    };
+    let result = ctx.ctx.receive_borrowed(
+        FfiApp(cb),
+        cb.address_sender(remote_address),
+        cb.mtu,
+        cb.send_to(),
+        &remote_address,
+        crate::ReceiveOptions::default(),
+        core::slice::from_raw_parts_mut(packet, packet_len),
+        <[u8]>::to_vec,
+        &mut output,
+    );
+    *payload_len = payload_capacity - output.len();
+    *event = ZSSP_EVENT_NONE;
+    *session_out = ptr::null_mut();
+    match result {
+        Ok((ReceiveOk::Unassociated, _)) => ZSSP_OK,
+        Ok((ReceiveOk::Fragment(session), _)) => {
+            *session_out = into_handle(session);
+            ZSSP_OK
+        }
+        Ok((ReceiveOk::Established(session), _)) => {
+            *event = ZSSP_EVENT_ESTABLISHED;
+            *session_out = into_handle(session);
+            ZSSP_OK
+        }
+        Ok((ReceiveOk::VersionUnsupported(session, ..), _)) => {
+            *event = ZSSP_EVENT_VERSION_UNSUPPORTED;
+            *session_out = into_handle(session);
+            ZSSP_OK
+        }
+        Ok((ReceiveOk::Associated(session, e), _)) => {
+            *event = event_code(e);
+            *session_out = into_handle(session);
+            ZSSP_OK
+        }
+        Err(ReceiveError::ByzantineFault(_)) => ZSSP_ERR_BYZANTINE_FAULT,
+        Err(ReceiveError::MaxKeyLifetimeExceeded(session)) => {
+            *session_out = into_handle(session);
+            ZSSP_ERR_MAX_KEY_LIFETIME_EXCEEDED
+        }
+        Err(ReceiveError::Rejected(_)) => ZSSP_ERR_REJECTED,
+        Err(ReceiveError::KeyIdsExhausted) => ZSSP_ERR_KEY_IDS_EXHAUSTED,
+        Err(ReceiveError::StorageReadError(_)) => ZSSP_ERR_STORAGE_READ,
+        Err(ReceiveError::StorageWriteError(_)) => ZSSP_ERR_STORAGE_WRITE,
+        Err(ReceiveError::WriteError(_, session)) => {
+            *payload_len = 0;
+            *session_out = into_handle(session);
+            ZSSP_ERR_PAYLOAD_TOO_LARGE
+        }
+    }
+}
+
+/// Encrypt and send `data` over `session`, see `Context::send`.
+///
+/// # Safety
+/// `ctx` must be a valid context, `session` a handle that has not been released and `data` must
+/// point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_send(
+    ctx: *const zssp_context_t,
+    session: *const zssp_session_t,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    if ctx.is_null() || session.is_null() {
+        return ZSSP_ERR_INVALID_ARGUMENT;
+    }
+    let ctx = &*ctx;
+    let session = borrow_handle(session);
+    let cb = &ctx.callbacks;
+    let mut work_buffer = vec![0u8; cb.mtu];
+    let send = cb.session_sender(session.session_data);
+    match ctx.ctx.send(&session, send, cb.mtu, &mut work_buffer, slice(data, len)) {
+        Ok(_) => ZSSP_OK,
+        Err(e) => send_error_code(e),
+    }
+}
+
+/// Perform periodic background service, see `Context::service`.
+/// Returns the number of milliseconds until this should be called again.
+///
+/// # Safety
+/// `ctx` must be a valid context.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_service(ctx: *const zssp_context_t) -> i64 {
+    let ctx = &*ctx;
+    ctx.ctx.service(FfiApp(&ctx.callbacks), ctx.callbacks.send_to())
+}
+
+/// Expire `session`, see `Session::expire`. The handle must still be released.
+///
+/// # Safety
+/// `session` must be a handle that has not been released.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_session_close(session: *const zssp_session_t) {
+    borrow_handle(session).expire();
+}
+/// Whether `session` is established, see `Session::established`.
+///
+/// # Safety
+/// `session` must be a handle that has not been released.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_session_established(session: *const zssp_session_t) -> bool {
+    borrow_handle(session).established()
+}
+/// The session data `session` was opened or accepted with.
+///
+/// # Safety
+/// `session` must be a handle that has not been released.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_session_data(session: *const zssp_session_t) -> u64 {
+    borrow_handle(session).session_data
+}
+/// Return a new handle to the same session as `session`.
+///
+/// # Safety
+/// `session` must be a handle that has not been released.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_session_retain(session: *const zssp_session_t) -> *mut zssp_session_t {
+    Arc::increment_strong_count(session as *const Session);
+    session as *mut zssp_session_t
+}
+/// Release a handle to a session.
+///
+/// # Safety
+/// `session` must be null or a handle that has not been released.
+#[no_mangle]
+pub unsafe extern "C" fn zssp_session_release(session: *mut zssp_session_t) {
+    if !session.is_null() {
+        drop(Arc::from_raw(session as *const Session));
+    }
+}
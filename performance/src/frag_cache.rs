@@ -1,12 +1,92 @@
-use std::collections::hash_map::RandomState;
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::mem::MaybeUninit;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::mem::MaybeUninit;
 
-use crate::application::CryptoLayer;
+use rand_core::RngCore;
+
+use crate::application::{CryptoLayer, FragCachePolicy};
+use crate::collections::{random_state, RandomState};
 use crate::crypto::AES_GCM_NONCE_SIZE;
 use crate::fragged::Assembled;
+use crate::indexed_heap::{BinaryHeapIndex, IndexedBinaryHeap};
 use crate::proto::{MAX_FRAGMENTS, MAX_UNASSOCIATED_FRAGMENTS, MAX_UNASSOCIATED_PACKETS, MAX_UNASSOCIATED_PACKET_SIZE};
 
+/// The unassociated fragment cache selected by `CryptoLayer::FRAG_CACHE_POLICY`.
+pub(crate) enum FragCache<C: CryptoLayer> {
+    TimeoutBased(Box<UnassociatedFragCache<C>>),
+    Lru(Box<LruFragCache<C>>),
+}
+impl<C: CryptoLayer> FragCache<C> {
+    pub(crate) fn new(rng: &mut impl RngCore) -> Self {
+        match C::FRAG_CACHE_POLICY {
+            FragCachePolicy::TimeoutBased => Self::TimeoutBased(Box::new(UnassociatedFragCache::new(rng))),
+            FragCachePolicy::Lru => Self::Lru(Box::new(LruFragCache::new(rng))),
+        }
+    }
+    /// See `UnassociatedFragCache::assemble`.
+    pub(crate) fn assemble(
+        &mut self,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        remote_address: impl Hash,
+        fragment_size: usize,
+        fragment: C::IncomingPacketBuffer,
+        fragment_no: usize,
+        fragment_count: usize,
+        current_time: i64,
+        ret_assembled: &mut Assembled<C::IncomingPacketBuffer>,
+    ) -> Option<i64> {
+        match self {
+            Self::TimeoutBased(cache) => cache.assemble(
+                nonce,
+                remote_address,
+                fragment_size,
+                fragment,
+                fragment_no,
+                fragment_count,
+                current_time,
+                ret_assembled,
+            ),
+            Self::Lru(cache) => cache.assemble(
+                nonce,
+                remote_address,
+                fragment_size,
+                fragment,
+                fragment_no,
+                fragment_count,
+                current_time,
+                ret_assembled,
+            ),
+        }
+    }
+    /// Returns the timestamp at which this function should be called again.
+    pub(crate) fn check_for_expiry(&mut self, current_time: i64) -> i64 {
+        match self {
+            Self::TimeoutBased(cache) => cache.check_for_expiry(current_time),
+            Self::Lru(cache) => cache.check_for_expiry(current_time),
+        }
+    }
+}
+
+/// Hash the identifying parts of an unassociated packet with a secret salt, so an adversary cannot
+/// control which slots of the cache their fragments index to. Never returns 0.
+fn packet_key(dos_salt: &RandomState, nonce: &[u8; AES_GCM_NONCE_SIZE], remote_address: impl Hash) -> u64 {
+    let mut hasher = dos_salt.build_hasher();
+    remote_address.hash(&mut hasher);
+    hasher.write(nonce);
+    hasher.finish().max(1)
+}
+/// The two slots of the open hash table that the packet with `key` may be stored in.
+fn candidate_slots(key: u64, map_len: usize) -> (usize, usize) {
+    let idx0 = (key as usize) % map_len;
+    let mut idx1 = (key as usize) / map_len % (map_len - 1);
+    if idx0 == idx1 {
+        idx1 = map_len - 1;
+    }
+    (idx0, idx1)
+}
+
 struct PacketMetadata {
     key: u64,
     frags_idx: u32,
@@ -28,12 +108,12 @@ pub(crate) struct UnassociatedFragCache<C: CryptoLayer> {
 /// Designed specifically to be extremely DDOS resistant.
 /// This datastructure takes raw unauthenticated fragments straight from the network.
 impl<C: CryptoLayer> UnassociatedFragCache<C> {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(rng: &mut impl RngCore) -> Self {
         Self {
-            dos_salt: RandomState::new(),
+            dos_salt: random_state(rng),
             frags_first_unused: 0,
             frags_unused_size: MAX_UNASSOCIATED_FRAGMENTS,
-            map: std::array::from_fn(|_| PacketMetadata {
+            map: core::array::from_fn(|_| PacketMetadata {
                 key: 0,
                 frags_idx: 0,
                 fragment_have: 0,
@@ -41,8 +121,8 @@ impl<C: CryptoLayer> UnassociatedFragCache<C> {
                 packet_size: 0,
                 creation_time: 0,
             }),
-            frags: std::array::from_fn(|_| MaybeUninit::zeroed()),
-            map_idx: std::array::from_fn(|_| u32::MAX),
+            frags: core::array::from_fn(|_| MaybeUninit::zeroed()),
+            map_idx: core::array::from_fn(|_| u32::MAX),
         }
     }
     /// Add a fragment and return an assembled packet container if all fragments have been received.
@@ -67,20 +147,8 @@ impl<C: CryptoLayer> UnassociatedFragCache<C> {
             return None;
         }
 
-        let mut hasher = self.dos_salt.build_hasher();
-        remote_address.hash(&mut hasher);
-        hasher.write(nonce);
-        let mut key = hasher.finish();
-        if key == 0 {
-            key = 1;
-        }
-
-        let map_len = self.map.len();
-        let idx0 = (key as usize) % map_len;
-        let mut idx1 = (key as usize) / map_len % (map_len - 1);
-        if idx0 == idx1 {
-            idx1 = map_len - 1;
-        }
+        let key = packet_key(&self.dos_salt, nonce, remote_address);
+        let (idx0, idx1) = candidate_slots(key, self.map.len());
 
         // Open hash lookup of just 2 slots.
         // To DOS, an adversary would either need to volumetrically spam the defrag table to keep most slots full
@@ -229,6 +297,170 @@ impl<C: CryptoLayer> Drop for UnassociatedFragCache<C> {
     }
 }
 
+struct LruPacketMetadata {
+    key: u64,
+    /// The index of this entry within `LruFragCache::lru`, if the entry is in use.
+    lru_idx: Option<BinaryHeapIndex>,
+    fragment_have: u64,
+    fragment_count: u8,
+    packet_size: u32,
+    /// The slots of `LruFragCache::frags` reserved for each fragment of this packet.
+    frag_slots: [u32; MAX_FRAGMENTS],
+}
+
+/// An alternative to `UnassociatedFragCache` that evicts the least recently active packets,
+/// rather than the oldest.
+///
+/// Packets are found with the same salted two slot hash lookup, but fragments are stored in a pool
+/// of slots instead of a ring buffer, so any packet can be evicted at any time.
+/// When a new packet does not fit, the least recently active of its two candidate slots is
+/// evicted, and then the least recently active packets overall until enough fragment slots are
+/// free. A packet that has not received a new fragment for `Settings::fragment_assembly_timeout`
+/// is still dropped.
+pub(crate) struct LruFragCache<C: CryptoLayer> {
+    dos_salt: RandomState,
+    map: [LruPacketMetadata; MAX_UNASSOCIATED_PACKETS],
+    /// Indices into `map` of all packets in use, keyed by the time they last received a fragment.
+    /// The least recently active packet is at the front.
+    lru: IndexedBinaryHeap<usize, Reverse<i64>>,
+    frags: [Option<C::IncomingPacketBuffer>; MAX_UNASSOCIATED_FRAGMENTS],
+    unused_frags: Vec<u32>,
+}
+impl<C: CryptoLayer> LruFragCache<C> {
+    pub(crate) fn new(rng: &mut impl RngCore) -> Self {
+        Self {
+            dos_salt: random_state(rng),
+            map: core::array::from_fn(|_| LruPacketMetadata {
+                key: 0,
+                lru_idx: None,
+                fragment_have: 0,
+                fragment_count: 0,
+                packet_size: 0,
+                frag_slots: [0; MAX_FRAGMENTS],
+            }),
+            lru: IndexedBinaryHeap::with_capacity(MAX_UNASSOCIATED_PACKETS),
+            frags: core::array::from_fn(|_| None),
+            unused_frags: (0..MAX_UNASSOCIATED_FRAGMENTS as u32).rev().collect(),
+        }
+    }
+    /// Add a fragment and return an assembled packet container if all fragments have been received.
+    /// Returns the time at which the cache should next be checked for expiry if the fragment is
+    /// part of a new packet.
+    pub(crate) fn assemble(
+        &mut self,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        remote_address: impl Hash,
+        fragment_size: usize,
+        fragment: C::IncomingPacketBuffer,
+        fragment_no: usize,
+        fragment_count: usize,
+        current_time: i64,
+        ret_assembled: &mut Assembled<C::IncomingPacketBuffer>,
+    ) -> Option<i64> {
+        debug_assert!(MAX_FRAGMENTS < MAX_UNASSOCIATED_FRAGMENTS);
+        if fragment_no >= fragment_count
+            || fragment_count > MAX_FRAGMENTS
+            || fragment_size > MAX_UNASSOCIATED_PACKET_SIZE
+        {
+            return None;
+        }
+
+        let key = packet_key(&self.dos_salt, nonce, remote_address);
+        let (idx0, idx1) = candidate_slots(key, self.map.len());
+
+        let mut new_expiry = None;
+        let idx = if self.map[idx0].key == key {
+            idx0
+        } else if self.map[idx1].key == key {
+            idx1
+        } else {
+            let idx = if self.map[idx0].key == 0 {
+                idx0
+            } else if self.map[idx1].key == 0 {
+                idx1
+            } else if self.last_active(idx0) <= self.last_active(idx1) {
+                idx0
+            } else {
+                idx1
+            };
+            if self.map[idx].key != 0 {
+                self.invalidate(idx);
+            }
+            while self.unused_frags.len() < fragment_count {
+                // There are not enough free fragment slots so evict the least recently active packet.
+                let (&lru, _, _) = self.lru.peek()?;
+                self.invalidate(lru);
+            }
+            new_expiry = Some(current_time + C::SETTINGS.fragment_assembly_timeout as i64);
+            let entry = &mut self.map[idx];
+            entry.key = key;
+            entry.lru_idx = Some(self.lru.push(idx, Reverse(current_time)));
+            entry.fragment_have = 0;
+            entry.fragment_count = fragment_count as u8;
+            entry.packet_size = 0;
+            for slot in &mut entry.frag_slots[..fragment_count] {
+                *slot = self.unused_frags.pop().unwrap();
+            }
+            idx
+        };
+        let entry = &mut self.map[idx];
+
+        let new_size = entry.packet_size + fragment_size as u32;
+        let got = 1u64.wrapping_shl(fragment_no as u32);
+        if got & entry.fragment_have == 0
+            && fragment_count == entry.fragment_count as usize
+            && new_size <= MAX_UNASSOCIATED_PACKET_SIZE as u32
+        {
+            entry.packet_size = new_size;
+            entry.fragment_have |= got;
+            self.frags[entry.frag_slots[fragment_no] as usize] = Some(fragment);
+
+            if entry.fragment_have == 1u64.wrapping_shl(fragment_count as u32) - 1 {
+                debug_assert!(ret_assembled.is_empty());
+                for slot in &entry.frag_slots[..fragment_count] {
+                    ret_assembled.push(self.frags[*slot as usize].take().unwrap());
+                }
+                self.invalidate(idx);
+            } else if let Some(lru_idx) = entry.lru_idx {
+                self.lru.change_priority(lru_idx, Reverse(current_time));
+            }
+        }
+        new_expiry
+    }
+    /// Returns the timestamp at which this function should be called again.
+    pub(crate) fn check_for_expiry(&mut self, current_time: i64) -> i64 {
+        let timeout = C::SETTINGS.fragment_assembly_timeout as i64;
+        while let Some((&idx, &Reverse(last_active), _)) = self.lru.peek() {
+            let expiry = last_active + timeout;
+            if expiry <= current_time {
+                self.invalidate(idx);
+            } else {
+                return expiry;
+            }
+        }
+        i64::MAX
+    }
+
+    fn last_active(&self, idx: usize) -> i64 {
+        let entry = self.map[idx].lru_idx.and_then(|lru_idx| self.lru.get(lru_idx));
+        entry.map_or(i64::MIN, |(_, Reverse(last_active))| *last_active)
+    }
+    fn invalidate(&mut self, idx: usize) {
+        let entry = &mut self.map[idx];
+        for slot in &entry.frag_slots[..entry.fragment_count as usize] {
+            self.frags[*slot as usize] = None;
+            self.unused_frags.push(*slot);
+        }
+        if let Some(lru_idx) = entry.lru_idx.take() {
+            self.lru.remove(lru_idx);
+        }
+        entry.key = 0;
+        entry.fragment_have = 0;
+        entry.fragment_count = 0;
+        entry.packet_size = 0;
+    }
+}
+
 #[test]
 fn test_cache() {
     use parking_lot::Mutex;
@@ -242,26 +474,9 @@ fn test_cache() {
         drop(x);
         r.wrapping_mul(0x2545F4914F6CDD1Du64)
     }
-    use crate::crypto_impl::*;
-    struct C {}
-    impl CryptoLayer for C {
-        type Rng = rand_core::OsRng;
-        type PrpEnc = OpenSSLAes256Enc;
-        type PrpDec = OpenSSLAes256Dec;
-        type Aead = OpenSSLAesGcm;
-        type AeadPool = OpenSSLAesGcmPool;
-        type Hash = CrateSha512;
-        type Hmac = CrateHmacSha512;
-        type PublicKey = CrateP384PublicKey;
-        type KeyPair = CrateP384KeyPair;
-        type Kem = CrateKyber1024PrivateKey;
-
-        type SessionData = ();
-        type FingerprintData = ();
-        type IncomingPacketBuffer = Vec<u8>;
-    }
+    use crate::sim::{SeededRng, SimCrypto};
 
-    let mut cache = UnassociatedFragCache::<C>::new();
+    let mut cache = UnassociatedFragCache::<SimCrypto>::new(&mut SeededRng::new(1));
     let mut assembled = Assembled::new();
 
     let mut time = 0;
@@ -352,3 +567,73 @@ fn test_cache() {
         }
     }
 }
+
+#[test]
+fn test_lru_cache() {
+    use crate::sim::SeededRng;
+    crate::sim::sim_crypto!(C {
+        const FRAG_CACHE_POLICY: FragCachePolicy = FragCachePolicy::Lru;
+    });
+
+    let mut cache = FragCache::<C>::new(&mut SeededRng::new(1));
+    let mut assembled = Assembled::new();
+    let nonce = |id: u32| {
+        let mut nonce = [0; 12];
+        nonce[..4].copy_from_slice(&id.to_be_bytes());
+        nonce
+    };
+
+    // One packet receives a fragment before every new packet. Together the new packets need far
+    // more fragment slots and hash table slots than the cache has, but since the packet is always
+    // the most recently active when a new packet arrives it must never be evicted.
+    let mut time = 0;
+    for fragment_no in 0..MAX_FRAGMENTS {
+        assembled.clear();
+        let fragment = vec![fragment_no as u8];
+        let expiry = cache.assemble(
+            &nonce(0),
+            0,
+            1,
+            fragment,
+            fragment_no,
+            MAX_FRAGMENTS,
+            time,
+            &mut assembled,
+        );
+        assert_eq!(expiry.is_some(), fragment_no == 0, "The packet was evicted");
+        time += 1;
+        if fragment_no + 1 < MAX_FRAGMENTS {
+            assert!(assembled.is_empty(), "Cache returned an incomplete packet");
+            let spam_id = fragment_no as u32 + 1;
+            cache.assemble(&nonce(spam_id), 0, 1, vec![0], 0, MAX_FRAGMENTS, time, &mut assembled);
+            time += 1;
+        }
+    }
+    assert_eq!(
+        assembled.as_ref().len(),
+        MAX_FRAGMENTS,
+        "Packet was dropped from the cache"
+    );
+    for (i, fragment) in assembled.as_ref().iter().enumerate() {
+        assert_eq!(fragment[0], i as u8, "Cache returned a corrupted packet");
+    }
+
+    // The timeout is measured from the last fragment a packet received.
+    assembled.clear();
+    let timeout = C::SETTINGS.fragment_assembly_timeout as i64;
+    assert!(cache.check_for_expiry(time) < i64::MAX);
+    cache.assemble(&nonce(u32::MAX), 0, 1, vec![0], 0, 2, time, &mut assembled);
+    assert_eq!(cache.check_for_expiry(time + timeout - 1), time + timeout);
+    cache.assemble(
+        &nonce(u32::MAX),
+        0,
+        1,
+        vec![1],
+        1,
+        2,
+        time + timeout - 1,
+        &mut assembled,
+    );
+    assert_eq!(assembled.as_ref().len(), 2, "Packet expired before its timeout");
+    assert_eq!(cache.check_for_expiry(time + 2 * timeout), i64::MAX);
+}
@@ -0,0 +1,159 @@
+use std::marker::PhantomData;
+use std::mem::{needs_drop, size_of, MaybeUninit};
+
+use memmap2::MmapMut;
+
+use crate::fragged::{Assembled, FragmentBuffer};
+use crate::proto::MAX_FRAGMENTS;
+
+/// Packet defragmenter that stores partially assembled packets in an anonymous memory map.
+///
+/// `Fragged` stores its fragment slots inline, so every session permanently carries
+/// `SESSION_MAX_FRAGMENTS_OOO` sets of slots whether or not it ever receives a fragmented packet.
+/// This type only maps memory for its slots when the first fragment of a packet arrives, and
+/// unmaps it again as soon as that packet is assembled or abandoned.
+///
+/// Mapping and unmapping costs a pair of system calls per fragmented packet, so this is only
+/// worthwhile on servers with many sessions that receive large fragmented packets.
+/// It is enabled with the `mmap-frags` feature.
+pub struct MmapFragBuffer<Fragment> {
+    nonce: u64,
+    count: u32,
+    have: u64,
+    map: Option<MmapMut>,
+    _frags: PhantomData<Fragment>,
+}
+
+impl<Fragment> MmapFragBuffer<Fragment> {
+    const MAP_SIZE: usize = if size_of::<Fragment>() == 0 {
+        1
+    } else {
+        size_of::<Fragment>() * MAX_FRAGMENTS
+    };
+
+    /// Returns a pointer to the slot for fragment `i`.
+    /// The memory map must exist and `i` must be less than `MAX_FRAGMENTS`.
+    unsafe fn slot(&mut self, i: usize) -> *mut MaybeUninit<Fragment> {
+        debug_assert!(i < MAX_FRAGMENTS);
+        let map = self.map.as_mut().unwrap_unchecked();
+        (map.as_mut_ptr() as *mut MaybeUninit<Fragment>).add(i)
+    }
+}
+
+impl<Fragment> FragmentBuffer<Fragment> for MmapFragBuffer<Fragment> {
+    fn new() -> Self {
+        Self {
+            nonce: u64::MAX,
+            count: 0,
+            have: 0,
+            map: None,
+            _frags: PhantomData,
+        }
+    }
+
+    /// If the memory map cannot be created the fragment is dropped, exactly as if it had been
+    /// lost in transit.
+    fn assemble(
+        &mut self,
+        nonce: u64,
+        fragment: Fragment,
+        fragment_no: usize,
+        fragment_count: usize,
+        ret_assembled: &mut Assembled<Fragment>,
+    ) {
+        if fragment_no < fragment_count && fragment_count <= MAX_FRAGMENTS {
+            // If the counter has changed, reset the structure to receive a new packet.
+            if nonce != self.nonce {
+                self.drop_in_place();
+                self.count = fragment_count as u32;
+                self.nonce = nonce;
+            }
+
+            let got = 1u64.wrapping_shl(fragment_no as u32);
+            if got & self.have == 0 && self.count == fragment_count as u32 {
+                if self.map.is_none() {
+                    // Anonymous maps are page aligned, which satisfies the alignment of any
+                    // reasonable fragment type.
+                    debug_assert!(std::mem::align_of::<Fragment>() <= 4096);
+                    match MmapMut::map_anon(Self::MAP_SIZE) {
+                        Ok(map) => self.map = Some(map),
+                        Err(_) => return,
+                    }
+                }
+                self.have |= got;
+                unsafe {
+                    (*self.slot(fragment_no)).write(fragment);
+                    if self.have == 1u64.wrapping_shl(self.count) - 1 {
+                        self.have = 0;
+                        self.count = 0;
+                        self.nonce = u64::MAX;
+                        for i in 0..fragment_count {
+                            ret_assembled.push((*self.slot(i)).assume_init_read());
+                        }
+                        // Every fragment has been moved out, so the map can be released.
+                        self.map = None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn drop_in_place(&mut self) {
+        if needs_drop::<Fragment>() {
+            let mut have = self.have;
+            let mut i = 0;
+            while have != 0 {
+                if (have & 1) != 0 {
+                    unsafe { (*self.slot(i)).assume_init_drop() };
+                }
+                have = have.wrapping_shr(1);
+                i += 1;
+            }
+        }
+        self.have = 0;
+        self.count = 0;
+        self.nonce = u64::MAX;
+        self.map = None;
+    }
+}
+
+impl<Fragment> Drop for MmapFragBuffer<Fragment> {
+    fn drop(&mut self) {
+        self.drop_in_place();
+    }
+}
+
+#[test]
+fn test_mmap_frag_buffer() {
+    use std::rc::Rc;
+    let mut buffer = MmapFragBuffer::<Rc<Vec<u8>>>::new();
+    let mut assembled = Assembled::new();
+    let frags: Vec<_> = (0..4u8).map(|i| Rc::new(vec![i; 100])).collect();
+
+    for i in [2, 0, 3] {
+        buffer.assemble(1, frags[i].clone(), i, 4, &mut assembled);
+        assert!(assembled.is_empty());
+        assert!(buffer.map.is_some());
+    }
+    // A repeated fragment is ignored.
+    buffer.assemble(1, frags[0].clone(), 0, 4, &mut assembled);
+    assert_eq!(Rc::strong_count(&frags[0]), 2);
+
+    buffer.assemble(1, frags[1].clone(), 1, 4, &mut assembled);
+    assert_eq!(assembled.len(), 4);
+    for (i, frag) in assembled.iter().enumerate() {
+        assert_eq!(frag[0], i as u8);
+    }
+    assert!(buffer.map.is_none());
+    assembled.clear();
+
+    // A new counter abandons the incomplete packet and drops its fragments.
+    buffer.assemble(2, frags[0].clone(), 0, 2, &mut assembled);
+    buffer.assemble(3, frags[1].clone(), 1, 2, &mut assembled);
+    assert!(assembled.is_empty());
+    assert_eq!(Rc::strong_count(&frags[0]), 1);
+    assert_eq!(Rc::strong_count(&frags[1]), 2);
+
+    drop(buffer);
+    assert!(frags.iter().all(|frag| Rc::strong_count(frag) == 1));
+}
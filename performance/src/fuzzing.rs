@@ -0,0 +1,65 @@
+use alloc::vec::Vec;
+use core::hash::Hash;
+
+use arrayvec::ArrayVec;
+
+use crate::application::{ApplicationLayer, CryptoLayer};
+use crate::crypto::Sha512Hash;
+use crate::fragged::{self, Assembled, Fragged, FragmentBuffer};
+use crate::proto::{MAX_FRAGMENTS, PACKET_TYPE_HANDSHAKE_HELLO};
+use crate::result::ReceiveError;
+use crate::zeta::{received_x1_trans, to_nonce};
+use crate::zssp::Context;
+
+/// Process the body of a handshake hello as if it had already passed the challenge layer.
+///
+/// `x1` is everything between the header and the challenge, and `counter` is the counter from
+/// the header of the hello. Any reply is discarded.
+pub fn received_x1<C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
+    ctx: &Context<C>,
+    remote_address: &impl Hash,
+    counter: u64,
+    x1: &mut [u8],
+) -> Result<Option<i64>, ReceiveError<C>> {
+    let nonce = to_nonce(PACKET_TYPE_HANDSHAKE_HELLO, counter);
+    received_x1_trans(app, &ctx.0, &mut C::Hash::new(), remote_address, &nonce, x1, |_, _| {})
+}
+
+/// Concatenate the payloads of the fragments of a control packet into `buffer`, as `receive`
+/// does before processing a control packet that arrived in more than one fragment.
+pub fn concat_payloads<'a, const CAP: usize>(
+    fragments: &[Vec<u8>],
+    buffer: &'a mut ArrayVec<u8, CAP>,
+) -> Option<&'a mut [u8]> {
+    fragged::concat_payloads(fragments, buffer)
+}
+
+/// The defragmentation buffer used by sessions, with owned fragments.
+pub struct Defragmenter(Fragged<Vec<u8>, MAX_FRAGMENTS>);
+
+impl Default for Defragmenter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Defragmenter {
+    /// Create an empty defragmentation buffer.
+    pub fn new() -> Self {
+        Self(Fragged::new())
+    }
+    /// Add a fragment, returning all fragments of its packet in order once every one of them
+    /// has been received. Returns an empty `Vec` otherwise.
+    pub fn assemble(
+        &mut self,
+        counter: u64,
+        fragment: Vec<u8>,
+        fragment_no: usize,
+        fragment_count: usize,
+    ) -> Vec<Vec<u8>> {
+        let mut assembled = Assembled::new();
+        self.0
+            .assemble(counter, fragment, fragment_no, fragment_count, &mut assembled);
+        assembled.into_iter().collect()
+    }
+}
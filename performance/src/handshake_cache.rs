@@ -1,80 +1,188 @@
-use std::num::NonZeroU32;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use parking_lot::RwLock;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::hash::{BuildHasher, Hash};
+use core::num::NonZeroU32;
 
+use rand_core::RngCore;
+
+use crate::application::CryptoLayer;
+use crate::collections::{random_state, HashMap, RandomState};
+use crate::sync::atomic::{AtomicBool, Ordering};
+use crate::sync::RwLock;
 use crate::zeta::StateB2;
-use crate::{application::CryptoLayer, proto::MAX_UNASSOCIATED_HANDSHAKE_STATES};
 
-pub(crate) struct UnassociatedHandshakeCache<Application: CryptoLayer> {
+/// `T` is only generic so the cache can be tested without constructing real handshake states.
+pub(crate) struct UnassociatedHandshakeCache<Application: CryptoLayer, T = Arc<StateB2<Application>>> {
     has_pending: AtomicBool, // Allowed to be falsely positive
-    cache: RwLock<CacheInner<Application>>,
+    address_salt: RandomState,
+    cache: RwLock<CacheInner<T>>,
+    _app: core::marker::PhantomData<fn() -> Application>,
+}
+/// The reason an older handshake was dropped from the cache to make room for a new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eviction {
+    /// No handshake was dropped.
+    None,
+    /// The remote address of the new handshake already had as many handshakes in progress as it
+    /// is allowed, so its own oldest handshake was dropped.
+    AddressLimit,
+    /// The cache was full, so the oldest handshake was dropped.
+    Capacity,
 }
-/// SoA format
-struct CacheInner<C: CryptoLayer> {
-    local_ids: [Option<NonZeroU32>; MAX_UNASSOCIATED_HANDSHAKE_STATES],
-    expiries: [i64; MAX_UNASSOCIATED_HANDSHAKE_STATES],
-    handshakes: [Option<Arc<StateB2<C>>>; MAX_UNASSOCIATED_HANDSHAKE_STATES],
+struct CacheInner<T> {
+    /// Maps each local id to its expiry, the salted hash of its remote address and its handshake.
+    handshakes: HashMap<NonZeroU32, (i64, u64, T)>,
+    /// The number of handshakes held for each salted remote address hash.
+    address_counts: HashMap<u64, usize>,
+    /// Every inserted id in order of insertion, and therefore in order of expiry.
+    /// Ids that were removed early are left in the ring until they reach the front, so an entry
+    /// is only live if its expiry still matches the one in `handshakes`.
+    expiry_ring: VecDeque<(i64, NonZeroU32)>,
+}
+impl<T> CacheInner<T> {
+    fn is_live(&self, expiry: i64, local_id: NonZeroU32) -> bool {
+        matches!(self.handshakes.get(&local_id), Some((e, _, _)) if *e == expiry)
+    }
+    fn remove_entry(&mut self, local_id: NonZeroU32) -> bool {
+        if let Some((_, address, _)) = self.handshakes.remove(&local_id) {
+            if let Some(count) = self.address_counts.get_mut(&address) {
+                *count -= 1;
+                if *count == 0 {
+                    self.address_counts.remove(&address);
+                }
+            }
+            true
+        } else {
+            false
+        }
+    }
+    /// Drops every expired or already removed entry from the front of the ring, and returns the
+    /// expiry of the oldest entry left.
+    fn remove_expired(&mut self, current_time: i64) -> Option<i64> {
+        while let Some(&(expiry, local_id)) = self.expiry_ring.front() {
+            if self.is_live(expiry, local_id) {
+                if expiry > current_time {
+                    return Some(expiry);
+                }
+                self.remove_entry(local_id);
+            }
+            self.expiry_ring.pop_front();
+        }
+        None
+    }
+    /// Drops the oldest live entry to make room for a new one.
+    fn remove_oldest(&mut self) {
+        while let Some((expiry, local_id)) = self.expiry_ring.pop_front() {
+            if self.is_live(expiry, local_id) {
+                self.remove_entry(local_id);
+                return;
+            }
+        }
+    }
+    /// Drops the oldest live entry belonging to `address`. Its dead entry in the ring is cleaned up
+    /// once it reaches the front.
+    fn remove_oldest_of(&mut self, address: u64) {
+        let oldest = self.expiry_ring.iter().find(|(expiry, local_id)| {
+            matches!(self.handshakes.get(local_id), Some((e, a, _)) if e == expiry && *a == address)
+        });
+        if let Some(&(_, local_id)) = oldest {
+            self.remove_entry(local_id);
+        }
+    }
 }
 
-/// Linear-search cache for capping the memory consumption of handshake data.
-/// Designed specifically to have short and simple code that clearly bounds above
-/// memory consumption.
-impl<Application: CryptoLayer> UnassociatedHandshakeCache<Application> {
-    pub(crate) fn new() -> Self {
+/// Hashed cache for capping the memory consumption of handshake data.
+/// The number of handshakes held is bounded above by `Settings::max_unassociated_handshake_states`,
+/// when it is full the oldest handshake is evicted.
+/// The number of handshakes held for any one remote address is bounded above by
+/// `Settings::max_unassociated_handshake_states_per_address`, so that a single address cannot
+/// evict the handshakes of every other address. When an address reaches this bound its own
+/// oldest handshake is evicted.
+impl<Application: CryptoLayer, T: Clone> UnassociatedHandshakeCache<Application, T> {
+    pub(crate) fn new(rng: &mut impl RngCore) -> Self {
+        let capacity = Application::SETTINGS.max_unassociated_handshake_states.max(1);
         Self {
             has_pending: AtomicBool::new(false),
+            address_salt: random_state(rng),
             cache: RwLock::new(CacheInner {
-                local_ids: std::array::from_fn(|_| None),
-                expiries: std::array::from_fn(|_| 0),
-                handshakes: std::array::from_fn(|_| None),
+                handshakes: HashMap::with_capacity(capacity),
+                address_counts: HashMap::new(),
+                expiry_ring: VecDeque::with_capacity(capacity),
             }),
+            _app: core::marker::PhantomData,
         }
     }
-    pub(crate) fn get(&self, local_id: NonZeroU32) -> Option<Arc<StateB2<Application>>> {
+    /// The number of handshakes currently cached, including any that have expired but have not
+    /// yet been removed by `service`.
+    pub(crate) fn len(&self) -> usize {
+        self.cache.read().handshakes.len()
+    }
+    /// How long ago the oldest handshake still held by the cache was inserted, or `None` if the
+    /// cache is empty. Handshakes that have expired but have not yet been removed by `service`
+    /// are included, so an age greater than `fragment_assembly_timeout` means `service` is late.
+    #[cfg_attr(not(any(feature = "logging", feature = "tracing")), allow(dead_code))]
+    pub fn oldest_entry_age(&self, current_time: i64) -> Option<i64> {
         let cache = self.cache.read();
-        for (i, id) in cache.local_ids.iter().enumerate() {
-            if *id == Some(local_id) {
-                return cache.handshakes[i].clone();
-            }
-        }
-        None
+        let &(expiry, _) = cache
+            .expiry_ring
+            .iter()
+            .find(|(expiry, local_id)| cache.is_live(*expiry, *local_id))?;
+        Some(current_time - (expiry - Application::SETTINGS.fragment_assembly_timeout as i64))
+    }
+    pub(crate) fn get(&self, local_id: NonZeroU32) -> Option<T> {
+        self.cache.read().handshakes.get(&local_id).map(|(_, _, state)| state.clone())
     }
     /// Returns the timestamp at which `service` should be called again, or `None` if there is no update.
+    /// Also returns whether an older handshake had to be evicted to make room for this one.
     pub(crate) fn insert(
         &self,
         local_id: NonZeroU32,
-        state: Arc<StateB2<Application>>,
+        remote_address: &impl Hash,
+        state: T,
         current_time: i64,
-    ) -> Option<i64> {
+    ) -> (Option<i64>, Eviction) {
+        let capacity = Application::SETTINGS.max_unassociated_handshake_states.max(1);
+        let address_limit = Application::SETTINGS
+            .max_unassociated_handshake_states_per_address
+            .clamp(1, capacity);
+        let address = self.address_salt.hash_one(remote_address);
+
         let mut cache = self.cache.write();
-        let mut idx = 0;
-        for i in 0..cache.local_ids.len() {
-            if cache.local_ids[i].is_none() || cache.expiries[i] <= current_time {
-                idx = i;
-                break;
-            } else if cache.local_ids[i] == Some(local_id) {
-                return None;
-            }
+        if cache.handshakes.contains_key(&local_id) {
+            return (None, Eviction::None);
+        }
+        cache.remove_expired(current_time);
+        let mut eviction = Eviction::None;
+        if cache.address_counts.get(&address).copied().unwrap_or(0) >= address_limit {
+            cache.remove_oldest_of(address);
+            eviction = Eviction::AddressLimit;
+        } else if cache.handshakes.len() >= capacity {
+            cache.remove_oldest();
+            eviction = Eviction::Capacity;
+        }
+        // Removed ids can leave dead entries in the ring, don't let them accumulate.
+        if cache.expiry_ring.len() >= 2 * capacity {
+            let CacheInner { handshakes, expiry_ring, .. } = &mut *cache;
+            expiry_ring.retain(|(expiry, id)| matches!(handshakes.get(id), Some((e, _, _)) if e == expiry));
         }
         let expiry = current_time + Application::SETTINGS.fragment_assembly_timeout as i64;
-        cache.local_ids[idx] = Some(local_id);
-        cache.expiries[idx] = expiry;
-        cache.handshakes[idx] = Some(state);
+        cache.handshakes.insert(local_id, (expiry, address, state));
+        *cache.address_counts.entry(address).or_default() += 1;
+        cache.expiry_ring.push_back((expiry, local_id));
         self.has_pending.store(true, Ordering::Release);
-        Some(expiry)
+        (Some(expiry), eviction)
     }
+    /// Only one caller will ever receive `true` for a given insertion of `local_id`.
+    /// `receive` relies on this to guarantee a handshake is completed at most once.
     pub(crate) fn remove(&self, local_id: NonZeroU32) -> bool {
+        self.cache.write().remove_entry(local_id)
+    }
+    /// Drops every cached handshake.
+    pub(crate) fn clear(&self) {
         let mut cache = self.cache.write();
-        for (i, id) in cache.local_ids.iter().enumerate() {
-            if *id == Some(local_id) {
-                cache.local_ids[i] = None;
-                cache.expiries[i] = 0;
-                cache.handshakes[i] = None;
-                return true;
-            }
-        }
-        false
+        cache.handshakes.clear();
+        cache.address_counts.clear();
+        cache.expiry_ring.clear();
     }
     /// Returns the timestamp at which this function should be called again.
     pub(crate) fn service(&self, current_time: i64) -> i64 {
@@ -83,23 +191,124 @@ impl<Application: CryptoLayer> UnassociatedHandshakeCache<Application> {
         let mut next_service_time = i64::MAX;
         if self.has_pending.swap(false, Ordering::Acquire) {
             // Check for packet expiration
-            let mut cache = self.cache.write();
-            for i in 0..cache.local_ids.len() {
-                if cache.local_ids[i].is_some() {
-                    let expiry = cache.expiries[i];
-                    if expiry <= current_time {
-                        cache.local_ids[i] = None;
-                        cache.expiries[i] = 0;
-                        cache.handshakes[i] = None;
-                    } else {
-                        next_service_time = next_service_time.min(expiry);
-                    }
-                }
-            }
-            if next_service_time < i64::MAX {
+            if let Some(expiry) = self.cache.write().remove_expired(current_time) {
+                next_service_time = expiry;
                 self.has_pending.store(true, Ordering::Release);
             }
         }
         next_service_time
     }
 }
+
+#[test]
+fn test_handshake_cache_concurrent() {
+    use crate::application::Settings;
+    use crate::sim::SeededRng;
+    crate::sim::sim_crypto!(C {
+        const SETTINGS: Settings = Settings {
+            max_unassociated_handshake_states: 64,
+            max_unassociated_handshake_states_per_address: 64,
+            ..Settings::new_ms()
+        };
+    });
+    const THREADS: u32 = 8;
+    const IDS: u32 = 1000;
+    let cache = Arc::new(UnassociatedHandshakeCache::<C, u32>::new(&mut SeededRng::new(1)));
+
+    let threads = (0..THREADS)
+        .map(|t| {
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                for i in 0..IDS {
+                    let id = NonZeroU32::new(i + 1).unwrap();
+                    let time = (i / 64) as i64;
+                    cache.insert(id, &t, i, time);
+                    if let Some(v) = cache.get(id) {
+                        assert_eq!(v, i);
+                    }
+                    if (i + t) % 3 == 0 {
+                        cache.remove(id);
+                    }
+                    cache.service(time);
+                    assert!(cache.cache.read().handshakes.len() <= 64);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert!(cache.cache.read().expiry_ring.len() <= 2 * 64);
+
+    // Every thread races to remove the same handshakes, each must be removed exactly once.
+    let ids = (1..=64).map(|i| NonZeroU32::new(IDS + i).unwrap()).collect::<Vec<_>>();
+    for id in &ids {
+        assert!(cache.insert(*id, &id.get(), id.get(), i64::MAX / 2).0.is_some());
+    }
+    let removed = Arc::new((0..ids.len()).map(|_| std::sync::atomic::AtomicU32::new(0)).collect::<Vec<_>>());
+    let threads = (0..THREADS)
+        .map(|_| {
+            let cache = cache.clone();
+            let removed = removed.clone();
+            let ids = ids.clone();
+            std::thread::spawn(move || {
+                for (i, id) in ids.iter().enumerate() {
+                    if cache.remove(*id) {
+                        removed[i].fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    for count in removed.iter() {
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+    assert!(cache.cache.read().handshakes.is_empty());
+}
+
+#[test]
+fn test_handshake_cache_address_limit() {
+    use crate::application::Settings;
+    use crate::sim::SeededRng;
+    crate::sim::sim_crypto!(C {
+        const SETTINGS: Settings = Settings {
+            max_unassociated_handshake_states: 32,
+            max_unassociated_handshake_states_per_address: 8,
+            ..Settings::new_ms()
+        };
+    });
+    let cache = UnassociatedHandshakeCache::<C, u32>::new(&mut SeededRng::new(1));
+    let flooder = "10.0.0.1:9993";
+    let peer = "10.0.0.2:9993";
+    assert_eq!(cache.oldest_entry_age(0), None);
+
+    for i in 1..=8 {
+        let (_, eviction) = cache.insert(NonZeroU32::new(i).unwrap(), &flooder, i, 0);
+        assert_eq!(eviction, Eviction::None);
+    }
+    // Every further hello from the flooding address evicts one of its own handshakes.
+    let peer_id = NonZeroU32::new(1000).unwrap();
+    for i in 9..=500 {
+        let (_, eviction) = cache.insert(NonZeroU32::new(i).unwrap(), &flooder, i, 0);
+        assert_eq!(eviction, Eviction::AddressLimit);
+        if i == 250 {
+            assert_eq!(cache.insert(peer_id, &peer, 1000, 0).1, Eviction::None);
+        }
+    }
+    assert_eq!(cache.cache.read().handshakes.len(), 9);
+    // The flooding address could not evict the handshake of the other address.
+    assert_eq!(cache.get(peer_id), Some(1000));
+    assert!(cache.remove(peer_id));
+    // Only the newest handshakes of the flooding address remain.
+    assert!(cache.get(NonZeroU32::new(492).unwrap()).is_none());
+    for i in 493..=500 {
+        assert_eq!(cache.get(NonZeroU32::new(i).unwrap()), Some(i));
+    }
+    // Every handshake was inserted at time 0, including the expired ones `service` has yet to drop.
+    assert_eq!(cache.oldest_entry_age(20_000), Some(20_000));
+    cache.service(20_000);
+    assert_eq!(cache.oldest_entry_age(20_000), None);
+}
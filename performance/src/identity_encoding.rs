@@ -0,0 +1,52 @@
+//! The encodings of the identity Alice sends in her handshake completion, see
+//! `ApplicationLayer::compress_identity`.
+use alloc::borrow::Cow;
+
+use crate::proto::*;
+
+/// Compresses `identity` with raw deflate, and prefixes it with its encoding.
+/// Returns `None` if that would not make it any smaller.
+#[cfg(feature = "deflate")]
+pub(crate) fn encode_identity(identity: &[u8]) -> Option<alloc::vec::Vec<u8>> {
+    let mut encoded = alloc::vec![IDENTITY_ENCODING_DEFLATE];
+    encoded.extend(miniz_oxide::deflate::compress_to_vec(identity, 6));
+    (encoded.len() < identity.len()).then_some(encoded)
+}
+/// Undoes `encode_identity`. Returns `None` if the encoding is unknown or not supported by this
+/// build, if the identity is malformed, or if it decodes to more than `max_size` bytes.
+pub(crate) fn decode_identity(encoded: &[u8], max_size: usize) -> Option<Cow<'_, [u8]>> {
+    let (&encoding, identity) = encoded.split_first()?;
+    match encoding {
+        IDENTITY_ENCODING_RAW => (identity.len() <= max_size).then_some(Cow::Borrowed(identity)),
+        // The limit stops decompression as soon as it is reached, so a small identity cannot
+        // inflate into a large allocation.
+        #[cfg(feature = "deflate")]
+        IDENTITY_ENCODING_DEFLATE => {
+            let identity = miniz_oxide::inflate::decompress_to_vec_with_limit(identity, max_size);
+            identity.ok().map(Cow::Owned)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(feature = "deflate")]
+#[test]
+fn test_identity_encoding() {
+    let identity = "CN=node.example.com,O=Example\n".repeat(100).into_bytes();
+    let encoded = encode_identity(&identity).unwrap();
+    assert!(encoded.len() < identity.len() / 4);
+    assert_eq!(decode_identity(&encoded, identity.len()).unwrap(), &identity[..]);
+    assert!(decode_identity(&encoded, identity.len() - 1).is_none());
+    // A zip bomb is cut off at the limit.
+    let bomb = encode_identity(&[0u8; 1 << 20]).unwrap();
+    assert!(bomb.len() < IDENTITY_MAX_SIZE);
+    assert!(decode_identity(&bomb, 1 << 14).is_none());
+    // Random bytes do not compress.
+    let mut random = [0u8; 256];
+    rand_core::RngCore::fill_bytes(&mut crate::sim::SeededRng::new(1), &mut random);
+    assert!(encode_identity(&random).is_none());
+    assert_eq!(decode_identity(&[IDENTITY_ENCODING_RAW, 1, 2], 2).unwrap(), &[1, 2][..]);
+    assert!(decode_identity(&[IDENTITY_ENCODING_RAW, 1, 2], 1).is_none());
+    assert!(decode_identity(&[IDENTITY_ENCODING_DEFLATE + 1, 1, 2], 2).is_none());
+    assert!(decode_identity(&[], 2).is_none());
+}
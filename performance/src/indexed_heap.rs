@@ -5,6 +5,7 @@
  * (c) ZeroTier, Inc.
  * https://www.zerotier.com/
  */
+use alloc::vec::Vec;
 
 /// A generational index into an `IndexedBinaryHeap`.
 /// Used to perform direct interactions with specific items contained within the binary heap.
@@ -16,7 +17,10 @@
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct BinaryHeapIndex(usize, u64);
 
-const RESERVED_MARKER: u64 = 1;
+/// Marks an index that has been reserved but has no item in the heap.
+/// Reserved indices keep their generation so a stale index cannot free or fill them.
+const RESERVED_MARKER: usize = usize::MAX;
+/// Marks an index that is free to be reserved.
 const EMPTY_MARKER: u64 = 0;
 
 /// A simple priority queue built from a binary heap and a generational array.
@@ -115,18 +119,40 @@ impl<T, P: Ord> IndexedBinaryHeap<T, P> {
             }
         }
     }
-    fn remove_idx(&mut self, data_idx: usize) -> (T, P) {
-        self.swap(data_idx, self.data.len() - 1);
+    /// Take the item at `data_idx` out of the heap and restore the heap property.
+    /// The caller must update the map entry of the returned item.
+    fn take_idx(&mut self, data_idx: usize) -> (T, P, usize) {
+        let last_idx = self.data.len() - 1;
+        self.swap(data_idx, last_idx);
         let ret = self.data.pop().unwrap();
+        // The item that was moved into the hole may belong either above or below it.
+        if data_idx < last_idx {
+            if data_idx > 0 && self.data[data_idx].1 > self.data[(data_idx - 1) / 2].1 {
+                self.bubble_up(data_idx);
+            } else {
+                self.bubble_down(data_idx);
+            }
+        }
+        ret
+    }
+    fn remove_idx(&mut self, data_idx: usize) -> (T, P) {
+        let ret = self.take_idx(data_idx);
         self.map[ret.2] = (self.free_list_head, EMPTY_MARKER);
         self.free_list_head = ret.2;
 
-        self.bubble_down(data_idx);
-
         (ret.0, ret.1)
     }
     fn deref_index(&self, idx: BinaryHeapIndex) -> Option<usize> {
-        (idx.0 < self.map.len() && self.map[idx.0].1 == idx.1).then(|| self.map[idx.0].0)
+        (idx.0 < self.map.len() && self.map[idx.0].1 == idx.1 && self.map[idx.0].0 != RESERVED_MARKER)
+            .then(|| self.map[idx.0].0)
+    }
+    /// Returns the number of items in the heap, not counting reserved indices.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    /// Returns true if there are no items in the heap.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
     /// Pop off the current top item of the binary heap, removing it from the heap.
     /// Returns the item and its priority.
@@ -160,10 +186,10 @@ impl<T, P: Ord> IndexedBinaryHeap<T, P> {
         if self.free_list_head != usize::MAX {
             let pre_head = self.free_list_head;
             self.free_list_head = self.map[pre_head].0;
-            self.map[pre_head] = (0, RESERVED_MARKER);
+            self.map[pre_head] = (RESERVED_MARKER, self.generation);
             BinaryHeapIndex(pre_head, self.generation)
         } else {
-            self.map.push((0, RESERVED_MARKER));
+            self.map.push((RESERVED_MARKER, self.generation));
             BinaryHeapIndex(self.map.len() - 1, self.generation)
         }
     }
@@ -174,7 +200,7 @@ impl<T, P: Ord> IndexedBinaryHeap<T, P> {
     ///
     /// Amortized runtime: O(log(n)).
     pub fn push_reserved(&mut self, idx: BinaryHeapIndex, item: T, priority: P) -> bool {
-        if idx.0 < self.map.len() && self.map[idx.0].1 == RESERVED_MARKER {
+        if idx.0 < self.map.len() && self.map[idx.0] == (RESERVED_MARKER, idx.1) {
             let data_idx = self.data.len();
             self.map[idx.0] = (data_idx, idx.1);
 
@@ -196,7 +222,7 @@ impl<T, P: Ord> IndexedBinaryHeap<T, P> {
         if let Some(data_idx) = self.deref_index(idx) {
             if let Some(new_priority) = f(&self.data[data_idx].1) {
                 let lesser = self.data[data_idx].1 > new_priority;
-                let old_priority = std::mem::replace(&mut self.data[data_idx].1, new_priority);
+                let old_priority = core::mem::replace(&mut self.data[data_idx].1, new_priority);
                 if lesser {
                     self.bubble_down(data_idx);
                 } else {
@@ -223,7 +249,7 @@ impl<T, P: Ord> IndexedBinaryHeap<T, P> {
     /// Amortized runtime: O(1).
     pub fn change_item(&mut self, idx: BinaryHeapIndex, new_item: T) -> Option<T> {
         self.deref_index(idx)
-            .map(|data_idx| std::mem::replace(&mut self.data[data_idx].0, new_item))
+            .map(|data_idx| core::mem::replace(&mut self.data[data_idx].0, new_item))
     }
     /// If the given index maps to an item in the heap, this function will return a reference
     /// to that item and its priority.
@@ -248,20 +274,75 @@ impl<T, P: Ord> IndexedBinaryHeap<T, P> {
     ///
     /// Amortized runtime: O(log(n)).
     pub fn remove(&mut self, idx: BinaryHeapIndex) -> Option<(T, P)> {
-        if idx.0 < self.map.len() {
-            if self.map[idx.0].1 == RESERVED_MARKER {
+        if idx.0 < self.map.len() && self.map[idx.0].1 == idx.1 {
+            if self.map[idx.0].0 == RESERVED_MARKER {
                 self.map[idx.0] = (self.free_list_head, EMPTY_MARKER);
                 self.free_list_head = idx.0;
                 None
-            } else if self.map[idx.0].1 == idx.1 {
-                Some(self.remove_idx(self.map[idx.0].0))
             } else {
-                None
+                Some(self.remove_idx(self.map[idx.0].0))
             }
         } else {
             None
         }
     }
+    /// Remove the item associated with this index from the queue, returning the item if it exists.
+    /// Unlike `remove` the index itself is not freed, instead it is returned to the reserved state
+    /// so the item can later be placed back into the queue with `push_reserved`.
+    ///
+    /// Amortized runtime: O(log(n)).
+    pub fn unlink(&mut self, idx: BinaryHeapIndex) -> Option<(T, P)> {
+        let data_idx = self.deref_index(idx)?;
+        let ret = self.take_idx(data_idx);
+        self.map[ret.2].0 = RESERVED_MARKER;
+
+        Some((ret.0, ret.1))
+    }
+    /// Remove every item for which `f` returns false, freeing their indices.
+    /// Returns the number of items removed.
+    ///
+    /// Runtime: O(n).
+    pub fn retain(&mut self, mut f: impl FnMut(&T, &P) -> bool) -> usize {
+        let mut removed = 0;
+        let mut i = 0;
+        while i < self.data.len() {
+            if f(&self.data[i].0, &self.data[i].1) {
+                i += 1;
+            } else {
+                self.swap(i, self.data.len() - 1);
+                let ret = self.data.pop().unwrap();
+                self.map[ret.2] = (self.free_list_head, EMPTY_MARKER);
+                self.free_list_head = ret.2;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            for i in (0..self.data.len() / 2).rev() {
+                self.bubble_down(i);
+            }
+        }
+        removed
+    }
+    /// Release the memory held by free indices at the end of the index space, and any capacity
+    /// beyond what the current items and indices need.
+    /// Reserved indices and the indices of items in the heap remain valid.
+    ///
+    /// Runtime: O(n).
+    pub fn shrink_to_fit(&mut self) {
+        while matches!(self.map.last(), Some((_, EMPTY_MARKER))) {
+            self.map.pop();
+        }
+        // Some of the truncated indices may have been on the free list, so it is rebuilt.
+        self.free_list_head = usize::MAX;
+        for (i, entry) in self.map.iter_mut().enumerate().rev() {
+            if entry.1 == EMPTY_MARKER {
+                entry.0 = self.free_list_head;
+                self.free_list_head = i;
+            }
+        }
+        self.map.shrink_to_fit();
+        self.data.shrink_to_fit();
+    }
     /// Completely empty the binary heap of all items.
     ///
     /// This has no effect on the allocated capacity of the heap.
@@ -286,6 +367,9 @@ fn test() {
     }
     assert_eq!(queue.change_priority(r1, 1234), Some(12));
     assert_eq!(queue.remove(r0), None);
+    assert_eq!(queue.unlink(r1), Some((1234, 1234)));
+    assert_eq!(queue.change_priority(r1, 12), None);
+    assert!(queue.push_reserved(r1, 1234, 1234));
     let mut last = usize::MAX;
     while let Some((i, j)) = queue.pop() {
         assert_eq!(i, j);
@@ -293,3 +377,147 @@ fn test() {
         last = i;
     }
 }
+
+#[test]
+fn test_remove_from_middle_keeps_heap_order() {
+    let mut queue = IndexedBinaryHeap::new();
+    let mut indices = Vec::new();
+    for p in [100, 50, 90, 10, 20, 80, 85] {
+        indices.push(queue.push(p, p));
+    }
+    // The last item must move above the hole left by 10 rather than below it.
+    assert_eq!(queue.remove(indices[3]), Some((10, 10)));
+    assert!((1..queue.data.len()).all(|i| queue.data[(i - 1) / 2].1 >= queue.data[i].1));
+    assert_eq!(queue.unlink(indices[4]), Some((20, 20)));
+    assert!((1..queue.data.len()).all(|i| queue.data[(i - 1) / 2].1 >= queue.data[i].1));
+    let mut popped = Vec::new();
+    while let Some((i, _)) = queue.pop() {
+        popped.push(i);
+    }
+    assert_eq!(popped, [100, 90, 85, 80, 50]);
+}
+
+#[test]
+fn test_stale_index_cannot_touch_reused_slot() {
+    let mut queue = IndexedBinaryHeap::new();
+    let stale = queue.push(1, 1);
+    assert_eq!(queue.remove(stale), Some((1, 1)));
+    // The freed slot is reused by a new reservation.
+    let reserved = queue.reserve_index();
+    assert_eq!(queue.remove(stale), None);
+    assert!(!queue.push_reserved(stale, 2, 2));
+    assert!(queue.push_reserved(reserved, 3, 3));
+    assert_eq!(queue.unlink(reserved), Some((3, 3)));
+    assert_eq!(queue.remove(stale), None);
+    assert_eq!(queue.change_priority(stale, 5), None);
+    // Had the stale index freed the reservation, this would reuse its slot.
+    let other = queue.push(4, 4);
+    assert!(queue.push_reserved(reserved, 3, 3));
+    assert_eq!(queue.get(reserved), Some((&3, &3)));
+    assert_eq!(queue.get(other), Some((&4, &4)));
+    assert_eq!(queue.len(), 2);
+}
+
+#[test]
+fn test_retain_and_shrink() {
+    let mut queue = IndexedBinaryHeap::new();
+    let indices: Vec<_> = (0..1000).map(|i| (i, queue.push(i, (i * 7919) % 1000))).collect();
+    let reserved = queue.reserve_index();
+    assert_eq!(queue.retain(|i, _| i % 10 == 0 || *i < 5), 896);
+    assert_eq!(queue.len(), 104);
+    queue.shrink_to_fit();
+    assert!(queue.map.len() <= 1001);
+    assert!(queue.data.capacity() < 1000);
+    for (i, idx) in &indices {
+        assert_eq!(queue.get(*idx).is_some(), i % 10 == 0 || *i < 5);
+    }
+    assert!(queue.push_reserved(reserved, 1000, 0));
+
+    // The rest of the heap must be unaffected by reusing the freed indices.
+    for i in 0..500 {
+        queue.push(2000 + i, i);
+    }
+    let mut last = usize::MAX;
+    while let Some((_, p)) = queue.pop() {
+        assert!(p <= last);
+        last = p;
+    }
+    queue.shrink_to_fit();
+    assert_eq!(queue.map.len(), 0);
+}
+
+#[test]
+fn test_random_interleavings() {
+    // A xorshift generator keeps the sequence of operations reproducible.
+    let mut rng = 0x2545f4914f6cdd1du64;
+    let mut next = move |n: u64| {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng % n
+    };
+    let mut queue = IndexedBinaryHeap::new();
+    // Our model of the heap: every index we hold, with its item and priority if it is in the heap.
+    let mut model: Vec<(BinaryHeapIndex, Option<(u64, u64)>)> = Vec::new();
+    let mut dead = Vec::new();
+    for item in 0..20000u64 {
+        let i = next(model.len().max(1) as u64) as usize;
+        match next(8) {
+            0 => model.push((queue.reserve_index(), None)),
+            1 | 2 => {
+                let priority = next(100);
+                model.push((queue.push(item, priority), Some((item, priority))));
+            }
+            3 if i < model.len() && model[i].1.is_none() => {
+                let priority = next(100);
+                assert!(queue.push_reserved(model[i].0, item, priority));
+                model[i].1 = Some((item, priority));
+            }
+            4 if i < model.len() => {
+                let priority = next(100);
+                let expected = model[i].1.map(|(_, p)| p);
+                assert_eq!(queue.change_priority(model[i].0, priority), expected);
+                if let Some((_, p)) = &mut model[i].1 {
+                    *p = priority;
+                }
+            }
+            5 if i < model.len() => {
+                let (idx, entry) = model.swap_remove(i);
+                assert_eq!(queue.remove(idx), entry);
+                dead.push(idx);
+            }
+            6 if i < model.len() => {
+                assert_eq!(queue.unlink(model[i].0), model[i].1.take());
+            }
+            7 => {
+                let threshold = next(100);
+                queue.retain(|_, p| *p >= threshold);
+                for (idx, entry) in std::mem::take(&mut model) {
+                    if !matches!(entry, Some((_, p)) if p < threshold) {
+                        model.push((idx, entry));
+                    } else {
+                        dead.push(idx);
+                    }
+                }
+                if next(4) == 0 {
+                    queue.shrink_to_fit();
+                }
+            }
+            _ => {}
+        }
+        // Indices that were removed must never reach another item, even after their slot is reused.
+        for idx in dead.iter().rev().take(4) {
+            assert!(queue.get(*idx).is_none());
+            assert!(!queue.push_reserved(*idx, 0, 0));
+        }
+        if let Some((item, priority, _)) = queue.peek() {
+            let max = model.iter().filter_map(|(_, e)| e.map(|(_, p)| p)).max();
+            assert_eq!(Some(*priority), max);
+            assert!(model.iter().any(|(_, e)| *e == Some((*item, *priority))));
+        }
+    }
+    for (idx, entry) in &model {
+        assert_eq!(queue.get(*idx).map(|(i, p)| (*i, *p)), *entry);
+    }
+    assert_eq!(queue.len(), model.iter().filter(|(_, e)| e.is_some()).count());
+}
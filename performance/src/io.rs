@@ -0,0 +1,94 @@
+#[cfg(feature = "std")]
+pub use std::io::{Error, Result, Write};
+
+#[cfg(not(feature = "std"))]
+pub use no_std::*;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use alloc::vec::Vec;
+    use core::fmt;
+
+    /// An error reported by the application, usually by its ratchet storage, or by a `Write`
+    /// implementation.
+    ///
+    /// This replaces `std::io::Error`, so it only carries a static message.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Error(&'static str);
+    impl Error {
+        /// Create an error with the given message.
+        pub const fn new(message: &'static str) -> Self {
+            Self(message)
+        }
+        /// The message this error was created with.
+        pub const fn message(&self) -> &'static str {
+            self.0
+        }
+    }
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+    impl core::error::Error for Error {}
+
+    /// A specialized `Result` type for I/O operations, like `std::io::Result`.
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// The subset of `std::io::Write` used by ZSSP.
+    pub trait Write {
+        /// Write some of `buf`, returning how many bytes were written.
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+        /// Write all of `buf`.
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => return Err(Error::new("failed to write whole buffer")),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+        /// Write a formatted string, so that `write!` and `writeln!` can be used.
+        fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> Result<()> {
+            struct Adapter<'a, W: ?Sized> {
+                inner: &'a mut W,
+                error: Option<Error>,
+            }
+            impl<W: Write + ?Sized> fmt::Write for Adapter<'_, W> {
+                fn write_str(&mut self, s: &str) -> fmt::Result {
+                    self.inner.write_all(s.as_bytes()).map_err(|e| {
+                        self.error = Some(e);
+                        fmt::Error
+                    })
+                }
+            }
+            let mut adapter = Adapter { inner: self, error: None };
+            match fmt::write(&mut adapter, args) {
+                Ok(()) => Ok(()),
+                Err(_) => Err(adapter.error.unwrap_or(Error::new("formatter error"))),
+            }
+        }
+    }
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+    /// Like `std`, writing to a slice fills it from the front and advances it past the written bytes.
+    impl Write for &mut [u8] {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let n = buf.len().min(self.len());
+            let (a, b) = core::mem::take(self).split_at_mut(n);
+            a.copy_from_slice(&buf[..n]);
+            *self = b;
+            Ok(n)
+        }
+    }
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+    }
+}
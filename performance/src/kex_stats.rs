@@ -0,0 +1,105 @@
+use crate::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use crate::sync::Mutex;
+
+/// How long the key exchanges of a session took, including the time spent waiting on resends.
+///
+/// All durations are measured with `ApplicationLayer::time`, so they are in milliseconds if the
+/// application follows the recommendation of that function.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KexStats {
+    /// The duration of the initial handshake, or `None` if it has not completed.
+    /// For Alice this includes any restarts of the handshake after a timeout.
+    pub handshake_duration_ms: Option<u64>,
+    /// The number of handshake packets we resent during the initial handshake.
+    pub handshake_resends: u32,
+    /// The number of rekeys initiated by us that have completed.
+    /// Rekeys initiated by the remote peer are not timed, since we only learn of them once they
+    /// are already underway.
+    pub rekeys: u64,
+    /// The duration of the last rekey initiated by us, or `None` if none have completed.
+    pub last_rekey_duration_ms: Option<u64>,
+    /// The number of rekey packets we resent during the last rekey initiated by us.
+    pub last_rekey_resends: u32,
+    /// The longest duration of any rekey initiated by us.
+    pub max_rekey_duration_ms: u64,
+}
+
+/// Times the key exchanges of a single session.
+pub(crate) struct KexTimer {
+    /// The time at which we started our current rekey, or `i64::MIN` if there is none.
+    rekey_start_time: AtomicI64,
+    /// The number of key exchange packets resent since the current key exchange started.
+    resends: AtomicU32,
+    stats: Mutex<KexStats>,
+}
+
+impl KexTimer {
+    pub fn new() -> Self {
+        Self {
+            rekey_start_time: AtomicI64::new(i64::MIN),
+            resends: AtomicU32::new(0),
+            stats: Mutex::new(KexStats::default()),
+        }
+    }
+    /// Count a resent key exchange packet.
+    pub fn resent(&self) {
+        self.resends.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn start_rekey(&self, current_time: i64) {
+        self.rekey_start_time.store(current_time, Ordering::Relaxed);
+        self.resends.store(0, Ordering::Relaxed);
+    }
+    /// Record the completion of the initial handshake, returning the number of resends it took.
+    pub fn complete_handshake(&self, duration_ms: u64) -> u32 {
+        let resends = self.resends.swap(0, Ordering::Relaxed);
+        let mut stats = self.stats.lock();
+        stats.handshake_duration_ms = Some(duration_ms);
+        stats.handshake_resends = resends;
+        resends
+    }
+    /// Record the completion of the current rekey, returning its start time, its duration and the
+    /// number of resends it took. Returns `None` if we did not start the current rekey.
+    pub fn complete_rekey(&self, current_time: i64) -> Option<(i64, u64, u32)> {
+        let start_time = self.rekey_start_time.swap(i64::MIN, Ordering::Relaxed);
+        if start_time == i64::MIN {
+            return None;
+        }
+        let duration_ms = current_time.saturating_sub(start_time).max(0) as u64;
+        let resends = self.resends.swap(0, Ordering::Relaxed);
+        let mut stats = self.stats.lock();
+        stats.rekeys += 1;
+        stats.last_rekey_duration_ms = Some(duration_ms);
+        stats.last_rekey_resends = resends;
+        stats.max_rekey_duration_ms = stats.max_rekey_duration_ms.max(duration_ms);
+        Some((start_time, duration_ms, resends))
+    }
+    pub fn stats(&self) -> KexStats {
+        *self.stats.lock()
+    }
+}
+
+#[test]
+fn test_kex_timer() {
+    let timer = KexTimer::new();
+    timer.resent();
+    timer.resent();
+    assert_eq!(timer.complete_handshake(1500), 2);
+    // A rekey that we did not start is not timed.
+    assert_eq!(timer.complete_rekey(2000), None);
+
+    timer.resent();
+    timer.start_rekey(10_000);
+    timer.resent();
+    assert_eq!(timer.complete_rekey(10_250), Some((10_000, 250, 1)));
+    timer.start_rekey(20_000);
+    assert_eq!(timer.complete_rekey(20_100), Some((20_000, 100, 0)));
+    assert_eq!(timer.complete_rekey(20_200), None);
+
+    let stats = timer.stats();
+    assert_eq!(stats.handshake_duration_ms, Some(1500));
+    assert_eq!(stats.handshake_resends, 2);
+    assert_eq!(stats.rekeys, 2);
+    assert_eq!(stats.last_rekey_duration_ms, Some(100));
+    assert_eq!(stats.last_rekey_resends, 0);
+    assert_eq!(stats.max_rekey_duration_ms, 250);
+}
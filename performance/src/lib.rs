@@ -36,8 +36,27 @@
 //!  - **KBKDF**: Key mixing, sub-key derivation
 //!  - **AES-256**: Single block encryption of header to harden packet fragmentation protocol
 //!  - **AES-256-GCM**: Authenticated encryption
+//!
+//! ## WebAssembly
+//!
+//! The high performance implementation builds for `wasm32-unknown-unknown` with its OpenSSL backed defaults disabled and the pure Rust implementations of every primitive enabled instead: `--no-default-features --features std,p384,sha2,pqc_kyber,aes`. Leaving out `std` as well builds ZSSP as `no_std` with `alloc`.
+//!
+//!  - **Randomness**: ZSSP only uses the RNG given to `Context::new`. To use `rand_core::OsRng` in a browser the final binary must enable the `js` feature of the `getrandom` crate.
+//!  - **Time**: ZSSP never reads a clock itself. `ApplicationLayer::time` can be implemented with `Date.now()` or `performance.now()`.
+//!  - **Ratchet storage**: `FileRatchetStore` needs a file system, so browsers must persist ratchet states some other way, such as with IndexedDB.
+//!
+//! The `wasm_handshake` example runs a full handshake over an in-memory channel with this configuration.
+//!
+//! ## C Interface
+//!
+//! The `ffi` feature of the high performance implementation exports a C interface, declared in `include/zssp.h`, for applications written in C or C++. It can be built as a static library with `cargo rustc -p zssp --release --features ffi --crate-type staticlib`.
+//!
+//! Contexts and sessions are opaque pointers, and packets and payloads are passed as a pointer and a length. Sessions are reference counted, so every handle ZSSP returns must be released with `zssp_session_release`. The application supplies its clock, its senders and the `ApplicationLayer` hooks as function pointers in a `zssp_callbacks_t`, and errors are reported as the fixed `ZSSP_ERR_` codes. The loopback test in `tests/ffi` runs a full handshake through this interface.
 #![warn(missing_docs, rust_2018_idioms)]
 #![allow(clippy::too_many_arguments, clippy::type_complexity, clippy::assertions_on_constants)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
 /// A collection of implementation-independent traits for the various specific cryptographic
 /// algorithms ZSSP depends on.
@@ -51,13 +70,24 @@
 ///
 /// The `crypto_impl` module contains implementations of these traits in terms of popular Rust
 /// crates.
+///
+/// # Header protection
+///
+/// `CryptoLayer::PrpEnc` and `CryptoLayer::PrpDec` encrypt one block of the header of every
+/// fragment with AES-256, which must be a pseudo-random permutation rather than just a
+/// pseudo-random function, since the receiver has to invert it for every fragment it receives.
+/// See `CryptoLayer::PrpEnc` for details.
 pub mod crypto;
 /// A module containing optional implementations of the ZSSP `crypto` traits in terms of popular
 /// Rust crates. Some of these crates are not thoroughly audited, so use at your own risk.
 ///
 /// The current version of the AES crate does not support stream encryption. For this reason
-/// ZSSP's AES trait is implemented with OpenSSL instead. It has been optimized for
+/// ZSSP's AES trait is implemented with OpenSSL by default. It has been optimized for
 /// hardware accelerated, parrallel encryption and decryption.
+/// The `aes` feature adds a slower pure Rust implementation built from the aes, ctr and ghash
+/// crates, for targets that OpenSSL does not support such as WebAssembly.
+/// The `gcm-siv` feature adds `AesGcmSivPool`, which lets sessions use the nonce misuse resistant
+/// AES-GCM-SIV instead of AES-GCM, at a cost in throughput.
 ///
 /// Note that none of these crates are FIPS certified, meaning a build of ZSSP using them will not
 /// be FIPS compliant. However lack of FIPS compliance by no means implies lack of security or lack
@@ -67,19 +97,71 @@ pub mod crypto;
 pub mod crypto_impl;
 
 mod antireplay;
+#[cfg(feature = "compact-window")]
+mod antireplay_compact;
 mod challenge;
+mod collections;
+mod fault_stats;
+/// A C interface to ZSSP, declared in `include/zssp.h`, for applications that cannot link
+/// against the Rust API directly.
+///
+/// Sessions created through it use the default cryptography, and their static key is generated by
+/// `zssp_context_new`. It is enabled with the `ffi` feature.
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod frag_cache;
 mod fragged;
+#[cfg(feature = "mmap-frags")]
+mod fragged_mmap;
+/// Entry points into the internals of ZSSP for the fuzz targets in `fuzz/` and the benchmarks.
+/// Only available with the `fuzzing` feature, and not part of the stable API.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
 mod handshake_cache;
+mod identity_encoding;
 /// A module that implements a priority queue using a binary heap.
 /// Generational indexing is used to improve performance and simplify lifetime management.
 ///
 /// This module is used by this implementation of ZSSP, but it isn't a core component of the protocol.
 /// Rather, it is a reuseable component that you may find useful on its own.
 pub mod indexed_heap;
+/// The I/O types used by the ZSSP API.
+///
+/// With the `std` feature these are re-exports from `std::io`. Without it they are minimal
+/// replacements, so the same API can be used on targets without an operating system.
+pub mod io;
+mod kex_stats;
 mod log_event;
+mod metrics;
 mod ratchet_state;
+mod reliable;
+/// Reference implementations of the ratchet storage an `ApplicationLayer` must provide, along
+/// with tools to check the consistency of other implementations.
+///
+/// These are not needed to use ZSSP, but they are tested against this implementation, so they
+/// are a correct model to copy or to use as is.
+#[cfg(feature = "std")]
+pub mod ratchet_storage;
+mod session_map;
+#[cfg(test)]
+mod sim;
 mod symmetric_state;
+mod sync;
+/// Known-answer test vectors of the initial handshake, for checking independent implementations
+/// of ZSSP against this one. The published vectors are in `tests/vectors/handshake.json`, and the
+/// `test_vectors` example regenerates them.
+/// Only available with the `test-vectors` feature, and not part of the stable API.
+#[cfg(feature = "test-vectors")]
+#[doc(hidden)]
+pub mod test_vectors;
+/// An in-process pair of contexts with a virtual clock and a link that can drop packets, so that
+/// applications can test their own reconnect and error handling against real ZSSP sessions.
+/// It is enabled with the `testing` feature.
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "udp")]
+mod udp;
 mod zeta;
 mod zssp;
 
@@ -93,6 +175,13 @@ pub mod proto;
 /// The collection of the major return types for ZSSP.
 pub mod result;
 
+pub use crate::antireplay::ReplayStats;
+pub use crate::challenge::{ChallengeFailure, ChallengeStats};
+pub use crate::fault_stats::{FaultCounts, FaultStats};
+pub use crate::handshake_cache::Eviction;
+pub use crate::kex_stats::KexStats;
 pub use crate::log_event::*;
+pub use crate::metrics::ContextStats;
+pub use crate::reliable::ReliableSendHandle;
 pub use crate::zeta::*;
 pub use crate::zssp::*;
@@ -1,9 +1,13 @@
-use std::sync::Arc;
+use alloc::sync::Arc;
 
 use crate::application::CryptoLayer;
+use crate::challenge::ChallengeFailure;
+use crate::handshake_cache::Eviction;
 use crate::zeta::Session;
 
 /// ZSSP events that might be interesting to log or aggregate into metrics.
+///
+/// Every variant has a code that is stable across releases, see `LogEvent::code`.
 #[allow(missing_docs)]
 pub enum LogEvent<'a, C: CryptoLayer> {
     ResentX1(&'a Arc<Session<C>>),
@@ -18,17 +22,28 @@ pub enum LogEvent<'a, C: CryptoLayer> {
     TimeoutK1(&'a Arc<Session<C>>),
     ResentK2(&'a Arc<Session<C>>),
     TimeoutK2(&'a Arc<Session<C>>),
+    SentKidRotate(&'a Arc<Session<C>>),
+    ResentKidRotate(&'a Arc<Session<C>>),
     /// `(packet_type, packet_counter, fragment_no, fragment_count)`
     ReceivedRawFragment(u8, u64, usize, usize),
     ReceivedRawX1,
-    X1FailedChallengeSentNewChallenge,
-    X1SucceededChallenge,
+    /// `(address_hash, reason, challenge_age)`
+    /// The address hash is salted per context, and the age is only known if the hello responded
+    /// to a valid challenge.
+    X1FailedChallengeSentNewChallenge(u64, ChallengeFailure, Option<i64>),
+    /// `(address_hash, challenge_age)`
+    X1SucceededChallenge(u64, i64),
     X1IsAuthSentX2,
+    /// An unassociated handshake was dropped to make room for a new one, for the given reason.
+    EvictedUnassociatedHandshake(Eviction),
     ReceivedRawChallenge,
     ChallengeIsAuth(&'a Arc<Session<C>>),
     ReceivedRawX2,
     X2IsAuthSentX3(&'a Arc<Session<C>>),
     ReceivedRawX3,
+    /// A part of an identity streamed by Alice after her handshake completion was received, see
+    /// `Settings::max_streamed_identity_size`.
+    ReceivedRawIdentityContinuation,
     X3IsAuthSentKeyConfirm(&'a Arc<Session<C>>),
     ReceivedRawKeyConfirm,
     KeyConfirmIsAuthSentAck(&'a Arc<Session<C>>),
@@ -36,14 +51,183 @@ pub enum LogEvent<'a, C: CryptoLayer> {
     AckIsAuth(&'a Arc<Session<C>>),
     ReceivedRawK1,
     K1IsAuthSentK2(&'a Arc<Session<C>>),
+    K1IsAuthSentDefer(&'a Arc<Session<C>>),
+    K1IsAuthRejected(&'a Arc<Session<C>>),
     ReceivedRawK2,
     K2IsAuthSentKeyConfirm(&'a Arc<Session<C>>),
     ReceivedRawD,
     DIsAuthClosedSession(&'a Arc<Session<C>>),
+    ReceivedRawKidRotate,
+    KidRotateIsAuthSentAck(&'a Arc<Session<C>>),
+    ReceivedRawRekeyDefer,
+    RekeyDeferIsAuth(&'a Arc<Session<C>>),
+    /// The initial handshake of a session completed, see `Session::kex_stats`.
+    /// Together with `started_at_ms` this is enough to record the handshake as a span.
+    HandshakeCompleted {
+        session: &'a Arc<Session<C>>,
+        started_at_ms: i64,
+        duration_ms: u64,
+        resend_count: u32,
+    },
+    /// A rekey initiated by us completed, see `Session::kex_stats`.
+    /// Together with `started_at_ms` this is enough to record the rekey as a span.
+    RekeyCompleted {
+        session: &'a Arc<Session<C>>,
+        started_at_ms: i64,
+        duration_ms: u64,
+        resend_count: u32,
+    },
+    /// A handshake was started at the given time. The session is `None` when we are Bob, since
+    /// Bob does not create a session until the handshake completes.
+    HandshakeStarted {
+        session: Option<&'a Arc<Session<C>>>,
+        timestamp_ms: i64,
+    },
+    /// We started rekeying the session at the given time.
+    RekeyStarted {
+        session: &'a Arc<Session<C>>,
+        timestamp_ms: i64,
+    },
+    /// `oldest_age_ms`
+    /// An unassociated handshake has been pending for longer than `Settings::initial_offer_timeout`,
+    /// which means `Context::service` is not being called often enough to expire them.
+    StaleUnassociatedHandshakes(i64),
+    /// `(address_hash, version)`
+    /// A hello of a protocol version we do not support was received, and unless too many were
+    /// received recently a version unsupported packet was sent back.
+    X1VersionUnsupported(u64, u8),
+    ReceivedRawVersionUnsupported,
+    /// `(session, min_version, max_version)`
+    /// Bob does not support the protocol version of our hello, see `ReceiveOk::VersionUnsupported`.
+    VersionUnsupported(&'a Arc<Session<C>>, u8, u8),
 }
 
-impl<'a, C: CryptoLayer> std::fmt::Debug for LogEvent<'a, C> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+/// The code of every `LogEvent` variant, see `LogEvent::code`.
+const LOG_EVENT_CODES: [(u16, &str); 51] = [
+    (1, "ResentX1"),
+    (2, "TimeoutX1"),
+    (3, "TimeoutX2"),
+    (4, "ResentX3"),
+    (5, "TimeoutX3"),
+    (6, "ResentKeyConfirm"),
+    (7, "TimeoutKeyConfirm"),
+    (8, "StartedRekeyingSentK1"),
+    (9, "ResentK1"),
+    (10, "TimeoutK1"),
+    (11, "ResentK2"),
+    (12, "TimeoutK2"),
+    (13, "SentKidRotate"),
+    (14, "ResentKidRotate"),
+    (15, "ReceivedRawFragment"),
+    (16, "ReceivedRawX1"),
+    (17, "X1FailedChallengeSentNewChallenge"),
+    (18, "X1SucceededChallenge"),
+    (19, "X1IsAuthSentX2"),
+    (20, "EvictedUnassociatedHandshake"),
+    (21, "ReceivedRawChallenge"),
+    (22, "ChallengeIsAuth"),
+    (23, "ReceivedRawX2"),
+    (24, "X2IsAuthSentX3"),
+    (25, "ReceivedRawX3"),
+    (26, "X3IsAuthSentKeyConfirm"),
+    (27, "ReceivedRawKeyConfirm"),
+    (28, "KeyConfirmIsAuthSentAck"),
+    (29, "ReceivedRawAck"),
+    (30, "AckIsAuth"),
+    (31, "ReceivedRawK1"),
+    (32, "K1IsAuthSentK2"),
+    (33, "K1IsAuthSentDefer"),
+    (34, "K1IsAuthRejected"),
+    (35, "ReceivedRawK2"),
+    (36, "K2IsAuthSentKeyConfirm"),
+    (37, "ReceivedRawD"),
+    (38, "DIsAuthClosedSession"),
+    (39, "ReceivedRawKidRotate"),
+    (40, "KidRotateIsAuthSentAck"),
+    (41, "ReceivedRawRekeyDefer"),
+    (42, "RekeyDeferIsAuth"),
+    (43, "HandshakeCompleted"),
+    (44, "RekeyCompleted"),
+    (45, "HandshakeStarted"),
+    (46, "RekeyStarted"),
+    (47, "StaleUnassociatedHandshakes"),
+    (48, "X1VersionUnsupported"),
+    (49, "ReceivedRawVersionUnsupported"),
+    (50, "VersionUnsupported"),
+    (51, "ReceivedRawIdentityContinuation"),
+];
+
+impl<'a, C: CryptoLayer> LogEvent<'a, C> {
+    /// A number that identifies the variant of this event.
+    ///
+    /// Unlike the names of variants, codes are stable across releases. Every variant is explicitly
+    /// assigned a code when it is added, and the code of a variant that is removed is never reused.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::ResentX1(..) => 1,
+            Self::TimeoutX1(..) => 2,
+            Self::TimeoutX2 => 3,
+            Self::ResentX3(..) => 4,
+            Self::TimeoutX3(..) => 5,
+            Self::ResentKeyConfirm(..) => 6,
+            Self::TimeoutKeyConfirm(..) => 7,
+            Self::StartedRekeyingSentK1(..) => 8,
+            Self::ResentK1(..) => 9,
+            Self::TimeoutK1(..) => 10,
+            Self::ResentK2(..) => 11,
+            Self::TimeoutK2(..) => 12,
+            Self::SentKidRotate(..) => 13,
+            Self::ResentKidRotate(..) => 14,
+            Self::ReceivedRawFragment(..) => 15,
+            Self::ReceivedRawX1 => 16,
+            Self::X1FailedChallengeSentNewChallenge(..) => 17,
+            Self::X1SucceededChallenge(..) => 18,
+            Self::X1IsAuthSentX2 => 19,
+            Self::EvictedUnassociatedHandshake(..) => 20,
+            Self::ReceivedRawChallenge => 21,
+            Self::ChallengeIsAuth(..) => 22,
+            Self::ReceivedRawX2 => 23,
+            Self::X2IsAuthSentX3(..) => 24,
+            Self::ReceivedRawX3 => 25,
+            Self::X3IsAuthSentKeyConfirm(..) => 26,
+            Self::ReceivedRawKeyConfirm => 27,
+            Self::KeyConfirmIsAuthSentAck(..) => 28,
+            Self::ReceivedRawAck => 29,
+            Self::AckIsAuth(..) => 30,
+            Self::ReceivedRawK1 => 31,
+            Self::K1IsAuthSentK2(..) => 32,
+            Self::K1IsAuthSentDefer(..) => 33,
+            Self::K1IsAuthRejected(..) => 34,
+            Self::ReceivedRawK2 => 35,
+            Self::K2IsAuthSentKeyConfirm(..) => 36,
+            Self::ReceivedRawD => 37,
+            Self::DIsAuthClosedSession(..) => 38,
+            Self::ReceivedRawKidRotate => 39,
+            Self::KidRotateIsAuthSentAck(..) => 40,
+            Self::ReceivedRawRekeyDefer => 41,
+            Self::RekeyDeferIsAuth(..) => 42,
+            Self::HandshakeCompleted { .. } => 43,
+            Self::RekeyCompleted { .. } => 44,
+            Self::HandshakeStarted { .. } => 45,
+            Self::RekeyStarted { .. } => 46,
+            Self::StaleUnassociatedHandshakes(..) => 47,
+            Self::X1VersionUnsupported(..) => 48,
+            Self::ReceivedRawVersionUnsupported => 49,
+            Self::VersionUnsupported(..) => 50,
+            Self::ReceivedRawIdentityContinuation => 51,
+        }
+    }
+    /// The name of the variant with the given code, or `None` if no variant has this code.
+    ///
+    /// Events borrow the session they refer to, so tooling that only has the code cannot recreate
+    /// the event itself.
+    pub fn name_from_code(code: u16) -> Option<&'static str> {
+        LOG_EVENT_CODES.iter().find(|(c, _)| *c == code).map(|(_, name)| *name)
+    }
+}
+
+impl<'a, C: CryptoLayer> core::fmt::Debug for LogEvent<'a, C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::ResentX1(_) => f.debug_tuple("ResentX1").finish(),
             Self::TimeoutX1(_) => f.debug_tuple("TimeoutX1").finish(),
@@ -57,6 +241,8 @@ impl<'a, C: CryptoLayer> std::fmt::Debug for LogEvent<'a, C> {
             Self::TimeoutK1(_) => f.debug_tuple("TimeoutK1").finish(),
             Self::ResentK2(_) => f.debug_tuple("ResentK2").finish(),
             Self::TimeoutK2(_) => f.debug_tuple("TimeoutK2").finish(),
+            Self::SentKidRotate(_) => f.debug_tuple("SentKidRotate").finish(),
+            Self::ResentKidRotate(_) => f.debug_tuple("ResentKidRotate").finish(),
             Self::ReceivedRawFragment(arg0, arg1, arg2, arg3) => f
                 .debug_tuple("ReceivedRawFragment")
                 .field(arg0)
@@ -65,14 +251,25 @@ impl<'a, C: CryptoLayer> std::fmt::Debug for LogEvent<'a, C> {
                 .field(arg3)
                 .finish(),
             Self::ReceivedRawX1 => write!(f, "ReceivedRawX1"),
-            Self::X1FailedChallengeSentNewChallenge => write!(f, "X1FailedChallengeSentNewChallenge"),
-            Self::X1SucceededChallenge => write!(f, "X1SucceededChallenge"),
+            Self::X1FailedChallengeSentNewChallenge(arg0, arg1, arg2) => f
+                .debug_tuple("X1FailedChallengeSentNewChallenge")
+                .field(arg0)
+                .field(arg1)
+                .field(arg2)
+                .finish(),
+            Self::X1SucceededChallenge(arg0, arg1) => {
+                f.debug_tuple("X1SucceededChallenge").field(arg0).field(arg1).finish()
+            }
             Self::X1IsAuthSentX2 => write!(f, "X1IsAuthSentX2"),
+            Self::EvictedUnassociatedHandshake(arg0) => {
+                f.debug_tuple("EvictedUnassociatedHandshake").field(arg0).finish()
+            }
             Self::ReceivedRawChallenge => write!(f, "ReceivedRawChallenge"),
             Self::ChallengeIsAuth(_) => f.debug_tuple("ChallengeIsAuth").finish(),
             Self::ReceivedRawX2 => write!(f, "ReceivedRawX2"),
             Self::X2IsAuthSentX3(_) => f.debug_tuple("X2IsAuthSentX3").finish(),
             Self::ReceivedRawX3 => write!(f, "ReceivedRawX3"),
+            Self::ReceivedRawIdentityContinuation => write!(f, "ReceivedRawIdentityContinuation"),
             Self::X3IsAuthSentKeyConfirm(_) => f.debug_tuple("X3IsAuthSentKeyConfirm").finish(),
             Self::ReceivedRawKeyConfirm => write!(f, "ReceivedRawKeyConfirm"),
             Self::KeyConfirmIsAuthSentAck(_) => f.debug_tuple("KeyConfirmIsAuthSentAck").finish(),
@@ -80,10 +277,162 @@ impl<'a, C: CryptoLayer> std::fmt::Debug for LogEvent<'a, C> {
             Self::AckIsAuth(_) => f.debug_tuple("AckIsAuth").finish(),
             Self::ReceivedRawK1 => write!(f, "ReceivedRawK1"),
             Self::K1IsAuthSentK2(_) => f.debug_tuple("K1IsAuthSentK2").finish(),
+            Self::K1IsAuthSentDefer(_) => f.debug_tuple("K1IsAuthSentDefer").finish(),
+            Self::K1IsAuthRejected(_) => f.debug_tuple("K1IsAuthRejected").finish(),
             Self::ReceivedRawK2 => write!(f, "ReceivedRawK2"),
             Self::K2IsAuthSentKeyConfirm(_) => f.debug_tuple("K2IsAuthSentKeyConfirm").finish(),
             Self::ReceivedRawD => write!(f, "ReceivedRawD"),
             Self::DIsAuthClosedSession(_) => f.debug_tuple("DIsAuthClosedSession").finish(),
+            Self::ReceivedRawKidRotate => write!(f, "ReceivedRawKidRotate"),
+            Self::KidRotateIsAuthSentAck(_) => f.debug_tuple("KidRotateIsAuthSentAck").finish(),
+            Self::ReceivedRawRekeyDefer => write!(f, "ReceivedRawRekeyDefer"),
+            Self::RekeyDeferIsAuth(_) => f.debug_tuple("RekeyDeferIsAuth").finish(),
+            Self::HandshakeCompleted { started_at_ms, duration_ms, resend_count, .. } => f
+                .debug_struct("HandshakeCompleted")
+                .field("started_at_ms", started_at_ms)
+                .field("duration_ms", duration_ms)
+                .field("resend_count", resend_count)
+                .finish_non_exhaustive(),
+            Self::RekeyCompleted { started_at_ms, duration_ms, resend_count, .. } => f
+                .debug_struct("RekeyCompleted")
+                .field("started_at_ms", started_at_ms)
+                .field("duration_ms", duration_ms)
+                .field("resend_count", resend_count)
+                .finish_non_exhaustive(),
+            Self::HandshakeStarted { timestamp_ms, .. } => f
+                .debug_struct("HandshakeStarted")
+                .field("timestamp_ms", timestamp_ms)
+                .finish_non_exhaustive(),
+            Self::RekeyStarted { timestamp_ms, .. } => f
+                .debug_struct("RekeyStarted")
+                .field("timestamp_ms", timestamp_ms)
+                .finish_non_exhaustive(),
+            Self::StaleUnassociatedHandshakes(arg0) => {
+                f.debug_tuple("StaleUnassociatedHandshakes").field(arg0).finish()
+            }
+            Self::X1VersionUnsupported(arg0, arg1) => {
+                f.debug_tuple("X1VersionUnsupported").field(arg0).field(arg1).finish()
+            }
+            Self::ReceivedRawVersionUnsupported => write!(f, "ReceivedRawVersionUnsupported"),
+            Self::VersionUnsupported(_, arg1, arg2) => {
+                f.debug_tuple("VersionUnsupported").field(arg1).field(arg2).finish()
+            }
         }
     }
 }
+
+#[cfg(feature = "tracing")]
+impl<'a, C: CryptoLayer> LogEvent<'a, C> {
+    /// Emits this event to the current `tracing` subscriber. Raw packets are emitted at the trace
+    /// level, stale handshakes at the warn level and everything else at the debug level.
+    pub(crate) fn trace(&self) {
+        use tracing::{debug, trace, warn};
+        match self {
+            Self::ReceivedRawFragment(packet_type, counter, fragment_no, fragment_count) => trace!(
+                packet_type,
+                counter,
+                fragment_no,
+                fragment_count,
+                "ReceivedRawFragment"
+            ),
+            Self::ReceivedRawX1
+            | Self::ReceivedRawChallenge
+            | Self::ReceivedRawX2
+            | Self::ReceivedRawX3
+            | Self::ReceivedRawIdentityContinuation
+            | Self::ReceivedRawKeyConfirm
+            | Self::ReceivedRawAck
+            | Self::ReceivedRawK1
+            | Self::ReceivedRawK2
+            | Self::ReceivedRawD
+            | Self::ReceivedRawKidRotate
+            | Self::ReceivedRawRekeyDefer
+            | Self::ReceivedRawVersionUnsupported => trace!("{:?}", self),
+            Self::X1FailedChallengeSentNewChallenge(address_hash, reason, age) => debug!(
+                address_hash,
+                ?reason,
+                ?age,
+                "X1FailedChallengeSentNewChallenge"
+            ),
+            Self::X1SucceededChallenge(address_hash, age) => debug!(address_hash, age, "X1SucceededChallenge"),
+            Self::EvictedUnassociatedHandshake(reason) => debug!(?reason, "EvictedUnassociatedHandshake"),
+            Self::X1VersionUnsupported(address_hash, version) => {
+                debug!(address_hash, version, "X1VersionUnsupported")
+            }
+            Self::VersionUnsupported(s, min_version, max_version) => debug!(
+                session = ?Arc::as_ptr(s),
+                min_version,
+                max_version,
+                "VersionUnsupported"
+            ),
+            Self::HandshakeCompleted { session, started_at_ms, duration_ms, resend_count } => debug!(
+                session = ?Arc::as_ptr(session),
+                started_at_ms,
+                duration_ms,
+                resend_count,
+                "HandshakeCompleted"
+            ),
+            Self::RekeyCompleted { session, started_at_ms, duration_ms, resend_count } => debug!(
+                session = ?Arc::as_ptr(session),
+                started_at_ms,
+                duration_ms,
+                resend_count,
+                "RekeyCompleted"
+            ),
+            Self::HandshakeStarted { session, timestamp_ms } => debug!(
+                session = ?session.map(Arc::as_ptr),
+                timestamp_ms,
+                "HandshakeStarted"
+            ),
+            Self::RekeyStarted { session, timestamp_ms } => {
+                debug!(session = ?Arc::as_ptr(session), timestamp_ms, "RekeyStarted")
+            }
+            Self::StaleUnassociatedHandshakes(oldest_age_ms) => warn!(oldest_age_ms, "StaleUnassociatedHandshakes"),
+            _ => match self.session() {
+                Some(session) => debug!(session = ?Arc::as_ptr(session), "{:?}", self),
+                None => debug!("{:?}", self),
+            },
+        }
+    }
+    fn session(&self) -> Option<&'a Arc<Session<C>>> {
+        match *self {
+            Self::ResentX1(s)
+            | Self::TimeoutX1(s)
+            | Self::ResentX3(s)
+            | Self::TimeoutX3(s)
+            | Self::ResentKeyConfirm(s)
+            | Self::TimeoutKeyConfirm(s)
+            | Self::StartedRekeyingSentK1(s)
+            | Self::ResentK1(s)
+            | Self::TimeoutK1(s)
+            | Self::ResentK2(s)
+            | Self::TimeoutK2(s)
+            | Self::SentKidRotate(s)
+            | Self::ResentKidRotate(s)
+            | Self::ChallengeIsAuth(s)
+            | Self::X2IsAuthSentX3(s)
+            | Self::X3IsAuthSentKeyConfirm(s)
+            | Self::KeyConfirmIsAuthSentAck(s)
+            | Self::AckIsAuth(s)
+            | Self::K1IsAuthSentK2(s)
+            | Self::K1IsAuthSentDefer(s)
+            | Self::K1IsAuthRejected(s)
+            | Self::K2IsAuthSentKeyConfirm(s)
+            | Self::DIsAuthClosedSession(s)
+            | Self::KidRotateIsAuthSentAck(s)
+            | Self::RekeyDeferIsAuth(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_log_event_codes() {
+    for (i, (code, name)) in LOG_EVENT_CODES.iter().enumerate() {
+        assert!(*code != 0);
+        assert!(LOG_EVENT_CODES[i + 1..].iter().all(|(c, n)| c != code && n != name));
+    }
+    // Codes must never change once assigned.
+    assert_eq!(LOG_EVENT_CODES[2], (3, "TimeoutX2"));
+    assert_eq!(LOG_EVENT_CODES[41], (42, "RekeyDeferIsAuth"));
+}
@@ -0,0 +1,190 @@
+use crate::io::Write;
+use crate::result::FaultType;
+use crate::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+const FAULT_TYPES: [(FaultType, &str); 5] = [
+    (FaultType::UnknownLocalKeyId, "unknown_local_key_id"),
+    (FaultType::InvalidPacket, "invalid_packet"),
+    (FaultType::FailedAuth, "failed_auth"),
+    (FaultType::ExpiredCounter, "expired_counter"),
+    (FaultType::OutOfSequence, "out_of_sequence"),
+];
+
+/// Counters of the traffic processed by a context, see `Context::render_metrics`.
+///
+/// Every counter is only updated with relaxed atomics, so they are cheap enough to update on the
+/// hot path but can be very slightly out of date when rendered while packets are in flight.
+pub(crate) struct Metrics {
+    sessions_opened: AtomicU64,
+    sessions_alive: AtomicUsize,
+    pub handshakes_completed: AtomicU64,
+    pub rekeys_completed: AtomicU64,
+    pub data_packets_rx: AtomicU64,
+    pub data_packets_tx: AtomicU64,
+    pub bytes_rx: AtomicU64,
+    pub bytes_tx: AtomicU64,
+    faults: [AtomicU64; FAULT_TYPES.len()],
+}
+
+/// Totals of the traffic processed by a context, see `Context::statistics` for how current they
+/// are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContextStats {
+    /// The number of sessions that were opened with `Context::open` or accepted from a remote
+    /// peer.
+    pub sessions_opened: u64,
+    /// The number of sessions of this context that have not been dropped yet, including sessions
+    /// that are still establishing, and sessions that are expired but still referenced.
+    pub sessions_alive: usize,
+    /// Payload bytes of data packets sent.
+    pub bytes_sent: u64,
+    /// Payload bytes of authenticated data packets received.
+    pub bytes_received: u64,
+    /// Byzantine faults caused by received packets, of every `FaultType`.
+    pub byzantine_faults: u64,
+    /// Handshakes that established a new session.
+    pub handshakes_completed: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            sessions_opened: AtomicU64::new(0),
+            sessions_alive: AtomicUsize::new(0),
+            handshakes_completed: AtomicU64::new(0),
+            rekeys_completed: AtomicU64::new(0),
+            data_packets_rx: AtomicU64::new(0),
+            data_packets_tx: AtomicU64::new(0),
+            bytes_rx: AtomicU64::new(0),
+            bytes_tx: AtomicU64::new(0),
+            faults: Default::default(),
+        }
+    }
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn record_data(packets: &AtomicU64, bytes: &AtomicU64, len: usize) {
+        packets.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+    pub fn record_fault(&self, error: FaultType) {
+        let i = FAULT_TYPES.iter().position(|(t, _)| *t == error).unwrap();
+        self.faults[i].fetch_add(1, Ordering::Relaxed);
+    }
+    /// Count a new session. `opened` is false for sessions recreated from an export or a
+    /// checkpoint, which were already counted by the context that opened them.
+    pub fn record_session_created(&self, opened: bool) {
+        if opened {
+            self.sessions_opened.fetch_add(1, Ordering::Relaxed);
+        }
+        self.sessions_alive.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn record_session_dropped(&self) {
+        self.sessions_alive.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn stats(&self) -> ContextStats {
+        ContextStats {
+            sessions_opened: self.sessions_opened.load(Ordering::Relaxed),
+            sessions_alive: self.sessions_alive.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_tx.load(Ordering::Relaxed),
+            bytes_received: self.bytes_rx.load(Ordering::Relaxed),
+            byzantine_faults: self.faults.iter().map(|c| c.load(Ordering::Relaxed)).sum(),
+            handshakes_completed: self.handshakes_completed.load(Ordering::Relaxed),
+        }
+    }
+    /// Write every metric to `out` in the Prometheus text exposition format.
+    pub fn render(&self, out: &mut impl Write, unassociated_handshakes: usize) -> crate::io::Result<()> {
+        let counters = [
+            (
+                "sessions_opened",
+                "Sessions opened locally or accepted from a remote peer.",
+                &self.sessions_opened,
+            ),
+            (
+                "handshakes_completed",
+                "Handshakes that established a new session.",
+                &self.handshakes_completed,
+            ),
+            (
+                "rekeys_completed",
+                "Rekeys in which this context derived a new session key.",
+                &self.rekeys_completed,
+            ),
+            (
+                "data_packets_received",
+                "Authenticated data packets received.",
+                &self.data_packets_rx,
+            ),
+            ("data_packets_sent", "Data packets sent.", &self.data_packets_tx),
+            (
+                "data_bytes_received",
+                "Payload bytes of authenticated data packets received.",
+                &self.bytes_rx,
+            ),
+            ("data_bytes_sent", "Payload bytes of data packets sent.", &self.bytes_tx),
+        ];
+        for (name, help, counter) in counters {
+            writeln!(out, "# HELP zssp_{name}_total {help}")?;
+            writeln!(out, "# TYPE zssp_{name}_total counter")?;
+            writeln!(out, "zssp_{name}_total {}", counter.load(Ordering::Relaxed))?;
+        }
+        writeln!(
+            out,
+            "# HELP zssp_faults_total Byzantine faults caused by received packets."
+        )?;
+        writeln!(out, "# TYPE zssp_faults_total counter")?;
+        for ((_, label), counter) in FAULT_TYPES.iter().zip(&self.faults) {
+            writeln!(
+                out,
+                "zssp_faults_total{{type=\"{label}\"}} {}",
+                counter.load(Ordering::Relaxed)
+            )?;
+        }
+        writeln!(out, "# HELP zssp_sessions Sessions that have not been dropped.")?;
+        writeln!(out, "# TYPE zssp_sessions gauge")?;
+        writeln!(out, "zssp_sessions {}", self.sessions_alive.load(Ordering::Relaxed))?;
+        writeln!(
+            out,
+            "# HELP zssp_unassociated_handshakes Handshakes waiting to be completed by Alice."
+        )?;
+        writeln!(out, "# TYPE zssp_unassociated_handshakes gauge")?;
+        writeln!(out, "zssp_unassociated_handshakes {unassociated_handshakes}")
+    }
+}
+
+#[test]
+fn test_render_metrics() {
+    let metrics = Metrics::new();
+    Metrics::increment(&metrics.handshakes_completed);
+    Metrics::record_data(&metrics.data_packets_tx, &metrics.bytes_tx, 100);
+    Metrics::record_data(&metrics.data_packets_tx, &metrics.bytes_tx, 50);
+    metrics.record_fault(FaultType::FailedAuth);
+    metrics.record_fault(FaultType::FailedAuth);
+    metrics.record_session_created(true);
+    metrics.record_session_created(false);
+    metrics.record_session_dropped();
+
+    let mut out = Vec::new();
+    metrics.render(&mut out, 3).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let get = |name: &str| {
+        let line = out
+            .lines()
+            .find(|line| line.starts_with(name) && line[name.len()..].starts_with(' '));
+        line.unwrap()[name.len() + 1..].parse::<u64>().unwrap()
+    };
+    assert_eq!(get("zssp_sessions_opened_total"), 1);
+    assert_eq!(get("zssp_sessions"), 1);
+    assert_eq!(get("zssp_handshakes_completed_total"), 1);
+    assert_eq!(get("zssp_rekeys_completed_total"), 0);
+    assert_eq!(get("zssp_data_packets_sent_total"), 2);
+    assert_eq!(get("zssp_data_bytes_sent_total"), 150);
+    assert_eq!(get("zssp_faults_total{type=\"failed_auth\"}"), 2);
+    assert_eq!(get("zssp_faults_total{type=\"invalid_packet\"}"), 0);
+    assert_eq!(get("zssp_unassociated_handshakes"), 3);
+    // Every sample is preceded by its metadata.
+    for line in out.lines().filter(|line| !line.starts_with('#')) {
+        let name = line.split(['{', ' ']).next().unwrap();
+        assert!(out.contains(&format!("# TYPE {name} ")));
+    }
+}
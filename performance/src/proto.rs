@@ -1,3 +1,33 @@
+//! Sizes, limits and wire format constants of ZSSP.
+//!
+//! ## Packet header
+//!
+//! Every fragment of every packet starts with a header of `HEADER_SIZE` bytes, written by
+//! `set_header` and the fragmentation code, and read back by `parse_fragment_header`:
+//!
+//! ```text
+//!  0               4       5       6       7       8                              16
+//! +---------------+-------+-------+-------+-------+-------------------------------+
+//! |      kid      | frag  | frag  |   0   | type  |            counter            |
+//! |               |  no   | count |       |       |                               |
+//! +---------------+-------+-------+-------+-------+-------------------------------+
+//!                                 |<--------------- packet nonce ---------------->|
+//! ```
+//!
+//! * `[0..4]` kid: the key id the recipient chose for the session, in the recipient's byte order.
+//!   Zero for packets not associated with a session, such as hellos and challenges.
+//! * `[4]` fragment number: the index of this fragment, always less than the fragment count.
+//! * `[5]` fragment count: the number of fragments of the packet, from 1 to `MAX_FRAGMENTS`.
+//! * `[6..16]` packet nonce: the last `PACKET_NONCE_SIZE` bytes of the AES-GCM nonce of the
+//!   packet. The two leading bytes of the full nonce are always zero.
+//!   * `[6]` reserved, always zero.
+//!   * `[7]` packet type, one of the `PACKET_TYPE_*` constants.
+//!   * `[8..16]` the 64-bit packet counter, big endian as required by Noise.
+//!
+//! Once a session has a header key, bytes `[4..20]` of every fragment, that is everything after
+//! the kid plus the first four bytes of the payload, are encrypted as a single AES block.
+//! Fragments shorter than `MIN_PACKET_SIZE` are dropped on receipt, so this block never runs past
+//! the end of a fragment.
 use crate::crypto::*;
 
 /* Common constants */
@@ -15,8 +45,41 @@ pub const MIN_TRANSPORT_MTU: usize = 128;
 pub const fn max_sendable_len(mtu: usize) -> usize {
     (mtu - HEADER_SIZE) * MAX_FRAGMENTS - AES_GCM_TAG_SIZE
 }
+/// The size of the sequence number `Session::send_ordered` prepends to the data it sends.
+/// The data passed to `Session::send_ordered` must be this much shorter than `max_sendable_len`.
+pub const SEQUENCE_NUMBER_SIZE: usize = 8;
 
-pub(crate) const KID_SIZE: usize = 4;
+/// The size of a key id. Key ids select the session a packet belongs to.
+pub const KID_SIZE: usize = 4;
+/// The size in bytes of both a ratchet key and a ratchet fingerprint.
+pub const RATCHET_SIZE: usize = 32;
+
+/* Versioning constants */
+
+/// The latest version of the protocol implemented by this crate.
+/// Alice sends her version in the clear right after her key id at the start of every handshake
+/// hello, where it is also mixed into the handshake hash. Bob answers in the version Alice used if
+/// he supports it, see `Session::agreed_protocol_version`.
+///
+/// Everything that may differ between versions lives in a module named after the version, such
+/// as `v1`. Everything at the top level of this module is shared by all versions.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// The size of the protocol version field of the handshake hello and response.
+pub const PROTOCOL_VERSION_SIZE: usize = 1;
+/// Set by Bob in the protocol version of his handshake response if he can encrypt data with a
+/// nonce misuse resistant AEAD, see `ApplicationLayer::misuse_resistance`. Protocol versions stay
+/// below this flag, so Alices that do not know it negotiate it away as a later version.
+pub(crate) const VERSION_FLAG_MISUSE_RESISTANT: u8 = 0x80;
+/// Set by Bob along with `VERSION_FLAG_MISUSE_RESISTANT` if he will reject a handshake that does
+/// not select it.
+pub(crate) const VERSION_FLAG_MISUSE_RESISTANCE_REQUIRED: u8 = 0x40;
+/// The size of a version unsupported packet without its header: the key id Alice sent in her
+/// hello, followed by the lowest and highest protocol version Bob accepts, zero padded up to
+/// `MIN_PACKET_SIZE`. The counter of its header repeats the counter of Alice's hello.
+/// Version unsupported packets are never fragmented.
+pub const VERSION_UNSUPPORTED_SIZE: usize = MIN_PACKET_SIZE - HEADER_SIZE;
+/// The size of a version unsupported packet with its header.
+pub const HEADERED_VERSION_UNSUPPORTED_SIZE: usize = VERSION_UNSUPPORTED_SIZE + HEADER_SIZE;
 
 /* Challenge protocol constants */
 
@@ -27,24 +90,32 @@ pub(crate) const MAC_SIZE: usize = 16;
 pub(crate) const POW_SIZE: usize = 8;
 pub(crate) const POW_START: usize = COUNTER_SIZE + MAC_SIZE;
 
-pub(crate) const CHALLENGE_SIZE: usize = COUNTER_SIZE + MAC_SIZE + POW_SIZE;
+/// The size of a challenge: a counter, the MAC Bob computes over it and a proof of work
+/// solution.
+pub const CHALLENGE_SIZE: usize = COUNTER_SIZE + MAC_SIZE + POW_SIZE;
+/// The difficulty of a challenge that does not specify one.
 pub(crate) const DIFFICULTY: u32 = 13;
-
-pub(crate) const HEADERED_CHALLENGE_SIZE: usize = CHALLENGE_SIZE + HEADER_SIZE + KID_SIZE;
+/// The greatest challenge difficulty Alice will attempt to solve.
+/// Each step of difficulty doubles the expected number of hashes needed to solve the challenge,
+/// so at this limit Alice must compute on the order of 2^24 hashes.
+pub(crate) const CHALLENGE_DIFFICULTY_LIMIT: u32 = 24;
+/// The difficulty of a challenge is encoded in the most significant byte of its counter.
+pub(crate) const CHALLENGE_DIFFICULTY_SHIFT: u32 = 56;
+pub(crate) const CHALLENGE_COUNTER_MASK: u64 = (1 << CHALLENGE_DIFFICULTY_SHIFT) - 1;
+/// Below the difficulty byte, the counter of a challenge holds the lower 32 bits of the time it
+/// was issued at, followed by a 24 bit sequence number.
+pub(crate) const CHALLENGE_TIME_SHIFT: u32 = 24;
+pub(crate) const CHALLENGE_TIME_MASK: u64 = (1 << 32) - 1;
+pub(crate) const CHALLENGE_SEQUENCE_MASK: u64 = (1 << CHALLENGE_TIME_SHIFT) - 1;
+
+/// The size of a challenge packet: a header, the key id Alice sent in her hello and a
+/// challenge. Challenge packets are never fragmented.
+pub const HEADERED_CHALLENGE_SIZE: usize = CHALLENGE_SIZE + HEADER_SIZE + KID_SIZE;
 
 /* Fragmentation constants */
-/*
-Header:
-    [0..4]   recipient key id
--- start AES(ck_es * h_e_e1_p) encrypted block --
-    [5]      fragment number (0..254)
-    [4]      fragment count (1..255)
--- start packet nonce --
-    [6]      reserved zero
-    [7]      packet type
-    [8..16]  64-bit counter
-*/
-pub(crate) const HEADER_SIZE: usize = 16;
+// See the module documentation for the layout of the header.
+/// The size of the header at the start of every fragment of every packet.
+pub const HEADER_SIZE: usize = 16;
 pub(crate) const PACKET_NONCE_SIZE: usize = 10;
 
 pub(crate) const HEADER_AUTH_START: usize = 4;
@@ -56,55 +127,24 @@ pub(crate) const FRAGMENT_COUNT_IDX: usize = 5;
 
 /// Maximum number of fragments a single packet may be split into. If a packet cannot fit
 /// into this number of fragments it will be dropped.
-pub(crate) const MAX_FRAGMENTS: usize = 48;
+pub const MAX_FRAGMENTS: usize = 48;
 
 pub(crate) const NONCE_SIZE_DIFF: usize = AES_GCM_NONCE_SIZE - PACKET_NONCE_SIZE;
 
-/* Key exchange constants */
-/*
-XKhfs+psk2:
-    <- s
-    ...
-    -> e, es, e1
-    <- e, ee, ekem1, psk
-    -> s, se
-*/
-/*
-KKpsk0:
-    -> s
-    <- s
-    ...
-    -> psk, e, es, ss
-    <- e, ee, se
-*/
-pub(crate) const HASHLEN: usize = SHA512_HASH_SIZE;
-/// The size in bytes of both a ratchet key and a ratchet fingerprint.
-pub const RATCHET_SIZE: usize = 32;
-
-/// Initial value of 'h'.
-pub(crate) const PROTOCOL_NAME_NOISE_XK: &[u8; HASHLEN] =
-    b"Noise_XKhfs+psk2_P384+Kyber1024_AESGCM_SHA512\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
-/// Initial value of 'ck' for rekeying.
-pub(crate) const PROTOCOL_NAME_NOISE_KK: &[u8; HASHLEN] =
-    b"Noise_KKpsk0_P384_AESGCM_SHA512\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
-
-pub(crate) const LABEL_OTP_TO_RATCHET: &[u8; 19] = b"ZSSP_OTP_TO_RATCHET";
-pub(crate) const LABEL_KBKDF_CHAIN: &[u8; 4] = b"ZSSP";
-pub(crate) const LABEL_RATCHET_STATE: &[u8; 4] = b"ASKR";
-pub(crate) const LABEL_HEADER_KEY: &[u8; 4] = b"ASKH";
-pub(crate) const LABEL_KEX_KEY: &[u8; 4] = b"ASKK";
+/* Key usage constants */
 
 pub(crate) const EXPIRE_AFTER_USES: u64 = (1 << 32) - 1;
+/// Data packets stop being sent this many uses into a key, leaving the rest of the key's uses to
+/// the control packets of the rekey that has to replace it.
+pub(crate) const REKEY_URGENTLY_AFTER_USES: u64 = EXPIRE_AFTER_USES - (1 << 20);
 pub(crate) const THREAD_SAFE_COUNTER_HARD_EXPIRE: u64 = u64::MAX - (1 << 16);
-/// Determines the number of counters a session will remember. If a counter arrives over
-/// this amount out of order relative to other received counters, it is likely to be
-/// rejected on the basis that the session can't remember if this counter was replayed.
-/// Increasing this value makes a session consume more memory.
-pub(crate) const COUNTER_WINDOW_MAX_OOO: usize = 128;
-/// Maximum number of counter steps that the counter is allowed to skip ahead.
+/// The smallest value `Settings::counter_window_max_skip_ahead` may be set to. Every peer accepts
+/// counters at least this far ahead, which a session restored from a checkpoint relies on.
+pub(crate) const COUNTER_WINDOW_MIN_SKIP_AHEAD_LIMIT: u64 = 1 << 20;
+/// The largest value `Settings::counter_window_max_skip_ahead` may be set to.
 /// This cannot be changed away from 2^24 without changing the header nonce handling code.
-pub(crate) const COUNTER_WINDOW_MAX_SKIP_AHEAD: u64 = 1 << 24;
-/// Similar to `COUNTER_WINDOW_MAX_OOO`, except this governs the receive context challenge
+pub(crate) const COUNTER_WINDOW_MAX_SKIP_AHEAD_LIMIT: u64 = 1 << 24;
+/// Similar to `Settings::counter_window_max_out_of_order`, except this governs the receive context challenge
 /// counter rather than the session counter.
 /// When Bob issues a challenge to Alice to mitigate DDOS, Bob will only accept Alice's
 /// response once, and then its attached counter is added to the window.
@@ -122,52 +162,163 @@ pub(crate) const PACKET_TYPE_REKEY_COMPLETE: u8 = 6;
 pub(crate) const PACKET_TYPE_SESSION_REJECTED: u8 = 7;
 pub(crate) const PACKET_TYPE_DATA: u8 = 8;
 pub(crate) const PACKET_TYPE_CHALLENGE: u8 = 9;
-pub(crate) const PACKET_TYPE_USES_COUNTER_RANGE: std::ops::Range<u8> = 3..9;
-
-pub(crate) const HANDSHAKE_HELLO_SIZE: usize =
-    KID_SIZE + P384_PUBLIC_KEY_SIZE + KYBER_PUBLIC_KEY_SIZE + AES_GCM_TAG_SIZE + 2 * RATCHET_SIZE + AES_GCM_TAG_SIZE;
-
-pub(crate) const HANDSHAKE_HELLO_CHALLENGE_SIZE: usize = HANDSHAKE_HELLO_SIZE + CHALLENGE_SIZE;
-
-pub(crate) const HEADERED_HANDSHAKE_HELLO_CHALLENGE_SIZE: usize = HANDSHAKE_HELLO_CHALLENGE_SIZE + HEADER_SIZE;
-
-pub(crate) const HANDSHAKE_RESPONSE_SIZE: usize =
-    P384_PUBLIC_KEY_SIZE + KYBER_CIPHERTEXT_SIZE + AES_GCM_TAG_SIZE + KID_SIZE + AES_GCM_TAG_SIZE;
-pub(crate) const HEADERED_HANDSHAKE_RESPONSE_SIZE: usize = HANDSHAKE_RESPONSE_SIZE + HEADER_SIZE;
-
-pub(crate) const HANDSHAKE_COMPLETION_MIN_SIZE: usize = P384_PUBLIC_KEY_SIZE + AES_GCM_TAG_SIZE + AES_GCM_TAG_SIZE;
-pub(crate) const HANDSHAKE_COMPLETION_MAX_SIZE: usize = HANDSHAKE_COMPLETION_MIN_SIZE + IDENTITY_MAX_SIZE;
-
-pub(crate) const HEADERED_HANDSHAKE_COMPLETION_MAX_SIZE: usize = HANDSHAKE_COMPLETION_MAX_SIZE + HEADER_SIZE;
-
-pub(crate) const KEY_CONFIRMATION_SIZE: usize = AES_GCM_TAG_SIZE;
-pub(crate) const HEADERED_KEY_CONFIRMATION_SIZE: usize = KEY_CONFIRMATION_SIZE + HEADER_SIZE;
-
-pub(crate) const ACKNOWLEDGEMENT_SIZE: usize = AES_GCM_TAG_SIZE;
-pub(crate) const HEADERED_ACKNOWLEDGEMENT_SIZE: usize = ACKNOWLEDGEMENT_SIZE + HEADER_SIZE;
-
-pub(crate) const SESSION_REJECTED_SIZE: usize = AES_GCM_TAG_SIZE;
-pub(crate) const HEADERED_SESSION_REJECTED_SIZE: usize = SESSION_REJECTED_SIZE + HEADER_SIZE;
-
-pub(crate) const REKEY_SIZE: usize = P384_PUBLIC_KEY_SIZE + KID_SIZE + AES_GCM_TAG_SIZE + AES_GCM_TAG_SIZE;
-pub(crate) const HEADERED_REKEY_SIZE: usize = REKEY_SIZE + HEADER_SIZE;
+pub(crate) const PACKET_TYPE_KID_ROTATE: u8 = 10;
+pub(crate) const PACKET_TYPE_REKEY_DEFER: u8 = 11;
+pub(crate) const PACKET_TYPE_VERSION_UNSUPPORTED: u8 = 12;
+pub(crate) const PACKET_TYPE_IDENTITY_CONTINUATION: u8 = 13;
+/// Never sent on the wire, only used for the nonces of `Session::encrypt_standalone`.
+pub(crate) const PACKET_TYPE_STANDALONE: u8 = 0xff;
+pub(crate) const PACKET_TYPE_USES_COUNTER_RANGE: core::ops::Range<u8> = 3..9;
+/// Set in the counter of a handshake completion that only carries the start of Alice's identity.
+/// The rest of it follows in identity continuations, whose counters count up from 1.
+pub(crate) const STREAMED_COMPLETION_COUNTER: u64 = 2;
+/// Set in the counter of a handshake completion whose identity starts with one of the
+/// `IDENTITY_ENCODING_*` bytes. It can be combined with `STREAMED_COMPLETION_COUNTER`.
+pub(crate) const ENCODED_COMPLETION_COUNTER: u64 = 4;
+/// Set in the counter of a handshake completion if Alice selected the nonce misuse resistant
+/// AEAD Bob offered, for the data packets of the session. It can be combined with the others.
+pub(crate) const MISUSE_RESISTANT_COMPLETION_COUNTER: u64 = 8;
+/// The encoded identity is the identity itself.
+pub(crate) const IDENTITY_ENCODING_RAW: u8 = 0;
+/// The encoded identity is compressed with raw deflate, see `ApplicationLayer::compress_identity`.
+#[cfg_attr(not(feature = "deflate"), allow(dead_code))]
+pub(crate) const IDENTITY_ENCODING_DEFLATE: u8 = 1;
+
+/// Constants of version 1 of the protocol: the Noise handshake, the key derivation labels and the
+/// sizes of the packets that depend on them.
+pub mod v1 {
+    use super::*;
+
+    /* Key exchange constants */
+    /*
+    XKhfs+psk2:
+        <- s
+        ...
+        -> e, es, e1
+        <- e, ee, ekem1, psk
+        -> s, se
+    */
+    /*
+    KKpsk0:
+        -> s
+        <- s
+        ...
+        -> psk, e, es, ss
+        <- e, ee, se
+    */
+    pub(crate) const HASHLEN: usize = SHA512_HASH_SIZE;
+    /// Initial value of 'h'.
+    pub(crate) const PROTOCOL_NAME_NOISE_XK: &[u8; HASHLEN] =
+        b"Noise_XKhfs+psk2_P384+Kyber1024_AESGCM_SHA512\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+    /// Initial value of 'ck' for rekeying.
+    pub(crate) const PROTOCOL_NAME_NOISE_KK: &[u8; HASHLEN] =
+        b"Noise_KKpsk0_P384_AESGCM_SHA512\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+    pub(crate) const LABEL_OTP_TO_RATCHET: &[u8; 19] = b"ZSSP_OTP_TO_RATCHET";
+    pub(crate) const LABEL_KBKDF_CHAIN: &[u8; 4] = b"ZSSP";
+    pub(crate) const LABEL_RATCHET_STATE: &[u8; 4] = b"ASKR";
+    pub(crate) const LABEL_HEADER_KEY: &[u8; 4] = b"ASKH";
+    pub(crate) const LABEL_KEX_KEY: &[u8; 4] = b"ASKK";
+    pub(crate) const LABEL_STANDALONE_KEY: &[u8; 4] = b"ASKS";
+
+    /* Packet size constants */
+
+    /// The size of a handshake hello without its challenge or header: Alice's key id and protocol
+    /// version, her ephemeral P-384 public key, her encrypted Kyber public key and its tag, and an
+    /// encrypted payload holding two ratchet fingerprints, followed by its tag.
+    pub const HANDSHAKE_HELLO_SIZE: usize = KID_SIZE
+        + PROTOCOL_VERSION_SIZE
+        + P384_PUBLIC_KEY_SIZE
+        + KYBER_PUBLIC_KEY_SIZE
+        + AES_GCM_TAG_SIZE
+        + 2 * RATCHET_SIZE
+        + AES_GCM_TAG_SIZE;
+
+    /// The size of a handshake hello with the challenge response Alice appends to it.
+    pub const HANDSHAKE_HELLO_CHALLENGE_SIZE: usize = HANDSHAKE_HELLO_SIZE + CHALLENGE_SIZE;
+
+    /// The size of a handshake hello with its challenge response and header, before fragmentation.
+    pub const HEADERED_HANDSHAKE_HELLO_CHALLENGE_SIZE: usize = HANDSHAKE_HELLO_CHALLENGE_SIZE + HEADER_SIZE;
+
+    /// The size of a handshake response without its header: Bob's ephemeral P-384 public key, his
+    /// encrypted Kyber ciphertext and its tag, and an encrypted payload holding his key id and
+    /// protocol version, followed by its tag.
+    pub const HANDSHAKE_RESPONSE_SIZE: usize = P384_PUBLIC_KEY_SIZE
+        + KYBER_CIPHERTEXT_SIZE
+        + AES_GCM_TAG_SIZE
+        + KID_SIZE
+        + PROTOCOL_VERSION_SIZE
+        + AES_GCM_TAG_SIZE;
+    /// The size of a handshake response with its header, before fragmentation.
+    pub const HEADERED_HANDSHAKE_RESPONSE_SIZE: usize = HANDSHAKE_RESPONSE_SIZE + HEADER_SIZE;
+
+    /// The size of a handshake completion carrying an empty identity, without its header: Alice's
+    /// encrypted static P-384 public key and its tag, and the tag of her encrypted identity.
+    pub const HANDSHAKE_COMPLETION_MIN_SIZE: usize = P384_PUBLIC_KEY_SIZE + AES_GCM_TAG_SIZE + AES_GCM_TAG_SIZE;
+    /// The size of a handshake completion carrying an identity of `IDENTITY_MAX_SIZE` bytes,
+    /// without its header.
+    pub const HANDSHAKE_COMPLETION_MAX_SIZE: usize = HANDSHAKE_COMPLETION_MIN_SIZE + IDENTITY_MAX_SIZE;
+
+    /// The largest size of a handshake completion with its header, before fragmentation.
+    pub const HEADERED_HANDSHAKE_COMPLETION_MAX_SIZE: usize = HANDSHAKE_COMPLETION_MAX_SIZE + HEADER_SIZE;
+
+    /// The size of the length of a streamed identity, which starts the identity payload of the
+    /// handshake completion that streams it.
+    pub const STREAMED_IDENTITY_LENGTH_SIZE: usize = 4;
+    /// The largest size of an identity continuation without its header: the next `IDENTITY_MAX_SIZE`
+    /// bytes of a streamed identity, encrypted, and their tag.
+    pub const IDENTITY_CONTINUATION_MAX_SIZE: usize = IDENTITY_MAX_SIZE + AES_GCM_TAG_SIZE;
+    /// The largest size of an identity continuation with its header, before fragmentation.
+    pub const HEADERED_IDENTITY_CONTINUATION_MAX_SIZE: usize = IDENTITY_CONTINUATION_MAX_SIZE + HEADER_SIZE;
+
+    /// The size of a key confirmation without its header, which is only an authentication tag.
+    pub const KEY_CONFIRMATION_SIZE: usize = AES_GCM_TAG_SIZE;
+    /// The size of a key confirmation with its header.
+    pub const HEADERED_KEY_CONFIRMATION_SIZE: usize = KEY_CONFIRMATION_SIZE + HEADER_SIZE;
+
+    /// The size of an acknowledgement without its header, which is only an authentication tag.
+    pub const ACKNOWLEDGEMENT_SIZE: usize = AES_GCM_TAG_SIZE;
+    /// The size of an acknowledgement with its header.
+    pub const HEADERED_ACKNOWLEDGEMENT_SIZE: usize = ACKNOWLEDGEMENT_SIZE + HEADER_SIZE;
+
+    /// The size of a session rejection without its header, which is only an authentication tag.
+    pub const SESSION_REJECTED_SIZE: usize = AES_GCM_TAG_SIZE;
+    /// The size of a session rejection with its header.
+    pub const HEADERED_SESSION_REJECTED_SIZE: usize = SESSION_REJECTED_SIZE + HEADER_SIZE;
+
+    /// The size of a rekey init or rekey complete without its header: an ephemeral P-384 public
+    /// key, an encrypted payload holding the sender's new key id and the tags of the Noise KK
+    /// handshake.
+    pub const REKEY_SIZE: usize = P384_PUBLIC_KEY_SIZE + KID_SIZE + AES_GCM_TAG_SIZE + AES_GCM_TAG_SIZE;
+    /// The size of a rekey init or rekey complete with its header.
+    pub const HEADERED_REKEY_SIZE: usize = REKEY_SIZE + HEADER_SIZE;
+
+    /// The size of a key id rotation without its header: the sender's new key id and an
+    /// authentication tag.
+    pub const KID_ROTATE_SIZE: usize = KID_SIZE + AES_GCM_TAG_SIZE;
+    /// The size of a key id rotation with its header.
+    pub const HEADERED_KID_ROTATE_SIZE: usize = KID_ROTATE_SIZE + HEADER_SIZE;
+
+    /// The size of a rekey deferral without its header, which is only an authentication tag.
+    pub const REKEY_DEFER_SIZE: usize = AES_GCM_TAG_SIZE;
+    /// The size of a rekey deferral with its header.
+    pub const HEADERED_REKEY_DEFER_SIZE: usize = REKEY_DEFER_SIZE + HEADER_SIZE;
+}
+pub use v1::*;
 
 /// The application has the ability to attach a data payload to Alice's handshake.
 /// It will be the first payload Bob receives from Alice.
 /// The application also must attach a static public identity to their handshake.
-/// The combined size of both in bytes must be at most this value.
+/// The combined size of both in bytes must be at most this value to fit in the handshake
+/// completion.
 ///
-/// If not ZSSP will return `OpenError::DataTooLarge` and refuse to create a session object.
+/// Larger identities are streamed in additional packets after the handshake completion, up to
+/// `Settings::max_streamed_identity_size`. Beyond that ZSSP will return
+/// `OpenError::IdentityTooLarge` and refuse to create a session object.
 pub const IDENTITY_MAX_SIZE: usize = 4096;
 
 /* DOS mitigation constants */
 
-/// The maximum number of `NoiseXKBobHandshakeState` that a receive context will cache.
-/// These are extremely large and since Alice has not been authenticated we put a hard
-/// limit to how many we cache.
-/// Larger values consume more memory but provide better reliability and DDOS resistance.
-pub(crate) const MAX_UNASSOCIATED_HANDSHAKE_STATES: usize = 32;
-
 /// The maximum number of unassociated packets that a receive context will cache.
 /// Additional packets will either be dropped or cause a different packet to be dropped
 /// from the cache.
@@ -182,8 +333,53 @@ pub(crate) const MAX_UNASSOCIATED_FRAGMENTS: usize = 32 * 32;
 
 /// The maximum size a packet that is not associated to a session may be.
 /// Excludes the size of headers for fragmentation.
-pub(crate) const MAX_UNASSOCIATED_PACKET_SIZE: usize = HANDSHAKE_HELLO_CHALLENGE_SIZE;
+pub const MAX_UNASSOCIATED_PACKET_SIZE: usize = HANDSHAKE_HELLO_CHALLENGE_SIZE;
+
+/// The number of version unsupported packets a context will send per
+/// `VERSION_UNSUPPORTED_RATE_WINDOW` milliseconds. Hellos with an unsupported version beyond this
+/// rate are dropped silently, so the unauthenticated replies cannot be used for reflection.
+pub(crate) const MAX_VERSION_UNSUPPORTED_RATE: u64 = 16;
+pub(crate) const VERSION_UNSUPPORTED_RATE_WINDOW: i64 = 1000;
+
+/// The number of sessions that must be dropped before `Context::service` compacts the session
+/// queue, purging entries of dead sessions and releasing unused memory.
+pub(crate) const SESSION_QUEUE_COMPACTION_THRESHOLD: usize = 1024;
 
 /// This number determines how many defragmentation buffers are created per session.
 /// Each defragmentation buffer handles one packet at a time.
 pub(crate) const SESSION_MAX_FRAGMENTS_OOO: usize = 64;
+
+#[test]
+fn test_packet_size_bounds() {
+    // Every packet must fit within `MAX_FRAGMENTS` fragments at the smallest MTU we accept.
+    let max_packet_size = (MIN_TRANSPORT_MTU - HEADER_SIZE) * MAX_FRAGMENTS + HEADER_SIZE;
+    for size in [
+        HEADERED_CHALLENGE_SIZE,
+        HEADERED_HANDSHAKE_HELLO_CHALLENGE_SIZE,
+        HEADERED_HANDSHAKE_RESPONSE_SIZE,
+        HEADERED_HANDSHAKE_COMPLETION_MAX_SIZE,
+        HEADERED_IDENTITY_CONTINUATION_MAX_SIZE,
+        HEADERED_KEY_CONFIRMATION_SIZE,
+        HEADERED_ACKNOWLEDGEMENT_SIZE,
+        HEADERED_SESSION_REJECTED_SIZE,
+        HEADERED_REKEY_SIZE,
+        HEADERED_KID_ROTATE_SIZE,
+        HEADERED_REKEY_DEFER_SIZE,
+        HEADERED_VERSION_UNSUPPORTED_SIZE,
+    ] {
+        assert!(size >= MIN_PACKET_SIZE);
+        assert!(size <= max_packet_size);
+    }
+    // Challenges and version unsupported packets are always sent as a single fragment.
+    assert!(HEADERED_CHALLENGE_SIZE <= MIN_TRANSPORT_MTU);
+    assert!(HEADERED_VERSION_UNSUPPORTED_SIZE <= MIN_TRANSPORT_MTU);
+    assert!(KID_SIZE + 2 * PROTOCOL_VERSION_SIZE <= VERSION_UNSUPPORTED_SIZE);
+    // Bob's handshake response carries flags in the bits above every protocol version.
+    assert!(PROTOCOL_VERSION < VERSION_FLAG_MISUSE_RESISTANCE_REQUIRED);
+    // Hellos are received before there is a session to defragment them.
+    assert!(HANDSHAKE_HELLO_CHALLENGE_SIZE <= MAX_UNASSOCIATED_PACKET_SIZE);
+    assert!(HANDSHAKE_COMPLETION_MIN_SIZE <= HANDSHAKE_COMPLETION_MAX_SIZE);
+    // Bob assembles identity continuations in the buffer of the handshake completion.
+    assert!(IDENTITY_CONTINUATION_MAX_SIZE <= HANDSHAKE_COMPLETION_MAX_SIZE);
+    assert!(SEQUENCE_NUMBER_SIZE <= max_sendable_len(MIN_TRANSPORT_MTU));
+}
@@ -3,8 +3,21 @@ use zeroize::Zeroizing;
 
 use crate::crypto::*;
 use crate::proto::*;
+use crate::symmetric_state::kbkdf;
+
+/// The version of the encoding produced by `RatchetState::to_bytes` and
+/// `RatchetStates::to_bytes`.
+const RATCHET_ENCODING_VERSION: u8 = 2;
+/// The size in bytes of the encoding produced by `RatchetState::to_bytes`.
+pub const RATCHET_STATE_ENCODED_SIZE: usize = RATCHET_STATE_V1_ENCODED_SIZE + 1;
+/// The size in bytes of the encoding produced by `RatchetStates::to_bytes`.
+pub const RATCHET_STATES_ENCODED_SIZE: usize = 2 + 2 * RATCHET_STATE_ENCODED_SIZE;
+/// Version 1 of the encoding did not hold `RatchetState::min_version`.
+const RATCHET_STATE_V1_ENCODED_SIZE: usize = 2 + 2 * RATCHET_SIZE + 8;
+
 /// A ratchet key and fingerprint,
-/// along with the length of the ratchet chain the keys were derived from.
+/// along with the length of the ratchet chain the keys were derived from and the highest protocol
+/// version used with the remote peer along that chain.
 ///
 /// Implements constant time equality.
 /// The hash implementation only uses the ratchet fingerprint.
@@ -16,21 +29,22 @@ pub struct RatchetState {
     pub(crate) key: Zeroizing<[u8; RATCHET_SIZE]>,
     pub(crate) fingerprint: Zeroizing<[u8; RATCHET_SIZE]>,
     pub(crate) chain_len: u64,
+    pub(crate) min_version: u8,
 }
 impl PartialEq for RatchetState {
     fn eq(&self, other: &Self) -> bool {
         self.fingerprint.eq(&other.fingerprint) & (self.chain_len == other.chain_len)
     }
 }
-impl std::hash::Hash for RatchetState {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+impl core::hash::Hash for RatchetState {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         state.write(self.fingerprint.as_ref())
     }
 }
 impl RatchetState {
     /// Creates a new ratchet state from the given ratchet key, ratchet fingerprint and chain length.
     pub fn new(key: Zeroizing<[u8; RATCHET_SIZE]>, fingerprint: Zeroizing<[u8; RATCHET_SIZE]>, chain_len: u64) -> Self {
-        RatchetState { key, fingerprint, chain_len }
+        RatchetState { key, fingerprint, chain_len, min_version: 0 }
     }
     /// Creates a new ratchet state from the given ratchet key, ratchet fingerprint and chain length.
     ///
@@ -41,6 +55,7 @@ impl RatchetState {
             key: Zeroizing::new(key),
             fingerprint: Zeroizing::new(fingerprint),
             chain_len,
+            min_version: 0,
         }
     }
     /// Creates a new "empty" ratchet state, where the ratchet fingerprint is the
@@ -52,6 +67,7 @@ impl RatchetState {
             key: Zeroizing::new([0u8; RATCHET_SIZE]),
             fingerprint: Zeroizing::new([0u8; RATCHET_SIZE]),
             chain_len: 0,
+            min_version: 0,
         }
     }
     /// Creates a new ratchet state derived from a one-time-password. If both sides of a session use
@@ -104,11 +120,53 @@ impl RatchetState {
     pub fn chain_len(&self) -> u64 {
         self.chain_len
     }
+    /// The highest protocol version negotiated with the remote peer by any session this ratchet
+    /// state was derived from, or 0 if it was not derived from a session.
+    ///
+    /// A handshake that uses this ratchet state in a lower protocol version is rejected, unless
+    /// `ApplicationLayer::allow_version_downgrade` allows it. This prevents an attacker from
+    /// forcing two peers back to an older version once they have both moved past it.
+    pub fn min_version(&self) -> u8 {
+        self.min_version
+    }
+    /// Returns this ratchet state with `min_version` replaced. This is only needed by applications
+    /// that store ratchet states in their own format instead of using `RatchetState::to_bytes`.
+    pub fn with_min_version(mut self, min_version: u8) -> Self {
+        self.min_version = min_version;
+        self
+    }
     /// Returns true if this is the "empty" ratchet state, where the ratchet fingerprint is the
     /// empty string, the ratchet key is all zeros, and the chain length is 0.
     pub fn is_empty(&self) -> bool {
         secure_eq(self.fingerprint(), &[0u8; RATCHET_SIZE])
     }
+    /// Derive a symmetric key shared with the remote peer that holds this same ratchet state,
+    /// without performing a new handshake. This can be used to bootstrap side-channel protocols
+    /// between known peers.
+    ///
+    /// Each peer contributes a salt, and the salts are combined commutatively so both peers derive
+    /// the same key when they swap `local_salt` and `remote_salt`. Different labels produce
+    /// independent keys. The derivation is HMAC-SHA512 KBKDF in counter mode, keyed with the
+    /// ratchet key, with the combined salt as its context.
+    ///
+    /// Returns `None` if this is the empty ratchet state, since its key is publicly known.
+    pub fn derive_pairwise_key<Hmac: Sha512Hmac>(
+        &self,
+        label: &[u8; 4],
+        local_salt: &[u8; 32],
+        remote_salt: &[u8; 32],
+    ) -> Option<Zeroizing<[u8; 32]>> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut salt = Zeroizing::new([0u8; 32]);
+        for (s, (a, b)) in salt.iter_mut().zip(local_salt.iter().zip(remote_salt)) {
+            *s = a ^ b;
+        }
+        let mut output = Zeroizing::new([0u8; HASHLEN]);
+        kbkdf(&mut Hmac::new(), self.key.as_ref(), label, &salt, 1, &mut output, None, None);
+        Some(Zeroizing::new(output[..32].try_into().unwrap()))
+    }
     /// Checks if the fingerprint of this ratchet state equals the
     ///  fingerprint contained in argument `rf`.
     ///
@@ -131,19 +189,84 @@ impl RatchetState {
             _ => false,
         }
     }
+    /// Encode this ratchet state into a fixed-size byte array, so that it can be persisted
+    /// directly by `ApplicationLayer::save_ratchet_state`. `RatchetState::from_bytes` decodes it.
+    ///
+    /// The layout is a version byte, a flag byte that is 1 unless this is the empty ratchet
+    /// state, the ratchet key, the ratchet fingerprint, the chain length as a big-endian `u64`
+    /// and finally `min_version`. This layout will not change without the version byte changing,
+    /// and `from_bytes` will keep accepting every previous version.
+    ///
+    /// The output contains the ratchet key, so it must be stored as securely as the key itself.
+    pub fn to_bytes(&self) -> Zeroizing<[u8; RATCHET_STATE_ENCODED_SIZE]> {
+        let mut out = Zeroizing::new([0u8; RATCHET_STATE_ENCODED_SIZE]);
+        out[0] = RATCHET_ENCODING_VERSION;
+        out[1] = !self.is_empty() as u8;
+        out[2..2 + RATCHET_SIZE].copy_from_slice(self.key.as_ref());
+        out[2 + RATCHET_SIZE..2 + 2 * RATCHET_SIZE].copy_from_slice(self.fingerprint.as_ref());
+        out[2 + 2 * RATCHET_SIZE..RATCHET_STATE_V1_ENCODED_SIZE].copy_from_slice(&self.chain_len.to_be_bytes());
+        out[RATCHET_STATE_V1_ENCODED_SIZE] = self.min_version;
+        out
+    }
+    /// Decode a ratchet state encoded by `RatchetState::to_bytes`, by the current or any previous
+    /// version of this crate. Encodings of previous versions may be shorter than
+    /// `RATCHET_STATE_ENCODED_SIZE`.
+    ///
+    /// Returns `None` if the version is unknown or the encoding is inconsistent, for example if
+    /// the flag byte claims this is the empty ratchet state while the fingerprint is not empty.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let min_version = match (bytes.first()?, bytes.len()) {
+            (1, RATCHET_STATE_V1_ENCODED_SIZE) => 0,
+            (&RATCHET_ENCODING_VERSION, RATCHET_STATE_ENCODED_SIZE) => bytes[RATCHET_STATE_V1_ENCODED_SIZE],
+            _ => return None,
+        };
+        let chain_len = &bytes[2 + 2 * RATCHET_SIZE..RATCHET_STATE_V1_ENCODED_SIZE];
+        let state = Self::new_raw(
+            bytes[2..2 + RATCHET_SIZE].try_into().unwrap(),
+            bytes[2 + RATCHET_SIZE..2 + 2 * RATCHET_SIZE].try_into().unwrap(),
+            u64::from_be_bytes(chain_len.try_into().unwrap()),
+        )
+        .with_min_version(min_version);
+        let is_empty = match bytes[1] {
+            0 => true,
+            1 => false,
+            _ => return None,
+        };
+        if is_empty != state.is_empty() {
+            return None;
+        }
+        let has_history = state.chain_len != 0 || state.min_version != 0;
+        if is_empty && (has_history || !secure_eq(state.key(), &[0u8; RATCHET_SIZE])) {
+            return None;
+        }
+        Some(state)
+    }
 }
 impl Default for RatchetState {
     fn default() -> Self {
         Self::empty()
     }
 }
+/// The ratchet key and fingerprint are never printed, so ratchet states can be logged safely.
+impl core::fmt::Debug for RatchetState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RatchetState")
+            .field("key", &format_args!("<redacted>"))
+            .field("fingerprint", &format_args!("<redacted>"))
+            .field("empty", &self.is_empty())
+            .field("chain_len", &self.chain_len)
+            .field("min_version", &self.min_version)
+            .finish()
+    }
+}
 
 /// An ordered pair of two ratchet states.
 /// It is expected that an instance of this object will be saved to a storage device per-peer,
 /// and be restore-able via the `ApplicationLayer` trait.
+/// `RatchetStates::to_bytes` provides a stable encoding for this purpose.
 ///
 /// This corresponds to the possible values of abstract variables `rf` and `rk` found in Section 4.3.
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct RatchetStates {
     /// The first ratchet state from the pair.
     pub state1: RatchetState,
@@ -177,6 +300,69 @@ impl RatchetStates {
             state2: None,
         }
     }
+    /// Returns true if neither ratchet state holds a ratchet key, as is the case for
+    /// `RatchetStates::new_initial_states`. The next handshake with this peer is then a first contact.
+    pub fn is_empty(&self) -> bool {
+        self.state1.is_empty() && self.state2.as_ref().is_none_or(RatchetState::is_empty)
+    }
+    /// The longest chain length of the two ratchet states, which is the number of handshakes that
+    /// have completed with this peer since the ratchet was first initialized.
+    pub fn chain_len(&self) -> u64 {
+        let chain_len2 = self.state2.as_ref().map_or(0, RatchetState::chain_len);
+        self.state1.chain_len().max(chain_len2)
+    }
+    /// Encode this pair of ratchet states into a fixed-size byte array, so that it can be stored
+    /// per peer as is. `RatchetStates::from_bytes` decodes it.
+    ///
+    /// The layout is a version byte, the encoding of `state1` as produced by
+    /// `RatchetState::to_bytes`, a flag byte that is 1 if `state2` is not `None`, and the
+    /// encoding of `state2`, which is all zeros if it is `None`. This layout will not change
+    /// without the version byte changing, and `from_bytes` will keep accepting every previous
+    /// version.
+    ///
+    /// The output contains ratchet keys, so it must be stored as securely as the keys themselves.
+    pub fn to_bytes(&self) -> Zeroizing<[u8; RATCHET_STATES_ENCODED_SIZE]> {
+        let mut out = Zeroizing::new([0u8; RATCHET_STATES_ENCODED_SIZE]);
+        let (state1, rest) = out[1..].split_at_mut(RATCHET_STATE_ENCODED_SIZE);
+        state1.copy_from_slice(self.state1.to_bytes().as_ref());
+        if let Some(state2) = &self.state2 {
+            rest[0] = 1;
+            rest[1..].copy_from_slice(state2.to_bytes().as_ref());
+        }
+        out[0] = RATCHET_ENCODING_VERSION;
+        out
+    }
+    /// Decode a pair of ratchet states encoded by `RatchetStates::to_bytes`, by the current or
+    /// any previous version of this crate. Encodings of previous versions may be shorter than
+    /// `RATCHET_STATES_ENCODED_SIZE`, see `RatchetStates::encoded_size`.
+    ///
+    /// Returns `None` if the version is unknown or the encoding is inconsistent.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if Some(bytes.len()) != Self::encoded_size(*bytes.first()?) {
+            return None;
+        }
+        let (state1, rest) = bytes[1..].split_at(bytes.len() / 2 - 1);
+        // The encoding of each state repeats the version byte, which must match.
+        if state1[0] != bytes[0] {
+            return None;
+        }
+        let state1 = RatchetState::from_bytes(state1)?;
+        let state2 = match rest[0] {
+            0 if rest[1..].iter().all(|b| *b == 0) => None,
+            1 => Some(RatchetState::from_bytes(&rest[1..]).filter(|_| rest[1] == bytes[0])?),
+            _ => return None,
+        };
+        Some(Self { state1, state2 })
+    }
+    /// The size of an encoding produced by `RatchetStates::to_bytes` that starts with the given
+    /// version byte, or `None` if the version is unknown.
+    pub fn encoded_size(version: u8) -> Option<usize> {
+        match version {
+            1 => Some(2 + 2 * RATCHET_STATE_V1_ENCODED_SIZE),
+            RATCHET_ENCODING_VERSION => Some(RATCHET_STATES_ENCODED_SIZE),
+            _ => None,
+        }
+    }
 }
 impl Default for RatchetStates {
     fn default() -> Self {
@@ -324,3 +510,306 @@ impl<'a> CompareAndSwap<'a> {
         self.cur_state1.eq(&other.state1) & self.cur_state2.eq(&other.state2.as_ref())
     }
 }
+
+/// `Serialize` and `Deserialize` for `RatchetState` and `RatchetStates`, through the encodings of
+/// their `to_bytes` functions. Human-readable formats get the encoding as a base64 string, and
+/// binary formats get the raw bytes.
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use core::fmt;
+
+    use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Visitor};
+    use serde::{Serialize, Serializer};
+    use zeroize::Zeroizing;
+
+    use super::*;
+
+    /// The length of the base64 encoding of the largest encoding, `RatchetStates::to_bytes`.
+    const BASE64_SIZE: usize = RATCHET_STATES_ENCODED_SIZE.div_ceil(3) * 4;
+
+    /// Maps a 6 bit value to its character in the standard base64 alphabet, without branching on
+    /// the value, since it is derived from a ratchet key.
+    fn encode_char(v: u32) -> u8 {
+        let v = v as i32;
+        let mut c = v + b'A' as i32;
+        c += ((25 - v) >> 8) & 6;
+        c -= ((51 - v) >> 8) & 75;
+        c -= ((61 - v) >> 8) & 15;
+        c += ((62 - v) >> 8) & 3;
+        c as u8
+    }
+    /// The inverse of `encode_char`, which returns -1 for characters outside the alphabet.
+    fn decode_char(c: u8) -> i32 {
+        let c = c as i32;
+        let mut v = -1;
+        v += (((0x40 - c) & (c - 0x5b)) >> 8) & (c - 64);
+        v += (((0x60 - c) & (c - 0x7b)) >> 8) & (c - 70);
+        v += (((0x2f - c) & (c - 0x3a)) >> 8) & (c + 5);
+        v += (((0x2a - c) & (c - 0x2c)) >> 8) & 63;
+        v += (((0x2e - c) & (c - 0x30)) >> 8) & 64;
+        v
+    }
+    /// Writes the padded base64 encoding of `bytes` to `out`, and returns its length.
+    fn encode_base64(bytes: &[u8], out: &mut [u8]) -> usize {
+        let mut len = 0;
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                out[len + i] = if i <= chunk.len() {
+                    encode_char((n >> (18 - 6 * i)) & 63)
+                } else {
+                    b'='
+                };
+            }
+            len += 4;
+        }
+        len
+    }
+    /// Decodes padded base64 into `out`, and returns the number of bytes written.
+    /// Returns `None` if `base64` is invalid or would not fit.
+    fn decode_base64(base64: &[u8], out: &mut [u8]) -> Option<usize> {
+        let padding = base64.iter().rev().take(2).take_while(|c| **c == b'=').count();
+        if !base64.len().is_multiple_of(4) || base64.len() / 4 * 3 - padding > out.len() {
+            return None;
+        }
+        let mut invalid = 0;
+        let mut len = 0;
+        for chunk in base64[..base64.len() - padding].chunks(4) {
+            let mut n = 0u32;
+            for (i, c) in chunk.iter().enumerate() {
+                let v = decode_char(*c);
+                invalid |= v;
+                n |= (v as u32 & 63) << (18 - 6 * i);
+            }
+            for i in 0..chunk.len() - 1 {
+                out[len] = (n >> (16 - 8 * i)) as u8;
+                len += 1;
+            }
+        }
+        (invalid >= 0).then_some(len)
+    }
+
+    fn serialize_encoding<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let mut base64 = Zeroizing::new([0u8; BASE64_SIZE]);
+            let len = encode_base64(bytes, &mut base64[..]);
+            serializer.serialize_str(core::str::from_utf8(&base64[..len]).unwrap())
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+    fn deserialize_encoding<'de, D: Deserializer<'de>, T>(
+        deserializer: D,
+        from_bytes: fn(&[u8]) -> Option<T>,
+    ) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(EncodingVisitor(from_bytes))
+        } else {
+            deserializer.deserialize_bytes(EncodingVisitor(from_bytes))
+        }
+    }
+
+    /// Decodes the output of a `to_bytes` function with the matching `from_bytes` function.
+    ///
+    /// Errors never include the input, which would leak ratchet keys into logs.
+    struct EncodingVisitor<T>(fn(&[u8]) -> Option<T>);
+    impl<'de, T> Visitor<'de> for EncodingVisitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an encoded ratchet state")
+        }
+        fn visit_str<E: Error>(self, v: &str) -> Result<T, E> {
+            let mut bytes = Zeroizing::new([0u8; RATCHET_STATES_ENCODED_SIZE]);
+            let len = decode_base64(v.as_bytes(), &mut bytes[..]).ok_or_else(|| E::custom("invalid base64"))?;
+            self.visit_bytes(&bytes[..len])
+        }
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<T, E> {
+            (self.0)(v).ok_or_else(|| E::custom("invalid ratchet state encoding"))
+        }
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+            let mut bytes = Zeroizing::new([0u8; RATCHET_STATES_ENCODED_SIZE]);
+            let mut len = 0;
+            while let Some(b) = seq.next_element::<u8>()? {
+                *bytes
+                    .get_mut(len)
+                    .ok_or_else(|| A::Error::custom("invalid ratchet state encoding"))? = b;
+                len += 1;
+            }
+            self.visit_bytes(&bytes[..len])
+        }
+    }
+
+    impl Serialize for RatchetState {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize_encoding(&self.to_bytes()[..], serializer)
+        }
+    }
+    impl<'de> Deserialize<'de> for RatchetState {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize_encoding(deserializer, RatchetState::from_bytes)
+        }
+    }
+    impl Serialize for RatchetStates {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize_encoding(&self.to_bytes()[..], serializer)
+        }
+    }
+    impl<'de> Deserialize<'de> for RatchetStates {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize_encoding(deserializer, RatchetStates::from_bytes)
+        }
+    }
+
+    #[test]
+    fn test_base64() {
+        let vectors = ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"];
+        for (len, base64) in vectors.into_iter().enumerate() {
+            let mut out = [0u8; 8];
+            let len_out = encode_base64(&b"foobar"[..len], &mut out);
+            assert_eq!(&out[..len_out], base64.as_bytes());
+            let len_out = decode_base64(base64.as_bytes(), &mut out).unwrap();
+            assert_eq!(&out[..len_out], &b"foobar"[..len]);
+        }
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        for v in 0..64 {
+            assert_eq!(encode_char(v), alphabet[v as usize]);
+        }
+        for c in 0..=255u8 {
+            assert_eq!(
+                decode_char(c),
+                alphabet.iter().position(|a| *a == c).map_or(-1, |v| v as i32)
+            );
+        }
+        for invalid in ["Zg=", "Z===", "Zm9v-g==", "Zm9vYmFy\n"] {
+            assert_eq!(decode_base64(invalid.as_bytes(), &mut [0u8; 8]), None);
+        }
+        assert_eq!(decode_base64(b"Zm9vYmFyYmF6", &mut [0u8; 8]), None);
+    }
+}
+
+#[test]
+fn test_ratchet_state_encoding() {
+    use rand_core::RngCore;
+    let random_state = || {
+        let mut key = [0u8; RATCHET_SIZE];
+        let mut fingerprint = [0u8; RATCHET_SIZE];
+        rand_core::OsRng.fill_bytes(&mut key);
+        rand_core::OsRng.fill_bytes(&mut fingerprint);
+        let min_version = rand_core::OsRng.next_u32() as u8;
+        RatchetState::new_raw(key, fingerprint, rand_core::OsRng.next_u64()).with_min_version(min_version)
+    };
+    let round_trip = |states: RatchetStates| {
+        let decoded = RatchetStates::from_bytes(&states.to_bytes()[..]).unwrap();
+        assert!(decoded == states);
+        assert_eq!(decoded.state1.key(), states.state1.key());
+        assert_eq!(decoded.state1.min_version(), states.state1.min_version());
+        assert_eq!(decoded.state2.map(|s| *s.key()), states.state2.map(|s| *s.key()));
+    };
+    round_trip(RatchetStates::new_initial_states());
+    round_trip(RatchetStates::new(RatchetState::empty(), Some(random_state())));
+    for _ in 0..100 {
+        round_trip(RatchetStates::new(random_state(), None));
+        round_trip(RatchetStates::new(random_state(), Some(random_state())));
+    }
+
+    // The layout is fixed, so a known encoding must keep decoding to the same state.
+    let state = RatchetState::new_raw([1; RATCHET_SIZE], [2; RATCHET_SIZE], 0x0102).with_min_version(3);
+    let bytes = state.to_bytes();
+    assert_eq!(bytes[..2], [2, 1]);
+    assert_eq!(bytes[2..2 + RATCHET_SIZE], [1; RATCHET_SIZE]);
+    assert_eq!(bytes[2 + RATCHET_SIZE..2 + 2 * RATCHET_SIZE], [2; RATCHET_SIZE]);
+    assert_eq!(bytes[2 + 2 * RATCHET_SIZE..], [0, 0, 0, 0, 0, 0, 1, 2, 3]);
+    assert!(RatchetState::from_bytes(&RatchetState::empty().to_bytes()[..]) == Some(RatchetState::empty()));
+
+    // Version 1 encodings, which have no version floor, are still accepted.
+    let mut v1 = [0u8; 2 + 2 * RATCHET_STATE_V1_ENCODED_SIZE];
+    v1[0] = 1;
+    v1[1..1 + RATCHET_STATE_V1_ENCODED_SIZE].copy_from_slice(&bytes[..RATCHET_STATE_V1_ENCODED_SIZE]);
+    v1[1] = 1;
+    let decoded = RatchetStates::from_bytes(&v1).unwrap();
+    assert!(decoded == RatchetStates::new(state.clone(), None));
+    assert_eq!(decoded.state1.min_version(), 0);
+    assert!(RatchetStates::from_bytes(&v1[..v1.len() - 1]).is_none());
+
+    let mut bad = *bytes;
+    bad[0] = 3;
+    assert!(RatchetState::from_bytes(&bad).is_none());
+    let mut bad = *bytes;
+    bad[1] = 0;
+    assert!(RatchetState::from_bytes(&bad).is_none());
+    let mut bad = *RatchetStates::new_initial_states().to_bytes();
+    bad[RATCHET_STATES_ENCODED_SIZE - 1] = 1;
+    assert!(RatchetStates::from_bytes(&bad).is_none());
+}
+
+#[test]
+fn test_ratchet_states_chain_len() {
+    let state = |chain_len| RatchetState::new_raw([1; RATCHET_SIZE], [chain_len as u8; RATCHET_SIZE], chain_len);
+    assert!(RatchetStates::new_initial_states().is_empty());
+    assert_eq!(RatchetStates::new_initial_states().chain_len(), 0);
+    let states = RatchetStates::new(RatchetState::empty(), Some(state(2)));
+    assert!(!states.is_empty());
+    assert_eq!(states.chain_len(), 2);
+    let states = RatchetStates::new(state(4), Some(state(3)));
+    assert!(!states.is_empty());
+    assert_eq!(states.chain_len(), 4);
+}
+
+#[test]
+fn test_ratchet_state_debug() {
+    let state = RatchetState::new_raw([0xab; RATCHET_SIZE], [0xcd; RATCHET_SIZE], 3);
+    let debug = format!("{:?}", RatchetStates::new(state, Some(RatchetState::empty())));
+    assert!(!debug.contains("171") && !debug.contains("205"), "{}", debug);
+    assert!(debug.contains("chain_len: 3"), "{}", debug);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_ratchet_state_serde() {
+    let state = RatchetState::new_raw([1; RATCHET_SIZE], [2; RATCHET_SIZE], 3).with_min_version(4);
+    let json = serde_json::to_string(&state).unwrap();
+    assert_eq!(json.len(), 2 + RATCHET_STATE_ENCODED_SIZE / 3 * 4);
+    let decoded: RatchetState = serde_json::from_str(&json).unwrap();
+    assert!(decoded == state);
+    assert_eq!(decoded.key(), state.key());
+    assert_eq!(decoded.min_version(), 4);
+
+    for states in [
+        RatchetStates::new_initial_states(),
+        RatchetStates::new(state.clone(), Some(state)),
+    ] {
+        let json = serde_json::to_string(&states).unwrap();
+        let decoded: RatchetStates = serde_json::from_str(&json).unwrap();
+        assert!(decoded == states);
+        assert_eq!(decoded.state2.map(|s| *s.key()), states.state2.map(|s| *s.key()));
+    }
+    assert!(serde_json::from_str::<RatchetState>("\"AAAA\"").is_err());
+    assert!(serde_json::from_str::<RatchetState>("\"not base64\"").is_err());
+    assert!(serde_json::from_str::<RatchetStates>("\"\"").is_err());
+}
+
+#[test]
+fn test_derive_pairwise_key() {
+    use crate::crypto_impl::CrateHmacSha512;
+    let state = RatchetState::new_raw([1; RATCHET_SIZE], [2; RATCHET_SIZE], 1);
+    let (alice_salt, bob_salt) = ([3u8; 32], [4u8; 32]);
+    let derive = |state: &RatchetState, label, local, remote| {
+        state.derive_pairwise_key::<CrateHmacSha512>(label, local, remote)
+    };
+
+    // Both peers derive the same key from their own point of view.
+    let alice_key = derive(&state, b"TEST", &alice_salt, &bob_salt).unwrap();
+    let bob_key = derive(&state, b"TEST", &bob_salt, &alice_salt).unwrap();
+    assert_eq!(*alice_key, *bob_key);
+    // Every input changes the key.
+    assert_ne!(*derive(&state, b"TES2", &alice_salt, &bob_salt).unwrap(), *alice_key);
+    assert_ne!(*derive(&state, b"TEST", &[5u8; 32], &bob_salt).unwrap(), *alice_key);
+    let other = RatchetState::new_raw([6; RATCHET_SIZE], [2; RATCHET_SIZE], 1);
+    assert_ne!(*derive(&other, b"TEST", &alice_salt, &bob_salt).unwrap(), *alice_key);
+    // The key of the empty ratchet state is public, so nothing is derived from it.
+    assert!(derive(&RatchetState::empty(), b"TEST", &alice_salt, &bob_salt).is_none());
+}
@@ -0,0 +1,341 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::hash::Hash;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use zeroize::Zeroizing;
+
+use crate::proto::RATCHET_SIZE;
+use crate::ratchet_state::{CompareAndSwap, RatchetState, RatchetStates};
+
+/// The inconsistencies between the two maps of a ratchet storage implementation found by
+/// `verify_consistency`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsistencyReport<K> {
+    /// Fingerprints in the fingerprint map that no identity holds a ratchet state for.
+    /// `ApplicationLayer::restore_by_fingerprint` can succeed for these while
+    /// `ApplicationLayer::restore_by_identity` returns different ratchet states, which will
+    /// cause handshakes with the remote peer to fail.
+    pub dangling_fingerprints: Vec<[u8; RATCHET_SIZE]>,
+    /// Non-empty ratchet states held by an identity whose fingerprint is missing from the
+    /// fingerprint map, along with that identity. Remote peers cannot be recognized by these
+    /// fingerprints.
+    pub missing_fingerprints: Vec<(K, [u8; RATCHET_SIZE])>,
+}
+impl<K> ConsistencyReport<K> {
+    /// Returns true if no inconsistencies were found.
+    pub fn is_consistent(&self) -> bool {
+        self.dangling_fingerprints.is_empty() && self.missing_fingerprints.is_empty()
+    }
+}
+
+fn held_states(states: &RatchetStates) -> impl Iterator<Item = &RatchetState> {
+    [Some(&states.state1), states.state2.as_ref()].into_iter().flatten()
+}
+
+/// Check that the identity map and the fingerprint map of a ratchet storage implementation agree
+/// with each other.
+///
+/// `identities` should yield every identity in storage along with the ratchet states that
+/// `ApplicationLayer::restore_by_identity` returns for it, and `fingerprints` should yield every
+/// fingerprint that `ApplicationLayer::restore_by_fingerprint` can find. Empty ratchet states are
+/// never looked up by fingerprint, so they are ignored.
+///
+/// This is meant for offline checks and debugging, it is not constant time.
+pub fn verify_consistency<'a, 'b, K: Clone>(
+    identities: impl IntoIterator<Item = (K, &'a RatchetStates)>,
+    fingerprints: impl IntoIterator<Item = &'b [u8; RATCHET_SIZE]>,
+) -> ConsistencyReport<K> {
+    let fingerprints: Vec<[u8; RATCHET_SIZE]> = fingerprints.into_iter().copied().collect();
+    let indexed: HashSet<&[u8; RATCHET_SIZE]> = fingerprints.iter().collect();
+    let mut held = HashSet::new();
+    let mut missing_fingerprints = Vec::new();
+    for (key, states) in identities {
+        for state in held_states(states) {
+            if state.is_empty() {
+                continue;
+            }
+            held.insert(*state.fingerprint());
+            if !indexed.contains(state.fingerprint()) {
+                missing_fingerprints.push((key.clone(), *state.fingerprint()));
+            }
+        }
+    }
+    let dangling_fingerprints = fingerprints.iter().filter(|rf| !held.contains(*rf)).copied().collect();
+    ConsistencyReport { dangling_fingerprints, missing_fingerprints }
+}
+
+/// A reference implementation of ratchet storage held entirely in memory, which the storage
+/// functions of an `ApplicationLayer` can delegate to.
+///
+/// Ratchet states are stored per identity, where an identity `K` is whatever the application
+/// uses to tell remote peers apart, for example the bytes of their static key. The fingerprint
+/// map only points at identities, so it can never return a ratchet state that
+/// `restore_by_identity` disagrees with.
+#[derive(Clone)]
+pub struct MemoryRatchetStore<K> {
+    identities: HashMap<K, RatchetStates>,
+    fingerprints: HashMap<[u8; RATCHET_SIZE], K>,
+}
+impl<K: Clone + Eq + Hash> MemoryRatchetStore<K> {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self { identities: HashMap::new(), fingerprints: HashMap::new() }
+    }
+    /// Find the ratchet state with fingerprint `rf`, along with the identity holding it.
+    /// The identity can be used as `CryptoLayer::FingerprintData`.
+    ///
+    /// Implements `ApplicationLayer::restore_by_fingerprint`.
+    pub fn restore_by_fingerprint(&self, rf: &[u8; RATCHET_SIZE]) -> Option<(RatchetState, K)> {
+        let key = self.fingerprints.get(rf)?;
+        let states = self.identities.get(key)?;
+        held_states(states)
+            .find(|state| state.fingerprint_eq(rf))
+            .map(|state| (state.clone(), key.clone()))
+    }
+    /// Find the ratchet states of identity `key`.
+    ///
+    /// Implements `ApplicationLayer::restore_by_identity`.
+    pub fn restore_by_identity(&self, key: &K) -> Option<RatchetStates> {
+        self.identities.get(key).cloned()
+    }
+    /// Apply `update` to the ratchet states of identity `key` if they are still the ratchet
+    /// states it expects, returning false otherwise.
+    ///
+    /// Implements `ApplicationLayer::save_ratchet_state`.
+    pub fn save_ratchet_state(&mut self, key: &K, update: CompareAndSwap<'_>) -> bool {
+        let matches = match self.identities.get(key) {
+            Some(cur) => update.compare(cur),
+            None => update.cur_is_initial_states(),
+        };
+        if !matches {
+            return false;
+        }
+        for rf in [update.deleted_fingerprint1(), update.deleted_fingerprint2()]
+            .into_iter()
+            .flatten()
+        {
+            self.fingerprints.remove(rf);
+        }
+        if let Some(rf) = update.added_fingerprint() {
+            self.fingerprints.insert(*rf, key.clone());
+        }
+        self.identities.insert(key.clone(), update.to_new_states());
+        true
+    }
+    /// Overwrite the ratchet states of identity `key`, for example with
+    /// `RatchetStates::new_otp_states` before connecting to a new peer.
+    pub fn insert(&mut self, key: K, states: RatchetStates) {
+        self.remove(&key);
+        for state in held_states(&states) {
+            if !state.is_empty() {
+                self.fingerprints.insert(*state.fingerprint(), key.clone());
+            }
+        }
+        self.identities.insert(key, states);
+    }
+    /// Remove identity `key` and all of its ratchet states from the store.
+    pub fn remove(&mut self, key: &K) -> Option<RatchetStates> {
+        let states = self.identities.remove(key)?;
+        for state in held_states(&states) {
+            self.fingerprints.remove(state.fingerprint());
+        }
+        Some(states)
+    }
+    /// Every identity in the store along with its ratchet states.
+    pub fn identities(&self) -> impl Iterator<Item = (&K, &RatchetStates)> {
+        self.identities.iter()
+    }
+}
+impl<K: Clone + Eq + Hash> Default for MemoryRatchetStore<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A reference implementation of ratchet storage backed by a single file, which the storage
+/// functions of an `ApplicationLayer` can delegate to.
+///
+/// Every ratchet state is kept in memory as well, and the whole file is rewritten after each
+/// change. A change only takes effect once the file has been replaced, so a failed write leaves
+/// both the file and the store unchanged. This is simple but only suited to a modest number of
+/// remote peers.
+///
+/// The file is a sequence of records, each consisting of the length of the identity as a
+/// little-endian `u16`, the identity itself and the encoding of its ratchet states produced by
+/// `RatchetStates::to_bytes`. It contains ratchet keys, so on unix it is created readable by its
+/// owner only.
+pub struct FileRatchetStore<K> {
+    path: PathBuf,
+    store: MemoryRatchetStore<K>,
+}
+impl<K: Clone + Eq + Hash + AsRef<[u8]> + for<'a> TryFrom<&'a [u8]>> FileRatchetStore<K> {
+    /// Load the store from the file at `path`, or start an empty store if it does not exist yet.
+    ///
+    /// Returns an error of kind `InvalidData` if the file is malformed.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut store = MemoryRatchetStore::new();
+        let data = match std::fs::read(&path) {
+            Ok(data) => Zeroizing::new(data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Zeroizing::new(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed ratchet store");
+        let mut r = &data[..];
+        while !r.is_empty() {
+            let len = u16::from_le_bytes(r.get(..2).ok_or_else(invalid)?.try_into().unwrap()) as usize;
+            let key = K::try_from(r.get(2..2 + len).ok_or_else(invalid)?).map_err(|_| invalid())?;
+            // Records written by previous versions of this crate may use an older encoding.
+            let version = *r.get(2 + len).ok_or_else(invalid)?;
+            let size = RatchetStates::encoded_size(version).ok_or_else(invalid)?;
+            let states = r.get(2 + len..2 + len + size).ok_or_else(invalid)?;
+            let states = RatchetStates::from_bytes(states).ok_or_else(invalid)?;
+            if store.restore_by_identity(&key).is_some() {
+                return Err(invalid());
+            }
+            store.insert(key, states);
+            r = &r[2 + len + size..];
+        }
+        Ok(Self { path, store })
+    }
+    /// See `MemoryRatchetStore::restore_by_fingerprint`.
+    pub fn restore_by_fingerprint(&self, rf: &[u8; RATCHET_SIZE]) -> Option<(RatchetState, K)> {
+        self.store.restore_by_fingerprint(rf)
+    }
+    /// See `MemoryRatchetStore::restore_by_identity`.
+    pub fn restore_by_identity(&self, key: &K) -> Option<RatchetStates> {
+        self.store.restore_by_identity(key)
+    }
+    /// See `MemoryRatchetStore::save_ratchet_state`.
+    /// The change is written to the file before this returns true.
+    pub fn save_ratchet_state(&mut self, key: &K, update: CompareAndSwap<'_>) -> io::Result<bool> {
+        let mut store = self.store.clone();
+        if !store.save_ratchet_state(key, update) {
+            return Ok(false);
+        }
+        self.replace(store)?;
+        Ok(true)
+    }
+    /// See `MemoryRatchetStore::insert`.
+    pub fn insert(&mut self, key: K, states: RatchetStates) -> io::Result<()> {
+        let mut store = self.store.clone();
+        store.insert(key, states);
+        self.replace(store)
+    }
+    /// See `MemoryRatchetStore::remove`.
+    pub fn remove(&mut self, key: &K) -> io::Result<Option<RatchetStates>> {
+        let mut store = self.store.clone();
+        let states = store.remove(key);
+        if states.is_some() {
+            self.replace(store)?;
+        }
+        Ok(states)
+    }
+    /// See `MemoryRatchetStore::identities`.
+    pub fn identities(&self) -> impl Iterator<Item = (&K, &RatchetStates)> {
+        self.store.identities()
+    }
+    /// Write `store` to a temporary file and move it over the current file, so the file is never
+    /// left partially written.
+    fn replace(&mut self, store: MemoryRatchetStore<K>) -> io::Result<()> {
+        let mut data = Zeroizing::new(Vec::new());
+        for (key, states) in store.identities() {
+            let key = key.as_ref();
+            let len = u16::try_from(key.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "identity is too long"))?;
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(key);
+            data.extend_from_slice(states.to_bytes().as_ref());
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.store = store;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn random_state(chain_len: u64) -> RatchetState {
+    use rand_core::RngCore;
+    let mut key = [0u8; RATCHET_SIZE];
+    let mut fingerprint = [0u8; RATCHET_SIZE];
+    rand_core::OsRng.fill_bytes(&mut key);
+    rand_core::OsRng.fill_bytes(&mut fingerprint);
+    RatchetState::new_raw(key, fingerprint, chain_len)
+}
+
+#[test]
+fn test_memory_ratchet_store() {
+    let (s1, s2, s3) = (random_state(1), random_state(2), random_state(3));
+    let empty = RatchetState::empty();
+    let mut store = MemoryRatchetStore::new();
+    // The first handshake with a peer we have no ratchet states for.
+    assert!(store.save_ratchet_state(&1u32, CompareAndSwap::new(&s1, None, true, &empty, None, false, false)));
+    assert!(!store.save_ratchet_state(&1u32, CompareAndSwap::new(&s1, None, true, &empty, None, false, false)));
+    assert!(store.restore_by_fingerprint(s1.fingerprint()) == Some((s1.clone(), 1)));
+    // Rekeys push the oldest ratchet state out of storage.
+    assert!(store.save_ratchet_state(&1, CompareAndSwap::new(&s2, Some(&s1), true, &s1, None, false, false)));
+    assert!(store.save_ratchet_state(
+        &1,
+        CompareAndSwap::new(&s3, Some(&s2), true, &s2, Some(&s1), false, true)
+    ));
+    assert!(store.restore_by_fingerprint(s1.fingerprint()).is_none());
+    assert!(store.restore_by_fingerprint(s2.fingerprint()) == Some((s2.clone(), 1)));
+    assert!(store.restore_by_identity(&1) == Some(RatchetStates::new(s3.clone(), Some(s2.clone()))));
+
+    store.insert(2, RatchetStates::new(s1.clone(), None));
+    assert!(verify_consistency(store.identities(), store.fingerprints.keys()).is_consistent());
+    assert!(store.remove(&2).is_some());
+    assert!(store.restore_by_fingerprint(s1.fingerprint()).is_none());
+    assert!(verify_consistency(store.identities(), store.fingerprints.keys()).is_consistent());
+
+    let identities = [(7, RatchetStates::new(s3.clone(), Some(s2.clone())))];
+    let report = verify_consistency(
+        identities.iter().map(|(k, s)| (*k, s)),
+        [s3.fingerprint(), s1.fingerprint()],
+    );
+    assert_eq!(report.dangling_fingerprints, vec![*s1.fingerprint()]);
+    assert_eq!(report.missing_fingerprints, vec![(7, *s2.fingerprint())]);
+}
+
+#[test]
+fn test_file_ratchet_store() {
+    use rand_core::RngCore;
+    let path = std::env::temp_dir().join(format!("zssp_ratchet_store_{:x}", rand_core::OsRng.next_u64()));
+    let (s1, s2) = (random_state(1), random_state(2));
+    let empty = RatchetState::empty();
+    let mut store = FileRatchetStore::<[u8; 4]>::open(&path).unwrap();
+    assert!(store
+        .save_ratchet_state(
+            &[1; 4],
+            CompareAndSwap::new(&s1, None, true, &empty, None, false, false)
+        )
+        .unwrap());
+    store.insert([2; 4], RatchetStates::new(s2.clone(), None)).unwrap();
+    drop(store);
+
+    let mut store = FileRatchetStore::<[u8; 4]>::open(&path).unwrap();
+    assert!(store.restore_by_fingerprint(s1.fingerprint()) == Some((s1.clone(), [1; 4])));
+    assert!(store.restore_by_identity(&[2; 4]) == Some(RatchetStates::new(s2.clone(), None)));
+    assert!(store.remove(&[2; 4]).unwrap().is_some());
+    let store = FileRatchetStore::<[u8; 4]>::open(&path).unwrap();
+    assert_eq!(store.identities().count(), 1);
+    assert!(verify_consistency(store.identities(), store.store.fingerprints.keys()).is_consistent());
+
+    let mut data = std::fs::read(&path).unwrap();
+    data.pop();
+    std::fs::write(&path, data).unwrap();
+    let e = FileRatchetStore::<[u8; 4]>::open(&path).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,135 @@
+//! Retransmission of data sent with `Context::send_reliable` until the application acknowledges it.
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use crate::application::{CryptoLayer, SendTo};
+use crate::proto::MIN_TRANSPORT_MTU;
+use crate::result::SendError;
+use crate::sync::Mutex;
+use crate::zeta::{send_payload, Session};
+use crate::zssp::ContextInner;
+
+/// Data sent with `Context::send_reliable` that has not been acknowledged yet.
+struct PendingSend<C: CryptoLayer> {
+    session: Weak<Session<C>>,
+    seq: u64,
+    data: Arc<[u8]>,
+    interval: i64,
+    /// `None` until the context is next serviced, since sending does not know the current time.
+    next_resend: Option<i64>,
+}
+
+/// The data of every session of a context that is waiting to be acknowledged.
+///
+/// `reliable_sends -> session_queue -> state_machine_lock -> state -> session_map`, since
+/// retransmitting can start a rekey.
+pub(crate) struct ReliableSends<C: CryptoLayer>(Mutex<Vec<PendingSend<C>>>);
+impl<C: CryptoLayer> ReliableSends<C> {
+    pub(crate) fn new() -> Self {
+        Self(Mutex::new(Vec::new()))
+    }
+    pub(crate) fn insert(&self, session: &Arc<Session<C>>, seq: u64, data: &[u8], interval: i64) {
+        self.0.lock().push(PendingSend {
+            session: Arc::downgrade(session),
+            seq,
+            data: data.into(),
+            interval,
+            next_resend: None,
+        });
+    }
+    fn position(pending: &[PendingSend<C>], session: &Weak<Session<C>>, seq: u64) -> Option<usize> {
+        pending.iter().position(|p| p.seq == seq && p.session.ptr_eq(session))
+    }
+    /// Retransmits all data whose timer has run out, and forgets the data of sessions that no
+    /// longer exist or can no longer send it. Returns the time at which to be serviced next.
+    ///
+    /// The data is sent after the lock is released, so `send_to` and the sender it returns are
+    /// free to call `ReliableSendHandle::acknowledge`.
+    pub(crate) fn service(&self, ctx: &Arc<ContextInner<C>>, current_time: i64, send_to: &mut impl SendTo<C>) -> i64 {
+        let mut next_service_time = i64::MAX;
+        let mut due = Vec::new();
+        self.0.lock().retain_mut(|p| {
+            if p.session.strong_count() == 0 {
+                return false;
+            }
+            let mut next_resend = *p.next_resend.get_or_insert(current_time.saturating_add(p.interval));
+            if next_resend <= current_time {
+                due.push((p.session.clone(), p.seq, p.data.clone()));
+                next_resend = current_time.saturating_add(p.interval);
+                p.next_resend = Some(next_resend);
+            }
+            next_service_time = next_service_time.min(next_resend);
+            true
+        });
+        let mut buffer = Vec::new();
+        for (weak, seq, data) in due {
+            let Some(session) = weak.upgrade() else {
+                continue;
+            };
+            let Some((sender, mut mtu)) = send_to.init_send(&session) else {
+                continue;
+            };
+            if mtu == 0 {
+                mtu = session.mtu_hint();
+            }
+            buffer.resize(mtu.max(MIN_TRANSPORT_MTU), 0);
+            match send_payload(ctx, &session, &[], &seq.to_le_bytes(), &data, sender, &mut buffer) {
+                // The data is retransmitted again once the rekey this started has completed.
+                Ok(_) | Err(SendError::RekeyUrgentlyNeeded) => {}
+                Err(_) => {
+                    let mut pending = self.0.lock();
+                    if let Some(i) = Self::position(&pending, &weak, seq) {
+                        pending.swap_remove(i);
+                    }
+                }
+            }
+        }
+        next_service_time
+    }
+}
+
+/// A handle to data sent with `Context::send_reliable`, used to stop it from being retransmitted.
+///
+/// Dropping the handle does not stop retransmission.
+pub struct ReliableSendHandle<C: CryptoLayer> {
+    ctx: Weak<ContextInner<C>>,
+    session: Weak<Session<C>>,
+    seq: u64,
+}
+impl<C: CryptoLayer> ReliableSendHandle<C> {
+    pub(crate) fn new(ctx: &Arc<ContextInner<C>>, session: &Arc<Session<C>>, seq: u64) -> Self {
+        Self {
+            ctx: Arc::downgrade(ctx),
+            session: Arc::downgrade(session),
+            seq,
+        }
+    }
+    /// The sequence number the data was sent with, see `Session::send_ordered`.
+    pub fn seq_no(&self) -> u64 {
+        self.seq
+    }
+    /// Stop retransmitting the data that was sent reliably over the same session with sequence
+    /// number `seq_no`, usually because the remote peer has responded to it.
+    ///
+    /// Returns whether that data was still being retransmitted.
+    pub fn acknowledge(&self, seq_no: u64) -> bool {
+        let Some(ctx) = self.ctx.upgrade() else {
+            return false;
+        };
+        let mut pending = ctx.reliable_sends.0.lock();
+        if let Some(i) = ReliableSends::position(&pending, &self.session, seq_no) {
+            pending.swap_remove(i);
+            true
+        } else {
+            false
+        }
+    }
+    /// Whether the data of this handle is still being retransmitted.
+    pub fn is_pending(&self) -> bool {
+        let Some(ctx) = self.ctx.upgrade() else {
+            return false;
+        };
+        let pending = ctx.reliable_sends.0.lock();
+        ReliableSends::position(&pending, &self.session, self.seq).is_some()
+    }
+}
@@ -1,6 +1,8 @@
-use std::error::Error;
-use std::fmt;
-use std::sync::Arc;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::error::Error;
+use core::fmt;
 
 use crate::application::CryptoLayer;
 use crate::zeta::Session;
@@ -9,16 +11,80 @@ use crate::zeta::Session;
 /// Depending on the error type trying again may not work.
 #[derive(Debug)]
 pub enum OpenError {
-    /// The given identity string was larger than `IDENTITY_MAX_SIZE`, a.k.a. 4096 bytes.
+    /// The given identity string was larger than both `IDENTITY_MAX_SIZE`, a.k.a. 4096 bytes, and
+    /// `Settings::max_streamed_identity_size`.
     IdentityTooLarge,
 
-    /// An error was returned by one of the ratchet state `ApplicationLayer` callbacks.
+    /// An invalid mtu was supplied to the function. The MTU can be no smaller than 128 bytes.
+    MtuTooSmall,
+
+    /// The given remote static key is our own static key. A session cannot be opened with ourselves.
+    InvalidRemoteKey,
+
+    /// An error was returned by `ApplicationLayer::restore_by_identity` while reading the
+    /// ratchet states of the remote peer.
     /// The session could not be openned as a result.
-    StorageError(std::io::Error),
+    StorageReadError(crate::io::Error),
+
+    /// The ratchet states of the remote peer record that a later protocol version than ours has
+    /// already been used with them, and `ApplicationLayer::allow_version_downgrade` returned false.
+    VersionDowngrade,
+
+    /// No unused local key id could be found for the session. This only happens when the context
+    /// already holds billions of sessions.
+    KeyIdsExhausted,
+}
+/// An error that can occur when attempting to export the state of a session with
+/// `Context::export_session_state`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ExportError {
+    /// The session has already expired, there is nothing left to export.
+    SessionExpired,
+
+    /// The initial key exchange of the session has not completed yet.
+    SessionNotEstablished,
+
+    /// The session is in the middle of rekeying. The ephemeral state of a key exchange is not
+    /// exported, so the caller should try again once the rekey has completed.
+    KeyExchangeInProgress,
+
+    /// `ExportMode::Migrate` was requested from inside a `Sender` or `PayloadSink` callback, where
+    /// the sends and receives in progress on this thread cannot be waited out.
+    InCallback,
+}
+/// An error that can occur when attempting to import the state of a session with
+/// `Context::import_session_state`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ImportError {
+    /// The blob was produced by a version of ZSSP with a different export format.
+    /// Contains the version byte found in the blob.
+    UnsupportedVersion(u8),
+
+    /// The blob was malformed, or it failed authentication under the given master key.
+    InvalidBlob,
+
+    /// The session was exported by a context with a different static key.
+    WrongStaticKey,
+
+    /// The session was exported by a context with incompatible settings, such as a different
+    /// `counter_window_max_out_of_order`.
+    Incompatible,
+
+    /// One of the key ids of the session is already in use by another session of this context.
+    KeyIdCollision,
+
+    /// The session is with a different remote peer, or its ratchet states are not the ones that
+    /// were saved for that peer, which means the blob is older than the saved ratchet states.
+    RatchetMismatch,
+
+    /// This context has already imported the blob. Each blob may only be imported once, since
+    /// the imported sessions would send with the same counters.
+    AlreadyImported,
 }
 /// An error that can occur when attempting to send data over a session.
 /// Depending on the error type trying again may not work.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SendError {
     /// An invalid mtu was supplied to the function. The MTU can be no smaller than 128 bytes.
     MtuTooSmall,
@@ -38,6 +104,23 @@ pub enum SendError {
 
     /// Data object is too large to send, even with fragmentation.
     DataTooLarge,
+
+    /// The session is in the middle of rekeying or rotating its key id, so the requested
+    /// operation cannot be started right now. It can be retried once the exchange has completed.
+    KeyExchangeInProgress,
+
+    /// The current key has been used so many times that it must be rekeyed before it can send
+    /// any more data, so the data was not sent. This also happens once a session has used half
+    /// of the counters reserved by its last checkpoint, see `ExportMode::Checkpoint`.
+    /// Unless one is already in progress, a rekey is started the next time the context is
+    /// serviced, which should be right away, as if `Context::send` returned `Ok(true)`.
+    /// Sending can be retried once the rekey has completed.
+    ///
+    /// If the rekey never completes the session will eventually expire.
+    RekeyUrgentlyNeeded,
+
+    /// No unused local key id could be found to rotate to, see `OpenError::KeyIdsExhausted`.
+    KeyIdsExhausted,
 }
 
 /// The contained session has just expired.
@@ -52,7 +135,10 @@ pub struct ExpiredError<C: CryptoLayer>(pub Arc<Session<C>>);
 ///
 /// An unauthenticated attacker can intentionally trigger any of these, so it is best to
 /// treat these as raw user input that needs to be sanitize.
+///
+/// Every variant has a code that is stable across releases, see `FaultType::code`.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FaultType {
     /// The received packet was addressed to an unrecognized local session.
     UnknownLocalKeyId,
@@ -78,7 +164,9 @@ pub enum FaultType {
 /// Because an unauthenticated remote peer can force these to occur with specific
 /// contained information, it is recommended in production to either drop these
 /// immediately, or log them safely to a local output stream and then drop them.
-pub struct ByzantineFault<C: CryptoLayer> {
+///
+/// `A` is the type of the remote address that was passed to receive.
+pub struct ByzantineFault<C: CryptoLayer, A = ()> {
     /// The session associated with this fault, if there was one.
     ///
     /// The sender specified this session within their packet, but they were not authenticated,
@@ -91,6 +179,13 @@ pub struct ByzantineFault<C: CryptoLayer> {
     ///
     /// If this returns true then it is guaranteed that the `session` field is occupied.
     pub caused_expiration: bool,
+    /// This field is true if the offending packet passed authentication under the keys of
+    /// `session` before the fault occurred, for example an authentic packet that was replayed.
+    ///
+    /// Unauthenticated faults can be caused by anyone who has observed the key id of a session,
+    /// since key ids are sent in the clear. Authenticated faults can only be caused by the remote
+    /// peer, or by replaying packets that the remote peer sent.
+    pub authenticated: bool,
     /// The type of fault that has occurred. Be cautious if you choose to read this
     /// value, as an attacker has control over it.
     pub error: FaultType,
@@ -107,6 +202,12 @@ pub struct ByzantineFault<C: CryptoLayer> {
     /// ZSSP also considers collisions of what are supposed to be uniform random
     /// numbers to be unnatural.
     pub unnatural: bool,
+    /// The address the offending packet was received from, as passed to `Context::receive`.
+    ///
+    /// This is always occupied when the fault was returned by one of the receive functions.
+    /// Keep in mind that source addresses are trivially spoofed on many transports, so blocking
+    /// addresses based on these faults can be abused to deny service to other peers.
+    pub remote_address: Option<A>,
     /// The file of this implementation of ZSSP from which this error was generated.
     #[cfg(feature = "debug")]
     pub(crate) file: &'static str,
@@ -123,7 +224,10 @@ pub struct ByzantineFault<C: CryptoLayer> {
 /// Keep in mind that when one of these occurs it inherently means that the packet from the remote
 /// peer has either not been authenticated or has failed authentication. As such, an attacker could
 /// trigger any of these. These errors should only be used for debugging and tracing.
-pub enum ReceiveError<C: CryptoLayer> {
+///
+/// `E` is the error type of the `PayloadSink` and `A` is the type of the remote address that were
+/// passed to receive.
+pub enum ReceiveError<C: CryptoLayer, E = crate::io::Error, A = ()> {
     /// A type of fault that can occur because a remote peer sent us a bad packet.
     /// Such packets will be ignored by ZSSP but a user of ZSSP might want to log
     /// them for debugging or tracing.
@@ -131,7 +235,7 @@ pub enum ReceiveError<C: CryptoLayer> {
     /// Because an unauthenticated remote peer can force these to occur with specific
     /// contained information, it is recommended in production to either drop these
     /// immediately, or log them safely to a local output stream and then drop them.
-    ByzantineFault(ByzantineFault<C>),
+    ByzantineFault(ByzantineFault<C, A>),
 
     /// Rekeying failed and session secret has reached its hard usage count limit.
     /// The associated session will no longer function and has to be dropped.
@@ -139,15 +243,39 @@ pub enum ReceiveError<C: CryptoLayer> {
 
     /// Either the `ApplicationLayer::incoming_session` or `ApplicationLayer::check_accept_session`
     /// callback rejected the remote peer's attempt to establish a new session.
-    Rejected,
+    ///
+    /// This is also returned if we do not support the protocol version of the remote peer, see
+    /// `ReceiveOk::VersionUnsupported`. If we were Alice, the session we opened is expired.
+    /// As Bob it is also returned when Alice streams an identity larger than
+    /// `Settings::max_streamed_identity_size`.
+    ///
+    /// Contains the address the attempt was received from, as passed to `Context::receive`.
+    Rejected(Option<A>),
 
-    /// An error was returned by one of the ratchet state `ApplicationLayer` callbacks.
+    /// No unused local key id could be found for a new session as Bob, or for a rekey requested by
+    /// the remote peer, see `OpenError::KeyIdsExhausted`. The received packet was dropped.
+    ///
+    /// A rekey is deferred, so the remote peer retries it later just as if
+    /// `ApplicationLayer::incoming_rekey` had returned `RekeyAction::Defer`.
+    KeyIdsExhausted,
+
+    /// An error was returned by `ApplicationLayer::restore_by_fingerprint` or
+    /// `ApplicationLayer::restore_by_identity` while reading a ratchet state.
     /// The received packet was dropped.
-    StorageError(std::io::Error),
+    ///
+    /// No session state was mutated, so the application may choose to retry, or to fall back to
+    /// an empty ratchet state if its policy allows downgrades.
+    StorageReadError(crate::io::Error),
+
+    /// An error was returned by `ApplicationLayer::save_ratchet_state` while persisting a new
+    /// ratchet state. The received packet was dropped and the state transition was aborted.
+    ///
+    /// The remote peer will resend the packet, so the save will be attempted again.
+    StorageWriteError(crate::io::Error),
 
-    /// An error was returned by the `output_buffer` passed to receive.
+    /// An error was returned by the `PayloadSink` passed to receive.
     /// The received packet was dropped.
-    WriteError(std::io::Error, Arc<Session<C>>),
+    WriteError(E, Arc<Session<C>>),
 }
 
 macro_rules! fault {
@@ -161,12 +289,21 @@ macro_rules! fault {
             unnatural: $unnatural,
             session: None,
             caused_expiration: false,
+            authenticated: false,
+            remote_address: None,
         })
     };
     ($name:expr, $unnatural:ident, $session:ident) => {
-        fault!($name, $unnatural, $session, false)
+        fault!(@session $name, $unnatural, $session, false, false)
     };
+    ($name:expr, $unnatural:ident, $session:ident, authenticated) => {
+        fault!(@session $name, $unnatural, $session, false, true)
+    };
+    // Only authenticated packets are trusted enough to expire a session.
     ($name:expr, $unnatural:ident, $session:ident, $e:ident) => {
+        fault!(@session $name, $unnatural, $session, $e, $e)
+    };
+    (@session $name:expr, $unnatural:ident, $session:ident, $e:ident, $auth:ident) => {
         ReceiveError::ByzantineFault(crate::result::ByzantineFault {
             #[cfg(feature = "debug")]
             file: file!(),
@@ -176,6 +313,8 @@ macro_rules! fault {
             unnatural: $unnatural,
             session: Some($session.clone()),
             caused_expiration: $e,
+            authenticated: $auth,
+            remote_address: None,
         })
     };
 }
@@ -190,15 +329,34 @@ pub enum ReceiveOk<C: CryptoLayer> {
     Unassociated,
     /// The received packet was authentic and belongs to this specific session.
     Associated(Arc<Session<C>>, SessionEvent),
+    /// When Alice calls `Context::open`, a session will be created, but Bob will not yet have
+    /// received this session. They will have to successfully complete a handshake first.
+    ///
+    /// Alice will receive this return value when the received packet confirms both parties
+    /// have completed the initial handshake and now have a shared session with each other.
+    /// If according to the upper protocol, Bob is the first party to send data, it is possible for
+    /// Alice to start receiving data from Bob before this value is returned.
+    ///
+    /// This return value can only occur once per session, only for session objects that were
+    /// created with `Context::open`.
+    Established(Arc<Session<C>>),
     /// The received packet was an incomplete fragment of a larger packet.
     ///
     /// ***The authenticity of this fragment cannot be fully known yet.***
     /// This return value should only be used for debugging and tracing purposes.
     Fragment(Arc<Session<C>>),
+    /// Bob does not support the protocol version of the handshake we started with this session.
+    /// Contains the lowest and highest protocol version Bob does support.
+    ///
+    /// ***This packet is not authenticated.*** It only proves its sender saw our handshake hello,
+    /// so the session is left as is and keeps retrying the handshake until it times out.
+    /// The application may drop the session early if it trusts the path to Bob.
+    VersionUnsupported(Arc<Session<C>>, u8, u8),
 }
 /// Something that can occur to an associated session when a packet is received successfully,
 /// including receiving a payload of decrypted, authenticated data.
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SessionEvent {
     /// The received packet was valid, and it contained the necessary keys to fully establish a new
     /// session with Alice, the handshake initiator.
@@ -216,27 +374,22 @@ pub enum SessionEvent {
     /// If the session Arc returned is dropped, the session with this peer will be immediately
     /// terminated. Save the session Arc to some long lived datastructure to keep it alive.
     NewDowngradedSession,
-    /// When Alice calls `Context::open`, a session will be created, but Bob will not yet have
-    /// received this session. They will have to successfully complete a handshake first.
-    ///
-    /// Alice will receive this return value when the received packet confirms both parties
-    /// have completed the initial handshake and now have a shared session with each other.
-    /// If according to the upper protocol, Bob is the first party to send data, it is possible for
-    /// Alice to start receiving data from Bob before this value is returned.
-    ///
-    /// This return value can only occur once per session, only for session objects that were
-    /// created with `Context::open`.
-    Established,
     /// Bob explicitly refused to establish a session with Alice.
     /// The application should immediately drop this session as Bob will not allow us to connect.
     ///
     /// This return value cannot occur after a session is fully established.
     Rejected,
+    /// The remote peer tried to rekey the session and `ApplicationLayer::incoming_rekey` returned
+    /// `RekeyAction::Reject`, so the session was expired.
+    /// The application should immediately drop this session.
+    RekeyRejected,
     /// The received packet was valid and a data payload was decoded and authenticated.
     ///
     /// Keep in mind that due to out-of-order transport, Alice can receive data payloads before
-    /// their session is "established", and the `Established` event is returned.
+    /// their session is "established", and `ReceiveOk::Established` is returned.
     /// Users are free to either treat such payloads as they would any other, or drop them.
+    ///
+    /// The payload may be empty if the remote peer sent it with `Session::send_keepalive`.
     Data,
     /// The received packet was some authentic protocol control packet. No action needs to be taken.
     Control,
@@ -247,16 +400,123 @@ pub enum SessionEvent {
     DowngradedRatchetKey,
 }
 
+/// An owned, thread-safe summary of an `OpenError`, suitable for logging or for sending
+/// to a metrics pipeline.
+///
+/// `std::io::Error` cannot be cloned or serialized, so storage errors are reduced to their message.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OpenErrorReport {
+    /// See `OpenError::IdentityTooLarge`.
+    IdentityTooLarge,
+    /// See `OpenError::MtuTooSmall`.
+    MtuTooSmall,
+    /// See `OpenError::InvalidRemoteKey`.
+    InvalidRemoteKey,
+    /// See `OpenError::StorageReadError`. Contains the message of the original error.
+    StorageReadError(String),
+    /// See `OpenError::VersionDowngrade`.
+    VersionDowngrade,
+    /// See `OpenError::KeyIdsExhausted`.
+    KeyIdsExhausted,
+}
+/// An owned, thread-safe summary of a `ByzantineFault`, suitable for logging or for sending
+/// to a metrics pipeline.
+///
+/// Instead of the session itself this holds the local key id of the session, if there was one.
+/// Remember that the contents of a fault are controlled by an unauthenticated remote peer.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FaultReport {
+    /// See `ByzantineFault::error`.
+    pub error: FaultType,
+    /// See `ByzantineFault::unnatural`.
+    pub unnatural: bool,
+    /// See `ByzantineFault::caused_expiration`.
+    pub caused_expiration: bool,
+    /// See `ByzantineFault::authenticated`.
+    pub authenticated: bool,
+    /// The file from which the fault was generated.
+    /// This is only known if ZSSP was compiled with the `debug` feature.
+    pub file: Option<String>,
+    /// The line number from which the fault was generated.
+    /// This is only known if ZSSP was compiled with the `debug` feature.
+    pub line: Option<u32>,
+    /// The key id we use to identify the session associated with this fault.
+    /// This is `None` if there was no such session or it was not established.
+    pub kid: Option<u32>,
+    /// The `Debug` representation of `ByzantineFault::remote_address`.
+    pub remote_address: Option<String>,
+}
+/// An owned, thread-safe summary of a `ReceiveError`, suitable for logging or for sending
+/// to a metrics pipeline.
+///
+/// Sessions are replaced by their local key id, and `std::io::Error`s by their message.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ReceiveErrorReport {
+    /// See `ReceiveError::ByzantineFault`.
+    ByzantineFault(FaultReport),
+    /// See `ReceiveError::MaxKeyLifetimeExceeded`.
+    MaxKeyLifetimeExceeded {
+        /// The local key id of the session, if it still had one.
+        kid: Option<u32>,
+    },
+    /// See `ReceiveError::Rejected`. Contains the `Debug` representation of the remote address.
+    Rejected(Option<String>),
+    /// See `ReceiveError::KeyIdsExhausted`.
+    KeyIdsExhausted,
+    /// See `ReceiveError::StorageReadError`. Contains the message of the original error.
+    StorageReadError(String),
+    /// See `ReceiveError::StorageWriteError`. Contains the message of the original error.
+    StorageWriteError(String),
+    /// See `ReceiveError::WriteError`. Contains the message of the original error
+    /// and the local key id of the session, if it had one.
+    WriteError(String, Option<u32>),
+}
+
 impl fmt::Display for OpenError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             OpenError::IdentityTooLarge => f.write_str("identity too large"),
-            OpenError::StorageError(e) => e.fmt(f),
+            OpenError::MtuTooSmall => f.write_str("mtu too small"),
+            OpenError::InvalidRemoteKey => f.write_str("invalid remote static key"),
+            OpenError::StorageReadError(e) => e.fmt(f),
+            OpenError::VersionDowngrade => f.write_str("protocol version downgrade"),
+            OpenError::KeyIdsExhausted => f.write_str("no unused key id"),
         }
     }
 }
 impl Error for OpenError {}
 
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            ExportError::SessionExpired => "session has expired",
+            ExportError::SessionNotEstablished => "session not established",
+            ExportError::KeyExchangeInProgress => "key exchange in progress",
+            ExportError::InCallback => "cannot migrate from inside a callback",
+        };
+        f.write_str(str)
+    }
+}
+impl Error for ExportError {}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::UnsupportedVersion(v) => write!(f, "unsupported session export version {}", v),
+            ImportError::InvalidBlob => f.write_str("invalid session export"),
+            ImportError::WrongStaticKey => f.write_str("session was exported with a different static key"),
+            ImportError::Incompatible => f.write_str("session was exported with incompatible settings"),
+            ImportError::KeyIdCollision => f.write_str("key id already in use"),
+            ImportError::RatchetMismatch => f.write_str("session export does not match the saved ratchet states"),
+            ImportError::AlreadyImported => f.write_str("session export was already imported"),
+        }
+    }
+}
+impl Error for ImportError {}
+
 impl fmt::Display for SendError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let str = match self {
@@ -264,16 +524,16 @@ impl fmt::Display for SendError {
             SendError::SessionExpired => "session has expired",
             SendError::SessionNotEstablished => "session not established",
             SendError::DataTooLarge => "data too large",
+            SendError::KeyExchangeInProgress => "key exchange in progress",
+            SendError::RekeyUrgentlyNeeded => "rekey urgently needed",
+            SendError::KeyIdsExhausted => "no unused key id",
         };
         f.write_str(str)
     }
 }
 impl Error for SendError {}
 
-impl<C: CryptoLayer> fmt::Debug for ExpiredError<C>
-where
-    Session<C>: fmt::Debug,
-{
+impl<C: CryptoLayer> fmt::Debug for ExpiredError<C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("ExpiredError").field(&self.0).finish()
     }
@@ -283,7 +543,7 @@ impl<C: CryptoLayer> fmt::Display for ExpiredError<C> {
         write!(f, "session expired")
     }
 }
-impl<C: CryptoLayer> Error for ExpiredError<C> where Session<C>: fmt::Debug {}
+impl<C: CryptoLayer> Error for ExpiredError<C> {}
 
 impl fmt::Display for FaultType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -298,10 +558,41 @@ impl fmt::Display for FaultType {
     }
 }
 impl Error for FaultType {}
+impl FaultType {
+    /// A number that identifies this type of fault.
+    ///
+    /// Unlike the names of variants, codes are stable across releases. Every variant is explicitly
+    /// assigned a code when it is added, and the code of a variant that is removed is never reused.
+    /// * 1 - `UnknownLocalKeyId`
+    /// * 2 - `InvalidPacket`
+    /// * 3 - `FailedAuth`
+    /// * 4 - `ExpiredCounter`
+    /// * 5 - `OutOfSequence`
+    pub fn code(&self) -> u16 {
+        match self {
+            FaultType::UnknownLocalKeyId => 1,
+            FaultType::InvalidPacket => 2,
+            FaultType::FailedAuth => 3,
+            FaultType::ExpiredCounter => 4,
+            FaultType::OutOfSequence => 5,
+        }
+    }
+    /// The type of fault with the given code, or `None` if no type has this code.
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1 => Some(FaultType::UnknownLocalKeyId),
+            2 => Some(FaultType::InvalidPacket),
+            3 => Some(FaultType::FailedAuth),
+            4 => Some(FaultType::ExpiredCounter),
+            5 => Some(FaultType::OutOfSequence),
+            _ => None,
+        }
+    }
+}
 
 // I don't like getter methods but in this case they are the only way to implement
 // conditionally compiled struct fields without the feature flag causing breaking changes.
-impl<C: CryptoLayer> ByzantineFault<C> {
+impl<C: CryptoLayer, A> ByzantineFault<C, A> {
     /// The file of this implementation of ZSSP from which this error was generated.
     #[cfg(feature = "debug")]
     pub fn file(&self) -> &'static str {
@@ -316,16 +607,84 @@ impl<C: CryptoLayer> ByzantineFault<C> {
         self.line
     }
 }
-impl<C: CryptoLayer> fmt::Debug for ByzantineFault<C>
-where
-    Session<C>: fmt::Debug,
-{
+impl<C: CryptoLayer, A> ByzantineFault<C, A> {
+    /// Formats this fault as one line of space separated `key=value` pairs, for log collectors
+    /// such as SIEMs. The keys are `fault`, `unnatural`, `session`, `caused_expiration`,
+    /// `authenticated`, `file` and `line` in that order, for example
+    /// `fault=FailedAuth unnatural=true session=0xdeadbeef caused_expiration=false ...`.
+    ///
+    /// `session` is the local key id of the session in hex, or `none` if there is no session or it
+    /// no longer has a key id. `file` and `line` are only present with the `debug` feature.
+    /// Like the rest of this fault, every value except `file` and `line` can be chosen by an
+    /// attacker, but none of them can contain spaces or quotes.
+    pub fn to_log_string(&self) -> String {
+        let session = match self.session.as_ref().and_then(|s| local_kid(s)) {
+            Some(kid) => format!("{:#010x}", kid),
+            None => "none".to_string(),
+        };
+        let line = format!(
+            "fault={:?} unnatural={} session={} caused_expiration={} authenticated={}",
+            self.error, self.unnatural, session, self.caused_expiration, self.authenticated
+        );
+        #[cfg(feature = "debug")]
+        let line = format!("{} file={} line={}", line, file_name(self.file), self.line);
+        line
+    }
+    /// Formats the same fields as `to_log_string` as a JSON object, in which `session` is a
+    /// string, or `null` instead of `none`.
+    pub fn to_json(&self) -> String {
+        let session = match self.session.as_ref().and_then(|s| local_kid(s)) {
+            Some(kid) => format!("\"{:#010x}\"", kid),
+            None => "null".to_string(),
+        };
+        let json = format!(
+            "{{\"fault\":\"{:?}\",\"unnatural\":{},\"session\":{},\"caused_expiration\":{},\"authenticated\":{}",
+            self.error, self.unnatural, session, self.caused_expiration, self.authenticated
+        );
+        #[cfg(feature = "debug")]
+        let json = format!("{},\"file\":\"{}\",\"line\":{}", json, file_name(self.file), self.line);
+        json + "}"
+    }
+}
+/// The name of a source file without its directories, which would need escaping on Windows.
+#[cfg(feature = "debug")]
+fn file_name(file: &'static str) -> &'static str {
+    file.rsplit(['/', '\\']).next().unwrap_or(file)
+}
+impl<C: CryptoLayer, E> ReceiveError<C, E> {
+    /// Attach the address the packet that caused this error was received from.
+    pub(crate) fn with_remote_address<A: Clone>(self, remote_address: &A) -> ReceiveError<C, E, A> {
+        match self {
+            ReceiveError::ByzantineFault(e) => ReceiveError::ByzantineFault(ByzantineFault {
+                session: e.session,
+                caused_expiration: e.caused_expiration,
+                authenticated: e.authenticated,
+                error: e.error,
+                unnatural: e.unnatural,
+                remote_address: Some(remote_address.clone()),
+                #[cfg(feature = "debug")]
+                file: e.file,
+                #[cfg(feature = "debug")]
+                line: e.line,
+            }),
+            ReceiveError::MaxKeyLifetimeExceeded(s) => ReceiveError::MaxKeyLifetimeExceeded(s),
+            ReceiveError::Rejected(_) => ReceiveError::Rejected(Some(remote_address.clone())),
+            ReceiveError::KeyIdsExhausted => ReceiveError::KeyIdsExhausted,
+            ReceiveError::StorageReadError(e) => ReceiveError::StorageReadError(e),
+            ReceiveError::StorageWriteError(e) => ReceiveError::StorageWriteError(e),
+            ReceiveError::WriteError(e, s) => ReceiveError::WriteError(e, s),
+        }
+    }
+}
+impl<C: CryptoLayer, A: fmt::Debug> fmt::Debug for ByzantineFault<C, A> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut a = f.debug_struct("ByzantineFault");
         a.field("session", &self.session)
             .field("error", &self.error)
             .field("unnatural", &self.unnatural)
-            .field("caused_expiration", &self.caused_expiration);
+            .field("caused_expiration", &self.caused_expiration)
+            .field("authenticated", &self.authenticated)
+            .field("remote_address", &self.remote_address);
         #[cfg(feature = "debug")]
         {
             a.field("file", &self.file).field("line", &self.line);
@@ -333,41 +692,279 @@ where
         a.finish()
     }
 }
-impl<C: CryptoLayer> fmt::Display for ByzantineFault<C> {
+/// The fault code is always included so logs can be searched for it without the `debug` feature.
+impl<C: CryptoLayer, A> fmt::Display for ByzantineFault<C, A> {
     #[cfg(feature = "debug")]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ({}:{})", self.error, self.file, self.line)
+        write!(f, "{} (fault {}, {}:{})", self.error, self.error.code(), self.file, self.line)
     }
     #[cfg(not(feature = "debug"))]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.error.fmt(f)
+        write!(f, "{} (fault {})", self.error, self.error.code())
     }
 }
-impl<C: CryptoLayer> Error for ByzantineFault<C> where Session<C>: fmt::Debug {}
+impl<C: CryptoLayer, A: fmt::Debug> Error for ByzantineFault<C, A> {}
 
-impl<C: CryptoLayer> fmt::Debug for ReceiveError<C>
-where
-    C::SessionData: fmt::Debug,
-{
+impl<C: CryptoLayer, E: fmt::Debug, A: fmt::Debug> fmt::Debug for ReceiveError<C, E, A> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::ByzantineFault(arg) => f.debug_tuple("ByzantineFault").field(arg).finish(),
             Self::MaxKeyLifetimeExceeded(arg0) => f.debug_tuple("MaxKeyLifetimeExceeded").field(arg0).finish(),
-            Self::Rejected => f.write_str("Rejected"),
-            Self::StorageError(arg0) => f.debug_tuple("StorageError").field(arg0).finish(),
+            Self::Rejected(arg0) => f.debug_tuple("Rejected").field(arg0).finish(),
+            Self::KeyIdsExhausted => write!(f, "KeyIdsExhausted"),
+            Self::StorageReadError(arg0) => f.debug_tuple("StorageReadError").field(arg0).finish(),
+            Self::StorageWriteError(arg0) => f.debug_tuple("StorageWriteError").field(arg0).finish(),
             Self::WriteError(arg0, arg1) => f.debug_tuple("WriteError").field(arg0).field(arg1).finish(),
         }
     }
 }
-impl<C: CryptoLayer> fmt::Display for ReceiveError<C> {
+impl<C: CryptoLayer, E: fmt::Display, A> fmt::Display for ReceiveError<C, E, A> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ReceiveError::ByzantineFault(e) => e.fmt(f),
             ReceiveError::MaxKeyLifetimeExceeded(_) => f.write_str("max key lifetime exceeded"),
-            ReceiveError::Rejected => f.write_str("attempt to establish session rejected"),
-            ReceiveError::StorageError(e) => e.fmt(f),
+            ReceiveError::Rejected(_) => f.write_str("attempt to establish session rejected"),
+            ReceiveError::KeyIdsExhausted => f.write_str("no unused key id"),
+            ReceiveError::StorageReadError(e) => e.fmt(f),
+            ReceiveError::StorageWriteError(e) => e.fmt(f),
             ReceiveError::WriteError(e, _) => e.fmt(f),
         }
     }
 }
-impl<C: CryptoLayer> Error for ReceiveError<C> where C::SessionData: fmt::Debug {}
+impl<C: CryptoLayer, E: fmt::Debug + fmt::Display, A: fmt::Debug> Error for ReceiveError<C, E, A> {}
+
+#[cfg(feature = "std")]
+impl From<SendError> for std::io::Error {
+    fn from(value: SendError) -> Self {
+        use std::io::ErrorKind;
+        let kind = match value {
+            SendError::MtuTooSmall | SendError::DataTooLarge => ErrorKind::InvalidInput,
+            SendError::SessionExpired => ErrorKind::ConnectionAborted,
+            SendError::SessionNotEstablished => ErrorKind::NotConnected,
+            SendError::KeyExchangeInProgress | SendError::RekeyUrgentlyNeeded => ErrorKind::WouldBlock,
+            SendError::KeyIdsExhausted => ErrorKind::OutOfMemory,
+        };
+        std::io::Error::new(kind, value)
+    }
+}
+/// Sessions are not carried over into the resulting `std::io::Error`, since it must be `Send`
+/// and `Sync`. Byzantine faults carry their `FaultType`, and errors returned by the application
+/// are passed through unchanged.
+#[cfg(feature = "std")]
+impl<C: CryptoLayer, A> From<ReceiveError<C, std::io::Error, A>> for std::io::Error {
+    fn from(value: ReceiveError<C, std::io::Error, A>) -> Self {
+        use std::io::ErrorKind;
+        match value {
+            ReceiveError::ByzantineFault(e) => std::io::Error::new(ErrorKind::InvalidData, e.error),
+            ReceiveError::MaxKeyLifetimeExceeded(_) => {
+                std::io::Error::new(ErrorKind::ConnectionReset, "max key lifetime exceeded")
+            }
+            ReceiveError::Rejected(_) => {
+                std::io::Error::new(ErrorKind::PermissionDenied, "attempt to establish session rejected")
+            }
+            ReceiveError::KeyIdsExhausted => std::io::Error::new(ErrorKind::OutOfMemory, "no unused key id"),
+            ReceiveError::StorageReadError(e) => e,
+            ReceiveError::StorageWriteError(e) => e,
+            ReceiveError::WriteError(e, _) => e,
+        }
+    }
+}
+
+impl From<&OpenError> for OpenErrorReport {
+    fn from(value: &OpenError) -> Self {
+        match value {
+            OpenError::IdentityTooLarge => Self::IdentityTooLarge,
+            OpenError::MtuTooSmall => Self::MtuTooSmall,
+            OpenError::InvalidRemoteKey => Self::InvalidRemoteKey,
+            OpenError::StorageReadError(e) => Self::StorageReadError(e.to_string()),
+            OpenError::VersionDowngrade => Self::VersionDowngrade,
+            OpenError::KeyIdsExhausted => Self::KeyIdsExhausted,
+        }
+    }
+}
+impl<C: CryptoLayer, A: fmt::Debug> From<&ByzantineFault<C, A>> for FaultReport {
+    fn from(value: &ByzantineFault<C, A>) -> Self {
+        Self {
+            error: value.error,
+            unnatural: value.unnatural,
+            caused_expiration: value.caused_expiration,
+            authenticated: value.authenticated,
+            #[cfg(feature = "debug")]
+            file: Some(value.file.to_string()),
+            #[cfg(not(feature = "debug"))]
+            file: None,
+            #[cfg(feature = "debug")]
+            line: Some(value.line),
+            #[cfg(not(feature = "debug"))]
+            line: None,
+            kid: value.session.as_ref().and_then(|s| local_kid(s)),
+            remote_address: value.remote_address.as_ref().map(|addr| format!("{:?}", addr)),
+        }
+    }
+}
+impl<C: CryptoLayer, E: fmt::Display, A: fmt::Debug> From<&ReceiveError<C, E, A>> for ReceiveErrorReport {
+    fn from(value: &ReceiveError<C, E, A>) -> Self {
+        match value {
+            ReceiveError::ByzantineFault(e) => Self::ByzantineFault(e.into()),
+            ReceiveError::MaxKeyLifetimeExceeded(s) => Self::MaxKeyLifetimeExceeded { kid: local_kid(s) },
+            ReceiveError::Rejected(addr) => Self::Rejected(addr.as_ref().map(|addr| format!("{:?}", addr))),
+            ReceiveError::KeyIdsExhausted => Self::KeyIdsExhausted,
+            ReceiveError::StorageReadError(e) => Self::StorageReadError(e.to_string()),
+            ReceiveError::StorageWriteError(e) => Self::StorageWriteError(e.to_string()),
+            ReceiveError::WriteError(e, s) => Self::WriteError(e.to_string(), local_kid(s)),
+        }
+    }
+}
+fn local_kid<C: CryptoLayer>(session: &Session<C>) -> Option<u32> {
+    session.local_session_id().map(|kid| kid.get())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + fmt::Debug>(value: T) {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value, "{}", json);
+    }
+    for e in [
+        FaultType::UnknownLocalKeyId,
+        FaultType::InvalidPacket,
+        FaultType::FailedAuth,
+        FaultType::ExpiredCounter,
+        FaultType::OutOfSequence,
+    ] {
+        round_trip(e);
+    }
+    for e in [
+        SendError::MtuTooSmall,
+        SendError::SessionExpired,
+        SendError::SessionNotEstablished,
+        SendError::DataTooLarge,
+        SendError::KeyExchangeInProgress,
+        SendError::RekeyUrgentlyNeeded,
+        SendError::KeyIdsExhausted,
+    ] {
+        round_trip(e);
+    }
+    for e in [
+        SessionEvent::NewSession,
+        SessionEvent::NewDowngradedSession,
+        SessionEvent::Rejected,
+        SessionEvent::RekeyRejected,
+        SessionEvent::Data,
+        SessionEvent::Control,
+        SessionEvent::DowngradedRatchetKey,
+    ] {
+        round_trip(e);
+    }
+    round_trip(OpenErrorReport::from(&OpenError::IdentityTooLarge));
+    round_trip(OpenErrorReport::from(&OpenError::MtuTooSmall));
+    round_trip(OpenErrorReport::from(&OpenError::InvalidRemoteKey));
+    round_trip(OpenErrorReport::from(&OpenError::VersionDowngrade));
+    round_trip(OpenErrorReport::from(&OpenError::KeyIdsExhausted));
+    let report = OpenErrorReport::from(&OpenError::StorageReadError(std::io::Error::other("disk full")));
+    assert_eq!(report, OpenErrorReport::StorageReadError("disk full".to_string()));
+    round_trip(report);
+
+    let fault = FaultReport {
+        error: FaultType::FailedAuth,
+        unnatural: true,
+        caused_expiration: false,
+        authenticated: false,
+        file: Some("zeta.rs".to_string()),
+        line: Some(42),
+        kid: Some(7),
+        remote_address: Some("127.0.0.1:9993".to_string()),
+    };
+    round_trip(fault.clone());
+    round_trip(ReceiveErrorReport::ByzantineFault(fault));
+    round_trip(ReceiveErrorReport::MaxKeyLifetimeExceeded { kid: Some(7) });
+    round_trip(ReceiveErrorReport::MaxKeyLifetimeExceeded { kid: None });
+    round_trip(ReceiveErrorReport::Rejected(Some("127.0.0.1:9993".to_string())));
+    round_trip(ReceiveErrorReport::Rejected(None));
+    round_trip(ReceiveErrorReport::KeyIdsExhausted);
+    round_trip(ReceiveErrorReport::StorageReadError("disk full".to_string()));
+    round_trip(ReceiveErrorReport::StorageWriteError("disk full".to_string()));
+    round_trip(ReceiveErrorReport::WriteError("broken pipe".to_string(), Some(7)));
+}
+
+#[test]
+fn test_io_error_conversion() {
+    use crate::sim::SimCrypto;
+    use std::io::ErrorKind;
+
+    let e = std::io::Error::from(SendError::SessionNotEstablished);
+    assert_eq!(e.kind(), ErrorKind::NotConnected);
+    assert_eq!(e.into_inner().unwrap().downcast_ref(), Some(&SendError::SessionNotEstablished));
+    assert_eq!(std::io::Error::from(SendError::DataTooLarge).kind(), ErrorKind::InvalidInput);
+    assert_eq!(std::io::Error::from(SendError::KeyExchangeInProgress).kind(), ErrorKind::WouldBlock);
+
+    let e = std::io::Error::from(fault!(FaultType::FailedAuth, true) as ReceiveError<SimCrypto>);
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert_eq!(e.into_inner().unwrap().downcast_ref(), Some(&FaultType::FailedAuth));
+    let e = std::io::Error::from(ReceiveError::<SimCrypto>::Rejected(None));
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    let e = std::io::Error::from(ReceiveError::<SimCrypto>::StorageWriteError(std::io::Error::other("disk full")));
+    assert_eq!(e.kind(), ErrorKind::Other);
+    assert_eq!(e.to_string(), "disk full");
+}
+
+#[test]
+fn test_fault_codes() {
+    let types = [
+        FaultType::UnknownLocalKeyId,
+        FaultType::InvalidPacket,
+        FaultType::FailedAuth,
+        FaultType::ExpiredCounter,
+        FaultType::OutOfSequence,
+    ];
+    // Codes must never change once assigned.
+    assert_eq!(types.map(|t| t.code()), [1, 2, 3, 4, 5]);
+    for t in types {
+        assert_eq!(FaultType::from_code(t.code()), Some(t));
+    }
+    assert_eq!(FaultType::from_code(0), None);
+    assert_eq!(FaultType::from_code(6), None);
+}
+
+#[test]
+fn test_fault_log_strings() {
+    use crate::sim::{LinkConfig, Sim, SimCrypto};
+    let sim = Sim::new(1, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let session = sim.alice.session.borrow().clone().unwrap();
+    let kid = session.local_session_id().unwrap().get();
+    let faults: [ReceiveError<SimCrypto>; 2] = [
+        fault!(FaultType::FailedAuth, true, session, true),
+        fault!(FaultType::OutOfSequence, false),
+    ];
+    let [ReceiveError::ByzantineFault(with_session), ReceiveError::ByzantineFault(without_session)] = faults else {
+        unreachable!();
+    };
+    let log = with_session.to_log_string();
+    let json = with_session.to_json();
+    let expected_log = format!(
+        "fault=FailedAuth unnatural=true session={:#010x} caused_expiration=true authenticated=true",
+        kid
+    );
+    let expected_json = format!(
+        "{{\"fault\":\"FailedAuth\",\"unnatural\":true,\"session\":\"{:#010x}\",{}",
+        kid, "\"caused_expiration\":true,\"authenticated\":true"
+    );
+    #[cfg(feature = "debug")]
+    {
+        let line = with_session.line;
+        assert_eq!(log, format!("{} file=result.rs line={}", expected_log, line));
+        let expected_json = format!("{},\"file\":\"result.rs\",\"line\":{}}}", expected_json, line);
+        assert_eq!(json, expected_json);
+    }
+    #[cfg(not(feature = "debug"))]
+    {
+        assert_eq!(log, expected_log);
+        assert_eq!(json, expected_json + "}");
+    }
+    let log = without_session.to_log_string();
+    assert!(log.starts_with("fault=OutOfSequence unnatural=false session=none"));
+    assert!(without_session.to_json().contains("\"session\":null,"));
+}
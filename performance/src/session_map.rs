@@ -0,0 +1,135 @@
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+
+use crate::collections::HashMap;
+use crate::sync::{RwLock, RwLockWriteGuard};
+
+/// The number of shards a `KidMap` is split into. Must be a power of two.
+const SHARD_COUNT: usize = 32;
+
+type Shard<V> = RwLock<HashMap<NonZeroU32, V>>;
+
+/// Each shard gets its own cache line so that threads locking different shards do not contend
+/// on the same line.
+#[repr(align(64))]
+struct AlignedShard<V>(Shard<V>);
+
+/// A map from local key ids to values, split into shards that are locked independently.
+///
+/// The shard owning a key id is selected by its low bits. Local key ids are the output of a
+/// secretly keyed PRP, so they are spread uniformly across all shards.
+///
+/// Shard locks are always the last lock acquired. Only one shard is locked at a time, except by
+/// `KidMap::write_many`, which locks shards in index order.
+pub(crate) struct KidMap<V> {
+    shards: [AlignedShard<V>; SHARD_COUNT],
+}
+
+/// Write locks held on every shard owning one of a set of key ids.
+/// See `KidMap::write_many`.
+pub(crate) struct KidMapWriteGuard<'a, V> {
+    guards: Vec<(usize, RwLockWriteGuard<'a, HashMap<NonZeroU32, V>>)>,
+}
+
+#[inline]
+fn shard_index(kid: NonZeroU32) -> usize {
+    kid.get() as usize & (SHARD_COUNT - 1)
+}
+
+impl<V> KidMap<V> {
+    pub fn new() -> Self {
+        Self {
+            shards: core::array::from_fn(|_| AlignedShard(RwLock::new(HashMap::new()))),
+        }
+    }
+    /// The shard that owns `kid`.
+    /// Any lookup, insertion or removal of `kid` must go through this shard.
+    #[inline]
+    pub fn shard(&self, kid: NonZeroU32) -> &Shard<V> {
+        &self.shards[shard_index(kid)].0
+    }
+    pub fn remove(&self, kid: &NonZeroU32) -> Option<V> {
+        self.shard(*kid).write().remove(kid)
+    }
+    /// Write lock every shard that owns one of `kids`, so they can be checked and inserted
+    /// atomically. Shards are locked in index order so two callers cannot deadlock.
+    pub fn write_many<'a>(&self, kids: impl IntoIterator<Item = &'a NonZeroU32>) -> KidMapWriteGuard<'_, V> {
+        let mut indices: Vec<usize> = kids.into_iter().map(|kid| shard_index(*kid)).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        KidMapWriteGuard {
+            guards: indices.into_iter().map(|i| (i, self.shards[i].0.write())).collect(),
+        }
+    }
+    /// A copy of every value, taken one shard at a time, so the result is not a consistent
+    /// snapshot of the whole map.
+    pub fn values(&self) -> Vec<V>
+    where
+        V: Clone,
+    {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.0.read().values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+    /// The total number of entries across all shards.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.0.read().len()).sum()
+    }
+}
+
+impl<'a, V> KidMapWriteGuard<'a, V> {
+    fn shard(&mut self, kid: NonZeroU32) -> &mut HashMap<NonZeroU32, V> {
+        let i = shard_index(kid);
+        let (_, guard) = self
+            .guards
+            .iter_mut()
+            .find(|(j, _)| *j == i)
+            .expect("key id was not passed to KidMap::write_many");
+        guard
+    }
+    /// Panics if `kid` was not one of the key ids this guard was created with.
+    pub fn contains_key(&mut self, kid: &NonZeroU32) -> bool {
+        self.shard(*kid).contains_key(kid)
+    }
+    /// Panics if `kid` was not one of the key ids this guard was created with.
+    pub fn insert(&mut self, kid: NonZeroU32, value: V) -> Option<V> {
+        self.shard(kid).insert(kid, value)
+    }
+}
+
+#[test]
+fn test_kid_map_concurrent() {
+    use std::sync::Arc;
+
+    let map = Arc::new(KidMap::<u32>::new());
+    let threads: Vec<_> = (0..8u32)
+        .map(|t| {
+            let map = map.clone();
+            std::thread::spawn(move || {
+                for i in 1..=1000u32 {
+                    let kid = NonZeroU32::new(t * 1000 + i).unwrap();
+                    assert_eq!(map.shard(kid).write().insert(kid, i), None);
+                    assert_eq!(map.shard(kid).read().get(&kid), Some(&i));
+                    if i % 2 == 0 {
+                        assert_eq!(map.remove(&kid), Some(i));
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(map.len(), 8 * 500);
+
+    let kids = [1, 2, 33, 8001].map(|kid| NonZeroU32::new(kid).unwrap());
+    let mut guard = map.write_many(&kids);
+    assert!(guard.contains_key(&kids[0]));
+    assert!(!guard.contains_key(&kids[1]));
+    assert!(!guard.contains_key(&kids[3]));
+    assert_eq!(guard.insert(kids[3], 7), None);
+    drop(guard);
+    assert_eq!(map.remove(&kids[3]), Some(7));
+}
@@ -0,0 +1,2647 @@
+//! A deterministic simulation of two contexts talking over lossy links, for tests.
+//!
+//! Everything runs on the calling thread against a virtual clock, and every random decision,
+//! including those made by the contexts, comes from a seeded RNG. A failing scenario can be
+//! replayed exactly by running it again with the same seed.
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Weak};
+use std::vec::Vec;
+
+use rand_core::{CryptoRng, RngCore};
+
+use crate::application::*;
+use crate::crypto::{Aes256Enc, P384KeyPair, P384PublicKey, AES_256_BLOCK_SIZE};
+use crate::crypto_impl::*;
+use crate::proto::{KID_SIZE, PACKET_TYPE_HANDSHAKE_HELLO};
+use crate::ratchet_storage::MemoryRatchetStore;
+use crate::result::{ExpiredError, FaultType, ReceiveError, ReceiveOk, SessionEvent};
+use crate::sync::Mutex;
+use crate::{Context, ContextStats, ReceiveOptions, Session};
+
+pub(crate) const MTU: usize = 1500;
+
+/// A splitmix64 generator. It is predictable, so it must never be used outside of tests.
+pub(crate) struct SeededRng(u64);
+impl SeededRng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+    /// Returns true with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < p
+    }
+}
+impl RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+impl CryptoRng for SeededRng {}
+
+/// Declares a `CryptoLayer` with the same cryptography as `SimCrypto` and the associated
+/// constants given in braces, for tests of components that depend on `Settings`.
+macro_rules! sim_crypto {
+    ($name:ident { $($consts:tt)* }) => {
+        pub(crate) struct $name;
+        impl $crate::application::CryptoLayer for $name {
+            $($consts)*
+            type Rng = $crate::sim::SeededRng;
+            type PrpEnc = $crate::crypto_impl::OpenSSLAes256Enc;
+            type PrpDec = $crate::crypto_impl::OpenSSLAes256Dec;
+            type Aead = $crate::crypto_impl::OpenSSLAesGcm;
+            #[cfg(not(feature = "gcm-siv"))]
+            type AeadPool = $crate::crypto_impl::OpenSSLAesGcmPool;
+            #[cfg(feature = "gcm-siv")]
+            type AeadPool = $crate::crypto_impl::AesGcmSivPool<$crate::crypto_impl::OpenSSLAesGcmPool>;
+            type Hash = $crate::crypto_impl::CrateSha512;
+            type Hmac = $crate::crypto_impl::CrateHmacSha512;
+            type PublicKey = $crate::crypto_impl::CrateP384PublicKey;
+            type KeyPair = $crate::crypto_impl::CrateP384KeyPair;
+            type Kem = $crate::crypto_impl::CrateKyber1024PrivateKey;
+
+            type SessionData = ();
+            type FingerprintData = ();
+            type IncomingPacketBuffer = ::std::vec::Vec<u8>;
+        }
+    };
+}
+pub(crate) use sim_crypto;
+
+sim_crypto!(SimCrypto {
+    // Short timers, so scenarios cover several rekeys within a few simulated minutes.
+    const SETTINGS: Settings = Settings {
+        rekey_timeout: 60 * 1000,
+        rekey_after_time: 3000,
+        rekey_time_max_jitter: 1000,
+        resend_time: 250,
+        ..Settings::new_ms()
+    };
+});
+
+/// Generates a key pair from `rng` and returns its public key.
+pub(crate) fn random_public_key(rng: &mut SeededRng) -> CrateP384PublicKey {
+    let secret = CrateP384KeyPair::generate(rng);
+    let public_key = <CrateP384KeyPair as P384KeyPair<SeededRng>>::public_key_bytes(&secret);
+    CrateP384PublicKey::from_bytes(&public_key).unwrap()
+}
+
+/// How a link treats the packets sent over it.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct LinkConfig {
+    /// The probability that a packet is lost.
+    pub loss: f64,
+    /// The probability that a packet is delivered twice.
+    pub duplicate: f64,
+    /// The number of milliseconds every packet takes to arrive.
+    pub latency: i64,
+    /// Up to this many milliseconds are added to the latency of each packet at random, which
+    /// reorders packets sent close together.
+    pub jitter: i64,
+}
+
+/// A one way link that loses, duplicates, delays and reorders packets according to its config.
+pub(crate) struct Link {
+    pub config: Cell<LinkConfig>,
+    /// Called with the index of every packet sent over the link, in addition to the config.
+    /// The packet is dropped if this returns false.
+    pub script: RefCell<Option<Box<dyn FnMut(u64) -> bool>>>,
+    /// While this is set every send over the link fails, as if the network interface was down.
+    pub unavailable: Cell<bool>,
+    clock: Rc<Cell<i64>>,
+    rng: RefCell<SeededRng>,
+    sent: Cell<u64>,
+    delivered: Cell<u64>,
+    in_flight: RefCell<Vec<(i64, u64, Vec<u8>)>>,
+}
+impl Link {
+    fn new(clock: Rc<Cell<i64>>, seed: u64, config: LinkConfig) -> Self {
+        Self {
+            config: Cell::new(config),
+            script: RefCell::new(None),
+            unavailable: Cell::new(false),
+            clock,
+            rng: RefCell::new(SeededRng::new(seed)),
+            sent: Cell::new(0),
+            delivered: Cell::new(0),
+            in_flight: RefCell::new(Vec::new()),
+        }
+    }
+    fn send(&self, packet: &[u8]) -> bool {
+        if self.unavailable.get() {
+            return false;
+        }
+        let config = self.config.get();
+        let i = self.sent.get();
+        self.sent.set(i + 1);
+        let scripted = self.script.borrow_mut().as_mut().is_none_or(|script| script(i));
+        let rng = &mut *self.rng.borrow_mut();
+        if !scripted || rng.chance(config.loss) {
+            return true;
+        }
+        let copies = if rng.chance(config.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let jitter = if config.jitter > 0 {
+                rng.next_u64() % (config.jitter as u64 + 1)
+            } else {
+                0
+            };
+            let arrival = self.clock.get() + config.latency + jitter as i64;
+            self.in_flight.borrow_mut().push((arrival, i, packet.to_vec()));
+        }
+        true
+    }
+    /// Removes the next packet that has arrived by now, in order of arrival.
+    fn recv(&self) -> Option<Vec<u8>> {
+        let mut in_flight = self.in_flight.borrow_mut();
+        let now = self.clock.get();
+        let (i, _) = in_flight
+            .iter()
+            .enumerate()
+            .filter(|(_, (arrival, _, _))| *arrival <= now)
+            .min_by_key(|(_, (arrival, seq, _))| (*arrival, *seq))?;
+        self.delivered.set(self.delivered.get() + 1);
+        Some(in_flight.remove(i).2)
+    }
+    /// The number of packets sent over this link, including lost ones.
+    pub fn sent(&self) -> u64 {
+        self.sent.get()
+    }
+    /// The number of packets that arrived, including duplicates.
+    pub fn delivered(&self) -> u64 {
+        self.delivered.get()
+    }
+}
+
+/// One side of the simulation. `&Peer` is its `ApplicationLayer`.
+pub(crate) struct Peer {
+    pub ctx: Context<SimCrypto>,
+    pub session: RefCell<Option<Arc<Session<SimCrypto>>>>,
+    /// Every payload received, in order of receipt.
+    pub received: RefCell<Vec<Vec<u8>>>,
+    /// The number of byzantine faults that could not have been caused by the network.
+    pub unnatural_faults: Cell<usize>,
+    /// What `incoming_session` returns, `Allow` by default.
+    pub incoming_session: RefCell<IncomingSessionAction>,
+    /// The packet type and fragment number of every packet `on_send_failure` was called for.
+    pub send_failures: RefCell<Vec<(u8, u8)>>,
+    /// The number of times `restore_by_identity` was called.
+    pub identity_restores: Cell<usize>,
+    /// Every identity `check_accept_session` was called with.
+    pub accepted_identities: RefCell<Vec<Vec<u8>>>,
+    /// What `compress_identity` returns, false by default.
+    #[cfg(feature = "deflate")]
+    pub compress_identity: Cell<bool>,
+    /// What `misuse_resistance` returns, `Disabled` by default.
+    pub misuse_resistance: Cell<MisuseResistance>,
+    /// What `choose_rekey_timing` returns, `None` by default.
+    pub rekey_timing: Cell<Option<(u64, u64)>>,
+    /// What `allow_version_downgrade` returns, false by default.
+    pub allow_version_downgrade: Cell<bool>,
+    /// Hellos received from this `u32` remote address are dropped by
+    /// `incoming_session_with_address`.
+    pub blocked_address: Cell<Option<u32>>,
+    /// The ratchet states this side has saved for the other side.
+    pub ratchets: RefCell<MemoryRatchetStore<()>>,
+    /// While this is set, reading the saved ratchet states fails as if the storage was unavailable.
+    pub storage_unavailable: Cell<bool>,
+    /// What `incoming_rekey` returns, `Allow` by default.
+    pub rekey_action: Cell<RekeyAction>,
+    /// The ratchet count of the session every time `incoming_rekey` was called.
+    pub rekey_requests: RefCell<Vec<u64>>,
+    /// What `prefer_kyber` returns, false by default to keep the simulation fast.
+    pub prefer_kyber: Cell<bool>,
+    /// What `require_kyber` returns, false by default.
+    pub require_kyber: Cell<bool>,
+    pub public_key: CrateP384PublicKey,
+    key_seed: u64,
+    rng: RefCell<SeededRng>,
+    clock: Rc<Cell<i64>>,
+    next_service: Cell<i64>,
+}
+impl ApplicationLayer<SimCrypto> for &Peer {
+    fn time(&mut self) -> i64 {
+        self.clock.get()
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        self.incoming_session.borrow().clone()
+    }
+    fn incoming_session_with_address(&mut self, remote_address: &dyn Any) -> IncomingSessionAction {
+        match remote_address.downcast_ref::<u32>() {
+            Some(address) if self.blocked_address.get() == Some(*address) => IncomingSessionAction::Drop,
+            _ => self.incoming_session(),
+        }
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session<SimCrypto>>) -> bool {
+        true
+    }
+    fn allow_version_downgrade(&mut self, _: u8, _: u8) -> bool {
+        self.allow_version_downgrade.get()
+    }
+    fn check_accept_session(
+        &mut self,
+        _: &CrateP384PublicKey,
+        identity: &[u8],
+        _: Option<&()>,
+    ) -> AcceptAction<SimCrypto> {
+        self.accepted_identities.borrow_mut().push(identity.to_vec());
+        AcceptAction {
+            session_data: Some(()),
+            responder_disallows_downgrade: true,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(&mut self, rf: &[u8; RATCHET_SIZE]) -> std::io::Result<Option<(RatchetState, ())>> {
+        self.check_storage()?;
+        Ok(self.ratchets.borrow().restore_by_fingerprint(rf))
+    }
+    fn restore_by_identity(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        self.identity_restores.set(self.identity_restores.get() + 1);
+        self.check_storage()?;
+        Ok(self.ratchets.borrow().restore_by_identity(&()))
+    }
+    fn save_ratchet_state(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        update: CompareAndSwap<'_>,
+    ) -> std::io::Result<bool> {
+        Ok(self.ratchets.borrow_mut().save_ratchet_state(&(), update))
+    }
+    fn prefer_kyber(&mut self) -> bool {
+        self.prefer_kyber.get()
+    }
+    fn require_kyber(&mut self) -> bool {
+        self.require_kyber.get()
+    }
+    #[cfg(feature = "deflate")]
+    fn compress_identity(&mut self) -> bool {
+        self.compress_identity.get()
+    }
+    fn misuse_resistance(&mut self) -> MisuseResistance {
+        self.misuse_resistance.get()
+    }
+    fn on_send_failure(&mut self, _: &Arc<Session<SimCrypto>>, packet_type: u8, fragment_no: u8) {
+        self.send_failures.borrow_mut().push((packet_type, fragment_no));
+    }
+    fn choose_rekey_timing(&mut self, _: &Arc<Session<SimCrypto>>) -> Option<(u64, u64)> {
+        self.rekey_timing.get()
+    }
+    fn incoming_rekey(&mut self, session: &Arc<Session<SimCrypto>>) -> RekeyAction {
+        // No lock is held during the call, so the session can be inspected.
+        self.rekey_requests.borrow_mut().push(session.ratchet_count());
+        self.rekey_action.get()
+    }
+}
+impl Peer {
+    fn new(clock: Rc<Cell<i64>>, rng: &mut SeededRng) -> Self {
+        let key_seed = rng.next_u64();
+        let mut rng = SeededRng::new(rng.next_u64());
+        Self {
+            ctx: Context::new(
+                CrateP384KeyPair::generate(&mut SeededRng::new(key_seed)),
+                SeededRng::new(rng.next_u64()),
+            ),
+            session: RefCell::new(None),
+            received: RefCell::new(Vec::new()),
+            unnatural_faults: Cell::new(0),
+            incoming_session: RefCell::new(IncomingSessionAction::Allow),
+            send_failures: RefCell::new(Vec::new()),
+            identity_restores: Cell::new(0),
+            accepted_identities: RefCell::new(Vec::new()),
+            #[cfg(feature = "deflate")]
+            compress_identity: Cell::new(false),
+            misuse_resistance: Cell::new(MisuseResistance::Disabled),
+            rekey_timing: Cell::new(None),
+            allow_version_downgrade: Cell::new(false),
+            blocked_address: Cell::new(None),
+            ratchets: RefCell::new(MemoryRatchetStore::new()),
+            storage_unavailable: Cell::new(false),
+            rekey_action: Cell::new(RekeyAction::Allow),
+            rekey_requests: RefCell::new(Vec::new()),
+            prefer_kyber: Cell::new(false),
+            require_kyber: Cell::new(false),
+            public_key: random_public_key(&mut SeededRng::new(key_seed)),
+            key_seed,
+            rng: RefCell::new(rng),
+            clock,
+            next_service: Cell::new(0),
+        }
+    }
+    /// Generates the static key of this side again, for a context that replaces its own.
+    pub fn static_secret(&self) -> CrateP384KeyPair {
+        CrateP384KeyPair::generate(&mut SeededRng::new(self.key_seed))
+    }
+    /// A new RNG for a context that replaces the one of this side.
+    pub fn context_rng(&self) -> SeededRng {
+        SeededRng::new(self.rng.borrow_mut().next_u64())
+    }
+    /// Replaces the context of this side with a new one with the same static key, as if the
+    /// process had restarted. The session is forgotten, but the saved ratchet states are kept.
+    pub fn restart(&mut self) {
+        self.ctx = Context::new(self.static_secret(), self.context_rng());
+        *self.session.get_mut() = None;
+        self.next_service.set(0);
+    }
+    /// Receives every packet that has arrived on `inbox` and services the context if it is due.
+    fn poll(&self, inbox: &Link, outbox: &Link) {
+        let send = |packet: &mut [u8]| outbox.send(packet);
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+        while let Some(packet) = inbox.recv() {
+            let mut data = Vec::new();
+            match self.ctx.receive(self, send, MTU, send_to, &(), packet, &mut data) {
+                Ok((result, reduced)) => {
+                    if let Some(time) = reduced {
+                        self.next_service.set(self.next_service.get().min(time));
+                    }
+                    match result {
+                        ReceiveOk::Associated(session, SessionEvent::NewSession) => {
+                            *self.session.borrow_mut() = Some(session);
+                        }
+                        ReceiveOk::Associated(_, SessionEvent::Data) => self.received.borrow_mut().push(data),
+                        _ => {}
+                    }
+                }
+                Err(ReceiveError::ByzantineFault(fault)) if fault.unnatural => {
+                    self.unnatural_faults.set(self.unnatural_faults.get() + 1);
+                }
+                Err(_) => {}
+            }
+        }
+        let now = self.clock.get();
+        if now >= self.next_service.get() {
+            self.next_service.set(now + self.ctx.service(self, send_to));
+        }
+    }
+    fn check_storage(&self) -> std::io::Result<()> {
+        match self.storage_unavailable.get() {
+            true => Err(std::io::Error::other("storage unavailable")),
+            false => Ok(()),
+        }
+    }
+    /// Whether this side has a session and it is established.
+    pub fn established(&self) -> bool {
+        self.session.borrow().as_ref().is_some_and(|s| s.established())
+    }
+    /// The ratchet count of the session of this side, or 0 if there is none.
+    pub fn ratchet_count(&self) -> u64 {
+        self.session.borrow().as_ref().map_or(0, |s| s.ratchet_count())
+    }
+}
+
+/// Alice and Bob, connected by a link in each direction.
+pub(crate) struct Sim {
+    pub alice: Peer,
+    pub bob: Peer,
+    pub to_alice: Link,
+    pub to_bob: Link,
+    clock: Rc<Cell<i64>>,
+}
+impl Sim {
+    /// Creates both sides with links in both directions configured as `config`.
+    /// Every random decision of the simulation is derived from `seed`.
+    pub fn new(seed: u64, config: LinkConfig) -> Self {
+        let clock = Rc::new(Cell::new(0));
+        let rng = &mut SeededRng::new(seed);
+        let alice = Peer::new(clock.clone(), rng);
+        let bob = Peer::new(clock.clone(), rng);
+        Self {
+            to_alice: Link::new(clock.clone(), rng.next_u64(), config),
+            to_bob: Link::new(clock.clone(), rng.next_u64(), config),
+            alice,
+            bob,
+            clock,
+        }
+    }
+    /// The current virtual time in milliseconds.
+    pub fn now(&self) -> i64 {
+        self.clock.get()
+    }
+    /// Alice opens a session with Bob.
+    pub fn open(&self) {
+        self.open_with_identity(&[]);
+    }
+    /// Alice opens a session with Bob, and sends him `identity`.
+    pub fn open_with_identity(&self, identity: &[u8]) {
+        let send = |packet: &mut [u8]| self.to_bob.send(packet);
+        let (session, _) = self
+            .alice
+            .ctx
+            .open(&self.alice, send, MTU, self.bob.public_key, (), identity)
+            .unwrap();
+        *self.alice.session.borrow_mut() = Some(session);
+    }
+    /// Moves the clock forward one millisecond at a time, delivering packets as they arrive and
+    /// servicing both sides when they ask to be.
+    pub fn advance_time(&self, ms: i64) {
+        for _ in 0..ms {
+            self.clock.set(self.clock.get() + 1);
+            self.alice.poll(&self.to_alice, &self.to_bob);
+            self.bob.poll(&self.to_bob, &self.to_alice);
+        }
+    }
+    /// Advances time until both sides have an established session, or `timeout` milliseconds
+    /// have passed. Returns whether the session was established.
+    pub fn run_until_established(&self, timeout: i64) -> bool {
+        let deadline = self.now() + timeout;
+        while !(self.alice.established() && self.bob.established()) {
+            if self.now() >= deadline {
+                return false;
+            }
+            self.advance_time(1);
+        }
+        true
+    }
+    /// Sends `data` from Alice to Bob, or from Bob to Alice if `from_alice` is false.
+    /// Returns false if there is no established session to send it over.
+    pub fn send(&self, from_alice: bool, data: &[u8]) -> bool {
+        let (peer, outbox) = if from_alice {
+            (&self.alice, &self.to_bob)
+        } else {
+            (&self.bob, &self.to_alice)
+        };
+        let session = peer.session.borrow();
+        let Some(session) = session.as_ref() else {
+            return false;
+        };
+        let send = |packet: &mut [u8]| outbox.send(packet);
+        peer.ctx.send(session, send, MTU, &mut [0u8; MTU], data).is_ok()
+    }
+}
+
+/// An `ApplicationLayer` that can be shared between threads, for tests that drive the contexts
+/// of a `Sim` from more than one thread. It accepts every session and saves no ratchet states.
+#[derive(Clone, Copy)]
+pub(crate) struct ThreadedApp<'a> {
+    pub clock: &'a AtomicI64,
+    /// What `choose_rekey_timing` returns.
+    pub rekey_timing: Option<(u64, u64)>,
+}
+impl ApplicationLayer<SimCrypto> for ThreadedApp<'_> {
+    fn time(&mut self) -> i64 {
+        self.clock.load(Ordering::Relaxed)
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        IncomingSessionAction::Allow
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session<SimCrypto>>) -> bool {
+        false
+    }
+    fn check_accept_session(&mut self, _: &CrateP384PublicKey, _: &[u8], _: Option<&()>) -> AcceptAction<SimCrypto> {
+        AcceptAction {
+            session_data: Some(()),
+            responder_disallows_downgrade: false,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(&mut self, _: &[u8; RATCHET_SIZE]) -> std::io::Result<Option<(RatchetState, ())>> {
+        Ok(None)
+    }
+    fn restore_by_identity(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        Ok(None)
+    }
+    fn save_ratchet_state(&mut self, _: &CrateP384PublicKey, _: &(), _: CompareAndSwap<'_>) -> std::io::Result<bool> {
+        Ok(true)
+    }
+    fn prefer_kyber(&mut self) -> bool {
+        false
+    }
+    fn choose_rekey_timing(&mut self, _: &Arc<Session<SimCrypto>>) -> Option<(u64, u64)> {
+        self.rekey_timing
+    }
+}
+
+/// A lossless link that can be shared between threads, for use with `ThreadedApp`.
+#[derive(Default)]
+pub(crate) struct ThreadedLink(Mutex<VecDeque<Vec<u8>>>);
+impl ThreadedLink {
+    pub fn send(&self, packet: &mut [u8]) -> bool {
+        self.0.lock().push_back(packet.to_vec());
+        true
+    }
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.0.lock().pop_front()
+    }
+    /// The number of packets waiting to be received.
+    pub fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+}
+
+/// Counts the heap allocations made by each thread, so tests can check that a code path does
+/// not allocate.
+struct CountingAllocator;
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        // `try_with` fails while the thread is being torn down, those allocations are not counted.
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+/// Returns the result of `f` along with the number of heap allocations it made.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = ALLOCATIONS.with(|n| n.get());
+    let result = f();
+    (result, ALLOCATIONS.with(|n| n.get()) - start)
+}
+
+/// Sends a numbered payload from Alice to Bob every `interval` milliseconds for `duration`
+/// milliseconds. Returns the number of payloads sent.
+fn send_for(sim: &Sim, duration: i64, interval: i64) -> u32 {
+    let mut sent = 0u32;
+    for _ in 0..duration / interval {
+        if sim.send(true, &sent.to_le_bytes()) {
+            sent += 1;
+        }
+        sim.advance_time(interval);
+    }
+    sent
+}
+fn received_numbers(peer: &Peer) -> Vec<u32> {
+    peer.received
+        .borrow()
+        .iter()
+        .map(|data| u32::from_le_bytes(data[..].try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn test_sim_lossless() {
+    let sim = Sim::new(1, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let sent = send_for(&sim, 30_000, 10);
+    sim.advance_time(100);
+    assert_eq!(received_numbers(&sim.bob), (0..sent).collect::<Vec<_>>());
+    // A rekey every 3 to 4 seconds.
+    assert!(sim.alice.ratchet_count() >= 8);
+    assert_eq!(sim.alice.ratchet_count(), sim.bob.ratchet_count());
+    assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+}
+
+#[test]
+fn test_sim_loss() {
+    for (seed, loss) in [(50, 0.5), (75, 0.25), (99, 0.01)] {
+        let sim = Sim::new(seed, LinkConfig { loss, latency: 10, ..LinkConfig::default() });
+        sim.open();
+        assert!(sim.run_until_established(60_000));
+        let ratchet_count = sim.alice.ratchet_count();
+        send_for(&sim, 60_000, 10);
+        assert!(!sim.bob.received.borrow().is_empty());
+        assert!(sim.alice.ratchet_count() > ratchet_count);
+        assert!(sim.alice.established() && sim.bob.established());
+        assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+    }
+}
+
+#[test]
+fn test_sim_reordering_and_duplicates() {
+    let config = LinkConfig {
+        duplicate: 0.3,
+        latency: 5,
+        jitter: 50,
+        ..LinkConfig::default()
+    };
+    let sim = Sim::new(2, config);
+    sim.open();
+    assert!(sim.run_until_established(5000));
+    let sent = send_for(&sim, 30_000, 10);
+    sim.advance_time(100);
+    assert!(sim.to_bob.delivered() > sim.to_bob.sent());
+    // Every payload arrives exactly once, though not in order.
+    let mut received = received_numbers(&sim.bob);
+    assert_ne!(received, (0..sent).collect::<Vec<_>>());
+    received.sort_unstable();
+    assert_eq!(received, (0..sent).collect::<Vec<_>>());
+    assert!(sim.alice.ratchet_count() >= 8);
+    assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+}
+
+#[test]
+fn test_sim_outage_during_rekey() {
+    let sim = Sim::new(3, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let ratchet_count = sim.alice.ratchet_count();
+    // Everything Bob sends is lost for longer than a rekey interval, so his replies to at least one
+    // rekey, and the acknowledgements of Alice's data, never arrive.
+    sim.to_alice.script.replace(Some(Box::new(|_| false)));
+    send_for(&sim, 10_000, 10);
+    assert_eq!(sim.alice.ratchet_count(), ratchet_count);
+    sim.to_alice.script.replace(None);
+    sim.advance_time(5000);
+    assert!(sim.alice.ratchet_count() > ratchet_count);
+    assert_eq!(sim.alice.ratchet_count(), sim.bob.ratchet_count());
+    // Data still flows in both directions.
+    sim.bob.received.borrow_mut().clear();
+    send_for(&sim, 1000, 10);
+    assert!(sim.send(false, &0u32.to_le_bytes()));
+    sim.advance_time(100);
+    assert!(!sim.alice.received.borrow().is_empty());
+    assert!(!sim.bob.received.borrow().is_empty());
+    assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+}
+
+#[test]
+fn test_recv_window() {
+    let sim = Sim::new(5, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    assert!(sim.send(true, &0u32.to_le_bytes()));
+    sim.advance_time(100);
+    let session = sim.bob.session.borrow().clone().unwrap();
+    let counter = session.replay_stats().max_counter;
+    let rejected = session.replay_stats().rejected;
+    assert!(!session.check_recv_window(counter));
+    // Checking records nothing, not even the rejection.
+    assert_eq!(session.replay_stats().rejected, rejected);
+    // Recording the counter of Alice's next packet makes Bob drop it as a replay.
+    assert!(session.check_recv_window(counter + 1));
+    assert!(session.update_recv_window(counter + 1));
+    assert!(!session.check_recv_window(counter + 1));
+    assert!(!session.update_recv_window(counter + 1));
+    assert!(sim.send(true, &1u32.to_le_bytes()));
+    assert!(sim.send(true, &2u32.to_le_bytes()));
+    sim.advance_time(100);
+    assert_eq!(received_numbers(&sim.bob), [0, 2]);
+}
+
+#[test]
+fn test_context_statistics() {
+    let sim = Sim::new(6, LinkConfig { latency: 10, ..LinkConfig::default() });
+    assert_eq!(sim.alice.ctx.statistics(), ContextStats::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let sent = send_for(&sim, 1000, 10);
+    sim.advance_time(100);
+    let alice = sim.alice.ctx.statistics();
+    let bob = sim.bob.ctx.statistics();
+    for stats in [alice, bob] {
+        assert_eq!(stats.sessions_opened, 1);
+        assert_eq!(stats.sessions_alive, 1);
+        assert_eq!(stats.handshakes_completed, 1);
+        assert_eq!(stats.byzantine_faults, 0);
+    }
+    assert_eq!(alice.bytes_sent, sent as u64 * 4);
+    assert_eq!(bob.bytes_received, alice.bytes_sent);
+    assert_eq!(bob.bytes_sent, 0);
+
+    sim.bob.session.replace(None);
+    assert_eq!(sim.bob.ctx.statistics().sessions_alive, 0);
+    assert_eq!(sim.bob.ctx.statistics().sessions_opened, 1);
+    // Alice's packets to Bob's dropped session are now faults.
+    assert!(sim.send(true, &0u32.to_le_bytes()));
+    sim.advance_time(100);
+    assert!(sim.bob.ctx.statistics().byzantine_faults > 0);
+}
+
+#[test]
+fn test_sim_is_deterministic() {
+    let run = || {
+        let sim = Sim::new(4, LinkConfig { loss: 0.2, duplicate: 0.1, latency: 5, jitter: 20 });
+        sim.open();
+        assert!(sim.run_until_established(60_000));
+        send_for(&sim, 20_000, 10);
+        let trace = (
+            sim.now(),
+            sim.to_alice.delivered(),
+            sim.to_bob.delivered(),
+            received_numbers(&sim.bob),
+        );
+        (trace, sim.alice.ratchet_count())
+    };
+    assert_eq!(run(), run());
+}
+
+/// Expires a session as soon as a payload is written to it. A receive writing to this sink is the
+/// same as a receive on another thread that has already loaded the keys when `expire` is called.
+struct ExpiringSink(Arc<Session<SimCrypto>>, Vec<u8>);
+impl std::io::Write for ExpiringSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.expire();
+        self.1.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_expire_during_receive() {
+    let sim = Sim::new(7, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(100);
+    assert!(sim.send(true, b"first"));
+    assert!(sim.send(true, b"second"));
+    sim.clock.set(sim.now() + 10);
+    let session = sim.bob.session.borrow().clone().unwrap();
+    let send = |_: &mut [u8]| true;
+    let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+    let receive = |sink: &mut ExpiringSink| {
+        let packet = sim.to_bob.recv().unwrap();
+        sim.bob
+            .ctx
+            .receive(&sim.bob, send, MTU, send_to, &(), packet, sink)
+            .map(|(result, _)| result)
+    };
+
+    // The receive that was already underway still delivers its packet.
+    let mut sink = ExpiringSink(session.clone(), Vec::new());
+    let result = receive(&mut sink);
+    assert!(matches!(result, Ok(ReceiveOk::Associated(_, SessionEvent::Data))));
+    assert_eq!(sink.1, b"first");
+    assert!(!session.established());
+    // The key id of the session is gone, so the next packet is not even associated with it.
+    let mut sink = ExpiringSink(session.clone(), Vec::new());
+    match receive(&mut sink) {
+        Err(ReceiveError::ByzantineFault(fault)) => assert_eq!(fault.error, FaultType::UnknownLocalKeyId),
+        _ => panic!("a packet for an expired session was accepted"),
+    }
+    assert!(sink.1.is_empty());
+}
+
+#[test]
+fn test_open_or_recover() {
+    let sim = Sim::new(8, LinkConfig { latency: 10, ..LinkConfig::default() });
+    let open = || {
+        let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+        let ctx = &sim.alice.ctx;
+        ctx.open_or_recover(&sim.alice, send, MTU, sim.bob.public_key, (), &[])
+            .unwrap()
+    };
+    let (session, _) = open();
+    // A session that is still establishing is reused, and nothing more is sent.
+    let sent = sim.to_bob.sent();
+    assert!(Arc::ptr_eq(&open().0, &session));
+    assert_eq!(sim.to_bob.sent(), sent);
+    *sim.alice.session.borrow_mut() = Some(session.clone());
+    assert!(sim.run_until_established(1000));
+    let (recovered, next_service) = open();
+    assert!(Arc::ptr_eq(&recovered, &session));
+    assert_eq!(next_service, None);
+
+    session.expire();
+    let (reopened, _) = open();
+    assert!(!Arc::ptr_eq(&reopened, &session));
+    assert!(reopened.establishing());
+}
+
+#[test]
+fn test_time_until_rekey() {
+    let sim = Sim::new(9, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    assert_eq!(alice.time_until_rekey(sim.now()), None);
+    assert_eq!(alice.uses_until_rekey(), None);
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(100);
+
+    // The rekey is due 3 seconds after the key is confirmed, minus up to 1 second of jitter.
+    let remaining = alice.time_until_rekey(sim.now()).unwrap();
+    assert!((1900..=3000).contains(&remaining), "{}", remaining);
+    assert_eq!(alice.time_until_rekey(sim.now() + remaining), Some(0));
+    let uses = alice.uses_until_rekey().unwrap();
+    assert!(sim.send(true, &0u32.to_le_bytes()));
+    assert_eq!(alice.uses_until_rekey(), Some(uses - 1));
+
+    // Once the rekey has happened, the countdown starts over.
+    let ratchet_count = alice.ratchet_count();
+    sim.advance_time(remaining + 1000);
+    assert!(alice.ratchet_count() > ratchet_count);
+    assert!(alice.time_until_rekey(sim.now()).unwrap() > 1000);
+}
+
+#[test]
+fn test_on_send_failure() {
+    let sim = Sim::new(10, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.to_bob.unavailable.set(true);
+    sim.open();
+    assert_eq!(*sim.alice.send_failures.borrow(), [(PACKET_TYPE_HANDSHAKE_HELLO, 0)]);
+    // Resends of the hello by the service timer fail too.
+    sim.advance_time(300);
+    assert_eq!(sim.alice.send_failures.borrow().len(), 2);
+
+    sim.to_bob.unavailable.set(false);
+    assert!(sim.run_until_established(1000));
+    assert_eq!(sim.alice.send_failures.borrow().len(), 2);
+    assert!(sim.bob.send_failures.borrow().is_empty());
+}
+
+#[test]
+fn test_emergency_wipe() {
+    let sim = Sim::new(11, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    assert!(!sim.alice.ratchets.borrow().restore_by_identity(&()).unwrap().is_empty());
+    // Leave Bob in the middle of a second handshake, holding the state of an unauthenticated Alice.
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    let pending = sim
+        .alice
+        .ctx
+        .open(&sim.alice, send, MTU, sim.bob.public_key, (), &[])
+        .unwrap()
+        .0;
+    sim.advance_time(15);
+    assert_eq!(sim.bob.ctx.0.unassociated_handshake_states.len(), 1);
+
+    sim.alice.ctx.emergency_wipe(&sim.alice).unwrap();
+    sim.bob.ctx.emergency_wipe(&sim.bob).unwrap();
+    assert!(alice.is_expired() && pending.is_expired());
+    assert!(alice.ratchet_states().is_empty());
+    assert!(sim.alice.ratchets.borrow().restore_by_identity(&()).unwrap().is_empty());
+    assert!(sim.bob.ratchets.borrow().restore_by_identity(&()).unwrap().is_empty());
+    assert_eq!(sim.bob.ctx.0.unassociated_handshake_states.len(), 0);
+    assert!(!sim.send(true, &0u32.to_le_bytes()));
+    // Neither side has anything left to resend.
+    let sent = (sim.to_alice.sent(), sim.to_bob.sent());
+    sim.advance_time(1000);
+    assert_eq!((sim.to_alice.sent(), sim.to_bob.sent()), sent);
+}
+
+#[test]
+fn test_wipe_and_migrate_during_send() {
+    use crate::crypto::AES_256_KEY_SIZE;
+    use crate::ExportMode;
+    let master_key = [9u8; AES_256_KEY_SIZE];
+    let sim = Sim::new(29, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let session = sim.alice.session.borrow().clone().unwrap();
+    let ctx = &sim.alice.ctx;
+    let clock = AtomicI64::new(sim.now());
+    let app = ThreadedApp { clock: &clock, rekey_timing: None };
+
+    // The session is migrated by another thread while a send of ours is inside `send_frag`, which
+    // reads the state of the session. The export waits for the send to finish, so the counter it
+    // used is accounted for.
+    let blob = std::thread::scope(|s| {
+        let mut exporter = None;
+        let send = |packet: &mut [u8]| {
+            let session = &session;
+            exporter = Some(s.spawn(move || ctx.export_session_state(session, &master_key, ExportMode::Migrate)));
+            while !session.is_expired() {
+                std::thread::yield_now();
+            }
+            sim.to_bob.send(packet)
+        };
+        assert!(ctx.send(&session, send, MTU, &mut [0u8; MTU], b"in flight").is_ok());
+        exporter.unwrap().join().unwrap().unwrap()
+    });
+    let (session, _) = ctx.import_session_state(&blob, &master_key, ()).unwrap();
+    *sim.alice.session.borrow_mut() = Some(session.clone());
+    assert!(sim.send(true, b"migrated"));
+    sim.advance_time(20);
+    assert_eq!(sim.bob.received.take(), [&b"in flight"[..], b"migrated"]);
+
+    // The same goes for a wipe.
+    std::thread::scope(|s| {
+        let mut wiper = None;
+        let send = |packet: &mut [u8]| {
+            wiper = Some(s.spawn(|| ctx.emergency_wipe(app)));
+            while !session.is_expired() {
+                std::thread::yield_now();
+            }
+            sim.to_bob.send(packet)
+        };
+        assert!(ctx.send(&session, send, MTU, &mut [0u8; MTU], b"wiped").is_ok());
+        wiper.unwrap().join().unwrap().unwrap();
+    });
+    assert!(session.ratchet_states().is_empty());
+}
+
+#[test]
+fn test_wipe_in_callback() {
+    use crate::crypto::AES_256_KEY_SIZE;
+    use crate::result::ExportError;
+    use crate::ExportMode;
+    /// Migrates and then wipes the session it is writing the payload of.
+    struct Wiping<'a> {
+        sim: &'a Sim,
+        session: Arc<Session<SimCrypto>>,
+        migrated: Option<Result<Vec<u8>, ExportError>>,
+    }
+    impl PayloadSink for &mut Wiping<'_> {
+        type Error = core::convert::Infallible;
+        fn write_payload(&mut self, _: &[u8]) -> Result<(), Self::Error> {
+            let ctx = &self.sim.bob.ctx;
+            let migrated = ctx.export_session_state(&self.session, &[9u8; AES_256_KEY_SIZE], ExportMode::Migrate);
+            self.migrated = Some(migrated);
+            ctx.emergency_wipe(&self.sim.bob).unwrap();
+            Ok(())
+        }
+    }
+    let sim = Sim::new(44, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let session = sim.bob.session.borrow().clone().unwrap();
+
+    // The receive calling the sink holds the keys of the session, so neither call may wait for it.
+    assert!(sim.send(true, b"wipe"));
+    let packet = sim.to_bob.recv().unwrap();
+    let mut sink = Wiping { sim: &sim, session: session.clone(), migrated: None };
+    let send_to = |_: &Arc<Session<SimCrypto>>| Some((|_: &mut [u8]| true, MTU));
+    let result = sim
+        .bob
+        .ctx
+        .receive(&sim.bob, |_: &mut [u8]| true, MTU, send_to, &(), packet, &mut sink);
+    assert!(matches!(result, Ok((ReceiveOk::Associated(_, SessionEvent::Data), _))));
+    assert_eq!(sink.migrated, Some(Err(ExportError::InCallback)));
+    assert!(session.is_expired());
+    assert!(session.ratchet_states().is_empty());
+}
+
+#[test]
+fn test_park_session() {
+    let sim = Sim::new(33, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(100);
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let bob = sim.bob.session.borrow().clone().unwrap();
+    let ratchet_count = alice.ratchet_count();
+
+    // Nothing is sent by either side across several rekey intervals, but data still flows.
+    sim.alice.ctx.park_session(&alice);
+    sim.bob.ctx.park_session(&bob);
+    let sent = (sim.to_alice.sent(), sim.to_bob.sent());
+    sim.advance_time(4 * SimCrypto::SETTINGS.rekey_after_time as i64);
+    assert_eq!((sim.to_alice.sent(), sim.to_bob.sent()), sent);
+    assert_eq!(alice.ratchet_count(), ratchet_count);
+    assert!(sim.send(true, b"parked"));
+    assert!(sim.send(false, b"also parked"));
+    sim.advance_time(20);
+    assert_eq!(sim.bob.received.take(), [b"parked"]);
+    assert_eq!(sim.alice.received.take(), [b"also parked"]);
+    assert_eq!(alice.ratchet_count(), ratchet_count);
+
+    // The key aged while parked, so it is rekeyed as soon as the sessions are unparked.
+    for (peer, session) in [(&sim.alice, &alice), (&sim.bob, &bob)] {
+        let next_service = peer.ctx.unpark_session(session, sim.now()).unwrap();
+        assert!(next_service <= sim.now());
+        peer.next_service.set(next_service);
+    }
+    assert_eq!(sim.alice.ctx.unpark_session(&alice, sim.now()), None);
+    sim.advance_time(1000);
+    assert!(alice.ratchet_count() > ratchet_count);
+    assert!(sim.send(true, b"unparked"));
+    sim.advance_time(20);
+    assert_eq!(sim.bob.received.take(), [b"unparked"]);
+
+    // A handshake that is parked is not resent, and is resent right away once unparked.
+    let sim = Sim::new(34, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.to_bob.unavailable.set(true);
+    sim.open();
+    sim.to_bob.unavailable.set(false);
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    sim.alice.ctx.park_session(&alice);
+    sim.advance_time(10 * SimCrypto::SETTINGS.resend_time as i64);
+    assert_eq!(sim.to_bob.sent(), 0);
+    assert!(!alice.is_expired() && !alice.established());
+    let next_service = sim.alice.ctx.unpark_session(&alice, sim.now()).unwrap();
+    sim.alice.next_service.set(next_service);
+    assert!(sim.run_until_established(1000));
+}
+
+#[test]
+fn test_receive_arrival_time() {
+    let sim = Sim::new(12, LinkConfig::default());
+    let timeout = SimCrypto::SETTINGS.fragment_assembly_timeout as i64;
+    sim.clock.set(10 * timeout);
+    // Delivers the first fragment of a hello to Bob as having arrived at `arrival_time`, services
+    // Bob, and then delivers the rest. Returns whether Bob replied to the hello.
+    let deliver_hello = |arrival_time: i64| {
+        let mut hello = Vec::new();
+        let send = |packet: &mut [u8]| {
+            hello.push(packet.to_vec());
+            true
+        };
+        let _session = sim
+            .alice
+            .ctx
+            .open(&sim.alice, send, MTU, sim.bob.public_key, (), &[])
+            .unwrap();
+        assert!(hello.len() > 1);
+        let replies = Cell::new(0);
+        let send = |_: &mut [u8]| {
+            replies.set(replies.get() + 1);
+            true
+        };
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+        let bob = &sim.bob.ctx;
+        let mut fragments = hello.into_iter();
+        let first = fragments.next().unwrap();
+        let _ = bob.receive_with_timestamp(&sim.bob, send, MTU, send_to, &(), arrival_time, first, &mut Vec::new());
+        bob.service(&sim.bob, send_to);
+        for fragment in fragments {
+            let _ = bob.receive(&sim.bob, send, MTU, send_to, &(), fragment, &mut Vec::new());
+        }
+        replies.get() > 0
+    };
+    assert!(deliver_hello(sim.now()));
+    // A fragment that arrived longer than `fragment_assembly_timeout` ago has expired by the time
+    // Bob is serviced, even though it was only just processed.
+    assert!(!deliver_hello(sim.now() - timeout - 1));
+}
+
+#[test]
+fn test_aad() {
+    let sim = Sim::new(24, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let session = sim.alice.session.borrow().clone().unwrap();
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+    // Sends `data` from Alice with `aad`, and returns what Bob received with `bob_aad`.
+    let send_with_aad = |aad: &[u8], data: &[u8], bob_aad: &[u8]| {
+        let alice = &sim.alice.ctx;
+        let sent = alice.send_with_aad(&session, send, MTU, &mut [0u8; MTU], aad, data);
+        assert!(sent.is_ok());
+        sim.clock.set(sim.now() + 1);
+        let mut received = Vec::new();
+        let mut result = None;
+        while let Some(packet) = sim.to_bob.recv() {
+            let bob = &sim.bob.ctx;
+            let options = ReceiveOptions { aad: bob_aad, ..Default::default() };
+            let r = bob.receive_with_options(&sim.bob, send, MTU, send_to, &(), options, packet, &mut received);
+            result = Some(r.map(|(result, _)| result));
+        }
+        (result.unwrap(), received)
+    };
+    let data: Vec<u8> = (0..3 * MTU as u32).map(|i| i as u8).collect();
+    for data in [&data[..10], &data[..]] {
+        let (result, received) = send_with_aad(b"route", data, b"route");
+        assert!(matches!(result, Ok(ReceiveOk::Associated(_, SessionEvent::Data))));
+        assert_eq!(received, data);
+        for bob_aad in [&b"other"[..], b""] {
+            match send_with_aad(b"route", data, bob_aad) {
+                (Err(ReceiveError::ByzantineFault(fault)), received) => {
+                    assert_eq!(fault.error, FaultType::FailedAuth);
+                    assert!(received.is_empty());
+                }
+                _ => panic!("data was accepted with the wrong aad"),
+            }
+        }
+    }
+    // Data sent without aad can still be received like any other.
+    let (result, received) = send_with_aad(b"", b"plain", b"");
+    assert!(matches!(result, Ok(ReceiveOk::Associated(_, SessionEvent::Data))));
+    assert_eq!(received, b"plain");
+}
+
+#[cfg(feature = "gcm-siv")]
+#[test]
+fn test_misuse_resistance() {
+    use MisuseResistance::*;
+    // The policies of Alice and Bob, and whether the session uses the misuse resistant AEAD, or
+    // `None` if it cannot be established.
+    let cases = [
+        (Preferred, Preferred, Some(true)),
+        (Required, Preferred, Some(true)),
+        (Preferred, Required, Some(true)),
+        (Disabled, Preferred, Some(false)),
+        (Preferred, Disabled, Some(false)),
+        (Required, Disabled, None),
+        (Disabled, Required, None),
+    ];
+    let lossy = LinkConfig { loss: 0.1, duplicate: 0.1, latency: 10, jitter: 20 };
+    for (alice, bob, expected) in cases {
+        let sim = Sim::new(26, lossy);
+        sim.alice.misuse_resistance.set(alice);
+        sim.bob.misuse_resistance.set(bob);
+        sim.open();
+        let Some(misuse_resistant) = expected else {
+            assert!(!sim.run_until_established(5000));
+            assert!(sim.alice.session.borrow().as_ref().unwrap().is_expired());
+            assert!(sim.bob.session.borrow().is_none());
+            assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+            continue;
+        };
+        assert!(sim.run_until_established(60_000));
+        for peer in [&sim.alice, &sim.bob] {
+            let session = peer.session.borrow().clone().unwrap();
+            assert_eq!(session.is_misuse_resistant(), misuse_resistant);
+        }
+        // Fragmented packets in both directions, across several rekeys.
+        let data: Vec<u8> = (0..3 * MTU as u32).map(|i| i as u8).collect();
+        let ratchet_count = sim.alice.ratchet_count();
+        for _ in 0..100 {
+            assert!(sim.send(true, &data) && sim.send(false, &data[..10]));
+            sim.advance_time(100);
+        }
+        assert!(sim.alice.ratchet_count() > ratchet_count);
+        assert!(sim.bob.received.borrow().iter().any(|received| received == &data));
+        let received = sim.alice.received.borrow();
+        assert!(!received.is_empty() && received.iter().all(|received| received == &data[..10]));
+        assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+    }
+}
+
+#[test]
+fn test_send_reliable() {
+    use crate::receive_sequence_number;
+    let sim = Sim::new(25, LinkConfig { latency: 10, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let (alice, session) = (&sim.alice.ctx, sim.alice.session.borrow().clone().unwrap());
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    // The first transmission is lost.
+    sim.to_bob.unavailable.set(true);
+    let handle = alice.send_reliable(&session, send, MTU, b"request", 100).unwrap();
+    sim.to_bob.unavailable.set(false);
+    assert!(sim.bob.received.borrow().is_empty());
+    sim.advance_time(2000);
+    let received = sim.bob.received.borrow().len();
+    assert!(received > 1);
+    for data in sim.bob.received.borrow().iter() {
+        assert_eq!(receive_sequence_number(data), Some((handle.seq_no(), &b"request"[..])));
+    }
+    assert!(handle.is_pending());
+    assert!(!handle.acknowledge(handle.seq_no() + 1));
+    assert!(handle.acknowledge(handle.seq_no()));
+    assert!(!handle.is_pending());
+    assert!(!handle.acknowledge(handle.seq_no()));
+    // Retransmissions already in flight still arrive, but no more are sent.
+    sim.advance_time(100);
+    let received = sim.bob.received.borrow().len();
+    sim.advance_time(2000);
+    assert_eq!(sim.bob.received.borrow().len(), received);
+    // Data is not retransmitted over sessions that have expired.
+    let handle = alice.send_reliable(&session, send, MTU, b"request", 100).unwrap();
+    session.expire();
+    sim.advance_time(SimCrypto::SETTINGS.fragment_assembly_timeout as i64);
+    assert!(!handle.is_pending());
+}
+
+#[test]
+fn test_send_reliable_acknowledged_by_sender() {
+    let sim = Sim::new(26, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let (alice, session) = (&sim.alice.ctx, sim.alice.session.borrow().clone().unwrap());
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    // An interval this long never comes due, but must not overflow the time of the next resend.
+    let forever = alice.send_reliable(&session, send, MTU, b"forever", u64::MAX).unwrap();
+    let handle = alice.send_reliable(&session, send, MTU, b"request", 100).unwrap();
+    // The retransmission timers start when the context is next serviced.
+    alice.service(&sim.alice, |_: &Arc<Session<SimCrypto>>| Some((send, MTU)));
+    // The sender acknowledges the data it is retransmitting, as if the response had just arrived.
+    sim.clock.set(sim.now() + 100);
+    let resends = Cell::new(0);
+    let send_to = |_: &Arc<Session<SimCrypto>>| {
+        let send = |packet: &mut [u8]| {
+            resends.set(resends.get() + 1);
+            assert!(handle.acknowledge(handle.seq_no()));
+            sim.to_bob.send(packet)
+        };
+        Some((send, MTU))
+    };
+    alice.service(&sim.alice, send_to);
+    assert_eq!(resends.get(), 1);
+    assert!(!handle.is_pending());
+    assert!(forever.is_pending());
+}
+
+#[test]
+fn test_session_debug_is_redacted() {
+    let sim = Sim::new(13, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let session = sim.alice.session.borrow().clone().unwrap();
+    let debug = format!("{:?}", session);
+    assert!(debug.contains("state: S2"), "{}", debug);
+    let fingerprint = format!("{:?}", session.ratchet_states().state1.fingerprint());
+    assert!(!debug.contains(&fingerprint[1..fingerprint.len() - 1]), "{}", debug);
+    // Errors that hold a session can be debug printed.
+    session.expire();
+    let debug = format!("{:?}", ExpiredError(session));
+    assert!(debug.contains("state: Expired"), "{}", debug);
+}
+
+#[test]
+fn test_wait_established() {
+    use std::time::Duration;
+    let sim = Sim::new(14, LinkConfig::default());
+    sim.open();
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    assert_eq!(alice.wait_established(Duration::ZERO), Err(Duration::ZERO));
+    let waiter = {
+        let alice = alice.clone();
+        std::thread::spawn(move || alice.wait_established(Duration::from_secs(60)))
+    };
+    assert!(sim.run_until_established(1000));
+    assert!(waiter.join().unwrap().is_ok());
+    assert_eq!(alice.wait_established(Duration::ZERO), Ok(()));
+    let bob = sim.bob.session.borrow().clone().unwrap();
+    assert_eq!(bob.wait_established(Duration::ZERO), Ok(()));
+
+    // Waiting without a deadline returns early once the session expires.
+    let sim = Sim::new(15, LinkConfig::default());
+    sim.to_bob.unavailable.set(true);
+    sim.open();
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let waiter = {
+        let alice = alice.clone();
+        std::thread::spawn(move || alice.wait_established(Duration::MAX))
+    };
+    alice.expire();
+    assert_eq!(waiter.join().unwrap(), Err(Duration::MAX));
+    assert!(matches!(alice.wait_established(Duration::from_secs(60)), Err(left) if !left.is_zero()));
+}
+
+#[test]
+fn test_open_with_ratchet_skips_restore() {
+    let sim = Sim::new(15, LinkConfig::default());
+    // Both sides were given the same one-time-password over a side channel.
+    let otp_states = RatchetStates::new_otp_states::<CrateHmacSha512>(b"pairing code");
+    for peer in [&sim.alice, &sim.bob] {
+        peer.ratchets.borrow_mut().insert((), otp_states.clone());
+    }
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    let (session, _) = sim
+        .alice
+        .ctx
+        .open_with_ratchet(&sim.alice, send, MTU, sim.bob.public_key, (), &[], otp_states)
+        .unwrap();
+    *sim.alice.session.borrow_mut() = Some(session);
+    assert!(sim.run_until_established(1000));
+    assert_eq!(sim.alice.identity_restores.get(), 0);
+    assert_eq!(sim.alice.ratchet_count(), 2);
+    // The ratchet derived during the handshake was still saved.
+    let saved = sim.alice.ratchets.borrow().restore_by_identity(&()).unwrap();
+    assert_eq!(saved.state1.chain_len, 2);
+}
+
+#[test]
+fn test_send_does_not_lock_state() {
+    let sim = Sim::new(16, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let session = sim.alice.session.borrow().clone().unwrap();
+    let ctx = &sim.alice.ctx;
+    // Sending reads its keys from `data_keys` and its counter from an atomic, so it must finish
+    // while another thread holds the state lock, for example to rekey.
+    let state = session.state.write();
+    let (sent, recv) = std::sync::mpsc::channel();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let send = |_: &mut [u8]| true;
+            let _ = sent.send(ctx.send(&session, send, MTU, &mut [0u8; MTU], b"data"));
+        });
+        let result = recv.recv_timeout(std::time::Duration::from_secs(10));
+        // Unlocked before asserting, so a send that does wait cannot hang the test.
+        drop(state);
+        assert!(matches!(result, Ok(Ok(_))));
+    });
+}
+
+#[test]
+fn test_send_counter_exhaustion() {
+    use crate::proto::{REKEY_URGENTLY_AFTER_USES, THREAD_SAFE_COUNTER_HARD_EXPIRE};
+    use crate::result::SendError;
+    use crate::zeta::ZetaAutomata;
+    use std::sync::atomic::Ordering::Relaxed;
+    let sim = Sim::new(22, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let session = sim.alice.session.borrow().clone().unwrap();
+    let ctx = &sim.alice.ctx;
+    let send = |_: &mut [u8]| true;
+    let counter = session.send_counter();
+
+    // Only a few packets have been sent with the current key, so this is just past the limit.
+    let c = counter.load(Relaxed) + REKEY_URGENTLY_AFTER_USES + 1;
+    counter.store(c, Relaxed);
+    for _ in 0..3 {
+        let result = ctx.send(&session, send, MTU, &mut [0u8; MTU], b"data");
+        assert_eq!(result, Err(SendError::RekeyUrgentlyNeeded));
+    }
+    assert_eq!(counter.load(Relaxed), c);
+    assert!(!session.is_expired());
+    // The rekey starts once Alice is serviced, with the uses left for control packets.
+    assert_eq!(ctx.next_service_time(), i64::MIN);
+    sim.alice.next_service.set(sim.now());
+    sim.advance_time(1);
+    assert!(matches!(&session.state.read().beta, ZetaAutomata::R1 { .. }));
+
+    // Sending is refused without moving the counter any closer to wrapping.
+    let c = THREAD_SAFE_COUNTER_HARD_EXPIRE + 1;
+    counter.store(c, Relaxed);
+    for _ in 0..3 {
+        let result = ctx.send(&session, send, MTU, &mut [0u8; MTU], b"data");
+        assert_eq!(result, Err(SendError::SessionExpired));
+    }
+    assert_eq!(counter.load(Relaxed), c);
+    assert!(session.is_expired());
+}
+
+#[test]
+fn test_streamed_identity() {
+    use crate::proto::IDENTITY_MAX_SIZE;
+    // Too large for the handshake completion, so it is streamed in three identity continuations.
+    let identity: Vec<u8> = (0..3 * IDENTITY_MAX_SIZE as u32).map(|i| (i % 251) as u8).collect();
+    let lossy = LinkConfig { loss: 0.2, duplicate: 0.2, latency: 10, jitter: 20 };
+    for (seed, config) in [(17, LinkConfig::default()), (18, lossy), (19, lossy)] {
+        let sim = Sim::new(seed, config);
+        sim.open_with_identity(&identity);
+        assert!(sim.run_until_established(60_000));
+        assert_eq!(sim.bob.accepted_identities.borrow().last(), Some(&identity));
+        assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+    }
+    // Identities that fit are still sent in the handshake completion alone.
+    let sim = Sim::new(20, LinkConfig::default());
+    sim.open_with_identity(&identity[..IDENTITY_MAX_SIZE]);
+    assert!(sim.run_until_established(1000));
+    let accepted = sim.bob.accepted_identities.borrow();
+    assert_eq!(accepted[..], [identity[..IDENTITY_MAX_SIZE].to_vec()]);
+}
+
+#[cfg(feature = "deflate")]
+#[test]
+fn test_compressed_identity() {
+    use crate::proto::IDENTITY_MAX_SIZE;
+    // Would be streamed as is, but compresses to fit in the handshake completion.
+    let text = "CN=node.example.com,O=Example\n"
+        .repeat(3 * IDENTITY_MAX_SIZE / 30)
+        .into_bytes();
+    // Still streamed once compressed.
+    let mut noisy = vec![0u8; IDENTITY_MAX_SIZE];
+    SeededRng::new(21).fill_bytes(&mut noisy);
+    noisy.extend(&text);
+    let lossy = LinkConfig { loss: 0.2, duplicate: 0.2, latency: 10, jitter: 20 };
+    for identity in [&text, &noisy] {
+        let mut delivered = [0; 2];
+        for (i, compress) in [false, true].into_iter().enumerate() {
+            let sim = Sim::new(22, LinkConfig::default());
+            sim.alice.compress_identity.set(compress);
+            sim.open_with_identity(identity);
+            assert!(sim.run_until_established(1000));
+            assert_eq!(sim.bob.accepted_identities.borrow()[..], [identity.to_vec()]);
+            delivered[i] = sim.to_bob.delivered();
+        }
+        assert!(delivered[1] < delivered[0]);
+
+        let sim = Sim::new(23, lossy);
+        sim.alice.compress_identity.set(true);
+        sim.open_with_identity(identity);
+        assert!(sim.run_until_established(60_000));
+        assert_eq!(sim.bob.accepted_identities.borrow().last(), Some(identity));
+        assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+    }
+}
+
+#[test]
+fn test_errors_carry_remote_address() {
+    use crate::result::ReceiveErrorReport;
+    use std::net::SocketAddr;
+    let address: SocketAddr = "192.0.2.1:9993".parse().unwrap();
+    let sim = Sim::new(24, LinkConfig::default());
+    let bob = &sim.bob;
+    let send = |_: &mut [u8]| true;
+    let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+
+    let result = bob
+        .ctx
+        .receive(bob, send, MTU, send_to, &address, vec![0u8; 8], &mut Vec::new());
+    let Err(ReceiveError::ByzantineFault(fault)) = result else {
+        panic!("a truncated packet should be a fault");
+    };
+    assert_eq!(fault.error, FaultType::InvalidPacket);
+    assert_eq!(fault.remote_address, Some(address));
+
+    sim.open();
+    *bob.incoming_session.borrow_mut() = IncomingSessionAction::Drop;
+    let mut result = None;
+    while let Some(fragment) = sim.to_bob.recv() {
+        let mut data = Vec::new();
+        result = Some(bob.ctx.receive(bob, send, MTU, send_to, &address, fragment, &mut data));
+    }
+    let Some(Err(e)) = result else {
+        panic!("the hello should have been dropped");
+    };
+    assert!(matches!(e, ReceiveError::Rejected(Some(a)) if a == address));
+    let report = ReceiveErrorReport::from(&e);
+    assert_eq!(report, ReceiveErrorReport::Rejected(Some("192.0.2.1:9993".to_string())));
+}
+
+#[test]
+fn test_export_import_round_trip() {
+    use crate::crypto::AES_256_KEY_SIZE;
+    use crate::proto::{PROTOCOL_VERSION, SEQUENCE_NUMBER_SIZE};
+    use crate::result::{ExportError, ImportError, SendError};
+    use crate::zeta::CHECKPOINT_COUNTER_SKIP;
+    use crate::{receive_sequence_number, ExportMode, RestoredSession};
+    let master_key = [7u8; AES_256_KEY_SIZE];
+    let mut sim = Sim::new(25, LinkConfig::default());
+    sim.alice.rekey_timing.set(Some((1 << 40, 1 << 20)));
+    sim.open();
+    let alice_session = sim.alice.session.borrow().clone().unwrap();
+    let e = sim
+        .alice
+        .ctx
+        .export_session_state(&alice_session, &master_key, ExportMode::Checkpoint);
+    assert_eq!(e, Err(ExportError::SessionNotEstablished));
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(10);
+    let bob_session = sim.bob.session.borrow().clone().unwrap();
+    assert_eq!(alice_session.agreed_protocol_version(), PROTOCOL_VERSION);
+    assert_eq!(bob_session.agreed_protocol_version(), PROTOCOL_VERSION);
+    // This is the first handshake between these peers, so no ratchet key was used.
+    assert_eq!(alice_session.peer_ratchet_fingerprint(), None);
+    assert_eq!(bob_session.peer_ratchet_fingerprint(), None);
+
+    let (seq, _) = alice_session
+        .send_ordered(|packet: &mut [u8]| sim.to_bob.send(packet), MTU, b"first")
+        .unwrap();
+    assert_eq!(seq, 0);
+    sim.advance_time(1);
+    assert_eq!(
+        receive_sequence_number(&sim.bob.received.take()[0]),
+        Some((0, &b"first"[..]))
+    );
+
+    alice_session.set_mtu_hint(1000);
+    let uses_until_rekey = alice_session.uses_until_rekey();
+    let blob = sim
+        .alice
+        .ctx
+        .export_session_state(&alice_session, &master_key, ExportMode::Migrate);
+    let blob = blob.unwrap();
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    let e = sim.alice.ctx.send(&alice_session, send, MTU, &mut [0u8; MTU], b"stale");
+    assert!(matches!(e, Err(SendError::SessionExpired)));
+
+    // Resume the session in a fresh context, without a new handshake.
+    sim.alice.restart();
+    let (alice_session, _) = sim.alice.ctx.import_session_state(&blob, &master_key, ()).unwrap();
+    assert_eq!(alice_session.agreed_protocol_version(), PROTOCOL_VERSION);
+    assert_eq!(alice_session.mtu_hint(), 1000);
+    assert_eq!(alice_session.uses_until_rekey(), uses_until_rekey);
+    // A second session imported from the same blob would reuse its nonces.
+    let e = sim.alice.ctx.import_session_state(&blob, &master_key, ()).err();
+    assert_eq!(e, Some(ImportError::AlreadyImported));
+    *sim.alice.session.borrow_mut() = Some(alice_session.clone());
+    assert!(sim.send(true, b"hello bob"));
+    sim.advance_time(1);
+    assert_eq!(sim.bob.received.take(), [b"hello bob"]);
+    assert!(Arc::ptr_eq(sim.bob.session.borrow().as_ref().unwrap(), &bob_session));
+    // Sequence numbers carry on from before the export, and ordered data can span fragments.
+    let data = [3u8; MTU * 2];
+    let (seq, _) = alice_session
+        .send_ordered(|packet: &mut [u8]| sim.to_bob.send(packet), MTU, &data)
+        .unwrap();
+    assert_eq!(seq, 1);
+    sim.advance_time(1);
+    assert_eq!(
+        receive_sequence_number(&sim.bob.received.take()[0]),
+        Some((1, &data[..]))
+    );
+    assert_eq!(receive_sequence_number(&[0u8; SEQUENCE_NUMBER_SIZE - 1]), None);
+
+    assert!(sim.send(false, b"hello alice"));
+    sim.advance_time(1);
+    assert_eq!(sim.alice.received.take(), [b"hello alice"]);
+
+    // Restart Alice again, restoring the session along with the context.
+    let ratchet_states = alice_session.ratchet_states();
+    let blob = sim
+        .alice
+        .ctx
+        .export_session_state(&alice_session, &master_key, ExportMode::Migrate);
+    let blob = blob.unwrap();
+    let restored = |ratchet_states| RestoredSession {
+        static_remote_key: sim.bob.public_key,
+        ratchet_states,
+        session_data: (),
+        exported_state: blob.clone(),
+    };
+    let stale = RatchetStates::new_otp_states::<CrateHmacSha512>(b"stale");
+    let restored_sessions = [restored(stale), restored(ratchet_states)];
+    let (ctx, sessions) = Context::new_with_existing_ratchets(
+        sim.alice.static_secret(),
+        sim.alice.context_rng(),
+        &master_key,
+        restored_sessions,
+    );
+    sim.alice.ctx = ctx;
+    let mut sessions = sessions.into_iter();
+    assert_eq!(sessions.next().unwrap().err(), Some(ImportError::RatchetMismatch));
+    let alice_session = sessions.next().unwrap().unwrap();
+    assert!(alice_session.established());
+    *sim.alice.session.borrow_mut() = Some(alice_session.clone());
+    assert!(sim.send(true, b"restarted"));
+    sim.advance_time(1);
+    assert_eq!(sim.bob.received.take(), [b"restarted"]);
+    assert!(Arc::ptr_eq(sim.bob.session.borrow().as_ref().unwrap(), &bob_session));
+
+    // Checkpoint the session while it keeps running, then lose the context as if it crashed.
+    let checkpoint = sim
+        .alice
+        .ctx
+        .export_session_state(&alice_session, &master_key, ExportMode::Checkpoint);
+    let checkpoint = checkpoint.unwrap();
+    assert!(sim.send(true, b"unsaved"));
+    sim.advance_time(1);
+    assert_eq!(sim.bob.received.take(), [b"unsaved"]);
+    let ratchet_count = alice_session.ratchet_count();
+    sim.alice.restart();
+
+    let (alice_session, _) = sim
+        .alice
+        .ctx
+        .import_session_state(&checkpoint, &master_key, ())
+        .unwrap();
+    assert!(alice_session.established());
+    *sim.alice.session.borrow_mut() = Some(alice_session.clone());
+    // The send counter skipped past the packet sent after the checkpoint, so Bob accepts this.
+    assert!(sim.send(true, b"recovered"));
+    // The restored session rekeys as soon as it is serviced.
+    sim.advance_time(10);
+    assert_eq!(sim.bob.received.take(), [b"recovered"]);
+    assert_eq!(alice_session.ratchet_count(), ratchet_count + 1);
+    let e = sim.alice.ctx.import_session_state(&checkpoint, &master_key, ()).err();
+    assert_eq!(e, Some(ImportError::AlreadyImported));
+
+    // The running session stops sending data halfway through the counters a checkpoint reserved,
+    // and rekeys so that it can carry on with a key the checkpoint does not have.
+    let counter = alice_session.send_counter().load(Ordering::Relaxed);
+    sim.alice
+        .ctx
+        .export_session_state(&alice_session, &master_key, ExportMode::Checkpoint)
+        .unwrap();
+    let data_limit = counter + CHECKPOINT_COUNTER_SKIP / 2;
+    alice_session.send_counter().store(data_limit - 1, Ordering::Relaxed);
+    assert!(sim.send(true, b"last"));
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    let e = sim
+        .alice
+        .ctx
+        .send(&alice_session, send, MTU, &mut [0u8; MTU], b"reserved");
+    assert!(matches!(e, Err(SendError::RekeyUrgentlyNeeded)));
+    sim.advance_time(1000);
+    assert_eq!(sim.bob.received.take(), [b"last"]);
+    assert_eq!(alice_session.ratchet_count(), ratchet_count + 2);
+    assert!(sim.send(true, b"rekeyed"));
+    sim.advance_time(1);
+    assert_eq!(sim.bob.received.take(), [b"rekeyed"]);
+}
+
+#[test]
+fn test_serialize_state() {
+    use crate::result::ImportError;
+    let mut sim = Sim::new(45, LinkConfig::default());
+    sim.open();
+    let alice_session = sim.alice.session.borrow().clone().unwrap();
+    assert_eq!(alice_session.serialize_state(), None);
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(10);
+
+    // A checkpoint is recovered after a crash just like an encrypted one.
+    let checkpoint = alice_session.serialize_state().unwrap();
+    assert!(sim.send(true, b"unsaved"));
+    sim.advance_time(1);
+    assert_eq!(sim.bob.received.take(), [b"unsaved"]);
+    let ratchet_count = alice_session.ratchet_count();
+    sim.alice.restart();
+
+    let (alice_session, _) = sim.alice.ctx.deserialize_state(&checkpoint, ()).unwrap();
+    assert!(alice_session.established());
+    *sim.alice.session.borrow_mut() = Some(alice_session.clone());
+    assert!(sim.send(true, b"recovered"));
+    sim.advance_time(10);
+    assert_eq!(sim.bob.received.take(), [b"recovered"]);
+    assert_eq!(alice_session.ratchet_count(), ratchet_count + 1);
+    let e = sim.alice.ctx.deserialize_state(&checkpoint, ()).err();
+    assert_eq!(e, Some(ImportError::AlreadyImported));
+    let e = sim.alice.ctx.deserialize_state(&checkpoint[..4], ()).err();
+    assert_eq!(e, Some(ImportError::InvalidBlob));
+}
+
+#[test]
+fn test_data_keys_across_rekeys() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    const SENDERS: usize = 4;
+    const RECEIVERS: usize = 2;
+    const REKEYS: u64 = 10;
+    const MAX_BACKLOG: usize = 256;
+    // Rekey after 100 key uses, long before the time based schedule.
+    let rekey_timing = Some((SimCrypto::SETTINGS.rekey_after_time, 100));
+    let sim = Sim::new(26, LinkConfig::default());
+    sim.alice.rekey_timing.set(rekey_timing);
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice_session = sim.alice.session.borrow().clone().unwrap();
+    let bob_session = sim.bob.session.borrow().clone().unwrap();
+    let (alice, bob) = (&sim.alice.ctx, &sim.bob.ctx);
+    // Advanced by the thread driving the rekeys, so lost rekey packets are eventually resent.
+    let clock = AtomicI64::new(sim.now());
+    let app = ThreadedApp { clock: &clock, rekey_timing };
+    let (to_alice, to_bob) = (ThreadedLink::default(), ThreadedLink::default());
+    // Receives one packet queued on `inbox`, returning `None` if there was none.
+    let deliver_one = |ctx: &Context<SimCrypto>, inbox: &ThreadedLink, outbox: &ThreadedLink| {
+        let packet = inbox.recv()?;
+        let mut data = Vec::new();
+        let send = |packet: &mut [u8]| outbox.send(packet);
+        let result = ctx.receive(
+            app,
+            send,
+            MTU,
+            |_: &Arc<Session<SimCrypto>>| Some((send, MTU)),
+            &(),
+            packet,
+            &mut data,
+        );
+        Some(match result {
+            Ok((ReceiveOk::Associated(session, event), _)) => Some((session, event, data)),
+            _ => None,
+        })
+    };
+
+    // Alice sends data from several threads and Bob receives it on several threads, while the
+    // session is rekeyed over and over. Each packet is encrypted with whichever keys were current
+    // when it was sent, so a torn read of the keys would show up as a packet failing to decrypt.
+    let target_ratchet_count = alice_session.ratchet_count() + REKEYS;
+    let senders_done = AtomicUsize::new(0);
+    let data_received = AtomicUsize::new(0);
+    // Threads count failures instead of panicking, so the other threads are not left waiting.
+    let failures = AtomicUsize::new(0);
+    let receivers_done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        for i in 0..SENDERS {
+            let (alice_session, to_bob) = (&alice_session, &to_bob);
+            let (senders_done, failures) = (&senders_done, &failures);
+            s.spawn(move || {
+                let mut seq = 0u32;
+                while alice_session.ratchet_count() < target_ratchet_count {
+                    // Keep the backlog short so rekey packets are not queued behind too much data.
+                    if to_bob.len() > MAX_BACKLOG {
+                        std::thread::yield_now();
+                        continue;
+                    }
+                    let mut payload = vec![i as u8];
+                    payload.extend(seq.to_le_bytes());
+                    seq += 1;
+                    let send = |packet: &mut [u8]| to_bob.send(packet);
+                    if alice.send(alice_session, send, MTU, &mut [0u8; MTU], &payload).is_err() {
+                        failures.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                }
+                senders_done.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        for _ in 0..RECEIVERS {
+            let (to_bob, to_alice) = (&to_bob, &to_alice);
+            let (data_received, receivers_done, failures) = (&data_received, &receivers_done, &failures);
+            s.spawn(move || loop {
+                match deliver_one(bob, to_bob, to_alice) {
+                    Some(Some((_, SessionEvent::Data, data))) => {
+                        if data.len() != 5 || data[0] as usize >= SENDERS {
+                            failures.fetch_add(1, Ordering::Relaxed);
+                        }
+                        data_received.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Some(_) => {}
+                    None if receivers_done.load(Ordering::Relaxed) => return,
+                    None => std::thread::yield_now(),
+                }
+            });
+        }
+        // Drive the rekeys, Alice starts one whenever enough data has been sent.
+        // Control packets can be rejected by Bob's replay window if they are overtaken by too much
+        // data, in which case they are resent once enough time has passed.
+        while senders_done.load(Ordering::Relaxed) < SENDERS || to_bob.len() > 0 {
+            clock.fetch_add(1, Ordering::Relaxed);
+            while deliver_one(alice, &to_alice, &to_bob).is_some() {}
+            alice.service(app, |_: &Arc<Session<SimCrypto>>| {
+                Some((|p: &mut [u8]| to_bob.send(p), MTU))
+            });
+            bob.service(app, |_: &Arc<Session<SimCrypto>>| {
+                Some((|p: &mut [u8]| to_alice.send(p), MTU))
+            });
+            std::thread::yield_now();
+        }
+        receivers_done.store(true, Ordering::Relaxed);
+    });
+
+    assert_eq!(failures.load(Ordering::Relaxed), 0);
+    assert!(alice_session.ratchet_count() >= target_ratchet_count);
+    assert!(data_received.load(Ordering::Relaxed) > 0);
+    for session in [&alice_session, &bob_session] {
+        let faults = session.fault_stats();
+        assert_eq!(faults.pre_auth.failed_auth + faults.post_auth.failed_auth, 0);
+    }
+}
+
+#[test]
+fn test_concurrent_replay() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Barrier;
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 50;
+    let sim = Sim::new(30, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice_session = sim.alice.session.borrow().clone().unwrap();
+    let bob = &sim.bob.ctx;
+    let clock = AtomicI64::new(sim.now());
+    let app = ThreadedApp { clock: &clock, rekey_timing: None };
+
+    // Every thread receives the same authenticated packet at the same time, as if the network
+    // duplicated it, and only one of them may deliver it.
+    for round in 0..ROUNDS {
+        let mut packet = Vec::new();
+        let send = |p: &mut [u8]| {
+            packet = p.to_vec();
+            true
+        };
+        let payload = (round as u32).to_le_bytes();
+        sim.alice
+            .ctx
+            .send(&alice_session, send, MTU, &mut [0u8; MTU], &payload)
+            .unwrap();
+        let delivered = AtomicUsize::new(0);
+        let barrier = Barrier::new(THREADS);
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                let (packet, delivered, barrier) = (&packet, &delivered, &barrier);
+                s.spawn(move || {
+                    let packet = packet.clone();
+                    let mut data = Vec::new();
+                    let send = |_: &mut [u8]| true;
+                    let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+                    barrier.wait();
+                    let result = bob.receive(app, send, MTU, send_to, &(), packet, &mut data);
+                    match result {
+                        Ok((ReceiveOk::Associated(_, SessionEvent::Data), _)) => {
+                            assert_eq!(data, payload);
+                            delivered.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(ReceiveError::ByzantineFault(fault)) => {
+                            assert_eq!(fault.error, FaultType::ExpiredCounter);
+                        }
+                        _ => panic!("unexpected result for a replayed packet"),
+                    }
+                });
+            }
+        });
+        assert_eq!(delivered.load(Ordering::Relaxed), 1, "round {}", round);
+    }
+}
+
+#[test]
+fn test_resends_do_not_allocate() {
+    const RESENDS: usize = 4;
+    let sim = Sim::new(27, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(10);
+    let ratchet_count = sim.alice.ratchet_count();
+    let lose_everything = |link: &Link| *link.script.borrow_mut() = Some(Box::new(|_| false));
+    // Services `peer` as if the packets it sends over `outbox` were lost, until it has resent them a
+    // few times, then lets them through. Returns the number of allocations made while resending.
+    let resend = |peer: &Peer, outbox: &Link| {
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((|packet: &mut [u8]| outbox.send(packet), MTU));
+        let mut allocations = 0;
+        for _ in 0..RESENDS {
+            let sent = outbox.sent();
+            sim.clock.set(sim.now() + SimCrypto::SETTINGS.resend_time as i64);
+            allocations += count_allocations(|| peer.ctx.service(peer, send_to)).1;
+            assert!(outbox.sent() > sent);
+        }
+        // Delivering the packet is what allocates, so this resend is not counted.
+        *outbox.script.borrow_mut() = None;
+        sim.clock.set(sim.now() + SimCrypto::SETTINGS.resend_time as i64);
+        peer.ctx.service(peer, send_to);
+        allocations
+    };
+
+    // Alice starts a rekey, and each of K1, K2 and C1 is lost a few times before it gets through.
+    lose_everything(&sim.to_bob);
+    let settings = SimCrypto::SETTINGS;
+    let rekey_time = settings.rekey_after_time + settings.rekey_time_max_jitter;
+    sim.clock.set(sim.now() + rekey_time as i64);
+    sim.alice.poll(&sim.to_alice, &sim.to_bob);
+    let mut allocations = resend(&sim.alice, &sim.to_bob);
+    lose_everything(&sim.to_alice);
+    sim.bob.poll(&sim.to_bob, &sim.to_alice);
+    allocations += resend(&sim.bob, &sim.to_alice);
+    lose_everything(&sim.to_bob);
+    sim.alice.poll(&sim.to_alice, &sim.to_bob);
+    allocations += resend(&sim.alice, &sim.to_bob);
+    sim.bob.poll(&sim.to_bob, &sim.to_alice);
+    sim.alice.poll(&sim.to_alice, &sim.to_bob);
+
+    assert_eq!(sim.alice.ratchet_count(), ratchet_count + 1);
+    assert_eq!(allocations, 0);
+}
+
+#[test]
+fn test_service_budgeted() {
+    const SESSIONS: usize = 10;
+    let sim = Sim::new(28, LinkConfig::default());
+    let rng = &mut SeededRng::new(28);
+    // None of the hellos are answered, so every session will want to resend at the same time.
+    let _sessions: Vec<_> = (0..SESSIONS)
+        .map(|_| {
+            let remote = random_public_key(rng);
+            sim.alice
+                .ctx
+                .open(&sim.alice, |_: &mut [u8]| true, MTU, remote, (), &[])
+                .unwrap()
+                .0
+        })
+        .collect();
+
+    let serviced = Cell::new(0);
+    let send_to = |_: &Arc<Session<SimCrypto>>| {
+        serviced.set(serviced.get() + 1);
+        Some((|_: &mut [u8]| true, MTU))
+    };
+    sim.clock.set(SimCrypto::SETTINGS.resend_time as i64);
+    let mut calls = Vec::new();
+    loop {
+        serviced.set(0);
+        let (interval, more) = sim.alice.ctx.service_budgeted(&sim.alice, send_to, 3);
+        calls.push(serviced.get());
+        if !more {
+            assert!(interval > 0);
+            break;
+        }
+        assert_eq!(interval, 0);
+    }
+    assert_eq!(calls, [3, 3, 3, 1]);
+    // Once caught up, the default service has nothing left to do.
+    serviced.set(0);
+    sim.alice.ctx.service(&sim.alice, send_to);
+    assert_eq!(serviced.get(), 0);
+}
+
+#[test]
+fn test_mtu_hint() {
+    use crate::proto::{v1::HEADERED_HANDSHAKE_HELLO_CHALLENGE_SIZE, HEADER_SIZE, MIN_TRANSPORT_MTU};
+    let sim = Sim::new(29, LinkConfig::default());
+    let remote = random_public_key(&mut SeededRng::new(29));
+    let (session, _) = sim
+        .alice
+        .ctx
+        .open(&sim.alice, |_: &mut [u8]| true, MTU, remote, (), &[])
+        .unwrap();
+    assert_eq!(session.mtu_hint(), MIN_TRANSPORT_MTU);
+    session.set_mtu_hint(600);
+    assert_eq!(session.mtu_hint(), 600);
+
+    // The hello is resent, fragmented to the hint because `send_to` does not know the MTU.
+    let fragments = RefCell::new(Vec::new());
+    let send_to = |_: &Arc<Session<SimCrypto>>| {
+        let send = |fragment: &mut [u8]| {
+            fragments.borrow_mut().push(fragment.len());
+            true
+        };
+        Some((send, 0))
+    };
+    sim.clock.set(SimCrypto::SETTINGS.resend_time as i64);
+    sim.alice.ctx.service(&sim.alice, send_to);
+    let fragments = fragments.into_inner();
+    assert!(fragments.len() > 1);
+    assert!(fragments.iter().all(|len| *len <= 600));
+    // Every fragment after the first carries its own copy of the header.
+    let headers = (fragments.len() - 1) * HEADER_SIZE;
+    let total = fragments.iter().sum::<usize>();
+    assert_eq!(total, HEADERED_HANDSHAKE_HELLO_CHALLENGE_SIZE + headers);
+
+    session.set_mtu_hint(0);
+    assert_eq!(session.mtu_hint(), MIN_TRANSPORT_MTU);
+}
+
+#[test]
+fn test_handshake_refragmented() {
+    const SMALL_MTU: usize = 300;
+    let sim = Sim::new(43, LinkConfig::default());
+    // The hello is first sent at the full MTU and lost.
+    let first = RefCell::new(Vec::new());
+    let (session, _) = sim
+        .alice
+        .ctx
+        .open(
+            &sim.alice,
+            |fragment: &mut [u8]| {
+                first.borrow_mut().push(fragment.len());
+                true
+            },
+            MTU,
+            sim.bob.public_key,
+            (),
+            &[],
+        )
+        .unwrap();
+    *sim.alice.session.borrow_mut() = Some(session);
+    let first = first.into_inner();
+    assert!(first.iter().any(|len| *len > SMALL_MTU));
+
+    // By the time it is resent the path MTU has shrunk, and `service` fragments it to fit.
+    let resent = RefCell::new(Vec::new());
+    let send_to = |_: &Arc<Session<SimCrypto>>| {
+        let send = |fragment: &mut [u8]| {
+            resent.borrow_mut().push(fragment.len());
+            sim.to_bob.send(fragment)
+        };
+        Some((send, SMALL_MTU))
+    };
+    sim.clock.set(SimCrypto::SETTINGS.resend_time as i64);
+    sim.alice.ctx.service(&sim.alice, send_to);
+    let resent = resent.into_inner();
+    assert!(resent.len() > first.len());
+    assert!(resent.iter().all(|len| *len <= SMALL_MTU));
+    assert!(sim.run_until_established(1000));
+}
+
+#[test]
+fn test_payload_sink() {
+    use arrayvec::ArrayVec;
+    /// A sink with a fixed capacity and its own error type, which is not an `io::Write`.
+    struct Capped(ArrayVec<u8, 16>);
+    #[derive(Debug, PartialEq)]
+    struct Full;
+    impl core::fmt::Display for Full {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.write_str("full")
+        }
+    }
+    impl PayloadSink for &mut Capped {
+        type Error = Full;
+        fn write_payload(&mut self, data: &[u8]) -> Result<(), Full> {
+            self.0.try_extend_from_slice(data).map_err(|_| Full)
+        }
+    }
+    /// Bob receives the next packet Alice sent into `sink`.
+    fn deliver<S: PayloadSink>(sim: &Sim, sink: S) -> Result<ReceiveOk<SimCrypto>, ReceiveError<SimCrypto, S::Error>> {
+        let packet = sim.to_bob.recv().unwrap();
+        let send = |packet: &mut [u8]| sim.to_alice.send(packet);
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+        let result = sim.bob.ctx.receive(&sim.bob, send, MTU, send_to, &(), packet, sink);
+        result.map(|(ok, _)| ok)
+    }
+    let sim = Sim::new(30, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(10);
+    let bob_session = sim.bob.session.borrow().clone().unwrap();
+
+    // A slice is advanced past the payload, so what is left of it shows how much was used.
+    let mut buffer = [0u8; 32];
+    let mut unused = &mut buffer[..];
+    assert!(sim.send(true, b"hello world"));
+    let result = deliver(&sim, &mut unused);
+    assert!(matches!(result, Ok(ReceiveOk::Associated(_, SessionEvent::Data))));
+    let used = 32 - unused.len();
+    assert_eq!(&buffer[..used], b"hello world");
+    // A payload that does not fit into the slice is dropped with an error.
+    assert!(sim.send(true, &[7u8; 33]));
+    match deliver(&sim, &mut buffer[..]) {
+        Err(ReceiveError::WriteError(e, session)) => {
+            assert_eq!(e.kind(), std::io::ErrorKind::WriteZero);
+            assert!(Arc::ptr_eq(&session, &bob_session));
+        }
+        _ => panic!("expected a write error"),
+    }
+
+    // The error type of a custom sink is passed through unchanged.
+    let mut capped = Capped(ArrayVec::new());
+    assert!(sim.send(true, &[1u8; 16]));
+    assert!(deliver(&sim, &mut capped).is_ok());
+    assert_eq!(capped.0.as_slice(), &[1u8; 16]);
+    assert!(sim.send(true, &[2u8; 1]));
+    match deliver(&sim, &mut capped) {
+        Err(ReceiveError::WriteError(e, _)) => assert_eq!(e, Full),
+        _ => panic!("expected a write error"),
+    }
+}
+
+#[test]
+fn test_receive_borrowed() {
+    let sim = Sim::new(31, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(10);
+    let alice_session = sim.alice.session.borrow().clone().unwrap();
+    // Bob receives the next packet Alice sent, counting the owned copies made of it.
+    let copies = Cell::new(0u64);
+    let deliver = |data: &mut Vec<u8>| {
+        let mut packet = sim.to_bob.recv().unwrap();
+        let send = |packet: &mut [u8]| sim.to_alice.send(packet);
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+        let take_ownership = |fragment: &[u8]| {
+            copies.set(copies.get() + 1);
+            fragment.to_vec()
+        };
+        let options = ReceiveOptions::default();
+        let result = sim.bob.ctx.receive_borrowed(
+            &sim.bob,
+            send,
+            MTU,
+            send_to,
+            &(),
+            options,
+            &mut packet,
+            take_ownership,
+            data,
+        );
+        result.map(|(ok, _)| ok).ok()
+    };
+
+    // A complete packet is decrypted where it lies.
+    let mut data = Vec::new();
+    assert!(sim.send(true, b"unfragmented"));
+    assert!(matches!(deliver(&mut data), Some(ReceiveOk::Associated(_, SessionEvent::Data))));
+    assert_eq!(data, b"unfragmented");
+    assert_eq!(copies.get(), 0);
+
+    // Each fragment of a larger packet is copied exactly once so it can be stored.
+    let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    let sent = sim.to_bob.sent();
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    sim.alice
+        .ctx
+        .send(&alice_session, send, 300, &mut [0u8; MTU], &payload)
+        .unwrap();
+    let fragments = sim.to_bob.sent() - sent;
+    assert!(fragments > 1);
+    let mut data = Vec::new();
+    for i in 1..fragments {
+        assert!(matches!(deliver(&mut data), Some(ReceiveOk::Fragment(_))));
+        assert_eq!(copies.get(), i);
+    }
+    assert!(matches!(deliver(&mut data), Some(ReceiveOk::Associated(_, SessionEvent::Data))));
+    assert_eq!(copies.get(), fragments);
+    assert_eq!(data, payload);
+}
+
+#[test]
+fn test_receive_owned() {
+    use crate::proto::HEADER_SIZE;
+    const FRAGMENT_MTU: usize = 300;
+    let sim = Sim::new(32, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(10);
+    let alice_session = sim.alice.session.borrow().clone().unwrap();
+    // Alice sends `payload` to Bob with the given MTU, and Bob receives every fragment of it.
+    let round_trip = |payload: &[u8], mtu: usize| {
+        let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+        sim.alice
+            .ctx
+            .send(&alice_session, send, mtu, &mut [0u8; MTU], payload)
+            .unwrap();
+        let mut received = None;
+        while let Some(packet) = sim.to_bob.recv() {
+            let send = |packet: &mut [u8]| sim.to_alice.send(packet);
+            let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+            let options = ReceiveOptions::default();
+            let result = sim.bob.ctx.receive_owned(&sim.bob, send, MTU, send_to, &(), options, packet);
+            match result {
+                Ok((ReceiveOk::Fragment(_), _, None)) => assert!(received.is_none()),
+                Ok((ReceiveOk::Associated(_, SessionEvent::Data), _, Some(data))) => received = Some(data),
+                _ => panic!("unexpected result for a data packet"),
+            }
+        }
+        received.unwrap()
+    };
+
+    for payload in [&b""[..], b"x", b"a single fragment"] {
+        assert_eq!(round_trip(payload, MTU), payload);
+    }
+    // Cover payloads that end on either side of fragment boundaries, so the plaintext of the last
+    // fragment is cut in every position relative to the tag.
+    let boundary = 2 * (FRAGMENT_MTU - HEADER_SIZE);
+    for len in boundary - 40..boundary + 40 {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        assert_eq!(round_trip(&payload, FRAGMENT_MTU), payload, "length {}", len);
+    }
+}
+
+#[test]
+fn test_send_keepalive() {
+    use crate::proto::MIN_TRANSPORT_MTU;
+    use crate::result::SendError;
+    let sim = Sim::new(35, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(10);
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let bob = sim.bob.session.borrow().clone().unwrap();
+
+    // The keepalive is delivered as empty data, and a duplicate of it is rejected as a replay.
+    let counter = alice.send_counter().load(Ordering::Relaxed);
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet) && sim.to_bob.send(packet);
+    assert_eq!(alice.send_keepalive(send, MTU), Ok(false));
+    assert_eq!(alice.send_counter().load(Ordering::Relaxed), counter + 1);
+    let rejected = bob.replay_stats().rejected;
+    sim.advance_time(1);
+    assert_eq!(sim.bob.received.take(), [Vec::<u8>::new()]);
+    assert_eq!(bob.replay_stats().rejected, rejected + 1);
+
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    assert_eq!(alice.send_keepalive(send, MIN_TRANSPORT_MTU - 1), Err(SendError::MtuTooSmall));
+}
+
+#[test]
+fn test_choose_rekey_timing() {
+    let sim = Sim::new(31, LinkConfig::default());
+    // Alice rekeys after a second or 10 key uses, while Bob keeps the default schedule of 3
+    // seconds, minus up to a second of jitter.
+    sim.alice.rekey_timing.set(Some((1000, 10)));
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let ratchet_count = sim.alice.ratchet_count();
+
+    // Jitter is limited to half of Alice's schedule, so nothing happens for half a second, and
+    // Bob's schedule is still a second away when Alice rekeys.
+    let start = sim.now();
+    while sim.alice.ratchet_count() == ratchet_count && sim.now() < start + 2000 {
+        sim.advance_time(1);
+    }
+    assert!((start + 490..=start + 1010).contains(&sim.now()));
+    sim.advance_time(10);
+    assert_eq!(sim.alice.ratchet_count(), ratchet_count + 1);
+    assert_eq!(sim.bob.ratchet_count(), ratchet_count + 1);
+
+    // Alice's new key is due after 10 uses instead of the default 2^30.
+    for _ in 0..12 {
+        assert!(sim.send(true, b"data"));
+    }
+    // The rekey is started by the next call to `service`.
+    sim.alice.next_service.set(sim.now());
+    sim.advance_time(10);
+    assert_eq!(sim.alice.ratchet_count(), ratchet_count + 2);
+}
+
+#[test]
+fn test_version_unsupported() {
+    use crate::proto::{
+        HEADERED_VERSION_UNSUPPORTED_SIZE, HEADER_SIZE, KID_SIZE, MAX_VERSION_UNSUPPORTED_RATE, PROTOCOL_VERSION,
+    };
+    let sim = Sim::new(32, LinkConfig::default());
+    let receive = |peer: &Peer, outbox: &Link, packet: Vec<u8>| {
+        let send = |packet: &mut [u8]| outbox.send(packet);
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+        let result = peer.ctx.receive(peer, send, MTU, send_to, &(), packet, Vec::new());
+        result.map(|(ok, _)| ok)
+    };
+    sim.open();
+    let alice_session = sim.alice.session.borrow().clone().unwrap();
+    // Pretend Alice speaks a version from the future. The version follows her key id in the clear.
+    let mut hello: Vec<_> = std::iter::from_fn(|| sim.to_bob.recv()).collect();
+    hello[0][HEADER_SIZE + KID_SIZE] = PROTOCOL_VERSION + 1;
+    let deliver_hello = || {
+        let mut result = Ok(ReceiveOk::Unassociated);
+        for fragment in &hello {
+            result = receive(&sim.bob, &sim.to_alice, fragment.clone());
+        }
+        result
+    };
+    assert!(matches!(deliver_hello(), Err(ReceiveError::Rejected(_))));
+    let reply = sim.to_alice.recv().unwrap();
+    assert_eq!(reply.len(), HEADERED_VERSION_UNSUPPORTED_SIZE);
+
+    // A reply that does not repeat the counter of the hello is not accepted.
+    let mut forged = reply.clone();
+    forged[HEADER_SIZE - 1] ^= 1;
+    let result = receive(&sim.alice, &sim.to_bob, forged);
+    assert!(matches!(result, Err(ReceiveError::ByzantineFault(_))));
+    match receive(&sim.alice, &sim.to_bob, reply) {
+        Ok(ReceiveOk::VersionUnsupported(session, min_version, max_version)) => {
+            assert!(Arc::ptr_eq(&session, &alice_session));
+            assert_eq!(min_version, SimCrypto::SETTINGS.min_accepted_version);
+            assert_eq!(max_version, PROTOCOL_VERSION);
+        }
+        _ => panic!("expected version unsupported"),
+    }
+    // The reply is unauthenticated, so the handshake is still in progress.
+    assert!(!alice_session.is_expired());
+    assert!(sim.to_bob.recv().is_none());
+
+    // Replies are rate limited.
+    for _ in 0..2 * MAX_VERSION_UNSUPPORTED_RATE {
+        assert!(deliver_hello().is_err());
+    }
+    let replies = std::iter::from_fn(|| sim.to_alice.recv()).count();
+    assert_eq!(replies as u64, MAX_VERSION_UNSUPPORTED_RATE - 1);
+}
+
+#[test]
+fn test_version_downgrade() {
+    use crate::proto::PROTOCOL_VERSION;
+    use crate::result::OpenError;
+    let sim = Sim::new(33, LinkConfig::default());
+    let handshake = || {
+        let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+        let (session, _) = sim.alice.ctx.open(&sim.alice, send, MTU, sim.bob.public_key, (), &[])?;
+        *sim.alice.session.borrow_mut() = Some(session.clone());
+        sim.advance_time(10);
+        Ok::<_, OpenError>(session.established())
+    };
+    let min_version = |peer: &Peer| {
+        peer.ratchets
+            .borrow()
+            .restore_by_identity(&())
+            .unwrap()
+            .state1
+            .min_version()
+    };
+    // Pretend a later version was negotiated before, so the current version is a downgrade.
+    let raise_floor = |peer: &Peer| {
+        let mut ratchets = peer.ratchets.borrow_mut();
+        let mut states = ratchets.restore_by_identity(&()).unwrap();
+        states.state1 = states.state1.clone().with_min_version(PROTOCOL_VERSION + 1);
+        ratchets.insert((), states);
+    };
+
+    // Both sides record the version of the first handshake as the floor of their new ratchet state.
+    assert!(handshake().unwrap());
+    assert_eq!(min_version(&sim.alice), PROTOCOL_VERSION);
+    assert_eq!(min_version(&sim.bob), PROTOCOL_VERSION);
+
+    raise_floor(&sim.bob);
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    let result = sim.alice.ctx.open(&sim.alice, send, MTU, sim.bob.public_key, (), &[]);
+    assert!(result.is_ok());
+    let mut result = Ok(ReceiveOk::Unassociated);
+    while let Some(packet) = sim.to_bob.recv() {
+        let send = |packet: &mut [u8]| sim.to_alice.send(packet);
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+        let received = sim
+            .bob
+            .ctx
+            .receive(&sim.bob, send, MTU, send_to, &(), packet, Vec::new());
+        result = received.map(|(ok, _)| ok);
+    }
+    assert!(matches!(result, Err(ReceiveError::Rejected(_))));
+    assert!(sim.to_alice.recv().is_none());
+    sim.bob.allow_version_downgrade.set(true);
+    assert!(handshake().unwrap());
+
+    // The floor is carried forward into the ratchet states created by the handshake.
+    assert_eq!(min_version(&sim.bob), PROTOCOL_VERSION + 1);
+
+    raise_floor(&sim.alice);
+    assert!(matches!(handshake(), Err(OpenError::VersionDowngrade)));
+    assert!(sim.to_bob.recv().is_none());
+    sim.alice.allow_version_downgrade.set(true);
+    assert!(handshake().unwrap());
+    assert_eq!(min_version(&sim.alice), PROTOCOL_VERSION + 1);
+}
+
+#[test]
+fn test_incoming_session_with_address() {
+    // Hellos from this address are dropped.
+    const BLOCKED: u32 = 2;
+    let sim = Sim::new(34, LinkConfig::default());
+    sim.bob.blocked_address.set(Some(BLOCKED));
+    sim.open();
+    let hello: Vec<_> = std::iter::from_fn(|| sim.to_bob.recv()).collect();
+    let deliver_hello = |remote_address: u32| {
+        let mut result = Ok(ReceiveOk::Unassociated);
+        for fragment in &hello {
+            let send = |packet: &mut [u8]| sim.to_alice.send(packet);
+            let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+            let bob = &sim.bob;
+            let received = bob
+                .ctx
+                .receive(bob, send, MTU, send_to, &remote_address, fragment.clone(), Vec::new());
+            result = received.map(|(ok, _)| ok);
+        }
+        result
+    };
+    assert!(matches!(deliver_hello(BLOCKED), Err(ReceiveError::Rejected(_))));
+    assert!(sim.to_alice.recv().is_none());
+    assert!(matches!(deliver_hello(1), Ok(ReceiveOk::Unassociated)));
+    assert!(sim.to_alice.recv().is_some());
+}
+
+#[test]
+fn test_open_errors() {
+    use crate::proto::{MIN_TRANSPORT_MTU, PROTOCOL_VERSION};
+    use crate::result::OpenError;
+    let sim = Sim::new(35, LinkConfig::default());
+    let open = |mtu: usize, remote_key: CrateP384PublicKey, identity: &[u8]| {
+        let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+        let result = sim.alice.ctx.open(&sim.alice, send, mtu, remote_key, (), identity);
+        result.map(|(session, _)| session)
+    };
+    let bob_key = sim.bob.public_key;
+    let max_size = Settings::MAX_STREAMED_IDENTITY_SIZE;
+
+    let e = open(MIN_TRANSPORT_MTU, bob_key, &vec![1; max_size + 1]);
+    assert!(matches!(e, Err(OpenError::IdentityTooLarge)));
+    let e = open(MIN_TRANSPORT_MTU - 1, bob_key, &[]);
+    assert!(matches!(e, Err(OpenError::MtuTooSmall)));
+    let e = open(MTU, sim.alice.public_key, &[]);
+    assert!(matches!(e, Err(OpenError::InvalidRemoteKey)));
+    sim.alice.storage_unavailable.set(true);
+    match open(MTU, bob_key, &[]) {
+        Err(OpenError::StorageReadError(e)) => assert_eq!(e.to_string(), "storage unavailable"),
+        _ => panic!("expected a storage read error"),
+    }
+    sim.alice.storage_unavailable.set(false);
+    // None of the failed calls created a session or sent anything.
+    assert_eq!(sim.alice.ctx.statistics().sessions_opened, 0);
+    assert_eq!(sim.to_bob.sent(), 0);
+
+    // The limits themselves are accepted.
+    let session = open(MIN_TRANSPORT_MTU, bob_key, &vec![1; max_size]).unwrap();
+    *sim.alice.session.borrow_mut() = Some(session);
+    assert!(sim.run_until_established(5000));
+
+    // Pretend a later version was negotiated with Bob before, so a new session would be a downgrade.
+    let mut ratchets = sim.alice.ratchets.borrow_mut();
+    let mut states = ratchets.restore_by_identity(&()).unwrap();
+    states.state1 = states.state1.clone().with_min_version(PROTOCOL_VERSION + 1);
+    ratchets.insert((), states);
+    drop(ratchets);
+    assert!(matches!(open(MTU, bob_key, &[]), Err(OpenError::VersionDowngrade)));
+}
+
+#[test]
+fn test_standalone() {
+    use crate::crypto::AES_GCM_TAG_SIZE;
+    /// Returns the plaintext, or `None` if the data failed to authenticate.
+    fn decrypt(
+        session: &Session<SimCrypto>,
+        hint: u64,
+        aad: &[u8],
+        data: &[u8],
+        tag: &[u8; AES_GCM_TAG_SIZE],
+    ) -> Option<Vec<u8>> {
+        let mut data = data.to_vec();
+        let authentic = session.decrypt_standalone(hint, aad, &mut data, tag).unwrap();
+        authentic.then_some(data)
+    }
+    let sim = Sim::new(36, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let bob = sim.bob.session.borrow().clone().unwrap();
+    for (from, to) in [(&alice, &bob), (&bob, &alice)] {
+        let mut data = b"out of band".to_vec();
+        let tag = from.encrypt_standalone(7, b"aad", &mut data).unwrap();
+        assert_ne!(data, b"out of band");
+        assert_eq!(decrypt(to, 7, b"aad", &data, &tag).unwrap(), b"out of band");
+        // Decryption is not protected against replays.
+        assert!(decrypt(to, 7, b"aad", &data, &tag).is_some());
+        // Only the exact ciphertext, nonce hint and aad authenticate, and only for the other side.
+        assert!(decrypt(to, 8, b"aad", &data, &tag).is_none());
+        assert!(decrypt(to, 7, b"", &data, &tag).is_none());
+        let mut tampered = data.clone();
+        tampered[0] ^= 1;
+        assert!(decrypt(to, 7, b"aad", &tampered, &tag).is_none());
+        assert!(decrypt(from, 7, b"aad", &data, &tag).is_none());
+    }
+
+    // Once both sides have rekeyed, data encrypted under the old key no longer decrypts.
+    let mut old = b"before the rekey".to_vec();
+    let old_tag = alice.encrypt_standalone(1, b"", &mut old).unwrap();
+    let ratchet_count = alice.ratchet_count();
+    while alice.ratchet_count() == ratchet_count || bob.ratchet_count() == ratchet_count || !alice.established() {
+        sim.advance_time(100);
+    }
+    assert!(decrypt(&bob, 1, b"", &old, &old_tag).is_none());
+    let mut data = b"after the rekey".to_vec();
+    let tag = alice.encrypt_standalone(1, b"", &mut data).unwrap();
+    assert_eq!(decrypt(&bob, 1, b"", &data, &tag).unwrap(), b"after the rekey");
+}
+
+#[test]
+fn test_rotate_kid() {
+    use crate::result::SendError;
+    let sim = Sim::new(37, LinkConfig::default());
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let bob = sim.bob.session.borrow().clone().unwrap();
+    let old_kid = alice.local_session_id().unwrap();
+    assert_eq!(bob.remote_session_id(), Some(old_kid));
+    let deliver_to_alice = |packet: Vec<u8>| {
+        let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+        let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+        let (ctx, mut data) = (&sim.alice.ctx, Vec::new());
+        let result = ctx.receive(&sim.alice, send, MTU, send_to, &(), packet, &mut data);
+        result.map(|(result, _)| (result, data))
+    };
+
+    // Bob does not hear of the rotation at first.
+    *sim.to_bob.script.borrow_mut() = Some(Box::new(|_| false));
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    let (new_kid, _) = sim.alice.ctx.rotate_kid(&sim.alice, &alice, send, MTU).unwrap();
+    assert_ne!(new_kid, old_kid);
+    assert_eq!(alice.local_session_id(), Some(new_kid));
+    let again = sim.alice.ctx.rotate_kid(&sim.alice, &alice, send, MTU);
+    assert_eq!(again.err(), Some(SendError::KeyExchangeInProgress));
+    // During the grace period Alice accepts packets addressed to either key id.
+    assert!(sim.send(false, b"grace"));
+    assert!(sim.send(false, b"stale"));
+    let (grace, stale) = (sim.to_alice.recv().unwrap(), sim.to_alice.recv().unwrap());
+    match deliver_to_alice(grace) {
+        Ok((ReceiveOk::Associated(session, SessionEvent::Data), data)) => {
+            assert!(Arc::ptr_eq(&session, &alice));
+            assert_eq!(data, b"grace");
+        }
+        _ => panic!("a packet addressed to the old key id was not accepted"),
+    }
+    assert!(Arc::ptr_eq(&sim.alice.ctx.get_session_by_kid(old_kid).unwrap(), &alice));
+    assert!(Arc::ptr_eq(&sim.alice.ctx.get_session_by_kid(new_kid).unwrap(), &alice));
+
+    // The rotation is resent until Bob acknowledges it, which ends the grace period.
+    *sim.to_bob.script.borrow_mut() = None;
+    sim.advance_time(1000);
+    assert_eq!(bob.remote_session_id(), Some(new_kid));
+    assert!(sim.alice.ctx.get_session_by_kid(old_kid).is_none());
+    match deliver_to_alice(stale) {
+        Err(ReceiveError::ByzantineFault(fault)) => assert_eq!(fault.error, FaultType::UnknownLocalKeyId),
+        _ => panic!("a packet addressed to the old key id was accepted after the grace period"),
+    }
+    assert!(sim.send(false, b"new kid"));
+    sim.advance_time(10);
+    assert_eq!(sim.alice.received.borrow().last().unwrap(), b"new kid");
+    // With the rotation complete another can be started.
+    let send = |packet: &mut [u8]| sim.to_bob.send(packet);
+    assert!(sim.alice.ctx.rotate_kid(&sim.alice, &alice, send, MTU).is_ok());
+}
+
+#[test]
+fn test_rekey_deferred() {
+    let sim = Sim::new(38, LinkConfig { latency: 5, ..LinkConfig::default() });
+    // Alice always rekeys first, so Bob is the one asked.
+    sim.alice.rekey_timing.set(Some((1000, u64::MAX)));
+    sim.bob.rekey_timing.set(Some((1 << 40, u64::MAX)));
+    sim.bob.rekey_action.set(RekeyAction::Defer);
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let ratchet_count = sim.alice.ratchet_count();
+    // The session survives being deferred for longer than `rekey_timeout`.
+    let duration = SimCrypto::SETTINGS.rekey_timeout as i64 + 10_000;
+    let sent = send_for(&sim, duration, 1000);
+    assert_eq!(sent as i64, duration / 1000);
+    assert!(sim.alice.established() && sim.bob.established());
+    assert_eq!(sim.alice.ratchet_count(), ratchet_count);
+    // Alice resends her request every `resend_time` and Bob is asked every time.
+    let requests = sim.bob.rekey_requests.take();
+    assert!(requests.len() as i64 >= duration / SimCrypto::SETTINGS.resend_time as i64 / 2);
+    assert!(requests.iter().all(|&count| count == ratchet_count));
+    assert_eq!(received_numbers(&sim.bob), (0..sent).collect::<Vec<_>>());
+
+    sim.bob.rekey_action.set(RekeyAction::Allow);
+    sim.advance_time(1000);
+    assert!(sim.alice.ratchet_count() > ratchet_count);
+    assert_eq!(sim.bob.ratchet_count(), sim.alice.ratchet_count());
+}
+
+#[test]
+fn test_rekey_rejected() {
+    let sim = Sim::new(39, LinkConfig { latency: 5, ..LinkConfig::default() });
+    sim.alice.rekey_timing.set(Some((1000, u64::MAX)));
+    sim.bob.rekey_timing.set(Some((1 << 40, u64::MAX)));
+    sim.bob.rekey_action.set(RekeyAction::Reject);
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let bob = sim.bob.session.borrow().clone().unwrap();
+    sim.advance_time(2000);
+    // Bob expires his session as soon as Alice asks to rekey, without telling her.
+    assert_eq!(sim.bob.rekey_requests.borrow().len(), 1);
+    assert!(bob.is_expired());
+    assert!(!alice.is_expired());
+    assert!(!sim.send(false, b"rejected"));
+    // Alice gives up once her rekey times out.
+    sim.advance_time(SimCrypto::SETTINGS.rekey_timeout as i64);
+    assert!(alice.is_expired());
+}
+
+#[test]
+fn test_rekey_key_ids_exhausted() {
+    let sim = Sim::new(46, LinkConfig { latency: 5, ..LinkConfig::default() });
+    sim.alice.rekey_timing.set(Some((1000, u64::MAX)));
+    sim.bob.rekey_timing.set(Some((1 << 40, u64::MAX)));
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    sim.advance_time(100);
+    let ratchet_count = sim.alice.ratchet_count();
+    // Occupy every key id Bob will try for a while, as if his context held billions of sessions.
+    let ctx = &sim.bob.ctx.0;
+    let start = ctx.kid_counter.load(Ordering::Relaxed);
+    let kids: Vec<NonZeroU32> = (start..start + (1 << 12))
+        .filter_map(|i| {
+            let mut block = [0u8; AES_256_BLOCK_SIZE];
+            block[..8].copy_from_slice(&i.to_ne_bytes());
+            ctx.kid_prp.encrypt_in_place(&mut block);
+            NonZeroU32::new(u32::from_ne_bytes(block[..KID_SIZE].try_into().unwrap()))
+        })
+        .filter(|kid| ctx.session_map.shard(*kid).write().insert(*kid, Weak::new()).is_none())
+        .collect();
+    // Only service Alice until she asks to rekey, so her request can be delivered by hand.
+    let sent = sim.to_bob.sent();
+    while sim.to_bob.sent() == sent {
+        sim.clock.set(sim.now() + 1);
+        sim.alice.poll(&sim.to_alice, &sim.to_bob);
+    }
+    sim.clock.set(sim.now() + 5);
+    let k1 = sim.to_bob.recv().unwrap();
+    let deferrals = sim.to_alice.sent();
+    let send = |packet: &mut [u8]| sim.to_alice.send(packet);
+    let send_to = |_: &Arc<Session<SimCrypto>>| Some((send, MTU));
+    let result = sim.bob.ctx.receive(&sim.bob, send, MTU, send_to, &(), k1, &mut Vec::new());
+    assert!(matches!(result, Err(ReceiveError::KeyIdsExhausted)));
+    // Bob asks Alice to retry later rather than leave her to time out.
+    assert_eq!(sim.to_alice.sent(), deferrals + 1);
+    sim.advance_time(4 * SimCrypto::SETTINGS.resend_time as i64);
+    assert!(sim.alice.established() && sim.bob.established());
+    assert_eq!(sim.alice.ratchet_count(), ratchet_count);
+    assert_eq!(sim.alice.unnatural_faults.get() + sim.bob.unnatural_faults.get(), 0);
+
+    for kid in kids {
+        ctx.session_map.remove(&kid);
+    }
+    sim.advance_time(1000);
+    assert!(sim.alice.ratchet_count() > ratchet_count);
+    assert_eq!(sim.bob.ratchet_count(), sim.alice.ratchet_count());
+}
+
+#[test]
+fn test_require_kyber() {
+    let sim = Sim::new(40, LinkConfig { latency: 5, ..LinkConfig::default() });
+    sim.alice.prefer_kyber.set(true);
+    sim.bob.require_kyber.set(true);
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let bob = sim.bob.session.borrow().clone().unwrap();
+    assert!(alice.is_post_quantum() && bob.is_post_quantum());
+    assert!(sim.send(true, b"post-quantum"));
+
+    let sim = Sim::new(41, LinkConfig { latency: 5, ..LinkConfig::default() });
+    sim.open();
+    assert!(sim.run_until_established(1000));
+    let alice = sim.alice.session.borrow().clone().unwrap();
+    let bob = sim.bob.session.borrow().clone().unwrap();
+    assert!(!alice.is_post_quantum() && !bob.is_post_quantum());
+
+    // Bob drops every hello without a Kyber public key, including the ones Alice sends each time
+    // her offer times out and she restarts the handshake.
+    let sim = Sim::new(42, LinkConfig { latency: 5, ..LinkConfig::default() });
+    sim.bob.require_kyber.set(true);
+    sim.open();
+    let timeout = SimCrypto::SETTINGS.initial_offer_timeout as i64;
+    assert!(!sim.run_until_established(2 * timeout));
+    assert!(sim.bob.session.borrow().is_none());
+}
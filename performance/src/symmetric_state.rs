@@ -1,5 +1,6 @@
-use std::marker::PhantomData;
+use core::marker::PhantomData;
 
+use arrayvec::ArrayVec;
 use zeroize::Zeroizing;
 
 use crate::application::CryptoLayer;
@@ -25,18 +26,53 @@ impl<C: CryptoLayer> Clone for SymmetricState<C> {
     }
 }
 
+/// HMAC-SHA512 key derivation based on KBKDF Counter Mode:
+/// https://csrc.nist.gov/publications/detail/sp/800-108/rev-1/final.
+/// These are the values we have assigned to the 4 variables involved in their KDF:
+/// * K_IN = `key`
+/// * Label = `label`
+/// * Context = `context`
+/// * L = `num_outputs*512u16`
+///
+/// We have intentionally made every input small and fixed size to avoid unnecessary complexity
+/// and data representation ambiguity.
+pub(crate) fn kbkdf<H: Sha512Hmac, const N: usize>(
+    hmac: &mut H,
+    key: &[u8],
+    label: &[u8; 4],
+    context: &[u8; N],
+    num_outputs: u16,
+    output1: &mut [u8; HASHLEN],
+    output2: Option<&mut [u8; HASHLEN]>,
+    output3: Option<&mut [u8; HASHLEN]>,
+) {
+    let mut buffer = Zeroizing::new(ArrayVec::<u8, { 8 + HASHLEN }>::new());
+    buffer.push(1);
+    buffer.extend(*label);
+    buffer.push(0x00);
+    buffer.try_extend_from_slice(context).unwrap();
+    buffer.extend((num_outputs * 8 * HASHLEN as u16).to_be_bytes());
+
+    debug_assert!(num_outputs >= 1);
+    hmac.hash(key, &buffer, output1);
+
+    if let Some(output2) = output2 {
+        debug_assert!(num_outputs >= 2);
+        buffer[0] = 2;
+        hmac.hash(key, &buffer, output2);
+    }
+
+    if let Some(output3) = output3 {
+        debug_assert!(num_outputs >= 3);
+        buffer[0] = 3;
+        hmac.hash(key, &buffer, output3);
+    }
+}
+
 impl<C: CryptoLayer> SymmetricState<C> {
-    /// HMAC-SHA512 key derivation based on KBKDF Counter Mode:
-    /// https://csrc.nist.gov/publications/detail/sp/800-108/rev-1/final.
+    /// `kbkdf` with `self.chaining_key` as the Context.
     /// Cryptographically this isn't meaningfully different from
     /// `HKDF(self.chaining_key, input_key_material)` but this is how NIST rolls.
-    /// These are the values we have assigned to the 4 variables involved in their KDF:
-    /// * K_IN = `input_key_material`
-    /// * Label = `label`
-    /// * Context = `self.chaining_key`
-    /// * L = `num_outputs*512u16`
-    /// We have intentionally made every input small and fixed size to avoid unnecessary complexity
-    /// and data representation ambiguity.
     /// Corresponds to Noise `HKDF`.
     fn kbkdf(
         &self,
@@ -48,32 +84,7 @@ impl<C: CryptoLayer> SymmetricState<C> {
         output2: Option<&mut [u8; HASHLEN]>,
         output3: Option<&mut [u8; HASHLEN]>,
     ) {
-        const LABEL_START: usize = 1;
-        const LABEL_END: usize = 5;
-        const CONTEXT_START: usize = 6;
-        const LEN_START: usize = 70;
-        const LEN_END: usize = 72;
-        let mut buffer = Zeroizing::new([0u8; LEN_END]);
-        buffer[0] = 1;
-        buffer[LABEL_START..LABEL_END].copy_from_slice(label);
-        buffer[LABEL_END] = 0x00;
-        buffer[CONTEXT_START..LEN_START].copy_from_slice(self.ck.as_ref());
-        buffer[LEN_START..LEN_END].copy_from_slice(&(num_outputs * 8 * HASHLEN as u16).to_be_bytes());
-
-        debug_assert!(num_outputs >= 1);
-        hmac.hash(input_key_material, buffer.as_ref(), output1);
-
-        if let Some(output2) = output2 {
-            debug_assert!(num_outputs >= 2);
-            buffer[0] = 2;
-            hmac.hash(input_key_material, buffer.as_ref(), output2);
-        }
-
-        if let Some(output3) = output3 {
-            debug_assert!(num_outputs >= 3);
-            buffer[0] = 3;
-            hmac.hash(input_key_material, buffer.as_ref(), output3);
-        }
+        kbkdf(hmac, input_key_material, label, &self.ck, num_outputs, output1, output2, output3);
     }
 
     /// Corresponds to Noise `Initialize` on a SymmetricState.
@@ -190,6 +201,34 @@ impl<C: CryptoLayer> SymmetricState<C> {
     pub fn split(self, hmac: &mut C::Hmac, key1: &mut [u8; HASHLEN], key2: &mut [u8; HASHLEN]) {
         self.kbkdf(hmac, &[], LABEL_KBKDF_CHAIN, 2, key1, Some(key2), None);
     }
+    /// A variant of Noise `Split` that produces three symmetric keys instead of two.
+    ///
+    /// This is intended for protocol extensions with more than two parties, where the third key
+    /// is shared by every party, for example as a group MAC key. Because `num_outputs` is part of
+    /// the KDF input, the first two keys are not the same as the keys `split` would have produced.
+    ///
+    /// Use this when the third key must be derived from the final chaining key alongside the
+    /// transport keys, so it is bound to the same point of the handshake. `get_ask` should be
+    /// preferred for any key that has a distinct purpose and label of its own, since it can be
+    /// called any number of times without consuming the state.
+    ///
+    /// The two party handshake does not use this, it exists for protocol extensions.
+    #[allow(unused)]
+    pub fn split3(
+        self,
+        hmac: &mut C::Hmac,
+    ) -> (
+        Zeroizing<[u8; AES_256_KEY_SIZE]>,
+        Zeroizing<[u8; AES_256_KEY_SIZE]>,
+        Zeroizing<[u8; AES_256_KEY_SIZE]>,
+    ) {
+        let mut key1 = Zeroizing::new([0u8; HASHLEN]);
+        let mut key2 = Zeroizing::new([0u8; HASHLEN]);
+        let mut key3 = Zeroizing::new([0u8; HASHLEN]);
+        self.kbkdf(hmac, &[], LABEL_KBKDF_CHAIN, 3, &mut key1, Some(&mut key2), Some(&mut key3));
+        let truncate = |key: Zeroizing<[u8; HASHLEN]>| Zeroizing::new(key[..AES_256_KEY_SIZE].try_into().unwrap());
+        (truncate(key1), truncate(key2), truncate(key3))
+    }
     /// Get an additional symmetric key (ASK) that is a collision resistant hash of the transcript,
     /// is forward secrect and is cryptographically independent from all other produced keys.
     /// Based on Noise's unstable ASK mechanism, using KBKDF instead of HKDF.
@@ -203,3 +242,25 @@ impl<C: CryptoLayer> SymmetricState<C> {
         (self.k[0], self.ck[0], self.h[0])
     }
 }
+
+#[test]
+fn test_split3() {
+    use crate::sim::SimCrypto;
+    let mut hmac = <SimCrypto as CryptoLayer>::Hmac::new();
+    let mut state = SymmetricState::<SimCrypto>::initialize(&[1u8; HASHLEN]);
+    state.mix_key(&mut hmac, &[2u8; 48]);
+
+    let (key1, key2, key3) = state.clone().split3(&mut hmac);
+    assert!(*key1 != *key2 && *key2 != *key3 && *key1 != *key3);
+    // The keys depend only on the state, so every party that reached it derives the same ones.
+    let again = state.clone().split3(&mut hmac);
+    assert_eq!((*key1, *key2, *key3), (*again.0, *again.1, *again.2));
+    // They are independent of the keys `split` derives from the same state.
+    let (mut split1, mut split2) = ([0u8; HASHLEN], [0u8; HASHLEN]);
+    state.split(&mut hmac, &mut split1, &mut split2);
+    for split in [&split1, &split2] {
+        for key in [&key1, &key2, &key3] {
+            assert_ne!(&split[..AES_256_KEY_SIZE], &key[..]);
+        }
+    }
+}
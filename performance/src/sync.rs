@@ -0,0 +1,40 @@
+#[cfg(feature = "std")]
+pub(crate) use arc_swap::ArcSwap;
+/// What `ArcSwap::load` returns.
+#[cfg(feature = "std")]
+pub(crate) type ArcSwapGuard<T> = arc_swap::Guard<alloc::sync::Arc<T>>;
+#[cfg(not(feature = "std"))]
+pub(crate) type ArcSwapGuard<T> = alloc::sync::Arc<T>;
+#[cfg(feature = "std")]
+pub(crate) use parking_lot::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(not(feature = "std"))]
+pub(crate) use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The atomic types of `core`, or of `portable_atomic` on targets without 64-bit atomics.
+pub(crate) mod atomic {
+    #[cfg(target_has_atomic = "64")]
+    pub(crate) use core::sync::atomic::*;
+    #[cfg(not(target_has_atomic = "64"))]
+    pub(crate) use portable_atomic::*;
+}
+
+/// A stand-in for `arc_swap::ArcSwap` built on a spin lock, since arc-swap requires `std`.
+///
+/// Readers only hold the lock long enough to clone the `Arc`.
+#[cfg(not(feature = "std"))]
+pub(crate) struct ArcSwap<T>(RwLock<alloc::sync::Arc<T>>);
+#[cfg(not(feature = "std"))]
+impl<T> ArcSwap<T> {
+    pub(crate) fn from_pointee(value: T) -> Self {
+        Self(RwLock::new(alloc::sync::Arc::new(value)))
+    }
+    pub(crate) fn load(&self) -> alloc::sync::Arc<T> {
+        self.0.read().clone()
+    }
+    pub(crate) fn store(&self, value: alloc::sync::Arc<T>) {
+        *self.0.write() = value;
+    }
+    pub(crate) fn swap(&self, value: alloc::sync::Arc<T>) -> alloc::sync::Arc<T> {
+        core::mem::replace(&mut *self.0.write(), value)
+    }
+}
@@ -0,0 +1,371 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::application::*;
+use crate::crypto::*;
+use crate::crypto_impl::*;
+use crate::result::{ReceiveOk, SessionEvent};
+use crate::{Context, Session};
+
+/// The MTU test vectors are produced with. It is large enough that no handshake packet is
+/// fragmented, so every packet of a vector is a single datagram.
+pub const VECTOR_MTU: usize = 4096;
+
+/// A test vector: the inputs of an initial handshake between Alice and Bob, and everything this
+/// implementation produces from them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestVector {
+    /// The inputs of the handshake.
+    pub inputs: VectorInputs,
+    /// The packets and keys that must be produced from `inputs`.
+    pub outputs: VectorOutputs,
+}
+
+/// Everything an initial handshake depends on.
+///
+/// P-384 private keys are 48 byte big-endian scalars. Kyber randomness is the bytes the Kyber1024
+/// reference implementation draws from its RNG, in order: 64 bytes for key generation, and 32
+/// bytes for encapsulation.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorInputs {
+    /// What the vector tests.
+    pub description: String,
+    /// The static private key of Alice.
+    #[serde(with = "hex")]
+    pub alice_static: Vec<u8>,
+    /// The static private key of Bob.
+    #[serde(with = "hex")]
+    pub bob_static: Vec<u8>,
+    /// The ephemeral private key of Alice's hello.
+    #[serde(with = "hex")]
+    pub alice_ephemeral: Vec<u8>,
+    /// The ephemeral private key of Bob's response.
+    #[serde(with = "hex")]
+    pub bob_ephemeral: Vec<u8>,
+    /// The randomness of Alice's Kyber key pair. If it is empty Alice does not use Kyber.
+    #[serde(with = "hex")]
+    pub alice_kyber_randomness: Vec<u8>,
+    /// The randomness of Bob's Kyber encapsulation. Must be empty if Alice does not use Kyber.
+    #[serde(with = "hex")]
+    pub bob_kyber_randomness: Vec<u8>,
+    /// The ratchet key both peers hold, or empty if they have never talked before.
+    #[serde(with = "hex")]
+    pub ratchet_key: Vec<u8>,
+    /// The fingerprint of `ratchet_key`, or empty if it is empty.
+    #[serde(with = "hex")]
+    pub ratchet_fingerprint: Vec<u8>,
+    /// The chain length of `ratchet_key`.
+    pub ratchet_chain_len: u64,
+    /// The identity Alice sends in her completion packet.
+    #[serde(with = "hex")]
+    pub identity: Vec<u8>,
+    /// Seeds the rest of the randomness of Alice's context, and plus one that of Bob's context.
+    /// This determines key ids and the filler of the challenge field of the hello, which an
+    /// independent implementation should take from the outputs instead.
+    pub context_seed: u64,
+}
+
+/// The packets and keys of an initial handshake, see `VectorInputs`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorOutputs {
+    /// The hello Alice sends.
+    #[serde(with = "hex")]
+    pub x1: Vec<u8>,
+    /// The response Bob sends.
+    #[serde(with = "hex")]
+    pub x2: Vec<u8>,
+    /// The completion Alice sends.
+    #[serde(with = "hex")]
+    pub x3: Vec<u8>,
+    /// The key confirmation Bob sends.
+    #[serde(with = "hex")]
+    pub c1: Vec<u8>,
+    /// The acknowledgement Alice sends.
+    #[serde(with = "hex")]
+    pub c2: Vec<u8>,
+    /// The keys of Alice's session once the handshake is complete.
+    pub alice: SessionKeys,
+    /// The keys of Bob's session once the handshake is complete.
+    pub bob: SessionKeys,
+    /// The ratchet key both peers save once the handshake is complete.
+    #[serde(with = "hex")]
+    pub ratchet_key: Vec<u8>,
+    /// The fingerprint of the new ratchet key.
+    #[serde(with = "hex")]
+    pub ratchet_fingerprint: Vec<u8>,
+    /// The chain length of the new ratchet key.
+    pub ratchet_chain_len: u64,
+}
+
+/// The key ids and keys of the current key of a session, see `Session::session_keys`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionKeys {
+    /// The key id the remote peer addresses this session with.
+    pub kid_recv: u32,
+    /// The key id this session addresses the remote peer with.
+    pub kid_send: u32,
+    /// The key that encrypts the headers of sent packets.
+    #[serde(with = "hex")]
+    pub hk_send: Vec<u8>,
+    /// The key that decrypts the headers of received packets.
+    #[serde(with = "hex")]
+    pub hk_recv: Vec<u8>,
+    /// The Noise key that encrypts sent data packets.
+    #[serde(with = "hex")]
+    pub nk_send: Vec<u8>,
+    /// The Noise key that decrypts received data packets.
+    #[serde(with = "hex")]
+    pub nk_recv: Vec<u8>,
+    /// The key exchange key that authenticates sent control packets.
+    #[serde(with = "hex")]
+    pub kek_send: Vec<u8>,
+    /// The key exchange key that authenticates received control packets.
+    #[serde(with = "hex")]
+    pub kek_recv: Vec<u8>,
+}
+
+/// An RNG that either replays fixed bytes, or generates a deterministic stream from a seed.
+/// It is predictable, so it must never be used outside of test vectors.
+pub struct VectorRng(Source);
+enum Source {
+    Replay(Vec<u8>),
+    Seeded(u64),
+}
+impl VectorRng {
+    /// An RNG that returns `bytes`, then panics if more are drawn.
+    pub fn replay(bytes: Vec<u8>) -> Self {
+        let mut bytes = bytes;
+        bytes.reverse();
+        Self(Source::Replay(bytes))
+    }
+    /// An RNG that returns the splitmix64 stream of `seed`.
+    pub fn seeded(seed: u64) -> Self {
+        Self(Source::Seeded(seed))
+    }
+}
+impl RngCore for VectorRng {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match &mut self.0 {
+            Source::Replay(bytes) => {
+                for byte in dest {
+                    *byte = bytes
+                        .pop()
+                        .expect("a test vector drew more randomness than it provides");
+                }
+            }
+            Source::Seeded(state) => {
+                for chunk in dest.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                    chunk.copy_from_slice(&(z ^ (z >> 31)).to_le_bytes()[..chunk.len()]);
+                }
+            }
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+impl CryptoRng for VectorRng {}
+
+struct VectorCrypto;
+impl CryptoLayer for VectorCrypto {
+    type Rng = VectorRng;
+    type PrpEnc = OpenSSLAes256Enc;
+    type PrpDec = OpenSSLAes256Dec;
+    type Aead = OpenSSLAesGcm;
+    type AeadPool = OpenSSLAesGcmPool;
+    type Hash = CrateSha512;
+    type Hmac = CrateHmacSha512;
+    type PublicKey = CrateP384PublicKey;
+    type KeyPair = CrateP384KeyPair;
+    type Kem = CrateKyber1024PrivateKey;
+
+    type SessionData = ();
+    type FingerprintData = ();
+    type IncomingPacketBuffer = Vec<u8>;
+}
+
+/// One side of the handshake. `&VectorApp` is its `ApplicationLayer`.
+struct VectorApp {
+    ratchet_state: Option<RatchetState>,
+    prefer_kyber: bool,
+    ephemeral_randomness: RefCell<Option<Vec<u8>>>,
+    saved: RefCell<Option<RatchetState>>,
+}
+impl ApplicationLayer<VectorCrypto> for &VectorApp {
+    fn time(&mut self) -> i64 {
+        0
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        IncomingSessionAction::Allow
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session<VectorCrypto>>) -> bool {
+        false
+    }
+    fn check_accept_session(&mut self, _: &CrateP384PublicKey, _: &[u8], _: Option<&()>) -> AcceptAction<VectorCrypto> {
+        AcceptAction {
+            session_data: Some(()),
+            responder_disallows_downgrade: false,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(&mut self, rf: &[u8; RATCHET_SIZE]) -> std::io::Result<Option<(RatchetState, ())>> {
+        let state = self.ratchet_state.as_ref().filter(|state| state.fingerprint_eq(rf));
+        Ok(state.map(|state| (state.clone(), ())))
+    }
+    fn restore_by_identity(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        Ok(self.ratchet_state.clone().map(|state| RatchetStates::new(state, None)))
+    }
+    fn save_ratchet_state(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        update: CompareAndSwap<'_>,
+    ) -> std::io::Result<bool> {
+        *self.saved.borrow_mut() = Some(update.to_new_states().state1);
+        Ok(true)
+    }
+    fn prefer_kyber(&mut self) -> bool {
+        self.prefer_kyber
+    }
+    fn ephemeral_rng(&mut self) -> Option<VectorRng> {
+        self.ephemeral_randomness.borrow_mut().take().map(VectorRng::replay)
+    }
+}
+
+fn key_pair(private_key: &[u8]) -> CrateP384KeyPair {
+    <CrateP384KeyPair as P384KeyPair<VectorRng>>::generate(&mut VectorRng::replay(private_key.to_vec()))
+}
+
+/// Run the initial handshake described by `inputs`, and return the packets and keys it produced.
+///
+/// Panics if the inputs are malformed, or if the handshake does not complete with exactly the five
+/// packets X1, X2, X3, C1 and C2.
+pub fn run_handshake(inputs: &VectorInputs) -> VectorOutputs {
+    let ratchet_state = (!inputs.ratchet_key.is_empty()).then(|| {
+        let key = inputs.ratchet_key[..].try_into().expect("invalid ratchet key");
+        let fingerprint = inputs.ratchet_fingerprint[..]
+            .try_into()
+            .expect("invalid ratchet fingerprint");
+        RatchetState::new_raw(key, fingerprint, inputs.ratchet_chain_len)
+    });
+    let app = |ephemeral: &[u8], kyber_randomness: &[u8]| VectorApp {
+        ratchet_state: ratchet_state.clone(),
+        prefer_kyber: !inputs.alice_kyber_randomness.is_empty(),
+        ephemeral_randomness: RefCell::new(Some([ephemeral, kyber_randomness].concat())),
+        saved: RefCell::new(None),
+    };
+    let alice_app = app(&inputs.alice_ephemeral, &inputs.alice_kyber_randomness);
+    let bob_app = app(&inputs.bob_ephemeral, &inputs.bob_kyber_randomness);
+    let bob_secret = key_pair(&inputs.bob_static);
+    let bob_public_key = <CrateP384KeyPair as P384KeyPair<VectorRng>>::public_key_bytes(&bob_secret);
+    let bob_public_key = CrateP384PublicKey::from_bytes(&bob_public_key).unwrap();
+    let alice = Context::<VectorCrypto>::new(key_pair(&inputs.alice_static), VectorRng::seeded(inputs.context_seed));
+    let bob = Context::<VectorCrypto>::new(bob_secret, VectorRng::seeded(inputs.context_seed.wrapping_add(1)));
+
+    // Every packet sent, and whether it was sent to Bob.
+    let sent = RefCell::new(Vec::<(bool, Vec<u8>)>::new());
+    let sender = |to_bob: bool| {
+        let sent = &sent;
+        move |packet: &mut [u8]| {
+            sent.borrow_mut().push((to_bob, packet.to_vec()));
+            true
+        }
+    };
+    let open = alice.open(
+        &alice_app,
+        sender(true),
+        VECTOR_MTU,
+        bob_public_key,
+        (),
+        &inputs.identity,
+    );
+    let (alice_session, _) = open.expect("Alice failed to open a session");
+    let mut bob_session = None;
+    let mut i = 0;
+    while i < sent.borrow().len() {
+        let (to_bob, packet) = sent.borrow()[i].clone();
+        let (ctx, app) = if to_bob {
+            (&bob, &bob_app)
+        } else {
+            (&alice, &alice_app)
+        };
+        let send_to = |_: &Arc<Session<VectorCrypto>>| Some((sender(!to_bob), VECTOR_MTU));
+        let result = ctx.receive(app, sender(!to_bob), VECTOR_MTU, send_to, &(), packet, &mut Vec::new());
+        if let Ok((ReceiveOk::Associated(session, SessionEvent::NewSession), _)) = result {
+            bob_session = Some(session);
+        }
+        i += 1;
+    }
+
+    let sent = sent.into_inner();
+    let directions: Vec<bool> = sent.iter().map(|(to_bob, _)| *to_bob).collect();
+    assert_eq!(
+        directions,
+        [true, false, true, false, true],
+        "the handshake did not complete as expected"
+    );
+    let mut packets = sent.into_iter().map(|(_, packet)| packet);
+    let ratchet_state = alice_app
+        .saved
+        .into_inner()
+        .expect("Alice did not save a ratchet state");
+    assert!(bob_app.saved.into_inner().as_ref() == Some(&ratchet_state));
+    VectorOutputs {
+        x1: packets.next().unwrap(),
+        x2: packets.next().unwrap(),
+        x3: packets.next().unwrap(),
+        c1: packets.next().unwrap(),
+        c2: packets.next().unwrap(),
+        alice: alice_session.session_keys().unwrap(),
+        bob: bob_session
+            .expect("Bob did not accept the session")
+            .session_keys()
+            .unwrap(),
+        ratchet_key: ratchet_state.key().to_vec(),
+        ratchet_fingerprint: ratchet_state.fingerprint().to_vec(),
+        ratchet_chain_len: ratchet_state.chain_len(),
+    }
+}
+
+/// Serializes byte strings as lowercase hex, which is easier to read and to use from other
+/// languages than an array of numbers.
+mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
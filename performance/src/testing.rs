@@ -0,0 +1,303 @@
+//! Two contexts connected in process, for testing applications against ZSSP without a network.
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use rand_core::OsRng;
+
+use crate::application::*;
+use crate::crypto::{P384KeyPair, P384PublicKey};
+use crate::crypto_impl::{CrateP384KeyPair, CrateP384PublicKey, DefaultCrypto};
+use crate::ratchet_storage::MemoryRatchetStore;
+use crate::result::{ReceiveError, ReceiveOk, SendError, SessionEvent};
+use crate::{Context, Session};
+
+/// The MTU of the loopback link in both directions.
+pub const LOOPBACK_MTU: usize = 1500;
+
+/// The `CryptoLayer` of both contexts of a `LoopbackPair`.
+pub struct LoopbackCrypto;
+impl DefaultCrypto for LoopbackCrypto {
+    type SessionData = ();
+    type IncomingPacketBuffer = Vec<u8>;
+}
+
+/// One of the two sides of a `LoopbackPair`. It is also the remote address that packets from
+/// this side arrive from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Side {
+    /// The side that opens the session in `LoopbackPair::establish`.
+    A,
+    /// The side that accepts the session.
+    B,
+}
+impl Side {
+    /// The other side.
+    pub fn peer(self) -> Side {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
+}
+
+/// A packet received by one side of a `LoopbackPair`, and what `Context::receive` made of it.
+pub struct Received {
+    /// The side that received the packet.
+    pub side: Side,
+    /// The return value of `Context::receive` for the packet.
+    pub result: Result<ReceiveOk<LoopbackCrypto>, ReceiveError<LoopbackCrypto, std::io::Error, Side>>,
+    /// The payload of the packet if it carried `SessionEvent::Data` or `SessionEvent::Control`,
+    /// otherwise empty.
+    pub data: Vec<u8>,
+}
+impl Received {
+    /// The session event of this packet, if it was associated with a session.
+    pub fn event(&self) -> Option<&SessionEvent> {
+        match &self.result {
+            Ok(ReceiveOk::Associated(_, event)) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+/// The packets in flight between the two sides, in the order they were sent.
+struct Wire {
+    in_flight: RefCell<VecDeque<(Side, Vec<u8>)>>,
+    drop_next: Cell<usize>,
+}
+impl Wire {
+    fn send(&self, to: Side, packet: &[u8]) -> bool {
+        match self.drop_next.get() {
+            0 => self.in_flight.borrow_mut().push_back((to, packet.to_vec())),
+            n => self.drop_next.set(n - 1),
+        }
+        true
+    }
+}
+
+/// One side of a `LoopbackPair`. `&Endpoint` is its `ApplicationLayer`.
+struct Endpoint {
+    ctx: Context<LoopbackCrypto>,
+    public_key: CrateP384PublicKey,
+    session: RefCell<Option<Arc<Session<LoopbackCrypto>>>>,
+    ratchets: RefCell<MemoryRatchetStore<()>>,
+    clock: Rc<Cell<i64>>,
+    next_service: Cell<i64>,
+}
+impl ApplicationLayer<LoopbackCrypto> for &Endpoint {
+    fn time(&mut self) -> i64 {
+        self.clock.get()
+    }
+    fn incoming_session(&mut self) -> IncomingSessionAction {
+        IncomingSessionAction::Allow
+    }
+    fn hello_requires_recognized_ratchet(&mut self) -> bool {
+        false
+    }
+    fn initiator_disallows_downgrade(&mut self, _: &Arc<Session<LoopbackCrypto>>) -> bool {
+        true
+    }
+    fn check_accept_session(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &[u8],
+        _: Option<&()>,
+    ) -> AcceptAction<LoopbackCrypto> {
+        AcceptAction {
+            session_data: Some(()),
+            responder_disallows_downgrade: true,
+            responder_silently_rejects: false,
+        }
+    }
+    fn restore_by_fingerprint(&mut self, rf: &[u8; RATCHET_SIZE]) -> std::io::Result<Option<(RatchetState, ())>> {
+        Ok(self.ratchets.borrow().restore_by_fingerprint(rf))
+    }
+    fn restore_by_identity(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        _: Option<&()>,
+    ) -> std::io::Result<Option<RatchetStates>> {
+        Ok(self.ratchets.borrow().restore_by_identity(&()))
+    }
+    fn save_ratchet_state(
+        &mut self,
+        _: &CrateP384PublicKey,
+        _: &(),
+        update: CompareAndSwap<'_>,
+    ) -> std::io::Result<bool> {
+        Ok(self.ratchets.borrow_mut().save_ratchet_state(&(), update))
+    }
+    fn prefer_kyber(&mut self) -> bool {
+        false
+    }
+}
+impl Endpoint {
+    fn new(clock: Rc<Cell<i64>>) -> Self {
+        let secret = CrateP384KeyPair::generate(&mut OsRng);
+        let public_key = <CrateP384KeyPair as P384KeyPair<OsRng>>::public_key_bytes(&secret);
+        Self {
+            ctx: Context::new(secret, OsRng),
+            public_key: CrateP384PublicKey::from_bytes(&public_key).unwrap(),
+            session: RefCell::new(None),
+            ratchets: RefCell::new(MemoryRatchetStore::new()),
+            clock,
+            next_service: Cell::new(0),
+        }
+    }
+}
+
+/// Two contexts that send their packets straight to each other, driven by a virtual clock.
+///
+/// Everything runs on the calling thread. Packets are delivered in the order they were sent by
+/// every call that sends them, and the clock only moves forward with `LoopbackPair::tick`, so the
+/// timers of both contexts, such as resends and rekeying, fire exactly when the test asks them to.
+///
+/// Both sides use the default cryptography without Kyber, accept every incoming session, and keep
+/// their ratchet states in memory.
+pub struct LoopbackPair {
+    a: Endpoint,
+    b: Endpoint,
+    wire: Wire,
+    clock: Rc<Cell<i64>>,
+}
+impl LoopbackPair {
+    /// Creates both sides with fresh static keys, at time 0.
+    pub fn new() -> Self {
+        let clock = Rc::new(Cell::new(0));
+        Self {
+            a: Endpoint::new(clock.clone()),
+            b: Endpoint::new(clock.clone()),
+            wire: Wire {
+                in_flight: RefCell::new(VecDeque::new()),
+                drop_next: Cell::new(0),
+            },
+            clock,
+        }
+    }
+    fn endpoint(&self, side: Side) -> &Endpoint {
+        match side {
+            Side::A => &self.a,
+            Side::B => &self.b,
+        }
+    }
+    /// The context of `side`.
+    pub fn context(&self, side: Side) -> &Context<LoopbackCrypto> {
+        &self.endpoint(side).ctx
+    }
+    /// The static public key of `side`.
+    pub fn public_key(&self, side: Side) -> &CrateP384PublicKey {
+        &self.endpoint(side).public_key
+    }
+    /// The latest session of `side`, if it has opened or accepted one.
+    pub fn session(&self, side: Side) -> Option<Arc<Session<LoopbackCrypto>>> {
+        self.endpoint(side).session.borrow().clone()
+    }
+    /// Whether both sides have an established session.
+    pub fn is_established(&self) -> bool {
+        let established = |side| self.session(side).is_some_and(|s| s.established());
+        established(Side::A) && established(Side::B)
+    }
+    /// The current virtual time in milliseconds.
+    pub fn now(&self) -> i64 {
+        self.clock.get()
+    }
+    /// Drops the next `n` packets sent by either side, in addition to any already set to be
+    /// dropped. This includes handshake packets and the fragments of larger packets.
+    pub fn drop_next_n_packets(&self, n: usize) {
+        self.wire.drop_next.set(self.wire.drop_next.get() + n);
+    }
+    /// A opens a session with B, and every packet of the handshake that is not dropped is
+    /// delivered. Returns the packets received by either side.
+    ///
+    /// If handshake packets were dropped the session is not established yet, and calling
+    /// `LoopbackPair::tick` lets the handshake resend them.
+    pub fn establish(&self) -> Vec<Received> {
+        let send = |packet: &mut [u8]| self.wire.send(Side::B, packet);
+        let (session, _) = self
+            .a
+            .ctx
+            .open(&self.a, send, LOOPBACK_MTU, self.b.public_key, (), &[])
+            .expect("opening a loopback session cannot fail");
+        *self.a.session.borrow_mut() = Some(session);
+        self.deliver()
+    }
+    /// Sends `data` from A to B over the session of A, and delivers it.
+    /// Returns the packets received by either side.
+    pub fn send_a_to_b(&self, data: &[u8]) -> Result<Vec<Received>, SendError> {
+        self.send(Side::A, data)
+    }
+    /// Sends `data` from B to A over the session of B, and delivers it.
+    /// Returns the packets received by either side.
+    pub fn send_b_to_a(&self, data: &[u8]) -> Result<Vec<Received>, SendError> {
+        self.send(Side::B, data)
+    }
+    fn send(&self, from: Side, data: &[u8]) -> Result<Vec<Received>, SendError> {
+        let session = self.session(from).ok_or(SendError::SessionNotEstablished)?;
+        let send = |packet: &mut [u8]| self.wire.send(from.peer(), packet);
+        let ctx = &self.endpoint(from).ctx;
+        ctx.send(&session, send, LOOPBACK_MTU, &mut [0u8; LOOPBACK_MTU], data)?;
+        Ok(self.deliver())
+    }
+    /// Moves the clock forward `ms` milliseconds. Both contexts are serviced every time one of them
+    /// asked to be, and the packets they send are delivered right away.
+    /// Returns the packets received by either side.
+    pub fn tick(&self, ms: i64) -> Vec<Received> {
+        let end = self.now() + ms;
+        let mut received = self.deliver();
+        loop {
+            let next = self.a.next_service.get().min(self.b.next_service.get());
+            if next > end {
+                break;
+            }
+            self.clock.set(self.now().max(next));
+            for side in [Side::A, Side::B] {
+                let endpoint = self.endpoint(side);
+                if endpoint.next_service.get() <= self.now() {
+                    let send = |packet: &mut [u8]| self.wire.send(side.peer(), packet);
+                    let send_to = |_: &Arc<Session<LoopbackCrypto>>| Some((send, LOOPBACK_MTU));
+                    let wait = endpoint.ctx.service(endpoint, send_to);
+                    endpoint.next_service.set(self.now() + wait.max(1));
+                }
+            }
+            received.extend(self.deliver());
+        }
+        self.clock.set(end);
+        received
+    }
+    /// Delivers packets until there are none left in flight.
+    fn deliver(&self) -> Vec<Received> {
+        let mut received = Vec::new();
+        loop {
+            let Some((to, packet)) = self.wire.in_flight.borrow_mut().pop_front() else {
+                break;
+            };
+            let endpoint = self.endpoint(to);
+            let send = |packet: &mut [u8]| self.wire.send(to.peer(), packet);
+            let send_to = |_: &Arc<Session<LoopbackCrypto>>| Some((send, LOOPBACK_MTU));
+            let mut data = Vec::new();
+            let result = endpoint
+                .ctx
+                .receive(endpoint, send, LOOPBACK_MTU, send_to, &to.peer(), packet, &mut data);
+            let result = result.map(|(result, next_service)| {
+                if let Some(time) = next_service {
+                    endpoint.next_service.set(endpoint.next_service.get().min(time));
+                }
+                if let ReceiveOk::Associated(session, SessionEvent::NewSession) = &result {
+                    *endpoint.session.borrow_mut() = Some(session.clone());
+                }
+                result
+            });
+            received.push(Received { side: to, result, data });
+        }
+        received
+    }
+}
+impl Default for LoopbackPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,53 @@
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::application::{ApplicationLayer, CryptoLayer, PayloadSink, SendTo};
+use crate::result::{ReceiveError, ReceiveOk};
+use crate::{Context, ReceiveOptions};
+
+/// The largest payload a UDP datagram can carry.
+const UDP_MAX_PAYLOAD_SIZE: usize = 65507;
+
+impl<C: CryptoLayer> Context<C>
+where
+    C::IncomingPacketBuffer: for<'a> From<&'a [u8]>,
+{
+    /// Receive a single datagram from `socket` and process it with `Context::receive_borrowed`.
+    ///
+    /// This blocks for as long as `socket.recv_from` does, so the timeout or non-blocking mode of
+    /// the socket determines how long this can wait for a datagram.
+    /// The datagram is received into a buffer on the stack, and is only copied into an owned
+    /// `CryptoLayer::IncomingPacketBuffer` if it is one fragment of a larger packet.
+    /// Replies to peers without a session are sent back through `socket`.
+    ///
+    /// The outer `Result` contains any error returned by the socket, in which case no packet was
+    /// processed. The inner `Result` is the result of processing the received packet.
+    /// It is enabled with the `udp` feature.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    /// * `socket` - The socket to receive from and to send unassociated replies on
+    /// * `mtu` - MTU for unassociated replies
+    /// * `send_to` - Function to get senders for existing sessions, permitting MTU and path lookup
+    /// * `output_buffer` - Sink to receive decrypted and authenticated object data
+    pub fn receive_udp_socket<App: ApplicationLayer<C>, S: PayloadSink>(
+        &self,
+        app: App,
+        socket: &UdpSocket,
+        mtu: usize,
+        send_to: impl SendTo<C>,
+        output_buffer: S,
+    ) -> std::io::Result<Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, S::Error, SocketAddr>>> {
+        let mut buffer = [0u8; UDP_MAX_PAYLOAD_SIZE];
+        let (len, remote_address) = socket.recv_from(&mut buffer)?;
+        Ok(self.receive_borrowed(
+            app,
+            |packet: &mut [u8]| socket.send_to(packet, remote_address).is_ok(),
+            mtu,
+            send_to,
+            &remote_address,
+            ReceiveOptions::default(),
+            &mut buffer[..len],
+            |fragment| fragment.into(),
+            output_buffer,
+        ))
+    }
+}
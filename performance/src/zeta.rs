@@ -1,28 +1,42 @@
-use std::cmp::Reverse;
-use std::collections::HashMap;
-use std::io::Write;
-use std::num::NonZeroU32;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
-use std::sync::{Arc, Weak};
-use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::hash::Hash;
+use core::num::NonZeroU32;
+use core::ops::{Deref, DerefMut, Range};
 
 use arrayvec::ArrayVec;
 use rand_core::RngCore;
 use zeroize::Zeroizing;
 
-use crate::antireplay::Window;
+use crate::antireplay::{AntiReplayWindow, ReplayStats, SessionWindow};
 use crate::application::*;
 use crate::challenge::{gen_null_response, respond_to_challenge_in_place};
+use crate::collections::HashMap;
 use crate::crypto::*;
-use crate::fragged::Fragged;
+use crate::fault_stats::{FaultCounters, FaultStats};
+use crate::fragged::{Assembled, FragmentBuffer, Fragged, SessionFragBuffer};
+use crate::handshake_cache::Eviction;
+#[cfg(feature = "deflate")]
+use crate::identity_encoding::encode_identity;
+use crate::identity_encoding::decode_identity;
 use crate::indexed_heap::BinaryHeapIndex;
+use crate::kex_stats::{KexStats, KexTimer};
+use crate::metrics::Metrics;
 use crate::proto::*;
 use crate::ratchet_state::{RatchetState, RatchetStates};
-use crate::result::{fault, FaultType, OpenError, ReceiveError, SendError};
+use crate::result::{fault, ExportError, FaultType, ImportError, OpenError, ReceiveError, SendError};
+use crate::session_map::KidMap;
 use crate::symmetric_state::SymmetricState;
-use crate::zssp::{log, ContextInner, SessionQueue};
-#[cfg(feature = "logging")]
+use crate::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use crate::sync::Condvar;
+use crate::sync::{ArcSwap, ArcSwapGuard, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::zssp::{capture, log, ContextInner, ExportMode, SessionQueue};
+#[cfg(any(feature = "logging", feature = "tracing"))]
 use crate::LogEvent::*;
 
 /// Corresponds to the Zeta State Machine found in Section 4.1.
@@ -36,17 +50,53 @@ pub struct Session<C: CryptoLayer> {
     /// This field is true if the local peer acted as Bob, the responder in the initial key exchange.
     pub was_bob: bool,
     queue_idx: BinaryHeapIndex,
+    parked: AtomicBool,
+    /// The time at which the initial key exchange of this session was started.
+    pub(crate) handshake_start_time: i64,
+    /// The protocol version agreed on during the initial key exchange.
+    /// This is 0 until Alice has received Bob's version.
+    proto_version: AtomicU8,
+    /// Whether data packets are encrypted with the misuse resistant AEAD of `C::AeadPool`, as
+    /// agreed on during the initial key exchange.
+    misuse_resistant: AtomicBool,
+    /// Whether the initial key exchange included the Kyber1024 key encapsulation.
+    post_quantum: AtomicBool,
 
     pub(crate) s_remote: C::PublicKey,
     send_counter: AtomicU64,
-
-    pub(crate) window: Window<COUNTER_WINDOW_MAX_OOO, COUNTER_WINDOW_MAX_SKIP_AHEAD>,
-    pub(crate) defrag: [Mutex<Fragged<C::IncomingPacketBuffer, MAX_FRAGMENTS>>; SESSION_MAX_FRAGMENTS_OOO],
+    /// The sequence number `Session::send_ordered` will send next.
+    ordered_send_counter: AtomicU64,
+    /// The last known path MTU to the remote peer, or 0 if it is unknown.
+    mtu_hint: AtomicUsize,
+    /// The number of uses after which the current key should be rekeyed, as chosen by
+    /// `ApplicationLayer::choose_rekey_timing` when the key was confirmed.
+    rekey_after_key_uses: AtomicU64,
+    /// The key creation counter of the key the last checkpoint of this session was taken with,
+    /// see `ExportMode::Checkpoint`.
+    checkpoint_key: AtomicU64,
+    /// The first counter reserved for the session restored from the last checkpoint. The key the
+    /// checkpoint was taken with must never be used with it or any counter after it.
+    checkpoint_limit: AtomicU64,
+
+    pub(crate) window: SessionWindow,
+    pub(crate) faults: FaultCounters,
+    pub(crate) kex: KexTimer,
+    pub(crate) defrag: [Mutex<SessionFragBuffer<C::IncomingPacketBuffer>>; SESSION_MAX_FRAGMENTS_OOO],
 
     /// `session_queue -> state_machine_lock -> state -> session_map`
     state_machine_lock: Mutex<()>,
     /// `session_queue -> state_machine_lock -> state -> session_map`
+    ///
+    /// Must only be write locked with `Session::write_state`, so `data_keys` stays up to date.
     pub(crate) state: RwLock<MutableState<C>>,
+    /// A copy of the parts of `state` that are needed to send and receive data packets, so the
+    /// data path never has to lock `state`. It is republished whenever those parts change.
+    pub(crate) data_keys: ArcSwap<DataKeys<C>>,
+
+    /// Set and notified once the initial key exchange completes, to true, or once the session
+    /// expires, to false. Used by `Session::wait_established`.
+    #[cfg(feature = "std")]
+    established_signal: (Mutex<Option<bool>>, Condvar),
 
     /// Pre-computed rekeying value.
     noise_kk_ss: Zeroizing<[u8; P384_ECDH_SHARED_SECRET_SIZE]>,
@@ -55,11 +105,22 @@ pub(crate) struct MutableState<C: CryptoLayer> {
     ratchet_state1: RatchetState,
     ratchet_state2: Option<RatchetState>,
 
-    pub(crate) hk_send: C::PrpEnc,
-    pub(crate) hk_recv: C::PrpDec,
+    pub(crate) hk_send: Arc<C::PrpEnc>,
+    pub(crate) hk_recv: Arc<C::PrpDec>,
+    /// The raw header keys, kept so the session can be exported.
+    hk_send_key: Zeroizing<[u8; AES_256_KEY_SIZE]>,
+    hk_recv_key: Zeroizing<[u8; AES_256_KEY_SIZE]>,
     key_creation_counter: u64,
     key_index: bool,
     keys: [DuplexKey<C>; 2],
+    /// The local key id we rotated away from with `Context::rotate_kid`, and the key id that
+    /// replaced it. The old key id stays valid until the remote peer acknowledges the rotation.
+    rotated_kid_recv: Option<(NonZeroU32, NonZeroU32)>,
+    /// The counter of the last key id rotation we applied on behalf of the remote peer.
+    kid_rotate_counter: u64,
+    /// The fingerprint of the ratchet key the initial key exchange was keyed with, or `None` if
+    /// it used the empty ratchet state.
+    ratchet_fingerprint_used_at_handshake: Option<Zeroizing<[u8; RATCHET_SIZE]>>,
 
     resend_timer: AtomicI64,
     timeout_timer: i64,
@@ -77,12 +138,44 @@ pub(crate) struct StateB2<C: CryptoLayer> {
     e_secret: C::KeyPair,
     noise: SymmetricState<C>,
     pub defrag: Mutex<Fragged<C::IncomingPacketBuffer, MAX_FRAGMENTS>>,
+    /// Alice's identity while she is streaming it, see `Settings::max_streamed_identity_size`.
+    pub identity_stream: Mutex<Option<Box<IdentityStream<C>>>>,
+    handshake_start_time: i64,
+    proto_version: u8,
+    /// Our policy when we answered the hello, which decides what Alice may select.
+    misuse_resistance: MisuseResistance,
+    /// Whether Alice's hello included a Kyber1024 public key.
+    post_quantum: bool,
+}
+/// What Bob learned from Alice's authenticated handshake completion.
+pub(crate) struct AuthenticatedX3<C: CryptoLayer> {
+    s_remote: C::PublicKey,
+    noise: SymmetricState<C>,
+    kek_send: Zeroizing<[u8; HASHLEN]>,
+    kek_recv: Zeroizing<[u8; HASHLEN]>,
+}
+/// An identity Alice is streaming in identity continuations after her handshake completion.
+pub(crate) struct IdentityStream<C: CryptoLayer> {
+    x3: AuthenticatedX3<C>,
+    /// The counter of the handshake completion that started the stream, which holds its flags,
+    /// such as `ENCODED_COMPLETION_COUNTER`.
+    x3_counter: u64,
+    /// Sized to the full identity from the start, so its parts can be received in any order.
+    identity: Vec<u8>,
+    /// Whether each continuation was received, indexed by its counter minus one.
+    received: Vec<bool>,
+    missing: usize,
+    /// Continuations are sent back to back, so each has its own buffer to assemble its fragments
+    /// in, indexed like `received`.
+    defrag: Vec<Fragged<C::IncomingPacketBuffer, MAX_FRAGMENTS>>,
 }
 
 pub(crate) struct DuplexKey<C: CryptoLayer> {
     send: Keys,
     recv: Keys,
-    nk: Option<C::AeadPool>,
+    nk: Option<Arc<C::AeadPool>>,
+    /// The raw send and receive keys of `nk`, kept so the session can be exported.
+    nk_keys: Option<Zeroizing<[u8; 2 * AES_256_KEY_SIZE]>>,
 }
 
 #[derive(Default)]
@@ -91,19 +184,63 @@ pub(crate) struct Keys {
     kid: Option<NonZeroU32>,
 }
 
+/// An immutable snapshot of the keys of a session that are used to send and receive data packets.
+/// See `Session::data_keys`.
+///
+/// A snapshot is replaced rather than modified, and every key it holds is reference counted, so a
+/// thread that loaded a snapshot can keep using it to completion even if the session rekeys.
+/// The retired keys are dropped once the last such thread is done with them.
+pub(crate) struct DataKeys<C: CryptoLayer> {
+    /// Whether the session is expired, in which case nothing may be sent with these keys.
+    expired: bool,
+    /// Whether the session is in a state where it is allowed to start a rekey.
+    can_rekey: bool,
+    /// Whether we are Alice and are waiting for Bob's handshake response.
+    pub(crate) awaiting_x2: bool,
+    pub(crate) hk_send: Arc<C::PrpEnc>,
+    pub(crate) hk_recv: Arc<C::PrpDec>,
+    key_creation_counter: u64,
+    key_index: bool,
+    keys: [DataKey<C>; 2],
+    rotated_kid_recv: Option<(NonZeroU32, NonZeroU32)>,
+}
+struct DataKey<C: CryptoLayer> {
+    kid_send: Option<NonZeroU32>,
+    kid_recv: Option<NonZeroU32>,
+    nk: Option<Arc<C::AeadPool>>,
+}
+
+/// A snapshot of `Session::data_keys` in use by the current thread, see `Session::load_data_keys`.
+pub(crate) struct DataKeysGuard<C: CryptoLayer>(ArcSwapGuard<DataKeys<C>>);
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    /// The number of `DataKeysGuard`s alive on the current thread, across all sessions.
+    static DATA_KEYS_HELD: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+/// A write lock on `Session::state`, which republishes `Session::data_keys` when it is released.
+pub(crate) struct StateWriteGuard<'a, C: CryptoLayer> {
+    session: &'a Session<C>,
+    state: RwLockWriteGuard<'a, MutableState<C>>,
+}
+
 /// Corresponds to State A_1 of the Zeta State Machine found in Section 4.1.
 #[derive(Clone)]
 pub(crate) struct StateA1<C: CryptoLayer> {
     noise: SymmetricState<C>,
     e_secret: C::KeyPair,
-    e1_secret: C::Kem,
-    identity: ArrayVec<u8, IDENTITY_MAX_SIZE>,
+    /// `None` if Alice chose not to use Kyber, see `ApplicationLayer::prefer_kyber`.
+    e1_secret: Option<C::Kem>,
+    identity: Vec<u8>,
     x1: ArrayVec<u8, HEADERED_HANDSHAKE_HELLO_CHALLENGE_SIZE>,
 }
 
 pub(crate) struct StateA3 {
-    identity: ArrayVec<u8, IDENTITY_MAX_SIZE>,
+    identity: Vec<u8>,
     x3: ArrayVec<u8, HEADERED_HANDSHAKE_COMPLETION_MAX_SIZE>,
+    /// The rest of an identity too large for `x3`, which is resent along with it.
+    continuations: Vec<ArrayVec<u8, HEADERED_IDENTITY_CONTINUATION_MAX_SIZE>>,
 }
 
 /// Corresponds to the ZKE Automata found in Section 4.1 - Definition 2.
@@ -113,6 +250,8 @@ pub(crate) enum ZetaAutomata<C: CryptoLayer> {
     A3(Box<StateA3>),
     S1,
     S2,
+    /// Same as S2, except we are waiting for the remote peer to acknowledge a key id rotation.
+    S3,
     R1 {
         noise: SymmetricState<C>,
         e_secret: C::KeyPair,
@@ -125,14 +264,22 @@ pub(crate) enum ZetaAutomata<C: CryptoLayer> {
 
 impl<C: CryptoLayer> Default for DuplexKey<C> {
     fn default() -> Self {
-        Self { send: Default::default(), recv: Default::default(), nk: None }
+        Self {
+            send: Default::default(),
+            recv: Default::default(),
+            nk: None,
+            nk_keys: None,
+        }
     }
 }
 impl<C: CryptoLayer> DuplexKey<C> {
     fn replace_nk(&mut self, nk_send: &[u8; HASHLEN], nk_recv: &[u8; HASHLEN]) {
         let nk_send = (&nk_send[..AES_256_KEY_SIZE]).try_into().unwrap();
         let nk_recv = (&nk_recv[..AES_256_KEY_SIZE]).try_into().unwrap();
-        self.nk = Some(C::AeadPool::new(nk_send, nk_recv))
+        self.nk = Some(Arc::new(C::AeadPool::new(nk_send, nk_recv)));
+        let nk_keys = self.nk_keys.get_or_insert(Zeroizing::new([0u8; 2 * AES_256_KEY_SIZE]));
+        nk_keys[..AES_256_KEY_SIZE].copy_from_slice(nk_send);
+        nk_keys[AES_256_KEY_SIZE..].copy_from_slice(nk_recv);
     }
 }
 impl Keys {
@@ -144,6 +291,106 @@ impl Keys {
     }
 }
 
+impl<C: CryptoLayer> DataKeys<C> {
+    fn new(state: &MutableState<C>) -> Self {
+        let key = |key: &DuplexKey<C>| DataKey {
+            kid_send: key.send.kid,
+            kid_recv: key.recv.kid,
+            nk: key.nk.clone(),
+        };
+        Self {
+            expired: matches!(&state.beta, ZetaAutomata::Null),
+            can_rekey: matches!(&state.beta, ZetaAutomata::S2 | ZetaAutomata::S3),
+            awaiting_x2: matches!(&state.beta, ZetaAutomata::A1(_)),
+            hk_send: state.hk_send.clone(),
+            hk_recv: state.hk_recv.clone(),
+            key_creation_counter: state.key_creation_counter,
+            key_index: state.key_index,
+            keys: [key(&state.keys[0]), key(&state.keys[1])],
+            rotated_kid_recv: state.rotated_kid_recv,
+        }
+    }
+    /// The same snapshot with every key removed, as it is published once a session expires.
+    fn expired(&self) -> Self {
+        let key = || DataKey { kid_send: None, kid_recv: None, nk: None };
+        Self {
+            expired: true,
+            can_rekey: false,
+            awaiting_x2: false,
+            hk_send: self.hk_send.clone(),
+            hk_recv: self.hk_recv.clone(),
+            key_creation_counter: self.key_creation_counter,
+            key_index: self.key_index,
+            keys: [key(), key()],
+            rotated_kid_recv: None,
+        }
+    }
+    /// Whether this snapshot is still an exact copy of `state`.
+    fn is_current(&self, state: &MutableState<C>) -> bool {
+        let key_eq = |a: &DataKey<C>, b: &DuplexKey<C>| {
+            let nk_eq = match (&a.nk, &b.nk) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (None, None) => true,
+                _ => false,
+            };
+            a.kid_send == b.send.kid && a.kid_recv == b.recv.kid && nk_eq
+        };
+        self.expired == matches!(&state.beta, ZetaAutomata::Null)
+            && self.can_rekey == matches!(&state.beta, ZetaAutomata::S2 | ZetaAutomata::S3)
+            && self.awaiting_x2 == matches!(&state.beta, ZetaAutomata::A1(_))
+            && Arc::ptr_eq(&self.hk_send, &state.hk_send)
+            && Arc::ptr_eq(&self.hk_recv, &state.hk_recv)
+            && self.key_creation_counter == state.key_creation_counter
+            && self.key_index == state.key_index
+            && key_eq(&self.keys[0], &state.keys[0])
+            && key_eq(&self.keys[1], &state.keys[1])
+            && self.rotated_kid_recv == state.rotated_kid_recv
+    }
+    fn key_ref(&self, is_next: bool) -> &DataKey<C> {
+        &self.keys[(self.key_index ^ is_next) as usize]
+    }
+    /// Maps a local key id we have rotated away from to the key id that replaced it.
+    pub(crate) fn resolve_kid(&self, kid: NonZeroU32) -> NonZeroU32 {
+        match self.rotated_kid_recv {
+            Some((old_kid, new_kid)) if old_kid == kid => new_kid,
+            _ => kid,
+        }
+    }
+}
+impl<'a, C: CryptoLayer> Deref for StateWriteGuard<'a, C> {
+    type Target = MutableState<C>;
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+impl<'a, C: CryptoLayer> DerefMut for StateWriteGuard<'a, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
+impl<'a, C: CryptoLayer> Drop for StateWriteGuard<'a, C> {
+    fn drop(&mut self) {
+        // This runs before the lock is released, so snapshots are published in the same order as
+        // the states they were copied from.
+        if !self.session.data_keys.load().is_current(&self.state) {
+            self.session.data_keys.store(Arc::new(DataKeys::new(&self.state)));
+        }
+    }
+}
+
+impl<C: CryptoLayer> Deref for DataKeysGuard<C> {
+    type Target = DataKeys<C>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl<C: CryptoLayer> Drop for DataKeysGuard<C> {
+    fn drop(&mut self) {
+        #[cfg(feature = "std")]
+        DATA_KEYS_HELD.with(|held| held.set(held.get() - 1));
+    }
+}
+
 impl<C: CryptoLayer> MutableState<C> {
     fn key_ref(&self, is_next: bool) -> &DuplexKey<C> {
         &self.keys[(self.key_index ^ is_next) as usize]
@@ -226,6 +473,7 @@ fn create_ratchet_state<C: CryptoLayer>(
     hmac: &mut C::Hmac,
     noise: &SymmetricState<C>,
     pre_chain_len: u64,
+    min_version: u8,
 ) -> RatchetState {
     let mut rk = Zeroizing::new([0u8; HASHLEN]);
     let mut rf = Zeroizing::new([0u8; HASHLEN]);
@@ -235,39 +483,178 @@ fn create_ratchet_state<C: CryptoLayer>(
         Zeroizing::new(rf[..RATCHET_SIZE].try_into().unwrap()),
         pre_chain_len + 1,
     )
+    .with_min_version(min_version)
 }
-fn get_counter<C: CryptoLayer>(session: &Session<C>, state: &MutableState<C>) -> Option<(u64, bool)> {
-    let c = session.send_counter.fetch_add(1, Ordering::Relaxed);
-    if c > THREAD_SAFE_COUNTER_HARD_EXPIRE || c > state.key_creation_counter + EXPIRE_AFTER_USES {
-        return None;
+/// Takes the next counter of the session, along with whether the key should now be rekeyed.
+///
+/// Returns `Err(true)` if the counter is exhausted and the session can never send again, and
+/// `Err(false)` if the key has been used `max_uses` times and must be rekeyed first. Neither
+/// consumes a counter, so a session that is refused cannot be pushed any closer to wrapping.
+///
+/// The counters reserved by a checkpoint of the key count as used. Data packets, which are
+/// limited to `REKEY_URGENTLY_AFTER_USES`, stop halfway to them, so that the key exchange that
+/// replaces the key can still be sent.
+fn get_counter<C: CryptoLayer>(
+    session: &Session<C>,
+    key_creation_counter: u64,
+    max_uses: u64,
+) -> Result<(u64, bool), bool> {
+    let expire_at = key_creation_counter.saturating_add(max_uses);
+    let mut checkpoint_limit = u64::MAX;
+    if session.checkpoint_key.load(Ordering::Acquire) == key_creation_counter {
+        checkpoint_limit = session.checkpoint_limit.load(Ordering::Relaxed);
+        if max_uses < EXPIRE_AFTER_USES {
+            checkpoint_limit = checkpoint_limit.saturating_sub(CHECKPOINT_COUNTER_SKIP / 2);
+        }
     }
-    let rekey_at = state.key_creation_counter + C::SETTINGS.rekey_after_key_uses;
-    Some((c, c > rekey_at))
+    let check = |c: u64| {
+        if c > THREAD_SAFE_COUNTER_HARD_EXPIRE {
+            Err(true)
+        } else if c > expire_at || c >= checkpoint_limit {
+            Err(false)
+        } else {
+            Ok(())
+        }
+    };
+    check(session.send_counter.load(Ordering::Relaxed))?;
+    // Concurrent senders can still take the counter past the limit between the check and the
+    // increment, which is why the hard limit leaves a margin before the counter wraps.
+    let c = session.send_counter.fetch_add(1, Ordering::Relaxed);
+    check(c)?;
+    let rekey_at = key_creation_counter.saturating_add(session.rekey_after_key_uses.load(Ordering::Relaxed));
+    Ok((c, c > rekey_at))
+}
+
+/// The maximum skip ahead of the counter window, clamped to the range every peer supports.
+pub(crate) fn max_skip_ahead<C: CryptoLayer>() -> u64 {
+    C::SETTINGS
+        .counter_window_max_skip_ahead
+        .clamp(COUNTER_WINDOW_MIN_SKIP_AHEAD_LIMIT, COUNTER_WINDOW_MAX_SKIP_AHEAD_LIMIT)
+}
+/// How far the send counter of a session restored from a checkpoint skips ahead, which is the
+/// number of counters the checkpointed session may go on to use.
+///
+/// The remote peer may be configured with a tighter skip ahead than ours, so this is half the
+/// smallest skip ahead any peer allows, which leaves room for slots of the remote counter window
+/// that are lagging behind.
+pub(crate) const CHECKPOINT_COUNTER_SKIP: u64 = COUNTER_WINDOW_MIN_SKIP_AHEAD_LIMIT / 2;
+/// The time at which a key that was just confirmed should be rekeyed, following the schedule
+/// `ApplicationLayer::choose_rekey_timing` chooses for `session`.
+fn next_rekey_time<C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
+    ctx: &Arc<ContextInner<C>>,
+    session: &Arc<Session<C>>,
+) -> i64 {
+    let (after_time, after_key_uses, max_jitter) = match app.choose_rekey_timing(session) {
+        Some((after_time, after_key_uses)) => {
+            // Jitter must not eat up a schedule that is much shorter than the default.
+            let max_jitter = C::SETTINGS.rekey_time_max_jitter.min(after_time / 2).max(1);
+            (after_time, after_key_uses, max_jitter)
+        }
+        None => (
+            C::SETTINGS.rekey_after_time,
+            C::SETTINGS.rekey_after_key_uses,
+            C::SETTINGS.rekey_time_max_jitter,
+        ),
+    };
+    session.rekey_after_key_uses.store(after_key_uses, Ordering::Relaxed);
+    let jitter = ctx.rng().lock().next_u64() % max_jitter;
+    app.time() + after_time.saturating_sub(jitter) as i64
+}
+fn new_window<C: CryptoLayer>() -> SessionWindow {
+    SessionWindow::new(C::SETTINGS.counter_window_max_out_of_order, max_skip_ahead::<C>())
+}
+/// The protocol version to use with a remote peer that sent us `remote_version`, or `None` if it
+/// is below `Settings::min_accepted_version`.
+fn negotiate_version<C: CryptoLayer>(remote_version: u8) -> Option<u8> {
+    let version = remote_version.min(PROTOCOL_VERSION);
+    (version > 0 && version >= C::SETTINGS.min_accepted_version).then_some(version)
+}
+/// Whether Bob can answer a handshake hello sent in `version`.
+/// Hellos of later versions may have a different layout, so unlike Alice he cannot negotiate down.
+pub(crate) fn is_supported_version<C: CryptoLayer>(version: u8) -> bool {
+    negotiate_version::<C>(version) == Some(version)
+}
+/// Whether we can use the misuse resistant AEAD of `C::AeadPool` under `policy`.
+fn can_use_misuse_resistance<C: CryptoLayer>(policy: MisuseResistance) -> bool {
+    C::AeadPool::MISUSE_RESISTANT && policy != MisuseResistance::Disabled
 }
 
-/// Generate a random local key id that is currently unused.
-fn gen_kid<T>(session_map: &HashMap<NonZeroU32, T>, rng: &mut impl RngCore) -> NonZeroU32 {
-    loop {
-        if let Some(kid) = NonZeroU32::new(rng.next_u32()) {
-            if !session_map.contains_key(&kid) {
-                return kid;
+/// The number of key ids `gen_kid_counter` tries before giving up.
+/// Each attempt collides with a probability of at most the fraction of the key id space in use,
+/// so this only runs out once a context holds billions of sessions.
+const KID_GENERATION_MAX_ATTEMPTS: usize = 64;
+/// Generate a local key id that is currently unused, or `None` if every one of
+/// `KID_GENERATION_MAX_ATTEMPTS` attempts collided with a key id in use.
+///
+/// Key ids are produced by encrypting an incrementing counter with a secret key and truncating
+/// the result to 32 bits, so they are unpredictable to an observer. The truncation means they
+/// repeat like the outputs of a random function, with a collision expected after around 2^16 key
+/// ids, so every key id is checked against those in use and another is tried if it collides.
+///
+/// The write lock of the shard owning the returned key id is returned with it, so the caller can
+/// claim the key id before anyone else does.
+fn gen_kid_counter<'a, T, PrpEnc: Aes256Enc>(
+    session_map: &'a KidMap<T>,
+    counter: &AtomicU64,
+    kid_prp: &PrpEnc,
+) -> Option<(NonZeroU32, RwLockWriteGuard<'a, HashMap<NonZeroU32, T>>)> {
+    for _ in 0..KID_GENERATION_MAX_ATTEMPTS {
+        let mut block = [0u8; AES_256_BLOCK_SIZE];
+        block[..8].copy_from_slice(&counter.fetch_add(1, Ordering::Relaxed).to_ne_bytes());
+        kid_prp.encrypt_in_place(&mut block);
+        if let Some(kid) = NonZeroU32::new(u32::from_ne_bytes(block[..KID_SIZE].try_into().unwrap())) {
+            let shard = session_map.shard(kid).write();
+            if !shard.contains_key(&kid) {
+                return Some((kid, shard));
             }
         }
     }
+    None
 }
-fn remap<C: CryptoLayer>(ctx: &Arc<ContextInner<C>>, session: &Arc<Session<C>>, state: &MutableState<C>) -> NonZeroU32 {
-    let mut session_map = ctx.session_map.write();
-    let weak = if let Some(Some(weak)) = state.key_ref(true).recv.kid.as_ref().map(|kid| session_map.remove(kid)) {
-        weak
-    } else {
-        Arc::downgrade(session)
-    };
-    let new_kid_recv = gen_kid(session_map.deref(), ctx.rng.lock().deref_mut());
-    session_map.insert(new_kid_recv, weak);
-    new_kid_recv
+/// Move the session from its old key id to a new one, or return `None` and leave it where it was
+/// if no unused key id could be found.
+fn remap<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session: &Arc<Session<C>>,
+    state: &MutableState<C>,
+) -> Option<NonZeroU32> {
+    let old_kid_recv = state.key_ref(true).recv.kid;
+    let weak = old_kid_recv
+        .and_then(|kid| ctx.session_map.remove(&kid))
+        .unwrap_or_else(|| Arc::downgrade(session));
+    match gen_kid_counter(&ctx.session_map, &ctx.kid_counter, &ctx.kid_prp) {
+        Some((new_kid_recv, mut shard)) => {
+            shard.insert(new_kid_recv, weak);
+            Some(new_kid_recv)
+        }
+        None => {
+            if let Some(kid) = old_kid_recv {
+                ctx.session_map.shard(kid).write().insert(kid, weak);
+            }
+            None
+        }
+    }
+}
+
+/// The RNG the ephemeral keys of a hello or response are generated with. With the `test-vectors`
+/// feature the application can replace it, see `ApplicationLayer::ephemeral_rng`.
+#[cfg_attr(not(feature = "test-vectors"), allow(unused_variables))]
+fn ephemeral_rng<'a, C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
+    rng: &'a Mutex<C::Rng>,
+    injected: &'a mut Option<Mutex<C::Rng>>,
+) -> &'a Mutex<C::Rng> {
+    #[cfg(feature = "test-vectors")]
+    {
+        *injected = app.ephemeral_rng().map(Mutex::new);
+    }
+    let injected: &'a Option<_> = injected;
+    injected.as_ref().unwrap_or(rng)
 }
 
-fn create_a1_state<C: CryptoLayer>(
+fn create_a1_state<C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
     hash: &mut C::Hash,
     hmac: &mut C::Hmac,
     rng: &Mutex<C::Rng>,
@@ -284,18 +671,29 @@ fn create_a1_state<C: CryptoLayer>(
     let mut x1 = ArrayVec::<u8, HEADERED_HANDSHAKE_HELLO_CHALLENGE_SIZE>::new();
     x1.extend([0u8; HEADER_SIZE]);
     // Noise process prologue.
-    let kid = kid_recv.get().to_ne_bytes();
-    x1.extend(kid);
-    noise.mix_hash(hash, &kid);
+    let i = x1.len();
+    x1.extend(kid_recv.get().to_ne_bytes());
+    x1.push(PROTOCOL_VERSION);
+    noise.mix_hash(hash, &x1[i..]);
     noise.mix_hash(hash, &s_remote.to_bytes());
     // Process message pattern 1 e token.
-    let e_secret = noise.write_e_no_init(hash, hmac, rng, &mut x1);
+    let mut injected_rng = None;
+    let e_rng = ephemeral_rng(app, rng, &mut injected_rng);
+    let e_secret = noise.write_e_no_init(hash, hmac, e_rng, &mut x1);
     // Process message pattern 1 es token.
     noise.mix_dh(hmac, &e_secret, s_remote);
     // Process message pattern 1 e1 token.
+    // If Kyber is not used the e1 field is all zeros, which Bob recognizes.
     let i = x1.len();
-    let (e1_secret, e1_public) = C::Kem::generate(rng.lock().deref_mut());
-    x1.extend(e1_public);
+    let e1_secret = if app.prefer_kyber() {
+        let (e1_secret, e1_public) = C::Kem::generate(e_rng.lock().deref_mut());
+        x1.extend(e1_public);
+        Some(e1_secret)
+    } else {
+        x1.extend([0u8; KYBER_PUBLIC_KEY_SIZE]);
+        None
+    };
+    capture!(app, Sent, PACKET_TYPE_HANDSHAKE_HELLO, 0, &x1[i..]);
     let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_HELLO, 0), &mut x1[i..]);
     x1.extend(tag);
     // Process message pattern 1 payload.
@@ -303,6 +701,7 @@ fn create_a1_state<C: CryptoLayer>(
     x1.try_extend_from_slice(ratchet_state1.fingerprint()).unwrap();
     x1.try_extend_from_slice(ratchet_state2.map_or(&[0u8; RATCHET_SIZE], |r| r.fingerprint()))
         .unwrap();
+    capture!(app, Sent, PACKET_TYPE_HANDSHAKE_HELLO, 1, &x1[i..]);
     let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_HELLO, 1), &mut x1[i..]);
     x1.extend(tag);
 
@@ -313,12 +712,12 @@ fn create_a1_state<C: CryptoLayer>(
 
     set_header(&mut x1, 0, &to_nonce(PACKET_TYPE_HANDSHAKE_HELLO, c));
 
-    let identity = identity.try_into().unwrap();
+    let identity = identity.to_vec();
     Box::new(StateA1 { noise, e_secret, e1_secret, identity, x1 })
 }
 /// Corresponds to Transition Algorithm 1 found in Section 4.3.
 pub(crate) fn trans_to_a1<C: CryptoLayer, App: ApplicationLayer<C>>(
-    mut app: App,
+    app: &mut App,
     ctx: &Arc<ContextInner<C>>,
     s_remote: C::PublicKey,
     session_data: C::SessionData,
@@ -327,17 +726,22 @@ pub(crate) fn trans_to_a1<C: CryptoLayer, App: ApplicationLayer<C>>(
     send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
 ) -> Result<(Arc<Session<C>>, Option<i64>), OpenError> {
     let RatchetStates { state1, state2 } = ratchet_states;
+    let min_version = state1.min_version.max(state2.as_ref().map_or(0, |rs| rs.min_version));
+    if PROTOCOL_VERSION < min_version && !app.allow_version_downgrade(min_version, PROTOCOL_VERSION) {
+        return Err(OpenError::VersionDowngrade);
+    }
 
     let mut session_queue = ctx.session_queue.lock();
-    let mut session_map = ctx.session_map.write();
-    let kid_recv = gen_kid(session_map.deref(), ctx.rng.lock().deref_mut());
+    let (kid_recv, mut shard) =
+        gen_kid_counter(&ctx.session_map, &ctx.kid_counter, &ctx.kid_prp).ok_or(OpenError::KeyIdsExhausted)?;
 
     let hash = &mut C::Hash::new();
     let hmac = &mut C::Hmac::new();
     let a1 = create_a1_state(
+        app,
         hash,
         hmac,
-        &ctx.rng,
+        ctx.rng(),
         &s_remote,
         kid_recv,
         &state1,
@@ -357,42 +761,69 @@ pub(crate) fn trans_to_a1<C: CryptoLayer, App: ApplicationLayer<C>>(
     let current_time = app.time();
     let queue_idx = session_queue.reserve_index();
     let resend_timer = current_time + C::SETTINGS.resend_time as i64;
+    let state = MutableState {
+        ratchet_state1: state1.clone(),
+        ratchet_state2: state2.clone(),
+        hk_send: Arc::new(C::PrpEnc::new((&hk_send[..AES_256_KEY_SIZE]).try_into().unwrap())),
+        hk_recv: Arc::new(C::PrpDec::new((&hk_recv[..AES_256_KEY_SIZE]).try_into().unwrap())),
+        hk_send_key: Zeroizing::new(hk_send[..AES_256_KEY_SIZE].try_into().unwrap()),
+        hk_recv_key: Zeroizing::new(hk_recv[..AES_256_KEY_SIZE].try_into().unwrap()),
+        key_creation_counter: 0,
+        key_index: true,
+        keys: [DuplexKey::default(), DuplexKey::default()],
+        rotated_kid_recv: None,
+        kid_rotate_counter: 0,
+        ratchet_fingerprint_used_at_handshake: None,
+        resend_timer: AtomicI64::new(resend_timer),
+        timeout_timer: current_time + C::SETTINGS.initial_offer_timeout as i64,
+        beta: ZetaAutomata::A1(a1),
+    };
     let session = Arc::new(Session {
         ctx: Arc::downgrade(ctx),
         session_data,
         was_bob: false,
         queue_idx,
+        parked: AtomicBool::new(false),
+        handshake_start_time: current_time,
+        proto_version: AtomicU8::new(0),
+        misuse_resistant: AtomicBool::new(false),
+        post_quantum: AtomicBool::new(false),
         s_remote,
         send_counter: AtomicU64::new(0),
-        window: Window::new(),
+        ordered_send_counter: AtomicU64::new(0),
+        mtu_hint: AtomicUsize::new(0),
+        rekey_after_key_uses: AtomicU64::new(C::SETTINGS.rekey_after_key_uses),
+        checkpoint_key: AtomicU64::new(u64::MAX),
+        checkpoint_limit: AtomicU64::new(u64::MAX),
+        window: new_window::<C>(),
+        faults: FaultCounters::new(),
+        kex: KexTimer::new(),
         state_machine_lock: Mutex::new(()),
-        state: RwLock::new(MutableState {
-            ratchet_state1: state1.clone(),
-            ratchet_state2: state2.clone(),
-            hk_send: C::PrpEnc::new((&hk_send[..AES_256_KEY_SIZE]).try_into().unwrap()),
-            hk_recv: C::PrpDec::new((&hk_recv[..AES_256_KEY_SIZE]).try_into().unwrap()),
-            key_creation_counter: 0,
-            key_index: true,
-            keys: [DuplexKey::default(), DuplexKey::default()],
-            resend_timer: AtomicI64::new(resend_timer),
-            timeout_timer: current_time + C::SETTINGS.initial_offer_timeout as i64,
-            beta: ZetaAutomata::A1(a1),
-        }),
+        data_keys: ArcSwap::from_pointee(DataKeys::new(&state)),
+        state: RwLock::new(state),
         noise_kk_ss: noise_kk_ss.clone(),
-        defrag: std::array::from_fn(|_| Mutex::new(Fragged::new())),
+        #[cfg(feature = "std")]
+        established_signal: (Mutex::new(None), Condvar::new()),
+        defrag: core::array::from_fn(|_| Mutex::new(SessionFragBuffer::new())),
     });
+    ctx.metrics.record_session_created(true);
     {
-        let mut state = session.state.write();
+        let mut state = session.write_state();
         state.key_mut(true).recv.kid = Some(kid_recv);
     }
 
-    session_map.insert(kid_recv, Arc::downgrade(&session));
+    shard.insert(kid_recv, Arc::downgrade(&session));
     session_queue.push_reserved(queue_idx, Arc::downgrade(&session), Reverse(resend_timer));
     let reduced_service_time = ctx.reduce_next_service_time(resend_timer);
-    drop(session_map);
+    drop(shard);
     drop(session_queue);
 
     send(&mut x1, None);
+    app.on_handshake_started(true);
+    log!(
+        app,
+        HandshakeStarted { session: Some(&session), timestamp_ms: current_time }
+    );
 
     Ok((session, reduced_service_time))
 }
@@ -402,23 +833,33 @@ pub(crate) fn respond_to_challenge<C: CryptoLayer>(
     session: &Session<C>,
     challenge: &[u8; CHALLENGE_SIZE],
 ) {
-    let mut state = session.state.write();
+    let mut state = session.write_state();
     if let ZetaAutomata::A1(a1) = &mut state.beta {
         let response_start = a1.x1.len() - CHALLENGE_SIZE;
-        let mut rng = ctx.rng.lock();
+        let mut rng = ctx.rng().lock();
         let response = (&mut a1.x1[response_start..]).try_into().unwrap();
         respond_to_challenge_in_place(rng.deref_mut(), &mut C::Hash::new(), challenge, response);
     }
 }
+/// Whether `session` is still waiting for a response to a hello whose header carried `counter`.
+pub(crate) fn is_reply_to_hello<C: CryptoLayer>(session: &Session<C>, counter: u64) -> bool {
+    let state = session.state.read();
+    if let ZetaAutomata::A1(a1) = &state.beta {
+        from_nonce(&a1.x1[..HEADER_SIZE]).1 == counter
+    } else {
+        false
+    }
+}
 /// Corresponds to Transition Algorithm 2 found in Section 4.3.
-pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
     app: &mut App,
     ctx: &ContextInner<C>,
     hash: &mut C::Hash,
+    remote_address: &impl Hash,
     n: &[u8; AES_GCM_NONCE_SIZE],
     x1: &mut [u8],
     send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
-) -> Result<Option<i64>, ReceiveError<C>> {
+) -> Result<Option<i64>, ReceiveError<C, E>> {
     use FaultType::*;
     //    <- s
     //    ...
@@ -436,11 +877,18 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     let mut i = 0;
     // Noise process prologue.
     let j = i + KID_SIZE;
-    noise.mix_hash(hash, &x1[i..j]);
+    let k = j + PROTOCOL_VERSION_SIZE;
+    noise.mix_hash(hash, &x1[i..k]);
     let kid_send =
         NonZeroU32::new(u32::from_ne_bytes(x1[i..j].try_into().unwrap())).ok_or_else(|| fault!(InvalidPacket, true))?;
+    // The version is hashed into the handshake, so it cannot be tampered with.
+    // `Context::receive` already replied to hellos of versions we do not support.
+    let proto_version = x1[j];
+    if !is_supported_version::<C>(proto_version) {
+        return Err(ReceiveError::Rejected(None));
+    }
     noise.mix_hash(hash, &ctx.s_secret.public_key_bytes());
-    i = j;
+    i = k;
     // Process message pattern 1 e token.
     let e_remote = noise
         .read_e_no_init(hash, hmac, &mut i, x1)
@@ -454,6 +902,7 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_HELLO, 0), &mut x1[i..j], tag) {
         return Err(fault!(FailedAuth, true));
     }
+    capture!(app, Received, PACKET_TYPE_HANDSHAKE_HELLO, 0, &x1[i..j]);
     let e1_start = i;
     let e1_end = j;
     i = k;
@@ -464,10 +913,11 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_HELLO, 1), &mut x1[i..j], tag) {
         return Err(fault!(FailedAuth, true));
     }
+    capture!(app, Received, PACKET_TYPE_HANDSHAKE_HELLO, 1, &x1[i..j]);
     debug_assert_eq!(k, x1.len());
 
     let rf1 = &x1[i..i + RATCHET_SIZE];
-    let rf2 = &x1[i + RATCHET_SIZE..j];
+    let rf2 = &x1[i + RATCHET_SIZE..i + 2 * RATCHET_SIZE];
     let mut lookup_data = None;
     let mut ratchet_state = None;
     if !secure_eq(rf1, &[0u8; RATCHET_SIZE]) {
@@ -477,7 +927,7 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 lookup_data = Some(data);
                 ratchet_state = Some(rs);
             }
-            Err(e) => return Err(ReceiveError::StorageError(e)),
+            Err(e) => return Err(ReceiveError::StorageReadError(e)),
         }
     }
     if ratchet_state.is_none() {
@@ -488,7 +938,7 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                     lookup_data = Some(data);
                     ratchet_state = Some(rs);
                 }
-                Err(e) => return Err(ReceiveError::StorageError(e)),
+                Err(e) => return Err(ReceiveError::StorageReadError(e)),
             }
         }
         if ratchet_state.is_none() && app.hello_requires_recognized_ratchet() {
@@ -498,6 +948,11 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     // If we get to this point and haven't found a full ratchet state,
     // set it to the empty ratchet state.
     let ratchet_state = ratchet_state.unwrap_or_default();
+    // Alice has already spoken a later version with us, so an older hello may be a downgrade attack.
+    let min_version = ratchet_state.min_version;
+    if proto_version < min_version && !app.allow_version_downgrade(min_version, proto_version) {
+        return Err(ReceiveError::Rejected(None));
+    }
 
     let mut hk_recv = Zeroizing::new([0u8; HASHLEN]);
     let mut hk_send = Zeroizing::new([0u8; HASHLEN]);
@@ -506,34 +961,56 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     let mut x2 = ArrayVec::<u8, HEADERED_HANDSHAKE_RESPONSE_SIZE>::new();
     x2.extend([0u8; HEADER_SIZE]);
     // Process message pattern 2 e token.
-    let e_secret = noise.write_e_no_init(hash, hmac, &ctx.rng, &mut x2);
+    let mut injected_rng = None;
+    let e_rng = ephemeral_rng(app, ctx.rng(), &mut injected_rng);
+    let e_secret = noise.write_e_no_init(hash, hmac, e_rng, &mut x2);
     // Process message pattern 2 ee token.
     noise.mix_dh(hmac, &e_secret, &e_remote);
     // Process message pattern 2 ekem1 token.
+    // An all zero e1 means Alice chose not to use Kyber, in which case we skip the encapsulation
+    // and send back an all zero ekem1.
+    let e1 = &x1[e1_start..e1_end];
+    let post_quantum = !secure_eq(e1, &[0u8; KYBER_PUBLIC_KEY_SIZE]);
+    if !post_quantum && app.require_kyber() {
+        return Err(ReceiveError::Rejected(None));
+    }
     {
         let i = x2.len();
-        let mut ekem1_secret = Zeroizing::new([0u8; KYBER_PLAINTEXT_SIZE]);
-        let ekem1 = C::Kem::encapsulate(
-            ctx.rng.lock().deref_mut(),
-            (&x1[e1_start..e1_end]).try_into().unwrap(),
-            &mut ekem1_secret,
-        )
-        .ok_or_else(|| fault!(FailedAuth, true))?;
-        x2.extend(ekem1);
+        let mut ekem1_secret = None;
+        if !post_quantum {
+            x2.extend([0u8; KYBER_CIPHERTEXT_SIZE]);
+        } else {
+            let mut secret = Zeroizing::new([0u8; KYBER_PLAINTEXT_SIZE]);
+            let ekem1 = C::Kem::encapsulate(e_rng.lock().deref_mut(), e1.try_into().unwrap(), &mut secret)
+                .ok_or_else(|| fault!(FailedAuth, true))?;
+            x2.extend(ekem1);
+            ekem1_secret = Some(secret);
+        }
+        capture!(app, Sent, PACKET_TYPE_HANDSHAKE_RESPONSE, 0, &x2[i..]);
         let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_RESPONSE, 0), &mut x2[i..]);
         x2.extend(tag);
-        noise.mix_key_no_init(hmac, ekem1_secret.as_ref());
+        if let Some(ekem1_secret) = ekem1_secret {
+            noise.mix_key_no_init(hmac, ekem1_secret.as_ref());
+        }
     }
     // Process message pattern 2 psk2 token.
     noise.mix_key_and_hash(hash, hmac, ratchet_state.key.as_ref());
     // Process message pattern 2 payload.
-    let kid_recv = gen_kid(
-        ctx.session_map.read().deref(),
-        ctx.rng.lock().deref_mut(),
-    );
+    let (kid_recv, _) =
+        gen_kid_counter(&ctx.session_map, &ctx.kid_counter, &ctx.kid_prp).ok_or(ReceiveError::KeyIdsExhausted)?;
 
     let i = x2.len();
     x2.extend(kid_recv.get().to_ne_bytes());
+    let misuse_resistance = app.misuse_resistance();
+    let mut version_flags = 0;
+    if can_use_misuse_resistance::<C>(misuse_resistance) {
+        version_flags |= VERSION_FLAG_MISUSE_RESISTANT;
+    }
+    if misuse_resistance == MisuseResistance::Required {
+        version_flags |= VERSION_FLAG_MISUSE_RESISTANCE_REQUIRED;
+    }
+    x2.push(proto_version | version_flags);
+    capture!(app, Sent, PACKET_TYPE_HANDSHAKE_RESPONSE, 0, &x2[i..]);
     let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_RESPONSE, 0), &mut x2[i..]);
     x2.extend(tag);
 
@@ -546,8 +1023,10 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
 
     set_header(&mut x2, kid_send.get(), &to_nonce(PACKET_TYPE_HANDSHAKE_RESPONSE, c));
 
-    let next_service_time = ctx.unassociated_handshake_states.insert(
+    let current_time = app.time();
+    let (next_service_time, eviction) = ctx.unassociated_handshake_states.insert(
         kid_recv,
+        remote_address,
         Arc::new(StateB2 {
             ratchet_state,
             kid_send,
@@ -557,10 +1036,18 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             e_secret,
             noise,
             defrag: Mutex::new(Fragged::new()),
+            identity_stream: Mutex::new(None),
             lookup_data,
+            handshake_start_time: current_time,
+            proto_version,
+            misuse_resistance,
+            post_quantum,
         }),
-        app.time(),
+        current_time,
     );
+    if eviction != Eviction::None {
+        log!(app, EvictedUnassociatedHandshake(eviction));
+    }
     let mut reduced_service_time = None;
     if let Some(next_service_time) = next_service_time {
         reduced_service_time = ctx.reduce_next_service_time(next_service_time);
@@ -570,18 +1057,20 @@ pub(crate) fn received_x1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         &mut x2,
         Some(&C::PrpEnc::new(&hk_send[..AES_256_KEY_SIZE].try_into().unwrap())),
     );
+    app.on_handshake_started(false);
+    log!(app, HandshakeStarted { session: None, timestamp_ms: current_time });
     Ok(reduced_service_time)
 }
 /// Corresponds to Transition Algorithm 3 found in Section 4.3.
-pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
     app: &mut App,
     ctx: &Arc<ContextInner<C>>,
     session: &Arc<Session<C>>,
     kid: NonZeroU32,
     n: &[u8; AES_GCM_NONCE_SIZE],
     x2: &mut [u8],
-    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
-) -> Result<(bool, Option<i64>), ReceiveError<C>> {
+    mut send: impl FnMut(&mut [u8], Option<&C::PrpEnc>),
+) -> Result<(bool, Option<i64>), ReceiveError<C, E>> {
     use FaultType::*;
     //    <- e, ee, ekem1, psk
     //    -> s, se
@@ -598,7 +1087,7 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         return Err(fault!(UnknownLocalKeyId, true, session));
     }
     let (_, c) = from_nonce(n);
-    if c >= COUNTER_WINDOW_MAX_SKIP_AHEAD || !secure_eq(&n[AES_GCM_NONCE_SIZE - 3..], &x2[x2.len() - 3..]) {
+    if c >= max_skip_ahead::<C>() || !secure_eq(&n[AES_GCM_NONCE_SIZE - 3..], &x2[x2.len() - 3..]) {
         return Err(fault!(FailedAuth, true, session));
     }
 
@@ -627,15 +1116,15 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_RESPONSE, 0), &mut x2[i..j], tag) {
             return Err(fault!(FailedAuth, true, session));
         }
-        let mut ekem1_secret = Zeroizing::new([0u8; KYBER_PLAINTEXT_SIZE]);
-        if !a1
-            .e1_secret
-            .decapsulate((&x2[i..j]).try_into().unwrap(), &mut ekem1_secret)
-        {
-            return Err(fault!(FailedAuth, true, session));
+        capture!(app, Received, PACKET_TYPE_HANDSHAKE_RESPONSE, 0, &x2[i..j]);
+        let post_quantum = a1.e1_secret.is_some();
+        if let Some(e1_secret) = &a1.e1_secret {
+            let mut ekem1_secret = Zeroizing::new([0u8; KYBER_PLAINTEXT_SIZE]);
+            if !e1_secret.decapsulate((&x2[i..j]).try_into().unwrap(), &mut ekem1_secret) {
+                return Err(fault!(FailedAuth, true, session));
+            }
+            noise.mix_key_no_init(hmac, ekem1_secret.as_ref());
         }
-        noise.mix_key_no_init(hmac, ekem1_secret.as_ref());
-        drop(ekem1_secret);
         i = k;
         // We attempt to decrypt the payload at most three times. First two times with
         // the ratchet keys Alice remembers, and final time with a ratchet
@@ -643,12 +1132,12 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         // The following code is not constant time, meaning we leak to an
         // attacker whether or not we downgraded.
         // We don't currently consider this sensitive enough information to hide.
-        let j = i + KID_SIZE;
+        let j = i + KID_SIZE + PROTOCOL_VERSION_SIZE;
         let k = j + AES_GCM_TAG_SIZE;
-        let payload: [u8; KID_SIZE] = x2[i..j].try_into().unwrap();
+        let payload: [u8; KID_SIZE + PROTOCOL_VERSION_SIZE] = x2[i..j].try_into().unwrap();
         let tag = x2[j..k].try_into().unwrap();
         // Check for which ratchet key Bob wants to use.
-        let mut test_ratchet_key = |ratchet_key| -> Option<(NonZeroU32, SymmetricState<C>)> {
+        let mut test_ratchet_key = |ratchet_key| -> Option<(_, SymmetricState<C>)> {
             let mut noise = noise.clone();
             let mut payload = payload;
             // Process message pattern 2 psk token.
@@ -657,11 +1146,14 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_RESPONSE, 0), &mut payload, tag) {
                 return None;
             }
-            NonZeroU32::new(u32::from_ne_bytes(payload)).map(|kid2| (kid2, noise))
+            Some((payload, noise))
         };
         // Check first key.
         let mut ratchet_i = 1;
         let mut chain_len = state.ratchet_state1.chain_len;
+        // The version floor outlives the ratchet chain, so it is kept even if Bob downgrades us.
+        let min_version = state.ratchet_state2.as_ref().map_or(0, |rs| rs.min_version);
+        let min_version = state.ratchet_state1.min_version.max(min_version);
         let mut result = test_ratchet_key(state.ratchet_state1.key.as_ref());
         // Check second key.
         if result.is_none() {
@@ -680,30 +1172,75 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             }
         }
 
-        let (kid_send, mut noise) = result.ok_or_else(|| fault!(FailedAuth, true, session))?;
+        let (payload, mut noise) = result.ok_or_else(|| fault!(FailedAuth, true, session))?;
+        capture!(app, Received, PACKET_TYPE_HANDSHAKE_RESPONSE, 0, &payload);
+        let kid_send = NonZeroU32::new(u32::from_ne_bytes(payload[..KID_SIZE].try_into().unwrap()))
+            .ok_or_else(|| fault!(InvalidPacket, true, session))?;
+        let version_flags = VERSION_FLAG_MISUSE_RESISTANT | VERSION_FLAG_MISUSE_RESISTANCE_REQUIRED;
+        let version = payload[KID_SIZE] & !version_flags;
+        let proto_version = negotiate_version::<C>(version).ok_or(ReceiveError::Rejected(None))?;
+        // Bob's flags were authenticated along with his version. If either of us requires the
+        // misuse resistant AEAD and we cannot agree on it, this session can never be established.
+        let misuse_resistance = app.misuse_resistance();
+        let offered = payload[KID_SIZE] & VERSION_FLAG_MISUSE_RESISTANT != 0;
+        let misuse_resistant = offered && can_use_misuse_resistance::<C>(misuse_resistance);
+        let required = misuse_resistance == MisuseResistance::Required
+            || payload[KID_SIZE] & VERSION_FLAG_MISUSE_RESISTANCE_REQUIRED != 0;
+        if required && !misuse_resistant {
+            return Err(ReceiveError::Rejected(None));
+        }
 
         let mut x3 = ArrayVec::<u8, HEADERED_HANDSHAKE_COMPLETION_MAX_SIZE>::new();
         x3.extend([0u8; HEADER_SIZE]);
         // Process message pattern 3 s token.
         let i = x3.len();
         x3.extend(ctx.s_secret.public_key_bytes());
+        capture!(app, Sent, PACKET_TYPE_HANDSHAKE_COMPLETION, 1, &x3[i..]);
         let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, 1), &mut x3[i..]);
         x3.extend(tag);
         // Process message pattern 3 se token.
         noise.mix_dh(hmac, &ctx.s_secret, &e_remote);
         // Process message pattern 3 payload.
+        // The identity is compressed here rather than in `create_a1_state`, so it is only ever
+        // compressed for a peer we are about to send it to.
+        #[cfg(feature = "deflate")]
+        let encoded = app.compress_identity().then(|| encode_identity(&a1.identity)).flatten();
+        #[cfg(not(feature = "deflate"))]
+        let encoded: Option<Vec<u8>> = None;
+        let (mut c, identity) = match &encoded {
+            Some(encoded) => (ENCODED_COMPLETION_COUNTER, &encoded[..]),
+            None => (0, &a1.identity[..]),
+        };
+        if misuse_resistant {
+            c |= MISUSE_RESISTANT_COMPLETION_COUNTER;
+        }
+        // An identity that does not fit is streamed. The payload then starts with its total length,
+        // and the rest of it follows in identity continuations once the kex keys are known.
         let i = x3.len();
-        x3.try_extend_from_slice(&a1.identity).unwrap();
-        let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, 0), &mut x3[i..]);
+        let rest = if identity.len() > IDENTITY_MAX_SIZE {
+            let (first, rest) = identity.split_at(IDENTITY_MAX_SIZE - STREAMED_IDENTITY_LENGTH_SIZE);
+            x3.extend((identity.len() as u32).to_be_bytes());
+            x3.try_extend_from_slice(first).unwrap();
+            c |= STREAMED_COMPLETION_COUNTER;
+            rest
+        } else {
+            x3.try_extend_from_slice(identity).unwrap();
+            &[][..]
+        };
+        capture!(app, Sent, PACKET_TYPE_HANDSHAKE_COMPLETION, c, &x3[i..]);
+        let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, c), &mut x3[i..]);
         x3.extend(tag);
 
-        let new_ratchet_state = create_ratchet_state(hmac, &noise, chain_len);
+        let new_ratchet_state = create_ratchet_state(hmac, &noise, chain_len, min_version.max(proto_version));
 
         let ratchet_to_preserve = if ratchet_i == 1 {
             Some(&state.ratchet_state1)
         } else {
             state.ratchet_state2.as_ref()
         };
+        let used_fingerprint = ratchet_to_preserve
+            .filter(|rs| !should_warn_missing_ratchet && !rs.is_empty())
+            .map(|rs| Zeroizing::new(*rs.fingerprint()));
         let result = app.save_ratchet_state(
             &session.s_remote,
             &session.session_data,
@@ -717,7 +1254,7 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 ratchet_i == 1,
             ),
         );
-        if !result.map_err(ReceiveError::StorageError)? {
+        if !result.map_err(ReceiveError::StorageWriteError)? {
             return Err(fault!(OutOfSequence, true, session, true));
         }
 
@@ -728,18 +1265,36 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         noise.get_ask(hmac, LABEL_KEX_KEY, &mut kek_recv, &mut kek_send);
         noise.split(hmac, &mut nk_recv, &mut nk_send);
 
-        let nonce = to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, 0);
+        let nonce = to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, c);
         set_header(&mut x3, kid_send.get(), &nonce);
 
+        let mut continuations = Vec::new();
+        for (c, chunk) in (1..).zip(rest.chunks(IDENTITY_MAX_SIZE)) {
+            let mut ic = ArrayVec::<u8, HEADERED_IDENTITY_CONTINUATION_MAX_SIZE>::new();
+            ic.extend([0u8; HEADER_SIZE]);
+            ic.try_extend_from_slice(chunk).unwrap();
+            let nonce = to_nonce(PACKET_TYPE_IDENTITY_CONTINUATION, c);
+            capture!(app, Sent, PACKET_TYPE_IDENTITY_CONTINUATION, c, &ic[HEADER_SIZE..]);
+            let kek_send = (&kek_send[..AES_256_KEY_SIZE]).try_into().unwrap();
+            let tag = C::Aead::encrypt_in_place(kek_send, &nonce, &[], &mut ic[HEADER_SIZE..]);
+            ic.extend(tag);
+            set_header(&mut ic, kid_send.get(), &nonce);
+            continuations.push(ic);
+        }
+
         drop(state);
         let resend_timer = {
-            let mut state = session.state.write();
+            let mut state = session.write_state();
             state.key_mut(true).send.kid = Some(kid_send);
             state.key_mut(true).send.replace_kek(&kek_send);
             state.key_mut(true).recv.replace_kek(&kek_recv);
             state.key_mut(true).replace_nk(&nk_send, &nk_recv);
             state.ratchet_state2 = Some(state.ratchet_state1.clone());
             state.ratchet_state1 = new_ratchet_state.clone();
+            session.proto_version.store(proto_version, Ordering::Relaxed);
+            session.misuse_resistant.store(misuse_resistant, Ordering::Relaxed);
+            session.post_quantum.store(post_quantum, Ordering::Relaxed);
+            state.ratchet_fingerprint_used_at_handshake = used_fingerprint;
             let current_time = app.time();
             state.key_creation_counter = session.send_counter.load(Ordering::Relaxed);
             let resend_timer = current_time + C::SETTINGS.resend_time as i64;
@@ -751,7 +1306,11 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 // This return is unreachable.
                 return Err(fault!(FailedAuth, true, session, true));
             };
-            state.beta = ZetaAutomata::A3(Box::new(StateA3 { identity: a1.identity.clone(), x3: x3.clone() }));
+            state.beta = ZetaAutomata::A3(Box::new(StateA3 {
+                identity: a1.identity.clone(),
+                x3: x3.clone(),
+                continuations: continuations.clone(),
+            }));
             resend_timer
         };
         drop(kex_lock);
@@ -760,7 +1319,7 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             .change_priority(session.queue_idx, Reverse(resend_timer));
         let reduced = ctx.reduce_next_service_time(resend_timer);
 
-        Ok((x3, reduced))
+        Ok((x3, continuations, reduced))
     })();
 
     match &mut result {
@@ -771,26 +1330,37 @@ pub(crate) fn received_x2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             // We can only reach this point if we are in state A1, and state A1 cannot expire.
             debug_assert!(timeout_trans(app, ctx, session, kex_lock, state, current_time, send).is_ok());
         }
-        Ok((packet, _)) => send(packet, Some(&session.state.read().hk_send)),
+        // Bob's version is too old for us, or we cannot agree on an AEAD, so this session can
+        // never be established.
+        Err(ReceiveError::Rejected(_)) => session.expire(),
+        Ok((packet, continuations, _)) => {
+            let state = session.state.read();
+            send(packet, Some(&*state.hk_send));
+            for packet in continuations {
+                send(packet, Some(&*state.hk_send));
+            }
+        }
         _ => {}
     }
-    result.map(|(_, reduced_service_time)| (should_warn_missing_ratchet, reduced_service_time))
+    result.map(|(_, _, reduced_service_time)| (should_warn_missing_ratchet, reduced_service_time))
 }
 /// Returns `Err(true)` if the counter expired.
-fn send_control<C: CryptoLayer, const CAP: usize>(
+fn send_control<C: CryptoLayer, App: ApplicationLayer<C>, const CAP: usize>(
+    app: &mut App,
     session: &Arc<Session<C>>,
     state: &MutableState<C>,
     packet_type: u8,
     mut payload: ArrayVec<u8, CAP>,
     send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
 ) -> Result<(), bool> {
-    if let Some((c, _)) = get_counter(session, state) {
+    if let Ok((c, _)) = get_counter(session, state.key_creation_counter, EXPIRE_AFTER_USES) {
         if let (Some(kek), Some(kid)) = (state.key_ref(false).send.kek.as_ref(), state.key_ref(false).send.kid) {
             let nonce = to_nonce(packet_type, c);
+            capture!(app, Sent, packet_type, c, &payload[HEADER_SIZE..]);
             let tag = C::Aead::encrypt_in_place(kek, &nonce, &[], &mut payload[HEADER_SIZE..]);
             payload.extend(tag);
             set_header(&mut payload, kid.get(), &nonce);
-            send(&mut payload, Some(&state.hk_send));
+            send(&mut payload, Some(&*state.hk_send));
             Ok(())
         } else {
             Err(false)
@@ -799,15 +1369,15 @@ fn send_control<C: CryptoLayer, const CAP: usize>(
         Err(true)
     }
 }
-/// Corresponds to Transition Algorithm 4 found in Section 4.3.
-pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+/// Authenticates Alice's handshake completion, sent with counter `c`.
+/// Returns what it authenticated and the range of `x3` that holds its decrypted payload.
+fn decrypt_x3<C: CryptoLayer, App: ApplicationLayer<C>, E>(
     app: &mut App,
-    ctx: &Arc<ContextInner<C>>,
-    zeta: Arc<StateB2<C>>,
+    zeta: &StateB2<C>,
     kid: NonZeroU32,
     x3: &mut [u8],
-    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
-) -> Result<(Arc<Session<C>>, bool, Option<i64>), ReceiveError<C>> {
+    c: u64,
+) -> Result<(AuthenticatedX3<C>, Range<usize>), ReceiveError<C, E>> {
     use FaultType::*;
     //    -> s, se
     if x3.len() < HANDSHAKE_COMPLETION_MIN_SIZE {
@@ -828,6 +1398,7 @@ pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, 1), &mut x3[i..j], tag) {
         return Err(fault!(FailedAuth, true));
     }
+    capture!(app, Received, PACKET_TYPE_HANDSHAKE_COMPLETION, 1, &x3[i..j]);
     let s_remote = C::PublicKey::from_bytes((&x3[i..j]).try_into().unwrap()).ok_or_else(|| fault!(FailedAuth, true))?;
     i = k;
     // Process message pattern 3 se token.
@@ -836,32 +1407,197 @@ pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     let k = x3.len();
     let j = k - AES_GCM_TAG_SIZE;
     let tag = x3[j..k].try_into().unwrap();
-    if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, 0), &mut x3[i..j], tag) {
+    if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_HANDSHAKE_COMPLETION, c), &mut x3[i..j], tag) {
         return Err(fault!(FailedAuth, true));
     }
-    let identity_start = i;
-    let identity_end = j;
+    capture!(app, Received, PACKET_TYPE_HANDSHAKE_COMPLETION, c, &x3[i..j]);
 
     let mut kek_recv = Zeroizing::new([0u8; HASHLEN]);
     let mut kek_send = Zeroizing::new([0u8; HASHLEN]);
     noise.get_ask(hmac, LABEL_KEX_KEY, &mut kek_send, &mut kek_recv);
+    Ok((AuthenticatedX3 { s_remote, noise, kek_send, kek_recv }, i..j))
+}
+/// Creates the packet that tells Alice her handshake completion was rejected.
+fn create_reject<C: CryptoLayer>(
+    zeta: &StateB2<C>,
+    kek_send: &[u8; HASHLEN],
+) -> ArrayVec<u8, HEADERED_SESSION_REJECTED_SIZE> {
+    // We just used a counter with this key, but we are not storing
+    // the fact we used it in memory. This is currently ok because the
+    // handshake is being dropped, so nonce reuse can't happen.
+    let mut d = ArrayVec::<u8, HEADERED_SESSION_REJECTED_SIZE>::new();
+    d.extend([0u8; HEADER_SIZE]);
+    let nonce = to_nonce(PACKET_TYPE_SESSION_REJECTED, 0);
+    let kek_send = (&kek_send[..AES_256_KEY_SIZE]).try_into().unwrap();
+    d.extend(C::Aead::encrypt_in_place(kek_send, &nonce, &[], &mut []));
+    set_header(&mut d, zeta.kid_send.get(), &nonce);
+    d
+}
+/// Corresponds to Transition Algorithm 4 found in Section 4.3.
+pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
+    ctx: &Arc<ContextInner<C>>,
+    zeta: Arc<StateB2<C>>,
+    kid: NonZeroU32,
+    x3: &mut [u8],
+    c: u64,
+    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
+) -> Result<(Arc<Session<C>>, bool, Option<i64>), ReceiveError<C, E>> {
+    let (authenticated, identity) = decrypt_x3(app, &zeta, kid, x3, c)?;
+    accept_x3(app, ctx, &zeta, authenticated, &x3[identity], c, send)
+}
+/// Starts receiving an identity Alice is streaming, from a handshake completion that carries only
+/// its length and first part. Resends of this handshake completion are ignored.
+pub(crate) fn received_streamed_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
+    zeta: &StateB2<C>,
+    kid: NonZeroU32,
+    x3: &mut [u8],
+    c: u64,
+    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
+) -> Result<(), ReceiveError<C, E>> {
+    use FaultType::*;
+    let mut stream = zeta.identity_stream.lock();
+    if stream.is_some() {
+        return Ok(());
+    }
+    let (authenticated, payload) = decrypt_x3(app, zeta, kid, x3, c)?;
+    let payload = &x3[payload];
+    if payload.len() != IDENTITY_MAX_SIZE {
+        return Err(fault!(InvalidPacket, true));
+    }
+    let (len, first) = payload.split_at(STREAMED_IDENTITY_LENGTH_SIZE);
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    if len <= first.len() {
+        return Err(fault!(InvalidPacket, true));
+    }
+    if len > C::SETTINGS.max_streamed_identity_size {
+        capture!(app, Sent, PACKET_TYPE_SESSION_REJECTED, 0, &[]);
+        let mut reject = create_reject(zeta, &authenticated.kek_send);
+        send(&mut reject, Some(&C::PrpEnc::new(&zeta.hk_send)));
+        return Err(ReceiveError::Rejected(None));
+    }
+    let count = (len - first.len()).div_ceil(IDENTITY_MAX_SIZE);
+    let mut identity = first.to_vec();
+    identity.resize(len, 0);
+    *stream = Some(Box::new(IdentityStream {
+        x3: authenticated,
+        x3_counter: c,
+        identity,
+        received: vec![false; count],
+        missing: count,
+        defrag: (0..count).map(|_| Fragged::new()).collect(),
+    }));
+    Ok(())
+}
+/// Assembles a fragment of the identity continuation sent with counter `c`.
+pub(crate) fn assemble_identity_continuation<C: CryptoLayer, E>(
+    zeta: &StateB2<C>,
+    c: u64,
+    fragment: C::IncomingPacketBuffer,
+    fragment_no: usize,
+    fragment_count: usize,
+    ret_assembled: &mut Assembled<C::IncomingPacketBuffer>,
+) -> Result<(), ReceiveError<C, E>> {
+    use FaultType::*;
+    let mut stream = zeta.identity_stream.lock();
+    let stream = stream.as_mut().ok_or_else(|| fault!(OutOfSequence, false))?;
+    let defrag = stream.defrag.get_mut((c as usize).wrapping_sub(1));
+    let defrag = defrag.ok_or_else(|| fault!(InvalidPacket, true))?;
+    defrag.assemble(c, fragment, fragment_no, fragment_count, ret_assembled);
+    Ok(())
+}
+/// Receives the part of a streamed identity sent with counter `c`.
+/// Returns whether the whole identity has now been received. Resends of a part that was already
+/// received are ignored.
+pub(crate) fn received_identity_continuation_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
+    zeta: &StateB2<C>,
+    c: u64,
+    ic: &mut [u8],
+) -> Result<bool, ReceiveError<C, E>> {
+    use FaultType::*;
+    let mut stream = zeta.identity_stream.lock();
+    // The handshake completion that starts the stream may have been lost or reordered, in which
+    // case Alice resends everything.
+    let stream = stream.as_mut().ok_or_else(|| fault!(OutOfSequence, false))?;
+    let i = (c as usize).wrapping_sub(1);
+    if i >= stream.received.len() {
+        return Err(fault!(InvalidPacket, true));
+    }
+    if stream.received[i] {
+        return Ok(false);
+    }
+    let start = IDENTITY_MAX_SIZE - STREAMED_IDENTITY_LENGTH_SIZE + i * IDENTITY_MAX_SIZE;
+    let end = stream.identity.len().min(start + IDENTITY_MAX_SIZE);
+    if ic.len() != end - start + AES_GCM_TAG_SIZE {
+        return Err(fault!(InvalidPacket, true));
+    }
+    let (chunk, tag) = ic.split_at_mut(end - start);
+    let kek_recv = (&stream.x3.kek_recv[..AES_256_KEY_SIZE]).try_into().unwrap();
+    let nonce = to_nonce(PACKET_TYPE_IDENTITY_CONTINUATION, c);
+    if !C::Aead::decrypt_in_place(kek_recv, &nonce, &[], chunk, (&*tag).try_into().unwrap()) {
+        return Err(fault!(FailedAuth, true));
+    }
+    capture!(app, Received, PACKET_TYPE_IDENTITY_CONTINUATION, c, chunk);
+    stream.identity[start..end].copy_from_slice(chunk);
+    stream.received[i] = true;
+    stream.missing -= 1;
+    Ok(stream.missing == 0)
+}
+/// Finishes the handshake once all of a streamed identity has been received, exactly like
+/// `received_x3_trans` would with the whole identity.
+pub(crate) fn received_streamed_identity_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
+    ctx: &Arc<ContextInner<C>>,
+    zeta: Arc<StateB2<C>>,
+    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
+) -> Result<(Arc<Session<C>>, bool, Option<i64>), ReceiveError<C, E>> {
+    use FaultType::*;
+    let stream = zeta.identity_stream.lock().take();
+    let stream = stream.ok_or_else(|| fault!(OutOfSequence, false))?;
+    accept_x3(app, ctx, &zeta, stream.x3, &stream.identity, stream.x3_counter, send)
+}
+/// The rest of Transition Algorithm 4, once Alice's identity is known.
+/// `x3_counter` is the counter of her handshake completion, which holds its flags.
+fn accept_x3<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
+    ctx: &Arc<ContextInner<C>>,
+    zeta: &StateB2<C>,
+    x3: AuthenticatedX3<C>,
+    identity: &[u8],
+    x3_counter: u64,
+    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
+) -> Result<(Arc<Session<C>>, bool, Option<i64>), ReceiveError<C, E>> {
+    use FaultType::*;
+    let hmac = &mut C::Hmac::new();
+    let AuthenticatedX3 { s_remote, noise, kek_send, kek_recv } = x3;
     let c = 0;
+    let encoded = x3_counter & ENCODED_COMPLETION_COUNTER != 0;
+    let misuse_resistant = x3_counter & MISUSE_RESISTANT_COMPLETION_COUNTER != 0;
+
+    // Alice can only select the misuse resistant AEAD if we offered it. An Alice that does not
+    // know our flags may not have noticed that we require it.
+    if misuse_resistant && !can_use_misuse_resistance::<C>(zeta.misuse_resistance) {
+        return Err(fault!(InvalidPacket, true));
+    }
+    if !misuse_resistant && zeta.misuse_resistance == MisuseResistance::Required {
+        capture!(app, Sent, PACKET_TYPE_SESSION_REJECTED, c, &[]);
+        let mut reject = create_reject(zeta, &kek_send);
+        send(&mut reject, Some(&C::PrpEnc::new(&zeta.hk_send)));
+        return Err(ReceiveError::Rejected(None));
+    }
 
-    let action = app.check_accept_session(&s_remote, &x3[identity_start..identity_end], zeta.lookup_data.as_ref());
+    // Encodings this build does not support are dropped rather than passed on as an identity.
+    let identity = if encoded {
+        let max_size = IDENTITY_MAX_SIZE.max(C::SETTINGS.max_streamed_identity_size);
+        decode_identity(identity, max_size).ok_or_else(|| fault!(InvalidPacket, true))?
+    } else {
+        Cow::Borrowed(identity)
+    };
+    let action = app.check_accept_session(&s_remote, &identity, zeta.lookup_data.as_ref());
     let responder_disallows_downgrade = action.responder_disallows_downgrade;
     let responder_silently_rejects = action.responder_silently_rejects;
-    let create_reject = || {
-        // We just used a counter with this key, but we are not storing
-        // the fact we used it in memory. This is currently ok because the
-        // handshake is being dropped, so nonce reuse can't happen.
-        let mut d = ArrayVec::<u8, HEADERED_SESSION_REJECTED_SIZE>::new();
-        d.extend([0u8; HEADER_SIZE]);
-        let nonce = to_nonce(PACKET_TYPE_SESSION_REJECTED, c);
-        let kek_send = (&kek_send[..AES_256_KEY_SIZE]).try_into().unwrap();
-        d.extend(C::Aead::encrypt_in_place(kek_send, &nonce, &[], &mut []));
-        set_header(&mut d, zeta.kid_send.get(), &nonce);
-        d
-    };
     if let Some(session_data) = action.session_data {
         let result = app.restore_by_identity(&s_remote, &session_data, zeta.lookup_data.as_ref());
         match result {
@@ -874,7 +1610,9 @@ pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                         should_warn_missing_ratchet = true;
                     } else {
                         if !responder_silently_rejects {
-                            send(&mut create_reject(), Some(&C::PrpEnc::new(&zeta.hk_send)))
+                            capture!(app, Sent, PACKET_TYPE_SESSION_REJECTED, c, &[]);
+                            let mut reject = create_reject(zeta, &kek_send);
+                            send(&mut reject, Some(&C::PrpEnc::new(&zeta.hk_send)))
                         }
                         return Err(fault!(FailedAuth, true));
                     }
@@ -883,7 +1621,12 @@ pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 let mut noise_kk_ss = Zeroizing::new([0u8; P384_ECDH_SHARED_SECRET_SIZE]);
                 ctx.s_secret.agree(&s_remote, &mut noise_kk_ss);
 
-                let new_ratchet_state = create_ratchet_state(hmac, &noise, zeta.ratchet_state.chain_len);
+                let new_ratchet_state = create_ratchet_state(
+                    hmac,
+                    &noise,
+                    zeta.ratchet_state.chain_len,
+                    zeta.ratchet_state.min_version.max(zeta.proto_version),
+                );
                 let mut nk_recv = Zeroizing::new([0u8; HASHLEN]);
                 let mut nk_send = Zeroizing::new([0u8; HASHLEN]);
                 noise.split(hmac, &mut nk_send, &mut nk_recv);
@@ -894,49 +1637,72 @@ pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                     &session_data,
                     CompareAndSwap::new(&new_ratchet_state, None, true, &state1, state2.as_ref(), true, true),
                 );
-                if !result.map_err(ReceiveError::StorageError)? {
+                if !result.map_err(ReceiveError::StorageWriteError)? {
                     return Err(fault!(OutOfSequence, true));
                 }
 
                 let (session, reduced_service_time) = {
-                    let mut session_map = ctx.session_map.write();
-                    use std::collections::hash_map::Entry::*;
-                    let entry = match session_map.entry(zeta.kid_recv) {
+                    let mut session_queue = ctx.session_queue.lock();
+                    let mut shard = ctx.session_map.shard(zeta.kid_recv).write();
+                    use crate::collections::hash_map::Entry::*;
+                    let entry = match shard.entry(zeta.kid_recv) {
                         // We could have issued the kid that we initially offered Alice to someone else
                         // before Alice was able to respond. It is unlikely but possible.
                         Occupied(_) => return Err(fault!(OutOfSequence, false)),
                         Vacant(entry) => entry,
                     };
-                    let mut session_queue = ctx.session_queue.lock();
                     let queue_idx = session_queue.reserve_index();
                     let current_time = app.time();
                     let resend_timer = current_time + C::SETTINGS.resend_time as i64;
+                    let state = MutableState {
+                        ratchet_state1: new_ratchet_state.clone(),
+                        ratchet_state2: None,
+                        hk_send: Arc::new(C::PrpEnc::new(&zeta.hk_send)),
+                        hk_recv: Arc::new(C::PrpDec::new(&zeta.hk_recv)),
+                        hk_send_key: zeta.hk_send.clone(),
+                        hk_recv_key: zeta.hk_recv.clone(),
+                        key_creation_counter: c + 1,
+                        key_index: false,
+                        keys: [DuplexKey::default(), DuplexKey::default()],
+                        rotated_kid_recv: None,
+                        kid_rotate_counter: 0,
+                        ratchet_fingerprint_used_at_handshake: (!zeta.ratchet_state.is_empty())
+                            .then(|| Zeroizing::new(*zeta.ratchet_state.fingerprint())),
+                        resend_timer: AtomicI64::new(resend_timer),
+                        timeout_timer: current_time + C::SETTINGS.rekey_timeout as i64,
+                        beta: ZetaAutomata::S1,
+                    };
                     let session = Arc::new(Session {
                         ctx: Arc::downgrade(ctx),
                         session_data,
                         was_bob: true,
                         s_remote,
                         send_counter: AtomicU64::new(c + 1),
+                        ordered_send_counter: AtomicU64::new(0),
+                        mtu_hint: AtomicUsize::new(0),
+                        rekey_after_key_uses: AtomicU64::new(C::SETTINGS.rekey_after_key_uses),
+                        checkpoint_key: AtomicU64::new(u64::MAX),
+                        checkpoint_limit: AtomicU64::new(u64::MAX),
                         state_machine_lock: Mutex::new(()),
-                        state: RwLock::new(MutableState {
-                            ratchet_state1: new_ratchet_state.clone(),
-                            ratchet_state2: None,
-                            hk_send: C::PrpEnc::new(&zeta.hk_send),
-                            hk_recv: C::PrpDec::new(&zeta.hk_recv),
-                            key_creation_counter: c + 1,
-                            key_index: false,
-                            keys: [DuplexKey::default(), DuplexKey::default()],
-                            resend_timer: AtomicI64::new(resend_timer),
-                            timeout_timer: current_time + C::SETTINGS.rekey_timeout as i64,
-                            beta: ZetaAutomata::S1,
-                        }),
-                        window: Window::new(),
+                        data_keys: ArcSwap::from_pointee(DataKeys::new(&state)),
+                        state: RwLock::new(state),
+                        window: new_window::<C>(),
+                        faults: FaultCounters::new(),
+                        kex: KexTimer::new(),
                         queue_idx,
+                        parked: AtomicBool::new(false),
+                        handshake_start_time: zeta.handshake_start_time,
+                        proto_version: AtomicU8::new(zeta.proto_version),
+                        misuse_resistant: AtomicBool::new(misuse_resistant),
+                        post_quantum: AtomicBool::new(zeta.post_quantum),
                         noise_kk_ss: noise_kk_ss.clone(),
-                        defrag: std::array::from_fn(|_| Mutex::new(Fragged::new())),
+                        #[cfg(feature = "std")]
+                        established_signal: (Mutex::new(Some(true)), Condvar::new()),
+                        defrag: core::array::from_fn(|_| Mutex::new(SessionFragBuffer::new())),
                     });
+                    ctx.metrics.record_session_created(true);
                     {
-                        let mut state = session.state.write();
+                        let mut state = session.write_state();
                         state.key_mut(false).replace_nk(&nk_send, &nk_recv);
                         state.key_mut(false).recv.kid = Some(zeta.kid_recv);
                         state.key_mut(false).recv.replace_kek(&kek_recv);
@@ -953,22 +1719,24 @@ pub(crate) fn received_x3_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 let mut c1 = ArrayVec::<u8, HEADERED_KEY_CONFIRMATION_SIZE>::new();
                 c1.extend([0u8; HEADER_SIZE]);
                 // This session is new so the result is overwhelmingly likely to be `Ok(())`.
-                let _ = send_control(&session, &state, PACKET_TYPE_KEY_CONFIRM, c1, send);
+                let _ = send_control(app, &session, &state, PACKET_TYPE_KEY_CONFIRM, c1, send);
                 drop(state);
 
                 Ok((session, should_warn_missing_ratchet, reduced_service_time))
             }
-            Err(e) => Err(ReceiveError::StorageError(e)),
+            Err(e) => Err(ReceiveError::StorageReadError(e)),
         }
     } else {
         if !responder_silently_rejects {
-            send(&mut create_reject(), Some(&C::PrpEnc::new(&zeta.hk_send)))
+            capture!(app, Sent, PACKET_TYPE_SESSION_REJECTED, c, &[]);
+            let mut reject = create_reject(zeta, &kek_send);
+            send(&mut reject, Some(&C::PrpEnc::new(&zeta.hk_send)))
         }
-        Err(ReceiveError::Rejected)
+        Err(ReceiveError::Rejected(None))
     }
 }
 /// Corresponds to Transition Algorithm 5 found in Section 4.3.
-pub(crate) fn received_c1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+pub(crate) fn received_c1_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
     app: &mut App,
     ctx: &Arc<ContextInner<C>>,
     session: &Arc<Session<C>>,
@@ -976,7 +1744,7 @@ pub(crate) fn received_c1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     n: &[u8; AES_GCM_NONCE_SIZE],
     c1: &[u8],
     send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
-) -> Result<(bool, Option<i64>), ReceiveError<C>> {
+) -> Result<(bool, Option<i64>), ReceiveError<C, E>> {
     use FaultType::*;
 
     if c1.len() != KEY_CONFIRMATION_SIZE {
@@ -1003,8 +1771,9 @@ pub(crate) fn received_c1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         return Err(fault!(FailedAuth, true, session));
     }
     let (_, c) = from_nonce(n);
+    capture!(app, Received, PACKET_TYPE_KEY_CONFIRM, c, &[]);
     if !session.window.update(c) {
-        return Err(fault!(ExpiredCounter, true, session));
+        return Err(fault!(ExpiredCounter, true, session, authenticated));
     }
     let mut reduced_service_time = None;
 
@@ -1025,7 +1794,7 @@ pub(crate) fn received_c1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                         true,
                     ),
                 );
-                if !result.map_err(ReceiveError::StorageError)? {
+                if !result.map_err(ReceiveError::StorageWriteError)? {
                     drop(state);
                     drop(kex_lock);
                     session.expire();
@@ -1033,17 +1802,20 @@ pub(crate) fn received_c1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 }
             }
             drop(state);
+            let rekey_time = next_rekey_time(app, ctx, session);
             let timeout_timer = {
-                let mut state = session.state.write();
+                let mut state = session.write_state();
                 state.ratchet_state2 = None;
                 state.key_index ^= true;
-                let jitter = ctx.rng.lock().next_u64() % C::SETTINGS.rekey_time_max_jitter;
-                state.timeout_timer = app.time() + C::SETTINGS.rekey_after_time.saturating_sub(jitter) as i64;
+                state.timeout_timer = rekey_time;
                 state.resend_timer = AtomicI64::new(i64::MAX);
                 state.beta = ZetaAutomata::S2;
                 state.timeout_timer
             };
             drop(kex_lock);
+            if just_establised {
+                session.signal_established(true);
+            }
             ctx.session_queue
                 .lock()
                 .change_priority(session.queue_idx, Reverse(timeout_timer));
@@ -1058,26 +1830,26 @@ pub(crate) fn received_c1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
 
     let mut c2 = ArrayVec::<u8, HEADERED_ACKNOWLEDGEMENT_SIZE>::new();
     c2.extend([0u8; HEADER_SIZE]);
-    match send_control(session, &state, PACKET_TYPE_ACK, c2, send) {
+    match send_control(app, session, &state, PACKET_TYPE_ACK, c2, send) {
         Ok(()) => Ok((just_establised, reduced_service_time)),
         Err(true) => {
             drop(state);
             session.expire();
             Err(fault!(ExpiredCounter, true, session, true))
         }
-        Err(false) => Err(fault!(OutOfSequence, true, session)),
+        Err(false) => Err(fault!(OutOfSequence, true, session, authenticated)),
     }
 }
 /// Corresponds to the trivial Transition Algorithm described for processing C_2 packets found in
 /// Section 4.3.
-pub(crate) fn received_c2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+pub(crate) fn received_c2_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
     app: &mut App,
     ctx: &Arc<ContextInner<C>>,
     session: &Arc<Session<C>>,
     kid: NonZeroU32,
     n: &[u8; AES_GCM_NONCE_SIZE],
     c2: &[u8],
-) -> Result<Option<i64>, ReceiveError<C>> {
+) -> Result<Option<i64>, ReceiveError<C, E>> {
     use FaultType::*;
 
     if c2.len() != ACKNOWLEDGEMENT_SIZE {
@@ -1089,9 +1861,12 @@ pub(crate) fn received_c2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
 
     if Some(kid) != state.key_ref(false).recv.kid {
         // Some acknowledgement may have arrived extremely delayed.
+        // This will also occur if an acknowledgement of anything other than a key id rotation
+        // arrives while a key id rotation is in progress.
         return Err(fault!(UnknownLocalKeyId, false, session));
     }
-    if !matches!(&state.beta, ZetaAutomata::S1) {
+    let is_kid_rotate_ack = matches!(&state.beta, ZetaAutomata::S3);
+    if !is_kid_rotate_ack && !matches!(&state.beta, ZetaAutomata::S1) {
         // Some acknowledgement may have arrived extremely delayed.
         return Err(fault!(OutOfSequence, false, session));
     }
@@ -1101,14 +1876,22 @@ pub(crate) fn received_c2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         return Err(fault!(FailedAuth, true, session));
     }
     let (_, c) = from_nonce(n);
+    capture!(app, Received, PACKET_TYPE_ACK, c, &[]);
     if !session.window.update(c) {
-        return Err(fault!(ExpiredCounter, true, session));
+        return Err(fault!(ExpiredCounter, true, session, authenticated));
     }
     drop(state);
+    let rekey_time = (!is_kid_rotate_ack).then(|| next_rekey_time(app, ctx, session));
     let timeout_timer = {
-        let mut state = session.state.write();
-        let jitter = ctx.rng.lock().next_u64() % C::SETTINGS.rekey_time_max_jitter;
-        state.timeout_timer = app.time() + C::SETTINGS.rekey_after_time.saturating_sub(jitter) as i64;
+        let mut state = session.write_state();
+        if is_kid_rotate_ack {
+            // The remote peer is now using our new key id, so the old one can be forgotten.
+            if let Some((old_kid, _)) = state.rotated_kid_recv.take() {
+                ctx.session_map.remove(&old_kid);
+            }
+        } else if let Some(rekey_time) = rekey_time {
+            state.timeout_timer = rekey_time;
+        }
         state.resend_timer = AtomicI64::new(i64::MAX);
         state.beta = ZetaAutomata::S2;
         state.timeout_timer
@@ -1122,12 +1905,13 @@ pub(crate) fn received_c2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
 }
 /// Corresponds to the trivial Transition Algorithm described for processing D packets found in
 /// Section 4.3.
-pub(crate) fn received_d_trans<C: CryptoLayer>(
+pub(crate) fn received_d_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
     session: &Arc<Session<C>>,
     kid: NonZeroU32,
     n: &[u8; AES_GCM_NONCE_SIZE],
     d: &[u8],
-) -> Result<(), ReceiveError<C>> {
+) -> Result<(), ReceiveError<C, E>> {
     use FaultType::*;
 
     if d.len() != SESSION_REJECTED_SIZE {
@@ -1146,8 +1930,9 @@ pub(crate) fn received_d_trans<C: CryptoLayer>(
         return Err(fault!(FailedAuth, true, session));
     }
     let (_, c) = from_nonce(n);
+    capture!(app, Received, PACKET_TYPE_SESSION_REJECTED, c, &[]);
     if !session.window.update(c) {
-        return Err(fault!(ExpiredCounter, true, session));
+        return Err(fault!(ExpiredCounter, true, session, authenticated));
     }
 
     drop(state);
@@ -1155,56 +1940,189 @@ pub(crate) fn received_d_trans<C: CryptoLayer>(
     session.expire();
     Ok(())
 }
-/// Corresponds to the timeout timer Transition Algorithm described in Section 4.1 - Definition 3.
-/// Returns `Err(())` if this session should be expired.
-fn timeout_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+/// Replaces the local key id of the current session key with a new random one, and tells the
+/// remote peer to address us with it from now on.
+pub(crate) fn rotate_kid<C: CryptoLayer, App: ApplicationLayer<C>>(
     app: &mut App,
     ctx: &Arc<ContextInner<C>>,
     session: &Arc<Session<C>>,
-    kex_lock: MutexGuard<'_, ()>,
-    state: RwLockReadGuard<'_, MutableState<C>>,
-    current_time: i64,
     send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
-) -> Result<i64, ()> {
+) -> Result<(NonZeroU32, Option<i64>), SendError> {
+    use SendError::*;
+    let kex_lock = session.state_machine_lock.lock();
+    let state = session.state.read();
     match &state.beta {
-        ZetaAutomata::Null => Err(()),
-        ZetaAutomata::A1(_) | ZetaAutomata::A3 { .. } => {
-            let identity = match &state.beta {
-                ZetaAutomata::A1(a1) => &a1.identity,
-                ZetaAutomata::A3(a3) => &a3.identity,
-                _ => unreachable!(),
-            };
-            if matches!(&state.beta, ZetaAutomata::A1(_)) {
-                log!(app, TimeoutX1(session));
-            } else {
-                log!(app, TimeoutX3(session));
-            }
-            let new_kid_recv = remap(ctx, session, &state);
+        ZetaAutomata::Null => return Err(SessionExpired),
+        ZetaAutomata::A1(_) | ZetaAutomata::A3(_) => return Err(SessionNotEstablished),
+        ZetaAutomata::S2 => {}
+        _ => return Err(KeyExchangeInProgress),
+    }
+    let old_kid = state.key_ref(false).recv.kid.ok_or(SessionNotEstablished)?;
+    let new_kid = {
+        // A previous rotation was abandoned because of a rekey, it is safe to forget it now.
+        if let Some((rotated_kid, _)) = state.rotated_kid_recv {
+            ctx.session_map.remove(&rotated_kid);
+        }
+        let (new_kid, mut shard) =
+            gen_kid_counter(&ctx.session_map, &ctx.kid_counter, &ctx.kid_prp).ok_or(KeyIdsExhausted)?;
+        shard.insert(new_kid, Arc::downgrade(session));
+        new_kid
+    };
+    drop(state);
+    let resend_timer = {
+        let mut state = session.write_state();
+        state.key_mut(false).recv.kid = Some(new_kid);
+        state.rotated_kid_recv = Some((old_kid, new_kid));
+        let resend_timer = app.time() + C::SETTINGS.resend_time as i64;
+        state.resend_timer = AtomicI64::new(resend_timer);
+        state.beta = ZetaAutomata::S3;
+        resend_timer
+    };
+    drop(kex_lock);
 
-            let hash = &mut C::Hash::new();
-            let hmac = &mut C::Hmac::new();
-            let a1 = create_a1_state(
-                hash,
-                hmac,
-                &ctx.rng,
-                &session.s_remote,
-                new_kid_recv,
-                &state.ratchet_state1,
-                state.ratchet_state2.as_ref(),
-                identity,
-            );
-            let mut hk_recv = Zeroizing::new([0u8; HASHLEN]);
-            let mut hk_send = Zeroizing::new([0u8; HASHLEN]);
-            a1.noise.get_ask(hmac, LABEL_HEADER_KEY, &mut hk_recv, &mut hk_send);
-            let mut x1 = a1.x1.clone();
+    let state = session.state.read();
+    let mut kr = ArrayVec::<u8, HEADERED_KID_ROTATE_SIZE>::new();
+    kr.extend([0u8; HEADER_SIZE]);
+    kr.extend(new_kid.get().to_ne_bytes());
+    let result = send_control(app, session, &state, PACKET_TYPE_KID_ROTATE, kr, send);
+    drop(state);
+    if let Err(true) = result {
+        session.expire();
+        return Err(SessionExpired);
+    }
+    log!(app, SentKidRotate(session));
 
-            drop(state);
-            let resend_timer = {
-                let mut state = session.state.write();
-                state.hk_recv.reset((&hk_recv[..AES_256_KEY_SIZE]).try_into().unwrap());
-                state.hk_send.reset((&hk_send[..AES_256_KEY_SIZE]).try_into().unwrap());
-                *state.key_mut(true) = DuplexKey::default();
-                state.key_mut(true).recv.kid = Some(new_kid_recv);
+    ctx.session_queue
+        .lock()
+        .change_priority(session.queue_idx, Reverse(resend_timer));
+    Ok((new_kid, ctx.reduce_next_service_time(resend_timer)))
+}
+/// Corresponds to the trivial Transition Algorithm for processing KR packets, where the remote peer
+/// has rotated its key id.
+pub(crate) fn received_kr_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
+    session: &Arc<Session<C>>,
+    kid: NonZeroU32,
+    n: &[u8; AES_GCM_NONCE_SIZE],
+    kr: &mut [u8],
+    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
+) -> Result<(), ReceiveError<C, E>> {
+    use FaultType::*;
+
+    if kr.len() != KID_ROTATE_SIZE {
+        return Err(fault!(InvalidPacket, true, session));
+    }
+
+    let kex_lock = session.state_machine_lock.lock();
+    let state = session.state.read();
+
+    let is_other = if Some(kid) == state.key_ref(true).recv.kid {
+        true
+    } else if Some(kid) == state.key_ref(false).recv.kid {
+        false
+    } else {
+        // The session may have been rekeyed since the rotation was sent.
+        return Err(fault!(UnknownLocalKeyId, false, session));
+    };
+    if matches!(&state.beta, ZetaAutomata::Null | ZetaAutomata::A1(_) | ZetaAutomata::A3(_)) {
+        return Err(fault!(OutOfSequence, true, session));
+    }
+
+    let i = kr.len() - AES_GCM_TAG_SIZE;
+    let tag = kr[i..].try_into().unwrap();
+    let kek_recv = state.key_ref(is_other).recv.kek.as_ref();
+    let kek_recv = kek_recv.ok_or_else(|| fault!(OutOfSequence, true, session))?;
+    if !C::Aead::decrypt_in_place(kek_recv, n, &[], &mut kr[..i], &tag) {
+        return Err(fault!(FailedAuth, true, session));
+    }
+    let (_, c) = from_nonce(n);
+    capture!(app, Received, PACKET_TYPE_KID_ROTATE, c, &kr[..i]);
+    if !session.window.update(c) {
+        return Err(fault!(ExpiredCounter, true, session, authenticated));
+    }
+    let new_kid = NonZeroU32::new(u32::from_ne_bytes(kr[..KID_SIZE].try_into().unwrap()));
+    let new_kid = new_kid.ok_or_else(|| fault!(InvalidPacket, true, session, authenticated))?;
+
+    drop(state);
+    {
+        let mut state = session.write_state();
+        // Resends of an older rotation may arrive after a newer one, only the newest can apply.
+        if c > state.kid_rotate_counter {
+            state.kid_rotate_counter = c;
+            state.key_mut(is_other).send.kid = Some(new_kid);
+        }
+    }
+    drop(kex_lock);
+
+    let state = session.state.read();
+    let mut c2 = ArrayVec::<u8, HEADERED_ACKNOWLEDGEMENT_SIZE>::new();
+    c2.extend([0u8; HEADER_SIZE]);
+    match send_control(app, session, &state, PACKET_TYPE_ACK, c2, send) {
+        Ok(()) => Ok(()),
+        Err(true) => {
+            drop(state);
+            session.expire();
+            Err(fault!(ExpiredCounter, true, session, true))
+        }
+        Err(false) => Err(fault!(OutOfSequence, true, session, authenticated)),
+    }
+}
+/// Corresponds to the timeout timer Transition Algorithm described in Section 4.1 - Definition 3.
+/// Returns `Err(())` if this session should be expired.
+fn timeout_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
+    ctx: &Arc<ContextInner<C>>,
+    session: &Arc<Session<C>>,
+    kex_lock: MutexGuard<'_, ()>,
+    state: RwLockReadGuard<'_, MutableState<C>>,
+    current_time: i64,
+    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
+) -> Result<i64, ()> {
+    match &state.beta {
+        ZetaAutomata::Null => Err(()),
+        ZetaAutomata::A1(_) | ZetaAutomata::A3 { .. } => {
+            let identity = match &state.beta {
+                ZetaAutomata::A1(a1) => &a1.identity,
+                ZetaAutomata::A3(a3) => &a3.identity,
+                _ => unreachable!(),
+            };
+            if matches!(&state.beta, ZetaAutomata::A1(_)) {
+                log!(app, TimeoutX1(session));
+            } else {
+                log!(app, TimeoutX3(session));
+            }
+            // If no key id is available the handshake is restarted on a later service.
+            let Some(new_kid_recv) = remap(ctx, session, &state) else {
+                return Ok(current_time + C::SETTINGS.resend_time as i64);
+            };
+
+            let hash = &mut C::Hash::new();
+            let hmac = &mut C::Hmac::new();
+            let a1 = create_a1_state(
+                app,
+                hash,
+                hmac,
+                ctx.rng(),
+                &session.s_remote,
+                new_kid_recv,
+                &state.ratchet_state1,
+                state.ratchet_state2.as_ref(),
+                identity,
+            );
+            let mut hk_recv = Zeroizing::new([0u8; HASHLEN]);
+            let mut hk_send = Zeroizing::new([0u8; HASHLEN]);
+            a1.noise.get_ask(hmac, LABEL_HEADER_KEY, &mut hk_recv, &mut hk_send);
+            let mut x1 = a1.x1.clone();
+
+            drop(state);
+            let resend_timer = {
+                let mut state = session.write_state();
+                state.hk_recv = Arc::new(C::PrpDec::new((&hk_recv[..AES_256_KEY_SIZE]).try_into().unwrap()));
+                state.hk_send = Arc::new(C::PrpEnc::new((&hk_send[..AES_256_KEY_SIZE]).try_into().unwrap()));
+                state.hk_recv_key.copy_from_slice(&hk_recv[..AES_256_KEY_SIZE]);
+                state.hk_send_key.copy_from_slice(&hk_send[..AES_256_KEY_SIZE]);
+                *state.key_mut(true) = DuplexKey::default();
+                state.key_mut(true).recv.kid = Some(new_kid_recv);
                 let resend_timer = current_time + C::SETTINGS.resend_time as i64;
                 state.resend_timer = AtomicI64::new(resend_timer);
                 state.timeout_timer = current_time + C::SETTINGS.initial_offer_timeout as i64;
@@ -1216,10 +2134,17 @@ fn timeout_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             send(&mut x1, None);
             Ok(resend_timer)
         }
-        ZetaAutomata::S2 => {
+        ZetaAutomata::S2 | ZetaAutomata::S3 => {
             // Corresponds to Transition Algorithm 6 found in Section 4.3.
+            // If a key id rotation is in progress it is abandoned. The old key id remains valid,
+            // so the remote peer can still reach us if it never received the rotation.
+            // If no key id is available the rekey is started on a later service.
+            let Some(new_kid_recv) = remap(ctx, session, &state) else {
+                return Ok(current_time + C::SETTINGS.resend_time as i64);
+            };
             log!(app, StartedRekeyingSentK1(session));
-            let new_kid_recv = remap(ctx, session, &state);
+            session.kex.start_rekey(current_time);
+            log!(app, RekeyStarted { session, timestamp_ms: current_time });
             //    -> s
             //    <- s
             //    ...
@@ -1235,7 +2160,7 @@ fn timeout_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             // Process message pattern 1 psk0 token.
             noise.mix_key_and_hash_no_init(hash, hmac, state.ratchet_state1.key.as_ref());
             // Process message pattern 1 e token.
-            let e_secret = noise.write_e_no_init(hash, hmac, &ctx.rng, &mut k1);
+            let e_secret = noise.write_e_no_init(hash, hmac, ctx.rng(), &mut k1);
             // Process message pattern 1 es token.
             noise.mix_dh_no_init(hmac, &e_secret, &session.s_remote);
             // Process message pattern 1 ss token.
@@ -1243,12 +2168,13 @@ fn timeout_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             // Process message pattern 1 payload.
             let i = k1.len();
             k1.extend(new_kid_recv.get().to_ne_bytes());
+            capture!(app, Sent, PACKET_TYPE_REKEY_INIT, 0, &k1[i..]);
             let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_REKEY_INIT, 0), &mut k1[i..]);
             k1.extend(tag);
 
             drop(state);
             let resend_timer = {
-                let mut state = session.state.write();
+                let mut state = session.write_state();
                 state.key_mut(true).recv.kid = Some(new_kid_recv);
                 state.timeout_timer = current_time + C::SETTINGS.rekey_timeout as i64;
                 let resend_timer = current_time + C::SETTINGS.resend_time as i64;
@@ -1259,7 +2185,7 @@ fn timeout_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             drop(kex_lock);
             let state = session.state.read();
 
-            match send_control(session, &state, PACKET_TYPE_REKEY_INIT, k1, send) {
+            match send_control(app, session, &state, PACKET_TYPE_REKEY_INIT, k1, send) {
                 Err(true) => Err(()),
                 _ => Ok(resend_timer),
             }
@@ -1285,7 +2211,7 @@ pub(crate) fn process_timers<C: CryptoLayer, App: ApplicationLayer<C>>(
     ctx: &Arc<ContextInner<C>>,
     session: &Arc<Session<C>>,
     current_time: i64,
-    send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
+    mut send: impl FnMut(&mut [u8], Option<&C::PrpEnc>),
 ) -> Result<i64, ()> {
     let kex_lock = session.state_machine_lock.lock();
     let state = session.state.read();
@@ -1298,36 +2224,56 @@ pub(crate) fn process_timers<C: CryptoLayer, App: ApplicationLayer<C>>(
         if ts <= current_time && state.resend_timer.fetch_max(resend_next, Ordering::Relaxed) == ts {
             // Corresponds to the resend timer rules found in Section 4.1 - Definition 3.
 
+            // Handshake packets are stored unfragmented, so they are re-fragmented on every resend
+            // according to whatever MTU the path currently has.
             let (packet_type, control_payload) = match &state.beta {
                 ZetaAutomata::Null => return Err(()),
                 ZetaAutomata::A1(a1) => {
                     log!(app, ResentX1(session));
+                    session.kex.resent();
                     send(&mut a1.x1.clone(), None);
                     return Ok(resend_next);
                 }
                 ZetaAutomata::A3(a3) => {
                     log!(app, ResentX3(session));
-                    send(&mut a3.x3.clone(), Some(&state.hk_send));
+                    session.kex.resent();
+                    send(&mut a3.x3.clone(), Some(&*state.hk_send));
+                    for ic in &a3.continuations {
+                        send(&mut ic.clone(), Some(&*state.hk_send));
+                    }
                     return Ok(resend_next);
                 }
                 ZetaAutomata::S1 => {
                     log!(app, ResentKeyConfirm(session));
+                    session.kex.resent();
                     let mut c1 = ArrayVec::new();
                     c1.extend([0u8; HEADER_SIZE]);
                     (PACKET_TYPE_KEY_CONFIRM, c1)
                 }
                 ZetaAutomata::S2 => return Ok(state.timeout_timer),
+                ZetaAutomata::S3 => {
+                    log!(app, ResentKidRotate(session));
+                    let mut kr = ArrayVec::new();
+                    kr.extend([0u8; HEADER_SIZE]);
+                    match state.rotated_kid_recv {
+                        Some((_, new_kid)) => kr.extend(new_kid.get().to_ne_bytes()),
+                        None => return Err(()),
+                    }
+                    (PACKET_TYPE_KID_ROTATE, kr)
+                }
                 ZetaAutomata::R1 { k1, .. } => {
                     log!(app, ResentK1(session));
+                    session.kex.resent();
                     (PACKET_TYPE_REKEY_INIT, k1.clone())
                 }
                 ZetaAutomata::R2 { k2, .. } => {
                     log!(app, ResentK2(session));
+                    session.kex.resent();
                     (PACKET_TYPE_REKEY_COMPLETE, k2.clone())
                 }
             };
 
-            match send_control(session, &state, packet_type, control_payload, send) {
+            match send_control(app, session, &state, packet_type, control_payload, send) {
                 Err(true) => Err(()),
                 _ => Ok(resend_next),
             }
@@ -1337,7 +2283,7 @@ pub(crate) fn process_timers<C: CryptoLayer, App: ApplicationLayer<C>>(
     }
 }
 /// Corresponds to Transition Algorithm 7 found in Section 4.3.
-pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
     app: &mut App,
     ctx: &Arc<ContextInner<C>>,
     session: &Arc<Session<C>>,
@@ -1345,7 +2291,7 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     n: &[u8; AES_GCM_NONCE_SIZE],
     k1: &mut [u8],
     send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
-) -> Result<Option<i64>, ReceiveError<C>> {
+) -> Result<(RekeyAction, Option<i64>), ReceiveError<C, E>> {
     use FaultType::*;
     //    -> s
     //    <- s
@@ -1363,12 +2309,12 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         // Some rekey packet may have arrived extremely delayed.
         return Err(fault!(UnknownLocalKeyId, false, session));
     }
-    let should_rekey_as_bob = match &state.beta {
-        ZetaAutomata::S2 { .. } => true,
+    let should_rekey_as_bob = |state: &MutableState<C>| match &state.beta {
+        ZetaAutomata::S2 | ZetaAutomata::S3 => true,
         ZetaAutomata::R1 { .. } => session.was_bob,
         _ => false,
     };
-    if !should_rekey_as_bob {
+    if !should_rekey_as_bob(&state) {
         // Some rekey packet may have arrived extremely delayed.
         return Err(fault!(OutOfSequence, false, session));
     }
@@ -1380,8 +2326,53 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         return Err(fault!(FailedAuth, true, session));
     }
     let (_, c) = from_nonce(n);
+    capture!(app, Received, PACKET_TYPE_REKEY_INIT, c, &k1[..i]);
     if !session.window.update(c) {
-        return Err(fault!(ExpiredCounter, true, session));
+        return Err(fault!(ExpiredCounter, true, session, authenticated));
+    }
+
+    // The application is consulted with no lock held, so it is free to call back into ZSSP.
+    drop(state);
+    drop(kex_lock);
+    let action = app.incoming_rekey(session);
+    let kex_lock = session.state_machine_lock.lock();
+    let state = session.state.read();
+    if Some(kid) != state.key_ref(false).recv.kid || !should_rekey_as_bob(&state) {
+        // The session expired, rekeyed or started rekeying itself in the meantime.
+        return Err(fault!(OutOfSequence, false, session, authenticated));
+    }
+    match action {
+        RekeyAction::Allow => {}
+        RekeyAction::Defer => {
+            if matches!(&state.beta, ZetaAutomata::R1 { .. }) {
+                // Our own rekey cannot complete while we are deferring the remote peer's,
+                // so we extend its timeout just as the remote peer will.
+                drop(state);
+                let mut state = session.write_state();
+                state.timeout_timer = state.timeout_timer.max(app.time() + C::SETTINGS.rekey_timeout as i64);
+            } else {
+                drop(state);
+            }
+            drop(kex_lock);
+            let state = session.state.read();
+            let mut rd = ArrayVec::<u8, HEADERED_REKEY_DEFER_SIZE>::new();
+            rd.extend([0u8; HEADER_SIZE]);
+            return match send_control(app, session, &state, PACKET_TYPE_REKEY_DEFER, rd, send) {
+                Ok(()) => Ok((RekeyAction::Defer, None)),
+                Err(false) => Err(fault!(OutOfSequence, true, session, authenticated)),
+                Err(true) => {
+                    drop(state);
+                    session.expire();
+                    Err(fault!(ExpiredCounter, true, session, true))
+                }
+            };
+        }
+        RekeyAction::Reject => {
+            drop(state);
+            drop(kex_lock);
+            session.expire();
+            return Ok((RekeyAction::Reject, None));
+        }
     }
 
     let result = (move || {
@@ -1409,25 +2400,37 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_REKEY_INIT, 0), &mut k1[i..j], tag) {
             return Err(fault!(FailedAuth, true, session, true));
         }
+        capture!(app, Received, PACKET_TYPE_REKEY_INIT, 0, &k1[i..j]);
         let kid_send = NonZeroU32::new(u32::from_ne_bytes(k1[i..j].try_into().unwrap()))
             .ok_or_else(|| fault!(FailedAuth, true, session, true))?;
 
         let mut k2 = ArrayVec::<u8, HEADERED_REKEY_SIZE>::new();
         k2.extend([0u8; HEADER_SIZE]);
         // Process message pattern 2 e token.
-        let e_secret = noise.write_e_no_init(hash, hmac, &ctx.rng, &mut k2);
+        let e_secret = noise.write_e_no_init(hash, hmac, ctx.rng(), &mut k2);
         // Process message pattern 2 ee token.
         noise.mix_dh_no_init(hmac, &e_secret, &e_remote);
         // Process message pattern 2 se token.
         noise.mix_dh(hmac, &ctx.s_secret, &e_remote);
         // Process message pattern 2 payload.
         let i = k2.len();
-        let new_kid_recv = remap(ctx, session, &state);
+        let Some(new_kid_recv) = remap(ctx, session, &state) else {
+            // Rather than leave the remote peer resending K1 until its rekey times out, we ask it
+            // to retry later, by when key ids may have been freed. If the deferral cannot be sent
+            // the remote peer just resends K1.
+            let mut rd = ArrayVec::<u8, HEADERED_REKEY_DEFER_SIZE>::new();
+            rd.extend([0u8; HEADER_SIZE]);
+            let _ = send_control(app, session, &state, PACKET_TYPE_REKEY_DEFER, rd, send);
+            return Err(ReceiveError::KeyIdsExhausted);
+        };
         k2.extend(new_kid_recv.get().to_ne_bytes());
+        capture!(app, Sent, PACKET_TYPE_REKEY_COMPLETE, 0, &k2[i..]);
         let tag = noise.encrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_REKEY_COMPLETE, 0), &mut k2[i..]);
         k2.extend(tag);
 
-        let new_ratchet_state = create_ratchet_state(hmac, &noise, state.ratchet_state1.chain_len);
+        let proto_version = session.proto_version.load(Ordering::Relaxed);
+        let min_version = state.ratchet_state1.min_version.max(proto_version);
+        let new_ratchet_state = create_ratchet_state(hmac, &noise, state.ratchet_state1.chain_len, min_version);
         let result = app.save_ratchet_state(
             &session.s_remote,
             &session.session_data,
@@ -1441,7 +2444,7 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 true,
             ),
         );
-        if !result.map_err(ReceiveError::StorageError)? {
+        if !result.map_err(ReceiveError::StorageWriteError)? {
             return Err(fault!(OutOfSequence, true, session, true));
         }
 
@@ -1454,7 +2457,7 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
 
         drop(state);
         let resend_timer = {
-            let mut state = session.state.write();
+            let mut state = session.write_state();
             state.key_mut(true).replace_nk(&nk_send, &nk_recv);
             state.key_mut(true).send.kid = Some(kid_send);
             state.key_mut(true).send.replace_kek(&kek_send);
@@ -1476,9 +2479,9 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             .change_priority(session.queue_idx, Reverse(resend_timer));
         let reduced_service_time = ctx.reduce_next_service_time(resend_timer);
         let state = session.state.read();
-        match send_control(session, &state, PACKET_TYPE_REKEY_COMPLETE, k2, send) {
+        match send_control(app, session, &state, PACKET_TYPE_REKEY_COMPLETE, k2, send) {
             Ok(()) => Ok(reduced_service_time),
-            Err(false) => Err(fault!(OutOfSequence, true, session)),
+            Err(false) => Err(fault!(OutOfSequence, true, session, authenticated)),
             Err(true) => Err(fault!(ExpiredCounter, true, session, true)),
         }
     })();
@@ -1489,10 +2492,63 @@ pub(crate) fn received_k1_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
         }
         _ => {}
     }
-    result
+    result.map(|reduced_service_time| (RekeyAction::Allow, reduced_service_time))
+}
+/// Processes a request from the remote peer to retry our rekey later, which it sends when its
+/// `ApplicationLayer::incoming_rekey` returns `RekeyAction::Defer`.
+pub(crate) fn received_rd_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
+    app: &mut App,
+    ctx: &Arc<ContextInner<C>>,
+    session: &Arc<Session<C>>,
+    kid: NonZeroU32,
+    n: &[u8; AES_GCM_NONCE_SIZE],
+    rd: &[u8],
+) -> Result<Option<i64>, ReceiveError<C, E>> {
+    use FaultType::*;
+
+    if rd.len() != REKEY_DEFER_SIZE {
+        return Err(fault!(InvalidPacket, true, session));
+    }
+
+    let kex_lock = session.state_machine_lock.lock();
+    let state = session.state.read();
+
+    if Some(kid) != state.key_ref(false).recv.kid {
+        // Some rekey packet may have arrived extremely delayed.
+        return Err(fault!(UnknownLocalKeyId, false, session));
+    }
+    if !matches!(&state.beta, ZetaAutomata::R1 { .. }) {
+        // A deferral may arrive after a later request of ours was accepted.
+        return Err(fault!(OutOfSequence, false, session));
+    }
+
+    let tag = rd[..].try_into().unwrap();
+    if !C::Aead::decrypt_in_place(state.key_ref(false).recv.kek.as_ref().unwrap(), n, &[], &mut [], tag) {
+        return Err(fault!(FailedAuth, true, session));
+    }
+    let (_, c) = from_nonce(n);
+    capture!(app, Received, PACKET_TYPE_REKEY_DEFER, c, &[]);
+    if !session.window.update(c) {
+        return Err(fault!(ExpiredCounter, true, session, authenticated));
+    }
+
+    drop(state);
+    let resend_timer = {
+        let mut state = session.write_state();
+        let current_time = app.time();
+        let resend_timer = current_time + C::SETTINGS.resend_time as i64;
+        state.timeout_timer = current_time + C::SETTINGS.rekey_timeout as i64;
+        state.resend_timer = AtomicI64::new(resend_timer);
+        resend_timer
+    };
+    drop(kex_lock);
+    ctx.session_queue
+        .lock()
+        .change_priority(session.queue_idx, Reverse(resend_timer));
+    Ok(ctx.reduce_next_service_time(resend_timer))
 }
 /// Corresponds to Transition Algorithm 8 found in Section 4.3.
-pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
+pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>, E>(
     app: &mut App,
     ctx: &Arc<ContextInner<C>>,
     session: &Arc<Session<C>>,
@@ -1500,7 +2556,7 @@ pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     n: &[u8; AES_GCM_NONCE_SIZE],
     k2: &mut [u8],
     send: impl FnOnce(&mut [u8], Option<&C::PrpEnc>),
-) -> Result<Option<i64>, ReceiveError<C>> {
+) -> Result<Option<i64>, ReceiveError<C, E>> {
     use FaultType::*;
     //    <- e, ee, se
     if k2.len() != REKEY_SIZE {
@@ -1523,8 +2579,9 @@ pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                 return Err(fault!(FailedAuth, true, session));
             }
             let (_, c) = from_nonce(n);
+            capture!(app, Received, PACKET_TYPE_REKEY_COMPLETE, c, &k2[..i]);
             if !session.window.update(c) {
-                return Err(fault!(ExpiredCounter, true, session));
+                return Err(fault!(ExpiredCounter, true, session, authenticated));
             }
 
             let mut noise = noise.clone();
@@ -1546,10 +2603,13 @@ pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
             if !noise.decrypt_and_hash_in_place(hash, to_nonce(PACKET_TYPE_REKEY_COMPLETE, 0), &mut k2[i..j], tag) {
                 return Err(fault!(FailedAuth, true, session, true));
             }
+            capture!(app, Received, PACKET_TYPE_REKEY_COMPLETE, 0, &k2[i..j]);
             let kid_send = NonZeroU32::new(u32::from_ne_bytes(k2[i..j].try_into().unwrap()))
                 .ok_or_else(|| fault!(InvalidPacket, true, session, true))?;
 
-            let new_ratchet_state = create_ratchet_state(hmac, &noise, state.ratchet_state1.chain_len);
+            let proto_version = session.proto_version.load(Ordering::Relaxed);
+            let min_version = state.ratchet_state1.min_version.max(proto_version);
+            let new_ratchet_state = create_ratchet_state(hmac, &noise, state.ratchet_state1.chain_len, min_version);
             let result = app.save_ratchet_state(
                 &session.s_remote,
                 &session.session_data,
@@ -1563,7 +2623,7 @@ pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
                     true,
                 ),
             );
-            if !result.map_err(ReceiveError::StorageError)? {
+            if !result.map_err(ReceiveError::StorageWriteError)? {
                 return Err(fault!(OutOfSequence, true, session, true));
             }
 
@@ -1576,7 +2636,7 @@ pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
 
             drop(state);
             let resend_timer = {
-                let mut state = session.state.write();
+                let mut state = session.write_state();
                 state.key_mut(true).replace_nk(&nk_send, &nk_recv);
                 state.key_mut(true).send.kid = Some(kid_send);
                 state.key_mut(true).send.replace_kek(&kek_send);
@@ -1600,9 +2660,9 @@ pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
 
             let mut c1 = ArrayVec::<u8, HEADERED_KEY_CONFIRMATION_SIZE>::new();
             c1.extend([0u8; HEADER_SIZE]);
-            match send_control(session, &state, PACKET_TYPE_KEY_CONFIRM, c1, send) {
+            match send_control(app, session, &state, PACKET_TYPE_KEY_CONFIRM, c1, send) {
                 Ok(()) => Ok(reduced_service_time),
-                Err(false) => Err(fault!(OutOfSequence, true, session)),
+                Err(false) => Err(fault!(OutOfSequence, true, session, authenticated)),
                 Err(true) => Err(fault!(ExpiredCounter, true, session, true)),
             }
         } else {
@@ -1619,37 +2679,148 @@ pub(crate) fn received_k2_trans<C: CryptoLayer, App: ApplicationLayer<C>>(
     }
     result
 }
+/// Stops all timers of this session by taking it out of the session queue.
+pub(crate) fn park_session<C: CryptoLayer>(ctx: &Arc<ContextInner<C>>, session: &Arc<Session<C>>) {
+    let mut session_queue = ctx.session_queue.lock();
+    if !session.parked.swap(true, Ordering::Relaxed) {
+        session_queue.unlink(session.queue_idx);
+    }
+}
+/// Places a parked session back into the session queue with freshly started timers.
+pub(crate) fn unpark_session<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session: &Arc<Session<C>>,
+    current_time: i64,
+) -> Option<i64> {
+    let mut session_queue = ctx.session_queue.lock();
+    if !session.parked.swap(false, Ordering::Relaxed) {
+        return None;
+    }
+    let _kex_lock = session.state_machine_lock.lock();
+    let mut state = session.write_state();
+    let timeout = match &state.beta {
+        ZetaAutomata::Null => return None,
+        ZetaAutomata::A1(_) | ZetaAutomata::A3(_) => C::SETTINGS.initial_offer_timeout,
+        ZetaAutomata::S1 | ZetaAutomata::R1 { .. } | ZetaAutomata::R2 { .. } => C::SETTINGS.rekey_timeout,
+        // The session key keeps aging while parked, so we do not extend its lifetime.
+        ZetaAutomata::S2 | ZetaAutomata::S3 => 0,
+    };
+    if timeout > 0 {
+        state.timeout_timer = current_time + timeout as i64;
+    }
+    // Anything we were waiting to resend should be sent as soon as possible.
+    if state.resend_timer.load(Ordering::Relaxed) != i64::MAX {
+        state.resend_timer = AtomicI64::new(current_time);
+    }
+    let next_timer = state.timeout_timer.min(state.resend_timer.load(Ordering::Relaxed));
+    drop(state);
+    session_queue.push_reserved(session.queue_idx, Arc::downgrade(session), Reverse(next_timer));
+    ctx.reduce_next_service_time(next_timer)
+}
+/// Expires the session and erases every secret it holds, including its ratchet states, which are
+/// returned so they can be deleted from storage.
+pub(crate) fn wipe_session<C: CryptoLayer>(ctx: &Arc<ContextInner<C>>, session: &Session<C>) -> RatchetStates {
+    let mut session_queue = ctx.session_queue.lock();
+    let kex_lock = session.state_machine_lock.lock();
+    let mut state = session.write_state();
+    let retired = session.retire_data_keys();
+    let ratchet_states = RatchetStates::new(core::mem::take(&mut state.ratchet_state1), state.ratchet_state2.take());
+    state.ratchet_fingerprint_used_at_handshake = None;
+    state.hk_send_key = Zeroizing::default();
+    state.hk_recv_key = Zeroizing::default();
+    state.hk_send = Arc::new(C::PrpEnc::new(&state.hk_send_key));
+    state.hk_recv = Arc::new(C::PrpDec::new(&state.hk_recv_key));
+    session.expire_locked(&mut state, Some(ctx), Some(&mut session_queue));
+    drop(state);
+    drop(kex_lock);
+    drop(session_queue);
+    // Wait out every thread that is still sending or receiving with the keys of this session, so
+    // they are erased by the time we return.
+    Session::wait_retired(retired);
+    ratchet_states
+}
+/// The state of encrypting a data packet, see `Session::is_misuse_resistant`.
+enum DataEnc<'a, P: HighThroughputAesGcmPool + 'a> {
+    Gcm(P::EncContext<'a>),
+    /// The misuse resistant AEAD cannot be streamed, so the whole packet is encrypted up front and
+    /// its ciphertext only copied into each fragment.
+    Siv(Vec<u8>, [u8; AES_GCM_TAG_SIZE]),
+}
+impl<'a, P: HighThroughputAesGcmPool> DataEnc<'a, P> {
+    fn finish(self, pool: &'a P) -> [u8; AES_GCM_TAG_SIZE] {
+        match self {
+            DataEnc::Gcm(cipher) => pool.finish_enc(cipher),
+            DataEnc::Siv(_, tag) => tag,
+        }
+    }
+}
+/// Stream encrypt bytes `range` of `prefix` followed by `payload` into `output`.
+fn encrypt_range<'a, P: HighThroughputAesGcmPool>(
+    pool: &'a P,
+    cipher: &mut DataEnc<'a, P>,
+    prefix: &[u8],
+    payload: &[u8],
+    range: Range<usize>,
+    output: &mut [u8],
+) {
+    let cipher = match cipher {
+        DataEnc::Gcm(cipher) => cipher,
+        DataEnc::Siv(ciphertext, _) => return output.copy_from_slice(&ciphertext[range]),
+    };
+    let split = prefix.len().clamp(range.start, range.end);
+    let (prefix_output, payload_output) = output.split_at_mut(split - range.start);
+    if !prefix_output.is_empty() {
+        pool.encrypt(cipher, &prefix[range.start..split], prefix_output);
+    }
+    if !payload_output.is_empty() {
+        let payload_range = split - prefix.len()..range.end - prefix.len();
+        pool.encrypt(cipher, &payload[payload_range], payload_output);
+    }
+}
 /// Corresponds to Algorithm 9 found in Section 4.3.
+///
+/// The plaintext sent is `prefix` followed by `payload`, and `aad` is authenticated along with it.
 pub(crate) fn send_payload<C: CryptoLayer>(
     ctx: &Arc<ContextInner<C>>,
     session: &Session<C>,
+    aad: &[u8],
+    prefix: &[u8],
     payload: &[u8],
     mut send: impl Sender,
     mtu_sized_buffer: &mut [u8],
 ) -> Result<bool, SendError> {
     use SendError::*;
     let mtu = mtu_sized_buffer.len();
+    let payload_len = prefix.len() + payload.len();
     if mtu < MIN_TRANSPORT_MTU {
         return Err(MtuTooSmall);
     }
 
-    let state = session.state.read();
-    if matches!(&state.beta, ZetaAutomata::Null) {
+    let keys = session.load_data_keys();
+    if keys.expired {
         return Err(SessionExpired);
     }
-    let (c, mut should_rekey) = match get_counter(session, &state) {
-        Some(c) => c,
-        None => {
-            drop(state);
+    let (c, mut should_rekey) = match get_counter(session, keys.key_creation_counter, REKEY_URGENTLY_AFTER_USES) {
+        Ok(c) => c,
+        Err(true) => {
+            drop(keys);
             session.expire();
             return Err(SessionExpired);
         }
+        Err(false) => {
+            let (can_rekey, key_creation_counter) = (keys.can_rekey, keys.key_creation_counter);
+            drop(keys);
+            if can_rekey {
+                start_rekey_now(ctx, session, key_creation_counter);
+            }
+            return Err(RekeyUrgentlyNeeded);
+        }
     };
     let nonce = to_nonce(PACKET_TYPE_DATA, c);
 
     let payload_mtu = mtu - HEADER_SIZE;
     debug_assert!(payload_mtu >= 4);
-    let tagged_payload_len = payload.len() + AES_GCM_TAG_SIZE;
+    let tagged_payload_len = payload_len + AES_GCM_TAG_SIZE;
     let fragment_count = tagged_payload_len.saturating_add(payload_mtu - 1) / payload_mtu; // Ceiling div.
     let fragment_base_size = tagged_payload_len / fragment_count;
     let fragment_size_remainder = tagged_payload_len % fragment_count;
@@ -1657,15 +2828,16 @@ pub(crate) fn send_payload<C: CryptoLayer>(
         return Err(DataTooLarge);
     }
 
-    debug_assert!(matches!(
-        &state.beta,
-        ZetaAutomata::S1 | ZetaAutomata::S2 | ZetaAutomata::R1 { .. } | ZetaAutomata::R2 { .. }
-    ));
-
-    let key = state.key_ref(false);
-    let kid_send = key.send.kid.ok_or(SessionNotEstablished)?.get().to_ne_bytes();
-    let cipher_pool = key.nk.as_ref().ok_or(SessionNotEstablished)?;
-    let mut cipher = cipher_pool.start_enc(&nonce);
+    let key = keys.key_ref(false);
+    let kid_send = key.kid_send.ok_or(SessionNotEstablished)?.get().to_ne_bytes();
+    let cipher_pool = key.nk.as_deref().ok_or(SessionNotEstablished)?;
+    let mut cipher = if session.misuse_resistant.load(Ordering::Relaxed) {
+        let mut ciphertext = [prefix, payload].concat();
+        let tag = cipher_pool.encrypt_siv(&nonce, aad, &mut ciphertext);
+        DataEnc::Siv(ciphertext, tag)
+    } else {
+        DataEnc::Gcm(cipher_pool.start_enc(&nonce, aad))
+    };
 
     let mut header = [0u8; HEADER_SIZE];
     header[..KID_SIZE].copy_from_slice(&kid_send);
@@ -1680,118 +2852,315 @@ pub(crate) fn send_payload<C: CryptoLayer>(
         mtu_sized_buffer[..HEADER_SIZE].copy_from_slice(&header);
         mtu_sized_buffer[FRAGMENT_NO_IDX] = fragment_no as u8;
         let fragment_start = &mut mtu_sized_buffer[HEADER_SIZE..HEADER_SIZE + fragment_len];
-        cipher_pool.encrypt(&mut cipher, &payload[i..j], fragment_start);
+        encrypt_range(cipher_pool, &mut cipher, prefix, payload, i..j, fragment_start);
 
         let header_auth = &mut mtu_sized_buffer[HEADER_AUTH_START..HEADER_AUTH_END];
-        state.hk_send.encrypt_in_place(header_auth.try_into().unwrap());
+        keys.hk_send.encrypt_in_place(header_auth.try_into().unwrap());
 
         if !send.send_frag(&mut mtu_sized_buffer[..HEADER_SIZE + fragment_len]) {
             // We need to give the cipher back to the pool instead of dropping it,
             // so it can do memory cleanup.
-            cipher_pool.finish_enc(cipher);
+            cipher.finish(cipher_pool);
             return Ok(false);
         }
         i = j;
     }
     let fragment_no = fragment_count - 1;
-    let payload_rem = payload.len() - i;
+    let payload_rem = payload_len - i;
     let fragment_len = payload_rem + AES_GCM_TAG_SIZE;
     debug_assert_eq!(fragment_len, fragment_base_size);
 
     mtu_sized_buffer[..HEADER_SIZE].copy_from_slice(&header);
     mtu_sized_buffer[FRAGMENT_NO_IDX] = fragment_no as u8;
     let fragment_start = &mut mtu_sized_buffer[HEADER_SIZE..HEADER_SIZE + payload_rem];
-    cipher_pool.encrypt(&mut cipher, &payload[i..], fragment_start);
+    let range = i..payload_len;
+    encrypt_range(cipher_pool, &mut cipher, prefix, payload, range, fragment_start);
     mtu_sized_buffer[HEADER_SIZE + payload_rem..HEADER_SIZE + fragment_len]
-        .copy_from_slice(&cipher_pool.finish_enc(cipher));
+        .copy_from_slice(&cipher.finish(cipher_pool));
 
     let header_auth = &mut mtu_sized_buffer[HEADER_AUTH_START..HEADER_AUTH_END];
-    state.hk_send.encrypt_in_place(header_auth.try_into().unwrap());
+    keys.hk_send.encrypt_in_place(header_auth.try_into().unwrap());
 
     if !send.send_frag(&mut mtu_sized_buffer[..HEADER_SIZE + fragment_len]) {
         return Ok(false);
     }
+    Metrics::record_data(&ctx.metrics.data_packets_tx, &ctx.metrics.bytes_tx, payload_len);
 
-    should_rekey &= matches!(&state.beta, ZetaAutomata::S2);
-    drop(state);
+    should_rekey &= keys.can_rekey;
+    let key_creation_counter = keys.key_creation_counter;
+    drop(keys);
 
     if should_rekey {
-        let mut state = session.state.write();
-        state.timeout_timer = i64::MIN;
-        drop(state);
-        ctx.session_queue
-            .lock()
-            .change_priority(session.queue_idx, Reverse(i64::MIN));
-        ctx.reduce_next_service_time(i64::MIN);
-        Ok(true)
+        Ok(start_rekey_now(ctx, session, key_creation_counter))
     } else {
         Ok(false)
     }
 }
+/// Has the next service of the context start rekeying the key created at `key_creation_counter`.
+/// Returns whether a rekey was scheduled.
+fn start_rekey_now<C: CryptoLayer>(ctx: &ContextInner<C>, session: &Session<C>, key_creation_counter: u64) -> bool {
+    let mut state = session.write_state();
+    // Our snapshot of the keys may be stale by now, in which case a rekey could already be in
+    // progress or have completed, and moving the timer would cut it short.
+    if !matches!(&state.beta, ZetaAutomata::S2 | ZetaAutomata::S3) || state.key_creation_counter != key_creation_counter
+    {
+        return false;
+    }
+    state.timeout_timer = i64::MIN;
+    drop(state);
+    ctx.session_queue
+        .lock()
+        .change_priority(session.queue_idx, Reverse(i64::MIN));
+    ctx.reduce_next_service_time(i64::MIN);
+    true
+}
 /// Corresponds to Algorithm 10 found in Section 4.3.
-pub(crate) fn receive_payload_in_place<C: CryptoLayer>(
+///
+/// Decrypts and authenticates the fragments of a data packet in place, and returns the length of
+/// the plaintext contained in the final fragment. The plaintext of every other fragment is
+/// everything after its header.
+///
+/// `aad` is authenticated once for the whole packet, however many fragments it was sent in.
+fn decrypt_payload_in_place<C: CryptoLayer, B: AsRef<[u8]> + AsMut<[u8]>, E>(
     session: &Arc<Session<C>>,
-    state: RwLockReadGuard<'_, MutableState<C>>,
+    keys: &DataKeys<C>,
     kid: NonZeroU32,
     nonce: &[u8; AES_GCM_NONCE_SIZE],
-    fragments: &mut [C::IncomingPacketBuffer],
-    mut output_buffer: impl Write,
-) -> Result<(), ReceiveError<C>> {
+    aad: &[u8],
+    fragments: &mut [B],
+) -> Result<usize, ReceiveError<C, E>> {
     use FaultType::*;
     debug_assert!(!fragments.is_empty());
 
-    let specified_key = if Some(kid) == state.keys[0].recv.kid {
-        state.keys[0].nk.as_ref()
-    } else if Some(kid) == state.keys[1].recv.kid {
-        state.keys[1].nk.as_ref()
+    let specified_key = if Some(kid) == keys.keys[0].kid_recv {
+        keys.keys[0].nk.as_ref()
+    } else if Some(kid) == keys.keys[1].kid_recv {
+        keys.keys[1].nk.as_ref()
     } else {
         // Should be unreachable unless we are leaking kids somewhere.
         return Err(fault!(UnknownLocalKeyId, true, session));
     };
 
     let cipher_pool = specified_key.ok_or_else(|| fault!(OutOfSequence, true, session))?;
-    let mut cipher = cipher_pool.start_dec(nonce);
     let (_, c) = from_nonce(nonce);
 
     // NOTE: This only works because we check the size of every received fragment in the receive
     // function, otherwise this could panic.
-    for i in 0..fragments.len() - 1 {
-        let fragment = &mut fragments[i].as_mut()[HEADER_SIZE..];
-        debug_assert!(fragment.len() >= AES_GCM_TAG_SIZE);
-        cipher_pool.decrypt_in_place(&mut cipher, fragment);
-    }
-    let fragment = &mut fragments[fragments.len() - 1].as_mut()[HEADER_SIZE..];
-    debug_assert!(fragment.len() >= AES_GCM_TAG_SIZE);
-    let tag_idx = fragment.len() - AES_GCM_TAG_SIZE;
-    cipher_pool.decrypt_in_place(&mut cipher, &mut fragment[..tag_idx]);
-
-    if !cipher_pool.finish_dec(cipher, (&fragment[tag_idx..]).try_into().unwrap()) {
+    let tag_idx = fragments[fragments.len() - 1].as_ref().len() - HEADER_SIZE - AES_GCM_TAG_SIZE;
+    let authentic = if session.misuse_resistant.load(Ordering::Relaxed) {
+        decrypt_siv_in_place(&**cipher_pool, nonce, aad, fragments, tag_idx)
+    } else {
+        let mut cipher = cipher_pool.start_dec(nonce, aad);
+        for i in 0..fragments.len() - 1 {
+            let fragment = &mut fragments[i].as_mut()[HEADER_SIZE..];
+            debug_assert!(fragment.len() >= AES_GCM_TAG_SIZE);
+            cipher_pool.decrypt_in_place(&mut cipher, fragment);
+        }
+        let fragment = &mut fragments[fragments.len() - 1].as_mut()[HEADER_SIZE..];
+        cipher_pool.decrypt_in_place(&mut cipher, &mut fragment[..tag_idx]);
+        cipher_pool.finish_dec(cipher, (&fragment[tag_idx..]).try_into().unwrap())
+    };
+    if !authentic {
         return Err(fault!(FailedAuth, true, session));
     }
 
     if !session.window.update(c) {
         // This error is marked as not happening naturally, but it could occur if something about
         // the transport protocol is duplicating packets.
-        return Err(fault!(ExpiredCounter, true, session));
+        return Err(fault!(ExpiredCounter, true, session, authenticated));
     }
 
+    Ok(tag_idx)
+}
+/// Decrypts the fragments of a data packet in place with the misuse resistant AEAD of `pool`, see
+/// `decrypt_payload_in_place`. It cannot be streamed, so the ciphertext of every fragment is
+/// gathered into one buffer, and the plaintext is only scattered back into the fragments once it
+/// has been authenticated.
+fn decrypt_siv_in_place<P: HighThroughputAesGcmPool, B: AsRef<[u8]> + AsMut<[u8]>>(
+    pool: &P,
+    nonce: &[u8; AES_GCM_NONCE_SIZE],
+    aad: &[u8],
+    fragments: &mut [B],
+    tag_idx: usize,
+) -> bool {
+    let (last, rest) = fragments.split_last_mut().unwrap();
+    let (last, tag) = last.as_mut()[HEADER_SIZE..].split_at_mut(tag_idx);
+    let mut data = Zeroizing::new(Vec::new());
+    for fragment in rest.iter() {
+        data.extend_from_slice(&fragment.as_ref()[HEADER_SIZE..]);
+    }
+    data.extend_from_slice(last);
+    if !pool.decrypt_siv(nonce, aad, &mut data, (&*tag).try_into().unwrap()) {
+        return false;
+    }
+    let mut i = 0;
+    for fragment in rest.iter_mut().map(|f| &mut f.as_mut()[HEADER_SIZE..]).chain([last]) {
+        fragment.copy_from_slice(&data[i..i + fragment.len()]);
+        i += fragment.len();
+    }
+    true
+}
+/// Decrypts a data packet in place and writes its plaintext to `output_buffer`, returning the
+/// length of the plaintext.
+pub(crate) fn receive_payload_in_place<C: CryptoLayer, B: AsRef<[u8]> + AsMut<[u8]>, S: PayloadSink>(
+    session: &Arc<Session<C>>,
+    keys: &DataKeys<C>,
+    kid: NonZeroU32,
+    nonce: &[u8; AES_GCM_NONCE_SIZE],
+    aad: &[u8],
+    fragments: &mut [B],
+    mut output_buffer: S,
+) -> Result<usize, ReceiveError<C, S::Error>> {
+    let tag_idx = decrypt_payload_in_place(session, keys, kid, nonce, aad, fragments)?;
+
+    let mut len = tag_idx;
     for i in 0..fragments.len() - 1 {
-        let result = output_buffer.write(&fragments[i].as_ref()[HEADER_SIZE..]);
+        len += fragments[i].as_ref().len() - HEADER_SIZE;
+        let result = output_buffer.write_payload(&fragments[i].as_ref()[HEADER_SIZE..]);
         if let Err(e) = result {
             return Err(ReceiveError::WriteError(e, session.clone()));
         }
     }
     let fragment = &fragments[fragments.len() - 1].as_ref()[HEADER_SIZE..];
-    let result = output_buffer.write(&fragment[..tag_idx]);
+    let result = output_buffer.write_payload(&fragment[..tag_idx]);
     if let Err(e) = result {
         return Err(ReceiveError::WriteError(e, session.clone()));
     }
 
-    Ok(())
+    Ok(len)
+}
+/// Decrypts a data packet in place and returns its plaintext as an owned buffer.
+///
+/// The buffer of the first fragment is reused to hold the plaintext. If the packet was not
+/// fragmented this means the plaintext is never copied into a second buffer, it is only shifted
+/// over the header within the same allocation.
+pub(crate) fn receive_payload_owned<C: CryptoLayer, B: AsRef<[u8]> + AsMut<[u8]> + Into<Vec<u8>>, E>(
+    session: &Arc<Session<C>>,
+    keys: &DataKeys<C>,
+    kid: NonZeroU32,
+    nonce: &[u8; AES_GCM_NONCE_SIZE],
+    aad: &[u8],
+    fragments: &mut Assembled<B>,
+) -> Result<Vec<u8>, ReceiveError<C, E>> {
+    let tag_idx = decrypt_payload_in_place(session, keys, kid, nonce, aad, fragments.as_mut())?;
+
+    let last = fragments.len() - 1;
+    let mut fragments = fragments.drain(..);
+    let mut payload: Vec<u8> = fragments.next().unwrap().into();
+    if last == 0 {
+        payload.truncate(HEADER_SIZE + tag_idx);
+    }
+    payload.drain(..HEADER_SIZE);
+    for (i, fragment) in fragments.enumerate() {
+        let fragment = &fragment.as_ref()[HEADER_SIZE..];
+        if i + 1 == last {
+            payload.extend_from_slice(&fragment[..tag_idx]);
+        } else {
+            payload.extend_from_slice(fragment);
+        }
+    }
+
+    Ok(payload)
+}
+/// Where the plaintext of an authenticated data packet is delivered to by `Context::receive`
+/// and its variants. Both methods return the length of the delivered plaintext.
+///
+/// `B` is the type of the incoming packet buffer, which only matches
+/// `CryptoLayer::IncomingPacketBuffer` when the context was given ownership of it.
+pub(crate) trait PayloadOutput<C: CryptoLayer, B> {
+    /// The error carried by `ReceiveError::WriteError` when the plaintext could not be delivered.
+    type Error: core::fmt::Display;
+    fn output_single(
+        self,
+        session: &Arc<Session<C>>,
+        keys: &DataKeys<C>,
+        kid: NonZeroU32,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        fragment: B,
+    ) -> Result<usize, ReceiveError<C, Self::Error>>;
+    fn output_assembled(
+        self,
+        session: &Arc<Session<C>>,
+        keys: &DataKeys<C>,
+        kid: NonZeroU32,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        fragments: &mut Assembled<C::IncomingPacketBuffer>,
+    ) -> Result<usize, ReceiveError<C, Self::Error>>;
+}
+impl<C: CryptoLayer, B: AsRef<[u8]> + AsMut<[u8]>, S: PayloadSink> PayloadOutput<C, B> for S {
+    type Error = S::Error;
+    fn output_single(
+        self,
+        session: &Arc<Session<C>>,
+        keys: &DataKeys<C>,
+        kid: NonZeroU32,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        mut fragment: B,
+    ) -> Result<usize, ReceiveError<C, Self::Error>> {
+        let fragments = core::slice::from_mut(&mut fragment);
+        receive_payload_in_place(session, keys, kid, nonce, aad, fragments, self)
+    }
+    fn output_assembled(
+        self,
+        session: &Arc<Session<C>>,
+        keys: &DataKeys<C>,
+        kid: NonZeroU32,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        fragments: &mut Assembled<C::IncomingPacketBuffer>,
+    ) -> Result<usize, ReceiveError<C, Self::Error>> {
+        receive_payload_in_place(session, keys, kid, nonce, aad, fragments.as_mut(), self)
+    }
+}
+/// Used by `Context::receive_owned` to take ownership of the decrypted packet buffer.
+pub(crate) struct OwnedPayload<'a>(pub(crate) &'a mut Option<Vec<u8>>);
+impl<'a, C: CryptoLayer> PayloadOutput<C, C::IncomingPacketBuffer> for OwnedPayload<'a>
+where
+    C::IncomingPacketBuffer: Into<Vec<u8>>,
+{
+    /// The payload is moved rather than written, so this is never returned.
+    type Error = crate::io::Error;
+    fn output_single(
+        self,
+        session: &Arc<Session<C>>,
+        keys: &DataKeys<C>,
+        kid: NonZeroU32,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        fragment: C::IncomingPacketBuffer,
+    ) -> Result<usize, ReceiveError<C, Self::Error>> {
+        let mut fragments = Assembled::new();
+        fragments.push(fragment);
+        let payload = receive_payload_owned(session, keys, kid, nonce, aad, &mut fragments)?;
+        let len = payload.len();
+        *self.0 = Some(payload);
+        Ok(len)
+    }
+    fn output_assembled(
+        self,
+        session: &Arc<Session<C>>,
+        keys: &DataKeys<C>,
+        kid: NonZeroU32,
+        nonce: &[u8; AES_GCM_NONCE_SIZE],
+        aad: &[u8],
+        fragments: &mut Assembled<C::IncomingPacketBuffer>,
+    ) -> Result<usize, ReceiveError<C, Self::Error>> {
+        let payload = receive_payload_owned(session, keys, kid, nonce, aad, fragments)?;
+        let len = payload.len();
+        *self.0 = Some(payload);
+        Ok(len)
+    }
 }
 
 impl<C: CryptoLayer> Drop for Session<C> {
     fn drop(&mut self) {
+        if let Some(ctx) = self.ctx.upgrade() {
+            ctx.dropped_sessions.fetch_add(1, Ordering::Relaxed);
+            ctx.metrics.record_session_dropped();
+        }
         self.expire();
     }
 }
@@ -1799,6 +3168,14 @@ impl<C: CryptoLayer> Session<C> {
     /// Mark a session as expired. This will make it impossible for this session to successfully
     /// receive or send data or control packets. It is recommended to simply `drop` the session
     /// instead, but this can provide some reassurance in complex shared ownership situations.
+    ///
+    /// Its keys are erased, and its key ids and timers are removed from the context right away,
+    /// so packets for this session that arrive afterwards are rejected as unknown.
+    ///
+    /// A call to `Context::receive` on another thread that already looked up this session and
+    /// loaded its keys before this call is not interrupted: it finishes decrypting its packet with
+    /// the old keys, and may still deliver it after this function returns. Any receive that
+    /// starts after this function returns will not see the old keys.
     pub fn expire(&self) {
         if let Some(ctx) = self.ctx.upgrade() {
             self.expire_inner(Some(&ctx), Some(&mut ctx.session_queue.lock()));
@@ -1806,14 +3183,77 @@ impl<C: CryptoLayer> Session<C> {
             self.expire_inner(None, None);
         }
     }
+    /// Write lock `state`. `data_keys` is republished when the returned guard is dropped if anything
+    /// it holds has changed.
+    pub(crate) fn write_state(&self) -> StateWriteGuard<'_, C> {
+        StateWriteGuard { session: self, state: self.state.write() }
+    }
+    /// Publish an expired snapshot of `data_keys`, returning the previous one, which threads that
+    /// are still sending or receiving with this session may be using.
+    /// The caller must hold the write lock of `state`, and must expire the session before
+    /// releasing it.
+    fn retire_data_keys(&self) -> Arc<DataKeys<C>> {
+        let expired = Arc::new(self.data_keys.load().expired());
+        self.data_keys.swap(expired)
+    }
+    /// Load the current snapshot of `data_keys` to send or receive with. The snapshot stays alive
+    /// for as long as the returned guard, which includes any `Sender` or `PayloadSink` callback
+    /// made while sending or receiving with it.
+    pub(crate) fn load_data_keys(&self) -> DataKeysGuard<C> {
+        #[cfg(feature = "std")]
+        DATA_KEYS_HELD.with(|held| held.set(held.get() + 1));
+        DataKeysGuard(self.data_keys.load())
+    }
+    /// Whether the current thread is sending or receiving with any session, which means it is
+    /// inside a `Sender` or `PayloadSink` callback if it is calling back into ZSSP.
+    ///
+    /// Without the `std` feature there is no notion of a thread, so this is always false.
+    pub(crate) fn is_reentrant() -> bool {
+        #[cfg(feature = "std")]
+        return DATA_KEYS_HELD.with(|held| held.get() > 0);
+        #[cfg(not(feature = "std"))]
+        false
+    }
+    /// Wait until every thread that is still sending or receiving with `retired` is done with it,
+    /// and drop it.
+    ///
+    /// Those threads may be inside `Sender::send_frag` or `PayloadSink::write_payload`, which are
+    /// free to use this session, so the caller must not hold any lock while waiting. If the caller
+    /// is inside such a callback itself it does not wait, since it may hold the retired snapshot
+    /// itself, or a snapshot of another session that the threads it would wait for are waiting on
+    /// in turn. The retired keys are then dropped when the last thread using them is done.
+    fn wait_retired(retired: Arc<DataKeys<C>>) {
+        if Self::is_reentrant() {
+            return;
+        }
+        // Swapping converts every outstanding load of the retired snapshot into a strong reference,
+        // so it is in use for as long as we do not hold the only one.
+        while Arc::strong_count(&retired) > 1 {
+            #[cfg(feature = "std")]
+            std::thread::yield_now();
+            #[cfg(not(feature = "std"))]
+            core::hint::spin_loop();
+        }
+    }
     /// Allows us to expire sessions with the correct locking order, preventing deadlock.
     pub(crate) fn expire_inner(&self, ctx: Option<&Arc<ContextInner<C>>>, session_queue: Option<&mut SessionQueue<C>>) {
         let _kex_lock = self.state_machine_lock.lock();
-        let mut state = self.state.write();
+        let mut state = self.write_state();
+        self.expire_locked(&mut state, ctx, session_queue);
+    }
+    /// The caller must hold the state machine lock.
+    fn expire_locked(
+        &self,
+        state: &mut MutableState<C>,
+        ctx: Option<&Arc<ContextInner<C>>>,
+        session_queue: Option<&mut SessionQueue<C>>,
+    ) {
         if !matches!(&state.beta, ZetaAutomata::Null) {
             state.beta = ZetaAutomata::Null;
+            self.signal_established(false);
 
-            let kids_to_remove = [state.keys[0].recv.kid, state.keys[1].recv.kid];
+            let rotated_kid = state.rotated_kid_recv.take().map(|(old_kid, _)| old_kid);
+            let kids_to_remove = [state.keys[0].recv.kid, state.keys[1].recv.kid, rotated_kid];
             state.keys = [DuplexKey::default(), DuplexKey::default()];
             state.resend_timer = AtomicI64::new(i64::MAX);
             state.timeout_timer = i64::MAX;
@@ -1822,9 +3262,8 @@ impl<C: CryptoLayer> Session<C> {
                 session_queue.remove(self.queue_idx);
             }
             if let Some(ctx) = ctx {
-                let mut session_map = ctx.session_map.write();
                 for kid_recv in kids_to_remove.iter().flatten() {
-                    session_map.remove(kid_recv);
+                    ctx.session_map.remove(kid_recv);
                 }
             }
         }
@@ -1835,10 +3274,49 @@ impl<C: CryptoLayer> Session<C> {
         let state = self.state.read();
         RatchetStates::new(state.ratchet_state1.clone(), state.ratchet_state2.clone())
     }
+    /// The number of milliseconds from `now` until this session starts its next rekey, which is
+    /// negative if the rekey is overdue. `now` is a time as returned by `ApplicationLayer::time`.
+    ///
+    /// This is an estimate: the schedule includes random jitter, the remote peer may start a rekey
+    /// sooner on its own schedule, and the rekey only starts on the next call to `Context::service`
+    /// after it is due, or never while the session is parked.
+    /// Returns `None` while the session is handshaking, rekeying, or expired.
+    pub fn time_until_rekey(&self, now: i64) -> Option<i64> {
+        let state = self.state.read();
+        matches!(&state.beta, ZetaAutomata::S2 | ZetaAutomata::S3).then(|| state.timeout_timer.saturating_sub(now))
+    }
+    /// The number of packets this session can send before one of them starts a rekey.
+    ///
+    /// This is an estimate, since other threads may be sending with this session concurrently and
+    /// the remote peer may start a rekey sooner on its own schedule.
+    /// Returns `None` while the session is handshaking, rekeying, or expired.
+    pub fn uses_until_rekey(&self) -> Option<u64> {
+        let state = self.state.read();
+        if !matches!(&state.beta, ZetaAutomata::S2 | ZetaAutomata::S3) {
+            return None;
+        }
+        let rekey_after_key_uses = self.rekey_after_key_uses.load(Ordering::Relaxed);
+        let rekey_at = state.key_creation_counter.saturating_add(rekey_after_key_uses);
+        let send_counter = self.send_counter.load(Ordering::Relaxed);
+        Some(rekey_at.saturating_add(1).saturating_sub(send_counter))
+    }
     /// The current ratchet count of this session.
     pub fn ratchet_count(&self) -> u64 {
         self.state.read().ratchet_state1.chain_len
     }
+    /// Checkpoint this session into a blob that `Context::deserialize_state` recreates it from,
+    /// so that it can be recovered after a crash without a new handshake. This is
+    /// `Context::export_session_state` with `ExportMode::Checkpoint`, see it for when checkpoints
+    /// must be taken, except that the blob is not encrypted. It contains the session keys, so the
+    /// application must store it securely.
+    ///
+    /// Returns `None` unless the session is fully established with no key exchange in progress,
+    /// since the other states are transient.
+    pub fn serialize_state(&self) -> Option<Vec<u8>> {
+        let ctx = self.ctx.upgrade()?;
+        let blob = write_export(&ctx, self, ExportMode::Checkpoint).ok()?;
+        Some(blob.to_vec())
+    }
     /// Check whether this session is established and can send data.
     ///
     /// Expired sessions will return false.
@@ -1848,6 +3326,51 @@ impl<C: CryptoLayer> Session<C> {
             ZetaAutomata::A1(_) | ZetaAutomata::A3 { .. } | ZetaAutomata::Null
         )
     }
+    /// Blocks the calling thread until the initial key exchange of this session completes, or
+    /// until `timeout` elapses. A `timeout` too large to be represented waits forever.
+    ///
+    /// Returns immediately if the session is already established. The handshake only progresses
+    /// when other threads call `Context::receive` and `Context::service`, which never wait on this
+    /// call.
+    ///
+    /// Returns `Err` with the time that was left of `timeout` if the session expires before it is
+    /// established, or with zero if `timeout` elapsed first.
+    #[cfg(feature = "std")]
+    pub fn wait_established(&self, timeout: std::time::Duration) -> Result<(), std::time::Duration> {
+        let deadline = std::time::Instant::now().checked_add(timeout);
+        let remaining = |deadline: Option<std::time::Instant>| {
+            deadline.map_or(timeout, |d| d.saturating_duration_since(std::time::Instant::now()))
+        };
+        let (outcome, condvar) = &self.established_signal;
+        let mut outcome = outcome.lock();
+        loop {
+            match *outcome {
+                Some(true) => return Ok(()),
+                Some(false) => return Err(remaining(deadline)),
+                None => {}
+            }
+            match deadline {
+                Some(deadline) => {
+                    let left = remaining(Some(deadline));
+                    if left.is_zero() || condvar.wait_for(&mut outcome, left).timed_out() {
+                        return Err(std::time::Duration::ZERO);
+                    }
+                }
+                None => condvar.wait(&mut outcome),
+            }
+        }
+    }
+    /// Wakes up every thread in `Session::wait_established`, which returns `Ok` if `established`
+    /// is true and `Err` otherwise.
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    fn signal_established(&self, established: bool) {
+        #[cfg(feature = "std")]
+        {
+            let (outcome, condvar) = &self.established_signal;
+            *outcome.lock() = Some(established);
+            condvar.notify_all();
+        }
+    }
     /// Check whether this session is still in the establishing phase of the handshake.
     /// Sessions that are establishing are not capable of sending data.
     ///
@@ -1858,38 +3381,765 @@ impl<C: CryptoLayer> Session<C> {
             ZetaAutomata::A1(_) | ZetaAutomata::A3 { .. }
         )
     }
+    /// Check whether this session has been parked with `Context::park_session`.
+    pub fn is_parked(&self) -> bool {
+        self.parked.load(Ordering::Relaxed)
+    }
     /// Check whether this session is expired and can no longer be used.
     pub fn is_expired(&self) -> bool {
         matches!(&self.state.read().beta, ZetaAutomata::Null)
     }
+    /// Statistics about the packets this session has received, as seen by its replay protection.
+    ///
+    /// A growing number of rejected packets on an otherwise healthy session can indicate that
+    /// something on the network path is duplicating or heavily reordering packets.
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.window.stats()
+    }
+    /// Check whether a packet with the given counter would pass this session's replay
+    /// protection, without recording it as received.
+    pub fn check_recv_window(&self, counter: u64) -> bool {
+        self.window.check(counter)
+    }
+    /// Record the given counter as received by this session's replay protection, for transports
+    /// that deduplicate packets themselves. Returns false if the counter was already received, is
+    /// too old, or is too far ahead.
+    ///
+    /// The window is shared with `Context::receive`, so a packet carrying a counter recorded here
+    /// will be rejected as a replay when it is received. If several threads concurrently record
+    /// the same counter, exactly one of them sees it as valid.
+    pub fn update_recv_window(&self, counter: u64) -> bool {
+        self.window.update(counter)
+    }
+    /// The key ids and keys of the current key of this session, for test vectors.
+    ///
+    /// Returns `None` until the session has derived its Noise keys.
+    /// Only available with the `test-vectors` feature.
+    #[cfg(feature = "test-vectors")]
+    pub fn session_keys(&self) -> Option<crate::test_vectors::SessionKeys> {
+        let state = self.state.read();
+        let key = state.key_ref(false);
+        let nk_keys = key.nk_keys.as_ref()?;
+        Some(crate::test_vectors::SessionKeys {
+            kid_recv: key.recv.kid?.get(),
+            kid_send: key.send.kid?.get(),
+            hk_send: state.hk_send_key.to_vec(),
+            hk_recv: state.hk_recv_key.to_vec(),
+            nk_send: nk_keys[..AES_256_KEY_SIZE].to_vec(),
+            nk_recv: nk_keys[AES_256_KEY_SIZE..].to_vec(),
+            kek_send: key.send.kek.as_ref()?.to_vec(),
+            kek_recv: key.recv.kek.as_ref()?.to_vec(),
+        })
+    }
+    /// Statistics about the byzantine faults caused by packets addressed to this session.
+    ///
+    /// See `Settings::unnatural_fault_threshold` to be notified when these grow too quickly.
+    pub fn fault_stats(&self) -> FaultStats {
+        self.faults.stats()
+    }
+    /// Statistics about how long the key exchanges of this session took.
+    pub fn kex_stats(&self) -> KexStats {
+        self.kex.stats()
+    }
+    /// The protocol version agreed on with the remote peer during the initial key exchange,
+    /// which is the version Alice sent in her hello, since Bob only answers hellos of versions he
+    /// supports.
+    ///
+    /// Returns 0 if we are Alice and have not yet received Bob's version.
+    pub fn agreed_protocol_version(&self) -> u8 {
+        self.proto_version.load(Ordering::Relaxed)
+    }
+    /// Whether the data packets of this session are encrypted with the nonce misuse resistant
+    /// AEAD of `CryptoLayer::AeadPool` instead of AES-GCM, as agreed on with the remote peer
+    /// during the initial key exchange. See `ApplicationLayer::misuse_resistance`.
+    ///
+    /// Returns false if we are Alice and have not yet received Bob's response.
+    pub fn is_misuse_resistant(&self) -> bool {
+        self.misuse_resistant.load(Ordering::Relaxed)
+    }
+    /// Whether the initial key exchange of this session included the Kyber1024 key encapsulation,
+    /// giving the session post-quantum forward secrecy. Rekeys do not use Kyber, but their keys
+    /// are chained from the keys of the initial key exchange, so this never changes.
+    /// See `ApplicationLayer::prefer_kyber` and `ApplicationLayer::require_kyber`.
+    ///
+    /// Returns false if we are Alice and have not yet received Bob's response.
+    pub fn is_post_quantum(&self) -> bool {
+        self.post_quantum.load(Ordering::Relaxed)
+    }
+    /// The counter the next packet of this session will be sent with.
+    #[cfg(test)]
+    pub(crate) fn send_counter(&self) -> &AtomicU64 {
+        &self.send_counter
+    }
+    /// The fingerprint of the ratchet key that was mixed into the initial key exchange of this
+    /// session, which identifies the ratchet epoch the session was established in.
+    ///
+    /// Returns `None` if the key exchange used the empty ratchet state, for example because the
+    /// peers had never connected before or one of them downgraded, or if it has not completed.
+    pub fn peer_ratchet_fingerprint(&self) -> Option<[u8; RATCHET_SIZE]> {
+        self.state
+            .read()
+            .ratchet_fingerprint_used_at_handshake
+            .as_deref()
+            .copied()
+    }
     /// The static public key of the remote peer.
     pub fn remote_static_key(&self) -> &C::PublicKey {
         &self.s_remote
     }
+    /// The metadata the application attached to this session, as returned by
+    /// `CryptoLayer::session_info` for its `session_data`.
+    pub fn info(&self) -> Option<&dyn SessionInfo> {
+        C::session_info(&self.session_data)
+    }
+    /// The key id the remote peer currently uses to identify this session, which is the key id
+    /// we place in the header of every packet we send.
+    ///
+    /// Combined with the key id of incoming packets, this can be used by a third party to
+    /// correlate packet captures taken at both ends of a session.
+    /// Returns `None` if the session is not yet established or is expired.
+    pub fn remote_session_id(&self) -> Option<NonZeroU32> {
+        self.state.read().key_ref(false).send.kid
+    }
+    /// The key id we currently use to identify this session, the counterpart of `remote_session_id`.
+    pub(crate) fn local_session_id(&self) -> Option<NonZeroU32> {
+        self.state.read().key_ref(false).recv.kid
+    }
+    /// Remember the last known path MTU to the remote peer, for example one found by path MTU
+    /// discovery. Passing 0 forgets it.
+    ///
+    /// Whenever a `SendTo` returns an MTU of 0 for this session, this MTU is used instead.
+    pub fn set_mtu_hint(&self, mtu: usize) {
+        self.mtu_hint.store(mtu, Ordering::Relaxed);
+    }
+    /// The MTU last passed to `Session::set_mtu_hint`, or `MIN_TRANSPORT_MTU` if there is none.
+    pub fn mtu_hint(&self) -> usize {
+        match self.mtu_hint.load(Ordering::Relaxed) {
+            0 => MIN_TRANSPORT_MTU,
+            mtu => mtu,
+        }
+    }
+    /// Send an authenticated data packet with an empty payload, for example to keep NAT mappings
+    /// along the path of an otherwise idle session alive.
+    ///
+    /// The packet consumes a counter and is authenticated like any other data packet. The remote
+    /// peer receives it as `SessionEvent::Data` with an empty payload.
+    ///
+    /// The returned boolean has the same meaning as the one returned by `Context::send`. Since a
+    /// keepalive uses the key like any other packet, it can be the one that makes the session due
+    /// for a rekey, and a session that only ever sends keepalives must still be serviced then.
+    ///
+    /// * `send` - Function to call to send the physical packet
+    /// * `mtu` - MTU for this call, must be at least `MIN_TRANSPORT_MTU`
+    pub fn send_keepalive(&self, send: impl Sender, mtu: usize) -> Result<bool, SendError> {
+        if mtu < MIN_TRANSPORT_MTU {
+            return Err(SendError::MtuTooSmall);
+        }
+        let ctx = self.ctx.upgrade().ok_or(SendError::SessionExpired)?;
+        // An empty payload always fits within a single fragment of the minimum size.
+        let mut buffer = [0u8; MIN_TRANSPORT_MTU];
+        send_payload(&ctx, self, &[], &[], &[], send, &mut buffer)
+    }
+    /// Encrypt and send data over the session, prefixed with a sequence number so the remote peer
+    /// can detect data that was reordered or lost in transit.
+    ///
+    /// ZSSP rejects replayed packets but otherwise delivers them in whatever order they arrive.
+    /// Each call takes the next sequence number of this session, starting from 0, even if sending
+    /// fails. The remote peer receives the sequence number as the first `SEQUENCE_NUMBER_SIZE`
+    /// bytes of the payload, which `receive_sequence_number` splits off again.
+    ///
+    /// Returns the sequence number the data was sent with, along with a boolean that has the same
+    /// meaning as the one returned by `Context::send`.
+    ///
+    /// * `send` - Function to call to send physical packet(s)
+    /// * `mtu` - MTU for this call, must be at least `MIN_TRANSPORT_MTU`
+    /// * `data` - Data to send
+    pub fn send_ordered(&self, send: impl Sender, mtu: usize, data: &[u8]) -> Result<(u64, bool), SendError> {
+        if mtu < MIN_TRANSPORT_MTU {
+            return Err(SendError::MtuTooSmall);
+        }
+        let ctx = self.ctx.upgrade().ok_or(SendError::SessionExpired)?;
+        let seq = self.ordered_send_counter.fetch_add(1, Ordering::Relaxed);
+        let mut buffer = vec![0u8; mtu];
+        let should_service = send_payload(&ctx, self, &[], &seq.to_le_bytes(), data, send, &mut buffer)?;
+        Ok((seq, should_service))
+    }
+    /// Encrypt `data` in place for out-of-band delivery to the remote peer, returning the
+    /// authentication tag. The remote peer can decrypt it with `decrypt_standalone`.
+    ///
+    /// The data is protected with a key derived from the current send key of this session, and a
+    /// nonce derived from `nonce_hint` and a secret value shared by the two peers.
+    ///
+    /// # Security
+    /// The caller must never use the same `nonce_hint` twice with the same session key, doing so
+    /// catastrophically breaks the confidentiality and integrity of AES-GCM.
+    /// Standalone data bypasses ZSSP's replay protection entirely, so the caller is responsible for
+    /// rejecting replayed data, for example by only accepting increasing nonce hints.
+    ///
+    /// Since the key changes whenever the session rekeys, data encrypted shortly before a rekey
+    /// may fail to decrypt on the remote peer if it has already switched to the new key.
+    pub fn encrypt_standalone(
+        &self,
+        nonce_hint: u64,
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; AES_GCM_TAG_SIZE], SendError> {
+        let state = self.state.read();
+        let (key, nonce) = standalone_key::<C>(&state, false, nonce_hint)?;
+        Ok(C::Aead::encrypt_in_place(&key, &nonce, aad, data))
+    }
+    /// Decrypt and authenticate `data` in place, that was encrypted by the remote peer with
+    /// `encrypt_standalone`.
+    ///
+    /// Returns `Ok(false)` if the data failed to authenticate, in which case the contents of
+    /// `data` are undefined.
+    ///
+    /// # Security
+    /// This bypasses ZSSP's replay protection entirely, the same data can be successfully
+    /// decrypted any number of times. See `encrypt_standalone`.
+    pub fn decrypt_standalone(
+        &self,
+        nonce_hint: u64,
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; AES_GCM_TAG_SIZE],
+    ) -> Result<bool, SendError> {
+        let state = self.state.read();
+        let (key, nonce) = standalone_key::<C>(&state, true, nonce_hint)?;
+        Ok(C::Aead::decrypt_in_place(&key, &nonce, aad, data, tag))
+    }
 }
 
-impl<C: CryptoLayer> std::fmt::Debug for Session<C>
-where
-    C::SessionData: std::fmt::Debug,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+/// Split the sequence number off of a payload sent with `Session::send_ordered`, returning it
+/// along with the data that was sent.
+///
+/// Returns `None` if `plaintext` is too short to have been sent with `Session::send_ordered`.
+pub fn receive_sequence_number(plaintext: &[u8]) -> Option<(u64, &[u8])> {
+    if plaintext.len() < SEQUENCE_NUMBER_SIZE {
+        return None;
+    }
+    let (seq, data) = plaintext.split_at(SEQUENCE_NUMBER_SIZE);
+    Some((u64::from_le_bytes(seq.try_into().unwrap()), data))
+}
+
+/// Derive the key and nonce for `Session::encrypt_standalone` and `Session::decrypt_standalone`
+/// from the current send or receive key exchange key.
+fn standalone_key<C: CryptoLayer>(
+    state: &MutableState<C>,
+    is_recv: bool,
+    nonce_hint: u64,
+) -> Result<(Zeroizing<[u8; AES_256_KEY_SIZE]>, [u8; AES_GCM_NONCE_SIZE]), SendError> {
+    if matches!(&state.beta, ZetaAutomata::Null) {
+        return Err(SendError::SessionExpired);
+    }
+    let key = state.key_ref(false);
+    let kek = if is_recv { &key.recv.kek } else { &key.send.kek };
+    let kek = kek.as_ref().ok_or(SendError::SessionNotEstablished)?;
+
+    let mut output = Zeroizing::new([0u8; HASHLEN]);
+    C::Hmac::new().hash(kek.as_ref(), LABEL_STANDALONE_KEY, &mut output);
+    let mask = u64::from_le_bytes(output[AES_256_KEY_SIZE..AES_256_KEY_SIZE + 8].try_into().unwrap());
+    // Packet nonces never use this packet type, so standalone nonces are domain separated from them.
+    let nonce = to_nonce(PACKET_TYPE_STANDALONE, nonce_hint ^ mask);
+    Ok((Zeroizing::new(output[..AES_256_KEY_SIZE].try_into().unwrap()), nonce))
+}
+
+/// The version of the format produced by `export_session`.
+pub(crate) const EXPORT_VERSION: u8 = 8;
+const EXPORT_HEADER_SIZE: usize = 1 + AES_GCM_NONCE_SIZE;
+
+fn write_keys(out: &mut Vec<u8>, keys: &Keys) {
+    out.push(keys.kek.is_some() as u8);
+    out.extend_from_slice(keys.kek.as_deref().unwrap_or(&[0u8; AES_256_KEY_SIZE]));
+    out.extend_from_slice(&keys.kid.map_or(0, NonZeroU32::get).to_le_bytes());
+}
+fn write_ratchet_state(out: &mut Vec<u8>, rs: &RatchetState) {
+    out.extend_from_slice(rs.key.as_ref());
+    out.extend_from_slice(rs.fingerprint.as_ref());
+    out.extend_from_slice(&rs.chain_len.to_le_bytes());
+    out.push(rs.min_version);
+}
+/// Reads the fields of a session in the order `write_session` wrote them.
+struct ExportReader<'a>(&'a [u8]);
+impl<'a> ExportReader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Option<&'a [u8; N]> {
+        if self.0.len() < N {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(N);
+        self.0 = rest;
+        bytes.try_into().ok()
+    }
+    fn flag(&mut self) -> Option<bool> {
+        match self.bytes::<1>()?[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(|b| u32::from_le_bytes(*b))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(|b| u64::from_le_bytes(*b))
+    }
+    fn i64(&mut self) -> Option<i64> {
+        self.bytes().map(|b| i64::from_le_bytes(*b))
+    }
+    fn key<const N: usize>(&mut self) -> Option<Zeroizing<[u8; N]>> {
+        self.bytes().map(|b| Zeroizing::new(*b))
+    }
+    fn keys(&mut self) -> Option<Keys> {
+        let has_kek = self.flag()?;
+        let kek = self.key()?;
+        let kid = NonZeroU32::new(self.u32()?);
+        Some(Keys { kek: has_kek.then_some(kek), kid })
+    }
+    fn ratchet_state(&mut self) -> Option<RatchetState> {
+        let rs = RatchetState::new(self.key()?, self.key()?, self.u64()?);
+        Some(rs.with_min_version(self.bytes::<1>()?[0]))
+    }
+}
+
+/// Writes the fields of a session that are not part of its `state`, in the order `read_session`
+/// reads them. They are followed by the fields written by `write_session_state`.
+fn write_session<C: CryptoLayer>(out: &mut Vec<u8>, ctx: &ContextInner<C>, session: &Session<C>, send_counter: u64) {
+    out.extend_from_slice(&ctx.s_secret.public_key_bytes());
+    out.extend_from_slice(&session.s_remote.to_bytes());
+    out.push(session.was_bob as u8);
+    out.push(session.post_quantum.load(Ordering::Relaxed) as u8);
+    // The AEAD is recorded as a flag of the version, so builds that do not know it refuse the
+    // session as incompatible.
+    let mut version = session.proto_version.load(Ordering::Relaxed);
+    if session.misuse_resistant.load(Ordering::Relaxed) {
+        version |= VERSION_FLAG_MISUSE_RESISTANT;
+    }
+    out.push(version);
+    out.extend_from_slice(&session.handshake_start_time.to_le_bytes());
+    out.extend_from_slice(session.noise_kk_ss.as_ref());
+    out.extend_from_slice(&send_counter.to_le_bytes());
+    out.extend_from_slice(&session.ordered_send_counter.load(Ordering::Relaxed).to_le_bytes());
+    out.extend_from_slice(&(session.mtu_hint.load(Ordering::Relaxed) as u64).to_le_bytes());
+    out.extend_from_slice(&session.rekey_after_key_uses.load(Ordering::Relaxed).to_le_bytes());
+    let slots = session.window.load_slots();
+    out.extend_from_slice(&(slots.len() as u32).to_le_bytes());
+    for slot in slots {
+        out.extend_from_slice(&slot.to_le_bytes());
+    }
+}
+/// Writes the fields of the `state` of a session, in the order `read_session` reads them.
+fn write_session_state<C: CryptoLayer>(out: &mut Vec<u8>, state: &MutableState<C>, beta: u8, mode: ExportMode) {
+    write_ratchet_state(out, &state.ratchet_state1);
+    out.push(state.ratchet_state2.is_some() as u8);
+    write_ratchet_state(out, state.ratchet_state2.as_ref().unwrap_or(&RatchetState::empty()));
+    out.extend_from_slice(state.hk_send_key.as_ref());
+    out.extend_from_slice(state.hk_recv_key.as_ref());
+    out.extend_from_slice(&state.key_creation_counter.to_le_bytes());
+    out.push(state.key_index as u8);
+    for key in &state.keys {
+        write_keys(out, &key.send);
+        write_keys(out, &key.recv);
+        out.push(key.nk_keys.is_some() as u8);
+        out.extend_from_slice(key.nk_keys.as_deref().unwrap_or(&[0u8; 2 * AES_256_KEY_SIZE]));
+    }
+    let (old_kid, new_kid) = state.rotated_kid_recv.map_or((0, 0), |(old, new)| (old.get(), new.get()));
+    out.extend_from_slice(&old_kid.to_le_bytes());
+    out.extend_from_slice(&new_kid.to_le_bytes());
+    out.extend_from_slice(&state.kid_rotate_counter.to_le_bytes());
+    let used_fingerprint = state.ratchet_fingerprint_used_at_handshake.as_deref();
+    out.push(used_fingerprint.is_some() as u8);
+    out.extend_from_slice(used_fingerprint.unwrap_or(&[0u8; RATCHET_SIZE]));
+    out.extend_from_slice(&state.resend_timer.load(Ordering::Relaxed).to_le_bytes());
+    out.extend_from_slice(&state.timeout_timer.to_le_bytes());
+    out.push(beta);
+    out.push((mode == ExportMode::Checkpoint) as u8);
+}
+/// Serializes the state of an established session into a blob encrypted with `master_key`.
+pub(crate) fn export_session<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session: &Session<C>,
+    master_key: &[u8; AES_256_KEY_SIZE],
+    mode: ExportMode,
+) -> Result<Vec<u8>, ExportError> {
+    let mut blob = write_export(ctx, session, mode)?;
+    let nonce: [u8; AES_GCM_NONCE_SIZE] = blob[1..EXPORT_HEADER_SIZE].try_into().unwrap();
+    let tag = C::Aead::encrypt_in_place(master_key, &nonce, &[EXPORT_VERSION], &mut blob[EXPORT_HEADER_SIZE..]);
+    blob.extend_from_slice(&tag);
+    Ok(core::mem::take(&mut *blob))
+}
+/// Serializes the state of an established session into a blob that is not encrypted, starting
+/// with the version and a random nonce that identifies the blob.
+/// With `ExportMode::Migrate` the session is expired, with `ExportMode::Checkpoint` the counters
+/// the restored session will use are reserved.
+fn write_export<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session: &Session<C>,
+    mode: ExportMode,
+) -> Result<Zeroizing<Vec<u8>>, ExportError> {
+    // Waiting out the sends and receives in progress would not be possible, see `wait_retired`.
+    if mode == ExportMode::Migrate && Session::<C>::is_reentrant() {
+        return Err(ExportError::InCallback);
+    }
+    let mut session_queue = ctx.session_queue.lock();
+    let kex_lock = session.state_machine_lock.lock();
+    let mut state = session.write_state();
+    let beta = match (&state.beta, mode) {
+        (ZetaAutomata::Null, _) => return Err(ExportError::SessionExpired),
+        (ZetaAutomata::A1(_) | ZetaAutomata::A3(_), _) => return Err(ExportError::SessionNotEstablished),
+        (ZetaAutomata::R1 { .. } | ZetaAutomata::R2 { .. }, _) => return Err(ExportError::KeyExchangeInProgress),
+        // A checkpoint is restored by rekeying right away, which can only start from S2.
+        (ZetaAutomata::S1 | ZetaAutomata::S3, ExportMode::Checkpoint) => {
+            return Err(ExportError::KeyExchangeInProgress)
+        }
+        (ZetaAutomata::S1, _) => 1u8,
+        (ZetaAutomata::S2, _) => 2,
+        (ZetaAutomata::S3, _) => 3,
+    };
+    let mut state_fields = Zeroizing::new(Vec::new());
+    write_session_state(&mut state_fields, &state, beta, mode);
+    let send_counter = match mode {
+        ExportMode::Migrate => {
+            let retired = session.retire_data_keys();
+            session.expire_locked(&mut state, Some(ctx), Some(&mut session_queue));
+            drop(state);
+            drop(kex_lock);
+            drop(session_queue);
+            // Wait out every thread that could be sending or receiving with this session, so the
+            // counters and the replay window we export can no longer change.
+            Session::wait_retired(retired);
+            session.send_counter.load(Ordering::Relaxed)
+        }
+        ExportMode::Checkpoint => {
+            // The key the previous checkpoint reserved counters of may still be in use, so the
+            // reservation only ever moves forward.
+            let limit = session.send_counter.load(Ordering::Relaxed);
+            let limit = limit.saturating_add(CHECKPOINT_COUNTER_SKIP);
+            // The limit is published before the key it applies to, see `get_counter`.
+            session.checkpoint_limit.store(limit, Ordering::Relaxed);
+            session
+                .checkpoint_key
+                .store(state.key_creation_counter, Ordering::Release);
+            limit
+        }
+    };
+    let mut blob = Zeroizing::new(Vec::new());
+    blob.push(EXPORT_VERSION);
+    let mut nonce = [0u8; AES_GCM_NONCE_SIZE];
+    ctx.rng().lock().fill_bytes(&mut nonce);
+    blob.extend_from_slice(&nonce);
+    write_session(&mut blob, ctx, session, send_counter);
+    blob.extend_from_slice(&state_fields);
+    Ok(blob)
+}
+/// The inverse of `export_session`.
+///
+/// If `expected` is given the session must be with that remote peer and have those ratchet states.
+pub(crate) fn import_session<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session_data: C::SessionData,
+    blob: &[u8],
+    master_key: &[u8; AES_256_KEY_SIZE],
+    expected: Option<(&C::PublicKey, &RatchetStates)>,
+) -> Result<(Arc<Session<C>>, Option<i64>), ImportError> {
+    use ImportError::*;
+    if blob.len() < EXPORT_HEADER_SIZE + AES_GCM_TAG_SIZE {
+        return Err(InvalidBlob);
+    }
+    if blob[0] != EXPORT_VERSION {
+        return Err(UnsupportedVersion(blob[0]));
+    }
+    let nonce: &[u8; AES_GCM_NONCE_SIZE] = blob[1..EXPORT_HEADER_SIZE].try_into().unwrap();
+    let (data, tag) = blob[EXPORT_HEADER_SIZE..].split_at(blob.len() - EXPORT_HEADER_SIZE - AES_GCM_TAG_SIZE);
+    let mut data = Zeroizing::new(data.to_vec());
+    if !C::Aead::decrypt_in_place(master_key, nonce, &[EXPORT_VERSION], &mut data, tag.try_into().unwrap()) {
+        return Err(InvalidBlob);
+    }
+    read_export(ctx, session_data, nonce, &data, expected)
+}
+/// The inverse of `Session::serialize_state`.
+pub(crate) fn deserialize_session<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session_data: C::SessionData,
+    blob: &[u8],
+) -> Result<(Arc<Session<C>>, Option<i64>), ImportError> {
+    if blob.len() < EXPORT_HEADER_SIZE {
+        return Err(ImportError::InvalidBlob);
+    }
+    if blob[0] != EXPORT_VERSION {
+        return Err(ImportError::UnsupportedVersion(blob[0]));
+    }
+    let nonce: &[u8; AES_GCM_NONCE_SIZE] = blob[1..EXPORT_HEADER_SIZE].try_into().unwrap();
+    read_export(ctx, session_data, nonce, &blob[EXPORT_HEADER_SIZE..], None)
+}
+/// Adds the session in `data`, the fields of a blob with the given nonce, to `ctx`, unless a blob
+/// with that nonce was imported before.
+fn read_export<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session_data: C::SessionData,
+    nonce: &[u8; AES_GCM_NONCE_SIZE],
+    data: &[u8],
+    expected: Option<(&C::PublicKey, &RatchetStates)>,
+) -> Result<(Arc<Session<C>>, Option<i64>), ImportError> {
+    use ImportError::*;
+    // Every blob has its own random nonce, which identifies it. Two sessions imported from the
+    // same blob would send with the same counters.
+    let mut imported_exports = ctx.imported_exports.lock();
+    if imported_exports.contains(nonce) {
+        return Err(AlreadyImported);
+    }
+    let result = read_session(ctx, session_data, data, expected)?;
+    imported_exports.insert(*nonce);
+    Ok(result)
+}
+/// Reads a session written by `write_session` and adds it to `ctx`.
+///
+/// A session restored from a checkpoint starts at the first counter the checkpoint reserved, and
+/// rekeys as soon as it is serviced. See `ExportMode::Checkpoint`.
+fn read_session<C: CryptoLayer>(
+    ctx: &Arc<ContextInner<C>>,
+    session_data: C::SessionData,
+    data: &[u8],
+    expected: Option<(&C::PublicKey, &RatchetStates)>,
+) -> Result<(Arc<Session<C>>, Option<i64>), ImportError> {
+    use ImportError::*;
+    let r = &mut ExportReader(data);
+    (|| {
+        if *r.bytes()? != ctx.s_secret.public_key_bytes() {
+            return Some(Err(WrongStaticKey));
+        }
+        let s_remote = C::PublicKey::from_bytes(r.bytes()?)?;
+        let was_bob = r.flag()?;
+        let post_quantum = r.flag()?;
+        let proto_version = r.bytes::<1>()?[0];
+        let misuse_resistant = proto_version & VERSION_FLAG_MISUSE_RESISTANT != 0;
+        let proto_version = proto_version & !VERSION_FLAG_MISUSE_RESISTANT;
+        if !is_supported_version::<C>(proto_version) || misuse_resistant && !C::AeadPool::MISUSE_RESISTANT {
+            return Some(Err(Incompatible));
+        }
+        let handshake_start_time = r.i64()?;
+        let noise_kk_ss = r.key()?;
+        let send_counter = r.u64()?;
+        let ordered_send_counter = r.u64()?;
+        let mtu_hint = usize::try_from(r.u64()?).ok()?;
+        let rekey_after_key_uses = r.u64()?;
+        let slots = (0..r.u32()?).map(|_| r.u64()).collect::<Option<Vec<_>>>()?;
+        let window = new_window::<C>();
+        if !window.store_slots(&slots) {
+            return Some(Err(Incompatible));
+        }
+
+        let ratchet_state1 = r.ratchet_state()?;
+        let has_ratchet_state2 = r.flag()?;
+        let ratchet_state2 = r.ratchet_state()?;
+        let hk_send_key: Zeroizing<[u8; AES_256_KEY_SIZE]> = r.key()?;
+        let hk_recv_key: Zeroizing<[u8; AES_256_KEY_SIZE]> = r.key()?;
+        let key_creation_counter = r.u64()?;
+        let key_index = r.flag()?;
+        let mut keys = [DuplexKey::default(), DuplexKey::default()];
+        for key in &mut keys {
+            key.send = r.keys()?;
+            key.recv = r.keys()?;
+            let has_nk = r.flag()?;
+            let nk_keys: Zeroizing<[u8; 2 * AES_256_KEY_SIZE]> = r.key()?;
+            if has_nk {
+                let (nk_send, nk_recv) = nk_keys.split_at(AES_256_KEY_SIZE);
+                key.nk = Some(Arc::new(C::AeadPool::new(nk_send.try_into().unwrap(), nk_recv.try_into().unwrap())));
+                key.nk_keys = Some(nk_keys);
+            }
+        }
+        let rotated_kid_recv = NonZeroU32::new(r.u32()?).zip(NonZeroU32::new(r.u32()?));
+        let kid_rotate_counter = r.u64()?;
+        let has_used_fingerprint = r.flag()?;
+        let used_fingerprint: Zeroizing<[u8; RATCHET_SIZE]> = r.key()?;
+        let resend_timer = r.i64()?;
+        let mut timeout_timer = r.i64()?;
+        let beta = match r.bytes::<1>()?[0] {
+            1 => ZetaAutomata::S1,
+            2 => ZetaAutomata::S2,
+            3 => ZetaAutomata::S3,
+            _ => return None,
+        };
+        if r.flag()? {
+            // Packets received after the checkpoint was taken can be replayed until the keys are
+            // replaced, so the restored session rekeys as soon as it is serviced.
+            timeout_timer = i64::MIN;
+        }
+        if !r.0.is_empty() {
+            return None;
+        }
+        let ratchet_state2 = has_ratchet_state2.then_some(ratchet_state2);
+        if let Some((static_remote_key, ratchet_states)) = expected {
+            if static_remote_key.to_bytes() != s_remote.to_bytes()
+                || ratchet_states.state1 != ratchet_state1
+                || ratchet_states.state2 != ratchet_state2
+            {
+                return Some(Err(RatchetMismatch));
+            }
+        }
+
+        let mut session_queue = ctx.session_queue.lock();
+        let kids = [keys[0].recv.kid, keys[1].recv.kid, rotated_kid_recv.map(|(old_kid, _)| old_kid)];
+        let mut session_map = ctx.session_map.write_many(kids.iter().flatten());
+        if kids.iter().flatten().any(|kid| session_map.contains_key(kid)) {
+            return Some(Err(KeyIdCollision));
+        }
+        let queue_idx = session_queue.reserve_index();
+        let state = MutableState {
+            ratchet_state1,
+            ratchet_state2,
+            hk_send: Arc::new(C::PrpEnc::new(&hk_send_key)),
+            hk_recv: Arc::new(C::PrpDec::new(&hk_recv_key)),
+            hk_send_key,
+            hk_recv_key,
+            key_creation_counter,
+            key_index,
+            keys,
+            rotated_kid_recv,
+            kid_rotate_counter,
+            ratchet_fingerprint_used_at_handshake: has_used_fingerprint.then_some(used_fingerprint),
+            resend_timer: AtomicI64::new(resend_timer),
+            timeout_timer,
+            beta,
+        };
+        let session = Arc::new(Session {
+            ctx: Arc::downgrade(ctx),
+            session_data,
+            was_bob,
+            queue_idx,
+            parked: AtomicBool::new(false),
+            handshake_start_time,
+            proto_version: AtomicU8::new(proto_version),
+            misuse_resistant: AtomicBool::new(misuse_resistant),
+            post_quantum: AtomicBool::new(post_quantum),
+            s_remote,
+            send_counter: AtomicU64::new(send_counter),
+            ordered_send_counter: AtomicU64::new(ordered_send_counter),
+            mtu_hint: AtomicUsize::new(mtu_hint),
+            rekey_after_key_uses: AtomicU64::new(rekey_after_key_uses),
+            checkpoint_key: AtomicU64::new(u64::MAX),
+            checkpoint_limit: AtomicU64::new(u64::MAX),
+            window,
+            faults: FaultCounters::new(),
+            kex: KexTimer::new(),
+            state_machine_lock: Mutex::new(()),
+            data_keys: ArcSwap::from_pointee(DataKeys::new(&state)),
+            state: RwLock::new(state),
+            noise_kk_ss,
+            #[cfg(feature = "std")]
+            established_signal: (Mutex::new(Some(true)), Condvar::new()),
+            defrag: core::array::from_fn(|_| Mutex::new(SessionFragBuffer::new())),
+        });
+        ctx.metrics.record_session_created(false);
+        for kid in kids.iter().flatten() {
+            session_map.insert(*kid, Arc::downgrade(&session));
+        }
+        let next_timer = resend_timer.min(timeout_timer);
+        session_queue.push_reserved(queue_idx, Arc::downgrade(&session), Reverse(next_timer));
+        let reduced_service_time = ctx.reduce_next_service_time(next_timer);
+        Some(Ok((session, reduced_service_time)))
+    })()
+    .unwrap_or(Err(InvalidBlob))
+}
+
+/// Stands in for key material in `Debug` output, showing only whether the key is present.
+struct Redacted(bool);
+impl core::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            true => f.write_str("<redacted>"),
+            false => f.write_str("None"),
+        }
+    }
+}
+/// Key material is never printed, so sessions can be logged safely. `session_data` is left out
+/// since it is not required to implement `Debug`.
+impl<C: CryptoLayer> core::fmt::Debug for Session<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Session")
-            .field("session_data", &self.session_data)
             .field("was_bob", &self.was_bob)
-            .field("state", &self.state.read().beta)
+            .field("proto_version", &self.proto_version.load(Ordering::Relaxed))
+            .field("parked", &self.parked.load(Ordering::Relaxed))
+            .field("send_counter", &self.send_counter.load(Ordering::Relaxed))
+            .field("state", &*self.state.read())
+            .finish_non_exhaustive()
+    }
+}
+impl<C: CryptoLayer> core::fmt::Debug for MutableState<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MutableState")
+            .field("state", &self.beta)
+            .field("ratchet_state1", &self.ratchet_state1)
+            .field("ratchet_state2", &self.ratchet_state2)
+            .field("hk_send", &Redacted(true))
+            .field("hk_recv", &Redacted(true))
+            .field("key_creation_counter", &self.key_creation_counter)
+            .field("key_index", &self.key_index)
+            .field("keys", &self.keys)
+            .field("rotated_kid_recv", &self.rotated_kid_recv)
+            .field("kid_rotate_counter", &self.kid_rotate_counter)
+            .field(
+                "ratchet_fingerprint_used_at_handshake",
+                &Redacted(self.ratchet_fingerprint_used_at_handshake.is_some()),
+            )
+            .field("resend_timer", &self.resend_timer.load(Ordering::Relaxed))
+            .field("timeout_timer", &self.timeout_timer)
+            .finish()
+    }
+}
+impl<C: CryptoLayer> core::fmt::Debug for DuplexKey<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DuplexKey")
+            .field("kid_send", &self.send.kid)
+            .field("kid_recv", &self.recv.kid)
+            .field("kek_send", &Redacted(self.send.kek.is_some()))
+            .field("kek_recv", &Redacted(self.recv.kek.is_some()))
+            .field("nk", &Redacted(self.nk.is_some()))
             .finish()
     }
 }
-impl<C: CryptoLayer> std::fmt::Debug for ZetaAutomata<C> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<C: CryptoLayer> core::fmt::Debug for ZetaAutomata<C> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::Null => write!(f, "Expired"),
             Self::A1(..) => write!(f, "A1"),
             Self::A3(..) => write!(f, "A3"),
             Self::S1 => write!(f, "S1"),
             Self::S2 => write!(f, "S2"),
+            Self::S3 => write!(f, "S3"),
             Self::R1 { .. } => write!(f, "R1"),
             Self::R2 { .. } => write!(f, "R2"),
         }
     }
 }
+
+#[test]
+fn test_gen_kid_counter_exhaustion() {
+    use crate::crypto_impl::OpenSSLAes256Enc;
+    let kid_prp = OpenSSLAes256Enc::new(&[1u8; AES_256_KEY_SIZE]);
+    let session_map = KidMap::new();
+    let counter = AtomicU64::new(0);
+    // The key id the attempt at counter value `i` tries.
+    let kid_at = |i: u64| {
+        let mut block = [0u8; AES_256_BLOCK_SIZE];
+        block[..8].copy_from_slice(&i.to_ne_bytes());
+        kid_prp.encrypt_in_place(&mut block);
+        NonZeroU32::new(u32::from_ne_bytes(block[..KID_SIZE].try_into().unwrap()))
+    };
+    // A nearly full key id space is simulated by occupying the key ids the next attempts try.
+    let max = KID_GENERATION_MAX_ATTEMPTS as u64;
+    for kid in (0..max - 1).filter_map(kid_at) {
+        session_map.shard(kid).write().insert(kid, ());
+    }
+    let (kid, _) = gen_kid_counter(&session_map, &counter, &kid_prp).unwrap();
+    assert_eq!(Some(kid), kid_at(max - 1));
+    assert_eq!(counter.load(Ordering::Relaxed), max);
+
+    for kid in (max..2 * max).filter_map(kid_at) {
+        session_map.shard(kid).write().insert(kid, ());
+    }
+    assert!(gen_kid_counter(&session_map, &counter, &kid_prp).is_none());
+    assert_eq!(counter.load(Ordering::Relaxed), 2 * max);
+    // Later attempts move on to key ids that are free.
+    let (kid, _) = gen_kid_counter(&session_map, &counter, &kid_prp).unwrap();
+    assert_eq!(Some(kid), kid_at(2 * max));
+}
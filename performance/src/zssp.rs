@@ -1,37 +1,70 @@
-use std::cmp::Reverse;
-use std::collections::HashMap;
-use std::hash::Hash;
-use std::io::Write;
-use std::num::NonZeroU32;
-use std::sync::atomic::{AtomicI64, Ordering};
-use std::sync::{Arc, Weak};
-use parking_lot::{Mutex, RwLock};
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::Cell;
+use core::cmp::Reverse;
+use core::hash::Hash;
+use core::num::NonZeroU32;
 
 use arrayvec::ArrayVec;
 use rand_core::RngCore;
+use zeroize::Zeroizing;
 
+use crate::antireplay::AntiReplayWindow;
 use crate::application::*;
-use crate::challenge::ChallengeContext;
+use crate::challenge::{ChallengeContext, ChallengeStats, HelloRate};
+use crate::collections::HashSet;
 use crate::crypto::*;
-use crate::frag_cache::UnassociatedFragCache;
-use crate::fragged::Assembled;
+use crate::frag_cache::FragCache;
+use crate::fragged::{concat_payloads, Assembled, FragmentBuffer};
 use crate::handshake_cache::UnassociatedHandshakeCache;
 use crate::indexed_heap::IndexedBinaryHeap;
+use crate::io::Write;
+use crate::metrics::{ContextStats, Metrics};
 use crate::proto::*;
-use crate::result::{fault, ExpiredError, FaultType, OpenError, ReceiveError, ReceiveOk, SendError, SessionEvent};
+use crate::reliable::{ReliableSendHandle, ReliableSends};
+use crate::result::{
+    fault, ByzantineFault, ExpiredError, ExportError, FaultType, ImportError, OpenError, ReceiveError, ReceiveOk,
+    SendError, SessionEvent,
+};
+use crate::session_map::KidMap;
+use crate::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use crate::sync::Mutex;
 use crate::zeta::*;
-#[cfg(feature = "logging")]
+#[cfg(any(feature = "logging", feature = "tracing"))]
 use crate::LogEvent::*;
 
 /// Macro to turn off logging at compile time.
 macro_rules! log {
     ($app:expr, $event:expr) => {
-        #[cfg(feature = "logging")]
-        $app.event_log($event);
+        #[cfg(any(feature = "logging", feature = "tracing"))]
+        {
+            let event: crate::LogEvent<'_, C> = $event;
+            #[cfg(feature = "tracing")]
+            event.trace();
+            #[cfg(feature = "logging")]
+            $app.event_log(event);
+        }
     };
 }
 pub(crate) use log;
 
+/// Macro to turn off packet capture at compile time.
+///
+/// When the `capture` feature is disabled nothing is passed to the application, not even the
+/// arguments are evaluated.
+macro_rules! capture {
+    ($app:expr, $direction:ident, $packet_type:expr, $counter:expr, $plaintext:expr) => {
+        #[cfg(feature = "capture")]
+        $app.debug_packet(crate::application::PacketDirection::$direction, $packet_type, $counter, $plaintext);
+        #[cfg(not(feature = "capture"))]
+        let _ = &$app;
+    };
+}
+pub(crate) use capture;
+
 /// Session context for local application.
 ///
 /// Each application using ZSSP must create an instance of this to own sessions and
@@ -45,7 +78,87 @@ impl<C: CryptoLayer> Clone for Context<C> {
     }
 }
 
-pub(crate) type SessionMap<C> = RwLock<HashMap<NonZeroU32, Weak<Session<C>>>>;
+/// What `Context::export_session_state` does with the session it exports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExportMode {
+    /// Expire the session, so it can be moved to another process or host, for example during a
+    /// rolling restart or a load balancer failover. The imported session resumes exactly where
+    /// the exported one left off.
+    ///
+    /// Sends and receives that already loaded the keys of the session are waited for, so the
+    /// packets they send or accept are accounted for in the export. For that reason migrating
+    /// from inside a `Sender` or `PayloadSink` callback fails with `ExportError::InCallback`.
+    /// Without the `std` feature this cannot be detected, so it must not be attempted, or the
+    /// export never returns.
+    Migrate,
+    /// Keep the session running, so that it can be recovered without a new handshake if the
+    /// process holding it crashes.
+    ///
+    /// The session restored from a checkpoint starts at the first of the next 2^19 counters, half
+    /// the smallest `Settings::counter_window_max_skip_ahead` any peer may use, and rekeys as soon
+    /// as it is serviced. Those counters are reserved for it: once the running session has used
+    /// half of them, sending data fails with `SendError::RekeyUrgentlyNeeded` and a rekey is
+    /// started.
+    /// Taking a new checkpoint moves the reservation forward, and the key that replaces the
+    /// checkpointed one can be used freely.
+    ///
+    /// # Security
+    /// Only the latest checkpoint of a session may ever be restored, and only while the session
+    /// it was taken from is no longer running. Earlier checkpoints reserved counters that the
+    /// running session has since been allowed to use. A checkpoint also goes stale once the
+    /// session rekeys, as the remote peer soon stops accepting the old keys, so take a new one
+    /// whenever `Session::ratchet_count` changes.
+    Checkpoint,
+}
+
+/// A session persisted by a previous instance of a context, to be passed to
+/// `Context::new_with_existing_ratchets`.
+pub struct RestoredSession<C: CryptoLayer> {
+    /// The static public key of the remote peer.
+    pub static_remote_key: C::PublicKey,
+    /// The ratchet states the application last saved for the remote peer with
+    /// `ApplicationLayer::save_ratchet_state`.
+    pub ratchet_states: RatchetStates,
+    /// Arbitrary data meaningful to the application to include with session object.
+    pub session_data: C::SessionData,
+    /// The blob returned by `Context::export_session_state` for this session.
+    pub exported_state: Vec<u8>,
+}
+
+/// Optional parameters of the receive functions, see `Context::receive_with_options`.
+///
+/// `ReceiveOptions::default()` receives a packet exactly like `Context::receive`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReceiveOptions<'a> {
+    /// The time at which the packet arrived.
+    ///
+    /// `Context::receive` calls `ApplicationLayer::time` to find out when a packet arrived, which
+    /// on a heavily loaded system can be a while after the packet reached the receive buffer of
+    /// the socket. If this is `Some` it is used instead wherever the age of the packet matters:
+    /// the expiry of fragments waiting to be reassembled, the expiry of challenge responses, the
+    /// hello rate challenges scale with, and the windows faults are counted in.
+    /// `ApplicationLayer::time` is still called for everything that schedules session timers,
+    /// such as resends, timeouts and rekeying.
+    ///
+    /// This must be on the same clock and in the same units as `ApplicationLayer::time`, for
+    /// example a kernel receive timestamp converted to that clock, and must not be later than the
+    /// current time.
+    pub arrival_time: Option<i64>,
+    /// Additional associated data that is authenticated but neither encrypted nor sent, such as a
+    /// routing header the application puts in front of every packet.
+    ///
+    /// A data packet is only accepted if it was sent with `Context::send_with_aad` and exactly
+    /// the same `aad`, otherwise receiving it fails with `FaultType::FailedAuth`. A packet sent in
+    /// several fragments is authenticated with `aad` once, after it has been reassembled, so all
+    /// of its fragments must be received with the same `aad`.
+    ///
+    /// Only data packets are authenticated with `aad`. Handshake and control packets, as well as
+    /// packets sent with `Session::send_keepalive` or `Session::send_ordered`, are received as if
+    /// `aad` was empty.
+    pub aad: &'a [u8],
+}
+
+pub(crate) type SessionMap<C> = KidMap<Weak<Session<C>>>;
 
 pub(crate) type SessionQueue<C> = IndexedBinaryHeap<Weak<Session<C>>, Reverse<i64>>;
 
@@ -53,28 +166,83 @@ pub(crate) type SessionQueue<C> = IndexedBinaryHeap<Weak<Session<C>>, Reverse<i6
 /// One of these is allocated as an `Arc` to initialize this implementation of ZSSP.
 /// See `Context::new`.
 pub struct ContextInner<C: CryptoLayer> {
-    /// The `CryptoRng` instance that was passed to ZSSP when this context was created.
-    pub rng: Mutex<C::Rng>,
+    /// The `CryptoRng` instances that were passed to ZSSP when this context was created.
+    /// See `CryptoLayer::RNG_SHARDS`.
+    pub(crate) rng_shards: Box<[Mutex<C::Rng>]>,
     pub(crate) next_service_time: AtomicI64,
     pub(crate) s_secret: C::KeyPair,
+    /// Counter and secretly keyed PRP used to generate unpredictable local key ids.
+    pub(crate) kid_counter: AtomicU64,
+    pub(crate) kid_prp: C::PrpEnc,
     /// `session_queue -> state_machine_lock -> state -> session_map`
     pub(crate) session_queue: Mutex<SessionQueue<C>>,
+    /// The number of sessions dropped since `session_queue` was last compacted.
+    pub(crate) dropped_sessions: AtomicUsize,
     /// `session_queue -> state_machine_lock -> state -> session_map`
+    ///
+    /// Sharded by key id, see `KidMap` for how its shard locks are ordered.
     pub(crate) session_map: SessionMap<C>,
-    pub(crate) unassociated_defrag_cache: Mutex<UnassociatedFragCache<C>>,
+    pub(crate) unassociated_defrag_cache: Mutex<FragCache<C>>,
     pub(crate) unassociated_handshake_states: UnassociatedHandshakeCache<C>,
 
     pub(crate) challenge: ChallengeContext,
+    pub(crate) hello_rate: HelloRate,
+    pub(crate) version_unsupported_rate: HelloRate,
+    pub(crate) metrics: Metrics,
+    /// `reliable_sends -> session_queue -> state_machine_lock -> state -> session_map`
+    pub(crate) reliable_sends: ReliableSends<C>,
+    /// The nonces of the blobs `Context::import_session_state` has imported, so that none is
+    /// imported twice.
+    ///
+    /// `imported_exports -> session_queue -> state_machine_lock -> state -> session_map`
+    pub(crate) imported_exports: Mutex<HashSet<[u8; AES_GCM_NONCE_SIZE]>>,
 }
 impl<C: CryptoLayer> ContextInner<C> {
+    /// Returns the `CryptoRng` instance assigned to the current thread.
+    ///
+    /// Threads are spread over the RNG shards by the hash of their thread ID, so each RNG lock is
+    /// only contended by a fraction of all threads. Without the `std` feature there is no notion
+    /// of a thread, so the first shard is always used.
+    pub fn rng(&self) -> &Mutex<C::Rng> {
+        #[cfg(feature = "std")]
+        if self.rng_shards.len() > 1 {
+            use std::hash::{Hash, Hasher};
+            thread_local! {
+                static THREAD_HASH: usize = {
+                    let mut hasher = std::hash::DefaultHasher::new();
+                    std::thread::current().id().hash(&mut hasher);
+                    hasher.finish() as usize
+                };
+            }
+            return &self.rng_shards[THREAD_HASH.with(|h| *h) % self.rng_shards.len()];
+        }
+        &self.rng_shards[0]
+    }
+    /// Returns the difficulty that challenges should currently have, given `hello_rate`, the
+    /// recent rate of received hellos.
+    ///
+    /// Load is the greater of the occupancy of the unassociated handshake cache and the recent rate
+    /// of hellos relative to `Settings::challenge_max_hello_rate`. The difficulty scales linearly
+    /// with load between the configured min and max.
+    pub(crate) fn challenge_difficulty(&self, hello_rate: u64) -> u32 {
+        let settings = &C::SETTINGS;
+        let max_states = settings.max_unassociated_handshake_states.max(1) as u64;
+        let occupancy = self.unassociated_handshake_states.len() as u64 * 256 / max_states;
+        let rate_load = hello_rate.saturating_mul(256) / settings.challenge_max_hello_rate.max(1);
+        let load = occupancy.max(rate_load).min(256) as u32;
+
+        let min = settings.challenge_difficulty_min.clamp(DIFFICULTY, CHALLENGE_DIFFICULTY_LIMIT);
+        let max = settings.challenge_difficulty_max.clamp(min, CHALLENGE_DIFFICULTY_LIMIT);
+        min + (max - min) * load / 256
+    }
     pub(crate) fn reduce_next_service_time(&self, time: i64) -> Option<i64> {
         (self.next_service_time.fetch_min(time, Ordering::Relaxed) > time).then_some(time)
     }
 }
 
-fn parse_fragment_header<C: CryptoLayer>(
+fn parse_fragment_header<C: CryptoLayer, E>(
     incoming_fragment: &[u8],
-) -> Result<(usize, usize, [u8; AES_GCM_NONCE_SIZE]), ReceiveError<C>> {
+) -> Result<(usize, usize, [u8; AES_GCM_NONCE_SIZE]), ReceiveError<C, E>> {
     let fragment_no = incoming_fragment[FRAGMENT_NO_IDX] as usize;
     let fragment_count = incoming_fragment[FRAGMENT_COUNT_IDX] as usize;
     if fragment_no >= fragment_count || fragment_count > MAX_FRAGMENTS {
@@ -86,6 +254,8 @@ fn parse_fragment_header<C: CryptoLayer>(
 }
 
 /// Fragments and sends the packet, destroying it in the process.
+/// If `send` fails no further fragments are sent, and the packet type and fragment number of the
+/// fragment that failed are returned.
 ///
 /// Corresponds to the fragmentation algorithm described in Section 6.
 fn send_with_fragmentation<PrpEnc: Aes256Enc>(
@@ -93,7 +263,7 @@ fn send_with_fragmentation<PrpEnc: Aes256Enc>(
     mtu: usize,
     headered_packet: &mut [u8],
     hk_send: Option<&PrpEnc>,
-) -> bool {
+) -> Option<(u8, u8)> {
     let payload_len = headered_packet.len() - HEADER_SIZE;
     let payload_mtu = mtu - HEADER_SIZE;
     debug_assert!(payload_mtu >= 4);
@@ -103,6 +273,7 @@ fn send_with_fragmentation<PrpEnc: Aes256Enc>(
 
     let mut header: [u8; HEADER_SIZE] = headered_packet[..HEADER_SIZE].try_into().unwrap();
     header[FRAGMENT_COUNT_IDX] = fragment_count as u8;
+    let (packet_type, _) = from_nonce(&header[PACKET_NONCE_START..]);
 
     let mut i = HEADER_SIZE;
     for fragment_no in 0..fragment_count {
@@ -116,34 +287,218 @@ fn send_with_fragmentation<PrpEnc: Aes256Enc>(
             hk_send.encrypt_in_place((&mut fragment[HEADER_AUTH_START..HEADER_AUTH_END]).try_into().unwrap());
         }
         if !send.send_frag(fragment) {
-            return false;
+            return Some((packet_type, fragment_no as u8));
         }
         i = j;
     }
-    true
+    None
+}
+/// Pass the packet `send_with_fragmentation` failed to send, if any, to
+/// `ApplicationLayer::on_send_failure`.
+fn report_send_failure<C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
+    session: &Arc<Session<C>>,
+    failure: &Cell<Option<(u8, u8)>>,
+) {
+    if let Some((packet_type, fragment_no)) = failure.take() {
+        app.on_send_failure(session, packet_type, fragment_no);
+    }
+}
+/// Emit `tracing` events for the outcomes of a receive call that deserve more attention than the
+/// `LogEvent`s emitted along the way.
+#[cfg(feature = "tracing")]
+fn trace_result<C: CryptoLayer, E: core::fmt::Display, A>(
+    result: &Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, E, A>>,
+) {
+    match result {
+        Ok((ReceiveOk::Associated(session, event), _)) => match event {
+            SessionEvent::NewDowngradedSession | SessionEvent::DowngradedRatchetKey => {
+                tracing::warn!(session = ?Arc::as_ptr(session), ?event, "ratchet key downgraded")
+            }
+            _ => tracing::debug!(session = ?Arc::as_ptr(session), ?event, "received packet"),
+        },
+        Ok((ReceiveOk::Established(session), _)) => {
+            tracing::debug!(session = ?Arc::as_ptr(session), "session established")
+        }
+        Ok(_) => {}
+        Err(ReceiveError::ByzantineFault(fault)) => {
+            let session = fault.session.as_ref().map(Arc::as_ptr);
+            #[cfg(feature = "debug")]
+            let (file, line) = (fault.file(), fault.line());
+            #[cfg(not(feature = "debug"))]
+            let (file, line) = ("", 0);
+            if fault.unnatural {
+                tracing::warn!(
+                    ?session,
+                    error = ?fault.error,
+                    authenticated = fault.authenticated,
+                    caused_expiration = fault.caused_expiration,
+                    file,
+                    line,
+                    "unnatural byzantine fault"
+                )
+            } else {
+                tracing::debug!(?session, error = ?fault.error, file, line, "byzantine fault")
+            }
+        }
+        Err(e) => tracing::debug!(error = %e, "receive error"),
+    }
+}
+/// Count a fault against the session it was attributed to, and notify the application if that
+/// session has exceeded `Settings::unnatural_fault_threshold`.
+fn record_fault<C: CryptoLayer, App: ApplicationLayer<C>, A: Any>(
+    app: &mut App,
+    fault: &ByzantineFault<C>,
+    remote_address: &A,
+    current_time: i64,
+) {
+    if let Some(session) = &fault.session {
+        let exceeded = session.faults.record(
+            fault.error,
+            fault.unnatural,
+            fault.authenticated,
+            current_time,
+            C::SETTINGS.unnatural_fault_threshold,
+            C::SETTINGS.unnatural_fault_window,
+        );
+        if exceeded {
+            app.on_fault_threshold_exceeded(session, remote_address, session.fault_stats());
+        }
+    }
+}
+
+/// Notify the application of, log, and count the completion of the initial handshake of a session.
+#[cfg_attr(not(any(feature = "logging", feature = "tracing")), allow(unused_variables))]
+fn handshake_completed<C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
+    ctx: &ContextInner<C>,
+    session: &Arc<Session<C>>,
+    is_alice: bool,
+) {
+    let duration = app.time().saturating_sub(session.handshake_start_time).max(0) as u64;
+    app.on_handshake_completed(session, is_alice, duration);
+    let resends = session.kex.complete_handshake(duration);
+    log!(
+        app,
+        HandshakeCompleted {
+            session,
+            started_at_ms: session.handshake_start_time,
+            duration_ms: duration,
+            resend_count: resends,
+        }
+    );
+    Metrics::increment(&ctx.metrics.handshakes_completed);
+}
+/// Log and count the completion of a rekey in which we received K2.
+#[cfg_attr(not(any(feature = "logging", feature = "tracing")), allow(unused_variables))]
+fn rekey_completed<C: CryptoLayer, App: ApplicationLayer<C>>(
+    app: &mut App,
+    ctx: &ContextInner<C>,
+    session: &Arc<Session<C>>,
+) {
+    Metrics::increment(&ctx.metrics.rekeys_completed);
+    if let Some((start, duration, resends)) = session.kex.complete_rekey(app.time()) {
+        log!(
+            app,
+            RekeyCompleted {
+                session,
+                started_at_ms: start,
+                duration_ms: duration,
+                resend_count: resends,
+            }
+        );
+    }
 }
 
 impl<C: CryptoLayer> Context<C> {
     /// Create a new session context.
-    pub fn new(static_secret_key: C::KeyPair, mut rng: C::Rng) -> Self {
-        let challenge = ChallengeContext::new(&mut rng);
+    ///
+    /// The context will use a single RNG shard regardless of `CryptoLayer::RNG_SHARDS`.
+    /// See `Context::new_sharded`.
+    pub fn new(static_secret_key: C::KeyPair, rng: C::Rng) -> Self {
+        Self::new_inner(static_secret_key, vec![Mutex::new(rng)].into_boxed_slice())
+    }
+    /// Create a new session context with `CryptoLayer::RNG_SHARDS` independent RNG instances.
+    ///
+    /// `new_rng` is called once per shard, and must return a unique, independently seeded RNG
+    /// every time it is called.
+    pub fn new_sharded(static_secret_key: C::KeyPair, mut new_rng: impl FnMut() -> C::Rng) -> Self {
+        let rng_shards = (0..C::RNG_SHARDS.max(1)).map(|_| Mutex::new(new_rng())).collect();
+        Self::new_inner(static_secret_key, rng_shards)
+    }
+    /// Create a new session context that resumes sessions persisted before a restart, so their
+    /// remote peers can keep sending without a new handshake.
+    ///
+    /// Ratchet states alone do not contain the keys of an established session, so every restored
+    /// session must come with the last blob `Context::export_session_state` produced for it, in
+    /// either `ExportMode`, before the previous context was shut down or crashed. A blob is only
+    /// imported if it is for the same remote peer and has the same ratchet states as the
+    /// `RestoredSession`, otherwise a newer session has advanced the ratchet since the blob was
+    /// exported and importing it would resume stale keys.
+    ///
+    /// Contexts only hold weak references to sessions, so the application must keep the returned
+    /// sessions alive. The result of importing each session is returned in iteration order.
+    /// `Context::service` should be called soon after, so that the timers of the restored
+    /// sessions are scheduled.
+    ///
+    /// * `static_secret_key` - The static key of the previous context
+    /// * `rng` - The RNG of the context, see `Context::new`
+    /// * `master_key` - The key the sessions were exported with
+    /// * `restored_sessions` - The persisted sessions to resume
+    pub fn new_with_existing_ratchets(
+        static_secret_key: C::KeyPair,
+        rng: C::Rng,
+        master_key: &[u8; AES_256_KEY_SIZE],
+        restored_sessions: impl IntoIterator<Item = RestoredSession<C>>,
+    ) -> (Self, Vec<Result<Arc<Session<C>>, ImportError>>) {
+        let ctx = Self::new(static_secret_key, rng);
+        let sessions = restored_sessions
+            .into_iter()
+            .map(|restored| {
+                let expected = Some((&restored.static_remote_key, &restored.ratchet_states));
+                let blob = &restored.exported_state;
+                import_session(&ctx.0, restored.session_data, blob, master_key, expected).map(|(session, _)| session)
+            })
+            .collect();
+        (ctx, sessions)
+    }
+    fn new_inner(static_secret_key: C::KeyPair, rng_shards: Box<[Mutex<C::Rng>]>) -> Self {
+        let mut rng = rng_shards[0].lock();
+        let challenge = ChallengeContext::new(
+            &mut *rng,
+            C::SETTINGS.challenge_timeout,
+            C::SETTINGS.challenge_cookie_lifetime,
+        );
+        let mut kid_key = Zeroizing::new([0u8; AES_256_KEY_SIZE]);
+        rng.fill_bytes(kid_key.as_mut());
+        let unassociated_defrag_cache = Mutex::new(FragCache::new(&mut *rng));
+        let unassociated_handshake_states = UnassociatedHandshakeCache::new(&mut *rng);
+        drop(rng);
         Self(Arc::new(ContextInner {
-            rng: Mutex::new(rng),
+            rng_shards,
             s_secret: static_secret_key,
+            kid_counter: AtomicU64::new(0),
+            kid_prp: C::PrpEnc::new(&kid_key),
             next_service_time: AtomicI64::new(i64::MAX),
-            session_map: RwLock::new(HashMap::new()),
+            session_map: KidMap::new(),
             challenge,
+            hello_rate: HelloRate::new(),
+            version_unsupported_rate: HelloRate::new(),
+            metrics: Metrics::new(),
             session_queue: Mutex::new(IndexedBinaryHeap::new()),
-            unassociated_defrag_cache: Mutex::new(UnassociatedFragCache::new()),
-            unassociated_handshake_states: UnassociatedHandshakeCache::new(),
+            dropped_sessions: AtomicUsize::new(0),
+            unassociated_defrag_cache,
+            unassociated_handshake_states,
+            reliable_sends: ReliableSends::new(),
+            imported_exports: Mutex::new(HashSet::new()),
         }))
     }
 
     /// Create a new session and send initialization packets to Bob, our remote peer.
     ///
     /// The session will not be "established" right away, and so will not be able to send data to
-    /// the remote peer until they respond and finish the handshake. A `SessionEvent` variant of
-    /// `Established` will be returned by `Context::receive` when this session is able to send data.
+    /// the remote peer until they respond and finish the handshake. `ReceiveOk::Established` will be
+    /// returned by `Context::receive` when this session is able to send data.
     ///
     /// This function returns an `Option<i64>`, which can safely be ignored if not using
     /// `Context::service_scheduled`. `Context::service_scheduled` contains documentation on how to
@@ -155,7 +510,7 @@ impl<C: CryptoLayer> Context<C> {
     /// * `app` - Application layer instance
     /// * `send` - Function to be called to send one or more initial packets to the remote being
     ///   contacted
-    /// * `mtu` - MTU for initial packets
+    /// * `mtu` - MTU for initial packets, which must be at least `MIN_TRANSPORT_MTU`
     /// * `static_remote_key` - Remote side's static public NIST P-384 key
     /// * `session_data` - Arbitrary data meaningful to the application to include with session
     ///   object
@@ -170,9 +525,10 @@ impl<C: CryptoLayer> Context<C> {
         session_data: C::SessionData,
         identity: &[u8],
     ) -> Result<(Arc<Session<C>>, Option<i64>), OpenError> {
+        self.check_open(mtu, &static_remote_key, identity)?;
         let ratchet_states = app
             .restore_by_identity(&static_remote_key, &session_data, None)
-            .map_err(OpenError::StorageError)?
+            .map_err(OpenError::StorageReadError)?
             .unwrap_or_default();
         self.open_with_ratchet(
             app,
@@ -198,8 +554,8 @@ impl<C: CryptoLayer> Context<C> {
     /// `app.initiator_disallows_downgrade` return false.
     ///
     /// The session will not be "established" right away, and so will not be able to send data to
-    /// the remote peer until they respond and finish the handshake. A `SessionEvent` variant of
-    /// `Established` will be returned by `Context::receive` when this session is able to send data.
+    /// the remote peer until they respond and finish the handshake. `ReceiveOk::Established` will be
+    /// returned by `Context::receive` when this session is able to send data.
     ///
     /// This function returns an `Option<i64>`, which can safely be ignored if not using
     /// `Context::service_scheduled`. `Context::service_scheduled` contains documentation on how to
@@ -211,7 +567,7 @@ impl<C: CryptoLayer> Context<C> {
     /// * `app` - Application layer instance
     /// * `send` - Function to be called to send one or more initial packets to the remote being
     ///   contacted
-    /// * `mtu` - MTU for initial packets
+    /// * `mtu` - MTU for initial packets, which must be at least `MIN_TRANSPORT_MTU`
     /// * `static_remote_key` - Remote side's static public NIST P-384 key
     /// * `session_data` - Arbitrary data meaningful to the application to include with session
     ///   object
@@ -220,30 +576,78 @@ impl<C: CryptoLayer> Context<C> {
     /// * `ratchet_states` - The set of ratchet states that Alice should use to connect to Bob.
     pub fn open_with_ratchet<App: ApplicationLayer<C>>(
         &self,
-        app: App,
+        mut app: App,
         send: impl Sender,
-        mut mtu: usize,
+        mtu: usize,
         static_remote_key: C::PublicKey,
         session_data: C::SessionData,
         identity: &[u8],
         ratchet_states: RatchetStates,
     ) -> Result<(Arc<Session<C>>, Option<i64>), OpenError> {
-        mtu = mtu.max(MIN_TRANSPORT_MTU);
-        if identity.len() > IDENTITY_MAX_SIZE {
-            return Err(OpenError::IdentityTooLarge);
-        }
+        self.check_open(mtu, &static_remote_key, identity)?;
         // Process zeta layer.
-        trans_to_a1(
-            app,
+        let send_failure = Cell::new(None);
+        let ret = trans_to_a1(
+            &mut app,
             &self.0,
             static_remote_key,
             session_data,
             identity,
             ratchet_states,
             |packet, hk_send| {
-                send_with_fragmentation(send, mtu, packet, hk_send);
+                send_failure.set(send_with_fragmentation(send, mtu, packet, hk_send));
             },
-        )
+        )?;
+        report_send_failure(&mut app, &ret.0, &send_failure);
+        Ok(ret)
+    }
+    /// Return a live session with the remote peer `static_remote_key` if this context has one,
+    /// and otherwise open a new session exactly like `Context::open`.
+    ///
+    /// This lets an application reconnect to a known peer without creating a second session with
+    /// it while the first is still alive. A session that is still establishing is returned as
+    /// well, since its handshake is already in progress. Expired sessions are ignored.
+    /// When an existing session is returned `session_data` and `identity` are not used, nothing
+    /// is sent, and the returned `Option<i64>` is `None`.
+    ///
+    /// Finding the existing session takes time proportional to the number of sessions of this
+    /// context. Concurrent calls for the same remote peer may both open a new session.
+    pub fn open_or_recover<App: ApplicationLayer<C>>(
+        &self,
+        app: App,
+        send: impl Sender,
+        mtu: usize,
+        static_remote_key: C::PublicKey,
+        session_data: C::SessionData,
+        identity: &[u8],
+    ) -> Result<(Arc<Session<C>>, Option<i64>), OpenError> {
+        self.check_open(mtu, &static_remote_key, identity)?;
+        let key_bytes = static_remote_key.to_bytes();
+        // Sessions are upgraded only after the map is unlocked, since dropping the last reference
+        // to a session removes it from the map.
+        let existing = self.0.session_map.values().into_iter().find_map(|session| {
+            let session = session.upgrade()?;
+            (!session.is_expired() && session.remote_static_key().to_bytes() == key_bytes).then_some(session)
+        });
+        match existing {
+            Some(session) => Ok((session, None)),
+            None => self.open(app, send, mtu, static_remote_key, session_data, identity),
+        }
+    }
+    /// Validate the arguments of `open` before anything is stored or sent.
+    fn check_open(&self, mtu: usize, static_remote_key: &C::PublicKey, identity: &[u8]) -> Result<(), OpenError> {
+        if identity.len() > IDENTITY_MAX_SIZE.max(C::SETTINGS.max_streamed_identity_size) {
+            return Err(OpenError::IdentityTooLarge);
+        }
+        if mtu < MIN_TRANSPORT_MTU {
+            return Err(OpenError::MtuTooSmall);
+        }
+        // A session with ourselves would never complete, since both sides would be using the same
+        // static key. Any other key was validated by `P384PublicKey::from_bytes` when it was created.
+        if static_remote_key.to_bytes() == self.0.s_secret.public_key_bytes() {
+            return Err(OpenError::InvalidRemoteKey);
+        }
+        Ok(())
     }
 
     /// Receive, authenticate, decrypt, and process a physical wire packet.
@@ -256,20 +660,257 @@ impl<C: CryptoLayer> Context<C> {
     /// * `send_unassociated_reply` - Function to send reply packets directly when no session exists
     /// * `send_unassociated_mtu` - MTU for unassociated replies
     /// * `send_to` - Function to get senders for existing sessions, permitting MTU and path lookup
-    /// * `remote_address` - Whatever the remote address is, as long as you can Hash it
+    /// * `remote_address` - The address of the remote peer, attached to any returned error
     /// * `incoming_fragment_buf` - Buffer containing incoming wire packet (the context takes ownership)
-    /// * `output_buffer` - Buffer to receive decrypted and authenticated object data
-    pub fn receive<App: ApplicationLayer<C>>(
+    /// * `output_buffer` - Sink to receive decrypted and authenticated object data
+    pub fn receive<App: ApplicationLayer<C>, S: PayloadSink, A: Hash + Clone + Any>(
+        &self,
+        app: App,
+        send_unassociated_reply: impl Sender,
+        send_unassociated_mtu: usize,
+        send_to: impl SendTo<C>,
+        remote_address: &A,
+        incoming_fragment_buf: C::IncomingPacketBuffer,
+        output_buffer: S,
+    ) -> Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, S::Error, A>> {
+        self.receive_inner(
+            app,
+            send_unassociated_reply,
+            send_unassociated_mtu,
+            send_to,
+            remote_address,
+            ReceiveOptions::default(),
+            incoming_fragment_buf,
+            |buf| buf,
+            output_buffer,
+        )
+    }
+    /// Receive, authenticate, decrypt, and process a physical wire packet exactly like
+    /// `Context::receive`, with the optional parameters in `options`. See `ReceiveOptions`.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    /// * `send_unassociated_reply` - Function to send reply packets directly when no session exists
+    /// * `send_unassociated_mtu` - MTU for unassociated replies
+    /// * `send_to` - Function to get senders for existing sessions, permitting MTU and path lookup
+    /// * `remote_address` - The address of the remote peer, attached to any returned error
+    /// * `options` - Optional parameters, such as the arrival time of the packet
+    /// * `incoming_fragment_buf` - Buffer containing incoming wire packet (the context takes ownership)
+    /// * `output_buffer` - Sink to receive decrypted and authenticated object data
+    pub fn receive_with_options<App: ApplicationLayer<C>, S: PayloadSink, A: Hash + Clone + Any>(
+        &self,
+        app: App,
+        send_unassociated_reply: impl Sender,
+        send_unassociated_mtu: usize,
+        send_to: impl SendTo<C>,
+        remote_address: &A,
+        options: ReceiveOptions<'_>,
+        incoming_fragment_buf: C::IncomingPacketBuffer,
+        output_buffer: S,
+    ) -> Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, S::Error, A>> {
+        self.receive_inner(
+            app,
+            send_unassociated_reply,
+            send_unassociated_mtu,
+            send_to,
+            remote_address,
+            options,
+            incoming_fragment_buf,
+            |buf| buf,
+            output_buffer,
+        )
+    }
+    /// Receive, authenticate, decrypt, and process a physical wire packet exactly like
+    /// `Context::receive`, given the time at which the packet arrived.
+    ///
+    /// This is `Context::receive_with_options` with only `ReceiveOptions::arrival_time` set, see it
+    /// for where `arrival_time` is used instead of `ApplicationLayer::time`.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    /// * `send_unassociated_reply` - Function to send reply packets directly when no session exists
+    /// * `send_unassociated_mtu` - MTU for unassociated replies
+    /// * `send_to` - Function to get senders for existing sessions, permitting MTU and path lookup
+    /// * `remote_address` - The address of the remote peer, attached to any returned error
+    /// * `arrival_time` - The time at which the packet arrived
+    /// * `incoming_fragment_buf` - Buffer containing incoming wire packet (the context takes ownership)
+    /// * `output_buffer` - Sink to receive decrypted and authenticated object data
+    pub fn receive_with_timestamp<App: ApplicationLayer<C>, S: PayloadSink, A: Hash + Clone + Any>(
+        &self,
+        app: App,
+        send_unassociated_reply: impl Sender,
+        send_unassociated_mtu: usize,
+        send_to: impl SendTo<C>,
+        remote_address: &A,
+        arrival_time: i64,
+        incoming_fragment_buf: C::IncomingPacketBuffer,
+        output_buffer: S,
+    ) -> Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, S::Error, A>> {
+        let options = ReceiveOptions { arrival_time: Some(arrival_time), ..ReceiveOptions::default() };
+        self.receive_with_options(
+            app,
+            send_unassociated_reply,
+            send_unassociated_mtu,
+            send_to,
+            remote_address,
+            options,
+            incoming_fragment_buf,
+            output_buffer,
+        )
+    }
+    /// Receive, authenticate, decrypt, and process a physical wire packet that is borrowed from
+    /// the caller rather than owned by the context.
+    ///
+    /// ZSSP only needs ownership of a packet when it is one fragment of a larger packet that has
+    /// not yet been fully received, since it must then be stored until the other fragments
+    /// arrive. Only in that case will `take_ownership` be called to produce an owned copy of the
+    /// fragment. Complete packets, which are the common case, are decrypted in place and never
+    /// copied.
+    ///
+    /// This function returns an `Option<i64>`, which can safely be ignored if not using
+    /// `Context::service_scheduled`. `Context::service_scheduled` contains documentation on how to
+    /// handle the return value.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    /// * `send_unassociated_reply` - Function to send reply packets directly when no session exists
+    /// * `send_unassociated_mtu` - MTU for unassociated replies
+    /// * `send_to` - Function to get senders for existing sessions, permitting MTU and path lookup
+    /// * `remote_address` - The address of the remote peer, attached to any returned error
+    /// * `options` - Optional parameters, see `ReceiveOptions`
+    /// * `incoming_fragment` - Buffer containing incoming wire packet, it may be modified in place
+    /// * `take_ownership` - Function to create an owned buffer from the incoming wire packet
+    /// * `output_buffer` - Sink to receive decrypted and authenticated object data
+    pub fn receive_borrowed<App: ApplicationLayer<C>, S: PayloadSink, A: Hash + Clone + Any>(
+        &self,
+        app: App,
+        send_unassociated_reply: impl Sender,
+        send_unassociated_mtu: usize,
+        send_to: impl SendTo<C>,
+        remote_address: &A,
+        options: ReceiveOptions<'_>,
+        incoming_fragment: &mut [u8],
+        take_ownership: impl FnOnce(&[u8]) -> C::IncomingPacketBuffer,
+        output_buffer: S,
+    ) -> Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, S::Error, A>> {
+        self.receive_inner(
+            app,
+            send_unassociated_reply,
+            send_unassociated_mtu,
+            send_to,
+            remote_address,
+            options,
+            incoming_fragment,
+            |buf| take_ownership(buf),
+            output_buffer,
+        )
+    }
+    /// Receive, authenticate, decrypt, and process a physical wire packet, returning the decrypted
+    /// payload directly instead of writing it to an output buffer.
+    ///
+    /// The buffer containing the incoming packet is reused to hold the decrypted payload, so when
+    /// the packet was not fragmented the payload is returned without being copied into a second
+    /// buffer. This is most useful when receiving very large payloads.
+    ///
+    /// The returned payload is `Some` only when the `SessionEvent` returned is `SessionEvent::Data`.
+    ///
+    /// This function returns an `Option<i64>`, which can safely be ignored if not using
+    /// `Context::service_scheduled`. `Context::service_scheduled` contains documentation on how to
+    /// handle the return value.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    /// * `send_unassociated_reply` - Function to send reply packets directly when no session exists
+    /// * `send_unassociated_mtu` - MTU for unassociated replies
+    /// * `send_to` - Function to get senders for existing sessions, permitting MTU and path lookup
+    /// * `remote_address` - The address of the remote peer, attached to any returned error
+    /// * `options` - Optional parameters, see `ReceiveOptions`
+    /// * `incoming_fragment_buf` - Buffer containing incoming wire packet (the context takes ownership)
+    pub fn receive_owned<App: ApplicationLayer<C>, A: Hash + Clone + Any>(
+        &self,
+        app: App,
+        send_unassociated_reply: impl Sender,
+        send_unassociated_mtu: usize,
+        send_to: impl SendTo<C>,
+        remote_address: &A,
+        options: ReceiveOptions<'_>,
+        incoming_fragment_buf: C::IncomingPacketBuffer,
+    ) -> Result<(ReceiveOk<C>, Option<i64>, Option<Vec<u8>>), ReceiveError<C, crate::io::Error, A>>
+    where
+        C::IncomingPacketBuffer: Into<Vec<u8>>,
+    {
+        let mut payload = None;
+        let (ok, next_service_time) = self.receive_inner(
+            app,
+            send_unassociated_reply,
+            send_unassociated_mtu,
+            send_to,
+            remote_address,
+            options,
+            incoming_fragment_buf,
+            |buf| buf,
+            OwnedPayload(&mut payload),
+        )?;
+        Ok((ok, next_service_time, payload))
+    }
+    fn receive_inner<
+        App: ApplicationLayer<C>,
+        B: AsRef<[u8]> + AsMut<[u8]>,
+        O: PayloadOutput<C, B>,
+        A: Hash + Clone + Any,
+    >(
         &self,
         mut app: App,
+        send_unassociated_reply: impl Sender,
+        send_unassociated_mtu: usize,
+        send_to: impl SendTo<C>,
+        remote_address: &A,
+        options: ReceiveOptions<'_>,
+        incoming_fragment_buf: B,
+        into_owned: impl FnOnce(B) -> C::IncomingPacketBuffer,
+        output: O,
+    ) -> Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, O::Error, A>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "zssp_receive",
+            kid = tracing::field::Empty,
+            session = tracing::field::Empty
+        )
+        .entered();
+        let result = self.receive_packet(
+            &mut app,
+            send_unassociated_reply,
+            send_unassociated_mtu,
+            send_to,
+            remote_address,
+            options,
+            incoming_fragment_buf,
+            into_owned,
+            output,
+        )
+        .map_err(|e| {
+            if let ReceiveError::ByzantineFault(fault) = &e {
+                self.0.metrics.record_fault(fault.error);
+                let current_time = options.arrival_time.unwrap_or_else(|| app.time());
+                record_fault(&mut app, fault, remote_address, current_time);
+            }
+            e.with_remote_address(remote_address)
+        });
+        #[cfg(feature = "tracing")]
+        trace_result(&result);
+        result
+    }
+    /// `into_owned` is only called if the incoming fragment needs to be stored for defragmentation.
+    fn receive_packet<App: ApplicationLayer<C>, B: AsRef<[u8]> + AsMut<[u8]>, O: PayloadOutput<C, B>, A: Hash + Any>(
+        &self,
+        app: &mut App,
         mut send_unassociated_reply: impl Sender,
         mut send_unassociated_mtu: usize,
         mut send_to: impl SendTo<C>,
-        remote_address: &impl Hash,
-        mut incoming_fragment_buf: C::IncomingPacketBuffer,
-        output_buffer: impl Write,
-    ) -> Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C>> {
+        remote_address: &A,
+        options: ReceiveOptions<'_>,
+        mut incoming_fragment_buf: B,
+        into_owned: impl FnOnce(B) -> C::IncomingPacketBuffer,
+        output: O,
+    ) -> Result<(ReceiveOk<C>, Option<i64>), ReceiveError<C, O::Error>> {
         use crate::result::FaultType::*;
+        let ReceiveOptions { arrival_time, aad } = options;
         let ctx = &self.0;
         send_unassociated_mtu = send_unassociated_mtu.max(MIN_TRANSPORT_MTU);
         let incoming_fragment: &mut [u8] = incoming_fragment_buf.as_mut();
@@ -281,11 +922,19 @@ impl<C: CryptoLayer> Context<C> {
 
         let kid_recv = incoming_fragment[0..KID_SIZE].try_into().unwrap();
         if let Some(kid_recv) = NonZeroU32::new(u32::from_ne_bytes(kid_recv)) {
-            let session = ctx.session_map.read().get(&kid_recv).map(|r| r.upgrade());
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("kid", kid_recv.get());
+            let session = ctx.session_map.shard(kid_recv).read().get(&kid_recv).map(|r| r.upgrade());
             if let Some(Some(session)) = session {
-                let state = session.state.read();
+                #[cfg(feature = "tracing")]
+                tracing::Span::current().record("session", tracing::field::debug(Arc::as_ptr(&session)));
+                // Data packets are received without locking the session state, see `DataKeys`.
+                let keys = session.load_data_keys();
+                // Packets addressed to a key id we have rotated away from are still accepted until
+                // the remote peer acknowledges the rotation.
+                let kid = keys.resolve_kid(kid_recv);
                 let header_auth = &mut incoming_fragment[HEADER_AUTH_START..HEADER_AUTH_END];
-                state.hk_recv.decrypt_in_place(header_auth.try_into().unwrap());
+                keys.hk_recv.decrypt_in_place(header_auth.try_into().unwrap());
 
                 let (fragment_no, fragment_count, nonce) = parse_fragment_header(incoming_fragment)?;
                 let (packet_type, incoming_counter) = from_nonce(&nonce);
@@ -298,22 +947,25 @@ impl<C: CryptoLayer> Context<C> {
 
                 //vrfy
                 if packet_type == PACKET_TYPE_HANDSHAKE_RESPONSE {
-                    if !matches!(&state.beta, ZetaAutomata::A1(_)) {
+                    if !keys.awaiting_x2 {
                         // A resent handshake response from Bob may have arrived out of order,
                         // after we already received one.
                         return Err(fault!(OutOfSequence, false, session));
                     }
-                    if incoming_counter >= COUNTER_WINDOW_MAX_SKIP_AHEAD {
+                    if incoming_counter >= max_skip_ahead::<C>() {
                         return Err(fault!(ExpiredCounter, true, session));
                     }
-                } else if PACKET_TYPE_USES_COUNTER_RANGE.contains(&packet_type) {
+                } else if PACKET_TYPE_USES_COUNTER_RANGE.contains(&packet_type)
+                    || packet_type == PACKET_TYPE_KID_ROTATE
+                    || packet_type == PACKET_TYPE_REKEY_DEFER
+                {
                     // For DOS resistant reply-protection we need to check that the given counter is
                     // in the window of valid counters immediately.
                     // But for packets larger than 1 fragment we can't actually record the
                     // counter as received until we've authenticated the packet.
                     // So we check the counter window twice, and only update it the second time
                     // after the packet has been authenticated.
-                    if !session.window.check(incoming_counter) {
+                    if !session.window.check_and_count(incoming_counter) {
                         // This can occur naturally if packets arrive way out of order, or
                         // if they are duplicates.
                         // This can also be naturally triggered if Bob has just successfully
@@ -322,48 +974,51 @@ impl<C: CryptoLayer> Context<C> {
                         // dropped, and the remote party is still sending us data.
                         return Err(fault!(ExpiredCounter, false, session));
                     }
-                } else if packet_type == PACKET_TYPE_HANDSHAKE_COMPLETION {
+                } else if packet_type == PACKET_TYPE_HANDSHAKE_COMPLETION
+                    || packet_type == PACKET_TYPE_IDENTITY_CONTINUATION
+                {
                     // This can be triggered if Bob successfully received a session key and
-                    // needs to reject all of Alice's resends of PACKET_TYPE_NOISE_XK_PATTERN_3.
+                    // needs to reject all of Alice's resends of PACKET_TYPE_NOISE_XK_PATTERN_3,
+                    // or of the identity continuations that follow it.
                     return Err(fault!(InvalidPacket, false, session));
                 } else {
                     return Err(fault!(InvalidPacket, true, session));
                 }
 
                 // Handle defragmentation.
+                let send_failure = Cell::new(None);
                 let ret = if packet_type == PACKET_TYPE_DATA {
-                    let fragments = if fragment_count > 1 {
+                    if fragment_count > 1 {
                         let idx = incoming_counter as usize % session.defrag.len();
                         session.defrag[idx].lock().assemble(
                             incoming_counter,
-                            incoming_fragment_buf,
+                            into_owned(incoming_fragment_buf),
                             fragment_no,
                             fragment_count,
                             &mut fragment_buffer,
                         );
                         if fragment_buffer.is_empty() {
-                            drop(state);
+                            drop(keys);
                             return Ok((ReceiveOk::Fragment(session), None));
-                        } else {
-                            // We have not yet authenticated the sender so we do not report
-                            // receiving a packet from them.
-                            fragment_buffer.as_mut()
                         }
+                        // We have not yet authenticated the sender so we do not report
+                        // receiving a packet from them.
+                        let len = output.output_assembled(&session, &keys, kid, &nonce, aad, &mut fragment_buffer)?;
+                        Metrics::record_data(&ctx.metrics.data_packets_rx, &ctx.metrics.bytes_rx, len);
                     } else {
-                        std::slice::from_mut(&mut incoming_fragment_buf)
-                    };
-
-                    receive_payload_in_place(&session, state, kid_recv, &nonce, fragments, output_buffer)?;
+                        let len = output.output_single(&session, &keys, kid, &nonce, aad, incoming_fragment_buf)?;
+                        Metrics::record_data(&ctx.metrics.data_packets_rx, &ctx.metrics.bytes_rx, len);
+                    }
 
                     (SessionEvent::Data, None)
                 } else {
-                    drop(state);
+                    drop(keys);
                     let mut buffer = ArrayVec::<u8, HANDSHAKE_RESPONSE_SIZE>::new();
                     let assembled_packet = if fragment_count > 1 {
                         let idx = incoming_counter as usize % session.defrag.len();
                         session.defrag[idx].lock().assemble(
                             incoming_counter,
-                            incoming_fragment_buf,
+                            into_owned(incoming_fragment_buf),
                             fragment_no,
                             fragment_count,
                             &mut fragment_buffer,
@@ -371,14 +1026,10 @@ impl<C: CryptoLayer> Context<C> {
                         if fragment_buffer.is_empty() {
                             return Ok((ReceiveOk::Fragment(session), None));
                         } else {
-                            for fragment in fragment_buffer.as_ref() {
-                                buffer
-                                    .try_extend_from_slice(&fragment.as_ref()[HEADER_SIZE..])
-                                    .map_err(|_| fault!(InvalidPacket, true, session))?;
-                            }
                             // We have not yet authenticated the sender so we do not report
                             // receiving a packet from them.
-                            buffer.as_mut()
+                            concat_payloads(&fragment_buffer, &mut buffer)
+                                .ok_or_else(|| fault!(InvalidPacket, true, session))?
                         }
                     } else {
                         &mut incoming_fragment_buf.as_mut()[HEADER_SIZE..]
@@ -386,18 +1037,21 @@ impl<C: CryptoLayer> Context<C> {
 
                     let send_associated = |packet: &mut [u8], hk_send: Option<&C::PrpEnc>| {
                         if let Some((sender, mut mtu)) = send_to.init_send(&session) {
+                            if mtu == 0 {
+                                mtu = session.mtu_hint();
+                            }
                             mtu = mtu.max(MIN_TRANSPORT_MTU);
-                            send_with_fragmentation(sender, mtu, packet, hk_send);
+                            send_failure.set(send_with_fragmentation(sender, mtu, packet, hk_send));
                         }
                     };
                     match packet_type {
                         PACKET_TYPE_HANDSHAKE_RESPONSE => {
                             log!(app, ReceivedRawX2);
                             let (should_warn_missing_ratchet, reduced) = received_x2_trans(
-                                &mut app,
+                                app,
                                 ctx,
                                 &session,
-                                kid_recv,
+                                kid,
                                 &nonce,
                                 assembled_packet,
                                 send_associated,
@@ -412,65 +1066,96 @@ impl<C: CryptoLayer> Context<C> {
                         PACKET_TYPE_KEY_CONFIRM => {
                             log!(app, ReceivedRawKeyConfirm);
                             let (just_established, reduced) = received_c1_trans(
-                                &mut app,
+                                app,
                                 ctx,
                                 &session,
-                                kid_recv,
+                                kid,
                                 &nonce,
                                 assembled_packet,
                                 send_associated,
                             )?;
                             log!(app, KeyConfirmIsAuthSentAck(&session));
                             if just_established {
-                                (SessionEvent::Established, reduced)
+                                handshake_completed(app, ctx, &session, true);
+                                report_send_failure(app, &session, &send_failure);
+                                return Ok((ReceiveOk::Established(session), reduced));
                             } else {
                                 (SessionEvent::Control, reduced)
                             }
                         }
                         PACKET_TYPE_ACK => {
                             log!(app, ReceivedRawAck);
+                            // Acknowledgements of a key id rotation are only valid if they are
+                            // addressed to the new key id, so we do not resolve `kid_recv` here.
                             let reduced =
-                                received_c2_trans(&mut app, ctx, &session, kid_recv, &nonce, assembled_packet)?;
+                                received_c2_trans(app, ctx, &session, kid_recv, &nonce, assembled_packet)?;
                             log!(app, AckIsAuth(&session));
                             (SessionEvent::Control, reduced)
                         }
                         PACKET_TYPE_REKEY_INIT => {
                             log!(app, ReceivedRawK1);
-                            let reduced = received_k1_trans(
-                                &mut app,
+                            let (action, reduced) = received_k1_trans(
+                                app,
                                 ctx,
                                 &session,
-                                kid_recv,
+                                kid,
                                 &nonce,
                                 assembled_packet,
                                 send_associated,
                             )?;
-                            log!(app, K1IsAuthSentK2(&session));
-                            (SessionEvent::Control, reduced)
+                            match action {
+                                RekeyAction::Allow => {
+                                    log!(app, K1IsAuthSentK2(&session));
+                                    Metrics::increment(&ctx.metrics.rekeys_completed);
+                                    (SessionEvent::Control, reduced)
+                                }
+                                RekeyAction::Defer => {
+                                    log!(app, K1IsAuthSentDefer(&session));
+                                    (SessionEvent::Control, reduced)
+                                }
+                                RekeyAction::Reject => {
+                                    log!(app, K1IsAuthRejected(&session));
+                                    (SessionEvent::RekeyRejected, reduced)
+                                }
+                            }
                         }
                         PACKET_TYPE_REKEY_COMPLETE => {
                             log!(app, ReceivedRawK2);
                             let reduced = received_k2_trans(
-                                &mut app,
+                                app,
                                 ctx,
                                 &session,
-                                kid_recv,
+                                kid,
                                 &nonce,
                                 assembled_packet,
                                 send_associated,
                             )?;
                             log!(app, K2IsAuthSentKeyConfirm(&session));
+                            rekey_completed(app, ctx, &session);
                             (SessionEvent::Control, reduced)
                         }
                         PACKET_TYPE_SESSION_REJECTED => {
                             log!(app, ReceivedRawD);
-                            received_d_trans(&session, kid_recv, &nonce, assembled_packet)?;
+                            received_d_trans(app, &session, kid, &nonce, assembled_packet)?;
                             log!(app, DIsAuthClosedSession(&session));
                             (SessionEvent::Rejected, None)
                         }
+                        PACKET_TYPE_KID_ROTATE => {
+                            log!(app, ReceivedRawKidRotate);
+                            received_kr_trans(app, &session, kid, &nonce, assembled_packet, send_associated)?;
+                            log!(app, KidRotateIsAuthSentAck(&session));
+                            (SessionEvent::Control, None)
+                        }
+                        PACKET_TYPE_REKEY_DEFER => {
+                            log!(app, ReceivedRawRekeyDefer);
+                            let reduced = received_rd_trans(app, ctx, &session, kid, &nonce, assembled_packet)?;
+                            log!(app, RekeyDeferIsAuth(&session));
+                            (SessionEvent::Control, reduced)
+                        }
                         _ => return Err(fault!(InvalidPacket, true, session)), // This is unreachable.
                     }
                 };
+                report_send_failure(app, &session, &send_failure);
                 Ok((ReceiveOk::Associated(session, ret.0), ret.1))
             } else {
                 // Check for and handle PACKET_TYPE_ALICE_NOISE_XK_PATTERN_3
@@ -490,44 +1175,79 @@ impl<C: CryptoLayer> Context<C> {
                     );
 
                     //vrfy
-                    if packet_type != PACKET_TYPE_HANDSHAKE_COMPLETION || incoming_counter != 0 {
+                    let x3_flags =
+                        STREAMED_COMPLETION_COUNTER | ENCODED_COMPLETION_COUNTER | MISUSE_RESISTANT_COMPLETION_COUNTER;
+                    let is_x3 = packet_type == PACKET_TYPE_HANDSHAKE_COMPLETION && incoming_counter & !x3_flags == 0;
+                    let is_continuation = packet_type == PACKET_TYPE_IDENTITY_CONTINUATION && incoming_counter > 0;
+                    if !is_x3 && !is_continuation {
                         return Err(fault!(InvalidPacket, true));
                     }
 
                     let mut buffer = ArrayVec::<u8, HANDSHAKE_COMPLETION_MAX_SIZE>::new();
                     let assembled_packet = if fragment_count > 1 {
-                        zeta.defrag.lock().assemble(
-                            incoming_counter,
-                            incoming_fragment_buf,
-                            fragment_no,
-                            fragment_count,
-                            &mut fragment_buffer,
-                        );
+                        if is_continuation {
+                            assemble_identity_continuation(
+                                &zeta,
+                                incoming_counter,
+                                into_owned(incoming_fragment_buf),
+                                fragment_no,
+                                fragment_count,
+                                &mut fragment_buffer,
+                            )?;
+                        } else {
+                            zeta.defrag.lock().assemble(
+                                incoming_counter,
+                                into_owned(incoming_fragment_buf),
+                                fragment_no,
+                                fragment_count,
+                                &mut fragment_buffer,
+                            );
+                        }
                         if fragment_buffer.is_empty() {
                             return Ok((ReceiveOk::Unassociated, None));
                         } else {
-                            for fragment in fragment_buffer.as_ref() {
-                                buffer
-                                    .try_extend_from_slice(&fragment.as_ref()[HEADER_SIZE..])
-                                    .map_err(|_| fault!(InvalidPacket, true))?;
-                            }
-                            buffer.as_mut()
+                            concat_payloads(&fragment_buffer, &mut buffer).ok_or_else(|| fault!(InvalidPacket, true))?
                         }
                     } else {
                         &mut incoming_fragment_buf.as_mut()[HEADER_SIZE..]
                     };
+                    let send_failure = Cell::new(None);
+                    let send_reply = |packet: &mut [u8], hk_send: Option<&C::PrpEnc>| {
+                        let mtu = send_unassociated_mtu;
+                        send_failure.set(send_with_fragmentation(send_unassociated_reply, mtu, packet, hk_send));
+                    };
+                    // An identity that Alice streams is buffered with her handshake until all of it
+                    // has been received.
+                    if is_continuation {
+                        log!(app, ReceivedRawIdentityContinuation);
+                        if !received_identity_continuation_trans(app, &zeta, incoming_counter, assembled_packet)? {
+                            return Ok((ReceiveOk::Unassociated, None));
+                        }
+                    } else if incoming_counter & STREAMED_COMPLETION_COUNTER != 0 {
+                        log!(app, ReceivedRawX3);
+                        let x3 = assembled_packet;
+                        let result = received_streamed_x3_trans(app, &zeta, kid_recv, x3, incoming_counter, send_reply);
+                        if let Err(ReceiveError::Rejected(_)) = &result {
+                            self.0.unassociated_handshake_states.remove(kid_recv);
+                        }
+                        result?;
+                        return Ok((ReceiveOk::Unassociated, None));
+                    }
                     // We must guarantee that this incoming handshake is processed once and only
                     // once. This prevents catastrophic nonce reuse caused by multithreading.
                     if !self.0.unassociated_handshake_states.remove(kid_recv) {
                         return Ok((ReceiveOk::Unassociated, None));
                     }
 
-                    log!(app, ReceivedRawX3);
-                    let (session, should_warn_missing_ratchet, reduced) =
-                        received_x3_trans(&mut app, ctx, zeta, kid_recv, assembled_packet, |packet, hk_send| {
-                            send_with_fragmentation(send_unassociated_reply, send_unassociated_mtu, packet, hk_send);
-                        })?;
+                    let (session, should_warn_missing_ratchet, reduced) = if is_continuation {
+                        received_streamed_identity_trans(app, ctx, zeta, send_reply)?
+                    } else {
+                        log!(app, ReceivedRawX3);
+                        received_x3_trans(app, ctx, zeta, kid_recv, assembled_packet, incoming_counter, send_reply)?
+                    };
                     log!(app, X3IsAuthSentKeyConfirm(&session));
+                    handshake_completed(app, ctx, &session, false);
+                    report_send_failure(app, &session, &send_failure);
                     Ok((
                         ReceiveOk::Associated(
                             session,
@@ -548,11 +1268,16 @@ impl<C: CryptoLayer> Context<C> {
             }
         } else {
             let (fragment_no, fragment_count, nonce) = parse_fragment_header(incoming_fragment)?;
-            let (packet_type, _c) = from_nonce(&nonce);
-            log!(app, ReceivedRawFragment(packet_type, _c, fragment_no, fragment_count));
+            let (packet_type, c) = from_nonce(&nonce);
+            log!(app, ReceivedRawFragment(packet_type, c, fragment_no, fragment_count));
+            let address_hash = ctx.challenge.address_hash(remote_address);
+            app.on_unassociated_packet(packet_type, address_hash, fragment_no, fragment_count);
 
             //vrfy
-            if packet_type != PACKET_TYPE_HANDSHAKE_HELLO && packet_type != PACKET_TYPE_CHALLENGE {
+            if packet_type != PACKET_TYPE_HANDSHAKE_HELLO
+                && packet_type != PACKET_TYPE_CHALLENGE
+                && packet_type != PACKET_TYPE_VERSION_UNSUPPORTED
+            {
                 return Err(fault!(InvalidPacket, true));
             }
 
@@ -562,10 +1287,10 @@ impl<C: CryptoLayer> Context<C> {
                     &nonce,
                     remote_address,
                     incoming_fragment.len() - HEADER_SIZE,
-                    incoming_fragment_buf,
+                    into_owned(incoming_fragment_buf),
                     fragment_no,
                     fragment_count,
-                    app.time(),
+                    arrival_time.unwrap_or_else(|| app.time()),
                     &mut fragment_buffer,
                 );
                 if let Some(t) = next_service_time {
@@ -574,12 +1299,7 @@ impl<C: CryptoLayer> Context<C> {
                 if fragment_buffer.is_empty() {
                     return Ok((ReceiveOk::Unassociated, next_service_time));
                 } else {
-                    for fragment in fragment_buffer.as_ref() {
-                        buffer
-                            .try_extend_from_slice(&fragment.as_ref()[HEADER_SIZE..])
-                            .map_err(|_| fault!(InvalidPacket, true))?;
-                    }
-                    buffer.as_mut()
+                    concat_payloads(&fragment_buffer, &mut buffer).ok_or_else(|| fault!(InvalidPacket, true))?
                 }
             } else {
                 &mut incoming_fragment_buf.as_mut()[HEADER_SIZE..]
@@ -588,49 +1308,92 @@ impl<C: CryptoLayer> Context<C> {
             if packet_type == PACKET_TYPE_HANDSHAKE_HELLO {
                 log!(app, ReceivedRawX1);
 
+                // Every version of the hello starts with Alice's key id and protocol version, so
+                // the version is checked before anything else about the hello is assumed.
+                if assembled_packet.len() < KID_SIZE + PROTOCOL_VERSION_SIZE {
+                    return Err(fault!(InvalidPacket, true));
+                }
+                let version = assembled_packet[KID_SIZE];
+                if !is_supported_version::<C>(version) {
+                    log!(app, X1VersionUnsupported(address_hash, version));
+                    let current_time = arrival_time.unwrap_or_else(|| app.time());
+                    let rate = ctx.version_unsupported_rate.record(current_time, VERSION_UNSUPPORTED_RATE_WINDOW);
+                    if rate <= MAX_VERSION_UNSUPPORTED_RATE {
+                        let mut packet = [0u8; HEADERED_VERSION_UNSUPPORTED_SIZE];
+                        packet[HEADER_SIZE..HEADER_SIZE + KID_SIZE].copy_from_slice(&assembled_packet[..KID_SIZE]);
+                        packet[HEADER_SIZE + KID_SIZE] = C::SETTINGS.min_accepted_version;
+                        packet[HEADER_SIZE + KID_SIZE + 1] = PROTOCOL_VERSION;
+                        packet[FRAGMENT_COUNT_IDX] = 1;
+                        set_header(&mut packet, 0, &to_nonce(PACKET_TYPE_VERSION_UNSUPPORTED, c));
+
+                        send_unassociated_reply.send_frag(&mut packet);
+                    }
+                    return Err(ReceiveError::Rejected(None));
+                }
                 if HANDSHAKE_HELLO_CHALLENGE_SIZE != assembled_packet.len() {
                     return Err(fault!(InvalidPacket, true));
                 }
                 // Process recv challenge layer.
                 let challenge_start = assembled_packet.len() - CHALLENGE_SIZE;
                 let hash = &mut C::Hash::new();
-                match app.incoming_session() {
+                let current_time = arrival_time.unwrap_or_else(|| app.time());
+                let hello_rate = ctx
+                    .hello_rate
+                    .record(current_time, C::SETTINGS.challenge_hello_rate_window as i64);
+                match app.incoming_session_with_address(remote_address) {
                     IncomingSessionAction::Allow => {}
-                    IncomingSessionAction::Challenge => {
-                        let result = ctx.challenge.process_hello(
-                            hash,
-                            remote_address,
-                            (&assembled_packet[challenge_start..]).try_into().unwrap(),
-                        );
-                        if let Err(challenge) = result {
-                            log!(app, X1FailedChallengeSentNewChallenge);
-                            let mut challenge_packet = ArrayVec::<u8, HEADERED_CHALLENGE_SIZE>::new();
-                            challenge_packet.extend([0u8; HEADER_SIZE]);
-                            challenge_packet
-                                .try_extend_from_slice(&assembled_packet[..KID_SIZE])
-                                .unwrap();
-                            challenge_packet.extend(challenge);
-                            let nonce = to_nonce(PACKET_TYPE_CHALLENGE, ctx.rng.lock().next_u64());
-                            challenge_packet[FRAGMENT_COUNT_IDX] = 1;
-                            challenge_packet[PACKET_NONCE_START..HEADER_SIZE]
-                                .copy_from_slice(&nonce[..PACKET_NONCE_SIZE]);
-                            set_header(&mut challenge_packet, 0, &nonce);
-
-                            send_unassociated_reply.send_frag(&mut challenge_packet);
-                            // If we issue a challenge the first hello packet will always fail.
-                            return Err(fault!(FailedAuth, false));
+                    IncomingSessionAction::Challenge(min_difficulty) => {
+                        let response = (&assembled_packet[challenge_start..]).try_into().unwrap();
+                        let difficulty = ctx.challenge_difficulty(hello_rate).max(min_difficulty);
+                        let result = if C::SETTINGS.stateless_challenges {
+                            ctx.challenge.process_hello_stateless(
+                                hash,
+                                ctx.rng(),
+                                remote_address,
+                                assembled_packet[..KID_SIZE].try_into().unwrap(),
+                                response,
+                                difficulty,
+                                current_time,
+                            )
                         } else {
-                            log!(app, X1SucceededChallenge);
+                            ctx.challenge.process_hello(hash, remote_address, response, difficulty, current_time)
+                        };
+                        match result {
+                            Err(rejection) => {
+                                log!(
+                                    app,
+                                    X1FailedChallengeSentNewChallenge(address_hash, rejection.reason, rejection.age)
+                                );
+                                let mut challenge_packet = ArrayVec::<u8, HEADERED_CHALLENGE_SIZE>::new();
+                                challenge_packet.extend([0u8; HEADER_SIZE]);
+                                challenge_packet
+                                    .try_extend_from_slice(&assembled_packet[..KID_SIZE])
+                                    .unwrap();
+                                challenge_packet.extend(rejection.challenge);
+                                let nonce = to_nonce(PACKET_TYPE_CHALLENGE, ctx.rng().lock().next_u64());
+                                challenge_packet[FRAGMENT_COUNT_IDX] = 1;
+                                challenge_packet[PACKET_NONCE_START..HEADER_SIZE]
+                                    .copy_from_slice(&nonce[..PACKET_NONCE_SIZE]);
+                                set_header(&mut challenge_packet, 0, &nonce);
+
+                                send_unassociated_reply.send_frag(&mut challenge_packet);
+                                // If we issue a challenge the first hello packet will always fail.
+                                return Err(fault!(FailedAuth, false));
+                            }
+                            Ok(_age) => {
+                                log!(app, X1SucceededChallenge(address_hash, _age));
+                            }
                         }
                     }
-                    IncomingSessionAction::Drop => return Err(ReceiveError::Rejected),
+                    IncomingSessionAction::Drop => return Err(ReceiveError::Rejected(None)),
                 }
 
                 // Process recv zeta layer.
                 let reduced = received_x1_trans(
-                    &mut app,
+                    app,
                     ctx,
                     hash,
+                    remote_address,
                     &nonce,
                     &mut assembled_packet[..challenge_start],
                     |packet, hk_send| {
@@ -649,13 +1412,40 @@ impl<C: CryptoLayer> Context<C> {
                 if let Some(kid_recv) =
                     NonZeroU32::new(u32::from_ne_bytes(assembled_packet[..KID_SIZE].try_into().unwrap()))
                 {
-                    if let Some(Some(session)) = ctx.session_map.read().get(&kid_recv).map(|r| r.upgrade()) {
+                    let session = ctx.session_map.shard(kid_recv).read().get(&kid_recv).and_then(Weak::upgrade);
+                    if let Some(session) = session {
                         respond_to_challenge(ctx, &session, &assembled_packet[KID_SIZE..].try_into().unwrap());
                         log!(app, ChallengeIsAuth(&session));
                         return Ok((ReceiveOk::Unassociated, None));
                     }
                 }
                 Err(fault!(UnknownLocalKeyId, true))
+            } else if packet_type == PACKET_TYPE_VERSION_UNSUPPORTED {
+                log!(app, ReceivedRawVersionUnsupported);
+                if assembled_packet.len() != VERSION_UNSUPPORTED_SIZE {
+                    return Err(fault!(InvalidPacket, true));
+                }
+                if let Some(kid_recv) =
+                    NonZeroU32::new(u32::from_ne_bytes(assembled_packet[..KID_SIZE].try_into().unwrap()))
+                {
+                    let session = ctx
+                        .session_map
+                        .shard(kid_recv)
+                        .read()
+                        .get(&kid_recv)
+                        .and_then(Weak::upgrade);
+                    if let Some(session) = session {
+                        // This only proves the sender saw our hello, so the session is left as is.
+                        if !is_reply_to_hello(&session, c) {
+                            return Err(fault!(FailedAuth, true, session));
+                        }
+                        let min_version = assembled_packet[KID_SIZE];
+                        let max_version = assembled_packet[KID_SIZE + 1];
+                        log!(app, VersionUnsupported(&session, min_version, max_version));
+                        return Ok((ReceiveOk::VersionUnsupported(session, min_version, max_version), None));
+                    }
+                }
+                Err(fault!(UnknownLocalKeyId, true))
             } else {
                 Err(fault!(InvalidPacket, true))
             }
@@ -667,19 +1457,277 @@ impl<C: CryptoLayer> Context<C> {
     /// then it should be called as soon as possible. If you are using `Context::service` instead,
     /// then this returned boolean can safely be ignored.
     ///
+    /// The MTU is specified per call, so it is safe for the MTU of the underlying path to change
+    /// at any point during the lifetime of a session.
+    ///
     /// * `session` - The session to send to
     /// * `send` - Function to call to send physical packet(s); the buffer passed to `send` is a
-    ///   slice of `data`
-    /// * `mtu_sized_buffer` - A writable work buffer whose size equals the MTU
+    ///   slice of `work_buffer`
+    /// * `mtu` - MTU for this call, must be at least `MIN_TRANSPORT_MTU`
+    /// * `work_buffer` - A writable work buffer whose size is at least `mtu`
     /// * `data` - Data to send
     pub fn send(
         &self,
         session: &Session<C>,
         send: impl Sender,
-        mtu_sized_buffer: &mut [u8],
+        mtu: usize,
+        work_buffer: &mut [u8],
+        data: &[u8],
+    ) -> Result<bool, SendError> {
+        if mtu < MIN_TRANSPORT_MTU || work_buffer.len() < mtu {
+            return Err(SendError::MtuTooSmall);
+        }
+        send_payload(&self.0, session, &[], &[], data, send, &mut work_buffer[..mtu])
+    }
+    /// Encrypt and send data over the session exactly like `Context::send`, authenticating it
+    /// along with `aad`.
+    ///
+    /// `aad` is additional associated data that is authenticated but neither encrypted nor sent.
+    /// The remote peer must receive the data with `ReceiveOptions::aad` set to exactly the same
+    /// `aad`, or it will be dropped with `FaultType::FailedAuth`. This binds the data to something
+    /// the application sends alongside it, such as its own routing header, so that it cannot be
+    /// spliced onto a different one. `aad` covers the data as a whole, however many fragments it
+    /// is sent in.
+    ///
+    /// * `session` - The session to send to
+    /// * `send` - Function to call to send physical packet(s); the buffer passed to `send` is a
+    ///   slice of `work_buffer`
+    /// * `mtu` - MTU for this call, must be at least `MIN_TRANSPORT_MTU`
+    /// * `work_buffer` - A writable work buffer whose size is at least `mtu`
+    /// * `aad` - Additional associated data to authenticate the data with
+    /// * `data` - Data to send
+    pub fn send_with_aad(
+        &self,
+        session: &Session<C>,
+        send: impl Sender,
+        mtu: usize,
+        work_buffer: &mut [u8],
+        aad: &[u8],
         data: &[u8],
     ) -> Result<bool, SendError> {
-        send_payload(&self.0, session, data, send, mtu_sized_buffer)
+        if mtu < MIN_TRANSPORT_MTU || work_buffer.len() < mtu {
+            return Err(SendError::MtuTooSmall);
+        }
+        send_payload(&self.0, session, aad, &[], data, send, &mut work_buffer[..mtu])
+    }
+    /// Encrypt and send data over the session exactly like `Session::send_ordered`, and keep
+    /// retransmitting it every `timeout_ms` milliseconds until it is acknowledged.
+    ///
+    /// ZSSP runs over unreliable transports and does not guarantee delivery. This is a simple
+    /// retransmission mechanism for request-response protocols built on top of it: the data is
+    /// retransmitted by `Context::service` until `ReliableSendHandle::acknowledge` is called with
+    /// its sequence number, typically once the response to it has been received. Retransmission
+    /// also stops once the session is dropped, or once retransmitting fails with a `SendError`,
+    /// for example because the session has expired. `SendError::RekeyUrgentlyNeeded` does not
+    /// stop it.
+    ///
+    /// Retransmissions are sent with a sender from the `send_to` passed to `Context::service`, and
+    /// carry the same sequence number as the original, so the remote peer may receive the data
+    /// more than once and should use `receive_sequence_number` to discard duplicates.
+    ///
+    /// The retransmission timer starts the next time the context is serviced, so this lowers
+    /// `Context::next_service_time` to `i64::MIN`, as if `Context::send` returned `Ok(true)`.
+    ///
+    /// * `session` - The session to send to
+    /// * `send` - Function to call to send the first transmission of the data
+    /// * `mtu` - MTU for this call, must be at least `MIN_TRANSPORT_MTU`
+    /// * `data` - Data to send
+    /// * `timeout_ms` - The number of milliseconds to wait for an acknowledgement before each
+    ///   retransmission
+    pub fn send_reliable(
+        &self,
+        session: &Arc<Session<C>>,
+        send: impl Sender,
+        mtu: usize,
+        data: &[u8],
+        timeout_ms: u64,
+    ) -> Result<ReliableSendHandle<C>, SendError> {
+        let (seq, _) = session.send_ordered(send, mtu, data)?;
+        let interval = timeout_ms.clamp(1, i64::MAX as u64) as i64;
+        self.0.reliable_sends.insert(session, seq, data, interval);
+        self.0.reduce_next_service_time(i64::MIN);
+        Ok(ReliableSendHandle::new(&self.0, session, seq))
+    }
+    /// Replace the key id the remote peer uses to address this session with a new random one.
+    ///
+    /// Key ids are sent in the clear, so a passive observer can use them to link together all of
+    /// the packets of a session. Rotating the key id, for example whenever the network path of
+    /// the session changes, makes this harder. The remote peer is told of the new key id with a
+    /// control packet that is resent until it is acknowledged. Until then packets addressed to
+    /// the old key id are still accepted.
+    ///
+    /// Only one rotation can be in progress at a time, and a rotation cannot be started while the
+    /// session is rekeying. In either case `SendError::KeyExchangeInProgress` is returned.
+    /// If no unused key id could be found `SendError::KeyIdsExhausted` is returned.
+    ///
+    /// On success the new key id is returned along with an `Option<i64>`, which can safely be
+    /// ignored if not using `Context::service_scheduled`. `Context::service_scheduled` contains
+    /// documentation on how to handle the return value.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    /// * `session` - The session to rotate the key id of
+    /// * `send` - Function to call to send the control packet
+    /// * `mtu` - MTU for this call, must be at least `MIN_TRANSPORT_MTU`
+    pub fn rotate_kid<App: ApplicationLayer<C>>(
+        &self,
+        mut app: App,
+        session: &Arc<Session<C>>,
+        send: impl Sender,
+        mtu: usize,
+    ) -> Result<(NonZeroU32, Option<i64>), SendError> {
+        if mtu < MIN_TRANSPORT_MTU {
+            return Err(SendError::MtuTooSmall);
+        }
+        let send_failure = Cell::new(None);
+        let ret = rotate_kid(&mut app, &self.0, session, |packet, hk_send| {
+            send_failure.set(send_with_fragmentation(send, mtu, packet, hk_send));
+        })?;
+        report_send_failure(&mut app, session, &send_failure);
+        Ok(ret)
+    }
+    /// Pause all timer processing for a session, for example while the network it uses is known
+    /// to be unavailable.
+    ///
+    /// A parked session will not resend handshake packets, will not time out and will not
+    /// initiate a rekey. It can still send and receive data, and it will still respond to
+    /// handshake packets from the remote peer.
+    /// Call `Context::unpark_session` to resume timer processing.
+    ///
+    /// * `session` - The session to park
+    pub fn park_session(&self, session: &Arc<Session<C>>) {
+        park_session(&self.0, session)
+    }
+    /// Resume timer processing for a session that was parked with `Context::park_session`.
+    /// Handshake timeouts are restarted as of `current_time`, and any pending resends are
+    /// sent on the next call to service. The session key is not considered to have been
+    /// refreshed by parking, so if it became due for a rekey while parked it will be rekeyed
+    /// right away.
+    ///
+    /// This function returns an `Option<i64>`, which can safely be ignored if not using
+    /// `Context::service_scheduled`. `Context::service_scheduled` contains documentation on how to
+    /// handle the return value.
+    ///
+    /// * `session` - The session to unpark
+    /// * `current_time` - The current time, as would be returned by `ApplicationLayer::time`
+    pub fn unpark_session(&self, session: &Arc<Session<C>>, current_time: i64) -> Option<i64> {
+        unpark_session(&self.0, session, current_time)
+    }
+    /// Serialize the state of an established session into a blob, so that it can be resumed by
+    /// `Context::import_session_state` without a new handshake. See `ExportMode` for the two
+    /// uses of this, moving the session to another context and checkpointing it.
+    ///
+    /// The returned blob contains the session keys and is encrypted and authenticated with
+    /// `master_key`, which must be shared with the importing context by the application.
+    /// A blob must be imported at most once, otherwise the imported sessions would reuse nonces.
+    /// The importing context refuses a blob it has already imported, but the application must
+    /// make sure that no two contexts import the same blob. `Session::serialize_state` and
+    /// `Context::deserialize_state` take and restore checkpoints that are not encrypted.
+    ///
+    /// Only sessions in an established state with no key exchange in progress can be exported.
+    /// A session cannot be migrated from inside a `Sender` or `PayloadSink` callback, see
+    /// `ExportMode::Migrate`.
+    ///
+    /// Timers are exported as absolute times as returned by `ApplicationLayer::time`, so the
+    /// importing host should share a clock with this one.
+    ///
+    /// * `session` - The session to export
+    /// * `master_key` - A secret 256-bit key used to encrypt the exported state
+    /// * `mode` - Whether the session is expired or keeps running
+    pub fn export_session_state(
+        &self,
+        session: &Arc<Session<C>>,
+        master_key: &[u8; AES_256_KEY_SIZE],
+        mode: ExportMode,
+    ) -> Result<Vec<u8>, ExportError> {
+        export_session(&self.0, session, master_key, mode)
+    }
+    /// Recreate a session from a blob returned by `Context::export_session_state`.
+    ///
+    /// This context must have the same static key and settings as the exporting context.
+    /// The session keeps the same key ids, so the remote peer will not notice as long as its
+    /// packets are routed to this context.
+    ///
+    /// This function returns an `Option<i64>`, which can safely be ignored if not using
+    /// `Context::service_scheduled`. `Context::service_scheduled` contains documentation on how to
+    /// handle the return value.
+    ///
+    /// * `blob` - The exported session state
+    /// * `master_key` - The key the session state was exported with
+    /// * `session_data` - Arbitrary data meaningful to the application to include with session
+    ///   object
+    pub fn import_session_state(
+        &self,
+        blob: &[u8],
+        master_key: &[u8; AES_256_KEY_SIZE],
+        session_data: C::SessionData,
+    ) -> Result<(Arc<Session<C>>, Option<i64>), ImportError> {
+        import_session(&self.0, session_data, blob, master_key, None)
+    }
+    /// Recreate a session from a checkpoint returned by `Session::serialize_state`, for example
+    /// after the process holding the original session crashed. This is
+    /// `Context::import_session_state` for blobs that are not encrypted.
+    ///
+    /// This function returns an `Option<i64>`, which can safely be ignored if not using
+    /// `Context::service_scheduled`. `Context::service_scheduled` contains documentation on how to
+    /// handle the return value.
+    ///
+    /// * `blob` - The checkpointed session state
+    /// * `session_data` - Arbitrary data meaningful to the application to include with session
+    ///   object
+    pub fn deserialize_state(
+        &self,
+        blob: &[u8],
+        session_data: C::SessionData,
+    ) -> Result<(Arc<Session<C>>, Option<i64>), ImportError> {
+        deserialize_session(&self.0, session_data, blob)
+    }
+    /// Expire every session of this context and erase all of the key material it holds in
+    /// memory, for example when the device it runs on is about to be seized.
+    ///
+    /// This erases the session keys, header keys and ratchet states of every session, and every
+    /// handshake in progress with an unauthenticated remote peer. For every session whose ratchet
+    /// states were not empty, `ApplicationLayer::save_ratchet_state` is called to replace them in
+    /// storage with the empty ratchet state. Updates that storage rejects, because it no longer
+    /// holds the ratchet states of that session, are skipped. Every session is wiped even if
+    /// storage fails, in which case the first error is returned.
+    ///
+    /// It is safe to call this while other threads are using this context. A call to
+    /// `Context::send` or `Context::receive` that already loaded the keys of a session finishes
+    /// with them, and this call waits for it to do so before returning, so they are erased by then.
+    /// Sessions or handshakes started by calls that run concurrently with this one may survive it.
+    ///
+    /// When called from inside a `Sender` or `PayloadSink` callback this does not wait, as the
+    /// send or receive making the callback holds keys itself. The keys still in use are then
+    /// erased as soon as the calls using them return. Without the `std` feature this cannot be
+    /// detected, so this must not be called from inside those callbacks, or it never returns.
+    ///
+    /// The static secret key of this context is not erased, nor is anything the application was
+    /// given a copy of, such as exported sessions or the result of `Session::ratchet_states`.
+    /// Drop the context to erase the static secret key. Ratchet states in storage for peers that
+    /// have no live session must be deleted by the application.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    pub fn emergency_wipe<App: ApplicationLayer<C>>(&self, mut app: App) -> crate::io::Result<()> {
+        // The sessions must be upgraded without holding any lock, since dropping the last
+        // reference to one expires it.
+        let sessions: Vec<_> = self.0.session_map.values().iter().filter_map(Weak::upgrade).collect();
+        self.0.unassociated_handshake_states.clear();
+        let mut defrag_cache = FragCache::new(&mut *self.0.rng().lock());
+        core::mem::swap(&mut *self.0.unassociated_defrag_cache.lock(), &mut defrag_cache);
+
+        let mut result = Ok(());
+        for session in &sessions {
+            let states = wipe_session(&self.0, session);
+            if states.is_empty() {
+                continue;
+            }
+            let empty = RatchetState::empty();
+            let update = CompareAndSwap::new(&empty, None, false, &states.state1, states.state2.as_ref(), true, true);
+            if let Err(e) = app.save_ratchet_state(&session.s_remote, &session.session_data, update) {
+                result = result.and(Err(e));
+            }
+        }
+        result
     }
     /// Perform periodic background service and cleanup tasks.
     ///
@@ -689,20 +1737,48 @@ impl<C: CryptoLayer> Context<C> {
     ///
     /// * `app` - Interface to application using ZSSP
     /// * `send_to` - Function to get a sender and an MTU to send something over an active session
-    pub fn service<App: ApplicationLayer<C>>(&self, mut app: App, mut send_to: impl SendTo<C>) -> i64 {
+    pub fn service<App: ApplicationLayer<C>>(&self, app: App, send_to: impl SendTo<C>) -> i64 {
+        self.service_budgeted(app, send_to, usize::MAX).0
+    }
+    /// Perform periodic background service and cleanup tasks, servicing the timers of at most
+    /// `max_sessions` sessions.
+    ///
+    /// When many session timers expire at once, for example after the process was suspended,
+    /// `Context::service` does all of the work in a single call. This function instead allows the
+    /// caller to interleave servicing with other work, such as receiving packets.
+    ///
+    /// Returns the number of milliseconds until it should be called again, like `Context::service`,
+    /// and whether sessions were left unserviced because the budget ran out. If so, it should be
+    /// called again as soon as possible, and the returned number of milliseconds will be 0.
+    ///
+    /// * `app` - Interface to application using ZSSP
+    /// * `send_to` - Function to get a sender and an MTU to send something over an active session
+    /// * `max_sessions` - The greatest number of sessions to service in this call
+    pub fn service_budgeted<App: ApplicationLayer<C>>(
+        &self,
+        mut app: App,
+        mut send_to: impl SendTo<C>,
+        max_sessions: usize,
+    ) -> (i64, bool) {
         let current_time = app.time();
-        let next_service_time = loop {
-            match self.service_inner(&mut app, send_to, current_time) {
-                Ok(ts) => break ts,
+        let mut budget = max_sessions;
+        let (next_service_time, more) = loop {
+            match self.service_inner(&mut app, send_to, current_time, &mut budget) {
+                Ok(result) => break result,
                 Err((_, s)) => send_to = s,
             }
         };
+        if more {
+            return (0, true);
+        }
         let max_interval = C::SETTINGS
             .fragment_assembly_timeout
             .min(C::SETTINGS.rekey_timeout)
             .min(C::SETTINGS.initial_offer_timeout);
 
-        (next_service_time - current_time).min(max_interval as i64)
+        // A concurrent send can lower the next service time all the way to `i64::MIN`.
+        let interval = next_service_time.saturating_sub(current_time).min(max_interval as i64);
+        (interval, false)
     }
     /// Perform periodic background service and cleanup tasks.
     ///
@@ -731,17 +1807,33 @@ impl<C: CryptoLayer> Context<C> {
         send_to: impl SendTo<C>,
     ) -> Result<i64, ExpiredError<C>> {
         let current_time = app.time();
-        self.service_inner(&mut app, send_to, current_time).map_err(|e| e.0)
+        let mut budget = usize::MAX;
+        self.service_inner(&mut app, send_to, current_time, &mut budget)
+            .map(|(next_service_time, _)| next_service_time)
+            .map_err(|e| e.0)
     }
+    /// Services at most `budget` sessions, decrementing it for each one.
+    /// Also returns whether any sessions were left unserviced because `budget` ran out.
     fn service_inner<App: ApplicationLayer<C>, F: SendTo<C>>(
         &self,
         app: &mut App,
         mut send_to: F,
         current_time: i64,
-    ) -> Result<i64, (ExpiredError<C>, F)> {
+        budget: &mut usize,
+    ) -> Result<(i64, bool), (ExpiredError<C>, F)> {
         let ctx = &self.0;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("zssp_service", current_time).entered();
         let mut session_queue = ctx.session_queue.lock();
+        // After a burst of short lived sessions the queue would otherwise hold on to the memory of
+        // all of them, and dead entries would only be found once they reach the top.
+        if ctx.dropped_sessions.load(Ordering::Relaxed) >= SESSION_QUEUE_COMPACTION_THRESHOLD {
+            ctx.dropped_sessions.store(0, Ordering::Relaxed);
+            session_queue.retain(|session, _| session.strong_count() > 0);
+            session_queue.shrink_to_fit();
+        }
         let mut queue_service_time = i64::MAX;
+        let mut more = false;
         // This update system takes advantage of the fact that sessions only need to be updated
         // either roughly every second or roughly every hour. That big gap allows for minor optimizations.
         // If the gap changes (unlikely) this code may need to be rewritten.
@@ -750,6 +1842,11 @@ impl<C: CryptoLayer> Context<C> {
                 queue_service_time = queue_service_time.min(*timer);
                 break;
             }
+            if *budget == 0 {
+                queue_service_time = queue_service_time.min(*timer);
+                more = true;
+                break;
+            }
             let session = match session.upgrade() {
                 Some(s) => s,
                 None => {
@@ -757,12 +1854,20 @@ impl<C: CryptoLayer> Context<C> {
                     continue;
                 }
             };
+            *budget -= 1;
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!("zssp_service_session", session = ?Arc::as_ptr(&session)).entered();
+            let send_failure = Cell::new(None);
             let result = process_timers(app, ctx, &session, current_time, |packet, hk_send| {
                 if let Some((sender, mut mtu)) = send_to.init_send(&session) {
+                    if mtu == 0 {
+                        mtu = session.mtu_hint();
+                    }
                     mtu = mtu.max(MIN_TRANSPORT_MTU);
-                    send_with_fragmentation(sender, mtu, packet, hk_send);
+                    send_failure.set(send_with_fragmentation(sender, mtu, packet, hk_send));
                 }
             });
+            report_send_failure(app, &session, &send_failure);
             if let Ok(next_timer) = result {
                 queue_service_time = queue_service_time.min(next_timer);
                 session_queue.change_priority(queue_idx, Reverse(next_timer));
@@ -783,12 +1888,25 @@ impl<C: CryptoLayer> Context<C> {
             .unassociated_defrag_cache
             .lock()
             .check_for_expiry(current_time);
+        // Handshakes only outlive `initial_offer_timeout` if this function is called too rarely,
+        // so check before `service` expires them.
+        #[cfg(any(feature = "logging", feature = "tracing"))]
+        if let Some(age) = ctx.unassociated_handshake_states.oldest_entry_age(current_time) {
+            if age > C::SETTINGS.initial_offer_timeout as i64 {
+                log!(app, StaleUnassociatedHandshakes(age));
+            }
+        }
         let handshake_service_time = self.0.unassociated_handshake_states.service(current_time);
+        let challenge_service_time = ctx.challenge.service(ctx.rng(), current_time);
+        let reliable_service_time = ctx.reliable_sends.service(ctx, current_time, &mut send_to);
 
-        let t2 = defrag_service_time.min(handshake_service_time);
+        let t2 = defrag_service_time
+            .min(handshake_service_time)
+            .min(challenge_service_time)
+            .min(reliable_service_time);
         let t1 = ctx.next_service_time.fetch_min(t2, Ordering::Relaxed);
 
-        Ok(t1.min(t2))
+        Ok((t1.min(t2), more))
     }
     /// Returns the exact timestamp at which either `Context::service` or
     /// `Context::service_scheduled` should be called again.
@@ -798,4 +1916,108 @@ impl<C: CryptoLayer> Context<C> {
     pub fn next_service_time(&self) -> i64 {
         self.0.next_service_time.load(Ordering::Relaxed)
     }
+    /// Statistics about the challenges this context has issued and verified, see
+    /// `IncomingSessionAction::Challenge`.
+    ///
+    /// Verifying a response costs at most two SHA-512 hashes, so `issued + succeeded + failed`
+    /// approximates the CPU spent on the challenge layer.
+    pub fn challenge_stats(&self) -> ChallengeStats {
+        self.0.challenge.stats()
+    }
+    /// Write counters of the handshakes, rekeys, data packets and faults this context has
+    /// processed to `out`, in the Prometheus text exposition format.
+    ///
+    /// All counters start at zero when the context is created. Every metric name is prefixed with
+    /// `zssp_`, and faults are counted per `FaultType` under the `type` label.
+    /// Only the payloads of data packets are counted as bytes, not their headers or tags.
+    pub fn render_metrics(&self, out: &mut impl Write) -> crate::io::Result<()> {
+        let ctx = &self.0;
+        ctx.metrics.render(out, ctx.unassociated_handshake_states.len())
+    }
+    /// Totals of the sessions, data, faults and handshakes this context has processed, for
+    /// monitoring endpoints that do not use `render_metrics`.
+    ///
+    /// All totals start at zero when the context is created.
+    ///
+    /// Totals are counted with relaxed atomics so that counting stays cheap on the hot path. When
+    /// read while packets are being processed concurrently they can be very slightly out of date,
+    /// and one total may already include a packet that another does not yet. The same holds for
+    /// `challenge_stats`, `Session::replay_stats` and `Session::fault_stats`.
+    pub fn statistics(&self) -> ContextStats {
+        self.0.metrics.stats()
+    }
+    /// Look up the session that the local key id `kid` currently belongs to, for example to
+    /// inspect a session whose key id was seen in the logs.
+    ///
+    /// A session can be found by every key id it will currently accept packets on, which may
+    /// include the key id of its previous key for a short while after a rekey or a key id
+    /// rotation. Returns `None` if no live session uses `kid`.
+    pub fn get_session_by_kid(&self, kid: NonZeroU32) -> Option<Arc<Session<C>>> {
+        self.0.session_map.shard(kid).read().get(&kid).and_then(Weak::upgrade)
+    }
+}
+
+#[test]
+fn test_import_errors() {
+    use crate::crypto_impl::OpenSSLAesGcm;
+    use crate::sim::{LinkConfig, Sim};
+    let sim = Sim::new(1, LinkConfig::default());
+    let ctx = &sim.alice.ctx;
+    let own_key = sim.alice.public_key.to_bytes();
+    let other_key = sim.bob.public_key.to_bytes();
+    let master_key = [7u8; AES_256_KEY_SIZE];
+    let seal = |version: u8, data: &[u8]| {
+        let nonce = [1u8; AES_GCM_NONCE_SIZE];
+        let mut blob = vec![version];
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(data);
+        let tag = OpenSSLAesGcm::encrypt_in_place(&master_key, &nonce, &[version], &mut blob[1 + AES_GCM_NONCE_SIZE..]);
+        blob.extend_from_slice(&tag);
+        blob
+    };
+
+    let import = |blob: &[u8], key: &[u8; AES_256_KEY_SIZE]| ctx.import_session_state(blob, key, ()).err();
+    assert_eq!(import(&[], &master_key), Some(ImportError::InvalidBlob));
+    assert_eq!(
+        import(&seal(EXPORT_VERSION - 1, &own_key), &master_key),
+        Some(ImportError::UnsupportedVersion(EXPORT_VERSION - 1))
+    );
+    assert_eq!(import(&seal(EXPORT_VERSION, &other_key), &master_key), Some(ImportError::WrongStaticKey));
+    // The static key matches but the rest of the state is missing.
+    assert_eq!(import(&seal(EXPORT_VERSION, &own_key), &master_key), Some(ImportError::InvalidBlob));
+    assert_eq!(import(&seal(EXPORT_VERSION, &other_key), &[8; AES_256_KEY_SIZE]), Some(ImportError::InvalidBlob));
+    let mut blob = seal(EXPORT_VERSION, &other_key);
+    blob[20] ^= 1;
+    assert_eq!(import(&blob, &master_key), Some(ImportError::InvalidBlob));
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_header_round_trip(
+        junk: [u8; MIN_PACKET_SIZE],
+        kid: u32,
+        packet_type: u8,
+        counter: u64,
+        fragment_no: u8,
+        fragment_count in 0..=MAX_FRAGMENTS as u8 + 1,
+    ) {
+        // Every field is written over whatever the buffer held before, the way the send path does.
+        let mut packet = junk;
+        set_header(&mut packet, kid, &to_nonce(packet_type, counter));
+        packet[FRAGMENT_NO_IDX] = fragment_no;
+        packet[FRAGMENT_COUNT_IDX] = fragment_count;
+
+        proptest::prop_assert_eq!(u32::from_ne_bytes(packet[..KID_SIZE].try_into().unwrap()), kid);
+        proptest::prop_assert_eq!(&packet[HEADER_SIZE..], &junk[HEADER_SIZE..]);
+        match parse_fragment_header::<crate::sim::SimCrypto, ()>(&packet) {
+            Ok((no, count, nonce)) => {
+                proptest::prop_assert!(fragment_no < fragment_count && fragment_count as usize <= MAX_FRAGMENTS);
+                proptest::prop_assert_eq!((no, count), (fragment_no as usize, fragment_count as usize));
+                proptest::prop_assert_eq!(nonce, to_nonce(packet_type, counter));
+                proptest::prop_assert_eq!(from_nonce(&nonce), (packet_type, counter));
+            }
+            Err(_) => proptest::prop_assert!(fragment_no >= fragment_count || fragment_count as usize > MAX_FRAGMENTS),
+        }
+    }
 }
@@ -0,0 +1,141 @@
+/* A handshake and a round of data between two contexts through the C interface, with packets
+ * carried by in-memory queues. Run by `tests/ffi_loopback.rs`. */
+#include <string.h>
+
+#include "zssp.h"
+
+#define MTU 1280
+#define QUEUE_CAPACITY 64
+
+struct queue {
+    uint8_t packets[QUEUE_CAPACITY][MTU];
+    size_t lens[QUEUE_CAPACITY];
+    size_t head, len;
+};
+
+struct peer {
+    int64_t *time;
+    struct queue *outbox;
+};
+
+static struct queue to_alice, to_bob;
+
+#define CHECK(cond)                                                                                                    \
+    if (!(cond))                                                                                                       \
+        return __LINE__;
+
+static int64_t time_cb(void *user) {
+    return *((struct peer *)user)->time;
+}
+
+static bool push(struct queue *q, const uint8_t *packet, size_t len) {
+    if (q->len == QUEUE_CAPACITY || len > MTU)
+        return false;
+    size_t i = (q->head + q->len++) % QUEUE_CAPACITY;
+    memcpy(q->packets[i], packet, len);
+    q->lens[i] = len;
+    return true;
+}
+
+static bool send_to_session(void *user, uint64_t session_data, uint8_t *packet, size_t len) {
+    (void)session_data;
+    return push(((struct peer *)user)->outbox, packet, len);
+}
+
+static bool send_to_address(void *user, uint64_t remote_address, uint8_t *packet, size_t len) {
+    (void)remote_address;
+    return push(((struct peer *)user)->outbox, packet, len);
+}
+
+static bool accept_all(void *user, const uint8_t *remote_key, const uint8_t *identity, size_t identity_len,
+                       uint64_t *session_data) {
+    (void)user;
+    (void)remote_key;
+    if (identity_len != 5 || memcmp(identity, "alice", 5) != 0)
+        return false;
+    *session_data = 2;
+    return true;
+}
+
+/* Deliver every queued packet to `ctx`, releasing returned handles except for a new session,
+ * which is stored in `new_session`. Returns the number of packets delivered, or -1 on error. */
+static int drain(zssp_context_t *ctx, struct queue *inbox, zssp_session_t **new_session, uint8_t *payload,
+                 size_t *payload_len) {
+    int delivered = 0;
+    while (inbox->len > 0) {
+        uint8_t *packet = inbox->packets[inbox->head];
+        size_t packet_len = inbox->lens[inbox->head];
+        inbox->head = (inbox->head + 1) % QUEUE_CAPACITY;
+        inbox->len--;
+        delivered++;
+
+        size_t len = 0;
+        int event = ZSSP_EVENT_NONE;
+        zssp_session_t *session = NULL;
+        int status = zssp_receive(ctx, 1, packet, packet_len, payload, MTU, &len, &event, &session);
+        if (status != ZSSP_OK)
+            return -1;
+        if (event == ZSSP_EVENT_NEW_SESSION) {
+            *new_session = zssp_session_retain(session);
+        } else if (event == ZSSP_EVENT_DATA) {
+            *payload_len = len;
+        }
+        zssp_session_release(session);
+    }
+    return delivered;
+}
+
+int zssp_loopback_test(void) {
+    int64_t time = 0;
+    struct peer alice_peer = {&time, &to_bob};
+    struct peer bob_peer = {&time, &to_alice};
+    zssp_callbacks_t callbacks = {0};
+    callbacks.mtu = MTU;
+    callbacks.time = time_cb;
+    callbacks.send_to_session = send_to_session;
+    CHECK(zssp_context_new(&callbacks) == NULL);
+    callbacks.send_to_address = send_to_address;
+
+    callbacks.user = &alice_peer;
+    zssp_context_t *alice = zssp_context_new(&callbacks);
+    callbacks.user = &bob_peer;
+    callbacks.check_accept_session = accept_all;
+    zssp_context_t *bob = zssp_context_new(&callbacks);
+    CHECK(alice != NULL && bob != NULL);
+
+    uint8_t bob_key[ZSSP_PUBLIC_KEY_SIZE];
+    zssp_context_public_key(bob, bob_key);
+    zssp_session_t *alice_session = NULL;
+    CHECK(zssp_open(alice, bob_key, 1, (const uint8_t *)"alice", 5, &alice_session) == ZSSP_OK);
+    CHECK(zssp_session_data(alice_session) == 1);
+    CHECK(zssp_send(alice, alice_session, (const uint8_t *)"early", 5) == ZSSP_ERR_SESSION_NOT_ESTABLISHED);
+
+    zssp_session_t *bob_session = NULL;
+    uint8_t payload[MTU];
+    size_t payload_len = 0;
+    int sent = 0;
+    while (payload_len == 0) {
+        time += 10;
+        if (!sent && zssp_session_established(alice_session)) {
+            CHECK(zssp_send(alice, alice_session, (const uint8_t *)"hello from c", 12) == ZSSP_OK);
+            sent = 1;
+        }
+        int a = drain(alice, &to_alice, &bob_session, payload, &payload_len);
+        int b = drain(bob, &to_bob, &bob_session, payload, &payload_len);
+        CHECK(a >= 0 && b >= 0);
+        /* The handshake stalled. */
+        CHECK(a + b > 0);
+        zssp_service(alice);
+        zssp_service(bob);
+    }
+    CHECK(payload_len == 12 && memcmp(payload, "hello from c", 12) == 0);
+    CHECK(bob_session != NULL && zssp_session_data(bob_session) == 2 && zssp_session_established(bob_session));
+
+    zssp_session_close(alice_session);
+    CHECK(zssp_send(alice, alice_session, (const uint8_t *)"late", 4) == ZSSP_ERR_SESSION_EXPIRED);
+    zssp_session_release(alice_session);
+    zssp_session_release(bob_session);
+    zssp_context_free(alice);
+    zssp_context_free(bob);
+    return 0;
+}
@@ -0,0 +1,16 @@
+use std::ffi::c_int;
+
+// Make sure the C interface is linked even though nothing in Rust calls it.
+#[allow(unused_imports)]
+use zssp::ffi::*;
+
+#[link(name = "zssp_loopback", kind = "static")]
+extern "C" {
+    fn zssp_loopback_test() -> c_int;
+}
+
+#[test]
+fn c_loopback_handshake() {
+    let line = unsafe { zssp_loopback_test() };
+    assert_eq!(line, 0, "tests/ffi/loopback.c failed a check on line {}", line);
+}
@@ -0,0 +1,49 @@
+use zssp::application::Settings;
+use zssp::result::SessionEvent;
+use zssp::testing::*;
+
+fn data(received: &[Received], side: Side) -> Vec<&[u8]> {
+    received
+        .iter()
+        .filter(|r| r.side == side && r.event() == Some(&SessionEvent::Data))
+        .map(|r| &r.data[..])
+        .collect()
+}
+
+#[test]
+fn loopback_pair_exchanges_data() {
+    let pair = LoopbackPair::new();
+    let received = pair.establish();
+    assert!(received
+        .iter()
+        .any(|r| r.side == Side::B && r.event() == Some(&SessionEvent::NewSession)));
+    assert!(pair.is_established());
+    assert_eq!(data(&pair.send_a_to_b(b"ping").unwrap(), Side::B), [b"ping"]);
+    assert_eq!(data(&pair.send_b_to_a(b"pong").unwrap(), Side::A), [b"pong"]);
+}
+
+#[test]
+fn loopback_pair_recovers_from_loss() {
+    let pair = LoopbackPair::new();
+    pair.drop_next_n_packets(1);
+    pair.establish();
+    assert!(!pair.is_established());
+    pair.tick(Settings::RESEND_TIME as i64);
+    assert!(pair.is_established());
+
+    pair.drop_next_n_packets(1);
+    assert!(data(&pair.send_a_to_b(b"lost").unwrap(), Side::B).is_empty());
+    assert_eq!(data(&pair.send_a_to_b(b"found").unwrap(), Side::B), [b"found"]);
+}
+
+#[test]
+fn loopback_pair_rekeys() {
+    let pair = LoopbackPair::new();
+    pair.establish();
+    let session = pair.session(Side::A).unwrap();
+    let ratchet_count = session.ratchet_count();
+    pair.tick(Settings::REKEY_AFTER_TIME_MS as i64 + Settings::REKEY_AFTER_TIME_MAX_JITTER_MS as i64);
+    assert!(session.ratchet_count() > ratchet_count);
+    assert_eq!(session.ratchet_count(), pair.session(Side::B).unwrap().ratchet_count());
+    assert_eq!(data(&pair.send_a_to_b(b"after").unwrap(), Side::B), [b"after"]);
+}
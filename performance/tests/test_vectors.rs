@@ -0,0 +1,17 @@
+//! Replays the published handshake test vectors, so that any change to the packets or the keys
+//! a handshake produces, such as a changed KDF label or hashing order, fails here first.
+use zssp::test_vectors::*;
+
+#[test]
+fn handshake_test_vectors() {
+    let vectors: Vec<TestVector> = serde_json::from_str(include_str!("vectors/handshake.json")).unwrap();
+    assert!(!vectors.is_empty());
+    for vector in vectors {
+        assert_eq!(
+            run_handshake(&vector.inputs),
+            vector.outputs,
+            "{}",
+            vector.inputs.description
+        );
+    }
+}